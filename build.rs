@@ -1,6 +1,14 @@
 use std::prelude::rust_2021::*;
+use std::process::Command;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("src/main/proto/helloworld.proto")?;
-    Ok(())
+fn main() {
+    // Record the commit the daemon is built from, for `GetServiceInfo`, if built from a git checkout:
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let commit = Command::new("git").args(["rev-parse", "HEAD"]).output().ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=GIT_COMMIT={}", commit.trim());
+    }
 }
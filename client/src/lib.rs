@@ -0,0 +1,394 @@
+//! A client of the `MuSig` service of the trade daemon, wrapping the generated tonic client with
+//! typed requests & results for each protocol step, so that the keys, nonces & signatures passed
+//! between the steps (and the peers) are [`Point`](secp::Point)s, [`PubNonce`](musig2::PubNonce)s
+//! and so on, rather than bare bytes. Failed calls are retried as set by a [`RetryPolicy`].
+//!
+//! Each party of a trade between two daemons, with the client relaying the payloads between them,
+//! would go as follows:
+//!
+//! ```no_run
+//! use musig_trade_client::{CloseTrade, GetNonceShares, GetPartialSignatures, InitTrade, PublishDepositTx,
+//!     SignDepositTx, SignSwapTx, TradeClient};
+//! use musig_trade_protocol::Role;
+//!
+//! # async fn run() -> Result<(), musig_trade_client::ClientError> {
+//! let buyer = TradeClient::connect("http://127.0.0.1:50051").await?;
+//! let seller = TradeClient::connect("http://127.0.0.1:50061").await?;
+//!
+//! let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await?;
+//! let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await?;
+//! let terms = |step: GetNonceShares| step.fee_rates(50.0, 40.0).amounts(200_000, 30_000, 30_000);
+//! let buyer_nonces = buyer.get_nonce_shares(terms(GetNonceShares::new("trade").peers_key_shares(&seller_keys))).await?;
+//! let seller_nonces = seller.get_nonce_shares(terms(GetNonceShares::new("trade").peers_key_shares(&buyer_keys))).await?;
+//! let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)).await?;
+//! let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&buyer_nonces)).await?;
+//! seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await?;
+//! let psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await?;
+//! buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(psbt)).await?;
+//!
+//! // Once the buyer has started payment:
+//! let swap_tx = seller.sign_swap_tx(SignSwapTx::new("trade").peers_partial_signatures(&buyer_sigs)).await?;
+//! let buyer_key = buyer.close_trade(CloseTrade::new("trade").peers_prv_key_share(&swap_tx.peer_output_prv_key_share)).await?;
+//! seller.close_trade(CloseTrade::new("trade").peers_prv_key_share(&buyer_key)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod retry;
+mod steps;
+
+pub use retry::RetryPolicy;
+pub use steps::{AcceptFeeRateChange, AcceptSwapTxFeeBump, CancelBeforeDeposit, CloseTrade, GetNonceShares, GetPartialSignatures, InitTrade, KeyShares, NonceShares,
+    PartialSignatures, ProposeFeeRateChange, ProposeSwapTxFeeBump, PrvKeyShareForPeer, PublishDepositTx, ResetSigningSession, RevealNonceShares, SignDepositTx, SignSwapTx, SwapTxSignature};
+
+use musig_proto::convert::ConvertError;
+use musig_proto::helloworld::mu_sig_client::MuSigClient;
+use musig_proto::helloworld::{self, TxConfirmationStatus};
+use std::future::Future;
+use std::prelude::rust_2021::*;
+use thiserror::Error;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Status, Streaming};
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("could not connect: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    #[error("call failed: {0}")]
+    Status(#[from] Status),
+    #[error("malformed response: {0}")]
+    Convert(#[from] ConvertError),
+}
+
+type Result<T, E = ClientError> = std::result::Result<T, E>;
+
+/// A client of the `MuSig` service. It is cheap to clone, with each clone sharing the connection.
+#[derive(Clone)]
+pub struct TradeClient {
+    inner: MuSigClient<Channel>,
+    retry_policy: RetryPolicy,
+}
+
+impl TradeClient {
+    /// Connect to the daemon at the given URL, e.g. `http://127.0.0.1:50051`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Transport`] if the URL is invalid or the daemon could not be reached.
+    pub async fn connect(url: impl Into<String>) -> Result<Self> {
+        let channel = Endpoint::from_shared(url.into())?.connect().await?;
+        Ok(Self::new(channel))
+    }
+
+    #[must_use]
+    pub fn new(channel: Channel) -> Self {
+        Self { inner: MuSigClient::new(channel), retry_policy: RetryPolicy::default() }
+    }
+
+    #[must_use]
+    pub const fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// The generated client, for the calls with no typed helper here.
+    pub const fn inner(&self) -> &MuSigClient<Channel> {
+        &self.inner
+    }
+
+    async fn call<R, T, F, Fut>(&self, request: R, call: F) -> Result<T, Status>
+        where R: Clone,
+              F: Fn(MuSigClient<Channel>, R) -> Fut,
+              Fut: Future<Output=Result<tonic::Response<T>, Status>>
+    {
+        let response = self.retry_policy.retry(|| call(self.inner.clone(), request.clone())).await?;
+        Ok(response.into_inner())
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails, or [`ClientError::Convert`] if the response
+    /// holds a malformed key share or identity signature.
+    pub async fn init_trade(&self, step: InitTrade) -> Result<KeyShares> {
+        let response = self.call(step.0, |mut c, r| async move { c.init_trade(r).await }).await?;
+        Ok(response.try_into()?)
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails, or [`ClientError::Convert`] if the response
+    /// holds a malformed nonce share.
+    pub async fn get_nonce_shares(&self, step: GetNonceShares) -> Result<NonceShares> {
+        let response = self.call(step.0, |mut c, r| async move { c.get_nonce_shares(r).await }).await?;
+        Ok(response.try_into()?)
+    }
+
+    /// Start a fresh signing session, returning our fresh nonce shares (or commitments to them).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails, or [`ClientError::Convert`] if the response
+    /// holds a malformed nonce share.
+    pub async fn reset_signing_session(&self, step: ResetSigningSession) -> Result<NonceShares> {
+        let response = self.call(step.0, |mut c, r| async move { c.reset_signing_session(r).await }).await?;
+        Ok(response.try_into()?)
+    }
+
+    /// Reveal our nonce shares, once the peer's nonce commitments are in, for a trade committing to
+    /// its nonces.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails, or [`ClientError::Convert`] if the response
+    /// holds a malformed nonce share.
+    pub async fn reveal_nonce_shares(&self, step: RevealNonceShares) -> Result<NonceShares> {
+        let response = self.call(step.0, |mut c, r| async move { c.reveal_nonce_shares(r).await }).await?;
+        Ok(response.try_into()?)
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails, or [`ClientError::Convert`] if the response
+    /// holds a malformed partial signature.
+    pub async fn get_partial_signatures(&self, step: GetPartialSignatures) -> Result<PartialSignatures> {
+        let response = self.call(step.0, |mut c, r| async move { c.get_partial_signatures(r).await }).await?;
+        Ok(response.try_into()?)
+    }
+
+    /// Propose (or complete) a change of the prepared tx fee rate, returning the message for the
+    /// peer's [`AcceptFeeRateChange`] step.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn propose_fee_rate_change(&self, step: ProposeFeeRateChange) -> Result<helloworld::FeeRateChangeMessage> {
+        Ok(self.call(step.0, |mut c, r| async move { c.propose_fee_rate_change(r).await }).await?)
+    }
+
+    /// Accept (or complete) the peer's change of the prepared tx fee rate, returning the message for
+    /// the peer's [`ProposeFeeRateChange`] step, unless the change is complete.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn accept_fee_rate_change(&self, step: AcceptFeeRateChange) -> Result<helloworld::FeeRateChangeMessage> {
+        Ok(self.call(step.0, |mut c, r| async move { c.accept_fee_rate_change(r).await }).await?)
+    }
+
+    /// Sign the deposit tx, returning our half of the deposit PSBT.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn sign_deposit_tx(&self, step: SignDepositTx) -> Result<Vec<u8>> {
+        let response = self.call(step.0, |mut c, r| async move { c.sign_deposit_tx(r).await }).await?;
+        Ok(response.deposit_psbt)
+    }
+
+    /// Cancel the trade before its deposit tx is published, returning our consent for the peer's
+    /// [`CancelBeforeDeposit`] step, with the trade as left by the step (archived, unless it awaits
+    /// the peer's consent).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn cancel_before_deposit(&self, step: CancelBeforeDeposit) -> Result<helloworld::CancelBeforeDepositResponse> {
+        Ok(self.call(step.0, |mut c, r| async move { c.cancel_before_deposit(r).await }).await?)
+    }
+
+    /// Publish the deposit tx, returning the stream of its confirmation statuses. Only the call
+    /// itself is retried, not the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn publish_deposit_tx(&self, step: PublishDepositTx) -> Result<Streaming<TxConfirmationStatus>> {
+        Ok(self.call(step.0, |mut c, r| async move { c.publish_deposit_tx(r).await }).await?)
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails, or [`ClientError::Convert`] if the response
+    /// holds a malformed swap tx signature or private key share.
+    pub async fn sign_swap_tx(&self, step: SignSwapTx) -> Result<SwapTxSignature> {
+        let response = self.call(step.0, |mut c, r| async move { c.sign_swap_tx(r).await }).await?;
+        Ok(response.try_into()?)
+    }
+
+    /// Propose (or complete), as the buyer, re-signing the swap tx at a higher fee rate, returning
+    /// the message for the seller's [`AcceptSwapTxFeeBump`] step.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn propose_swap_tx_fee_bump(&self, step: ProposeSwapTxFeeBump) -> Result<helloworld::SwapTxFeeBumpMessage> {
+        Ok(self.call(step.0, |mut c, r| async move { c.propose_swap_tx_fee_bump(r).await }).await?)
+    }
+
+    /// Accept (or complete), as the seller, the buyer's bump of the swap tx fee rate, returning the
+    /// message for the buyer's [`ProposeSwapTxFeeBump`] step or, once the bump is complete, the
+    /// message holding the re-signed swap tx.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn accept_swap_tx_fee_bump(&self, step: AcceptSwapTxFeeBump) -> Result<helloworld::SwapTxFeeBumpMessage> {
+        Ok(self.call(step.0, |mut c, r| async move { c.accept_swap_tx_fee_bump(r).await }).await?)
+    }
+
+    /// Confirm, as the buyer, that we have started payment, returning our receipt for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn confirm_payment_started(&self, trade_id: impl Into<String>, expected_revision: Option<u64>)
+        -> Result<helloworld::PaymentReceipt>
+    {
+        let request = helloworld::ConfirmPaymentRequest { trade_id: trade_id.into(), expected_revision };
+        Ok(self.call(request, |mut c, r| async move { c.confirm_payment_started(r).await }).await?)
+    }
+
+    /// Confirm, as the seller, that we have received payment, returning our receipt for it. This
+    /// must be done before signing the swap tx.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn confirm_payment_received(&self, trade_id: impl Into<String>, expected_revision: Option<u64>)
+        -> Result<helloworld::PaymentReceipt>
+    {
+        let request = helloworld::ConfirmPaymentRequest { trade_id: trade_id.into(), expected_revision };
+        Ok(self.call(request, |mut c, r| async move { c.confirm_payment_received(r).await }).await?)
+    }
+
+    /// Release the buyer's partial signature on the swap tx to the seller's daemon, for a trade
+    /// exchanging its peer payloads directly.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn release_swap_tx_signature(&self, trade_id: impl Into<String>) -> Result<()> {
+        let request = helloworld::ReleaseSwapTxSignatureRequest { trade_id: trade_id.into() };
+        self.call(request, |mut c, r| async move { c.release_swap_tx_signature(r).await }).await?;
+        Ok(())
+    }
+
+    /// Close the trade, returning our private key share for the peer's output.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails, or [`ClientError::Convert`] if the response
+    /// holds a malformed private key share.
+    pub async fn close_trade(&self, step: CloseTrade) -> Result<PrvKeyShareForPeer> {
+        let response = self.call(step.0, |mut c, r| async move { c.close_trade(r).await }).await?;
+        Ok(response.try_into()?)
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn archive_trade(&self, trade_id: impl Into<String>, expected_revision: Option<u64>)
+        -> Result<helloworld::TradeSummary>
+    {
+        let request = helloworld::ArchiveTradeRequest { trade_id: trade_id.into(), expected_revision };
+        Ok(self.call(request, |mut c, r| async move { c.archive_trade(r).await }).await?)
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn list_trades(&self, archived: bool) -> Result<Vec<helloworld::TradeSummary>> {
+        let request = helloworld::ListTradesRequest { archived };
+        Ok(self.call(request, |mut c, r| async move { c.list_trades(r).await }).await?.trades)
+    }
+
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn get_trade_state(&self, trade_id: impl Into<String>) -> Result<helloworld::TradeState> {
+        let request = helloworld::GetTradeStateRequest { trade_id: trade_id.into() };
+        Ok(self.call(request, |mut c, r| async move { c.get_trade_state(r).await }).await?)
+    }
+
+    /// Everything needed to pick a trade up again after a restart: its state, transcript and
+    /// progress, with the peer payloads handed out so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn resume_trade(&self, trade_id: impl Into<String>) -> Result<helloworld::ResumeTradeResponse> {
+        let request = helloworld::ResumeTradeRequest { trade_id: trade_id.into() };
+        Ok(self.call(request, |mut c, r| async move { c.resume_trade(r).await }).await?)
+    }
+
+    /// The signed package of our fully-signed warning & redirect txs for a trade, to be saved to a
+    /// file (as its `package` bytes) and kept offline.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn export_prepared_tx_package(&self, trade_id: impl Into<String>)
+        -> Result<helloworld::ExportPreparedTxPackageResponse>
+    {
+        let request = helloworld::ExportPreparedTxPackageRequest { trade_id: trade_id.into() };
+        Ok(self.call(request, |mut c, r| async move { c.export_prepared_tx_package(r).await }).await?)
+    }
+
+    /// The daemon's version & build, and what it supports & is configured with.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn get_service_info(&self) -> Result<helloworld::ServiceInfo> {
+        Ok(self.call(helloworld::GetServiceInfoRequest {}, |mut c, r| async move { c.get_service_info(r).await }).await?)
+    }
+
+    /// The wallet's UTXOs reserved by the daemon's live trades, and the balance of the given UTXOs of
+    /// the wallet left available for further trades.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ClientError::Status`] if the call fails.
+    pub async fn get_wallet_status(&self, wallet_utxos: Vec<helloworld::FundingInput>) -> Result<helloworld::WalletStatus> {
+        let request = helloworld::GetWalletStatusRequest { wallet_utxos };
+        Ok(self.call(request, |mut c, r| async move { c.get_wallet_status(r).await }).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use tonic::Code;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let policy = RetryPolicy::default();
+        let backoffs: Vec<_> = (1..=7).map(|attempt| policy.backoff(attempt).as_millis()).collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1600, 2000, 2000]);
+        assert_eq!(policy.backoff(100), Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn retry_only_retries_retryable_failures() {
+        let policy = RetryPolicy { initial_backoff: Duration::from_millis(1), ..RetryPolicy::default() };
+        for (code, expected_attempts) in [(Code::Unavailable, 3), (Code::ResourceExhausted, 3), (Code::Aborted, 1)] {
+            let attempts = AtomicU32::new(0);
+            let result: Result<(), _> = policy.retry(|| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err(Status::new(code, "failed")) }
+            }).await;
+            assert_eq!(result.unwrap_err().code(), code);
+            assert_eq!(attempts.into_inner(), expected_attempts);
+        }
+    }
+
+    #[test]
+    fn malformed_key_share_names_field() {
+        let response = helloworld::PubKeySharesResponse { buyer_output_pub_key_share: vec![2; 33], ..Default::default() };
+        let err = KeyShares::try_from(response).err().unwrap();
+        assert_eq!(err.to_string(), "could not decode seller_output_pub_key_share: \
+            malformed point: expected a 33-byte compressed point, got 0 bytes");
+    }
+}
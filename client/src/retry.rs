@@ -0,0 +1,63 @@
+use std::future::Future;
+use std::prelude::rust_2021::*;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// How many times to try a call, and how long to back off between tries. Only calls failing with
+/// `UNAVAILABLE` (the daemon could not be reached) or `RESOURCE_EXHAUSTED` (the call was refused by
+/// a rate limit or quota) are retried, as the daemon has then not run the step. Any other failure
+/// is returned straight away.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// The most tries of each call, including the first (so 1 turns retries off).
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 3, initial_backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(2) }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy trying each call just once.
+    #[must_use]
+    pub const fn never() -> Self {
+        Self { max_attempts: 1, initial_backoff: Duration::ZERO, max_backoff: Duration::ZERO }
+    }
+
+    /// The backoff after the given (1-based) failed try, doubling with each try up to the maximum.
+    #[must_use]
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1_u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    #[must_use]
+    pub fn is_retryable(status: &Status) -> bool {
+        matches!(status.code(), Code::Unavailable | Code::ResourceExhausted)
+    }
+
+    /// Make the given call, retrying it as long as the policy allows.
+    ///
+    /// # Errors
+    ///
+    /// Returns the status of the last try, if every try fails.
+    pub async fn retry<T, F, Fut>(&self, mut call: F) -> Result<T, Status>
+        where F: FnMut() -> Fut,
+              Fut: Future<Output=Result<T, Status>>
+    {
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Err(status) if attempt < self.max_attempts && Self::is_retryable(&status) => {
+                    tokio::time::sleep(self.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
@@ -0,0 +1,620 @@
+//! The requests of each protocol step, built up from the typed values handed out by the earlier
+//! steps (of either party), and the typed results of the steps.
+
+use musig2::{CompactSignature, LiftedSignature};
+use musig_proto::convert::{decode, decode_role, ConvertError};
+use musig_proto::helloworld;
+use musig_proto::helloworld::partial_signatures_message::SwapTxInput;
+use musig_trade_protocol::storage::{ByVal, Redactable};
+use musig_trade_protocol::{CoinControl, ExchangedNonces, ExchangedSigs, FundingInput, PeerEndpoint, Role};
+use secp::{Point, Scalar};
+use std::prelude::rust_2021::*;
+
+/// Our key shares for a trade, as handed out by `InitTrade`, to pass on to the peer's
+/// [`GetNonceShares`] step.
+#[derive(Clone, Debug)]
+pub struct KeyShares {
+    pub buyer_output_pub_key_share: Point,
+    pub seller_output_pub_key_share: Point,
+    pub identity_pub_key: Point,
+    pub identity_signature: CompactSignature,
+    pub current_block_height: u32,
+    /// The address of the daemon's `MuSigPeer` service, for the peer's [`PeerEndpoint`], if served.
+    pub my_peer_address: Option<String>,
+    /// Our half of the deposit tx, holding our funding inputs (if any), which is passed on to the
+    /// peer unchanged, as it is signed.
+    pub half_deposit_psbt: Vec<u8>,
+    /// Our ID for the trade, which the funding inputs are proven for.
+    pub trade_id: String,
+    pub role: Role,
+    /// Our static X25519 key for the Noise channel sealing the peer payloads, if the trade seals
+    /// them, or else empty.
+    pub noise_static_pub_key: Vec<u8>,
+}
+
+impl TryFrom<helloworld::PubKeySharesResponse> for KeyShares {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::PubKeySharesResponse) -> Result<Self, ConvertError> {
+        Ok(Self {
+            buyer_output_pub_key_share: decode(&value.buyer_output_pub_key_share, "buyer_output_pub_key_share")?,
+            seller_output_pub_key_share: decode(&value.seller_output_pub_key_share, "seller_output_pub_key_share")?,
+            identity_pub_key: decode(&value.identity_pub_key, "identity_pub_key")?,
+            identity_signature: decode(&value.identity_signature, "identity_signature")?,
+            current_block_height: value.current_block_height,
+            my_peer_address: Some(value.my_peer_address).filter(|a| !a.is_empty()),
+            half_deposit_psbt: value.half_deposit_psbt,
+            trade_id: value.trade_id,
+            role: decode_role(value.my_role, "my_role")?,
+            noise_static_pub_key: value.noise_static_pub_key,
+        })
+    }
+}
+
+/// Our nonce shares for a trade, as handed out by `GetNonceShares` (or by `RevealNonceShares`, for a
+/// trade committing to its nonces), to pass on to the peer's [`GetPartialSignatures`] step.
+pub struct NonceShares {
+    /// The message as returned, which is passed on to the peer unchanged, as it is signed.
+    pub message: helloworld::NonceSharesMessage,
+    /// The nonce shares in the message, unless it is sealed or holds just our nonce commitments, to
+    /// pass on to the peer's [`RevealNonceShares`] step in its place.
+    pub nonce_shares: Option<ExchangedNonces<'static, ByVal>>,
+}
+
+impl TryFrom<helloworld::NonceSharesMessage> for NonceShares {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::NonceSharesMessage) -> Result<Self, ConvertError> {
+        let nonce_shares = if value.sealed_payload.is_some() || value.nonce_commitments.is_some() {
+            None
+        } else {
+            Some(value.clone().try_into()?)
+        };
+        Ok(Self { message: value, nonce_shares })
+    }
+}
+
+/// Our partial signatures for a trade, as handed out by `GetPartialSignatures`, to pass on to the
+/// peer's [`SignDepositTx`] step (redacted, for the buyer's) and, for the buyer's once it has
+/// started payment, [`SignSwapTx`].
+pub struct PartialSignatures {
+    /// The message as returned, which is passed on to the peer unchanged, as it is signed.
+    pub message: helloworld::PartialSignaturesMessage,
+    /// The partial signatures in the message, unless it is sealed. The buyer's partial signature on
+    /// the swap tx is redacted if the daemon withholds it, as it does for a direct peer exchange.
+    pub partial_signatures: Option<ExchangedSigs<'static, ByVal>>,
+}
+
+impl TryFrom<helloworld::PartialSignaturesMessage> for PartialSignatures {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::PartialSignaturesMessage) -> Result<Self, ConvertError> {
+        let partial_signatures = if value.sealed_payload.is_some() { None } else { Some(value.clone().try_into()?) };
+        Ok(Self { message: value, partial_signatures })
+    }
+}
+
+impl PartialSignatures {
+    /// The buyer's partial signatures with the (plain or sealed) partial signature on the swap tx
+    /// left out, to pass on to the seller before the buyer has started payment.
+    #[must_use]
+    pub fn redacted(&self) -> Self {
+        let mut message = self.message.clone();
+        message.redact_swap_tx_input_partial_signature();
+        Self {
+            message,
+            partial_signatures: self.partial_signatures.as_ref()
+                .map(|sigs| ExchangedSigs { swap_tx_input_partial_signature: Redactable::Redacted, ..sigs.by_ref().cloned() }),
+        }
+    }
+}
+
+/// Our private key share for the peer's output, as handed out by `SignSwapTx` or `CloseTrade`.
+#[derive(Debug)]
+pub enum PrvKeyShareForPeer {
+    Plain(Scalar),
+    /// Sealed to the peer, to be passed on as it is.
+    Sealed(Vec<u8>),
+    /// Delivered by the daemon to the peer's daemon directly, so there is nothing to pass on.
+    Delivered,
+}
+
+impl PrvKeyShareForPeer {
+    fn decode(plain: &[u8], sealed: Option<Vec<u8>>, field: &str) -> Result<Self, ConvertError> {
+        Ok(match sealed {
+            Some(sealed) => Self::Sealed(sealed),
+            None if plain.is_empty() => Self::Delivered,
+            None => Self::Plain(decode(plain, field)?),
+        })
+    }
+}
+
+/// The seller's signed swap tx and key share for the buyer's output, as handed out by `SignSwapTx`.
+#[derive(Debug)]
+pub struct SwapTxSignature {
+    pub swap_tx: LiftedSignature,
+    pub peer_output_prv_key_share: PrvKeyShareForPeer,
+}
+
+impl TryFrom<helloworld::SwapTxSignatureResponse> for SwapTxSignature {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::SwapTxSignatureResponse) -> Result<Self, ConvertError> {
+        Ok(Self {
+            swap_tx: decode(&value.swap_tx, "swap_tx")?,
+            peer_output_prv_key_share: PrvKeyShareForPeer::decode(&value.peer_output_prv_key_share,
+                value.sealed_peer_output_prv_key_share, "peer_output_prv_key_share")?,
+        })
+    }
+}
+
+impl TryFrom<helloworld::CloseTradeResponse> for PrvKeyShareForPeer {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::CloseTradeResponse) -> Result<Self, ConvertError> {
+        Self::decode(&value.peer_output_prv_key_share, value.sealed_peer_output_prv_key_share, "peer_output_prv_key_share")
+    }
+}
+
+/// The first step of a trade, generating our key shares.
+#[derive(Clone)]
+pub struct InitTrade(pub(crate) helloworld::PubKeySharesRequest);
+
+impl InitTrade {
+    pub fn new(trade_id: impl Into<String>, my_role: Role) -> Self {
+        Self(helloworld::PubKeySharesRequest {
+            trade_id: trade_id.into(),
+            my_role: helloworld::Role::from(my_role).into(),
+            ..Default::default()
+        })
+    }
+
+    /// Open the trade under an ID generated by the daemon, given in the [`KeyShares`] returned. This
+    /// cannot be combined with [`Self::funding_inputs`], whose ownership proofs are for the ID.
+    #[must_use]
+    pub fn with_generated_id(my_role: Role) -> Self {
+        Self::new(String::new(), my_role)
+    }
+
+    /// Seal every peer payload after the key shares, as both peers must agree to.
+    #[must_use]
+    pub const fn seal_peer_payloads(mut self) -> Self {
+        self.0.seal_peer_payloads = true;
+        self
+    }
+
+    /// Commit to the nonce shares before revealing them, as both peers must agree to, with the
+    /// extra [`RevealNonceShares`] step. This cannot yet be combined with [`Self::peer`].
+    #[must_use]
+    pub const fn commit_to_nonces(mut self) -> Self {
+        self.0.commit_to_nonces = true;
+        self
+    }
+
+    /// Exchange every peer payload after the key shares directly with the given peer's daemon. The
+    /// peer's payloads may then be left out of the later steps.
+    #[must_use]
+    pub fn peer(mut self, peer: PeerEndpoint) -> Self {
+        self.0.peer = Some(helloworld::PeerEndpoint { address: peer.address, trade_id: peer.trade_id });
+        self
+    }
+
+    /// Play the trade through as a dry run, with deterministic test keys and instant synthetic
+    /// confirmations, touching no real entropy, signer or chain. Never for real funds.
+    #[must_use]
+    pub const fn dry_run(mut self) -> Self {
+        self.0.dry_run = true;
+        self
+    }
+
+    /// Set our funding inputs to the deposit tx, each with its ownership proof for our ID for the
+    /// trade (see [`musig_trade_protocol::funding_input_ownership_message`]), to hand out to the peer.
+    #[must_use]
+    pub fn funding_inputs(mut self, funding_inputs: &[FundingInput]) -> Self {
+        self.0.funding_inputs = funding_inputs.iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set our coin control over the funding of our half of the deposit tx, which our funding
+    /// inputs (if any) must keep to.
+    #[must_use]
+    pub fn coin_control(mut self, coin_control: &CoinControl) -> Self {
+        self.0.coin_control = Some(coin_control.into());
+        self
+    }
+}
+
+/// The step taking in the peer's key shares and the trade terms, generating our nonce shares.
+#[derive(Clone)]
+pub struct GetNonceShares(pub(crate) helloworld::NonceSharesRequest);
+
+impl GetNonceShares {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::NonceSharesRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    #[must_use]
+    pub fn peers_key_shares(mut self, key_shares: &KeyShares) -> Self {
+        self.0.buyer_output_peers_pub_key_share = key_shares.buyer_output_pub_key_share.serialize().into();
+        self.0.seller_output_peers_pub_key_share = key_shares.seller_output_pub_key_share.serialize().into();
+        self.0.peers_identity_pub_key = key_shares.identity_pub_key.serialize().into();
+        self.0.peers_pub_key_shares_identity_signature = key_shares.identity_signature.serialize().into();
+        self.0.peers_half_deposit_psbt.clone_from(&key_shares.half_deposit_psbt);
+        self.0.peers_trade_id.clone_from(&key_shares.trade_id);
+        self.0.peers_role = helloworld::Role::from(key_shares.role).into();
+        self.0.peers_noise_static_pub_key.clone_from(&key_shares.noise_static_pub_key);
+        self
+    }
+
+    /// Set the fee rates (in sats per vbyte) of the deposit tx and of the prepared txs.
+    #[must_use]
+    pub const fn fee_rates(mut self, deposit_tx_fee_rate: f64, prepared_tx_fee_rate: f64) -> Self {
+        self.0.deposit_tx_fee_rate = deposit_tx_fee_rate;
+        self.0.prepared_tx_fee_rate = prepared_tx_fee_rate;
+        self
+    }
+
+    /// Set the trade amount and security deposits, in sats.
+    #[must_use]
+    pub const fn amounts(mut self, trade_amount: u64, buyers_security_deposit: u64, sellers_security_deposit: u64)
+        -> Self
+    {
+        self.0.trade_amount = trade_amount;
+        self.0.buyers_security_deposit = buyers_security_deposit;
+        self.0.sellers_security_deposit = sellers_security_deposit;
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step discarding the signing session of a trade whose deposit tx isn't signed yet, generating
+/// fresh nonce shares (or commitments) in place of those from [`GetNonceShares`].
+#[derive(Clone)]
+pub struct ResetSigningSession(pub(crate) helloworld::ResetSigningSessionRequest);
+
+impl ResetSigningSession {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::ResetSigningSessionRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    /// Set the renegotiated fee rates (in sats per vbyte) of the deposit tx and of the prepared txs.
+    #[must_use]
+    pub const fn fee_rates(mut self, deposit_tx_fee_rate: f64, prepared_tx_fee_rate: f64) -> Self {
+        self.0.deposit_tx_fee_rate = Some(deposit_tx_fee_rate);
+        self.0.prepared_tx_fee_rate = Some(prepared_tx_fee_rate);
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step taking in the peer's nonce commitments, revealing our nonce shares, for a trade
+/// committing to its nonces.
+#[derive(Clone)]
+pub struct RevealNonceShares(pub(crate) helloworld::RevealNonceSharesRequest);
+
+impl RevealNonceShares {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::RevealNonceSharesRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    /// Take the peer's nonce commitments from its `GetNonceShares` result.
+    #[must_use]
+    pub fn peers_nonce_commitments(mut self, nonce_shares: &NonceShares) -> Self {
+        self.0.peers_nonce_commitments.clone_from(&nonce_shares.message.nonce_commitments);
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step proposing a change of the prepared tx fee rate of a trade whose deposit tx is signed or,
+/// once the peer has accepted it, completing the change. Its result goes to the peer's
+/// [`AcceptFeeRateChange`] step.
+#[derive(Clone)]
+pub struct ProposeFeeRateChange(pub(crate) helloworld::FeeRateChangeRequest);
+
+impl ProposeFeeRateChange {
+    /// Propose the given prepared tx fee rate (in sats per vbyte).
+    pub fn new(trade_id: impl Into<String>, prepared_tx_fee_rate: f64) -> Self {
+        Self(helloworld::FeeRateChangeRequest {
+            trade_id: trade_id.into(),
+            prepared_tx_fee_rate: Some(prepared_tx_fee_rate),
+            ..Default::default()
+        })
+    }
+
+    /// Complete the change proposed, taking the peer's acceptance from its [`AcceptFeeRateChange`]
+    /// result.
+    #[must_use]
+    pub fn peers_acceptance(mut self, message: &helloworld::FeeRateChangeMessage) -> Self {
+        self.0.peers_message = Some(message.clone());
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step accepting the change of the prepared tx fee rate proposed by the peer or, given the
+/// peer's second [`ProposeFeeRateChange`] result, completing it.
+#[derive(Clone)]
+pub struct AcceptFeeRateChange(pub(crate) helloworld::FeeRateChangeRequest);
+
+impl AcceptFeeRateChange {
+    /// Take in the peer's [`ProposeFeeRateChange`] result, consenting to the given prepared tx fee
+    /// rate (in sats per vbyte), which must be the one proposed.
+    pub fn new(trade_id: impl Into<String>, prepared_tx_fee_rate: f64, peers_message: &helloworld::FeeRateChangeMessage) -> Self {
+        Self(helloworld::FeeRateChangeRequest {
+            trade_id: trade_id.into(),
+            prepared_tx_fee_rate: Some(prepared_tx_fee_rate),
+            peers_message: Some(peers_message.clone()),
+            expected_revision: None,
+        })
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step taking in the peer's nonce shares, generating our partial signatures.
+#[derive(Clone)]
+pub struct GetPartialSignatures(pub(crate) helloworld::PartialSignaturesRequest);
+
+impl GetPartialSignatures {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::PartialSignaturesRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    #[must_use]
+    pub fn peers_nonce_shares(mut self, nonce_shares: &NonceShares) -> Self {
+        self.0.peers_nonce_shares = Some(nonce_shares.message.clone());
+        self
+    }
+
+    /// Add a receiver of the trade amount (or part of it), by address and amount in sats.
+    #[must_use]
+    pub fn receiver(mut self, address: impl Into<String>, amount: u64) -> Self {
+        self.0.receivers.push(helloworld::ReceiverAddressAndAmount { address: address.into(), amount });
+        self
+    }
+
+    /// Add the mediator's signature on the receivers, required by a daemon with a mediator set.
+    #[must_use]
+    pub fn receivers_mediator_signature(mut self, signature: impl Into<Vec<u8>>) -> Self {
+        self.0.receivers_mediator_signature = signature.into();
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step taking in the peer's partial signatures, signing the deposit tx.
+#[derive(Clone)]
+pub struct SignDepositTx(pub(crate) helloworld::DepositTxSignatureRequest);
+
+impl SignDepositTx {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::DepositTxSignatureRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    /// Take in the peer's partial signatures. The seller should only be given the buyer's
+    /// [`redacted`](PartialSignatures::redacted) partial signatures, until the buyer has started payment.
+    #[must_use]
+    pub fn peers_partial_signatures(mut self, partial_signatures: &PartialSignatures) -> Self {
+        self.0.peers_partial_signatures = Some(partial_signatures.message.clone());
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step cancelling a trade whose deposit tx isn't published yet, discarding its signing session
+/// and archiving it, or (once the deposit tx is signed) just handing out our consent for the peer,
+/// until the peer's consent is in.
+#[derive(Clone)]
+pub struct CancelBeforeDeposit(pub(crate) helloworld::CancelBeforeDepositRequest);
+
+impl CancelBeforeDeposit {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::CancelBeforeDepositRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    /// Take the peer's consent to the cancellation from its `CancelBeforeDeposit` result, for a
+    /// mutual cancellation.
+    #[must_use]
+    pub fn peers_cancellation(mut self, response: &helloworld::CancelBeforeDepositResponse) -> Self {
+        self.0.peers_cancellation.clone_from(&response.my_cancellation);
+        self
+    }
+
+    /// Cancel without the peer's consent, even though the deposit tx is signed, as our funding
+    /// inputs have been spent elsewhere, so that it can never be published.
+    #[must_use]
+    pub const fn my_funding_inputs_spent(mut self) -> Self {
+        self.0.my_funding_inputs_spent = true;
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The step publishing the deposit tx, from the combined deposit PSBT.
+#[derive(Clone)]
+pub struct PublishDepositTx(pub(crate) helloworld::PublishDepositTxRequest);
+
+impl PublishDepositTx {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::PublishDepositTxRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    #[must_use]
+    pub fn deposit_psbt(mut self, deposit_psbt: Vec<u8>) -> Self {
+        self.0.deposit_psbt = Some(helloworld::DepositPsbt { deposit_psbt });
+        self
+    }
+
+    /// Follow the deposit tx to the given number of confirmations (rather than just one) before the
+    /// stream of its confirmations ends.
+    #[must_use]
+    pub const fn confirmations(mut self, confirmations: u32) -> Self {
+        self.0.confirmations = Some(confirmations);
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The seller's step taking in the buyer's partial signature on the swap tx, signing the swap tx.
+#[derive(Clone)]
+pub struct SignSwapTx(pub(crate) helloworld::SwapTxSignatureRequest);
+
+impl SignSwapTx {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::SwapTxSignatureRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    /// Take in the buyer's (plain or sealed) partial signature on the swap tx, from its unredacted
+    /// partial signatures.
+    #[must_use]
+    pub fn peers_partial_signatures(mut self, partial_signatures: &PartialSignatures) -> Self {
+        let message = &partial_signatures.message;
+        match &message.swap_tx_input {
+            Some(SwapTxInput::SwapTxInputPartialSignature(sig)) => self.0.swap_tx_input_peers_partial_signature.clone_from(sig),
+            Some(SwapTxInput::SealedSwapTxInputPartialSignature(sealed)) =>
+                self.0.sealed_swap_tx_input_peers_partial_signature = Some(sealed.clone()),
+            Some(SwapTxInput::SwapTxInputPartialSignatureRedacted(_)) | None => {}
+        }
+        self.0.swap_tx_input_peers_identity_signature = message.swap_tx_input_identity_signature.clone().unwrap_or_default();
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The buyer's step proposing to re-sign the swap tx at a higher fee rate, once payment is started,
+/// or, once the seller has accepted, completing the bump. Its result goes to the seller's
+/// [`AcceptSwapTxFeeBump`] step.
+#[derive(Clone)]
+pub struct ProposeSwapTxFeeBump(pub(crate) helloworld::SwapTxFeeBumpRequest);
+
+impl ProposeSwapTxFeeBump {
+    /// Propose the given swap tx fee rate (in sats per vbyte).
+    pub fn new(trade_id: impl Into<String>, swap_tx_fee_rate: f64) -> Self {
+        Self(helloworld::SwapTxFeeBumpRequest {
+            trade_id: trade_id.into(),
+            swap_tx_fee_rate: Some(swap_tx_fee_rate),
+            ..Default::default()
+        })
+    }
+
+    /// Complete the bump proposed, taking the seller's acceptance from its [`AcceptSwapTxFeeBump`]
+    /// result.
+    #[must_use]
+    pub fn peers_acceptance(mut self, message: &helloworld::SwapTxFeeBumpMessage) -> Self {
+        self.0.peers_message = Some(message.clone());
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The seller's step accepting the buyer's proposal to re-sign the swap tx at a higher fee rate or,
+/// given the buyer's second [`ProposeSwapTxFeeBump`] result, completing it.
+#[derive(Clone)]
+pub struct AcceptSwapTxFeeBump(pub(crate) helloworld::SwapTxFeeBumpRequest);
+
+impl AcceptSwapTxFeeBump {
+    /// Take in the buyer's [`ProposeSwapTxFeeBump`] result, consenting to the given swap tx fee rate
+    /// (in sats per vbyte), which must be the one proposed.
+    pub fn new(trade_id: impl Into<String>, swap_tx_fee_rate: f64, peers_message: &helloworld::SwapTxFeeBumpMessage) -> Self {
+        Self(helloworld::SwapTxFeeBumpRequest {
+            trade_id: trade_id.into(),
+            swap_tx_fee_rate: Some(swap_tx_fee_rate),
+            peers_message: Some(peers_message.clone()),
+            expected_revision: None,
+        })
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
+
+/// The last step of a trade, taking in the peer's key share for our output (to close the trade
+/// cooperatively) or, for the buyer only, the seller's signed swap tx.
+#[derive(Clone)]
+pub struct CloseTrade(pub(crate) helloworld::CloseTradeRequest);
+
+impl CloseTrade {
+    pub fn new(trade_id: impl Into<String>) -> Self {
+        Self(helloworld::CloseTradeRequest { trade_id: trade_id.into(), ..Default::default() })
+    }
+
+    #[must_use]
+    pub fn peers_prv_key_share(mut self, prv_key_share: &PrvKeyShareForPeer) -> Self {
+        match prv_key_share {
+            PrvKeyShareForPeer::Plain(key) => self.0.my_output_peers_prv_key_share = Some(key.serialize().into()),
+            PrvKeyShareForPeer::Sealed(sealed) => self.0.sealed_my_output_peers_prv_key_share = Some(sealed.clone()),
+            PrvKeyShareForPeer::Delivered => {}
+        }
+        self
+    }
+
+    #[must_use]
+    pub fn swap_tx(mut self, swap_tx: LiftedSignature) -> Self {
+        self.0.swap_tx = Some(swap_tx.serialize().into());
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_revision(mut self, revision: u64) -> Self {
+        self.0.expected_revision = Some(revision);
+        self
+    }
+}
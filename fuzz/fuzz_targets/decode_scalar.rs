@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use musig_proto::convert::decode;
+use secp::Scalar;
+use tonic::{Code, Status};
+
+// Any bytes a peer may send must either decode or be rejected as an invalid argument, never panic:
+fuzz_target!(|bytes: &[u8]| {
+    if let Err(e) = decode::<Scalar>(bytes, "field") {
+        assert_eq!(Status::from(e).code(), Code::InvalidArgument);
+    }
+});
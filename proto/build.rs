@@ -0,0 +1,29 @@
+use std::env;
+use std::path::PathBuf;
+use std::prelude::rust_2021::*;
+
+const PROTO_DIR: &str = "../src/main/proto";
+
+/// The messages holding private keys, which get hand-written Debug impls redacting them instead.
+const SECRET_MESSAGES: &[&str] = &[
+    ".helloworld.SwapTxSignatureResponse",
+    ".helloworld.CloseTradeRequest",
+    ".helloworld.CloseTradeResponse",
+    ".signer.RevealKeyShareResponse",
+    ".peer.PrvKeyShare",
+];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The proto files are kept where the Maven build of the Java client expects to find them:
+    let mut protos = vec![format!("{}/helloworld.proto", PROTO_DIR), format!("{}/signer.proto", PROTO_DIR),
+        format!("{}/peer.proto", PROTO_DIR), format!("{}/health.proto", PROTO_DIR), format!("{}/admin.proto", PROTO_DIR)];
+    if env::var_os("CARGO_FEATURE_DEMO").is_some() {
+        protos.push(format!("{}/greeter.proto", PROTO_DIR));
+    }
+    // The descriptors of the messages, for the JSON gateway to transcode them by:
+    let descriptor_path = PathBuf::from(env::var_os("OUT_DIR").ok_or("OUT_DIR not set")?).join("musig_descriptor.bin");
+    let builder = SECRET_MESSAGES.iter()
+        .fold(tonic_build::configure().file_descriptor_set_path(descriptor_path), tonic_build::Builder::skip_debug);
+    builder.compile_protos(&protos, &[PROTO_DIR])?;
+    Ok(())
+}
@@ -0,0 +1,917 @@
+//! Conversions between the gRPC messages of the `MuSig` & signer services and the protocol types,
+//! decoding each field of an incoming message with an error naming its path within the request.
+
+use musig2::{AggNonce, CompactSignature, KeyAggContext, LiftedSignature, PubNonce};
+use prost::Message as _;
+use secp::{MaybePoint, MaybeScalar, Point, Scalar};
+use std::prelude::rust_2021::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tonic::Status;
+
+use crate::helloworld;
+use crate::helloworld::partial_signatures_message::SwapTxInput;
+use musig_trade_protocol::{AuditEntry, Cancellation, ChangePolicy, CoinControl, ExchangedNonceCommitments, ExchangedNonces, ExchangedPreparedTxNonces, ExchangedSigs, FeeStrategy, FundingInput, KeyTranscript, PayloadKind, PaymentMilestone,
+    PaymentReceipt, PeerEndpoint, PreparedTxPackage, Role, SignedTxInput, SigTranscript, SwapTxSignatureState, TradePhase, TradeSummary, TradeTranscript};
+use musig_trade_protocol::status::{self, FieldViolation};
+use musig_trade_protocol::storage::{ByRef, ByVal, Redactable};
+
+pub use musig_trade_protocol::status::{bad_request, error_info, BadRequest, ErrorInfo};
+
+type Result<T, E = ConvertError> = std::result::Result<T, E>;
+
+#[derive(Error, Debug)]
+pub enum ConvertError {
+    #[error("could not decode {field}: malformed {expected}: {detail}")]
+    Malformed { field: String, expected: &'static str, detail: String },
+    #[error("could not decode {field}: unknown enum value: {value}")]
+    UnknownEnumValue { field: String, value: i32 },
+}
+
+impl ConvertError {
+    /// Prefix the path of the offending field with that of the message field it was nested in.
+    #[must_use]
+    pub fn in_field(mut self, parent: &str) -> Self {
+        let field = self.field_mut();
+        *field = format!("{}.{}", parent, field);
+        self
+    }
+
+    fn field_mut(&mut self) -> &mut String {
+        let (Self::Malformed { field, .. } | Self::UnknownEnumValue { field, .. }) = self;
+        field
+    }
+
+    /// What was wrong with the offending field, without its path.
+    fn description(&self) -> String {
+        match self {
+            Self::Malformed { expected, detail, .. } => format!("malformed {}: {}", expected, detail),
+            Self::UnknownEnumValue { value, .. } => format!("unknown enum value: {}", value),
+        }
+    }
+}
+
+/// The status of a failure to decode a request, with a `google.rpc.BadRequest` in its details giving
+/// the proto path of the offending field, as well as in its message.
+impl From<ConvertError> for Status {
+    fn from(mut value: ConvertError) -> Self {
+        let code = match value {
+            ConvertError::Malformed { .. } => tonic::Code::InvalidArgument,
+            ConvertError::UnknownEnumValue { .. } => tonic::Code::OutOfRange,
+        };
+        let violation = FieldViolation { field: proto_field_path(value.field_mut()), description: value.description() };
+        status::with_bad_request(code, value.to_string(), violation)
+    }
+}
+
+/// The path of the given (Rust) field in the proto, whose field names are in lower camel case.
+#[must_use]
+pub fn proto_field_path(field: &str) -> String {
+    let mut path = String::with_capacity(field.len());
+    let mut upper = false;
+    for c in field.chars() {
+        match c {
+            '_' => upper = true,
+            '.' | '[' => {
+                upper = false;
+                path.push(c);
+            }
+            c if upper => {
+                upper = false;
+                path.push(c.to_ascii_uppercase());
+            }
+            c => path.push(c),
+        }
+    }
+    path
+}
+
+/// A protocol type held in a `bytes` field of a proto message, in its usual serialized form.
+pub trait FromBytes: for<'a> TryFrom<&'a [u8]> {
+    const DESCRIPTION: &'static str;
+    /// The serialized form expected, including its length.
+    const FORMAT: &'static str;
+}
+
+impl FromBytes for Point {
+    const DESCRIPTION: &'static str = "point";
+    const FORMAT: &'static str = "a 33-byte compressed point";
+}
+
+impl FromBytes for MaybePoint {
+    const DESCRIPTION: &'static str = "point";
+    const FORMAT: &'static str = "a 33-byte compressed point, or 33 zero bytes for the point at infinity";
+}
+
+impl FromBytes for PubNonce {
+    const DESCRIPTION: &'static str = "pub nonce";
+    const FORMAT: &'static str = "a 66-byte pair of compressed points";
+}
+
+impl FromBytes for AggNonce {
+    const DESCRIPTION: &'static str = "aggregated nonce";
+    const FORMAT: &'static str = "a 66-byte pair of compressed points, either of which may be zero for infinity";
+}
+
+impl FromBytes for KeyAggContext {
+    const DESCRIPTION: &'static str = "key aggregation context";
+    const FORMAT: &'static str = "a key aggregation context as serialized by the musig2 crate";
+}
+
+impl FromBytes for Scalar {
+    const DESCRIPTION: &'static str = "scalar";
+    const FORMAT: &'static str = "a 32-byte big-endian nonzero scalar below the curve order";
+}
+
+impl FromBytes for MaybeScalar {
+    const DESCRIPTION: &'static str = "scalar";
+    const FORMAT: &'static str = "a 32-byte big-endian scalar below the curve order";
+}
+
+impl FromBytes for LiftedSignature {
+    const DESCRIPTION: &'static str = "signature";
+    const FORMAT: &'static str = "a 64-byte BIP 340 signature";
+}
+
+impl FromBytes for CompactSignature {
+    const DESCRIPTION: &'static str = "signature";
+    const FORMAT: &'static str = "a 64-byte BIP 340 signature";
+}
+
+impl FromBytes for [u8; 32] {
+    const DESCRIPTION: &'static str = "hash";
+    const FORMAT: &'static str = "32 bytes";
+}
+
+/// Decode a protocol type from the given field.
+///
+/// # Errors
+///
+/// Returns [`ConvertError::Malformed`] if the bytes are not a valid serialization of the type.
+pub fn decode<T: FromBytes>(bytes: &[u8], field: &str) -> Result<T> {
+    T::try_from(bytes).map_err(|_| ConvertError::Malformed {
+        field: field.to_owned(),
+        expected: T::DESCRIPTION,
+        detail: format!("expected {}, got {} bytes", T::FORMAT, bytes.len()),
+    })
+}
+
+/// Decode a protocol type from the given optional field, if present.
+///
+/// # Errors
+///
+/// Returns [`ConvertError::Malformed`] if the bytes are not a valid serialization of the type.
+pub fn decode_opt<T: FromBytes>(bytes: Option<&[u8]>, field: &str) -> Result<Option<T>> {
+    bytes.map(|bytes| decode(bytes, field)).transpose()
+}
+
+/// Decode a duration from the given optional well-known type field, if present.
+///
+/// # Errors
+///
+/// Returns [`ConvertError::Malformed`] if the duration is negative.
+#[cfg(feature = "demo")]
+pub fn decode_duration(value: Option<prost_types::Duration>, field: &str) -> Result<Option<std::time::Duration>> {
+    value.map(|value| std::time::Duration::try_from(value).map_err(|_| ConvertError::Malformed {
+        field: field.to_owned(),
+        expected: "duration",
+        detail: "expected a nonnegative duration".to_owned(),
+    })).transpose()
+}
+
+/// Decode a trader role from the given enum field.
+///
+/// # Errors
+///
+/// Returns [`ConvertError::UnknownEnumValue`] if the value is not that of a known role.
+pub fn decode_role(value: i32, field: &str) -> Result<Role> {
+    helloworld::Role::try_from(value)
+        .map_err(|_| ConvertError::UnknownEnumValue { field: field.to_owned(), value })
+        .map(Into::into)
+}
+
+impl TryFrom<&helloworld::FundingInput> for FundingInput {
+    type Error = ConvertError;
+
+    fn try_from(value: &helloworld::FundingInput) -> Result<Self> {
+        Ok(Self {
+            txid: decode(&value.txid, "txid")?,
+            vout: value.vout,
+            amount: value.amount,
+            owner_pub_key: decode(&value.owner_pub_key, "owner_pub_key")?,
+            ownership_proof: decode(&value.ownership_proof, "ownership_proof")?,
+        })
+    }
+}
+
+impl From<&FundingInput> for helloworld::FundingInput {
+    fn from(value: &FundingInput) -> Self {
+        Self {
+            txid: value.txid.into(),
+            vout: value.vout,
+            amount: value.amount,
+            owner_pub_key: value.owner_pub_key.serialize().into(),
+            ownership_proof: value.ownership_proof.serialize().into(),
+        }
+    }
+}
+
+/// Decode the funding inputs of the given repeated field.
+///
+/// # Errors
+///
+/// Returns [`ConvertError::Malformed`] if any of the inputs has a malformed field.
+pub fn decode_funding_inputs(inputs: &[helloworld::FundingInput], field: &str) -> Result<Vec<FundingInput>> {
+    inputs.iter().enumerate()
+        .map(|(i, input)| input.try_into().map_err(|e: ConvertError| e.in_field(&format!("{}[{}]", field, i))))
+        .collect()
+}
+
+/// Decode the coin control of the given field.
+///
+/// # Errors
+///
+/// Returns [`ConvertError::Malformed`] if an outpoint has a malformed txid, or a change address is
+/// given for other than `CHANGE_TO_ADDRESS` (or missing for it), and
+/// [`ConvertError::UnknownEnumValue`] for an unknown fee strategy or change policy.
+pub fn decode_coin_control(value: &helloworld::CoinControl, field: &str) -> Result<CoinControl> {
+    let outpoints = |outpoints: &[helloworld::Outpoint], outpoints_field: &str| outpoints.iter().enumerate()
+        .map(|(i, outpoint)| Ok((decode(&outpoint.txid, &format!("{}.{}[{}].txid", field, outpoints_field, i))?, outpoint.vout)))
+        .collect::<Result<_>>();
+    let fee_strategy = match helloworld::FeeStrategy::try_from(value.fee_strategy) {
+        Ok(helloworld::FeeStrategy::AvoidChange) => FeeStrategy::AvoidChange,
+        Ok(helloworld::FeeStrategy::LowestFee) => FeeStrategy::LowestFee,
+        Ok(helloworld::FeeStrategy::Consolidate) => FeeStrategy::Consolidate,
+        Err(_) => return Err(ConvertError::UnknownEnumValue { field: format!("{}.fee_strategy", field), value: value.fee_strategy }),
+    };
+    let change_address_error = |detail: &str| ConvertError::Malformed {
+        field: format!("{}.change_address", field),
+        expected: "change address",
+        detail: detail.to_owned(),
+    };
+    let change_policy = match (helloworld::ChangePolicy::try_from(value.change_policy), &value.change_address[..]) {
+        (Ok(helloworld::ChangePolicy::ChangeToAddress), "") => return Err(change_address_error("expected an address")),
+        (Ok(helloworld::ChangePolicy::ChangeToAddress), address) => ChangePolicy::ToAddress(address.to_owned()),
+        (Ok(_), address) if !address.is_empty() => return Err(change_address_error("only given for CHANGE_TO_ADDRESS")),
+        (Ok(helloworld::ChangePolicy::ChangeToNewAddress), _) => ChangePolicy::NewAddress,
+        (Ok(helloworld::ChangePolicy::NoChange), _) => ChangePolicy::NoChange,
+        (Err(_), _) => return Err(ConvertError::UnknownEnumValue { field: format!("{}.change_policy", field), value: value.change_policy }),
+    };
+    Ok(CoinControl {
+        use_outpoints: outpoints(&value.use_outpoints, "use_outpoints")?,
+        avoid_outpoints: outpoints(&value.avoid_outpoints, "avoid_outpoints")?,
+        fee_strategy,
+        change_policy,
+    })
+}
+
+impl From<&CoinControl> for helloworld::CoinControl {
+    fn from(value: &CoinControl) -> Self {
+        let outpoints = |outpoints: &[([u8; 32], u32)]| outpoints.iter()
+            .map(|&(txid, vout)| helloworld::Outpoint { txid: txid.into(), vout })
+            .collect();
+        let (change_policy, change_address) = match &value.change_policy {
+            ChangePolicy::NewAddress => (helloworld::ChangePolicy::ChangeToNewAddress, String::new()),
+            ChangePolicy::ToAddress(address) => (helloworld::ChangePolicy::ChangeToAddress, address.clone()),
+            ChangePolicy::NoChange => (helloworld::ChangePolicy::NoChange, String::new()),
+        };
+        Self {
+            use_outpoints: outpoints(&value.use_outpoints),
+            avoid_outpoints: outpoints(&value.avoid_outpoints),
+            fee_strategy: match value.fee_strategy {
+                FeeStrategy::AvoidChange => helloworld::FeeStrategy::AvoidChange,
+                FeeStrategy::LowestFee => helloworld::FeeStrategy::LowestFee,
+                FeeStrategy::Consolidate => helloworld::FeeStrategy::Consolidate,
+            }.into(),
+            change_policy: change_policy.into(),
+            change_address,
+        }
+    }
+}
+
+/// The magic bytes which every PSBT starts with, as per BIP 174.
+pub const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// Our half of the deposit tx with the given funding inputs, as a (stand-in) PSBT, or nothing if
+/// there are none.
+#[must_use]
+pub fn encode_half_deposit_psbt(funding_inputs: &[FundingInput]) -> Vec<u8> {
+    if funding_inputs.is_empty() {
+        return vec![];
+    }
+    let psbt = helloworld::HalfDepositPsbt { funding_inputs: funding_inputs.iter().map(Into::into).collect() };
+    [PSBT_MAGIC, &psbt.encode_to_vec()].concat()
+}
+
+/// Decode the funding inputs of a half of the deposit tx given as a (stand-in) PSBT, or none if
+/// it is empty.
+///
+/// # Errors
+///
+/// Returns [`ConvertError::Malformed`] if the bytes are not a PSBT, or any of its funding inputs
+/// has a malformed field.
+pub fn decode_half_deposit_psbt(bytes: &[u8], field: &str) -> Result<Vec<FundingInput>> {
+    if bytes.is_empty() {
+        return Ok(vec![]);
+    }
+    let malformed = |detail: &str| ConvertError::Malformed { field: field.to_owned(), expected: "PSBT", detail: detail.to_owned() };
+    let psbt = bytes.strip_prefix(PSBT_MAGIC).ok_or_else(|| malformed("expected the BIP 174 magic bytes first"))?;
+    let psbt = helloworld::HalfDepositPsbt::decode(psbt).map_err(|_| malformed("could not decode its funding inputs"))?;
+    decode_funding_inputs(&psbt.funding_inputs, "funding_inputs").map_err(|e| e.in_field(field))
+}
+
+impl TryFrom<helloworld::NonceSharesMessage> for ExchangedNonces<'_, ByVal> {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::NonceSharesMessage) -> Result<Self> {
+        Ok(Self {
+            swap_tx_input_nonce_share:
+            decode(&value.swap_tx_input_nonce_share, "swap_tx_input_nonce_share")?,
+            buyers_warning_tx_buyer_input_nonce_share:
+            decode(&value.buyers_warning_tx_buyer_input_nonce_share, "buyers_warning_tx_buyer_input_nonce_share")?,
+            buyers_warning_tx_seller_input_nonce_share:
+            decode(&value.buyers_warning_tx_seller_input_nonce_share, "buyers_warning_tx_seller_input_nonce_share")?,
+            sellers_warning_tx_buyer_input_nonce_share:
+            decode(&value.sellers_warning_tx_buyer_input_nonce_share, "sellers_warning_tx_buyer_input_nonce_share")?,
+            sellers_warning_tx_seller_input_nonce_share:
+            decode(&value.sellers_warning_tx_seller_input_nonce_share, "sellers_warning_tx_seller_input_nonce_share")?,
+            buyers_redirect_tx_input_nonce_share:
+            decode(&value.buyers_redirect_tx_input_nonce_share, "buyers_redirect_tx_input_nonce_share")?,
+            sellers_redirect_tx_input_nonce_share:
+            decode(&value.sellers_redirect_tx_input_nonce_share, "sellers_redirect_tx_input_nonce_share")?,
+        })
+    }
+}
+
+/// Fill in just the nonce shares of the message, leaving the remaining fields to the caller.
+impl From<ExchangedNonces<'_, ByRef>> for helloworld::NonceSharesMessage {
+    fn from(value: ExchangedNonces<'_, ByRef>) -> Self {
+        Self {
+            swap_tx_input_nonce_share:
+            value.swap_tx_input_nonce_share.serialize().into(),
+            buyers_warning_tx_buyer_input_nonce_share:
+            value.buyers_warning_tx_buyer_input_nonce_share.serialize().into(),
+            buyers_warning_tx_seller_input_nonce_share:
+            value.buyers_warning_tx_seller_input_nonce_share.serialize().into(),
+            sellers_warning_tx_buyer_input_nonce_share:
+            value.sellers_warning_tx_buyer_input_nonce_share.serialize().into(),
+            sellers_warning_tx_seller_input_nonce_share:
+            value.sellers_warning_tx_seller_input_nonce_share.serialize().into(),
+            buyers_redirect_tx_input_nonce_share:
+            value.buyers_redirect_tx_input_nonce_share.serialize().into(),
+            sellers_redirect_tx_input_nonce_share:
+            value.sellers_redirect_tx_input_nonce_share.serialize().into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl TryFrom<helloworld::NonceCommitmentsMessage> for ExchangedNonceCommitments<'_, ByVal> {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::NonceCommitmentsMessage) -> Result<Self> {
+        Ok(Self {
+            swap_tx_input_nonce_commitment:
+            decode(&value.swap_tx_input_nonce_commitment, "swap_tx_input_nonce_commitment")?,
+            buyers_warning_tx_buyer_input_nonce_commitment:
+            decode(&value.buyers_warning_tx_buyer_input_nonce_commitment, "buyers_warning_tx_buyer_input_nonce_commitment")?,
+            buyers_warning_tx_seller_input_nonce_commitment:
+            decode(&value.buyers_warning_tx_seller_input_nonce_commitment, "buyers_warning_tx_seller_input_nonce_commitment")?,
+            sellers_warning_tx_buyer_input_nonce_commitment:
+            decode(&value.sellers_warning_tx_buyer_input_nonce_commitment, "sellers_warning_tx_buyer_input_nonce_commitment")?,
+            sellers_warning_tx_seller_input_nonce_commitment:
+            decode(&value.sellers_warning_tx_seller_input_nonce_commitment, "sellers_warning_tx_seller_input_nonce_commitment")?,
+            buyers_redirect_tx_input_nonce_commitment:
+            decode(&value.buyers_redirect_tx_input_nonce_commitment, "buyers_redirect_tx_input_nonce_commitment")?,
+            sellers_redirect_tx_input_nonce_commitment:
+            decode(&value.sellers_redirect_tx_input_nonce_commitment, "sellers_redirect_tx_input_nonce_commitment")?,
+        })
+    }
+}
+
+/// Fill in just the nonce commitments of the message, leaving the identity signature to the caller.
+impl From<ExchangedNonceCommitments<'_, ByVal>> for helloworld::NonceCommitmentsMessage {
+    fn from(value: ExchangedNonceCommitments<'_, ByVal>) -> Self {
+        Self {
+            swap_tx_input_nonce_commitment: value.swap_tx_input_nonce_commitment.into(),
+            buyers_warning_tx_buyer_input_nonce_commitment: value.buyers_warning_tx_buyer_input_nonce_commitment.into(),
+            buyers_warning_tx_seller_input_nonce_commitment: value.buyers_warning_tx_seller_input_nonce_commitment.into(),
+            sellers_warning_tx_buyer_input_nonce_commitment: value.sellers_warning_tx_buyer_input_nonce_commitment.into(),
+            sellers_warning_tx_seller_input_nonce_commitment: value.sellers_warning_tx_seller_input_nonce_commitment.into(),
+            buyers_redirect_tx_input_nonce_commitment: value.buyers_redirect_tx_input_nonce_commitment.into(),
+            sellers_redirect_tx_input_nonce_commitment: value.sellers_redirect_tx_input_nonce_commitment.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl TryFrom<&helloworld::FeeRateChangeMessage> for ExchangedPreparedTxNonces<'_, ByVal> {
+    type Error = ConvertError;
+
+    fn try_from(value: &helloworld::FeeRateChangeMessage) -> Result<Self> {
+        Ok(Self {
+            buyers_warning_tx_buyer_input_nonce_share:
+            decode(&value.buyers_warning_tx_buyer_input_nonce_share, "buyers_warning_tx_buyer_input_nonce_share")?,
+            buyers_warning_tx_seller_input_nonce_share:
+            decode(&value.buyers_warning_tx_seller_input_nonce_share, "buyers_warning_tx_seller_input_nonce_share")?,
+            sellers_warning_tx_buyer_input_nonce_share:
+            decode(&value.sellers_warning_tx_buyer_input_nonce_share, "sellers_warning_tx_buyer_input_nonce_share")?,
+            sellers_warning_tx_seller_input_nonce_share:
+            decode(&value.sellers_warning_tx_seller_input_nonce_share, "sellers_warning_tx_seller_input_nonce_share")?,
+            buyers_redirect_tx_input_nonce_share:
+            decode(&value.buyers_redirect_tx_input_nonce_share, "buyers_redirect_tx_input_nonce_share")?,
+            sellers_redirect_tx_input_nonce_share:
+            decode(&value.sellers_redirect_tx_input_nonce_share, "sellers_redirect_tx_input_nonce_share")?,
+        })
+    }
+}
+
+/// Fill in just the nonce shares of the message, leaving the remaining fields to the caller.
+impl From<ExchangedPreparedTxNonces<'_, ByRef>> for helloworld::FeeRateChangeMessage {
+    fn from(value: ExchangedPreparedTxNonces<'_, ByRef>) -> Self {
+        Self {
+            buyers_warning_tx_buyer_input_nonce_share:
+            value.buyers_warning_tx_buyer_input_nonce_share.serialize().into(),
+            buyers_warning_tx_seller_input_nonce_share:
+            value.buyers_warning_tx_seller_input_nonce_share.serialize().into(),
+            sellers_warning_tx_buyer_input_nonce_share:
+            value.sellers_warning_tx_buyer_input_nonce_share.serialize().into(),
+            sellers_warning_tx_seller_input_nonce_share:
+            value.sellers_warning_tx_seller_input_nonce_share.serialize().into(),
+            buyers_redirect_tx_input_nonce_share:
+            value.buyers_redirect_tx_input_nonce_share.serialize().into(),
+            sellers_redirect_tx_input_nonce_share:
+            value.sellers_redirect_tx_input_nonce_share.serialize().into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The partial signatures of the fee rate change message, if it holds them, which it must either
+/// all or none of.
+///
+/// # Errors
+///
+/// Fails if any of the partial signatures are malformed, or only some of them are present.
+pub fn fee_rate_change_partial_signatures(value: &helloworld::FeeRateChangeMessage)
+    -> Result<Option<ExchangedSigs<'static, ByVal>>>
+{
+    let sigs = (
+        decode_opt(value.peers_warning_tx_buyer_input_partial_signature.as_deref(), "peers_warning_tx_buyer_input_partial_signature")?,
+        decode_opt(value.peers_warning_tx_seller_input_partial_signature.as_deref(), "peers_warning_tx_seller_input_partial_signature")?,
+        decode_opt(value.peers_redirect_tx_input_partial_signature.as_deref(), "peers_redirect_tx_input_partial_signature")?,
+    );
+    match sigs {
+        (None, None, None) => Ok(None),
+        (Some(warning_tx_buyer_input), Some(warning_tx_seller_input), Some(redirect_tx_input)) => Ok(Some(ExchangedSigs {
+            peers_warning_tx_buyer_input_partial_signature: warning_tx_buyer_input,
+            peers_warning_tx_seller_input_partial_signature: warning_tx_seller_input,
+            peers_redirect_tx_input_partial_signature: redirect_tx_input,
+            swap_tx_input_partial_signature: Redactable::Omitted,
+        })),
+        _ => Err(ConvertError::Malformed { field: "peers_redirect_tx_input_partial_signature".to_owned(),
+            expected: "complete set of partial signatures",
+            detail: "expected all three of the prepared tx partial signatures, or none".to_owned() }),
+    }
+}
+
+/// The fields of the swap tx fee bump message signed by its identity signature: the given encoding
+/// of its fee rate, followed by the rest in field number order.
+#[must_use]
+pub fn swap_tx_fee_bump_signed_fields<'a>(value: &'a helloworld::SwapTxFeeBumpMessage, fee_rate: &'a [u8; 8]) -> Vec<&'a [u8]> {
+    let mut fields = vec![&fee_rate[..], &value.swap_tx_input_nonce_share];
+    fields.extend(value.swap_tx_input_partial_signature.as_deref());
+    fields
+}
+
+/// The fields of the fee rate change message signed by its identity signature: the given encoding
+/// of its fee rate (which isn't held as bytes), followed by the rest in field number order.
+#[must_use]
+pub fn fee_rate_change_signed_fields<'a>(value: &'a helloworld::FeeRateChangeMessage, fee_rate: &'a [u8; 8]) -> Vec<&'a [u8]> {
+    let mut fields = vec![
+        &fee_rate[..],
+        &value.buyers_warning_tx_buyer_input_nonce_share,
+        &value.buyers_warning_tx_seller_input_nonce_share,
+        &value.sellers_warning_tx_buyer_input_nonce_share,
+        &value.sellers_warning_tx_seller_input_nonce_share,
+        &value.buyers_redirect_tx_input_nonce_share,
+        &value.sellers_redirect_tx_input_nonce_share,
+    ];
+    fields.extend([
+        &value.peers_warning_tx_buyer_input_partial_signature,
+        &value.peers_warning_tx_seller_input_partial_signature,
+        &value.peers_redirect_tx_input_partial_signature,
+    ].into_iter().flatten().map(Vec::as_slice));
+    fields
+}
+
+impl TryFrom<helloworld::PartialSignaturesMessage> for ExchangedSigs<'_, ByVal> {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::PartialSignaturesMessage) -> Result<Self> {
+        Ok(Self {
+            peers_warning_tx_buyer_input_partial_signature:
+            decode(&value.peers_warning_tx_buyer_input_partial_signature, "peers_warning_tx_buyer_input_partial_signature")?,
+            peers_warning_tx_seller_input_partial_signature:
+            decode(&value.peers_warning_tx_seller_input_partial_signature, "peers_warning_tx_seller_input_partial_signature")?,
+            peers_redirect_tx_input_partial_signature:
+            decode(&value.peers_redirect_tx_input_partial_signature, "peers_redirect_tx_input_partial_signature")?,
+            swap_tx_input_partial_signature: match &value.swap_tx_input {
+                Some(SwapTxInput::SwapTxInputPartialSignature(sig)) =>
+                    Redactable::Value(decode(sig, "swap_tx_input_partial_signature")?),
+                Some(SwapTxInput::SwapTxInputPartialSignatureRedacted(_)) => Redactable::Redacted,
+                Some(SwapTxInput::SealedSwapTxInputPartialSignature(_)) => return Err(ConvertError::Malformed {
+                    field: "sealed_swap_tx_input_partial_signature".to_owned(), expected: "unsealed partial signature",
+                    detail: "expected a plain or redacted partial signature, got a sealed one".to_owned() }),
+                None => return Err(ConvertError::Malformed {
+                    field: "swap_tx_input_partial_signature".to_owned(), expected: "partial signature or redaction",
+                    detail: "expected one of the swap_tx_input fields to be set, got none".to_owned() }),
+            },
+        })
+    }
+}
+
+/// Fill in just the partial signatures of the message, leaving the identity signatures to the caller.
+impl From<helloworld::PeerEndpoint> for PeerEndpoint {
+    fn from(value: helloworld::PeerEndpoint) -> Self {
+        Self { address: value.address, trade_id: value.trade_id }
+    }
+}
+
+impl From<ExchangedSigs<'_, ByRef>> for helloworld::PartialSignaturesMessage {
+    fn from(value: ExchangedSigs<'_, ByRef>) -> Self {
+        Self {
+            peers_warning_tx_buyer_input_partial_signature:
+            value.peers_warning_tx_buyer_input_partial_signature.serialize().into(),
+            peers_warning_tx_seller_input_partial_signature:
+            value.peers_warning_tx_seller_input_partial_signature.serialize().into(),
+            peers_redirect_tx_input_partial_signature:
+            value.peers_redirect_tx_input_partial_signature.serialize().into(),
+            swap_tx_input: match value.swap_tx_input_partial_signature {
+                Redactable::Value(sig) => Some(SwapTxInput::SwapTxInputPartialSignature(sig.serialize().into())),
+                Redactable::Redacted => Some(SwapTxInput::SwapTxInputPartialSignatureRedacted(helloworld::Redacted {})),
+                Redactable::Omitted => None,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl helloworld::PartialSignaturesMessage {
+    /// Redact the (plain or sealed) partial signature on the swap tx, along with its identity
+    /// signature, as the buyer does until it has started payment.
+    pub fn redact_swap_tx_input_partial_signature(&mut self) {
+        self.swap_tx_input = Some(SwapTxInput::SwapTxInputPartialSignatureRedacted(helloworld::Redacted {}));
+        self.swap_tx_input_identity_signature = None;
+    }
+}
+
+/// A message passed on to the peer, signed with the sender's identity key. The fields signed are
+/// those of the payload proper, in field number order, leaving out the identity signatures (and
+/// anything signed separately).
+pub trait SignedPayload {
+    const KIND: PayloadKind;
+
+    fn signed_fields(&self) -> Vec<&[u8]>;
+}
+
+impl SignedPayload for helloworld::NonceSharesMessage {
+    const KIND: PayloadKind = PayloadKind::NonceShares;
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![
+            self.warning_tx_fee_bump_address.as_bytes(),
+            self.redirect_tx_fee_bump_address.as_bytes(),
+            &self.half_deposit_psbt,
+            &self.swap_tx_input_nonce_share,
+            &self.buyers_warning_tx_buyer_input_nonce_share,
+            &self.buyers_warning_tx_seller_input_nonce_share,
+            &self.sellers_warning_tx_buyer_input_nonce_share,
+            &self.sellers_warning_tx_seller_input_nonce_share,
+            &self.buyers_redirect_tx_input_nonce_share,
+            &self.sellers_redirect_tx_input_nonce_share,
+        ]
+    }
+}
+
+impl SignedPayload for helloworld::NonceCommitmentsMessage {
+    const KIND: PayloadKind = PayloadKind::NonceCommitments;
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![
+            &self.swap_tx_input_nonce_commitment,
+            &self.buyers_warning_tx_buyer_input_nonce_commitment,
+            &self.buyers_warning_tx_seller_input_nonce_commitment,
+            &self.sellers_warning_tx_buyer_input_nonce_commitment,
+            &self.sellers_warning_tx_seller_input_nonce_commitment,
+            &self.buyers_redirect_tx_input_nonce_commitment,
+            &self.sellers_redirect_tx_input_nonce_commitment,
+        ]
+    }
+}
+
+impl SignedPayload for helloworld::PartialSignaturesMessage {
+    const KIND: PayloadKind = PayloadKind::PartialSignatures;
+
+    fn signed_fields(&self) -> Vec<&[u8]> {
+        vec![
+            &self.peers_warning_tx_buyer_input_partial_signature,
+            &self.peers_warning_tx_seller_input_partial_signature,
+            &self.peers_redirect_tx_input_partial_signature,
+        ]
+    }
+}
+
+impl From<helloworld::Role> for Role {
+    fn from(value: helloworld::Role) -> Self {
+        match value {
+            helloworld::Role::SellerAsMaker => Self::SellerAsMaker,
+            helloworld::Role::SellerAsTaker => Self::SellerAsTaker,
+            helloworld::Role::BuyerAsMaker => Self::BuyerAsMaker,
+            helloworld::Role::BuyerAsTaker => Self::BuyerAsTaker
+        }
+    }
+}
+
+impl From<Role> for helloworld::Role {
+    fn from(value: Role) -> Self {
+        match value {
+            Role::SellerAsMaker => Self::SellerAsMaker,
+            Role::SellerAsTaker => Self::SellerAsTaker,
+            Role::BuyerAsMaker => Self::BuyerAsMaker,
+            Role::BuyerAsTaker => Self::BuyerAsTaker
+        }
+    }
+}
+
+impl From<TradePhase> for helloworld::TradePhase {
+    fn from(value: TradePhase) -> Self {
+        match value {
+            TradePhase::Initialized => Self::Initialized,
+            TradePhase::KeySharesGenerated => Self::KeySharesGenerated,
+            TradePhase::NonceSharesGenerated => Self::NonceSharesGenerated,
+            TradePhase::PartialSignaturesGenerated => Self::PartialSignaturesGenerated,
+            TradePhase::DepositTxSigned => Self::DepositTxSigned,
+            TradePhase::DepositTxPublished => Self::DepositTxPublished,
+            TradePhase::SwapTxSigned => Self::SwapTxSigned,
+            TradePhase::Closed => Self::Closed
+        }
+    }
+}
+
+impl From<helloworld::TradePhase> for TradePhase {
+    fn from(value: helloworld::TradePhase) -> Self {
+        match value {
+            helloworld::TradePhase::Initialized => Self::Initialized,
+            helloworld::TradePhase::KeySharesGenerated => Self::KeySharesGenerated,
+            helloworld::TradePhase::NonceSharesGenerated => Self::NonceSharesGenerated,
+            helloworld::TradePhase::PartialSignaturesGenerated => Self::PartialSignaturesGenerated,
+            helloworld::TradePhase::DepositTxSigned => Self::DepositTxSigned,
+            helloworld::TradePhase::DepositTxPublished => Self::DepositTxPublished,
+            helloworld::TradePhase::SwapTxSigned => Self::SwapTxSigned,
+            helloworld::TradePhase::Closed => Self::Closed
+        }
+    }
+}
+
+impl From<TradeSummary> for helloworld::TradeSummary {
+    fn from(value: TradeSummary) -> Self {
+        Self {
+            trade_id: value.trade_id,
+            my_role: helloworld::Role::from(value.my_role).into(),
+            phase: helloworld::TradePhase::from(value.phase).into(),
+            trade_amount: value.trade_amount,
+            buyers_security_deposit: value.buyers_security_deposit,
+            sellers_security_deposit: value.sellers_security_deposit,
+            archived_at_millis: value.archived_at.map(to_millis),
+            revision: value.revision,
+            cancellation: match value.cancellation {
+                None => helloworld::Cancellation::NotCancelled,
+                Some(Cancellation::Unilateral) => helloworld::Cancellation::CancelledUnilaterally,
+                Some(Cancellation::Mutual) => helloworld::Cancellation::CancelledMutually,
+            }.into(),
+        }
+    }
+}
+
+impl From<SwapTxSignatureState> for helloworld::SwapTxSignatureState {
+    fn from(value: SwapTxSignatureState) -> Self {
+        match value {
+            SwapTxSignatureState::NotExchanged => Self::NotExchanged,
+            SwapTxSignatureState::Promised => Self::Promised,
+            SwapTxSignatureState::Revealed => Self::Revealed,
+        }
+    }
+}
+
+impl From<PaymentMilestone> for helloworld::PaymentMilestone {
+    fn from(value: PaymentMilestone) -> Self {
+        match value {
+            PaymentMilestone::Started => Self::PaymentStarted,
+            PaymentMilestone::Received => Self::PaymentReceived,
+        }
+    }
+}
+
+impl From<PaymentReceipt> for helloworld::PaymentReceipt {
+    fn from(value: PaymentReceipt) -> Self {
+        Self {
+            milestone: helloworld::PaymentMilestone::from(value.milestone).into(),
+            at_millis: to_millis(value.at),
+            identity_signature: value.identity_signature,
+        }
+    }
+}
+
+impl From<AuditEntry> for helloworld::AuditEntry {
+    fn from(value: AuditEntry) -> Self {
+        Self {
+            step: value.step,
+            at_millis: to_millis(value.at),
+            request_digest: value.request_digest.into(),
+            response_digest: value.response_digest.map(Into::into),
+            phase: helloworld::TradePhase::from(value.phase).into(),
+            error: value.error,
+            note: value.note,
+            correlation_id: value.correlation_id,
+            client: value.client,
+        }
+    }
+}
+
+// The export time & audit log of the transcript are left for the caller to fill in.
+impl From<TradeTranscript> for helloworld::TradeTranscript {
+    fn from(value: TradeTranscript) -> Self {
+        Self {
+            trade_id: value.trade_id,
+            my_role: helloworld::Role::from(value.my_role).into(),
+            phase: helloworld::TradePhase::from(value.phase).into(),
+            exported_at_millis: 0,
+            trade_amount: value.trade_amount,
+            buyers_security_deposit: value.buyers_security_deposit,
+            sellers_security_deposit: value.sellers_security_deposit,
+            my_identity_pub_key: value.my_identity_pub_key.map(|k| k.serialize().into()),
+            peers_identity_pub_key: value.peers_identity_pub_key.map(|k| k.serialize().into()),
+            buyer_output_key: Some(value.buyer_output_key.into()),
+            seller_output_key: Some(value.seller_output_key.into()),
+            swap_tx_input: Some(value.swap_tx_input.into()),
+            buyers_warning_tx_buyer_input: Some(value.buyers_warning_tx_buyer_input.into()),
+            buyers_warning_tx_seller_input: Some(value.buyers_warning_tx_seller_input.into()),
+            sellers_warning_tx_buyer_input: Some(value.sellers_warning_tx_buyer_input.into()),
+            sellers_warning_tx_seller_input: Some(value.sellers_warning_tx_seller_input.into()),
+            buyers_redirect_tx_input: Some(value.buyers_redirect_tx_input.into()),
+            sellers_redirect_tx_input: Some(value.sellers_redirect_tx_input.into()),
+            audit_log: vec![],
+        }
+    }
+}
+
+impl From<KeyTranscript> for helloworld::KeyTranscript {
+    fn from(value: KeyTranscript) -> Self {
+        Self {
+            my_key_share: value.my_key_share.map(|k| k.serialize().into()),
+            peers_key_share: value.peers_key_share.map(|k| k.serialize().into()),
+            aggregated_key: value.aggregated_key.map(|k| k.serialize().into()),
+        }
+    }
+}
+
+impl From<SigTranscript> for helloworld::SigTranscript {
+    fn from(value: SigTranscript) -> Self {
+        Self {
+            message: value.message,
+            my_nonce_share: value.my_nonce_share.map(|n| n.serialize().into()),
+            peers_nonce_share: value.peers_nonce_share.map(|n| n.serialize().into()),
+            my_partial_signature: value.my_partial_signature.map(|s| s.serialize().into()),
+            peers_partial_signature: value.peers_partial_signature.map(|s| s.serialize().into()),
+        }
+    }
+}
+
+/// The version of the `PreparedTxPackage` format, as exported.
+pub const PREPARED_TX_PACKAGE_VERSION: u32 = 1;
+
+// The export time of the package is left for the caller to fill in.
+impl From<PreparedTxPackage> for helloworld::PreparedTxPackage {
+    fn from(value: PreparedTxPackage) -> Self {
+        Self {
+            version: PREPARED_TX_PACKAGE_VERSION,
+            trade_id: value.trade_id,
+            my_role: helloworld::Role::from(value.my_role).into(),
+            exported_at_millis: 0,
+            prepared_tx_fee_rate: value.prepared_tx_fee_rate,
+            warning_tx_buyer_input: Some(value.warning_tx_buyer_input.into()),
+            warning_tx_seller_input: Some(value.warning_tx_seller_input.into()),
+            redirect_tx_input: Some(value.redirect_tx_input.into()),
+            redirect_receivers: value.redirect_receivers.into_iter()
+                .map(|(address, amount)| helloworld::ReceiverAddressAndAmount { address, amount })
+                .collect(),
+            claim_descriptor: value.claim_descriptor,
+        }
+    }
+}
+
+impl From<SignedTxInput> for helloworld::SignedTxInput {
+    fn from(value: SignedTxInput) -> Self {
+        Self {
+            message: value.message,
+            signature: value.signature.serialize().into(),
+            pub_key: value.pub_key.serialize_xonly().into(),
+        }
+    }
+}
+
+impl TryFrom<helloworld::TradeTranscript> for TradeTranscript {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::TradeTranscript) -> Result<Self> {
+        let phase = helloworld::TradePhase::try_from(value.phase)
+            .map_err(|_| ConvertError::UnknownEnumValue { field: "phase".to_owned(), value: value.phase })?;
+        let key = |key: Option<helloworld::KeyTranscript>, field| KeyTranscript::try_from(key.unwrap_or_default())
+            .map_err(|e| e.in_field(field));
+        let input = |input: Option<helloworld::SigTranscript>, field| SigTranscript::try_from(input.unwrap_or_default())
+            .map_err(|e| e.in_field(field));
+        Ok(Self {
+            trade_id: value.trade_id,
+            my_role: decode_role(value.my_role, "my_role")?,
+            phase: phase.into(),
+            trade_amount: value.trade_amount,
+            buyers_security_deposit: value.buyers_security_deposit,
+            sellers_security_deposit: value.sellers_security_deposit,
+            my_identity_pub_key: decode_opt(value.my_identity_pub_key.as_deref(), "my_identity_pub_key")?,
+            peers_identity_pub_key: decode_opt(value.peers_identity_pub_key.as_deref(), "peers_identity_pub_key")?,
+            buyer_output_key: key(value.buyer_output_key, "buyer_output_key")?,
+            seller_output_key: key(value.seller_output_key, "seller_output_key")?,
+            swap_tx_input: input(value.swap_tx_input, "swap_tx_input")?,
+            buyers_warning_tx_buyer_input: input(value.buyers_warning_tx_buyer_input, "buyers_warning_tx_buyer_input")?,
+            buyers_warning_tx_seller_input: input(value.buyers_warning_tx_seller_input, "buyers_warning_tx_seller_input")?,
+            sellers_warning_tx_buyer_input: input(value.sellers_warning_tx_buyer_input, "sellers_warning_tx_buyer_input")?,
+            sellers_warning_tx_seller_input: input(value.sellers_warning_tx_seller_input, "sellers_warning_tx_seller_input")?,
+            buyers_redirect_tx_input: input(value.buyers_redirect_tx_input, "buyers_redirect_tx_input")?,
+            sellers_redirect_tx_input: input(value.sellers_redirect_tx_input, "sellers_redirect_tx_input")?,
+        })
+    }
+}
+
+impl TryFrom<helloworld::KeyTranscript> for KeyTranscript {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::KeyTranscript) -> Result<Self> {
+        Ok(Self {
+            my_key_share: decode_opt(value.my_key_share.as_deref(), "my_key_share")?,
+            peers_key_share: decode_opt(value.peers_key_share.as_deref(), "peers_key_share")?,
+            aggregated_key: decode_opt(value.aggregated_key.as_deref(), "aggregated_key")?,
+        })
+    }
+}
+
+impl TryFrom<helloworld::SigTranscript> for SigTranscript {
+    type Error = ConvertError;
+
+    fn try_from(value: helloworld::SigTranscript) -> Result<Self> {
+        Ok(Self {
+            message: value.message,
+            my_nonce_share: decode_opt(value.my_nonce_share.as_deref(), "my_nonce_share")?,
+            peers_nonce_share: decode_opt(value.peers_nonce_share.as_deref(), "peers_nonce_share")?,
+            my_partial_signature: decode_opt(value.my_partial_signature.as_deref(), "my_partial_signature")?,
+            peers_partial_signature: decode_opt(value.peers_partial_signature.as_deref(), "peers_partial_signature")?,
+        })
+    }
+}
+
+/// The given time as milliseconds since the Unix epoch, as held by the time fields of the messages.
+#[must_use]
+pub fn to_millis(time: SystemTime) -> u64 {
+    u64::try_from(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_nested_field_is_named_by_path() {
+        let message = helloworld::PartialSignaturesMessage {
+            peers_warning_tx_buyer_input_partial_signature: vec![0; 32],
+            peers_warning_tx_seller_input_partial_signature: vec![0; 31],
+            ..Default::default()
+        };
+        let err = ExchangedSigs::<ByVal>::try_from(message).err().unwrap().in_field("peers_partial_signatures");
+        let status = Status::from(err);
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "could not decode peers_partial_signatures.peers_warning_tx_seller_input_partial_signature: \
+            malformed scalar: expected a 32-byte big-endian scalar below the curve order, got 31 bytes");
+        let violations = bad_request(&status).unwrap().field_violations;
+        assert_eq!(violations, [FieldViolation {
+            field: "peersPartialSignatures.peersWarningTxSellerInputPartialSignature".to_owned(),
+            description: "malformed scalar: expected a 32-byte big-endian scalar below the curve order, got 31 bytes".to_owned(),
+        }]);
+    }
+
+    #[test]
+    fn indexed_field_path_is_in_proto_case() {
+        assert_eq!(proto_field_path("peers_nonce_shares.funding_inputs[2].owner_pub_key"),
+            "peersNonceShares.fundingInputs[2].ownerPubKey");
+    }
+}
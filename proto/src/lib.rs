@@ -0,0 +1,38 @@
+//! The gRPC interface of the trade daemon, generated from `helloworld.proto`, together with the
+//! conversions between its messages and the types of the trade protocol, and the interface of the
+//! external signing service the daemon may call out to, generated from `signer.proto`, and of the
+//! service by which two daemons exchange their peer payloads directly, generated from `peer.proto`,
+//! as well as the standard gRPC health checking service, generated from `health.proto`, and the
+//! admin service of the daemon's operators, generated from `admin.proto`.
+
+pub mod convert;
+mod redact;
+
+/// The encoded `FileDescriptorSet` of every proto file compiled, by which their messages may be
+/// transcoded to & from JSON.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("musig_descriptor");
+
+pub mod helloworld {
+    #![allow(clippy::all, clippy::pedantic, clippy::restriction, clippy::nursery)]
+    tonic::include_proto!("helloworld");
+}
+
+pub mod signer {
+    #![allow(clippy::all, clippy::pedantic, clippy::restriction, clippy::nursery)]
+    tonic::include_proto!("signer");
+}
+
+pub mod peer {
+    #![allow(clippy::all, clippy::pedantic, clippy::restriction, clippy::nursery)]
+    tonic::include_proto!("peer");
+}
+
+pub mod health {
+    #![allow(clippy::all, clippy::pedantic, clippy::restriction, clippy::nursery)]
+    tonic::include_proto!("grpc.health.v1");
+}
+
+pub mod admin {
+    #![allow(clippy::all, clippy::pedantic, clippy::restriction, clippy::nursery)]
+    tonic::include_proto!("admin");
+}
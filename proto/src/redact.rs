@@ -0,0 +1,83 @@
+//! Debug impls for the messages holding private keys, which show every field but those (sealed
+//! fields being safe to show), so that logging a request or response never leaks them.
+
+use std::fmt;
+
+use crate::helloworld::{CloseTradeRequest, CloseTradeResponse, SwapTxSignatureResponse};
+use crate::peer::PrvKeyShare;
+use crate::signer::RevealKeyShareResponse;
+
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl fmt::Debug for SwapTxSignatureResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SwapTxSignatureResponse")
+            .field("swap_tx", &self.swap_tx)
+            .field("peer_output_prv_key_share", &Redacted)
+            .field("sealed_peer_output_prv_key_share", &self.sealed_peer_output_prv_key_share)
+            .finish()
+    }
+}
+
+impl fmt::Debug for CloseTradeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloseTradeRequest")
+            .field("trade_id", &self.trade_id)
+            .field("my_output_peers_prv_key_share", &self.my_output_peers_prv_key_share.as_ref().map(|_| Redacted))
+            .field("swap_tx", &self.swap_tx)
+            .field("expected_revision", &self.expected_revision)
+            .field("sealed_my_output_peers_prv_key_share", &self.sealed_my_output_peers_prv_key_share)
+            .finish()
+    }
+}
+
+impl fmt::Debug for CloseTradeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CloseTradeResponse")
+            .field("peer_output_prv_key_share", &Redacted)
+            .field("sealed_peer_output_prv_key_share", &self.sealed_peer_output_prv_key_share)
+            .finish()
+    }
+}
+
+impl fmt::Debug for RevealKeyShareResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RevealKeyShareResponse")
+            .field("prv_key", &Redacted)
+            .finish()
+    }
+}
+
+impl fmt::Debug for PrvKeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrvKeyShare")
+            .field("prv_key_share", &Redacted)
+            .field("sealed_prv_key_share", &self.sealed_prv_key_share)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prv_key_shares_are_redacted() {
+        let request = CloseTradeRequest {
+            trade_id: "trade".to_owned(),
+            my_output_peers_prv_key_share: Some(vec![0xab; 32]),
+            swap_tx: None,
+            expected_revision: Some(3),
+            sealed_my_output_peers_prv_key_share: None,
+        };
+        let debug = format!("{:?}", request);
+        assert_eq!(debug, "CloseTradeRequest { trade_id: \"trade\", my_output_peers_prv_key_share: Some([REDACTED]), \
+            swap_tx: None, expected_revision: Some(3), sealed_my_output_peers_prv_key_share: None }");
+    }
+}
@@ -0,0 +1,78 @@
+//! Time each stage of the signing of a whole trade, as a pair of buyer & seller trade models, with
+//! every tx input of the trade signed by both parties.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use musig_trade_protocol::{ProtocolErrorKind, Role, TradeModel};
+
+type Result<T> = std::result::Result<T, ProtocolErrorKind>;
+
+type Parties = [TradeModel; 2];
+
+/// A buyer & seller with each other's public key shares, ready to aggregate them.
+fn key_shares_exchanged() -> Parties {
+    let mut buyer = TradeModel::builder("trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares().unwrap().build();
+    let mut seller = TradeModel::builder("trade".to_owned(), Role::SellerAsMaker).with_my_key_shares().unwrap().build();
+    let [b1, b2] = buyer.get_my_key_shares().unwrap().map(|k| k.pub_key);
+    let [s1, s2] = seller.get_my_key_shares().unwrap().map(|k| k.pub_key);
+    buyer.set_peer_key_shares(s1, s2);
+    seller.set_peer_key_shares(b1, b2);
+    [buyer, seller]
+}
+
+/// A buyer & seller with each other's nonce shares, ready to aggregate them.
+fn nonce_shares_exchanged() -> Parties {
+    let [mut buyer, mut seller] = key_shares_exchanged();
+    for trade_model in [&mut buyer, &mut seller] {
+        trade_model.aggregate_key_shares().unwrap();
+        trade_model.init_my_nonce_shares().unwrap();
+    }
+    seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+    buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+    [buyer, seller]
+}
+
+/// A buyer & seller ready to sign.
+fn nonce_shares_aggregated() -> Parties {
+    let mut parties = nonce_shares_exchanged();
+    for trade_model in &mut parties {
+        trade_model.aggregate_nonce_shares().unwrap();
+    }
+    parties
+}
+
+/// A buyer & seller with each other's partial signatures, ready to aggregate them.
+fn partial_signatures_exchanged() -> Parties {
+    let [mut buyer, mut seller] = nonce_shares_aggregated();
+    for trade_model in [&mut buyer, &mut seller] {
+        trade_model.sign_partial().unwrap();
+    }
+    seller.peer_partial_signatures_on_my_txs_mut().set(buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+    buyer.peer_partial_signatures_on_my_txs_mut().set(seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+    [buyer, seller]
+}
+
+fn bench_trade_signing_stages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trade signing");
+    let mut bench_stage = |stage, setup: fn() -> Parties, step: fn(&mut TradeModel) -> Result<()>| {
+        group.bench_function(stage, |b| b.iter_batched_ref(setup, |parties| {
+            for trade_model in parties {
+                step(trade_model).unwrap();
+            }
+        }, BatchSize::SmallInput));
+    };
+    bench_stage("key aggregation", key_shares_exchanged, TradeModel::aggregate_key_shares);
+    bench_stage("nonce aggregation", nonce_shares_exchanged, TradeModel::aggregate_nonce_shares);
+    bench_stage("partial signing", nonce_shares_aggregated, TradeModel::sign_partial);
+    // Only the seller has the swap tx to sign, once the buyer reveals its partial signature on it:
+    bench_stage("signature aggregation", partial_signatures_exchanged, |trade_model| {
+        trade_model.aggregate_partial_signatures()?;
+        if !trade_model.am_buyer() {
+            trade_model.aggregate_swap_tx_partial_signatures()?;
+        }
+        Ok(())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_trade_signing_stages);
+criterion_main!(benches);
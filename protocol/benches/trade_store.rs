@@ -0,0 +1,52 @@
+//! Compare the throughput of concurrent trade model lookups (as every RPC does) with a single shard,
+//! equivalent to one global store mutex, against the default number of shards.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use musig_trade_protocol::{lock_trade_model, Role, TradeModel, TradeModelMemoryStore, TradeModelStore as _};
+use std::hint;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TRADE_COUNT: usize = 500;
+const THREAD_COUNT: usize = 8;
+
+fn store_with_trades(store: TradeModelMemoryStore) -> TradeModelMemoryStore {
+    for i in 0..TRADE_COUNT {
+        store.add_trade_model(TradeModel::new(format!("trade-{}", i), Role::SellerAsMaker)).unwrap();
+    }
+    store
+}
+
+/// Look up & lock trade models from every thread at once, with the given number of lookups in all.
+fn concurrent_lookups(store: &TradeModelMemoryStore, lookup_count: u64) -> Duration {
+    let lookups_per_thread = usize::try_from(lookup_count).unwrap().div_ceil(THREAD_COUNT);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for t in 0..THREAD_COUNT {
+            s.spawn(move || {
+                for i in 0..lookups_per_thread {
+                    let trade_id = format!("trade-{}", (i * THREAD_COUNT + t) % TRADE_COUNT);
+                    let trade_model = store.get_trade_model(&trade_id).unwrap();
+                    hint::black_box(lock_trade_model(&trade_model).phase());
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_concurrent_trade_model_access(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent trade model lookups");
+    group.throughput(Throughput::Elements(1));
+    for (shards, store) in [
+        ("1 shard", TradeModelMemoryStore::with_shard_count(1)),
+        ("default shards", TradeModelMemoryStore::default()),
+    ] {
+        let store = store_with_trades(store);
+        group.bench_function(BenchmarkId::from_parameter(shards), |b| b.iter_custom(|n| concurrent_lookups(&store, n)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_concurrent_trade_model_access);
+criterion_main!(benches);
@@ -0,0 +1,266 @@
+//! The published BIP-327 test vectors (key aggregation, nonce aggregation, partial signing & its
+//! tweaked variant), run through our own key & signing contexts instead of `musig2` directly, so
+//! that a change to the `musig2` dependency, or to how the contexts drive it, which breaks byte
+//! compatibility with the reference implementation shows up here first.
+//!
+//! Only the valid cases are taken over, along with the error cases which our contexts check for
+//! themselves (rather than leaving to `musig2`'s decoding).
+
+use musig2::{AggNonce, LiftedSignature, PubNonce, SecNonce};
+use secp::{MaybePoint, MaybeScalar, Point, Scalar};
+use std::prelude::rust_2021::*;
+
+use crate::{KeyCtx, KeyPair, LocalSigner, NoncePair, ProtocolErrorKind, Secret, SigCtx};
+
+/// From `key_agg_vectors.json`.
+const KEY_AGG_PUB_KEYS: [&str; 3] = [
+    "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+    "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+    "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+];
+
+/// The key indices of each case, and the x-only aggregated key expected.
+const KEY_AGG_CASES: [(&[usize], &str); 4] = [
+    (&[0, 1, 2], "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C"),
+    (&[2, 1, 0], "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B"),
+    (&[0, 0, 0], "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935"),
+    (&[0, 0, 1, 1], "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E"),
+];
+
+/// From `nonce_agg_vectors.json`.
+const NONCE_AGG_PUB_NONCES: [&str; 4] = [
+    "020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E666\
+     03BA47FBC1834437B3212E89A84D8425E7BF12E0245D98262268EBDCB385D50641",
+    "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A6\
+     0248C264CDD57D3C24D79990B0F865674EB62A0F9018277A95011B41BFC193B833",
+    "020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E666\
+     0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+    "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A6\
+     0379BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+];
+
+/// The nonce indices of each case, and the aggregated nonce expected. The second half of the last
+/// is the point at infinity, which (unlike both halves being so) we let through.
+const NONCE_AGG_CASES: [([usize; 2], &str); 2] = [
+    ([0, 1], "035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B\
+              024725377345BDE0E9C33AF3C43C0A29A9249F2F2956FA8CFEB55C8573D0262DC8"),
+    ([2, 3], "035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B\
+              000000000000000000000000000000000000000000000000000000000000000000"),
+];
+
+/// From `sign_verify_vectors.json` (and `tweak_vectors.json`, which shares them): the secret key &
+/// secret nonce of the signer whose partial signatures are given.
+const SIGN_PRV_KEY: &str = "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671";
+const SIGN_SEC_NONCE: &str = "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61\
+                              FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F7";
+
+const SIGN_PUB_KEYS: [&str; 3] = [
+    "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+    "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+    "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661",
+];
+
+const SIGN_PUB_NONCES: [&str; 4] = [
+    "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA\
+     0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+    "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798\
+     0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+    "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE93\
+     03E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+    "0237C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA\
+     0387BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+];
+
+const SIGN_AGG_NONCE: &str = "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61\
+                              037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9";
+
+const SIGN_MESSAGES: [&str; 3] = [
+    "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF",
+    "",
+    "2626262626262626262626262626262626262626262626262626262626262626262626262626",
+];
+
+/// The key (and matching nonce) indices of each case, the index of the signer among them, the
+/// message index, and the partial signature expected.
+const SIGN_CASES: [(&[usize], usize, usize, &str); 5] = [
+    (&[0, 1, 2], 0, 0, "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB"),
+    (&[1, 0, 2], 1, 0, "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52"),
+    (&[1, 2, 0], 2, 0, "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900"),
+    (&[0, 1, 2], 0, 1, "D7D63FFD644CCDA4E62BC2BC0B1D02DD32A1DC3030E155195810231D1037D82D"),
+    (&[0, 1, 2], 0, 2, "E184351828DA5094A97C79CABDAAA0BFB87608C32E8829A4DF5340A6F243B78C"),
+];
+
+/// From `tweak_vectors.json`, in which the third signing pubkey differs from the sign vectors'.
+const TWEAK_PUB_KEYS: [&str; 3] = [
+    SIGN_PUB_KEYS[0],
+    SIGN_PUB_KEYS[1],
+    "02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+];
+
+const TWEAKS: [&str; 4] = [
+    "E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB",
+    "AE2EA797CC0FE72AC5B97B97F3C6957D7E4199A167A58EB08BCAFFDA70AC0455",
+    "F52ECBC565B3D8BEA2DFD5B75A4F457E54369809322E4120831626F290FA87E0",
+    "1969AD73CC177FA0B4FCED6DF1F7BF9907E665FDE9BA196A74FED0A3CF5AEF9D",
+];
+
+/// The tweak indices of each case, whether each tweak is x-only, and the partial signature expected
+/// of the last of the signers `[1, 2, 0]`, on the first sign message.
+const TWEAK_CASES: [(&[(usize, bool)], &str); 5] = [
+    (&[(0, true)], "E28A5C66E61E178C2BA19DB77B6CF9F7E2F0F56C17918CD13135E60CC848FE91"),
+    (&[(0, false)], "38B0767798252F21BF5702C48028B095428320F73A4B14DB1E25DE58543D2D2D"),
+    (&[(0, false), (1, true)], "408A0A21C4A0F5DACAF9646AD6EB6FECD7F7A11F03ED1F48DFFF2185BC2C2408"),
+    (&[(0, false), (1, false), (2, true), (3, true)], "45ABD206E61E3DF2EC9E264A6FEC8292141A633C28586388235541F9ADE75435"),
+    (&[(0, true), (1, false), (2, true), (3, false)], "B255FDCAC27B40C7CE7848E2D3B7BF5EA0ED756DA81565AC804CCCA3E1D5D239"),
+];
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+
+/// The key context of the given signers, with every public key share set, and our private key
+/// share too if given, aggregated.
+fn key_ctx(pub_keys: &[Point], my_index: usize, my_prv_key: Option<Scalar>) -> KeyCtx {
+    let mut key_ctx = KeyCtx::new(pub_keys.len(), my_index);
+    for (key_share, &pub_key) in key_ctx.key_shares.iter_mut().zip(pub_keys) {
+        *key_share = Some(KeyPair::from_public(pub_key));
+    }
+    if let Some(prv_key) = my_prv_key {
+        key_ctx.key_shares[my_index].as_mut().unwrap().set_prv_key(prv_key).unwrap();
+    }
+    key_ctx.aggregate_key_shares().unwrap();
+    key_ctx
+}
+
+/// A signing context holding our (unused) nonce share and the given aggregated nonce, ready for
+/// partial signing.
+fn sig_ctx(sec_nonce: SecNonce, aggregated_nonce: AggNonce) -> SigCtx {
+    SigCtx {
+        my_nonce_share: Some(NoncePair { pub_nonce: sec_nonce.public_nonce(), sec_nonce: Some(Secret::new(sec_nonce)) }),
+        aggregated_nonce: Some(aggregated_nonce),
+        ..Default::default()
+    }
+}
+
+fn pub_keys(key_indices: &[usize], pub_keys: &[&str]) -> Vec<Point> {
+    key_indices.iter().map(|&i| pub_keys[i].parse().unwrap()).collect()
+}
+
+#[test]
+fn key_aggregation_vectors() {
+    for (key_indices, expected) in KEY_AGG_CASES {
+        let key_ctx = key_ctx(&pub_keys(key_indices, &KEY_AGG_PUB_KEYS), 0, None);
+        let aggregated_key = key_ctx.aggregated_key.as_ref().unwrap().pub_key;
+        assert_eq!(aggregated_key.serialize_xonly().to_vec(), from_hex(expected), "keys {:?}", key_indices);
+        assert_eq!(key_ctx.key_agg_ctx.unwrap().aggregated_pubkey::<Point>(), aggregated_key);
+    }
+}
+
+#[test]
+fn nonce_aggregation_vectors() {
+    for ([i, j], expected) in NONCE_AGG_CASES {
+        let mut sig_ctx = SigCtx {
+            am_buyer: true,
+            my_nonce_share: Some(NoncePair { pub_nonce: NONCE_AGG_PUB_NONCES[i].parse().unwrap(), sec_nonce: None }),
+            peers_nonce_share: Some(NONCE_AGG_PUB_NONCES[j].parse().unwrap()),
+            ..Default::default()
+        };
+        let aggregated_nonce = sig_ctx.aggregate_nonce_shares().unwrap();
+        assert_eq!(*aggregated_nonce, expected.parse().unwrap(), "nonces {:?}", [i, j]);
+    }
+}
+
+/// The sign vector whose aggregated nonce is wholly the point at infinity, which `musig2` (like the
+/// BIP) accepts, but we reject before signing.
+#[test]
+fn nonce_aggregation_rejects_infinite_aggregated_nonce() {
+    let mut sig_ctx = SigCtx {
+        am_buyer: true,
+        my_nonce_share: Some(NoncePair { pub_nonce: SIGN_PUB_NONCES[0].parse().unwrap(), sec_nonce: None }),
+        peers_nonce_share: Some(SIGN_PUB_NONCES[3].parse().unwrap()),
+        ..Default::default()
+    };
+    assert!(matches!(sig_ctx.aggregate_nonce_shares(), Err(ProtocolErrorKind::ZeroNonce)));
+    assert!(sig_ctx.aggregated_nonce.is_none());
+}
+
+#[test]
+fn partial_signing_vectors() {
+    let prv_key: Scalar = SIGN_PRV_KEY.parse().unwrap();
+    assert_eq!(prv_key.base_point_mul(), SIGN_PUB_KEYS[0].parse::<Point>().unwrap());
+    let sec_nonce: SecNonce = SIGN_SEC_NONCE.parse().unwrap();
+    assert_eq!(sec_nonce.public_nonce(), SIGN_PUB_NONCES[0].parse::<PubNonce>().unwrap());
+    let aggregated_nonce: AggNonce = SIGN_AGG_NONCE.parse().unwrap();
+    assert_eq!(AggNonce::sum(SIGN_PUB_NONCES[..3].iter().map(|n| n.parse::<PubNonce>().unwrap())), aggregated_nonce);
+
+    for (key_indices, my_index, message_index, expected) in SIGN_CASES {
+        let key_ctx = key_ctx(&pub_keys(key_indices, &SIGN_PUB_KEYS), my_index, Some(prv_key));
+        let mut sig_ctx = sig_ctx(sec_nonce.clone(), aggregated_nonce.clone());
+        let sig = *sig_ctx.sign_partial(&key_ctx, from_hex(SIGN_MESSAGES[message_index]), &LocalSigner).unwrap();
+        assert_eq!(sig, expected.parse().unwrap(), "keys {:?}, message {}", key_indices, message_index);
+        assert_eq!(sig_ctx.adaptor_point, MaybePoint::Infinity);
+        assert!(sig_ctx.my_nonce_share.unwrap().sec_nonce.is_none(), "secret nonce not used up");
+    }
+}
+
+#[test]
+fn tweaked_partial_signing_vectors() {
+    let prv_key: Scalar = SIGN_PRV_KEY.parse().unwrap();
+    let sec_nonce: SecNonce = SIGN_SEC_NONCE.parse().unwrap();
+    let aggregated_nonce: AggNonce = SIGN_AGG_NONCE.parse().unwrap();
+    let tweaks: Vec<Scalar> = TWEAKS.iter().map(|t| t.parse().unwrap()).collect();
+
+    for (tweak_indices, expected) in TWEAK_CASES {
+        let mut key_ctx = key_ctx(&pub_keys(&[1, 2, 0], &TWEAK_PUB_KEYS), 2, Some(prv_key));
+        for &(i, is_xonly) in tweak_indices {
+            key_ctx.key_agg_ctx = Some(key_ctx.key_agg_ctx.unwrap().with_tweak(tweaks[i], is_xonly).unwrap());
+        }
+        let mut sig_ctx = sig_ctx(sec_nonce.clone(), aggregated_nonce.clone());
+        let sig = *sig_ctx.sign_partial(&key_ctx, from_hex(SIGN_MESSAGES[0]), &LocalSigner).unwrap();
+        assert_eq!(sig, expected.parse().unwrap(), "tweaks {:?}", tweak_indices);
+    }
+}
+
+/// The tweaks of the tweak vectors, applied to a 2-of-2 output of our own, checking that the final
+/// signature aggregated without a check (as done in batches) is the one `musig2` aggregates, so
+/// that a change to how we fold the tweaks in isn't missed.
+#[test]
+fn tweaked_unverified_aggregation_matches_musig2() {
+    let prv_keys = [Scalar::reduce_from(&[1; 32]), Scalar::reduce_from(&[2; 32])];
+    let my_pub_keys = prv_keys.map(|k| k.base_point_mul());
+    let tweaks: Vec<Scalar> = TWEAKS.iter().map(|t| t.parse().unwrap()).collect();
+    let message = from_hex(SIGN_MESSAGES[0]);
+
+    for (tweak_indices, _) in TWEAK_CASES {
+        let key_ctxs = [0, 1].map(|my_index| {
+            let mut key_ctx = key_ctx(&my_pub_keys, my_index, Some(prv_keys[my_index]));
+            for &(i, is_xonly) in tweak_indices {
+                key_ctx.key_agg_ctx = Some(key_ctx.key_agg_ctx.unwrap().with_tweak(tweaks[i], is_xonly).unwrap());
+            }
+            key_ctx
+        });
+        let mut sig_ctxs = [0, 1].map(|my_index| {
+            let sec_nonce = SecNonce::build([[1; 32], [2; 32]][my_index]).build();
+            SigCtx {
+                am_buyer: my_index == 0,
+                my_nonce_share: Some(NoncePair { pub_nonce: sec_nonce.public_nonce(), sec_nonce: Some(Secret::new(sec_nonce)) }),
+                ..Default::default()
+            }
+        });
+        let pub_nonces = sig_ctxs.each_ref().map(|s| s.my_nonce_share.as_ref().unwrap().pub_nonce.clone());
+        sig_ctxs[0].peers_nonce_share = Some(pub_nonces[1].clone());
+        sig_ctxs[1].peers_nonce_share = Some(pub_nonces[0].clone());
+        let mut partial_sigs = Vec::new();
+        for (sig_ctx, key_ctx) in sig_ctxs.iter_mut().zip(&key_ctxs) {
+            sig_ctx.aggregate_nonce_shares().unwrap();
+            partial_sigs.push(*sig_ctx.sign_partial(key_ctx, message.clone(), &LocalSigner).unwrap());
+        }
+        sig_ctxs[0].peers_partial_sig = Some(partial_sigs[1]);
+
+        let unverified_sig = sig_ctxs[0].aggregate_partial_signatures_unverified(&key_ctxs[0]).unwrap();
+        let sig = *sig_ctxs[0].aggregate_partial_signatures(&key_ctxs[0]).unwrap();
+        assert_eq!(unverified_sig.sig, sig, "tweaks {:?}", tweak_indices);
+        let tweaked_pub_key: Point = key_ctxs[0].key_agg_ctx.as_ref().unwrap().aggregated_pubkey();
+        musig2::verify_single(tweaked_pub_key, sig.adapt::<LiftedSignature>(MaybeScalar::Zero).unwrap(), &message).unwrap();
+    }
+}
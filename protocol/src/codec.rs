@@ -0,0 +1,1404 @@
+//! Binary encoding of [`TradeModel`] for persistent trade model stores. The records are plain
+//! protobuf messages, encoded with `prost` as for the gRPC interface, so that fields can be added
+//! later without breaking previously written records.
+//!
+//! Each record carries a format version. Changes to the meaning of existing fields (as opposed to
+//! mere field additions) must bump [`CURRENT_VERSION`] and add a migration to [`MIGRATIONS`], which
+//! are applied in turn to upgrade older records as they are decoded.
+
+use musig2::{KeyAggContext, SecNonce};
+use prost::Message as _;
+use secp::{MaybePoint, Point};
+use std::convert::Infallible;
+use std::prelude::rust_2021::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::{AuditEntry, Cancellation, ChangePolicy, CoinControl, Deadline, DeadlineDue, DeadlineKind, DeadlineState, FeeRateChange, FeeStrategy, FundingInput, KeyCtx, KeyPair, NoncePair, PaymentMilestone,
+    PaymentReceipt, PeerEndpoint, PolicyAction, PolicyActionKind, PolicyOverrides, Role, Secret, SigCtx, SwapTxFeeBump, TradeModel,
+    TradePhase, TradeSummary};
+use crate::storage::ByOptVal;
+
+#[derive(Clone, PartialEq, prost::Message)]
+#[expect(clippy::struct_excessive_bools, reason = "the record mirrors the flags of the trade model, field for field")]
+struct TradeModelRecord {
+    #[prost(string, tag = "1")]
+    trade_id: String,
+    #[prost(int32, tag = "2")]
+    my_role: i32,
+    #[prost(uint64, optional, tag = "3")]
+    trade_amount: Option<u64>,
+    #[prost(uint64, optional, tag = "4")]
+    buyers_security_deposit: Option<u64>,
+    #[prost(uint64, optional, tag = "5")]
+    sellers_security_deposit: Option<u64>,
+    #[prost(double, optional, tag = "6")]
+    deposit_tx_fee_rate: Option<f64>,
+    #[prost(double, optional, tag = "7")]
+    prepared_tx_fee_rate: Option<f64>,
+    #[prost(message, optional, tag = "8")]
+    buyer_output_key_ctx: Option<KeyCtxRecord>,
+    #[prost(message, optional, tag = "9")]
+    seller_output_key_ctx: Option<KeyCtxRecord>,
+    #[prost(message, optional, tag = "10")]
+    swap_tx_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "11")]
+    buyers_warning_tx_buyer_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "12")]
+    buyers_warning_tx_seller_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "13")]
+    sellers_warning_tx_buyer_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "14")]
+    sellers_warning_tx_seller_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "15")]
+    buyers_redirect_tx_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "16")]
+    sellers_redirect_tx_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(int32, tag = "17")]
+    phase: i32,
+    #[prost(uint32, tag = "18")]
+    version: u32,
+    #[prost(uint64, optional, tag = "19")]
+    created_at_millis: Option<u64>,
+    /// Whether the secret fields below are encrypted with a [`SecretCipher`].
+    #[prost(bool, tag = "20")]
+    secrets_encrypted: bool,
+    #[prost(uint64, tag = "21")]
+    revision: u64,
+    #[prost(bytes = "vec", optional, tag = "22")]
+    my_signed_half_deposit_psbt: Option<Vec<u8>>,
+    #[prost(message, optional, tag = "23")]
+    my_identity_key: Option<KeyPairRecord>,
+    #[prost(bytes = "vec", optional, tag = "24")]
+    peers_identity_pub_key: Option<Vec<u8>>,
+    #[prost(bool, tag = "25")]
+    seal_peer_payloads: bool,
+    #[prost(message, optional, tag = "26")]
+    peer_endpoint: Option<PeerEndpointRecord>,
+    #[prost(string, optional, tag = "27")]
+    opened_by: Option<String>,
+    #[prost(message, repeated, tag = "28")]
+    deadlines: Vec<DeadlineRecord>,
+    #[prost(message, optional, tag = "29")]
+    policy_overrides: Option<PolicyOverridesRecord>,
+    #[prost(message, repeated, tag = "30")]
+    policy_actions: Vec<PolicyActionRecord>,
+    #[prost(uint32, optional, tag = "31")]
+    deposit_tx_height: Option<u32>,
+    #[prost(message, repeated, tag = "32")]
+    payment_receipts: Vec<PaymentReceiptRecord>,
+    #[prost(message, repeated, tag = "33")]
+    redirect_receivers: Vec<RedirectReceiverRecord>,
+    #[prost(bool, tag = "34")]
+    commit_to_nonces: bool,
+    #[prost(uint32, tag = "35")]
+    signing_session: u32,
+    #[prost(message, optional, tag = "36")]
+    fee_rate_change: Option<FeeRateChangeRecord>,
+    #[prost(message, repeated, tag = "37")]
+    my_funding_inputs: Vec<FundingInputRecord>,
+    #[prost(message, repeated, tag = "38")]
+    peers_funding_inputs: Vec<FundingInputRecord>,
+    #[prost(uint64, optional, tag = "39")]
+    peer_last_seen_millis: Option<u64>,
+    #[prost(bool, tag = "40")]
+    peer_unresponsive: bool,
+    #[prost(double, optional, tag = "41")]
+    swap_tx_fee_rate: Option<f64>,
+    #[prost(message, optional, tag = "42")]
+    swap_tx_fee_bump: Option<SwapTxFeeBumpRecord>,
+    #[prost(bytes = "vec", repeated, tag = "43")]
+    superseded_swap_tx_sigs: Vec<Vec<u8>>,
+    #[prost(bool, tag = "44")]
+    dry_run: bool,
+    #[prost(int32, optional, tag = "45")]
+    cancellation: Option<i32>,
+    #[prost(string, optional, tag = "46")]
+    peer_nonce_reused_from: Option<String>,
+    #[prost(message, repeated, tag = "47")]
+    step_completed_at: Vec<StepCompletionRecord>,
+    #[prost(message, optional, tag = "48")]
+    coin_control: Option<CoinControlRecord>,
+    #[prost(bytes = "vec", optional, tag = "49")]
+    peers_noise_static_pub_key: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct TradeSummaryRecord {
+    #[prost(string, tag = "1")]
+    trade_id: String,
+    #[prost(int32, tag = "2")]
+    my_role: i32,
+    #[prost(int32, tag = "3")]
+    phase: i32,
+    #[prost(uint64, optional, tag = "4")]
+    trade_amount: Option<u64>,
+    #[prost(uint64, optional, tag = "5")]
+    buyers_security_deposit: Option<u64>,
+    #[prost(uint64, optional, tag = "6")]
+    sellers_security_deposit: Option<u64>,
+    #[prost(uint64, optional, tag = "7")]
+    archived_at_millis: Option<u64>,
+    #[prost(uint64, tag = "8")]
+    revision: u64,
+    #[prost(int32, optional, tag = "9")]
+    cancellation: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct AuditEntryRecord {
+    #[prost(string, tag = "1")]
+    step: String,
+    #[prost(uint64, tag = "2")]
+    at_millis: u64,
+    #[prost(bytes = "vec", tag = "3")]
+    request_digest: Vec<u8>,
+    #[prost(bytes = "vec", optional, tag = "4")]
+    response_digest: Option<Vec<u8>>,
+    #[prost(int32, tag = "5")]
+    phase: i32,
+    #[prost(string, optional, tag = "6")]
+    error: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    note: Option<String>,
+    #[prost(string, optional, tag = "8")]
+    correlation_id: Option<String>,
+    #[prost(string, optional, tag = "9")]
+    client: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct FeeRateChangeRecord {
+    #[prost(double, tag = "1")]
+    prepared_tx_fee_rate: f64,
+    #[prost(message, optional, tag = "2")]
+    buyers_warning_tx_buyer_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "3")]
+    buyers_warning_tx_seller_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "4")]
+    sellers_warning_tx_buyer_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "5")]
+    sellers_warning_tx_seller_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "6")]
+    buyers_redirect_tx_input_sig_ctx: Option<SigCtxRecord>,
+    #[prost(message, optional, tag = "7")]
+    sellers_redirect_tx_input_sig_ctx: Option<SigCtxRecord>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SwapTxFeeBumpRecord {
+    #[prost(double, tag = "1")]
+    swap_tx_fee_rate: f64,
+    #[prost(message, optional, tag = "2")]
+    swap_tx_input_sig_ctx: Option<SigCtxRecord>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct FundingInputRecord {
+    #[prost(bytes = "vec", tag = "1")]
+    txid: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    vout: u32,
+    #[prost(uint64, tag = "3")]
+    amount: u64,
+    #[prost(bytes = "vec", tag = "4")]
+    owner_pub_key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "5")]
+    ownership_proof: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CoinControlRecord {
+    #[prost(message, repeated, tag = "1")]
+    use_outpoints: Vec<OutpointRecord>,
+    #[prost(message, repeated, tag = "2")]
+    avoid_outpoints: Vec<OutpointRecord>,
+    #[prost(int32, tag = "3")]
+    fee_strategy: i32,
+    #[prost(int32, tag = "4")]
+    change_policy: i32,
+    #[prost(string, optional, tag = "5")]
+    change_address: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct OutpointRecord {
+    #[prost(bytes = "vec", tag = "1")]
+    txid: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    vout: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct DeadlineRecord {
+    #[prost(int32, tag = "1")]
+    kind: i32,
+    #[prost(int32, tag = "2")]
+    phase: i32,
+    #[prost(uint64, optional, tag = "3")]
+    warn_at_millis: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    warn_height: Option<u32>,
+    #[prost(uint64, optional, tag = "5")]
+    due_at_millis: Option<u64>,
+    #[prost(uint32, optional, tag = "6")]
+    due_height: Option<u32>,
+    #[prost(int32, tag = "7")]
+    state: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PolicyOverridesRecord {
+    #[prost(uint32, optional, tag = "1")]
+    warning_tx_after_blocks: Option<u32>,
+    #[prost(bool, optional, tag = "2")]
+    auto_claim: Option<bool>,
+    #[prost(bool, optional, tag = "3")]
+    dry_run: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PolicyActionRecord {
+    #[prost(int32, tag = "1")]
+    kind: i32,
+    #[prost(uint64, tag = "2")]
+    at_millis: u64,
+    #[prost(uint32, optional, tag = "3")]
+    height: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RedirectReceiverRecord {
+    #[prost(string, tag = "1")]
+    address: String,
+    #[prost(uint64, tag = "2")]
+    amount: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct StepCompletionRecord {
+    #[prost(string, tag = "1")]
+    step: String,
+    #[prost(uint64, tag = "2")]
+    at_millis: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PaymentReceiptRecord {
+    #[prost(int32, tag = "1")]
+    milestone: i32,
+    #[prost(uint64, tag = "2")]
+    at_millis: u64,
+    #[prost(bytes = "vec", tag = "3")]
+    identity_signature: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PeerEndpointRecord {
+    #[prost(string, tag = "1")]
+    address: String,
+    #[prost(string, tag = "2")]
+    trade_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct KeyPairRecord {
+    #[prost(bytes = "vec", tag = "1")]
+    pub_key: Vec<u8>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    prv_key: Option<Vec<u8>>,
+}
+
+// The key aggregation context isn't recorded, as it is cheaply recomputed from the key shares. (Only
+// the 2-of-2 outputs of a trade are recorded yet, so the key shares are just ours & the peer's.)
+#[derive(Clone, PartialEq, prost::Message)]
+struct KeyCtxRecord {
+    #[prost(message, optional, tag = "1")]
+    my_key_share: Option<KeyPairRecord>,
+    #[prost(message, optional, tag = "2")]
+    peers_key_share: Option<KeyPairRecord>,
+    #[prost(message, optional, tag = "3")]
+    aggregated_key: Option<KeyPairRecord>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct NoncePairRecord {
+    #[prost(bytes = "vec", tag = "1")]
+    pub_nonce: Vec<u8>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    sec_nonce: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SigCtxRecord {
+    #[prost(bytes = "vec", tag = "1")]
+    adaptor_point: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    my_nonce_share: Option<NoncePairRecord>,
+    #[prost(bytes = "vec", optional, tag = "3")]
+    peers_nonce_share: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "4")]
+    aggregated_nonce: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "5")]
+    message: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "6")]
+    my_partial_sig: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "7")]
+    peers_partial_sig: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "8")]
+    aggregated_sig: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "9")]
+    peers_nonce_commitment: Option<Vec<u8>>,
+}
+
+/// The version of the trade model records written by [`TradeModel::encode_to_vec`].
+const CURRENT_VERSION: u32 = 1;
+
+/// Upgrades of older records, where `MIGRATIONS[n]` takes a record from version `n` to `n + 1`.
+/// (Records written before versioning was introduced have an implicit version of zero.)
+const MIGRATIONS: [fn(&mut TradeModelRecord); CURRENT_VERSION as usize] = [
+    infer_missing_phase,
+];
+
+type Result<T> = std::result::Result<T, CodecError>;
+
+/// Why a trade model or trade summary record could not be decoded.
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum CodecError {
+    #[error("malformed trade model record field: {0}")]
+    MalformedField(&'static str),
+    #[error("unknown role: {0}")]
+    UnknownRole(i32),
+    #[error("unknown trade phase: {0}")]
+    UnknownPhase(i32),
+    #[error("unknown deadline kind: {0}")]
+    UnknownDeadlineKind(i32),
+    #[error("unknown deadline state: {0}")]
+    UnknownDeadlineState(i32),
+    #[error("unknown cancellation: {0}")]
+    UnknownCancellation(i32),
+    #[error("unknown policy action: {0}")]
+    UnknownPolicyAction(i32),
+    #[error("unknown payment milestone: {0}")]
+    UnknownPaymentMilestone(i32),
+    #[error("unknown fee strategy: {0}")]
+    UnknownFeeStrategy(i32),
+    #[error("unknown change policy: {0}")]
+    UnknownChangePolicy(i32),
+    #[error("unsupported trade model record version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("trade model record has encrypted secrets, but no cipher was given")]
+    MissingCipher,
+    #[error("could not decrypt trade model record field: {0} (is the key correct?)")]
+    UndecryptableField(&'static str),
+    Decode(#[from] prost::DecodeError),
+}
+
+/// Which of the secret fields of a trade model (private key shares and secret nonces) to encode.
+#[derive(Clone, Copy)]
+pub enum SecretFields<'a> {
+    /// Encode every secret, as needed to resume the trade after decoding the model again.
+    Include,
+    /// Encode every secret, like [`Self::Include`], but encrypted with the given cipher.
+    Encrypt(&'a dyn SecretCipher),
+    /// Encode only the public protocol data. The resulting record is suitable for inspection or
+    /// export, but cannot be decoded back into a [`TradeModel`], as our own key shares are gone.
+    Omit,
+}
+
+/// Authenticated encryption of the secret fields of trade model records, for stores which must not
+/// keep secrets in plaintext. Each secret is bound to associated data identifying the trade and the
+/// field it belongs to, so that ciphertexts cannot be swapped around undetected.
+pub trait SecretCipher: Send + Sync {
+    fn seal(&self, plaintext: &[u8], associated_data: &[u8]) -> Vec<u8>;
+
+    /// Decrypt a ciphertext from [`Self::seal`], returning `None` if it isn't authentic.
+    fn open(&self, ciphertext: &[u8], associated_data: &[u8]) -> Option<Vec<u8>>;
+}
+
+impl TradeModel {
+    /// Encode the trade model as a record for a persistent store, with its secret fields handled
+    /// as given.
+    #[must_use]
+    pub fn encode_to_vec(&self, secret_fields: SecretFields) -> Vec<u8> {
+        let mut record = TradeModelRecord::from(self);
+        match secret_fields {
+            SecretFields::Include => {}
+            SecretFields::Encrypt(cipher) => record.seal_secrets(cipher),
+            SecretFields::Omit => record.strip_secrets(),
+        }
+        record.encode_to_vec()
+    }
+
+    /// Decode a trade model record, using the given cipher to decrypt its secrets if they were
+    /// encrypted. (Records with plaintext secrets are accepted either way.)
+    ///
+    /// # Errors
+    ///
+    /// Fails if the record is malformed, or from a later version, or if its secrets are encrypted
+    /// but no cipher (or the wrong one) is given.
+    pub fn decode(bytes: &[u8], cipher: Option<&dyn SecretCipher>) -> Result<Self> {
+        let mut record = TradeModelRecord::decode(bytes)?;
+        record.migrate()?;
+        if record.secrets_encrypted {
+            record.open_secrets(cipher.ok_or(CodecError::MissingCipher)?)?;
+        }
+        record.try_into()
+    }
+}
+
+impl TradeSummary {
+    #[must_use]
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        TradeSummaryRecord {
+            trade_id: self.trade_id.clone(),
+            my_role: role_to_i32(self.my_role),
+            phase: phase_to_i32(self.phase),
+            trade_amount: self.trade_amount,
+            buyers_security_deposit: self.buyers_security_deposit,
+            sellers_security_deposit: self.sellers_security_deposit,
+            archived_at_millis: self.archived_at.map(to_millis),
+            revision: self.revision,
+            cancellation: self.cancellation.map(cancellation_to_i32),
+        }.encode_to_vec()
+    }
+
+    /// Decode a trade summary record.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the record is malformed.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let record = TradeSummaryRecord::decode(bytes)?;
+        Ok(Self {
+            trade_id: record.trade_id,
+            my_role: role_from_i32(record.my_role)?,
+            phase: phase_from_i32(record.phase)?,
+            trade_amount: record.trade_amount,
+            buyers_security_deposit: record.buyers_security_deposit,
+            sellers_security_deposit: record.sellers_security_deposit,
+            archived_at: record.archived_at_millis.map(from_millis),
+            revision: record.revision,
+            cancellation: record.cancellation.map(cancellation_from_i32).transpose()?,
+        })
+    }
+}
+
+impl AuditEntry {
+    /// Encode the entry as a length-delimited record, to be appended to an audit log.
+    #[must_use]
+    pub fn encode_length_delimited_to_vec(&self) -> Vec<u8> {
+        AuditEntryRecord {
+            step: self.step.clone(),
+            at_millis: to_millis(self.at),
+            request_digest: self.request_digest.into(),
+            response_digest: self.response_digest.map(Into::into),
+            phase: phase_to_i32(self.phase),
+            error: self.error.clone(),
+            note: self.note.clone(),
+            correlation_id: self.correlation_id.clone(),
+            client: self.client.clone(),
+        }.encode_length_delimited_to_vec()
+    }
+
+    /// Decode an audit log of length-delimited entry records. A truncated last record, as left by
+    /// a crash mid-append, is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if any (complete) record is malformed.
+    pub fn decode_log(mut bytes: &[u8]) -> Result<Vec<Self>> {
+        let mut entries = Vec::new();
+        while !bytes.is_empty() {
+            let mut rest = bytes;
+            let len = prost::decode_length_delimiter(&mut rest)?;
+            if rest.len() < len {
+                break;
+            }
+            let record = AuditEntryRecord::decode(&rest[..len])?;
+            bytes = &rest[len..];
+            entries.push(Self {
+                step: record.step,
+                at: from_millis(record.at_millis),
+                request_digest: decode_field(&record.request_digest, "request_digest")?,
+                response_digest: decode_opt_field(record.response_digest.as_ref(), "response_digest")?,
+                phase: phase_from_i32(record.phase)?,
+                error: record.error,
+                note: record.note,
+                correlation_id: record.correlation_id,
+                client: record.client,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+fn decode_field<T: for<'a> TryFrom<&'a [u8]>>(bytes: &[u8], field: &'static str) -> Result<T> {
+    T::try_from(bytes).map_err(|_| CodecError::MalformedField(field))
+}
+
+fn decode_opt_field<T: for<'a> TryFrom<&'a [u8]>>(bytes: Option<&Vec<u8>>, field: &'static str) -> Result<Option<T>> {
+    bytes.map(|b| decode_field(b, field)).transpose()
+}
+
+const fn role_to_i32(role: Role) -> i32 {
+    match role {
+        Role::SellerAsMaker => 0,
+        Role::SellerAsTaker => 1,
+        Role::BuyerAsMaker => 2,
+        Role::BuyerAsTaker => 3,
+    }
+}
+
+const fn role_from_i32(value: i32) -> Result<Role> {
+    Ok(match value {
+        0 => Role::SellerAsMaker,
+        1 => Role::SellerAsTaker,
+        2 => Role::BuyerAsMaker,
+        3 => Role::BuyerAsTaker,
+        i => return Err(CodecError::UnknownRole(i)),
+    })
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+const fn phase_to_i32(phase: TradePhase) -> i32 {
+    match phase {
+        TradePhase::Initialized => 0,
+        TradePhase::KeySharesGenerated => 1,
+        TradePhase::NonceSharesGenerated => 2,
+        TradePhase::PartialSignaturesGenerated => 3,
+        TradePhase::DepositTxSigned => 4,
+        TradePhase::DepositTxPublished => 5,
+        TradePhase::SwapTxSigned => 6,
+        TradePhase::Closed => 7,
+    }
+}
+
+const fn phase_from_i32(value: i32) -> Result<TradePhase> {
+    Ok(match value {
+        0 => TradePhase::Initialized,
+        1 => TradePhase::KeySharesGenerated,
+        2 => TradePhase::NonceSharesGenerated,
+        3 => TradePhase::PartialSignaturesGenerated,
+        4 => TradePhase::DepositTxSigned,
+        5 => TradePhase::DepositTxPublished,
+        6 => TradePhase::SwapTxSigned,
+        7 => TradePhase::Closed,
+        i => return Err(CodecError::UnknownPhase(i)),
+    })
+}
+
+const fn deadline_kind_to_i32(kind: DeadlineKind) -> i32 {
+    match kind {
+        DeadlineKind::PeerResponse => 0,
+        DeadlineKind::Payment => 1,
+        DeadlineKind::WarningTxClaim => 2,
+    }
+}
+
+const fn deadline_kind_from_i32(value: i32) -> Result<DeadlineKind> {
+    Ok(match value {
+        0 => DeadlineKind::PeerResponse,
+        1 => DeadlineKind::Payment,
+        2 => DeadlineKind::WarningTxClaim,
+        i => return Err(CodecError::UnknownDeadlineKind(i)),
+    })
+}
+
+const fn deadline_state_to_i32(state: DeadlineState) -> i32 {
+    match state {
+        DeadlineState::Pending => 0,
+        DeadlineState::Approaching => 1,
+        DeadlineState::Passed => 2,
+    }
+}
+
+const fn deadline_state_from_i32(value: i32) -> Result<DeadlineState> {
+    Ok(match value {
+        0 => DeadlineState::Pending,
+        1 => DeadlineState::Approaching,
+        2 => DeadlineState::Passed,
+        i => return Err(CodecError::UnknownDeadlineState(i)),
+    })
+}
+
+const fn cancellation_to_i32(cancellation: Cancellation) -> i32 {
+    match cancellation {
+        Cancellation::Unilateral => 0,
+        Cancellation::Mutual => 1,
+    }
+}
+
+const fn cancellation_from_i32(value: i32) -> Result<Cancellation> {
+    Ok(match value {
+        0 => Cancellation::Unilateral,
+        1 => Cancellation::Mutual,
+        i => return Err(CodecError::UnknownCancellation(i)),
+    })
+}
+
+impl From<&CoinControl> for CoinControlRecord {
+    fn from(value: &CoinControl) -> Self {
+        let outpoints = |outpoints: &[([u8; 32], u32)]| outpoints.iter()
+            .map(|&(txid, vout)| OutpointRecord { txid: txid.into(), vout })
+            .collect();
+        let (change_policy, change_address) = match &value.change_policy {
+            ChangePolicy::NewAddress => (0, None),
+            ChangePolicy::ToAddress(address) => (1, Some(address.clone())),
+            ChangePolicy::NoChange => (2, None),
+        };
+        Self {
+            use_outpoints: outpoints(&value.use_outpoints),
+            avoid_outpoints: outpoints(&value.avoid_outpoints),
+            fee_strategy: match value.fee_strategy {
+                FeeStrategy::AvoidChange => 0,
+                FeeStrategy::LowestFee => 1,
+                FeeStrategy::Consolidate => 2,
+            },
+            change_policy,
+            change_address,
+        }
+    }
+}
+
+impl TryFrom<CoinControlRecord> for CoinControl {
+    type Error = CodecError;
+
+    fn try_from(value: CoinControlRecord) -> Result<Self> {
+        let outpoints = |outpoints: Vec<OutpointRecord>| outpoints.into_iter()
+            .map(|outpoint| Ok((decode_field(&outpoint.txid, "coin_control.outpoint.txid")?, outpoint.vout)))
+            .collect::<Result<_>>();
+        Ok(Self {
+            use_outpoints: outpoints(value.use_outpoints)?,
+            avoid_outpoints: outpoints(value.avoid_outpoints)?,
+            fee_strategy: match value.fee_strategy {
+                0 => FeeStrategy::AvoidChange,
+                1 => FeeStrategy::LowestFee,
+                2 => FeeStrategy::Consolidate,
+                i => return Err(CodecError::UnknownFeeStrategy(i)),
+            },
+            change_policy: match (value.change_policy, value.change_address) {
+                (0, _) => ChangePolicy::NewAddress,
+                (1, Some(address)) => ChangePolicy::ToAddress(address),
+                (1, None) => return Err(CodecError::MalformedField("coin_control.change_address")),
+                (2, _) => ChangePolicy::NoChange,
+                (i, _) => return Err(CodecError::UnknownChangePolicy(i)),
+            },
+        })
+    }
+}
+
+impl From<&Deadline> for DeadlineRecord {
+    fn from(value: &Deadline) -> Self {
+        Self {
+            kind: deadline_kind_to_i32(value.kind),
+            phase: phase_to_i32(value.phase),
+            warn_at_millis: value.warn_at.at.map(to_millis),
+            warn_height: value.warn_at.height,
+            due_at_millis: value.due.at.map(to_millis),
+            due_height: value.due.height,
+            state: deadline_state_to_i32(value.state),
+        }
+    }
+}
+
+impl TryFrom<DeadlineRecord> for Deadline {
+    type Error = CodecError;
+
+    fn try_from(value: DeadlineRecord) -> Result<Self> {
+        Ok(Self {
+            kind: deadline_kind_from_i32(value.kind)?,
+            phase: phase_from_i32(value.phase)?,
+            warn_at: DeadlineDue { at: value.warn_at_millis.map(from_millis), height: value.warn_height },
+            due: DeadlineDue { at: value.due_at_millis.map(from_millis), height: value.due_height },
+            state: deadline_state_from_i32(value.state)?,
+        })
+    }
+}
+
+impl From<&PolicyAction> for PolicyActionRecord {
+    fn from(value: &PolicyAction) -> Self {
+        let kind = match value.kind {
+            PolicyActionKind::PublishWarningTx => 0,
+            PolicyActionKind::ClaimWarningTx => 1,
+        };
+        Self { kind, at_millis: to_millis(value.at), height: value.height }
+    }
+}
+
+impl TryFrom<PolicyActionRecord> for PolicyAction {
+    type Error = CodecError;
+
+    fn try_from(value: PolicyActionRecord) -> Result<Self> {
+        let kind = match value.kind {
+            0 => PolicyActionKind::PublishWarningTx,
+            1 => PolicyActionKind::ClaimWarningTx,
+            i => return Err(CodecError::UnknownPolicyAction(i)),
+        };
+        Ok(Self { kind, at: from_millis(value.at_millis), height: value.height })
+    }
+}
+
+impl From<&PaymentReceipt> for PaymentReceiptRecord {
+    fn from(value: &PaymentReceipt) -> Self {
+        let milestone = match value.milestone {
+            PaymentMilestone::Started => 0,
+            PaymentMilestone::Received => 1,
+        };
+        Self { milestone, at_millis: to_millis(value.at), identity_signature: value.identity_signature.clone() }
+    }
+}
+
+impl TryFrom<PaymentReceiptRecord> for PaymentReceipt {
+    type Error = CodecError;
+
+    fn try_from(value: PaymentReceiptRecord) -> Result<Self> {
+        let milestone = match value.milestone {
+            0 => PaymentMilestone::Started,
+            1 => PaymentMilestone::Received,
+            i => return Err(CodecError::UnknownPaymentMilestone(i)),
+        };
+        Ok(Self { milestone, at: from_millis(value.at_millis), identity_signature: value.identity_signature })
+    }
+}
+
+impl From<&FundingInput> for FundingInputRecord {
+    fn from(value: &FundingInput) -> Self {
+        Self {
+            txid: value.txid.into(),
+            vout: value.vout,
+            amount: value.amount,
+            owner_pub_key: value.owner_pub_key.serialize().into(),
+            ownership_proof: value.ownership_proof.serialize().into(),
+        }
+    }
+}
+
+impl TryFrom<FundingInputRecord> for FundingInput {
+    type Error = CodecError;
+
+    fn try_from(value: FundingInputRecord) -> Result<Self> {
+        Ok(Self {
+            txid: decode_field(&value.txid, "funding_input.txid")?,
+            vout: value.vout,
+            amount: value.amount,
+            owner_pub_key: decode_field(&value.owner_pub_key, "funding_input.owner_pub_key")?,
+            ownership_proof: decode_field(&value.ownership_proof, "funding_input.ownership_proof")?,
+        })
+    }
+}
+
+impl TradeModelRecord {
+    fn migrate(&mut self) -> Result<()> {
+        let migrations = MIGRATIONS.get(self.version as usize..)
+            .ok_or(CodecError::UnsupportedVersion(self.version))?;
+        for migration in migrations {
+            migration(self);
+            self.version += 1;
+        }
+        Ok(())
+    }
+
+    /// Call the given function on every secret field present, along with the names of the field and
+    /// of the context it belongs to.
+    fn for_each_secret<E>(&mut self, mut f: impl FnMut(&'static str, &'static str, &mut Option<Vec<u8>>)
+        -> std::result::Result<(), E>) -> std::result::Result<(), E>
+    {
+        if let Some(key_pair) = &mut self.my_identity_key {
+            f("trade_model", "my_identity_key.prv_key", &mut key_pair.prv_key)?;
+        }
+        for (ctx_name, ctx) in [
+            ("buyer_output_key_ctx", &mut self.buyer_output_key_ctx),
+            ("seller_output_key_ctx", &mut self.seller_output_key_ctx)
+        ] {
+            let Some(ctx) = ctx else { continue };
+            for (name, key_pair) in [
+                ("my_key_share.prv_key", &mut ctx.my_key_share),
+                ("peers_key_share.prv_key", &mut ctx.peers_key_share),
+                ("aggregated_key.prv_key", &mut ctx.aggregated_key)
+            ] {
+                if let Some(key_pair) = key_pair {
+                    f(ctx_name, name, &mut key_pair.prv_key)?;
+                }
+            }
+        }
+        for (ctx_name, ctx) in [
+            ("swap_tx_input_sig_ctx", &mut self.swap_tx_input_sig_ctx),
+            ("buyers_warning_tx_buyer_input_sig_ctx", &mut self.buyers_warning_tx_buyer_input_sig_ctx),
+            ("buyers_warning_tx_seller_input_sig_ctx", &mut self.buyers_warning_tx_seller_input_sig_ctx),
+            ("sellers_warning_tx_buyer_input_sig_ctx", &mut self.sellers_warning_tx_buyer_input_sig_ctx),
+            ("sellers_warning_tx_seller_input_sig_ctx", &mut self.sellers_warning_tx_seller_input_sig_ctx),
+            ("buyers_redirect_tx_input_sig_ctx", &mut self.buyers_redirect_tx_input_sig_ctx),
+            ("sellers_redirect_tx_input_sig_ctx", &mut self.sellers_redirect_tx_input_sig_ctx)
+        ] {
+            if let Some(nonce_pair) = ctx.as_mut().and_then(|ctx| ctx.my_nonce_share.as_mut()) {
+                f(ctx_name, "my_nonce_share.sec_nonce", &mut nonce_pair.sec_nonce)?;
+            }
+        }
+        if let Some(change) = &mut self.fee_rate_change {
+            for (ctx_name, ctx) in [
+                ("fee_rate_change.buyers_warning_tx_buyer_input_sig_ctx", &mut change.buyers_warning_tx_buyer_input_sig_ctx),
+                ("fee_rate_change.buyers_warning_tx_seller_input_sig_ctx", &mut change.buyers_warning_tx_seller_input_sig_ctx),
+                ("fee_rate_change.sellers_warning_tx_buyer_input_sig_ctx", &mut change.sellers_warning_tx_buyer_input_sig_ctx),
+                ("fee_rate_change.sellers_warning_tx_seller_input_sig_ctx", &mut change.sellers_warning_tx_seller_input_sig_ctx),
+                ("fee_rate_change.buyers_redirect_tx_input_sig_ctx", &mut change.buyers_redirect_tx_input_sig_ctx),
+                ("fee_rate_change.sellers_redirect_tx_input_sig_ctx", &mut change.sellers_redirect_tx_input_sig_ctx)
+            ] {
+                if let Some(nonce_pair) = ctx.as_mut().and_then(|ctx| ctx.my_nonce_share.as_mut()) {
+                    f(ctx_name, "my_nonce_share.sec_nonce", &mut nonce_pair.sec_nonce)?;
+                }
+            }
+        }
+        if let Some(nonce_pair) = self.swap_tx_fee_bump.as_mut()
+            .and_then(|bump| bump.swap_tx_input_sig_ctx.as_mut())
+            .and_then(|ctx| ctx.my_nonce_share.as_mut()) {
+            f("swap_tx_fee_bump.swap_tx_input_sig_ctx", "my_nonce_share.sec_nonce", &mut nonce_pair.sec_nonce)?;
+        }
+        Ok(())
+    }
+
+    fn strip_secrets(&mut self) {
+        let Ok(()) = self.for_each_secret(|_, _, secret| {
+            *secret = None;
+            Ok::<_, Infallible>(())
+        });
+    }
+
+    fn seal_secrets(&mut self, cipher: &dyn SecretCipher) {
+        let trade_id = self.trade_id.clone();
+        let Ok(()) = self.for_each_secret(|ctx_name, name, secret| {
+            if let Some(plaintext) = secret {
+                *plaintext = cipher.seal(plaintext, &associated_data(&trade_id, ctx_name, name));
+            }
+            Ok::<_, Infallible>(())
+        });
+        self.secrets_encrypted = true;
+    }
+
+    fn open_secrets(&mut self, cipher: &dyn SecretCipher) -> Result<()> {
+        let trade_id = self.trade_id.clone();
+        self.for_each_secret(|ctx_name, name, secret| {
+            if let Some(ciphertext) = secret {
+                *ciphertext = cipher.open(ciphertext, &associated_data(&trade_id, ctx_name, name))
+                    .ok_or(CodecError::UndecryptableField(ctx_name))?;
+            }
+            Ok::<_, CodecError>(())
+        })?;
+        self.secrets_encrypted = false;
+        Ok(())
+    }
+}
+
+fn associated_data(trade_id: &str, ctx_name: &str, name: &str) -> Vec<u8> {
+    [trade_id, ctx_name, name].join("\0").into_bytes()
+}
+
+/// Version 0 to 1: Records written before the trade phase was tracked always decode to the initial
+/// phase, so work out the furthest phase they could have reached from the data present. (We cannot
+/// tell whether the deposit tx has been published yet, so assume it hasn't.)
+fn infer_missing_phase(record: &mut TradeModelRecord) {
+    let am_buyer = role_from_i32(record.my_role).is_ok_and(|role| matches!(role, Role::BuyerAsMaker | Role::BuyerAsTaker));
+    let [my_output_key_ctx, peer_output_key_ctx] = if am_buyer {
+        [&record.buyer_output_key_ctx, &record.seller_output_key_ctx]
+    } else {
+        [&record.seller_output_key_ctx, &record.buyer_output_key_ctx]
+    };
+    let my_sig_ctxs = if am_buyer {
+        [&record.buyers_warning_tx_buyer_input_sig_ctx, &record.buyers_warning_tx_seller_input_sig_ctx,
+            &record.buyers_redirect_tx_input_sig_ctx]
+    } else {
+        [&record.sellers_warning_tx_buyer_input_sig_ctx, &record.sellers_warning_tx_seller_input_sig_ctx,
+            &record.sellers_redirect_tx_input_sig_ctx]
+    };
+    let all_sig_ctxs = [
+        &record.swap_tx_input_sig_ctx,
+        &record.buyers_warning_tx_buyer_input_sig_ctx,
+        &record.buyers_warning_tx_seller_input_sig_ctx,
+        &record.sellers_warning_tx_buyer_input_sig_ctx,
+        &record.sellers_warning_tx_seller_input_sig_ctx,
+        &record.buyers_redirect_tx_input_sig_ctx,
+        &record.sellers_redirect_tx_input_sig_ctx,
+    ];
+
+    let phase = if my_output_key_ctx.as_ref()
+        .and_then(|ctx| ctx.aggregated_key.as_ref()).is_some_and(|key| key.prv_key.is_some()) {
+        TradePhase::Closed
+    } else if !am_buyer && record.swap_tx_input_sig_ctx.as_ref().is_some_and(|ctx| ctx.aggregated_sig.is_some()) {
+        TradePhase::SwapTxSigned
+    } else if my_sig_ctxs.iter().any(|ctx| ctx.as_ref().is_some_and(|ctx| ctx.aggregated_sig.is_some())) {
+        TradePhase::DepositTxSigned
+    } else if all_sig_ctxs.iter().any(|ctx| ctx.as_ref().is_some_and(|ctx| ctx.my_partial_sig.is_some())) {
+        TradePhase::PartialSignaturesGenerated
+    } else if all_sig_ctxs.iter().any(|ctx| ctx.as_ref().is_some_and(|ctx| ctx.my_nonce_share.is_some())) {
+        TradePhase::NonceSharesGenerated
+    } else if [my_output_key_ctx, peer_output_key_ctx].iter()
+        .any(|ctx| ctx.as_ref().is_some_and(|ctx| ctx.my_key_share.is_some())) {
+        TradePhase::KeySharesGenerated
+    } else {
+        TradePhase::Initialized
+    };
+    record.phase = phase_to_i32(phase);
+}
+
+impl From<&TradeModel> for TradeModelRecord {
+    fn from(value: &TradeModel) -> Self {
+        Self {
+            trade_id: value.trade_id.clone(),
+            my_role: role_to_i32(value.my_role),
+            phase: phase_to_i32(value.phase),
+            version: CURRENT_VERSION,
+            created_at_millis: value.created_at.map(to_millis),
+            secrets_encrypted: false,
+            revision: value.revision,
+            trade_amount: value.trade_amount,
+            buyers_security_deposit: value.buyers_security_deposit,
+            sellers_security_deposit: value.sellers_security_deposit,
+            deposit_tx_fee_rate: value.deposit_tx_fee_rate,
+            prepared_tx_fee_rate: value.prepared_tx_fee_rate,
+            my_signed_half_deposit_psbt: value.my_signed_half_deposit_psbt.clone(),
+            my_identity_key: value.my_identity_key.as_ref().map(Into::into),
+            peers_identity_pub_key: value.peers_identity_pub_key.map(|k| k.serialize().into()),
+            peers_noise_static_pub_key: value.peers_noise_static_pub_key.map(Into::into),
+            seal_peer_payloads: value.seal_peer_payloads,
+            peer_endpoint: value.peer_endpoint.clone().map(|e| PeerEndpointRecord { address: e.address, trade_id: e.trade_id }),
+            opened_by: value.opened_by.clone(),
+            deadlines: value.deadlines.iter().map(Into::into).collect(),
+            policy_overrides: Some(PolicyOverridesRecord {
+                warning_tx_after_blocks: value.policy_overrides.warning_tx_after_blocks,
+                auto_claim: value.policy_overrides.auto_claim,
+                dry_run: value.policy_overrides.dry_run,
+            }),
+            policy_actions: value.policy_actions.iter().map(Into::into).collect(),
+            deposit_tx_height: value.deposit_tx_height,
+            payment_receipts: value.payment_receipts.iter().map(Into::into).collect(),
+            redirect_receivers: value.redirect_receivers.iter()
+                .map(|(address, amount)| RedirectReceiverRecord { address: address.clone(), amount: *amount })
+                .collect(),
+            commit_to_nonces: value.commit_to_nonces,
+            signing_session: value.signing_session,
+            fee_rate_change: value.fee_rate_change.as_ref().map(|change| FeeRateChangeRecord {
+                prepared_tx_fee_rate: change.prepared_tx_fee_rate,
+                buyers_warning_tx_buyer_input_sig_ctx: Some((&change.buyers_warning_tx_buyer_input_sig_ctx).into()),
+                buyers_warning_tx_seller_input_sig_ctx: Some((&change.buyers_warning_tx_seller_input_sig_ctx).into()),
+                sellers_warning_tx_buyer_input_sig_ctx: Some((&change.sellers_warning_tx_buyer_input_sig_ctx).into()),
+                sellers_warning_tx_seller_input_sig_ctx: Some((&change.sellers_warning_tx_seller_input_sig_ctx).into()),
+                buyers_redirect_tx_input_sig_ctx: Some((&change.buyers_redirect_tx_input_sig_ctx).into()),
+                sellers_redirect_tx_input_sig_ctx: Some((&change.sellers_redirect_tx_input_sig_ctx).into()),
+            }),
+            my_funding_inputs: value.my_funding_inputs.iter().map(Into::into).collect(),
+            peers_funding_inputs: value.peers_funding_inputs.iter().map(Into::into).collect(),
+            peer_last_seen_millis: value.peer_last_seen.map(to_millis),
+            peer_unresponsive: value.peer_unresponsive,
+            swap_tx_fee_rate: value.swap_tx_fee_rate,
+            swap_tx_fee_bump: value.swap_tx_fee_bump.as_ref().map(|bump| SwapTxFeeBumpRecord {
+                swap_tx_fee_rate: bump.swap_tx_fee_rate,
+                swap_tx_input_sig_ctx: Some((&bump.swap_tx_input_sig_ctx).into()),
+            }),
+            superseded_swap_tx_sigs: value.superseded_swap_tx_sigs.iter().map(|s| s.serialize().into()).collect(),
+            dry_run: value.dry_run,
+            cancellation: value.cancellation.map(cancellation_to_i32),
+            peer_nonce_reused_from: value.peer_nonce_reused_from.clone(),
+            step_completed_at: value.step_completed_at.iter()
+                .map(|(step, at)| StepCompletionRecord { step: step.clone(), at_millis: to_millis(*at) })
+                .collect(),
+            coin_control: Some(&value.coin_control).filter(|coin_control| **coin_control != CoinControl::default())
+                .map(Into::into),
+            buyer_output_key_ctx: Some((&value.buyer_output_key_ctx).into()),
+            seller_output_key_ctx: Some((&value.seller_output_key_ctx).into()),
+            swap_tx_input_sig_ctx: Some((&value.swap_tx_input_sig_ctx).into()),
+            buyers_warning_tx_buyer_input_sig_ctx: Some((&value.buyers_warning_tx_buyer_input_sig_ctx).into()),
+            buyers_warning_tx_seller_input_sig_ctx: Some((&value.buyers_warning_tx_seller_input_sig_ctx).into()),
+            sellers_warning_tx_buyer_input_sig_ctx: Some((&value.sellers_warning_tx_buyer_input_sig_ctx).into()),
+            sellers_warning_tx_seller_input_sig_ctx: Some((&value.sellers_warning_tx_seller_input_sig_ctx).into()),
+            buyers_redirect_tx_input_sig_ctx: Some((&value.buyers_redirect_tx_input_sig_ctx).into()),
+            sellers_redirect_tx_input_sig_ctx: Some((&value.sellers_redirect_tx_input_sig_ctx).into()),
+        }
+    }
+}
+
+impl TryFrom<TradeModelRecord> for TradeModel {
+    type Error = CodecError;
+
+    fn try_from(value: TradeModelRecord) -> Result<Self> {
+        let mut trade_model = Self::new(value.trade_id, role_from_i32(value.my_role)?);
+        trade_model.phase = phase_from_i32(value.phase)?;
+        // Records written before creation times were kept are treated as if created upon loading:
+        if let Some(millis) = value.created_at_millis {
+            trade_model.created_at = Some(from_millis(millis));
+        }
+        trade_model.revision = value.revision;
+        trade_model.trade_amount = value.trade_amount;
+        trade_model.buyers_security_deposit = value.buyers_security_deposit;
+        trade_model.sellers_security_deposit = value.sellers_security_deposit;
+        trade_model.deposit_tx_fee_rate = value.deposit_tx_fee_rate;
+        trade_model.prepared_tx_fee_rate = value.prepared_tx_fee_rate;
+        trade_model.my_signed_half_deposit_psbt = value.my_signed_half_deposit_psbt;
+        trade_model.seal_peer_payloads = value.seal_peer_payloads;
+        trade_model.peer_endpoint = value.peer_endpoint.map(|e| PeerEndpoint { address: e.address, trade_id: e.trade_id });
+        trade_model.opened_by = value.opened_by;
+        trade_model.deadlines = value.deadlines.into_iter().map(TryInto::try_into).collect::<Result<_>>()?;
+        if let Some(overrides) = value.policy_overrides {
+            trade_model.policy_overrides = PolicyOverrides {
+                warning_tx_after_blocks: overrides.warning_tx_after_blocks,
+                auto_claim: overrides.auto_claim,
+                dry_run: overrides.dry_run,
+            };
+        }
+        trade_model.policy_actions = value.policy_actions.into_iter().map(TryInto::try_into).collect::<Result<_>>()?;
+        trade_model.deposit_tx_height = value.deposit_tx_height;
+        trade_model.payment_receipts = value.payment_receipts.into_iter().map(TryInto::try_into).collect::<Result<_>>()?;
+        trade_model.redirect_receivers = value.redirect_receivers.into_iter().map(|r| (r.address, r.amount)).collect();
+        trade_model.commit_to_nonces = value.commit_to_nonces;
+        trade_model.signing_session = value.signing_session;
+        trade_model.my_funding_inputs = value.my_funding_inputs.into_iter().map(TryInto::try_into).collect::<Result<_>>()?;
+        trade_model.peers_funding_inputs = value.peers_funding_inputs.into_iter().map(TryInto::try_into).collect::<Result<_>>()?;
+        trade_model.peer_last_seen = value.peer_last_seen_millis.map(from_millis);
+        trade_model.peer_unresponsive = value.peer_unresponsive;
+        trade_model.dry_run = value.dry_run;
+        trade_model.cancellation = value.cancellation.map(cancellation_from_i32).transpose()?;
+        trade_model.peer_nonce_reused_from = value.peer_nonce_reused_from;
+        trade_model.step_completed_at = value.step_completed_at.into_iter()
+            .map(|record| (record.step, from_millis(record.at_millis))).collect();
+        trade_model.coin_control = value.coin_control.map(TryInto::try_into).transpose()?.unwrap_or_default();
+        trade_model.swap_tx_fee_rate = value.swap_tx_fee_rate;
+        trade_model.superseded_swap_tx_sigs = value.superseded_swap_tx_sigs.iter()
+            .map(|s| decode_field(s, "superseded_swap_tx_sigs")).collect::<Result<_>>()?;
+        trade_model.my_identity_key = value.my_identity_key.map(TryInto::try_into).transpose()?;
+        trade_model.peers_identity_pub_key = decode_opt_field(value.peers_identity_pub_key.as_ref(),
+            "peers_identity_pub_key")?;
+        trade_model.peers_noise_static_pub_key = decode_opt_field(value.peers_noise_static_pub_key.as_ref(),
+            "peers_noise_static_pub_key")?;
+        for (record, ctx) in [
+            (value.buyer_output_key_ctx, &mut trade_model.buyer_output_key_ctx),
+            (value.seller_output_key_ctx, &mut trade_model.seller_output_key_ctx)
+        ] {
+            if let Some(record) = record {
+                record.load_into(ctx)?;
+            }
+        }
+        for (record, ctx) in [
+            (value.swap_tx_input_sig_ctx, &mut trade_model.swap_tx_input_sig_ctx),
+            (value.buyers_warning_tx_buyer_input_sig_ctx, &mut trade_model.buyers_warning_tx_buyer_input_sig_ctx),
+            (value.buyers_warning_tx_seller_input_sig_ctx, &mut trade_model.buyers_warning_tx_seller_input_sig_ctx),
+            (value.sellers_warning_tx_buyer_input_sig_ctx, &mut trade_model.sellers_warning_tx_buyer_input_sig_ctx),
+            (value.sellers_warning_tx_seller_input_sig_ctx, &mut trade_model.sellers_warning_tx_seller_input_sig_ctx),
+            (value.buyers_redirect_tx_input_sig_ctx, &mut trade_model.buyers_redirect_tx_input_sig_ctx),
+            (value.sellers_redirect_tx_input_sig_ctx, &mut trade_model.sellers_redirect_tx_input_sig_ctx)
+        ] {
+            if let Some(record) = record {
+                record.load_into(ctx)?;
+            }
+        }
+        if let Some(value) = value.fee_rate_change {
+            let mut change = FeeRateChange::new(value.prepared_tx_fee_rate, trade_model.am_buyer());
+            for (record, ctx) in [
+                (value.buyers_warning_tx_buyer_input_sig_ctx, &mut change.buyers_warning_tx_buyer_input_sig_ctx),
+                (value.buyers_warning_tx_seller_input_sig_ctx, &mut change.buyers_warning_tx_seller_input_sig_ctx),
+                (value.sellers_warning_tx_buyer_input_sig_ctx, &mut change.sellers_warning_tx_buyer_input_sig_ctx),
+                (value.sellers_warning_tx_seller_input_sig_ctx, &mut change.sellers_warning_tx_seller_input_sig_ctx),
+                (value.buyers_redirect_tx_input_sig_ctx, &mut change.buyers_redirect_tx_input_sig_ctx),
+                (value.sellers_redirect_tx_input_sig_ctx, &mut change.sellers_redirect_tx_input_sig_ctx)
+            ] {
+                if let Some(record) = record {
+                    record.load_into(ctx)?;
+                }
+            }
+            trade_model.fee_rate_change = Some(Box::new(change));
+        }
+        if let Some(value) = value.swap_tx_fee_bump {
+            let mut bump = SwapTxFeeBump { swap_tx_fee_rate: value.swap_tx_fee_rate, ..SwapTxFeeBump::default() };
+            bump.swap_tx_input_sig_ctx.am_buyer = trade_model.am_buyer();
+            if let Some(record) = value.swap_tx_input_sig_ctx {
+                record.load_into(&mut bump.swap_tx_input_sig_ctx)?;
+            }
+            trade_model.swap_tx_fee_bump = Some(Box::new(bump));
+        }
+        Ok(trade_model)
+    }
+}
+
+impl From<&KeyPair<ByOptVal>> for KeyPairRecord {
+    fn from(value: &KeyPair<ByOptVal>) -> Self {
+        Self { pub_key: value.pub_key.serialize().into(), prv_key: value.prv_key.as_ref().map(|k| k.expose_secret().serialize().into()) }
+    }
+}
+
+impl TryFrom<KeyPairRecord> for KeyPair<ByOptVal> {
+    type Error = CodecError;
+
+    fn try_from(value: KeyPairRecord) -> Result<Self> {
+        let mut key_pair = Self::from_public(decode_field(&value.pub_key, "key_pair.pub_key")?);
+        if let Some(prv_key) = decode_opt_field(value.prv_key.as_ref(), "key_pair.prv_key")? {
+            key_pair.set_prv_key(prv_key).map_err(|_| CodecError::MalformedField("key_pair.prv_key"))?;
+        }
+        Ok(key_pair)
+    }
+}
+
+impl From<&KeyCtx> for KeyCtxRecord {
+    fn from(value: &KeyCtx) -> Self {
+        Self {
+            my_key_share: value.my_key_share().map(Into::into),
+            peers_key_share: value.peers_key_share().map(Into::into),
+            aggregated_key: value.aggregated_key.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl KeyCtxRecord {
+    fn load_into(self, ctx: &mut KeyCtx) -> Result<()> {
+        *ctx.my_key_share_mut() = self.my_key_share.map(TryInto::try_into).transpose()?;
+        *ctx.peers_key_share_mut() = self.peers_key_share.map(TryInto::try_into).transpose()?;
+        ctx.aggregated_key = self.aggregated_key.map(TryInto::try_into).transpose()?;
+        if let Some(aggregated_key) = &ctx.aggregated_key {
+            let key_agg_ctx = KeyAggContext::new(ctx.get_key_shares()
+                .ok_or(CodecError::MalformedField("key_ctx.aggregated_key"))?)
+                .map_err(|_| CodecError::MalformedField("key_ctx.aggregated_key"))?;
+            if key_agg_ctx.aggregated_pubkey::<Point>() != aggregated_key.pub_key {
+                return Err(CodecError::MalformedField("key_ctx.aggregated_key"));
+            }
+            ctx.key_agg_ctx = Some(key_agg_ctx);
+        }
+        Ok(())
+    }
+}
+
+impl From<&NoncePair> for NoncePairRecord {
+    fn from(value: &NoncePair) -> Self {
+        Self {
+            pub_nonce: value.pub_nonce.serialize().into(),
+            sec_nonce: value.sec_nonce.as_ref().map(|n| n.expose_secret().serialize().into()),
+        }
+    }
+}
+
+impl TryFrom<NoncePairRecord> for NoncePair {
+    type Error = CodecError;
+
+    fn try_from(value: NoncePairRecord) -> Result<Self> {
+        let sec_nonce: Option<SecNonce> = decode_opt_field(value.sec_nonce.as_ref(), "nonce_pair.sec_nonce")?;
+        let pub_nonce = decode_field(&value.pub_nonce, "nonce_pair.pub_nonce")?;
+        if sec_nonce.as_ref().is_some_and(|n| n.public_nonce() != pub_nonce) {
+            return Err(CodecError::MalformedField("nonce_pair.pub_nonce"));
+        }
+        Ok(Self { pub_nonce, sec_nonce: sec_nonce.map(Secret::new) })
+    }
+}
+
+impl From<&SigCtx> for SigCtxRecord {
+    fn from(value: &SigCtx) -> Self {
+        Self {
+            adaptor_point: value.adaptor_point.serialize().into(),
+            my_nonce_share: value.my_nonce_share.as_ref().map(Into::into),
+            peers_nonce_share: value.peers_nonce_share.as_ref().map(|n| n.serialize().into()),
+            aggregated_nonce: value.aggregated_nonce.as_ref().map(|n| n.serialize().into()),
+            message: value.message.clone(),
+            my_partial_sig: value.my_partial_sig.map(|s| s.serialize().into()),
+            peers_partial_sig: value.peers_partial_sig.map(|s| s.serialize().into()),
+            aggregated_sig: value.aggregated_sig.map(|s| s.serialize().into()),
+            peers_nonce_commitment: value.peers_nonce_commitment.map(Into::into),
+        }
+    }
+}
+
+impl SigCtxRecord {
+    fn load_into(self, ctx: &mut SigCtx) -> Result<()> {
+        ctx.adaptor_point = decode_field::<MaybePoint>(&self.adaptor_point, "sig_ctx.adaptor_point")?;
+        ctx.my_nonce_share = self.my_nonce_share.map(TryInto::try_into).transpose()?;
+        ctx.peers_nonce_share = decode_opt_field(self.peers_nonce_share.as_ref(), "sig_ctx.peers_nonce_share")?;
+        ctx.aggregated_nonce = decode_opt_field(self.aggregated_nonce.as_ref(), "sig_ctx.aggregated_nonce")?;
+        ctx.message = self.message;
+        ctx.my_partial_sig = decode_opt_field(self.my_partial_sig.as_ref(), "sig_ctx.my_partial_sig")?;
+        ctx.peers_partial_sig = decode_opt_field(self.peers_partial_sig.as_ref(), "sig_ctx.peers_partial_sig")?;
+        ctx.aggregated_sig = decode_opt_field(self.aggregated_sig.as_ref(), "sig_ctx.aggregated_sig")?;
+        ctx.peers_nonce_commitment = self.peers_nonce_commitment.map(<[u8; 32]>::try_from).transpose()
+            .map_err(|_| CodecError::MalformedField("sig_ctx.peers_nonce_commitment"))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade_model_pair() -> (TradeModel, TradeModel) {
+        let mut buyer = TradeModel::new("buyer-trade".to_owned(), Role::BuyerAsTaker);
+        let mut seller = TradeModel::new("seller-trade".to_owned(), Role::SellerAsMaker);
+        buyer.init_my_key_shares().unwrap();
+        seller.init_my_key_shares().unwrap();
+        let [b1, b2] = buyer.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = seller.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        buyer.aggregate_key_shares().unwrap();
+        seller.aggregate_key_shares().unwrap();
+        buyer.init_my_nonce_shares().unwrap();
+        seller.init_my_nonce_shares().unwrap();
+        (buyer, seller)
+    }
+
+    /// A toy cipher, which just prepends the associated data and flips every bit of the plaintext.
+    struct ToyCipher;
+
+    impl SecretCipher for ToyCipher {
+        fn seal(&self, plaintext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+            [associated_data, &plaintext.iter().map(|b| !b).collect::<Vec<_>>()].concat()
+        }
+
+        fn open(&self, ciphertext: &[u8], associated_data: &[u8]) -> Option<Vec<u8>> {
+            Some(ciphertext.strip_prefix(associated_data)?.iter().map(|b| !b).collect())
+        }
+    }
+
+    fn encode_as_version_0(trade_model: &TradeModel) -> Vec<u8> {
+        let mut record = TradeModelRecord::from(trade_model);
+        record.version = 0;
+        record.phase = 0;
+        record.encode_to_vec()
+    }
+
+    #[test]
+    fn round_trip_current_version() {
+        let (mut buyer, _) = trade_model_pair();
+        buyer.bump_revision();
+        buyer.peer_endpoint = Some(PeerEndpoint { address: "http://peer.onion:50053".to_owned(), trade_id: "peer-trade".to_owned() });
+        buyer.opened_by = Some("127.0.0.1".to_owned());
+        buyer.deadlines.push(Deadline {
+            kind: DeadlineKind::WarningTxClaim,
+            phase: TradePhase::DepositTxPublished,
+            warn_at: DeadlineDue { at: Some(from_millis(1_000)), height: Some(900_100) },
+            due: DeadlineDue { at: None, height: Some(900_144) },
+            state: DeadlineState::Approaching,
+        });
+        buyer.policy_overrides = PolicyOverrides { warning_tx_after_blocks: Some(6), auto_claim: None, dry_run: Some(true) };
+        buyer.policy_actions.push(PolicyAction { kind: PolicyActionKind::PublishWarningTx, at: from_millis(2_000), height: None });
+        buyer.payment_receipts.push(PaymentReceipt {
+            milestone: PaymentMilestone::Started, at: from_millis(3_000), identity_signature: vec![7; 64],
+        });
+        buyer.redirect_receivers.push(("bc1qburningman".to_owned(), 230_000));
+        buyer.commit_to_nonces = true;
+        buyer.peer_last_seen = Some(from_millis(4_000));
+        buyer.peer_unresponsive = true;
+        buyer.swap_tx_fee_rate = Some(12.5);
+        buyer.signing_session = 2;
+        buyer.dry_run = true;
+        buyer.cancellation = Some(Cancellation::Mutual);
+        buyer.peer_nonce_reused_from = Some("earlier trade".to_owned());
+        buyer.step_completed_at.push(("GetNonceShares".to_owned(), from_millis(5_000)));
+        buyer.coin_control = CoinControl {
+            use_outpoints: vec![([7; 32], 1)],
+            avoid_outpoints: vec![([8; 32], 0)],
+            fee_strategy: FeeStrategy::Consolidate,
+            change_policy: ChangePolicy::ToAddress("bc1qchange".to_owned()),
+        };
+        let owner_key = secp::Scalar::random(&mut rand::thread_rng());
+        buyer.peers_funding_inputs.push(FundingInput {
+            txid: [7; 32], vout: 1, amount: 250_000, owner_pub_key: owner_key.base_point_mul(),
+            ownership_proof: crate::identity::sign(owner_key, &[0; 32]),
+        });
+        let bytes = buyer.encode_to_vec(SecretFields::Include);
+        let decoded = TradeModel::decode(&bytes, None).unwrap();
+
+        assert_eq!(decoded.phase(), TradePhase::NonceSharesGenerated);
+        assert_eq!(decoded.revision(), 1);
+        assert_eq!(decoded.deadlines, buyer.deadlines);
+        assert_eq!(decoded.policy_overrides, buyer.policy_overrides);
+        assert_eq!(decoded.policy_actions, buyer.policy_actions);
+        assert_eq!(decoded.payment_receipts, buyer.payment_receipts);
+        assert_eq!(decoded.redirect_receivers, buyer.redirect_receivers);
+        assert!(decoded.commit_to_nonces);
+        assert_eq!((decoded.peer_last_seen, decoded.peer_unresponsive), (Some(from_millis(4_000)), true));
+        assert_eq!(decoded.swap_tx_fee_rate, Some(12.5));
+        assert_eq!(decoded.signing_session(), 2);
+        assert!(decoded.dry_run);
+        assert_eq!(decoded.cancellation, Some(Cancellation::Mutual));
+        assert_eq!(decoded.peer_nonce_reused_from.as_deref(), Some("earlier trade"));
+        assert_eq!(decoded.step_completed_at, buyer.step_completed_at);
+        assert_eq!(decoded.coin_control, buyer.coin_control);
+        assert_eq!(decoded.peers_funding_inputs(), buyer.peers_funding_inputs());
+        assert_eq!(decoded.encode_to_vec(SecretFields::Include), bytes);
+    }
+
+    #[test]
+    fn round_trip_encrypted_secrets() {
+        let (mut buyer, _) = trade_model_pair();
+        // A fee rate change or swap tx fee bump under way holds secret nonces of its own:
+        buyer.phase = TradePhase::DepositTxPublished;
+        buyer.start_fee_rate_change(25.0).unwrap();
+        buyer.payment_receipts.push(PaymentReceipt {
+            milestone: PaymentMilestone::Started, at: from_millis(3_000), identity_signature: vec![7; 64],
+        });
+        buyer.start_swap_tx_fee_bump(30.0).unwrap();
+        let bytes = buyer.encode_to_vec(SecretFields::Encrypt(&ToyCipher));
+        let decoded = TradeModel::decode(&bytes, Some(&ToyCipher)).unwrap();
+
+        assert_eq!(decoded.pending_prepared_tx_fee_rate(), Some(25.0));
+        assert_eq!(decoded.pending_swap_tx_fee_rate(), Some(30.0));
+        assert_eq!(decoded.encode_to_vec(SecretFields::Include), buyer.encode_to_vec(SecretFields::Include));
+        assert!(matches!(TradeModel::decode(&bytes, None), Err(CodecError::MissingCipher)));
+    }
+
+    #[test]
+    fn reject_secrets_encrypted_for_another_trade() {
+        let (buyer, _) = trade_model_pair();
+        let mut record = TradeModelRecord::from(&buyer);
+        record.seal_secrets(&ToyCipher);
+        record.trade_id = "other-trade".to_owned();
+
+        assert!(matches!(TradeModel::decode(&record.encode_to_vec(), Some(&ToyCipher)),
+            Err(CodecError::UndecryptableField(_))));
+    }
+
+    #[test]
+    fn migrate_version_0_infers_phase() {
+        let fresh = TradeModel::new("fresh-trade".to_owned(), Role::SellerAsTaker);
+        let (buyer, seller) = trade_model_pair();
+
+        let decoded_fresh = TradeModel::decode(&encode_as_version_0(&fresh), None).unwrap();
+        let decoded_buyer = TradeModel::decode(&encode_as_version_0(&buyer), None).unwrap();
+        let decoded_seller = TradeModel::decode(&encode_as_version_0(&seller), None).unwrap();
+
+        assert_eq!(decoded_fresh.phase(), TradePhase::Initialized);
+        assert_eq!(decoded_buyer.phase(), TradePhase::NonceSharesGenerated);
+        assert_eq!(decoded_seller.phase(), TradePhase::NonceSharesGenerated);
+        assert_eq!(decoded_buyer.encode_to_vec(SecretFields::Include), buyer.encode_to_vec(SecretFields::Include));
+    }
+
+    #[test]
+    fn reject_future_version() {
+        let (buyer, _) = trade_model_pair();
+        let mut record = TradeModelRecord::from(&buyer);
+        record.version = CURRENT_VERSION + 1;
+
+        assert!(matches!(TradeModel::decode(&record.encode_to_vec(), None),
+            Err(CodecError::UnsupportedVersion(v)) if v == CURRENT_VERSION + 1));
+    }
+
+    #[test]
+    fn audit_log_drops_truncated_last_entry() {
+        let entry = AuditEntry {
+            step: "GetNonceShares".to_owned(),
+            at: from_millis(1_700_000_000_000),
+            request_digest: [1; 32],
+            response_digest: None,
+            phase: TradePhase::KeySharesGenerated,
+            error: Some("invalid peer signature".to_owned()),
+            note: None,
+            correlation_id: Some("0190b2a4-7c1e-7000-8000-000000000000".to_owned()),
+            client: Some("127.0.0.1".to_owned()),
+        };
+        let record = entry.encode_length_delimited_to_vec();
+        let log = [&record[..], &record[..], &record[..record.len() - 1]].concat();
+
+        assert_eq!(AuditEntry::decode_log(&log).unwrap(), [entry.clone(), entry]);
+    }
+}
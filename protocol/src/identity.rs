@@ -0,0 +1,236 @@
+//! Authentication of the payloads exchanged with the peer, which are relayed by the (untrusted)
+//! front-ends of either party, with a per-trade identity key of each party. The identity public
+//! keys are swapped along with the key shares, after which every payload must be signed by the
+//! sender's identity key before the receiver accepts it into its trade model.
+//!
+//! Trading the identity keys via the relays only pins them for the rest of the trade: for them to
+//! be trusted in the first place, they must be authenticated out of band (bound to the offer, say).
+
+use musig2::CompactSignature;
+use secp::{Point, Scalar};
+use sha2::{Digest as _, Sha256};
+use std::prelude::rust_2021::*;
+
+/// What a signed payload holds, so that a signature on one kind of payload cannot pass for another.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadKind {
+    KeyShares,
+    NonceShares,
+    PartialSignatures,
+    /// The buyer's partial signature on the swap tx, signed on its own as it is revealed to the
+    /// seller later than the other partial signatures.
+    SwapTxInputPartialSignature,
+    /// The encoded transcript of the trade, signed for a third party (such as an arbitrator) rather
+    /// than for the peer.
+    Transcript,
+    /// A receipt for a milestone of the off-chain payment, kept as evidence for a dispute.
+    PaymentReceipt,
+    /// The commitments to the nonce shares, sent ahead of the nonce shares when committing to them.
+    NonceCommitments,
+    /// The nonce shares (and then partial signatures) for a change of the prepared tx fee rate,
+    /// whose signature doubles as the sender's consent to the new fee rate.
+    FeeRateChange,
+    /// The nonce share (and then partial signature) for re-signing the swap tx at a higher fee
+    /// rate, whose signature doubles as the sender's consent to the new fee rate.
+    SwapTxFeeBump,
+    /// The sender's consent to cancelling the trade before its deposit tx is published, kept as
+    /// evidence that the cancellation was mutual.
+    Cancellation,
+    /// The encoded package of our fully-signed prepared txs, signed (like the transcript) for the
+    /// trader's own safekeeping rather than for the peer.
+    PreparedTxPackage,
+}
+
+impl PayloadKind {
+    const fn to_byte(self) -> u8 {
+        match self {
+            Self::KeyShares => 0,
+            Self::NonceShares => 1,
+            Self::PartialSignatures => 2,
+            Self::SwapTxInputPartialSignature => 3,
+            Self::Transcript => 4,
+            Self::PaymentReceipt => 5,
+            Self::NonceCommitments => 6,
+            Self::FeeRateChange => 7,
+            Self::SwapTxFeeBump => 8,
+            Self::Cancellation => 9,
+            Self::PreparedTxPackage => 10,
+        }
+    }
+
+    /// Whether the payload belongs to a single signing session (see
+    /// [`crate::TradeModel::reset_signing_session`]), rather than to the trade as a whole.
+    pub(crate) const fn is_per_session(self) -> bool {
+        matches!(self, Self::NonceShares | Self::NonceCommitments | Self::PartialSignatures
+            | Self::SwapTxInputPartialSignature | Self::FeeRateChange | Self::SwapTxFeeBump)
+    }
+}
+
+const TAG: &[u8] = b"MuSigTradeProtocol/payload";
+
+/// The message signed for a payload of the given kind & fields, sent by the buyer or the seller.
+/// It is a BIP 340 style tagged hash, with each field length-prefixed so that no two different
+/// lists of fields hash alike. (The trade ID needn't be included, as the identity keys are already
+/// unique to the trade.)
+pub(crate) fn payload_message(kind: PayloadKind, from_buyer: bool, fields: &[&[u8]]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(TAG);
+    let mut hasher = Sha256::new()
+        .chain_update(tag_hash)
+        .chain_update(tag_hash)
+        .chain_update([kind.to_byte(), u8::from(from_buyer)]);
+    for field in fields {
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field);
+    }
+    hasher.finalize().into()
+}
+
+const REDIRECT_RECEIVERS_TAG: &[u8] = b"MuSigTradeProtocol/redirect receivers";
+
+/// The message a mediator signs to approve the given receivers (by address & amount in sats) of
+/// the redirect tx of the trade between the holders of the given buyer's & seller's identity keys.
+/// Like a payload message, it is a tagged hash, with each address length-prefixed.
+#[must_use]
+pub fn redirect_receivers_message(buyer_identity_pub_key: Point, seller_identity_pub_key: Point,
+                                  receivers: &[(&str, u64)]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(REDIRECT_RECEIVERS_TAG);
+    let mut hasher = Sha256::new()
+        .chain_update(tag_hash)
+        .chain_update(tag_hash)
+        .chain_update(buyer_identity_pub_key.serialize())
+        .chain_update(seller_identity_pub_key.serialize());
+    for (address, amount) in receivers {
+        hasher.update((address.len() as u64).to_be_bytes());
+        hasher.update(address);
+        hasher.update(amount.to_be_bytes());
+    }
+    hasher.finalize().into()
+}
+
+const FUNDING_INPUT_TAG: &[u8] = b"MuSigTradeProtocol/funding input";
+
+/// The message the owner of a funding input of the deposit tx signs (BIP 322 style) with the key of
+/// the output it spends, to prove that it may spend it in the trade with the given ID. Like a
+/// payload message, it is a tagged hash, with the trade ID & scriptPubKey length-prefixed, so the
+/// proof is bound to both the trade and the script of the input.
+#[must_use]
+pub fn funding_input_ownership_message(trade_id: &str, script_pub_key: &[u8], txid: &[u8; 32], vout: u32,
+                                       amount: u64) -> [u8; 32] {
+    let tag_hash = Sha256::digest(FUNDING_INPUT_TAG);
+    Sha256::new()
+        .chain_update(tag_hash)
+        .chain_update(tag_hash)
+        .chain_update((trade_id.len() as u64).to_be_bytes())
+        .chain_update(trade_id)
+        .chain_update((script_pub_key.len() as u64).to_be_bytes())
+        .chain_update(script_pub_key)
+        .chain_update(txid)
+        .chain_update(vout.to_be_bytes())
+        .chain_update(amount.to_be_bytes())
+        .finalize().into()
+}
+
+const NOISE_STATIC_KEY_TAG: &[u8] = b"MuSigTradeProtocol/noise static key";
+
+/// The static X25519 private key for the Noise channel with the peer, derived from the given
+/// identity private key as a tagged hash (which the X25519 function then clamps).
+pub(crate) fn noise_static_key(identity_prv_key: Scalar) -> [u8; 32] {
+    let tag_hash = Sha256::digest(NOISE_STATIC_KEY_TAG);
+    Sha256::new()
+        .chain_update(tag_hash)
+        .chain_update(tag_hash)
+        .chain_update(identity_prv_key.serialize())
+        .finalize().into()
+}
+
+/// Sign the message of a payload with the given identity key.
+pub(crate) fn sign(prv_key: Scalar, message: &[u8; 32]) -> CompactSignature {
+    musig2::sign_solo(prv_key, message, rand::random::<[u8; 32]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FundingInput, ProtocolErrorKind, Result, Role, TradeModel};
+
+    #[test]
+    fn tampered_payload_is_rejected() -> Result<()> {
+        let buyer = TradeModel::builder("buyer-trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares()?.build();
+        let mut seller = TradeModel::builder("seller-trade".to_owned(), Role::SellerAsMaker).with_my_key_shares()?.build();
+        seller.set_peer_identity_pub_key(buyer.get_my_identity_pub_key().unwrap())?;
+
+        let signature = buyer.sign_payload(PayloadKind::NonceShares, &[b"nonce 1", b"nonce 2"])?;
+        seller.verify_peer_payload(PayloadKind::NonceShares, &[b"nonce 1", b"nonce 2"], &signature)?;
+
+        // Neither the fields, nor their boundaries, nor the kind of payload may be changed:
+        for (kind, fields) in [
+            (PayloadKind::NonceShares, [&b"nonce 1"[..], b"nonce 3"]),
+            (PayloadKind::NonceShares, [b"nonce 1n", b"once 2"]),
+            (PayloadKind::PartialSignatures, [b"nonce 1", b"nonce 2"]),
+        ] {
+            assert!(matches!(seller.verify_peer_payload(kind, &fields, &signature),
+                Err(ProtocolErrorKind::InvalidPeerSignature(k)) if k == kind));
+        }
+        // Nor may a payload signed with any other key pass, or the peer's key then be swapped:
+        let signature = seller.sign_payload(PayloadKind::NonceShares, &[b"nonce 1", b"nonce 2"])?;
+        assert!(seller.verify_peer_payload(PayloadKind::NonceShares, &[b"nonce 1", b"nonce 2"], &signature).is_err());
+        assert!(matches!(seller.set_peer_identity_pub_key(seller.get_my_identity_pub_key().unwrap()),
+            Err(ProtocolErrorKind::ChangedIdentityKey)));
+        Ok(())
+    }
+
+    #[test]
+    fn redirect_receivers_must_be_signed_by_mediator() -> Result<()> {
+        let mut buyer = TradeModel::builder("buyer-trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares()?.build();
+        let seller = TradeModel::builder("seller-trade".to_owned(), Role::SellerAsMaker).with_my_key_shares()?.build();
+        buyer.set_peer_identity_pub_key(seller.get_my_identity_pub_key().unwrap())?;
+        let mediator_key = Scalar::random(&mut rand::thread_rng());
+        let receivers = [("bc1qreceiver", 90_000), ("bc1qfee", 10_000)];
+        let message = redirect_receivers_message(buyer.get_my_identity_pub_key().unwrap(),
+            seller.get_my_identity_pub_key().unwrap(), &receivers);
+        let signature = sign(mediator_key, &message);
+        buyer.verify_redirect_receivers(mediator_key.base_point_mul(), &receivers, &signature)?;
+
+        // Neither the receivers, nor the mediator, may be swapped:
+        let tampered = [("bc1qreceiver", 100_000)];
+        assert!(matches!(buyer.verify_redirect_receivers(mediator_key.base_point_mul(), &tampered, &signature),
+            Err(ProtocolErrorKind::InvalidMediatorSignature)));
+        let other_key = Scalar::random(&mut rand::thread_rng());
+        assert!(matches!(buyer.verify_redirect_receivers(other_key.base_point_mul(), &receivers, &signature),
+            Err(ProtocolErrorKind::InvalidMediatorSignature)));
+        Ok(())
+    }
+
+    #[test]
+    fn funding_inputs_must_be_owned_for_trade_and_enough() -> Result<()> {
+        let mut buyer = TradeModel::builder("buyer-trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares()?.build();
+        (buyer.trade_amount, buyer.buyers_security_deposit, buyer.sellers_security_deposit) = (Some(200_000), Some(30_000), Some(30_000));
+        let input = |trade_id: &str, vout: u32, amount: u64| {
+            let key = Scalar::random(&mut rand::thread_rng());
+            let mut input = FundingInput {
+                txid: [7; 32], vout, amount, owner_pub_key: key.base_point_mul(), ownership_proof: sign(key, &[0; 32]),
+            };
+            let message = funding_input_ownership_message(trade_id, &input.script_pub_key(), &input.txid, vout, amount);
+            input.ownership_proof = sign(key, &message);
+            input
+        };
+
+        // The buyer may fund its security deposit from several inputs, and the seller the rest:
+        buyer.set_my_funding_inputs(vec![input("buyer-trade", 0, 10_000), input("buyer-trade", 1, 20_000)])?;
+        buyer.set_peer_funding_inputs("seller-trade", vec![input("seller-trade", 2, 150_000), input("seller-trade", 3, 80_000)])?;
+        buyer.check_funding()?;
+        assert_eq!(buyer.peers_funding_inputs().len(), 2);
+
+        // But not with too little, nor with an input proven for another trade (or script) or already spent:
+        buyer.set_peer_funding_inputs("seller-trade", vec![input("seller-trade", 2, 229_999)])?;
+        assert!(matches!(buyer.check_funding(), Err(ProtocolErrorKind::InsufficientFunding { needed: 230_000, funded: 229_999 })));
+        assert!(matches!(buyer.set_peer_funding_inputs("seller-trade", vec![input("seller-trade", 2, 100_000), input("buyer-trade", 3, 130_000)]),
+            Err(ProtocolErrorKind::InvalidOwnershipProof(1))));
+        assert!(matches!(buyer.set_peer_funding_inputs("seller-trade", vec![input("seller-trade", 1, 230_000)]),
+            Err(ProtocolErrorKind::DuplicateFundingInput(0))));
+        let mut tampered = input("seller-trade", 2, 230_000);
+        tampered.amount = 250_000;
+        assert!(matches!(buyer.set_peer_funding_inputs("seller-trade", vec![tampered]), Err(ProtocolErrorKind::InvalidOwnershipProof(0))));
+        Ok(())
+    }
+}
@@ -0,0 +1,3140 @@
+//! The `MuSig` trade protocol: the trade model of each party, built up step by step from the key
+//! shares, nonce shares and partial signatures exchanged with the peer, and the stores they are
+//! kept in.
+//!
+//! This crate knows nothing of gRPC (or any other transport). A front-end creates a [`TradeModel`]
+//! for its side of each trade with [`TradeModel::builder`], then drives it through the protocol
+//! steps, passing what each step hands out to the peer by whatever means it likes, and what the
+//! peer hands back into the next step. The [`TradePhase`] of the trade model records the furthest
+//! step reached. Each party of a trade between two in-process trade models would go as follows:
+//!
+//! ```
+//! use musig_trade_protocol::{Role, TradeModel, TradePhase};
+//!
+//! let mut buyer = TradeModel::builder("trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares()?.build();
+//! let mut seller = TradeModel::builder("trade".to_owned(), Role::SellerAsMaker).with_my_key_shares()?.build();
+//!
+//! // Exchange the key shares, then the nonce shares:
+//! let [b1, b2] = buyer.get_my_key_shares().unwrap().map(|k| k.pub_key);
+//! let [s1, s2] = seller.get_my_key_shares().unwrap().map(|k| k.pub_key);
+//! buyer.set_peer_key_shares(s1, s2);
+//! seller.set_peer_key_shares(b1, b2);
+//! for trade_model in [&mut buyer, &mut seller] {
+//!     trade_model.aggregate_key_shares()?;
+//!     trade_model.init_my_nonce_shares()?;
+//! }
+//! seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+//! buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+//!
+//! // Sign & exchange the partial signatures on each other's txs:
+//! buyer.aggregate_nonce_shares()?;
+//! seller.aggregate_nonce_shares()?;
+//! buyer.sign_partial()?;
+//! seller.sign_partial()?;
+//! seller.peer_partial_signatures_on_my_txs_mut().set(buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+//! buyer.peer_partial_signatures_on_my_txs_mut().set(seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+//! buyer.aggregate_partial_signatures()?;
+//! seller.aggregate_partial_signatures()?;
+//!
+//! assert_eq!(buyer.phase(), TradePhase::DepositTxSigned);
+//! assert_eq!(seller.phase(), TradePhase::DepositTxSigned);
+//! # Ok::<_, musig_trade_protocol::ProtocolErrorKind>(())
+//! ```
+//!
+//! The remaining steps close the trade, either cooperatively by swapping the private key shares
+//! for each other's outputs, or by the seller via the swap tx (from which the buyer recovers the
+//! seller's key share). Every fallible step fails with a [`ProtocolErrorKind`], leaving the trade
+//! model usable for a retry unless it says otherwise. Trade models are kept between steps in a
+//! [`TradeModelStore`], which may persist them with [`TradeModel::encode_to_vec`].
+//!
+//! Our key shares, nonce shares and partial signatures are made by a [`Signer`], set when building
+//! the trade model. The default [`LocalSigner`] keeps their secrets in the trade model itself, but
+//! a signer may instead keep them out of the trade daemon altogether.
+//!
+//! The secret key shares & nonces held in the trade model are each wrapped in a [`Secret`], which
+//! keeps them out of swap and debug output, and must be explicitly exposed to be read or encoded.
+//!
+//! As the payloads are passed to & from the peer by whatever relays the front-end uses, each party
+//! also has a per-trade identity key, handed out with its key shares, with which it signs every
+//! later payload, so that the peer may check it with [`TradeModel::verify_peer_payload`] before
+//! taking it in. (The doc example above skips this, for brevity.)
+
+use musig2::{AggNonce, CompactSignature, KeyAggContext, LiftedSignature, PartialSignature, PubNonce, SecNonce};
+use musig2::adaptor::AdaptorSignature;
+use musig2::errors::VerifyError;
+use rayon::prelude::*;
+use secp::{MaybePoint, MaybeScalar, Point, Scalar, G};
+use sha2::{Digest as _, Sha256};
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher as _, RandomState};
+use std::io;
+use std::iter;
+use std::mem;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::SystemTime;
+use thiserror::Error;
+
+use crate::storage::{storage_struct, ByMutRef, ByRef, ByVal, ByOptVal, Redactable, ValStorage};
+
+#[cfg(test)]
+mod bip327_tests;
+mod codec;
+mod identity;
+mod prepared_txs;
+mod secret;
+mod signer;
+#[cfg(feature = "tonic")]
+pub mod status;
+pub mod storage;
+pub mod test_vectors;
+mod transcript;
+mod tx_checks;
+pub mod tx_outputs;
+
+pub use codec::{CodecError, SecretCipher, SecretFields};
+pub use identity::{funding_input_ownership_message, redirect_receivers_message, PayloadKind};
+pub use prepared_txs::{PreparedTxPackage, SignedTxInput};
+pub use secret::{Inline, Secret};
+pub use signer::{LocalSigner, Signer, SigningSession, TestSigner};
+pub use transcript::{KeyTranscript, ReplayError, ReplayStep, SigTranscript, TradeTranscript};
+pub use tx_checks::{AssembledDepositTx, TxCheckFailure, FEE_RATE_TOLERANCE};
+
+/// The version of the trade protocol implemented by this crate: the payloads exchanged with the
+/// peer and the txs signed. It is to be bumped on any change which a peer on the old version could
+/// not follow.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Where the trade models are kept between protocol steps, each behind its own lock, along with the
+/// summaries of the archived trades. The store needn't persist anything, but a persistent store
+/// must write out each trade model when asked to with [`Self::save_trade_model`].
+pub trait TradeModelStore {
+    /// Add a new live trade model, unless the store already has a trade (live or archived) with its
+    /// ID, which is never replaced, as that would lose its key material.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`trade_exists`] if there is already a trade with the ID, or if a persistent store
+    /// could not write out the trade model.
+    fn add_trade_model(&self, trade_model: TradeModel) -> io::Result<()>;
+
+    /// The live trade model with the given ID, if any.
+    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>>;
+
+    /// Whether there is a trade with the given ID, live or archived, so that no other trade may be
+    /// added with it.
+    fn contains_trade(&self, trade_id: &str) -> bool {
+        self.get_trade_model(trade_id).is_some() || self.list_archived_trades().iter().any(|s| s.trade_id == trade_id)
+    }
+
+    /// Write out a trade model obtained from [`Self::get_trade_model`], after mutating it. This is
+    /// a no-op for stores which don't persist their trade models.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the trade model could not be written out, in which case the step which mutated it
+    /// must not be reported as done.
+    fn save_trade_model(&self, _trade_model: &TradeModel) -> io::Result<()> { Ok(()) }
+
+    /// Durably record that an irreversible step of the given trade is about to start, before any
+    /// of its effects are saved. A persistent store which finds no matching completion entry when
+    /// it next loads the trade must assume that the step was half-completed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the intent could not be durably recorded, in which case the step must not start.
+    fn log_intent(&self, _trade_id: &str, _intent: Intent) -> io::Result<()> { Ok(()) }
+
+    /// Record that an irreversible step of the given trade has completed and its effects have been
+    /// saved with [`Self::save_trade_model`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the completion could not be recorded, which only costs a needless recovery later.
+    fn log_completion(&self, _trade_id: &str, _intent: Intent) -> io::Result<()> { Ok(()) }
+
+    /// Summaries of all the live (unarchived) trade models, in trade ID order.
+    fn list_trade_models(&self) -> Vec<TradeSummary>;
+
+    /// Remove the given trade model, along with all its secrets, keeping only a summary of it.
+    /// Returns the summary, or `None` if there is no live trade model with that ID.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a persistent store could not record the archived trade.
+    fn archive_trade_model(&self, trade_id: &str) -> io::Result<Option<TradeSummary>> {
+        self.archive_trade_model_if(trade_id, |_| true)
+    }
+
+    /// Like [`Self::archive_trade_model`], but only if the given condition holds for the trade
+    /// model, checked under its lock so that it cannot change before the trade model is removed.
+    /// Returns `None` if the condition doesn't hold.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Self::archive_trade_model`].
+    fn archive_trade_model_if(&self, trade_id: &str, condition: impl FnOnce(&TradeModel) -> bool)
+        -> io::Result<Option<TradeSummary>>;
+
+    /// Summaries of all the archived trade models, in trade ID order.
+    fn list_archived_trades(&self) -> Vec<TradeSummary>;
+
+    /// Add the summary of a trade archived elsewhere, such as one imported from a snapshot.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a persistent store could not write out the summary.
+    fn add_archived_trade(&self, summary: TradeSummary) -> io::Result<()>;
+
+    /// Append an entry to the audit log of the given trade, which is kept even once the trade is
+    /// archived, and is never rewritten.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a persistent store could not durably record the entry.
+    fn log_audit_entry(&self, trade_id: &str, entry: &AuditEntry) -> io::Result<()>;
+
+    /// The audit log of the given trade, live or archived, oldest entry first. It is empty if there
+    /// is no such trade.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a persistent store could not read the log back.
+    fn get_audit_log(&self, trade_id: &str) -> io::Result<Vec<AuditEntry>>;
+}
+
+/// An irreversible protocol step, to be recorded in the store's write-ahead intent log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Intent {
+    /// Consume our secret nonces to produce partial signatures. If this is interrupted, the partial
+    /// signatures may or may not have been released, so the nonces must never be used again.
+    ConsumeNonces,
+}
+
+const DEFAULT_SHARD_COUNT: usize = 16;
+
+/// The error of adding a trade model to a store which already has a trade with the given ID, of
+/// kind [`io::ErrorKind::AlreadyExists`].
+#[must_use]
+pub fn trade_exists(trade_id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("trade with id {} already exists", trade_id))
+}
+
+type TradeModelMap = BTreeMap<String, Arc<Mutex<TradeModel>>>;
+
+/// Lock the given trade model, recovering it should a panic while it was locked (mid-step, say) have
+/// poisoned the lock. As the panic may have left the trade model half-changed, the trade is then
+/// marked failed (see [`TradeModel::fail`]), rather than being locked out for good, along with any
+/// store listing it.
+pub fn lock_trade_model(trade_model: &Mutex<TradeModel>) -> MutexGuard<'_, TradeModel> {
+    trade_model.lock().unwrap_or_else(|poisoned| {
+        trade_model.clear_poison();
+        let mut trade_model = poisoned.into_inner();
+        trade_model.fail("a step on the trade was interrupted by a panic".to_owned());
+        trade_model
+    })
+}
+
+/// A trade model store which keeps everything in memory. The live trade models are split between a
+/// number of independently locked shards by trade ID hash, so that concurrent RPCs on different
+/// trades rarely contend with each other for the map, only for the individual trade model locks.
+pub struct TradeModelMemoryStore {
+    shards: Box<[RwLock<TradeModelMap>]>,
+    hasher: RandomState,
+    archived_trades: Mutex<BTreeMap<String, TradeSummary>>,
+    audit_logs: Mutex<BTreeMap<String, Vec<AuditEntry>>>,
+}
+
+impl Default for TradeModelMemoryStore {
+    fn default() -> Self {
+        Self::with_shard_count(DEFAULT_SHARD_COUNT)
+    }
+}
+
+impl TradeModelMemoryStore {
+    pub fn with_shard_count(shard_count: usize) -> Self {
+        Self {
+            shards: iter::repeat_with(RwLock::default).take(shard_count.max(1)).collect(),
+            hasher: RandomState::new(),
+            archived_trades: Mutex::default(),
+            audit_logs: Mutex::default(),
+        }
+    }
+
+    fn is_archived(&self, trade_id: &str) -> bool {
+        self.archived_trades.lock().unwrap_or_else(PoisonError::into_inner).contains_key(trade_id)
+    }
+
+    fn shard(&self, trade_id: &str) -> &RwLock<TradeModelMap> {
+        let hash = usize::try_from(self.hasher.hash_one(trade_id) % self.shards.len() as u64).unwrap();
+        &self.shards[hash]
+    }
+}
+
+impl TradeModelStore for TradeModelMemoryStore {
+    fn add_trade_model(&self, trade_model: TradeModel) -> io::Result<()> {
+        // Check for the ID under the shard lock, which a trade model is only archived under, so that
+        // the ID is never free in between:
+        let mut shard = self.shard(&trade_model.trade_id).write().unwrap_or_else(PoisonError::into_inner);
+        if shard.contains_key(&trade_model.trade_id) || self.is_archived(&trade_model.trade_id) {
+            return Err(trade_exists(&trade_model.trade_id));
+        }
+        shard.insert(trade_model.trade_id.clone(), Arc::new(Mutex::new(trade_model)));
+        drop(shard);
+        Ok(())
+    }
+
+    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>> {
+        self.shard(trade_id).read().unwrap_or_else(PoisonError::into_inner).get(trade_id).map(Arc::clone)
+    }
+
+    fn contains_trade(&self, trade_id: &str) -> bool {
+        self.get_trade_model(trade_id).is_some() || self.is_archived(trade_id)
+    }
+
+    fn list_trade_models(&self) -> Vec<TradeSummary> {
+        let trade_models: Vec<_> = self.shards.iter()
+            .flat_map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner).values().map(Arc::clone).collect::<Vec<_>>())
+            .collect();
+        let mut summaries: Vec<_> = trade_models.iter()
+            .map(|trade_model| lock_trade_model(trade_model).summarize(None))
+            .collect();
+        summaries.sort_unstable_by(|a, b| a.trade_id.cmp(&b.trade_id));
+        summaries
+    }
+
+    fn archive_trade_model_if(&self, trade_id: &str, condition: impl FnOnce(&TradeModel) -> bool)
+        -> io::Result<Option<TradeSummary>>
+    {
+        let Some(trade_model) = self.get_trade_model(trade_id) else {
+            return Ok(None);
+        };
+        // Lock the trade model, to wait for any in-progress step to finish with it, and keep it
+        // locked until it is detached, so that no further steps can start in the meantime:
+        let trade_model_guard = lock_trade_model(&trade_model);
+        if !condition(&trade_model_guard) {
+            return Ok(None);
+        }
+        let summary = trade_model_guard.summarize(Some(SystemTime::now()));
+        let mut shard = self.shard(trade_id).write().unwrap_or_else(PoisonError::into_inner);
+        // Check that the trade model wasn't archived while we waited:
+        if !shard.get(trade_id).is_some_and(|m| Arc::ptr_eq(m, &trade_model)) {
+            return Ok(None);
+        }
+        shard.remove(trade_id);
+        // Archive it before unlocking the shard, so that no new trade model can take its ID:
+        self.add_archived_trade(summary.clone())?;
+        drop(shard);
+        drop(trade_model_guard);
+        Ok(Some(summary))
+    }
+
+    fn list_archived_trades(&self) -> Vec<TradeSummary> {
+        self.archived_trades.lock().unwrap_or_else(PoisonError::into_inner).values().cloned().collect()
+    }
+
+    fn add_archived_trade(&self, summary: TradeSummary) -> io::Result<()> {
+        self.archived_trades.lock().unwrap_or_else(PoisonError::into_inner).insert(summary.trade_id.clone(), summary);
+        Ok(())
+    }
+
+    fn log_audit_entry(&self, trade_id: &str, entry: &AuditEntry) -> io::Result<()> {
+        self.audit_logs.lock().unwrap_or_else(PoisonError::into_inner).entry(trade_id.to_owned()).or_default().push(entry.clone());
+        Ok(())
+    }
+
+    fn get_audit_log(&self, trade_id: &str) -> io::Result<Vec<AuditEntry>> {
+        Ok(self.audit_logs.lock().unwrap_or_else(PoisonError::into_inner).get(trade_id).cloned().unwrap_or_default())
+    }
+}
+
+/// A compact, secret-free record of a trade, as kept for it once archived.
+#[derive(Clone, Debug)]
+pub struct TradeSummary {
+    pub trade_id: String,
+    pub my_role: Role,
+    /// The furthest phase the trade reached: [`TradePhase::Closed`] for a completed trade, and
+    /// anything earlier for a trade that was archived while live or aborted.
+    pub phase: TradePhase,
+    pub trade_amount: Option<u64>,
+    pub buyers_security_deposit: Option<u64>,
+    pub sellers_security_deposit: Option<u64>,
+    pub archived_at: Option<SystemTime>,
+    pub revision: u64,
+    /// How the trade was cancelled before its deposit tx was published, if it was.
+    pub cancellation: Option<Cancellation>,
+}
+
+/// An entry of the audit log of a trade, recorded as each protocol step is run on it (or fails), so
+/// that disputes & bug reports can reconstruct exactly what happened. It holds no secrets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuditEntry {
+    /// The name of the step, which is that of the RPC running it.
+    pub step: String,
+    pub at: SystemTime,
+    /// The SHA-256 digest of the (encoded) request message of the step.
+    pub request_digest: [u8; 32],
+    /// The SHA-256 digest of the (encoded) response message of the step, if it succeeded.
+    pub response_digest: Option<[u8; 32]>,
+    /// The phase the trade was left in by the step.
+    pub phase: TradePhase,
+    /// The error the step failed with, if it did.
+    pub error: Option<String>,
+    /// Anything else about the step worth keeping as evidence, such as the consent of either party
+    /// to a change of the trade's terms.
+    pub note: Option<String>,
+    /// The correlation ID of the call which ran the step, if known, by which to find the front-end's
+    /// log lines about it.
+    pub correlation_id: Option<String>,
+    /// The identity of the client which made the call, if known, in the form of
+    /// [`TradeModel::opened_by`].
+    pub client: Option<String>,
+}
+
+#[derive(Default)]
+#[expect(clippy::struct_excessive_bools, reason = "the flags are independent options & states of the trade, not a state machine")]
+pub struct TradeModel {
+    trade_id: String,
+    my_role: Role,
+    phase: TradePhase,
+    created_at: Option<SystemTime>,
+    revision: u64,
+    signer: Option<Arc<dyn Signer>>,
+    /// Why the trade failed, if it did. This is only kept in memory, as a restarted daemon takes the
+    /// trade up again as last saved, from before the step which failed it.
+    failure: Option<String>,
+    pub trade_amount: Option<u64>,
+    pub buyers_security_deposit: Option<u64>,
+    pub sellers_security_deposit: Option<u64>,
+    pub deposit_tx_fee_rate: Option<f64>,
+    pub prepared_tx_fee_rate: Option<f64>,
+    /// Our half of the deposit tx as a PSBT, with our funding inputs signed outside of the daemon
+    /// (by an HWI-compatible hardware wallet, say), if it was handed off to be signed that way.
+    pub my_signed_half_deposit_psbt: Option<Vec<u8>>,
+    /// Our coin control over the funding of our half of the deposit tx, as set before our funding
+    /// inputs, which are checked against it.
+    pub coin_control: CoinControl,
+    /// Whether the payloads for the peer are to be sealed (encrypted to the peer's identity key),
+    /// rather than passed to the relaying front-end in the clear, as agreed with the peer.
+    pub seal_peer_payloads: bool,
+    /// The peer's daemon, if the payloads for the peer are exchanged with it directly, rather than
+    /// relayed by the front-ends.
+    pub peer_endpoint: Option<PeerEndpoint>,
+    /// The client which opened the trade (as told apart by the server, say by IP address), to
+    /// count the trade against its quota of open trades, and as the only client which may make
+    /// calls for it.
+    pub opened_by: Option<String>,
+    /// The protocol deadlines of the trade, as set & fired by the daemon, kept with the trade model
+    /// so that they outlive a restart.
+    pub deadlines: Vec<Deadline>,
+    pub policy_overrides: PolicyOverrides,
+    /// The automatic protocol responses taken for the trade so far, in order, each of which is
+    /// only ever taken once.
+    pub policy_actions: Vec<PolicyAction>,
+    /// The height of the block the deposit tx confirmed in, once seen.
+    pub deposit_tx_height: Option<u32>,
+    /// Our receipts for the off-chain payment milestones confirmed so far, at most one of each.
+    pub payment_receipts: Vec<PaymentReceipt>,
+    /// The receivers (by address & amount in sats) our redirect tx pays out to, as of when we
+    /// signed it.
+    pub redirect_receivers: Vec<(String, u64)>,
+    /// Whether the nonce shares are to be committed to (by hash) before either party reveals its
+    /// own, as agreed with the peer, so that neither (nor a relay) may pick theirs after seeing the
+    /// other's.
+    pub commit_to_nonces: bool,
+    /// When we last took in a payload from the peer, in a protocol step which succeeded, if ever.
+    pub peer_last_seen: Option<SystemTime>,
+    /// Whether the peer has been announced as unresponsive, for having sent nothing within the
+    /// response window since it was last seen (or the trade was opened), and not been seen since.
+    pub peer_unresponsive: bool,
+    /// The fee rate the swap tx was last re-signed at, if it has been, which it then pays in place
+    /// of the prepared tx fee rate.
+    pub swap_tx_fee_rate: Option<f64>,
+    /// Whether the trade is a dry run, for client development: played through with test keys (as
+    /// made by a [`TestSigner`]) and a synthetic chain, with no real funds at stake.
+    pub dry_run: bool,
+    /// How the trade was cancelled, if it was, after which it is only ever archived.
+    pub cancellation: Option<Cancellation>,
+    /// The id of the (other) trade in which the peer first sent a public nonce which it has since
+    /// sent again in this one, if it has: a sign of a broken or malicious peer, whose key shares may
+    /// be leaked by the signatures made with it.
+    pub peer_nonce_reused_from: Option<String>,
+    /// When each protocol step on the trade (by the name of its RPC) first succeeded, in order, for
+    /// monitoring how long trades take to get through the protocol.
+    pub step_completed_at: Vec<(String, SystemTime)>,
+    signing_session: u32,
+    fee_rate_change: Option<Box<FeeRateChange>>,
+    swap_tx_fee_bump: Option<Box<SwapTxFeeBump>>,
+    /// The (adaptor) signatures on the earlier versions of the swap tx, replaced by re-signing it,
+    /// any of which the seller might yet publish.
+    superseded_swap_tx_sigs: Vec<AdaptorSignature>,
+    my_funding_inputs: Vec<FundingInput>,
+    peers_funding_inputs: Vec<FundingInput>,
+    my_identity_key: Option<KeyPair<ByOptVal>>,
+    peers_identity_pub_key: Option<Point>,
+    /// The peer's static X25519 key for the Noise channel sealing the payloads between us, if it
+    /// seals them, as received (signed) with its key shares.
+    peers_noise_static_pub_key: Option<[u8; 32]>,
+    buyer_output_key_ctx: KeyCtx,
+    seller_output_key_ctx: KeyCtx,
+    swap_tx_input_sig_ctx: SigCtx,
+    buyers_warning_tx_buyer_input_sig_ctx: SigCtx,
+    buyers_warning_tx_seller_input_sig_ctx: SigCtx,
+    sellers_warning_tx_buyer_input_sig_ctx: SigCtx,
+    sellers_warning_tx_seller_input_sig_ctx: SigCtx,
+    buyers_redirect_tx_input_sig_ctx: SigCtx,
+    sellers_redirect_tx_input_sig_ctx: SigCtx,
+}
+
+/// Where to reach the peer's daemon, to exchange the payloads for the peer with it directly.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerEndpoint {
+    /// The URL of the peer service of the peer's daemon, which may be an onion service.
+    pub address: String,
+    /// The peer's ID for the trade, which needn't match ours.
+    pub trade_id: String,
+}
+
+/// One of a party's funding inputs to the deposit tx, of which it may have any number: a P2TR
+/// output of its wallet, with a BIP 340 signature by the key of the output on its
+/// [`funding_input_ownership_message`], to prove that the party may spend it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FundingInput {
+    pub txid: [u8; 32],
+    pub vout: u32,
+    /// The amount of the output in sats, which is signed off by the ownership proof, but (as yet)
+    /// not checked against the UTXO set.
+    pub amount: u64,
+    pub owner_pub_key: Point,
+    pub ownership_proof: CompactSignature,
+}
+
+impl FundingInput {
+    /// The scriptPubKey of the output spent: a segwit v1 program of the x-only owner key.
+    #[must_use]
+    pub fn script_pub_key(&self) -> [u8; 34] {
+        let mut script = [0; 34];
+        script[..2].copy_from_slice(&[0x51, 0x20]);
+        script[2..].copy_from_slice(&self.owner_pub_key.serialize_xonly());
+        script
+    }
+}
+
+/// Our coin control over the funding of our half of the deposit tx, for a trader keeping the funding
+/// of its trades apart from its other coins. Any funding inputs given are checked against it, and
+/// it is kept with the trade for the wallet's coin selector, should our half of the deposit tx be
+/// funded without them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CoinControl {
+    /// The outpoints (by txid & vout) which must fund our half of the deposit tx.
+    pub use_outpoints: Vec<([u8; 32], u32)>,
+    /// The outpoints which must not.
+    pub avoid_outpoints: Vec<([u8; 32], u32)>,
+    pub fee_strategy: FeeStrategy,
+    pub change_policy: ChangePolicy,
+}
+
+/// How the coin selector trades off the fee of the deposit tx against the UTXOs left in the wallet.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FeeStrategy {
+    /// Avoid a change output where the UTXOs allow, as by branch and bound.
+    #[default]
+    AvoidChange,
+    /// Spend as few inputs as possible, for the lowest fee now.
+    LowestFee,
+    /// Spend as many (small) UTXOs as needed, to consolidate the wallet while fees are low.
+    Consolidate,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum ChangePolicy {
+    /// Pay any change to a fresh internal address of the wallet.
+    #[default]
+    NewAddress,
+    /// Pay any change to the given address.
+    ToAddress(String),
+    /// Leave no change output, giving up any change to the fee.
+    NoChange,
+}
+
+impl CoinControl {
+    /// Check the given funding inputs (if any are given) against the outpoints to use and to avoid.
+    ///
+    /// # Errors
+    ///
+    /// Fails if an outpoint is both to use and to avoid, or if the inputs spend one to avoid or
+    /// leave out one to use.
+    pub fn check(&self, inputs: &[FundingInput]) -> Result<()> {
+        if let Some(i) = self.avoid_outpoints.iter().position(|outpoint| self.use_outpoints.contains(outpoint)) {
+            return Err(ProtocolErrorKind::ConflictingCoinControl(i));
+        }
+        if inputs.is_empty() {
+            return Ok(());
+        }
+        if let Some(i) = inputs.iter().position(|input| self.avoid_outpoints.contains(&(input.txid, input.vout))) {
+            return Err(ProtocolErrorKind::AvoidedFundingInput(i));
+        }
+        if let Some(i) = self.use_outpoints.iter()
+            .position(|&outpoint| !inputs.iter().any(|input| (input.txid, input.vout) == outpoint))
+        {
+            return Err(ProtocolErrorKind::UnusedCoinControlOutpoint(i));
+        }
+        Ok(())
+    }
+}
+
+/// A protocol deadline of a trade, by which the trade should have moved on from the phase it was in
+/// when the deadline was set.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Deadline {
+    pub kind: DeadlineKind,
+    pub phase: TradePhase,
+    /// When to announce that the deadline is approaching.
+    pub warn_at: DeadlineDue,
+    pub due: DeadlineDue,
+    pub state: DeadlineState,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeadlineKind {
+    /// The peer's payload for the next protocol step, whether relayed by the client or delivered by
+    /// the peer's daemon.
+    PeerResponse,
+    /// The buyer's payment, once the deposit tx is published.
+    Payment,
+    /// The end of the window, counted from the deposit tx's confirmation, after which a warning tx
+    /// may be claimed.
+    WarningTxClaim,
+}
+
+/// A moment given by the wall clock, the block height or both, which is reached as soon as either
+/// is.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DeadlineDue {
+    pub at: Option<SystemTime>,
+    pub height: Option<u32>,
+}
+
+impl DeadlineDue {
+    /// Whether the moment has been reached, as of the given time & block height (if known).
+    #[must_use]
+    pub fn is_reached(&self, now: SystemTime, height: Option<u32>) -> bool {
+        self.at.is_some_and(|at| at <= now) || self.height.zip(height).is_some_and(|(due, height)| due <= height)
+    }
+}
+
+/// How far a deadline has got. A deadline only ever moves forwards through these, announcing each
+/// move once.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub enum DeadlineState {
+    #[default] Pending,
+    Approaching,
+    Passed,
+}
+
+/// Overrides for one trade of the daemon's policy of automatic protocol responses, each falling back
+/// to the daemon's setting if `None`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PolicyOverrides {
+    /// How many blocks past a missed payment deadline to publish our warning tx, or 0 never to.
+    pub warning_tx_after_blocks: Option<u32>,
+    /// Whether to claim the deposit once the timelock of our published warning tx expires.
+    pub auto_claim: Option<bool>,
+    /// Whether to only announce the responses which would be taken, without taking them.
+    pub dry_run: Option<bool>,
+}
+
+/// An automatic protocol response taken by the daemon, with the time & block height (if known) at
+/// which it was taken.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PolicyAction {
+    pub kind: PolicyActionKind,
+    pub at: SystemTime,
+    pub height: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PolicyActionKind {
+    PublishWarningTx,
+    ClaimWarningTx,
+}
+
+/// A signed receipt for a milestone of the off-chain payment, as confirmed by our side of the trade
+/// at the given time: by the buyer, on starting payment, and by the seller, on receiving it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaymentReceipt {
+    pub milestone: PaymentMilestone,
+    pub at: SystemTime,
+    /// Our identity signature on the milestone & time, as a payload of kind
+    /// [`PayloadKind::PaymentReceipt`] with [`PaymentReceipt::signed_fields`].
+    pub identity_signature: Vec<u8>,
+}
+
+impl PaymentReceipt {
+    /// The fields signed for a receipt for the given milestone at the given time (in Unix millis).
+    #[must_use]
+    pub const fn signed_fields(milestone: PaymentMilestone, at_millis: u64) -> [[u8; 8]; 2] {
+        let milestone: u64 = match milestone {
+            PaymentMilestone::Started => 0,
+            PaymentMilestone::Received => 1,
+        };
+        [milestone.to_be_bytes(), at_millis.to_be_bytes()]
+    }
+}
+
+/// How a trade was cancelled before its deposit tx was published (see
+/// [`TradeModel::cancel_before_deposit`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Cancellation {
+    /// By us alone, without the peer's consent, as when the peer walked away.
+    Unilateral,
+    /// By both parties, with the peer's signed consent in hand.
+    Mutual,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PaymentMilestone {
+    Started,
+    Received,
+}
+
+/// How far the peer's partial signature on the swap tx has got to us: the seller only gets the
+/// buyer's once it has been promised (redacted from the buyer's other partial signatures) and later
+/// revealed, after the buyer has started payment.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SwapTxSignatureState {
+    /// The peer's partial signatures haven't been taken in yet.
+    #[default] NotExchanged,
+    /// The peer's other partial signatures have been taken in, with the swap tx one redacted.
+    Promised,
+    Revealed,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Role {
+    #[default] SellerAsMaker,
+    SellerAsTaker,
+    BuyerAsMaker,
+    BuyerAsTaker,
+}
+
+impl Role {
+    /// The role the peer must take for a trade with us in this role: the other side of the trade
+    /// (buyer or seller) and of the offer (maker or taker).
+    #[must_use]
+    pub const fn peers_role(self) -> Self {
+        match self {
+            Self::SellerAsMaker => Self::BuyerAsTaker,
+            Self::SellerAsTaker => Self::BuyerAsMaker,
+            Self::BuyerAsMaker => Self::SellerAsTaker,
+            Self::BuyerAsTaker => Self::SellerAsMaker,
+        }
+    }
+}
+
+/// The furthest step of the trade protocol reached so far. The phases are ordered, and a trade
+/// model only ever moves forwards through them, though not every phase applies to every role (only
+/// the seller signs the swap tx, for example).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub enum TradePhase {
+    #[default] Initialized,
+    KeySharesGenerated,
+    NonceSharesGenerated,
+    PartialSignaturesGenerated,
+    DepositTxSigned,
+    DepositTxPublished,
+    SwapTxSigned,
+    Closed,
+}
+
+storage_struct! {
+    pub struct ExchangedNonces<'a, S>(PubNonce) {
+        pub swap_tx_input_nonce_share,
+        pub buyers_warning_tx_buyer_input_nonce_share,
+        pub buyers_warning_tx_seller_input_nonce_share,
+        pub sellers_warning_tx_buyer_input_nonce_share,
+        pub sellers_warning_tx_seller_input_nonce_share,
+        pub buyers_redirect_tx_input_nonce_share,
+        pub sellers_redirect_tx_input_nonce_share,
+    }
+}
+
+storage_struct! {
+    pub struct ExchangedNonceCommitments<'a, S>([u8; 32]) {
+        pub swap_tx_input_nonce_commitment,
+        pub buyers_warning_tx_buyer_input_nonce_commitment,
+        pub buyers_warning_tx_seller_input_nonce_commitment,
+        pub sellers_warning_tx_buyer_input_nonce_commitment,
+        pub sellers_warning_tx_seller_input_nonce_commitment,
+        pub buyers_redirect_tx_input_nonce_commitment,
+        pub sellers_redirect_tx_input_nonce_commitment,
+    }
+}
+
+storage_struct! {
+    pub struct ExchangedSigs<'a, S>(PartialSignature) {
+        pub peers_warning_tx_buyer_input_partial_signature,
+        pub peers_warning_tx_seller_input_partial_signature,
+        pub peers_redirect_tx_input_partial_signature,
+        pub swap_tx_input_partial_signature: Redactable,
+    }
+}
+
+storage_struct! {
+    pub struct ExchangedPreparedTxNonces<'a, S>(PubNonce) {
+        pub buyers_warning_tx_buyer_input_nonce_share,
+        pub buyers_warning_tx_seller_input_nonce_share,
+        pub sellers_warning_tx_buyer_input_nonce_share,
+        pub sellers_warning_tx_seller_input_nonce_share,
+        pub buyers_redirect_tx_input_nonce_share,
+        pub sellers_redirect_tx_input_nonce_share,
+    }
+}
+
+/// A public key (share), with its private key held as given by the storage type: always present by
+/// default, but possibly not yet known for the peer's key shares and the aggregated keys.
+pub struct KeyPair<PrvKey: ValStorage = ByVal> {
+    pub pub_key: Point,
+    pub prv_key: PrvKey::Store<Secret<Scalar>>,
+}
+
+/// A public nonce share, with its secret nonce until the latter is used (or discarded).
+pub struct NoncePair {
+    pub pub_nonce: PubNonce,
+    pub sec_nonce: Option<Secret<SecNonce>>,
+}
+
+/// The index of the buyer's & seller's key shares in the 2-of-2 outputs between them.
+const BUYER_INDEX: usize = 0;
+const SELLER_INDEX: usize = 1;
+
+/// The key shares of the signers of an n-of-n multisig output, in key aggregation order, of which
+/// ours is the one at `my_index`. Every output of a trade so far is 2-of-2, between the buyer and
+/// the seller, in that order.
+#[derive(Default)]
+struct KeyCtx {
+    my_index: usize,
+    key_shares: Vec<Option<KeyPair<ByOptVal>>>,
+    aggregated_key: Option<KeyPair<ByOptVal>>,
+    key_agg_ctx: Option<KeyAggContext>,
+}
+
+// TODO: For safety, this should hold a reference to the KeyCtx our nonce & signature share (& final
+//  aggregation) are built from, so that we don't have to pass it repeatedly as a method parameter.
+#[derive(Default)]
+struct SigCtx {
+    am_buyer: bool,
+    adaptor_point: MaybePoint,
+    my_nonce_share: Option<NoncePair>,
+    peers_nonce_share: Option<PubNonce>,
+    peers_nonce_commitment: Option<[u8; 32]>,
+    aggregated_nonce: Option<AggNonce>,
+    message: Option<Vec<u8>>,
+    my_partial_sig: Option<PartialSignature>,
+    peers_partial_sig: Option<PartialSignature>,
+    aggregated_sig: Option<AdaptorSignature>,
+}
+
+/// A change of the prepared tx fee rate agreed with the peer mid-trade, for which the warning &
+/// redirect txs are re-signed in signing contexts of their own. These only replace the trade's once
+/// the change is complete, so that the txs signed at the old fee rate stay in force until then.
+#[derive(Default)]
+struct FeeRateChange {
+    prepared_tx_fee_rate: f64,
+    buyers_warning_tx_buyer_input_sig_ctx: SigCtx,
+    buyers_warning_tx_seller_input_sig_ctx: SigCtx,
+    sellers_warning_tx_buyer_input_sig_ctx: SigCtx,
+    sellers_warning_tx_seller_input_sig_ctx: SigCtx,
+    buyers_redirect_tx_input_sig_ctx: SigCtx,
+    sellers_redirect_tx_input_sig_ctx: SigCtx,
+}
+
+/// A re-signing of the swap tx at a higher fee rate, agreed with the peer before the swap tx is
+/// published, in a signing context of its own. The warning & redirect txs are left alone, and the
+/// re-signed swap tx only replaces the trade's once the bump is complete.
+#[derive(Default)]
+struct SwapTxFeeBump {
+    swap_tx_fee_rate: f64,
+    swap_tx_input_sig_ctx: SigCtx,
+}
+
+/// A builder for a new [`TradeModel`], obtained from [`TradeModel::builder`].
+#[must_use]
+pub struct TradeModelBuilder {
+    trade_model: TradeModel,
+}
+
+impl TradeModelBuilder {
+    /// Set the creation time, instead of the current time.
+    pub const fn created_at(mut self, created_at: SystemTime) -> Self {
+        self.trade_model.created_at = Some(created_at);
+        self
+    }
+
+    pub const fn trade_amount(mut self, trade_amount: u64) -> Self {
+        self.trade_model.trade_amount = Some(trade_amount);
+        self
+    }
+
+    pub const fn security_deposits(mut self, buyers_security_deposit: u64, sellers_security_deposit: u64) -> Self {
+        self.trade_model.buyers_security_deposit = Some(buyers_security_deposit);
+        self.trade_model.sellers_security_deposit = Some(sellers_security_deposit);
+        self
+    }
+
+    pub const fn fee_rates(mut self, deposit_tx_fee_rate: f64, prepared_tx_fee_rate: f64) -> Self {
+        self.trade_model.deposit_tx_fee_rate = Some(deposit_tx_fee_rate);
+        self.trade_model.prepared_tx_fee_rate = Some(prepared_tx_fee_rate);
+        self
+    }
+
+    /// Use the given signer for our key shares, nonce shares and partial signatures, instead of
+    /// the default [`LocalSigner`].
+    pub fn signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        self.trade_model.signer = Some(signer);
+        self
+    }
+
+    /// Generate our key shares up front, as the first protocol step would, so that the trade model
+    /// is built ready to hand them out with [`TradeModel::get_my_key_shares`]. This should be done
+    /// after setting the signer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the signer could not generate the key shares.
+    pub fn with_my_key_shares(mut self) -> Result<Self> {
+        self.trade_model.init_my_key_shares()?;
+        Ok(self)
+    }
+
+    #[must_use]
+    pub fn build(self) -> TradeModel {
+        self.trade_model
+    }
+}
+
+impl TradeModel {
+    /// A fresh trade model, created now, with nothing but the trade ID and our role set. This is
+    /// short for `TradeModel::builder(trade_id, my_role).build()`.
+    #[must_use]
+    pub fn new(trade_id: String, my_role: Role) -> Self {
+        Self::builder(trade_id, my_role).build()
+    }
+
+    /// Start building a fresh trade model, created now, for our side of the given trade.
+    pub fn builder(trade_id: String, my_role: Role) -> TradeModelBuilder {
+        let mut trade_model = Self { trade_id, my_role, created_at: Some(SystemTime::now()), ..Default::default() };
+        let am_buyer = trade_model.am_buyer();
+        trade_model.buyer_output_key_ctx = KeyCtx::between_buyer_and_seller(am_buyer);
+        trade_model.seller_output_key_ctx = KeyCtx::between_buyer_and_seller(am_buyer);
+        trade_model.swap_tx_input_sig_ctx.am_buyer = am_buyer;
+        trade_model.buyers_warning_tx_buyer_input_sig_ctx.am_buyer = am_buyer;
+        trade_model.buyers_warning_tx_seller_input_sig_ctx.am_buyer = am_buyer;
+        trade_model.sellers_warning_tx_buyer_input_sig_ctx.am_buyer = am_buyer;
+        trade_model.sellers_warning_tx_seller_input_sig_ctx.am_buyer = am_buyer;
+        trade_model.buyers_redirect_tx_input_sig_ctx.am_buyer = am_buyer;
+        trade_model.sellers_redirect_tx_input_sig_ctx.am_buyer = am_buyer;
+        TradeModelBuilder { trade_model }
+    }
+
+    #[must_use]
+    pub fn trade_id(&self) -> &str {
+        &self.trade_id
+    }
+
+    #[must_use]
+    pub const fn my_role(&self) -> Role {
+        self.my_role
+    }
+
+    #[must_use]
+    pub const fn phase(&self) -> TradePhase {
+        self.phase
+    }
+
+    /// When the trade model was created, if known. (Trade models saved by old versions lack this.)
+    #[must_use]
+    pub const fn created_at(&self) -> Option<SystemTime> {
+        self.created_at
+    }
+
+    /// The number of changes to the trade model saved so far, for optimistic concurrency control.
+    #[must_use]
+    pub const fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Record a change to the trade model, to be called once per step just before it is saved.
+    pub const fn bump_revision(&mut self) {
+        self.revision += 1;
+    }
+
+    /// Why the trade failed, if it did, after which no further step may be run on it (though it may
+    /// still be looked up).
+    #[must_use]
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    /// Mark the trade failed, for the given reason, discarding our unused secret nonces, as the step
+    /// which failed it may have got as far as using some of them. Our key shares are kept, as they
+    /// may yet be needed to recover the deposit.
+    pub fn fail(&mut self, reason: String) {
+        self.discard_unused_sec_nonces();
+        self.failure.get_or_insert(reason);
+    }
+
+    /// The number of times the signing session has been reset with [`Self::reset_signing_session`].
+    #[must_use]
+    pub const fn signing_session(&self) -> u32 {
+        self.signing_session
+    }
+
+    /// Use the given signer from now on, as for [`TradeModelBuilder::signer`]. This is for trade
+    /// models loaded from a store, which don't record their signer.
+    pub fn set_signer(&mut self, signer: Arc<dyn Signer>) {
+        self.signer = Some(signer);
+    }
+
+    /// A secret-free summary of the trade, as of now, tagged with the given archival time.
+    #[must_use]
+    pub fn summarize(&self, archived_at: Option<SystemTime>) -> TradeSummary {
+        TradeSummary {
+            trade_id: self.trade_id.clone(),
+            my_role: self.my_role,
+            phase: self.phase,
+            trade_amount: self.trade_amount,
+            buyers_security_deposit: self.buyers_security_deposit,
+            sellers_security_deposit: self.sellers_security_deposit,
+            archived_at,
+            revision: self.revision,
+            cancellation: self.cancellation,
+        }
+    }
+
+    /// Our receipt for the given payment milestone, if it has been confirmed.
+    #[must_use]
+    pub fn payment_receipt(&self, milestone: PaymentMilestone) -> Option<&PaymentReceipt> {
+        self.payment_receipts.iter().find(|receipt| receipt.milestone == milestone)
+    }
+
+    fn signer(&self) -> &dyn Signer {
+        signer_or_default(self.signer.as_ref())
+    }
+
+    fn advance_phase(&mut self, phase: TradePhase) {
+        self.phase = self.phase.max(phase);
+    }
+
+    #[must_use]
+    pub const fn am_buyer(&self) -> bool {
+        matches!(self.my_role, Role::BuyerAsMaker | Role::BuyerAsTaker)
+    }
+
+    /// Generate our (random) key shares for the buyer's & seller's outputs. This is the first step
+    /// of the protocol, unless it was done when building the trade model.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the signer could not generate the key shares.
+    pub fn init_my_key_shares(&mut self) -> Result<()> {
+        let signer = signer_or_default(self.signer.as_ref());
+        // The identity key is made by the signer as for the key shares, so that a signer holding
+        // the secrets outside of the trade daemon holds this one too:
+        self.my_identity_key = Some(signer.new_key_share()?);
+        let buyer_output_pub_key = self.buyer_output_key_ctx.init_my_key_share(signer)?.pub_key;
+        self.seller_output_key_ctx.init_my_key_share(signer)?;
+        if !self.am_buyer() {
+            self.swap_tx_input_sig_ctx.adaptor_point = MaybePoint::Valid(buyer_output_pub_key);
+        }
+        self.advance_phase(TradePhase::KeySharesGenerated);
+        Ok(())
+    }
+
+    /// Our key shares for the buyer's & seller's outputs respectively, the public halves of which
+    /// are to be sent to the peer. Returns `None` if they haven't been generated yet.
+    #[must_use]
+    pub fn get_my_key_shares(&self) -> Option<[&KeyPair<ByOptVal>; 2]> {
+        Some([
+            self.buyer_output_key_ctx.my_key_share()?,
+            self.seller_output_key_ctx.my_key_share()?
+        ])
+    }
+
+    /// Our identity public key for the trade, to be sent to the peer with our key shares. Returns
+    /// `None` if it hasn't been generated yet.
+    #[must_use]
+    pub fn get_my_identity_pub_key(&self) -> Option<Point> {
+        self.my_identity_key.as_ref().map(|k| k.pub_key)
+    }
+
+    /// The peer's identity public key for the trade, if set yet.
+    #[must_use]
+    pub const fn get_peer_identity_pub_key(&self) -> Option<Point> {
+        self.peers_identity_pub_key
+    }
+
+    /// The peer's static X25519 key for sealing payloads to it, if set yet.
+    #[must_use]
+    pub const fn get_peer_noise_static_pub_key(&self) -> Option<[u8; 32]> {
+        self.peers_noise_static_pub_key
+    }
+
+    /// Our static X25519 private key for the Noise channel sealing the payloads to & from the peer.
+    /// It is derived from our identity private key, so that it needn't be kept (or held by the
+    /// signer) apart from it, and its public key is handed out signed with our key shares.
+    ///
+    /// # Errors
+    ///
+    /// Fails if our identity key hasn't been generated yet, or the signer could not reveal it.
+    pub fn noise_static_key(&self) -> Result<[u8; 32]> {
+        let identity_key = self.my_identity_key.as_ref().ok_or(ProtocolErrorKind::MissingIdentityKey)?;
+        Ok(identity::noise_static_key(self.signer().reveal_key_share(identity_key)?))
+    }
+
+    /// Set the peer's static X25519 key for the Noise channel, received (signed by its identity
+    /// key) with its key shares. Like the identity key, it cannot be changed once set.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the peer's static key has already been set to a different key.
+    pub fn set_peer_noise_static_pub_key(&mut self, pub_key: [u8; 32]) -> Result<()> {
+        if *self.peers_noise_static_pub_key.get_or_insert(pub_key) != pub_key {
+            return Err(ProtocolErrorKind::ChangedIdentityKey);
+        }
+        Ok(())
+    }
+
+    /// Set the peer's identity public key for the trade, received with its key shares, which every
+    /// later payload from the peer must be signed with. It cannot be changed once set.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the peer's identity key has already been set to a different key.
+    pub fn set_peer_identity_pub_key(&mut self, pub_key: Point) -> Result<()> {
+        if *self.peers_identity_pub_key.get_or_insert(pub_key) != pub_key {
+            return Err(ProtocolErrorKind::ChangedIdentityKey);
+        }
+        Ok(())
+    }
+
+    /// Check that the peer's role, as claimed with its key shares, complements ours.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the peer's role is not [`Role::peers_role`] of ours.
+    pub fn check_peer_role(&self, peers_role: Role) -> Result<()> {
+        if peers_role != self.my_role.peers_role() {
+            return Err(ProtocolErrorKind::MismatchedPeerRole { mine: self.my_role, peers: peers_role });
+        }
+        Ok(())
+    }
+
+    /// Sign the payload of the given kind & fields, to be sent to the peer, with our identity key.
+    /// The fields are the bytes of each field of the payload, in a fixed order agreed with the peer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if our identity key hasn't been generated yet, or the signer could not reveal it.
+    pub fn sign_payload(&self, kind: PayloadKind, fields: &[&[u8]]) -> Result<CompactSignature> {
+        let identity_key = self.my_identity_key.as_ref().ok_or(ProtocolErrorKind::MissingIdentityKey)?;
+        let prv_key = self.signer().reveal_key_share(identity_key)?;
+        Ok(identity::sign(prv_key, &self.payload_message(kind, self.am_buyer(), fields)))
+    }
+
+    /// Check that the payload of the given kind & fields, received from the peer, is signed with
+    /// the peer's identity key, before any of it is taken into the trade model.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the peer's identity key hasn't been set yet, or the signature is invalid (as it
+    /// would be for a payload tampered with in transit).
+    pub fn verify_peer_payload(&self, kind: PayloadKind, fields: &[&[u8]], signature: &CompactSignature) -> Result<()> {
+        let pub_key = self.peers_identity_pub_key.ok_or(ProtocolErrorKind::MissingIdentityKey)?;
+        let message = self.payload_message(kind, !self.am_buyer(), fields);
+        musig2::verify_single(pub_key, *signature, message)
+            .map_err(|_| ProtocolErrorKind::InvalidPeerSignature(kind))
+    }
+
+    fn payload_message(&self, kind: PayloadKind, from_buyer: bool, fields: &[&[u8]]) -> [u8; 32] {
+        // The payloads of each signing session after the first are bound to it, so that those of an
+        // earlier session cannot be replayed into a later one:
+        if kind.is_per_session() && self.signing_session != 0 {
+            let session = self.signing_session.to_be_bytes();
+            let fields: Vec<_> = fields.iter().copied().chain(iter::once(&session[..])).collect();
+            return identity::payload_message(kind, from_buyer, &fields);
+        }
+        identity::payload_message(kind, from_buyer, fields)
+    }
+
+    /// Set our funding inputs to the deposit tx, as picked by our wallet and handed out to the peer
+    /// with our key shares. No funding inputs need be given, in which case our half of the deposit
+    /// tx is left to be funded outside of the daemon.
+    ///
+    /// # Errors
+    ///
+    /// Fails if any of the inputs is given twice or lacks a valid ownership proof for this trade, or
+    /// if they break our coin control.
+    pub fn set_my_funding_inputs(&mut self, inputs: Vec<FundingInput>) -> Result<()> {
+        check_funding_inputs(&inputs, &self.trade_id, &self.peers_funding_inputs)?;
+        self.coin_control.check(&inputs)?;
+        self.my_funding_inputs = inputs;
+        Ok(())
+    }
+
+    /// Set the peer's funding inputs to the deposit tx, as handed out with its key shares, and
+    /// proven for the trade by the peer's ID for it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if any of the inputs is given twice (or also by us) or lacks a valid ownership proof
+    /// for the peer's trade.
+    pub fn set_peer_funding_inputs(&mut self, peers_trade_id: &str, inputs: Vec<FundingInput>) -> Result<()> {
+        check_funding_inputs(&inputs, peers_trade_id, &self.my_funding_inputs)?;
+        self.peers_funding_inputs = inputs;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn my_funding_inputs(&self) -> &[FundingInput] {
+        &self.my_funding_inputs
+    }
+
+    #[must_use]
+    pub fn peers_funding_inputs(&self) -> &[FundingInput] {
+        &self.peers_funding_inputs
+    }
+
+    /// Check that each party's funding inputs (if it gave any) are enough to fund its half of the
+    /// deposit tx, once the trade amounts are known.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the trade amounts aren't known yet, or if either party's inputs fall short.
+    pub fn check_funding(&self) -> Result<()> {
+        let am_buyer = self.am_buyer();
+        for (inputs, buyer) in [(&self.my_funding_inputs, am_buyer), (&self.peers_funding_inputs, !am_buyer)] {
+            if inputs.is_empty() {
+                continue;
+            }
+            let needed = self.funding_contribution(buyer).ok_or(ProtocolErrorKind::MissingAmounts)?;
+            let funded = inputs.iter().fold(0u64, |sum, input| sum.saturating_add(input.amount));
+            if funded < needed {
+                return Err(ProtocolErrorKind::InsufficientFunding { needed, funded });
+            }
+        }
+        Ok(())
+    }
+
+    /// What the buyer or seller puts into the deposit tx in sats (before fees): its security
+    /// deposit, plus the trade amount for the seller. This is also the amount of its payout output.
+    #[must_use]
+    pub fn funding_contribution(&self, buyer: bool) -> Option<u64> {
+        if buyer {
+            self.buyers_security_deposit
+        } else {
+            self.trade_amount?.checked_add(self.sellers_security_deposit?)
+        }
+    }
+
+    /// Check that the given receivers of the redirect tx (by address & amount in sats) are approved
+    /// by the mediator with the given public key, for this trade, so that the peer cannot redirect
+    /// the trade funds to receivers of its own choosing.
+    ///
+    /// # Errors
+    ///
+    /// Fails if either identity key isn't known yet, or the mediator's signature is invalid.
+    pub fn verify_redirect_receivers(&self, mediator_pub_key: Point, receivers: &[(&str, u64)],
+                                     signature: &CompactSignature) -> Result<()> {
+        let (my_pub_key, peers_pub_key) = self.get_my_identity_pub_key().zip(self.peers_identity_pub_key)
+            .ok_or(ProtocolErrorKind::MissingIdentityKey)?;
+        let (buyer_pub_key, seller_pub_key) = if self.am_buyer() {
+            (my_pub_key, peers_pub_key)
+        } else {
+            (peers_pub_key, my_pub_key)
+        };
+        let message = identity::redirect_receivers_message(buyer_pub_key, seller_pub_key, receivers);
+        musig2::verify_single(mediator_pub_key, *signature, message)
+            .map_err(|_| ProtocolErrorKind::InvalidMediatorSignature)
+    }
+
+    /// Set the peer's public key shares for the buyer's & seller's outputs respectively.
+    pub fn set_peer_key_shares(&mut self, buyer_output_pub_key: Point, seller_output_pub_key: Point) {
+        *self.buyer_output_key_ctx.peers_key_share_mut() = Some(KeyPair::from_public(buyer_output_pub_key));
+        *self.seller_output_key_ctx.peers_key_share_mut() = Some(KeyPair::from_public(seller_output_pub_key));
+        if self.am_buyer() {
+            // TODO: Should check that signing hasn't already begun before setting an adaptor point.
+            self.swap_tx_input_sig_ctx.adaptor_point = MaybePoint::Valid(buyer_output_pub_key);
+        }
+    }
+
+    /// Aggregate our key shares with the peer's, to get the public keys of both outputs.
+    ///
+    /// # Errors
+    ///
+    /// Fails if either party's key shares are missing.
+    pub fn aggregate_key_shares(&mut self) -> Result<()> {
+        self.buyer_output_key_ctx.aggregate_key_shares()?;
+        self.seller_output_key_ctx.aggregate_key_shares()?;
+        Ok(())
+    }
+
+    /// Generate our (random) nonce shares for every tx input we are to sign with the peer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the key shares haven't been aggregated yet, or if the signer could not generate
+    /// the nonce shares.
+    pub fn init_my_nonce_shares(&mut self) -> Result<()> {
+        let signer = signer_or_default(self.signer.as_ref());
+        for ctx in [
+            &mut self.buyers_warning_tx_buyer_input_sig_ctx,
+            &mut self.sellers_warning_tx_buyer_input_sig_ctx,
+            &mut self.buyers_redirect_tx_input_sig_ctx
+        ] {
+            ctx.init_my_nonce_share(&self.buyer_output_key_ctx, signer)?;
+        }
+        for ctx in [
+            &mut self.swap_tx_input_sig_ctx,
+            &mut self.buyers_warning_tx_seller_input_sig_ctx,
+            &mut self.sellers_warning_tx_seller_input_sig_ctx,
+            &mut self.sellers_redirect_tx_input_sig_ctx
+        ] {
+            ctx.init_my_nonce_share(&self.seller_output_key_ctx, signer)?;
+        }
+        self.advance_phase(TradePhase::NonceSharesGenerated);
+        Ok(())
+    }
+
+    /// Our public nonce shares, to be sent to the peer. Returns `None` if they haven't been
+    /// generated yet.
+    #[must_use]
+    pub fn get_my_nonce_shares(&self) -> Option<ExchangedNonces<'_, ByRef>> {
+        Some(ExchangedNonces {
+            swap_tx_input_nonce_share:
+            &(self.swap_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_warning_tx_buyer_input_nonce_share:
+            &(self.buyers_warning_tx_buyer_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_warning_tx_seller_input_nonce_share:
+            &(self.buyers_warning_tx_seller_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_warning_tx_buyer_input_nonce_share:
+            &(self.sellers_warning_tx_buyer_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_warning_tx_seller_input_nonce_share:
+            &(self.sellers_warning_tx_seller_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_redirect_tx_input_nonce_share:
+            &(self.buyers_redirect_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_redirect_tx_input_nonce_share:
+            &(self.sellers_redirect_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+        })
+    }
+
+    /// The slots for the peer's nonce shares, to be filled in before they are aggregated.
+    pub fn peer_nonce_shares_mut(&mut self) -> ExchangedNonces<'_, ByMutRef> {
+        ExchangedNonces {
+            swap_tx_input_nonce_share:
+            &mut self.swap_tx_input_sig_ctx.peers_nonce_share,
+            buyers_warning_tx_buyer_input_nonce_share:
+            &mut self.buyers_warning_tx_buyer_input_sig_ctx.peers_nonce_share,
+            buyers_warning_tx_seller_input_nonce_share:
+            &mut self.buyers_warning_tx_seller_input_sig_ctx.peers_nonce_share,
+            sellers_warning_tx_buyer_input_nonce_share:
+            &mut self.sellers_warning_tx_buyer_input_sig_ctx.peers_nonce_share,
+            sellers_warning_tx_seller_input_nonce_share:
+            &mut self.sellers_warning_tx_seller_input_sig_ctx.peers_nonce_share,
+            buyers_redirect_tx_input_nonce_share:
+            &mut self.buyers_redirect_tx_input_sig_ctx.peers_nonce_share,
+            sellers_redirect_tx_input_nonce_share:
+            &mut self.sellers_redirect_tx_input_sig_ctx.peers_nonce_share,
+        }
+    }
+
+    /// Our commitments to our public nonce shares, to be sent to the peer ahead of the nonce shares
+    /// themselves if [`Self::commit_to_nonces`] is set. Returns `None` if they haven't been
+    /// generated yet.
+    #[must_use]
+    pub fn get_my_nonce_commitments(&self) -> Option<ExchangedNonceCommitments<'static, ByVal>> {
+        Some(ExchangedNonceCommitments {
+            swap_tx_input_nonce_commitment:
+            nonce_commitment(&self.swap_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_warning_tx_buyer_input_nonce_commitment:
+            nonce_commitment(&self.buyers_warning_tx_buyer_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_warning_tx_seller_input_nonce_commitment:
+            nonce_commitment(&self.buyers_warning_tx_seller_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_warning_tx_buyer_input_nonce_commitment:
+            nonce_commitment(&self.sellers_warning_tx_buyer_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_warning_tx_seller_input_nonce_commitment:
+            nonce_commitment(&self.sellers_warning_tx_seller_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_redirect_tx_input_nonce_commitment:
+            nonce_commitment(&self.buyers_redirect_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_redirect_tx_input_nonce_commitment:
+            nonce_commitment(&self.sellers_redirect_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+        })
+    }
+
+    /// Set the peer's nonce commitments, against which its nonce shares are checked as they are
+    /// aggregated.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the peer's nonce commitments have already been set to different ones, as the peer
+    /// could otherwise commit afresh once it had seen our nonce shares.
+    pub fn set_peer_nonce_commitments(&mut self, commitments: &ExchangedNonceCommitments<'_, ByVal>) -> Result<()> {
+        let slots = [
+            (&mut self.swap_tx_input_sig_ctx.peers_nonce_commitment, commitments.swap_tx_input_nonce_commitment),
+            (&mut self.buyers_warning_tx_buyer_input_sig_ctx.peers_nonce_commitment, commitments.buyers_warning_tx_buyer_input_nonce_commitment),
+            (&mut self.buyers_warning_tx_seller_input_sig_ctx.peers_nonce_commitment, commitments.buyers_warning_tx_seller_input_nonce_commitment),
+            (&mut self.sellers_warning_tx_buyer_input_sig_ctx.peers_nonce_commitment, commitments.sellers_warning_tx_buyer_input_nonce_commitment),
+            (&mut self.sellers_warning_tx_seller_input_sig_ctx.peers_nonce_commitment, commitments.sellers_warning_tx_seller_input_nonce_commitment),
+            (&mut self.buyers_redirect_tx_input_sig_ctx.peers_nonce_commitment, commitments.buyers_redirect_tx_input_nonce_commitment),
+            (&mut self.sellers_redirect_tx_input_sig_ctx.peers_nonce_commitment, commitments.sellers_redirect_tx_input_nonce_commitment),
+        ];
+        if slots.iter().any(|(slot, commitment)| slot.is_some_and(|c| c != *commitment)) {
+            return Err(ProtocolErrorKind::ChangedNonceCommitment);
+        }
+        for (slot, commitment) in slots {
+            *slot = Some(commitment);
+        }
+        Ok(())
+    }
+
+    /// Whether the peer's nonce commitments have been set, with [`Self::set_peer_nonce_commitments`].
+    #[must_use]
+    pub const fn has_peer_nonce_commitments(&self) -> bool {
+        // The peer's nonce commitments are all set at once, so checking any one of them will do:
+        self.swap_tx_input_sig_ctx.peers_nonce_commitment.is_some()
+    }
+
+    /// Aggregate our nonce shares with the peer's, once the latter have been filled in with
+    /// [`Self::peer_nonce_shares_mut`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if either party's nonce shares are missing, or if they aggregate to an invalid nonce.
+    /// If [`Self::commit_to_nonces`] is set, also fails if any of the peer's nonce commitments are
+    /// missing, or if its nonce shares don't match them.
+    pub fn aggregate_nonce_shares(&mut self) -> Result<()> {
+        if self.commit_to_nonces && !self.has_peer_nonce_commitments() {
+            return Err(ProtocolErrorKind::MissingNonceCommitment);
+        }
+        self.swap_tx_input_sig_ctx.aggregate_nonce_shares()?;
+        self.buyers_warning_tx_buyer_input_sig_ctx.aggregate_nonce_shares()?;
+        self.buyers_warning_tx_seller_input_sig_ctx.aggregate_nonce_shares()?;
+        self.sellers_warning_tx_buyer_input_sig_ctx.aggregate_nonce_shares()?;
+        self.sellers_warning_tx_seller_input_sig_ctx.aggregate_nonce_shares()?;
+        self.buyers_redirect_tx_input_sig_ctx.aggregate_nonce_shares()?;
+        self.sellers_redirect_tx_input_sig_ctx.aggregate_nonce_shares()?;
+        Ok(())
+    }
+
+    /// Partially sign every tx input we are to sign with the peer, consuming our secret nonces.
+    /// This is an irreversible step: see [`Intent::ConsumeNonces`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the nonce shares haven't been aggregated yet, if our secret nonces have already
+    /// been used (or discarded), or if the signer could not sign.
+    pub fn sign_partial(&mut self) -> Result<()> {
+        // TODO: Make these dummy messages (txs-to-sign) non-fixed, for greater realism:
+        let [buyer_key_ctx, seller_key_ctx] = [&self.buyer_output_key_ctx, &self.seller_output_key_ctx];
+        let signer = signer_or_default(self.signer.as_ref());
+        let signing_session = self.signing_session;
+        let message = |tx: &str| session_message(tx.as_bytes(), signing_session);
+
+        sign_inputs([
+            ("buyer's warning tx buyer input", &mut self.buyers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx),
+            ("seller's warning tx buyer input", &mut self.sellers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx),
+            ("buyer's redirect tx input", &mut self.buyers_redirect_tx_input_sig_ctx, buyer_key_ctx),
+
+            ("swap tx input", &mut self.swap_tx_input_sig_ctx, seller_key_ctx),
+            ("buyer's warning tx seller input", &mut self.buyers_warning_tx_seller_input_sig_ctx, seller_key_ctx),
+            ("seller's warning tx seller input", &mut self.sellers_warning_tx_seller_input_sig_ctx, seller_key_ctx),
+            ("seller's redirect tx input", &mut self.sellers_redirect_tx_input_sig_ctx, seller_key_ctx),
+        ], message, signer)?;
+        self.advance_phase(TradePhase::PartialSignaturesGenerated);
+        Ok(())
+    }
+
+    /// Our partial signatures on the peer's txs (and on the swap tx), to be sent to the peer. The
+    /// buyer should redact the swap tx signature until payment is started. Returns `None` if they
+    /// haven't been made yet.
+    #[must_use]
+    pub fn get_my_partial_signatures_on_peer_txs(&self) -> Option<ExchangedSigs<'_, ByRef>> {
+        Some(if self.am_buyer() {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: self.sellers_warning_tx_buyer_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_warning_tx_seller_input_partial_signature: self.sellers_warning_tx_seller_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_redirect_tx_input_partial_signature: self.sellers_redirect_tx_input_sig_ctx.my_partial_sig.as_ref()?,
+                swap_tx_input_partial_signature: Redactable::Value(self.swap_tx_input_sig_ctx.my_partial_sig.as_ref()?),
+            }
+        } else {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: self.buyers_warning_tx_buyer_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_warning_tx_seller_input_partial_signature: self.buyers_warning_tx_seller_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_redirect_tx_input_partial_signature: self.buyers_redirect_tx_input_sig_ctx.my_partial_sig.as_ref()?,
+                swap_tx_input_partial_signature: Redactable::Value(self.swap_tx_input_sig_ctx.my_partial_sig.as_ref()?),
+            }
+        })
+    }
+
+    /// The slots for the peer's partial signatures on our own txs, to be filled in before they are
+    /// aggregated.
+    pub fn peer_partial_signatures_on_my_txs_mut(&mut self) -> ExchangedSigs<'_, ByMutRef> {
+        // NOTE: The swap tx partial signature passed to the seller would normally be redacted, which leaves its slot
+        // empty (in the 'Promised' state). The buyer should redact the field at the trade start and reveal it later,
+        // after payment is started, to prevent premature trade closure by the seller.
+        if self.am_buyer() {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: &mut self.buyers_warning_tx_buyer_input_sig_ctx.peers_partial_sig,
+                peers_warning_tx_seller_input_partial_signature: &mut self.buyers_warning_tx_seller_input_sig_ctx.peers_partial_sig,
+                peers_redirect_tx_input_partial_signature: &mut self.buyers_redirect_tx_input_sig_ctx.peers_partial_sig,
+                swap_tx_input_partial_signature: Redactable::Value(&mut self.swap_tx_input_sig_ctx.peers_partial_sig),
+            }
+        } else {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: &mut self.sellers_warning_tx_buyer_input_sig_ctx.peers_partial_sig,
+                peers_warning_tx_seller_input_partial_signature: &mut self.sellers_warning_tx_seller_input_sig_ctx.peers_partial_sig,
+                peers_redirect_tx_input_partial_signature: &mut self.sellers_redirect_tx_input_sig_ctx.peers_partial_sig,
+                swap_tx_input_partial_signature: Redactable::Value(&mut self.swap_tx_input_sig_ctx.peers_partial_sig),
+            }
+        }
+    }
+
+    /// Aggregate our partial signatures on our own txs with the peer's, once the latter have been
+    /// filled in with [`Self::peer_partial_signatures_on_my_txs_mut`], to get their final signatures.
+    ///
+    /// # Errors
+    ///
+    /// Fails if any of the partial signatures are missing or invalid, naming the peer's partial
+    /// signature at fault, by its field in the `PartialSignaturesMessage` it was sent in.
+    pub fn aggregate_partial_signatures(&mut self) -> Result<()> {
+        let [buyer_key_ctx, seller_key_ctx] = [&self.buyer_output_key_ctx, &self.seller_output_key_ctx];
+        if self.am_buyer() {
+            SigCtx::aggregate_partial_signatures_batched([
+                (&mut self.buyers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx, "peersWarningTxBuyerInputPartialSignature"),
+                (&mut self.buyers_warning_tx_seller_input_sig_ctx, seller_key_ctx, "peersWarningTxSellerInputPartialSignature"),
+                (&mut self.buyers_redirect_tx_input_sig_ctx, buyer_key_ctx, "peersRedirectTxInputPartialSignature"),
+
+                // This forms a validated adaptor signature on the swap tx for the buyer, ensuring that the seller's
+                // private key share is revealed if the swap tx is published. The seller doesn't get the full adaptor
+                // signature (or the ordinary signature) until later on in the trade, when the buyer confirms payment:
+                (&mut self.swap_tx_input_sig_ctx, seller_key_ctx, "swapTxInputPartialSignature"),
+            ])?;
+        } else {
+            SigCtx::aggregate_partial_signatures_batched([
+                (&mut self.sellers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx, "peersWarningTxBuyerInputPartialSignature"),
+                (&mut self.sellers_warning_tx_seller_input_sig_ctx, seller_key_ctx, "peersWarningTxSellerInputPartialSignature"),
+                (&mut self.sellers_redirect_tx_input_sig_ctx, seller_key_ctx, "peersRedirectTxInputPartialSignature"),
+            ])?;
+        }
+        self.advance_phase(TradePhase::DepositTxSigned);
+        Ok(())
+    }
+
+    /// Discard any of our secret nonces not yet used for signing, so that every subsequent attempt
+    /// to sign with them fails. This is for recovery from a half-completed signing step, where it
+    /// is unknown whether some of the nonces were used.
+    pub fn discard_unused_sec_nonces(&mut self) {
+        let signer = signer_or_default(self.signer.as_ref());
+        for ctx in [
+            &mut self.swap_tx_input_sig_ctx,
+            &mut self.buyers_warning_tx_buyer_input_sig_ctx,
+            &mut self.buyers_warning_tx_seller_input_sig_ctx,
+            &mut self.sellers_warning_tx_buyer_input_sig_ctx,
+            &mut self.sellers_warning_tx_seller_input_sig_ctx,
+            &mut self.buyers_redirect_tx_input_sig_ctx,
+            &mut self.sellers_redirect_tx_input_sig_ctx
+        ] {
+            if let Some(nonce_pair) = &mut ctx.my_nonce_share {
+                nonce_pair.sec_nonce = None;
+                signer.discard_nonce_share(&nonce_pair.pub_nonce);
+            }
+        }
+    }
+
+    /// Start a fresh signing session, as after the nonce shares failed to aggregate or the fee
+    /// rates were renegotiated, without starting a new trade: discard our nonce shares, along with
+    /// the peer's and any partial signatures made with them, and generate fresh nonce shares. The
+    /// session counter is bumped and bound into every message signed from then on, so that no
+    /// signature or payload of an earlier session can pass for one of the new session. Nothing may
+    /// be reset once the deposit tx is signed, as the peer may then hold signed txs spending it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the nonce shares haven't been generated yet, if the deposit tx has already been
+    /// signed, or if the signer could not generate the fresh nonce shares.
+    pub fn reset_signing_session(&mut self) -> Result<()> {
+        if !(TradePhase::NonceSharesGenerated..TradePhase::DepositTxSigned).contains(&self.phase) {
+            return Err(ProtocolErrorKind::SigningSessionClosed(self.phase));
+        }
+        self.clear_signing_session();
+        self.signing_session += 1;
+        self.phase = TradePhase::KeySharesGenerated;
+        self.init_my_nonce_shares()
+    }
+
+    /// Discard our nonce shares (telling the signer to forget the unused ones), the peer's, and
+    /// every partial & aggregated signature made with them.
+    fn clear_signing_session(&mut self) {
+        self.discard_unused_sec_nonces();
+        for ctx in [
+            &mut self.swap_tx_input_sig_ctx,
+            &mut self.buyers_warning_tx_buyer_input_sig_ctx,
+            &mut self.buyers_warning_tx_seller_input_sig_ctx,
+            &mut self.sellers_warning_tx_buyer_input_sig_ctx,
+            &mut self.sellers_warning_tx_seller_input_sig_ctx,
+            &mut self.buyers_redirect_tx_input_sig_ctx,
+            &mut self.sellers_redirect_tx_input_sig_ctx
+        ] {
+            *ctx = SigCtx { am_buyer: ctx.am_buyer, adaptor_point: ctx.adaptor_point, ..SigCtx::default() };
+        }
+        self.my_signed_half_deposit_psbt = None;
+        self.redirect_receivers.clear();
+    }
+
+    /// Cancel the trade before its deposit tx is published, as recorded by the given kind of
+    /// cancellation: invalidate its signing session, discarding our nonce shares & every signature
+    /// made with them (along with any fee rate change under way), so that nothing further can be
+    /// signed for the trade, which is then only fit to be archived. Our key shares are kept until
+    /// then. Should the deposit tx be signed already, it is for the caller to make sure that it
+    /// can't be published after all, as by the peer's consent or by spending our funding inputs
+    /// elsewhere.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the deposit tx has already been published.
+    pub fn cancel_before_deposit(&mut self, cancellation: Cancellation) -> Result<()> {
+        if self.phase >= TradePhase::DepositTxPublished {
+            return Err(ProtocolErrorKind::CancellationClosed(self.phase));
+        }
+        self.discard_fee_rate_change();
+        self.clear_signing_session();
+        self.cancellation = Some(cancellation);
+        Ok(())
+    }
+
+    /// The prepared tx fee rate of the fee rate change under way, if any.
+    #[must_use]
+    pub fn pending_prepared_tx_fee_rate(&self) -> Option<f64> {
+        self.fee_rate_change.as_ref().map(|change| change.prepared_tx_fee_rate)
+    }
+
+    /// Start a change of the prepared tx fee rate, once the deposit tx is signed (before which the
+    /// signing session may simply be reset instead), generating fresh nonce shares for the inputs of
+    /// the warning & redirect txs to re-sign them with. Any change already under way is abandoned.
+    /// The swap tx is left alone, as it pays no prepared tx fee of its own to renegotiate.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the deposit tx hasn't been signed yet or the trade is closed, or if the signer
+    /// could not generate the nonce shares.
+    pub fn start_fee_rate_change(&mut self, prepared_tx_fee_rate: f64) -> Result<()> {
+        if !(TradePhase::DepositTxSigned..TradePhase::Closed).contains(&self.phase) {
+            return Err(ProtocolErrorKind::FeeRateChangeClosed(self.phase));
+        }
+        self.discard_fee_rate_change();
+        let signer = signer_or_default(self.signer.as_ref());
+        let mut change = Box::new(FeeRateChange::new(prepared_tx_fee_rate, self.am_buyer()));
+        for ctx in [
+            &mut change.buyers_warning_tx_buyer_input_sig_ctx,
+            &mut change.sellers_warning_tx_buyer_input_sig_ctx,
+            &mut change.buyers_redirect_tx_input_sig_ctx
+        ] {
+            ctx.init_my_nonce_share(&self.buyer_output_key_ctx, signer)?;
+        }
+        for ctx in [
+            &mut change.buyers_warning_tx_seller_input_sig_ctx,
+            &mut change.sellers_warning_tx_seller_input_sig_ctx,
+            &mut change.sellers_redirect_tx_input_sig_ctx
+        ] {
+            ctx.init_my_nonce_share(&self.seller_output_key_ctx, signer)?;
+        }
+        self.fee_rate_change = Some(change);
+        Ok(())
+    }
+
+    /// Abandon the fee rate change under way (if any), discarding our unused secret nonces for it.
+    pub fn discard_fee_rate_change(&mut self) {
+        let signer = signer_or_default(self.signer.as_ref());
+        for ctx in self.fee_rate_change.iter_mut().flat_map(|change| change.sig_ctxs_mut()) {
+            if let Some(nonce_pair) = &mut ctx.my_nonce_share {
+                nonce_pair.sec_nonce = None;
+                signer.discard_nonce_share(&nonce_pair.pub_nonce);
+            }
+        }
+        self.fee_rate_change = None;
+    }
+
+    /// Our public nonce shares for the fee rate change under way, to be sent to the peer. Returns
+    /// `None` if there is no change under way.
+    #[must_use]
+    pub fn get_my_fee_rate_change_nonce_shares(&self) -> Option<ExchangedPreparedTxNonces<'_, ByRef>> {
+        let change = self.fee_rate_change.as_ref()?;
+        Some(ExchangedPreparedTxNonces {
+            buyers_warning_tx_buyer_input_nonce_share:
+            &(change.buyers_warning_tx_buyer_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_warning_tx_seller_input_nonce_share:
+            &(change.buyers_warning_tx_seller_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_warning_tx_buyer_input_nonce_share:
+            &(change.sellers_warning_tx_buyer_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_warning_tx_seller_input_nonce_share:
+            &(change.sellers_warning_tx_seller_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            buyers_redirect_tx_input_nonce_share:
+            &(change.buyers_redirect_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+            sellers_redirect_tx_input_nonce_share:
+            &(change.sellers_redirect_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce),
+        })
+    }
+
+    /// The slots for the peer's nonce shares for the fee rate change under way, to be filled in
+    /// before they are aggregated. Returns `None` if there is no change under way.
+    pub fn fee_rate_change_peer_nonce_shares_mut(&mut self) -> Option<ExchangedPreparedTxNonces<'_, ByMutRef>> {
+        let change = self.fee_rate_change.as_mut()?;
+        Some(ExchangedPreparedTxNonces {
+            buyers_warning_tx_buyer_input_nonce_share:
+            &mut change.buyers_warning_tx_buyer_input_sig_ctx.peers_nonce_share,
+            buyers_warning_tx_seller_input_nonce_share:
+            &mut change.buyers_warning_tx_seller_input_sig_ctx.peers_nonce_share,
+            sellers_warning_tx_buyer_input_nonce_share:
+            &mut change.sellers_warning_tx_buyer_input_sig_ctx.peers_nonce_share,
+            sellers_warning_tx_seller_input_nonce_share:
+            &mut change.sellers_warning_tx_seller_input_sig_ctx.peers_nonce_share,
+            buyers_redirect_tx_input_nonce_share:
+            &mut change.buyers_redirect_tx_input_sig_ctx.peers_nonce_share,
+            sellers_redirect_tx_input_nonce_share:
+            &mut change.sellers_redirect_tx_input_sig_ctx.peers_nonce_share,
+        })
+    }
+
+    /// Aggregate the nonce shares for the fee rate change under way, once the peer's have been
+    /// filled in with [`Self::fee_rate_change_peer_nonce_shares_mut`], then partially sign the
+    /// warning & redirect tx inputs at the new fee rate, consuming our secret nonces for the change.
+    /// This is an irreversible step: see [`Intent::ConsumeNonces`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if there is no change under way, if either party's nonce shares are missing or they
+    /// aggregate to an invalid nonce, if our secret nonces have already been used (or discarded),
+    /// or if the signer could not sign.
+    pub fn sign_fee_rate_change(&mut self) -> Result<()> {
+        let change = self.fee_rate_change.as_mut().ok_or(ProtocolErrorKind::MissingFeeRateChange)?;
+        for ctx in change.sig_ctxs_mut() {
+            ctx.aggregate_nonce_shares()?;
+        }
+        let [buyer_key_ctx, seller_key_ctx] = [&self.buyer_output_key_ctx, &self.seller_output_key_ctx];
+        let signer = signer_or_default(self.signer.as_ref());
+        let (signing_session, fee_rate) = (self.signing_session, change.prepared_tx_fee_rate);
+        let message = |tx: &str| fee_rate_change_message(tx.as_bytes(), signing_session, fee_rate);
+
+        sign_inputs([
+            ("buyer's warning tx buyer input", &mut change.buyers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx),
+            ("seller's warning tx buyer input", &mut change.sellers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx),
+            ("buyer's redirect tx input", &mut change.buyers_redirect_tx_input_sig_ctx, buyer_key_ctx),
+
+            ("buyer's warning tx seller input", &mut change.buyers_warning_tx_seller_input_sig_ctx, seller_key_ctx),
+            ("seller's warning tx seller input", &mut change.sellers_warning_tx_seller_input_sig_ctx, seller_key_ctx),
+            ("seller's redirect tx input", &mut change.sellers_redirect_tx_input_sig_ctx, seller_key_ctx),
+        ], message, signer)
+    }
+
+    /// Our partial signatures on the peer's re-signed txs for the fee rate change under way, to be
+    /// sent to the peer. Returns `None` if they haven't been made yet.
+    #[must_use]
+    pub fn get_my_fee_rate_change_partial_signatures_on_peer_txs(&self) -> Option<ExchangedSigs<'_, ByRef>> {
+        let change = self.fee_rate_change.as_ref()?;
+        Some(if self.am_buyer() {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: change.sellers_warning_tx_buyer_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_warning_tx_seller_input_partial_signature: change.sellers_warning_tx_seller_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_redirect_tx_input_partial_signature: change.sellers_redirect_tx_input_sig_ctx.my_partial_sig.as_ref()?,
+                swap_tx_input_partial_signature: Redactable::Omitted,
+            }
+        } else {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: change.buyers_warning_tx_buyer_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_warning_tx_seller_input_partial_signature: change.buyers_warning_tx_seller_input_sig_ctx.my_partial_sig.as_ref()?,
+                peers_redirect_tx_input_partial_signature: change.buyers_redirect_tx_input_sig_ctx.my_partial_sig.as_ref()?,
+                swap_tx_input_partial_signature: Redactable::Omitted,
+            }
+        })
+    }
+
+    /// The slots for the peer's partial signatures on our own re-signed txs for the fee rate change
+    /// under way, to be filled in before they are aggregated. Returns `None` if there is no change
+    /// under way.
+    pub fn fee_rate_change_peer_partial_signatures_on_my_txs_mut(&mut self) -> Option<ExchangedSigs<'_, ByMutRef>> {
+        let am_buyer = self.am_buyer();
+        let change = self.fee_rate_change.as_mut()?;
+        Some(if am_buyer {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: &mut change.buyers_warning_tx_buyer_input_sig_ctx.peers_partial_sig,
+                peers_warning_tx_seller_input_partial_signature: &mut change.buyers_warning_tx_seller_input_sig_ctx.peers_partial_sig,
+                peers_redirect_tx_input_partial_signature: &mut change.buyers_redirect_tx_input_sig_ctx.peers_partial_sig,
+                swap_tx_input_partial_signature: Redactable::Omitted,
+            }
+        } else {
+            ExchangedSigs {
+                peers_warning_tx_buyer_input_partial_signature: &mut change.sellers_warning_tx_buyer_input_sig_ctx.peers_partial_sig,
+                peers_warning_tx_seller_input_partial_signature: &mut change.sellers_warning_tx_seller_input_sig_ctx.peers_partial_sig,
+                peers_redirect_tx_input_partial_signature: &mut change.sellers_redirect_tx_input_sig_ctx.peers_partial_sig,
+                swap_tx_input_partial_signature: Redactable::Omitted,
+            }
+        })
+    }
+
+    /// Complete the fee rate change under way, once the peer's partial signatures on our own txs
+    /// have been filled in with [`Self::fee_rate_change_peer_partial_signatures_on_my_txs_mut`]:
+    /// aggregate them to get the final signatures on our re-signed txs, then put the re-signed txs
+    /// in place of the old ones, at the new prepared tx fee rate.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there is no change under way, or if any of the partial signatures are missing or
+    /// invalid, in which case the change stays under way.
+    pub fn complete_fee_rate_change(&mut self) -> Result<()> {
+        let am_buyer = self.am_buyer();
+        let change = self.fee_rate_change.as_mut().ok_or(ProtocolErrorKind::MissingFeeRateChange)?;
+        let [buyer_key_ctx, seller_key_ctx] = [&self.buyer_output_key_ctx, &self.seller_output_key_ctx];
+        if am_buyer {
+            SigCtx::aggregate_partial_signatures_batched([
+                (&mut change.buyers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx, "peersWarningTxBuyerInputPartialSignature"),
+                (&mut change.buyers_warning_tx_seller_input_sig_ctx, seller_key_ctx, "peersWarningTxSellerInputPartialSignature"),
+                (&mut change.buyers_redirect_tx_input_sig_ctx, buyer_key_ctx, "peersRedirectTxInputPartialSignature"),
+            ])?;
+        } else {
+            SigCtx::aggregate_partial_signatures_batched([
+                (&mut change.sellers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx, "peersWarningTxBuyerInputPartialSignature"),
+                (&mut change.sellers_warning_tx_seller_input_sig_ctx, seller_key_ctx, "peersWarningTxSellerInputPartialSignature"),
+                (&mut change.sellers_redirect_tx_input_sig_ctx, seller_key_ctx, "peersRedirectTxInputPartialSignature"),
+            ])?;
+        }
+        let change = *self.fee_rate_change.take().ok_or(ProtocolErrorKind::MissingFeeRateChange)?;
+        self.prepared_tx_fee_rate = Some(change.prepared_tx_fee_rate);
+        self.buyers_warning_tx_buyer_input_sig_ctx = change.buyers_warning_tx_buyer_input_sig_ctx;
+        self.buyers_warning_tx_seller_input_sig_ctx = change.buyers_warning_tx_seller_input_sig_ctx;
+        self.sellers_warning_tx_buyer_input_sig_ctx = change.sellers_warning_tx_buyer_input_sig_ctx;
+        self.sellers_warning_tx_seller_input_sig_ctx = change.sellers_warning_tx_seller_input_sig_ctx;
+        self.buyers_redirect_tx_input_sig_ctx = change.buyers_redirect_tx_input_sig_ctx;
+        self.sellers_redirect_tx_input_sig_ctx = change.sellers_redirect_tx_input_sig_ctx;
+        Ok(())
+    }
+
+    /// The swap tx fee rate of the fee bump under way, if any.
+    #[must_use]
+    pub fn pending_swap_tx_fee_rate(&self) -> Option<f64> {
+        self.swap_tx_fee_bump.as_ref().map(|bump| bump.swap_tx_fee_rate)
+    }
+
+    /// Start re-signing the swap tx at a higher fee rate, in case fees spike before the seller
+    /// publishes it, generating a fresh nonce share for its input alone. Any bump already under way
+    /// is abandoned. This is open to the buyer once payment is started (when it would reveal its
+    /// partial signature on the swap tx anyway), and to the seller once it holds the swap tx.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the swap tx cannot be re-signed in the current phase, if the fee rate doesn't
+    /// exceed the one the swap tx already pays, or if the signer could not generate the nonce share.
+    pub fn start_swap_tx_fee_bump(&mut self, swap_tx_fee_rate: f64) -> Result<()> {
+        let open = if self.am_buyer() {
+            self.phase < TradePhase::Closed && self.payment_receipt(PaymentMilestone::Started).is_some()
+        } else {
+            self.phase == TradePhase::SwapTxSigned
+        };
+        if !open {
+            return Err(ProtocolErrorKind::SwapTxFeeBumpClosed(self.phase));
+        }
+        if let Some(current_fee_rate) = self.swap_tx_fee_rate.or(self.prepared_tx_fee_rate) {
+            if swap_tx_fee_rate <= current_fee_rate {
+                return Err(ProtocolErrorKind::SwapTxFeeRateNotRaised(current_fee_rate));
+            }
+        }
+        self.discard_swap_tx_fee_bump();
+        let mut bump = Box::new(SwapTxFeeBump { swap_tx_fee_rate, ..SwapTxFeeBump::default() });
+        bump.swap_tx_input_sig_ctx.am_buyer = self.am_buyer();
+        bump.swap_tx_input_sig_ctx.adaptor_point = self.swap_tx_input_sig_ctx.adaptor_point;
+        bump.swap_tx_input_sig_ctx.init_my_nonce_share(&self.seller_output_key_ctx, self.signer())?;
+        self.swap_tx_fee_bump = Some(bump);
+        Ok(())
+    }
+
+    /// Abandon the swap tx fee bump under way (if any), discarding our unused secret nonce for it.
+    pub fn discard_swap_tx_fee_bump(&mut self) {
+        let signer = signer_or_default(self.signer.as_ref());
+        if let Some(nonce_pair) = self.swap_tx_fee_bump.as_mut()
+            .and_then(|bump| bump.swap_tx_input_sig_ctx.my_nonce_share.as_mut()) {
+            nonce_pair.sec_nonce = None;
+            signer.discard_nonce_share(&nonce_pair.pub_nonce);
+        }
+        self.swap_tx_fee_bump = None;
+    }
+
+    /// Our public nonce share for the swap tx fee bump under way, to be sent to the peer. Returns
+    /// `None` if there is no bump under way.
+    #[must_use]
+    pub fn get_my_swap_tx_fee_bump_nonce_share(&self) -> Option<&PubNonce> {
+        Some(&self.swap_tx_fee_bump.as_ref()?.swap_tx_input_sig_ctx.my_nonce_share.as_ref()?.pub_nonce)
+    }
+
+    /// The slot for the peer's nonce share for the swap tx fee bump under way, to be filled in
+    /// before it is aggregated. Returns `None` if there is no bump under way.
+    pub fn swap_tx_fee_bump_peer_nonce_share_mut(&mut self) -> Option<&mut Option<PubNonce>> {
+        Some(&mut self.swap_tx_fee_bump.as_mut()?.swap_tx_input_sig_ctx.peers_nonce_share)
+    }
+
+    /// Aggregate the nonce shares for the swap tx fee bump under way, once the peer's has been
+    /// filled in with [`Self::swap_tx_fee_bump_peer_nonce_share_mut`], then partially sign the swap
+    /// tx input at the new fee rate, consuming our secret nonce for the bump. The buyer only signs
+    /// once it has the seller's partial signature as well, so that it may complete the bump (and
+    /// hold the adaptor signature on the re-signed swap tx) before the seller can. This is an
+    /// irreversible step: see [`Intent::ConsumeNonces`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if there is no bump under way, if either party's nonce share (or, for the buyer, the
+    /// seller's partial signature) is missing or the nonce shares aggregate to an invalid nonce, if
+    /// our secret nonce has already been used (or discarded), or if the signer could not sign.
+    pub fn sign_swap_tx_fee_bump(&mut self) -> Result<()> {
+        let am_buyer = self.am_buyer();
+        let bump = self.swap_tx_fee_bump.as_mut().ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)?;
+        if am_buyer && bump.swap_tx_input_sig_ctx.peers_partial_sig.is_none() {
+            return Err(ProtocolErrorKind::MissingPartialSig);
+        }
+        bump.swap_tx_input_sig_ctx.aggregate_nonce_shares()?;
+        let message = swap_tx_fee_bump_message(b"swap tx input", self.signing_session, bump.swap_tx_fee_rate);
+        let signer = signer_or_default(self.signer.as_ref());
+        bump.swap_tx_input_sig_ctx.sign_partial(&self.seller_output_key_ctx, message, signer)?;
+        Ok(())
+    }
+
+    /// Our partial signature on the re-signed swap tx for the fee bump under way, to be sent to the
+    /// peer. Returns `None` if it hasn't been made yet.
+    #[must_use]
+    pub fn get_my_swap_tx_fee_bump_partial_signature(&self) -> Option<&PartialSignature> {
+        self.swap_tx_fee_bump.as_ref()?.swap_tx_input_sig_ctx.my_partial_sig.as_ref()
+    }
+
+    /// The slot for the peer's partial signature on the re-signed swap tx for the fee bump under
+    /// way, to be filled in before it is aggregated. Returns `None` if there is no bump under way.
+    pub fn swap_tx_fee_bump_peer_partial_signature_mut(&mut self) -> Option<&mut Option<PartialSignature>> {
+        Some(&mut self.swap_tx_fee_bump.as_mut()?.swap_tx_input_sig_ctx.peers_partial_sig)
+    }
+
+    /// Complete the swap tx fee bump under way, once the peer's partial signature has been filled
+    /// in with [`Self::swap_tx_fee_bump_peer_partial_signature_mut`]: aggregate it to get the
+    /// adaptor signature on the re-signed swap tx, then put that in place of the old one, at the new
+    /// swap tx fee rate. The old adaptor signature is kept, so that the buyer may still recover the
+    /// seller's key share should the seller publish the old swap tx instead.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there is no bump under way, or if either partial signature is missing or invalid,
+    /// in which case the bump stays under way.
+    pub fn complete_swap_tx_fee_bump(&mut self) -> Result<()> {
+        let bump = self.swap_tx_fee_bump.as_mut().ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)?;
+        bump.swap_tx_input_sig_ctx.aggregate_partial_signatures(&self.seller_output_key_ctx)?;
+        let bump = *self.swap_tx_fee_bump.take().ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)?;
+        let old_sig_ctx = mem::replace(&mut self.swap_tx_input_sig_ctx, bump.swap_tx_input_sig_ctx);
+        self.superseded_swap_tx_sigs.extend(old_sig_ctx.aggregated_sig);
+        self.swap_tx_fee_rate = Some(bump.swap_tx_fee_rate);
+        Ok(())
+    }
+
+    /// Record that the deposit tx has been published.
+    pub fn set_deposit_tx_published(&mut self) {
+        self.advance_phase(TradePhase::DepositTxPublished);
+    }
+
+    /// Set the buyer's partial signature on the swap tx, revealed to the seller once payment is
+    /// started.
+    pub fn set_swap_tx_input_peers_partial_signature(&mut self, sig: PartialSignature) {
+        self.swap_tx_input_sig_ctx.peers_partial_sig = Some(sig);
+    }
+
+    /// How far the peer's partial signature on the swap tx has got to us. The buyer gets the
+    /// seller's along with its other partial signatures, so it is never just promised.
+    #[must_use]
+    pub fn peers_swap_tx_signature_state(&self) -> SwapTxSignatureState {
+        let my_redirect_tx_input_sig_ctx = if self.am_buyer() {
+            &self.buyers_redirect_tx_input_sig_ctx
+        } else {
+            &self.sellers_redirect_tx_input_sig_ctx
+        };
+        if self.swap_tx_input_sig_ctx.peers_partial_sig.is_some() {
+            SwapTxSignatureState::Revealed
+        } else if my_redirect_tx_input_sig_ctx.peers_partial_sig.is_some() {
+            SwapTxSignatureState::Promised
+        } else {
+            SwapTxSignatureState::NotExchanged
+        }
+    }
+
+    /// Aggregate the partial signatures on the swap tx, so that the seller may publish it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if either partial signature is missing or invalid.
+    pub fn aggregate_swap_tx_partial_signatures(&mut self) -> Result<()> {
+        let my_key_ctx = if self.am_buyer() {
+            &self.buyer_output_key_ctx
+        } else {
+            &self.seller_output_key_ctx
+        };
+        self.swap_tx_input_sig_ctx.aggregate_partial_signatures(my_key_ctx)?;
+        self.advance_phase(TradePhase::SwapTxSigned);
+        Ok(())
+    }
+
+    /// Our private key share for the peer's output, revealed by the signer, to be sent to the peer
+    /// to close the trade cooperatively.
+    ///
+    /// # Errors
+    ///
+    /// Fails if we have no key share for the peer's output, or the signer could not reveal it.
+    pub fn get_my_private_key_share_for_peer_output(&self) -> Result<Scalar> {
+        // TODO: Check that it's actually safe to release the funds at this point.
+        let peer_key_ctx = if self.am_buyer() {
+            &self.seller_output_key_ctx
+        } else {
+            &self.buyer_output_key_ctx
+        };
+        let my_key_share = peer_key_ctx.my_key_share().ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        self.signer().reveal_key_share(my_key_share)
+    }
+
+    //noinspection RsSelfConvention
+    fn get_my_key_ctx_mut(&mut self) -> &mut KeyCtx {
+        if self.am_buyer() {
+            &mut self.buyer_output_key_ctx
+        } else {
+            &mut self.seller_output_key_ctx
+        }
+    }
+
+    /// Set the peer's private key share for our own output, received on cooperative closure.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the peer's public key share is missing, or doesn't match the given private key share.
+    pub fn set_peer_private_key_share_for_my_output(&mut self, prv_key_share: Scalar) -> Result<()> {
+        self.get_my_key_ctx_mut().peers_key_share_mut().as_mut()
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?
+            .set_prv_key(prv_key_share)?;
+        Ok(())
+    }
+
+    /// Aggregate our private key share for our own output with the peer's, returning the private
+    /// key which controls it outright.
+    ///
+    /// # Errors
+    ///
+    /// Fails if either private key share is missing, or the signer could not reveal ours.
+    pub fn aggregate_private_keys_for_my_output(&mut self) -> Result<&Scalar> {
+        let signer = self.signer.clone();
+        self.get_my_key_ctx_mut().aggregate_prv_key_shares(signer_or_default(signer.as_ref()))
+    }
+
+    /// Record that the trade has been closed.
+    pub fn set_closed(&mut self) {
+        self.advance_phase(TradePhase::Closed);
+    }
+
+    /// Adapt the aggregated swap tx adaptor signature into an ordinary signature, for the seller to
+    /// publish the swap tx with.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the adaptor signature or the adaptor secret is missing, or the signer could not
+    /// reveal the latter.
+    pub fn compute_swap_tx_input_signature(&self) -> Result<LiftedSignature> {
+        let adaptor_sig = self.swap_tx_input_sig_ctx.aggregated_sig
+            .ok_or(ProtocolErrorKind::MissingAggSig)?;
+        let adaptor_secret = self.buyer_output_key_ctx.get_prv_key_share(SELLER_INDEX, self.signer())?;
+        adaptor_sig.adapt(adaptor_secret).ok_or(ProtocolErrorKind::ZeroNonce)
+    }
+
+    /// Recover the seller's private key share for the buyer's output from the swap tx signature,
+    /// once the seller has published the swap tx, so that the buyer controls the output outright.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the signature doesn't match any of our adaptor signatures (on the swap tx as last
+    /// re-signed, or on any version of it before). This is a no-op for the seller.
+    pub fn recover_seller_private_key_share_for_buyer_output(&mut self, swap_tx_input_signature: &LiftedSignature) -> Result<()> {
+        let adaptor_sig = self.swap_tx_input_sig_ctx.aggregated_sig
+            .ok_or(ProtocolErrorKind::MissingAggSig)?;
+        // The seller may have published any version of the swap tx it was given, re-signed or not:
+        let adaptor_secret: MaybeScalar = iter::once(&adaptor_sig).chain(self.superseded_swap_tx_sigs.iter().rev())
+            .find_map(|sig| sig.reveal_secret(swap_tx_input_signature))
+            .ok_or(ProtocolErrorKind::MismatchedSigs)?;
+        self.buyer_output_key_ctx.set_others_prv_key_share(SELLER_INDEX, adaptor_secret.try_into()?)
+    }
+}
+
+impl FeeRateChange {
+    fn new(prepared_tx_fee_rate: f64, am_buyer: bool) -> Self {
+        let mut change = Self { prepared_tx_fee_rate, ..Self::default() };
+        for ctx in change.sig_ctxs_mut() {
+            ctx.am_buyer = am_buyer;
+        }
+        change
+    }
+
+    fn sig_ctxs_mut(&mut self) -> [&mut SigCtx; 6] {
+        [
+            &mut self.buyers_warning_tx_buyer_input_sig_ctx,
+            &mut self.buyers_warning_tx_seller_input_sig_ctx,
+            &mut self.sellers_warning_tx_buyer_input_sig_ctx,
+            &mut self.sellers_warning_tx_seller_input_sig_ctx,
+            &mut self.buyers_redirect_tx_input_sig_ctx,
+            &mut self.sellers_redirect_tx_input_sig_ctx
+        ]
+    }
+}
+
+impl KeyPair<ByOptVal> {
+    const fn from_public(pub_key: Point) -> Self {
+        Self { pub_key, prv_key: None }
+    }
+
+    fn set_prv_key(&mut self, prv_key: Scalar) -> Result<&Scalar> {
+        if self.pub_key != prv_key.base_point_mul() {
+            return Err(ProtocolErrorKind::MismatchedKeyPair);
+        }
+        Ok(self.prv_key.insert(Secret::new(prv_key)).expose_secret())
+    }
+}
+
+impl KeyCtx {
+    fn new(signer_count: usize, my_index: usize) -> Self {
+        Self { my_index, key_shares: iter::repeat_with(|| None).take(signer_count).collect(), ..Default::default() }
+    }
+
+    /// The context of a 2-of-2 output, between the buyer and the seller.
+    fn between_buyer_and_seller(am_buyer: bool) -> Self {
+        Self::new(2, if am_buyer { BUYER_INDEX } else { SELLER_INDEX })
+    }
+
+    fn my_key_share(&self) -> Option<&KeyPair<ByOptVal>> {
+        self.key_shares.get(self.my_index)?.as_ref()
+    }
+
+    fn my_key_share_mut(&mut self) -> &mut Option<KeyPair<ByOptVal>> {
+        &mut self.key_shares[self.my_index]
+    }
+
+    /// The other signer's key share of a 2-of-2 output.
+    fn peers_key_share(&self) -> Option<&KeyPair<ByOptVal>> {
+        debug_assert_eq!(self.key_shares.len(), 2, "not a 2-of-2 output");
+        self.key_shares[1 - self.my_index].as_ref()
+    }
+
+    fn peers_key_share_mut(&mut self) -> &mut Option<KeyPair<ByOptVal>> {
+        debug_assert_eq!(self.key_shares.len(), 2, "not a 2-of-2 output");
+        &mut self.key_shares[1 - self.my_index]
+    }
+
+    fn init_my_key_share(&mut self, signer: &dyn Signer) -> Result<&KeyPair<ByOptVal>> {
+        Ok(self.my_key_share_mut().insert(signer.new_key_share()?))
+    }
+
+    /// Every signer's public key share, in order, if all have been set.
+    fn get_key_shares(&self) -> Option<Vec<Point>> {
+        self.key_shares.iter().map(|k| Some(k.as_ref()?.pub_key)).collect()
+    }
+
+    fn aggregate_key_shares(&mut self) -> Result<()> {
+        let agg_ctx = KeyAggContext::new(self.get_key_shares()
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?)?;
+        self.aggregated_key = Some(KeyPair::from_public(agg_ctx.aggregated_pubkey()));
+        self.key_agg_ctx = Some(agg_ctx);
+        Ok(())
+    }
+
+    /// The private key share of the signer at the given index: revealed by the signer if ours, or
+    /// as received from the other signer otherwise.
+    fn get_prv_key_share(&self, index: usize, signer: &dyn Signer) -> Result<Scalar> {
+        let key_share = self.key_shares.get(index).and_then(Option::as_ref)
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        if index == self.my_index {
+            return signer.reveal_key_share(key_share);
+        }
+        key_share.prv_key.as_ref().map(|k| *k.expose_secret()).ok_or(ProtocolErrorKind::MissingKeyShare)
+    }
+
+    fn aggregate_prv_key_shares(&mut self, signer: &dyn Signer) -> Result<&Scalar> {
+        let prv_key_shares = (0..self.key_shares.len())
+            .map(|index| self.get_prv_key_share(index, signer))
+            .collect::<Result<Vec<_>>>()?;
+        let agg_ctx = self.key_agg_ctx.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        let agg_key = self.aggregated_key.as_mut()
+            .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        agg_key.set_prv_key(agg_ctx.aggregated_seckey(prv_key_shares)?)
+    }
+
+    /// Set the private key share of the signer at the given index, checked against its public key
+    /// share, unless it is ours (which the signer already holds).
+    fn set_others_prv_key_share(&mut self, index: usize, prv_key: Scalar) -> Result<()> {
+        if index != self.my_index {
+            self.key_shares.get_mut(index).and_then(Option::as_mut)
+                .ok_or(ProtocolErrorKind::MissingKeyShare)?
+                .set_prv_key(prv_key)?;
+        }
+        Ok(())
+    }
+}
+
+impl SigCtx {
+    fn init_my_nonce_share(&mut self, key_ctx: &KeyCtx, signer: &dyn Signer) -> Result<()> {
+        let aggregated_pub_key = key_ctx.aggregated_key.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggPubKey)?.pub_key;
+        let my_key_share = key_ctx.my_key_share()
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        self.my_nonce_share = Some(signer.new_nonce_share(my_key_share, aggregated_pub_key)?);
+        Ok(())
+    }
+
+    fn get_nonce_shares(&self) -> Option<[&PubNonce; 2]> {
+        Some(if self.am_buyer {
+            [&self.my_nonce_share.as_ref()?.pub_nonce, self.peers_nonce_share.as_ref()?]
+        } else {
+            [self.peers_nonce_share.as_ref()?, &self.my_nonce_share.as_ref()?.pub_nonce]
+        })
+    }
+
+    fn aggregate_nonce_shares(&mut self) -> Result<&AggNonce> {
+        if let (Some(commitment), Some(nonce)) = (&self.peers_nonce_commitment, &self.peers_nonce_share) {
+            if *commitment != nonce_commitment(nonce) {
+                return Err(ProtocolErrorKind::MismatchedNonceCommitment);
+            }
+        }
+        let agg_nonce = AggNonce::sum(self.get_nonce_shares()
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?);
+        if matches!((&agg_nonce.R1, &agg_nonce.R2), (MaybePoint::Infinity, MaybePoint::Infinity)) {
+            // Fail early if the aggregated nonce is zero, since otherwise an attacker could force
+            // the final signature nonce to be equal to the base point, G. While that might not be
+            // a problem (for us), there would be an attack vector if such signatures were ever
+            // deemed to be nonstandard. (Note that being able to assign blame later by allowing
+            // this through is unimportant for a two-party protocol.)
+            return Err(ProtocolErrorKind::ZeroNonce);
+        }
+        Ok(self.aggregated_nonce.insert(agg_nonce))
+    }
+
+    /// Check that everything needed to sign is in place, short of the secret nonce (which some
+    /// signers keep to themselves), so that a step signing several inputs can check them all before
+    /// using up the secret nonce of any.
+    fn check_ready_to_sign(&self, key_ctx: &KeyCtx) -> Result<()> {
+        key_ctx.key_agg_ctx.as_ref().ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        key_ctx.my_key_share().ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        self.my_nonce_share.as_ref().ok_or(ProtocolErrorKind::MissingNonceShare)?;
+        self.aggregated_nonce.as_ref().ok_or(ProtocolErrorKind::MissingAggNonce)?;
+        Ok(())
+    }
+
+    fn sign_partial(&mut self, key_ctx: &KeyCtx, message: Vec<u8>, signer: &dyn Signer) -> Result<&PartialSignature> {
+        let key_agg_ctx = key_ctx.key_agg_ctx.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        let my_key_share = key_ctx.my_key_share()
+            .ok_or(ProtocolErrorKind::MissingKeyShare)?;
+        let my_nonce_share = self.my_nonce_share.as_mut()
+            .ok_or(ProtocolErrorKind::MissingNonceShare)?;
+        let aggregated_nonce = &self.aggregated_nonce.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggNonce)?;
+        // Only take the secret nonce once nothing else can stop it being used, so that signing too
+        // early (before the nonce shares are aggregated) doesn't waste it:
+        let sec_nonce = my_nonce_share.sec_nonce.take();
+
+        let session = SigningSession { key_agg_ctx, aggregated_nonce, adaptor_point: self.adaptor_point, message: &message };
+        let sig = signer.sign_partial(&session, my_key_share, &my_nonce_share.pub_nonce, sec_nonce)?;
+        self.message = Some(message);
+        Ok(self.my_partial_sig.insert(sig))
+    }
+
+    fn get_partial_signatures(&self) -> Option<[PartialSignature; 2]> {
+        Some(if self.am_buyer {
+            [self.my_partial_sig?, self.peers_partial_sig?]
+        } else {
+            [self.peers_partial_sig?, self.my_partial_sig?]
+        })
+    }
+
+    fn aggregate_partial_signatures(&mut self, key_ctx: &KeyCtx) -> Result<&AdaptorSignature> {
+        let key_agg_ctx = key_ctx.key_agg_ctx.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        let aggregated_nonce = &self.aggregated_nonce.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggNonce)?;
+        let partial_signatures = self.get_partial_signatures()
+            .ok_or(ProtocolErrorKind::MissingPartialSig)?;
+        let message = &self.message.as_ref()
+            .ok_or(ProtocolErrorKind::MissingPartialSig)?[..];
+
+        let sig = musig2::adaptor::aggregate_partial_signatures(key_agg_ctx, aggregated_nonce,
+            self.adaptor_point, partial_signatures, message)?;
+        Ok(self.aggregated_sig.insert(sig))
+    }
+
+    /// Aggregate the partial signatures without checking the final signature, returning it along
+    /// with what it takes to check it (alone or in a batch with others). The steps are those of
+    /// [`musig2::adaptor::aggregate_partial_signatures`], short of its final check.
+    fn aggregate_partial_signatures_unverified(&self, key_ctx: &KeyCtx) -> Result<UnverifiedSig> {
+        let key_agg_ctx = key_ctx.key_agg_ctx.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+        let aggregated_nonce = &self.aggregated_nonce.as_ref()
+            .ok_or(ProtocolErrorKind::MissingAggNonce)?;
+        let partial_signatures = self.get_partial_signatures()
+            .ok_or(ProtocolErrorKind::MissingPartialSig)?;
+        let message = &self.message.as_ref()
+            .ok_or(ProtocolErrorKind::MissingPartialSig)?[..];
+
+        let aggregated_pub_key: Point = key_agg_ctx.aggregated_pubkey();
+        let nonce_coefficient: MaybeScalar = aggregated_nonce.nonce_coefficient(aggregated_pub_key, message);
+        let final_nonce: Point = aggregated_nonce.final_nonce(nonce_coefficient);
+        let adapted_nonce = final_nonce + self.adaptor_point;
+        let challenge: MaybeScalar = musig2::compute_challenge_hash_tweak(
+            &adapted_nonce.serialize_xonly(), &aggregated_pub_key, message);
+        let tweak_sum = key_agg_ctx.tweak_sum().unwrap_or(MaybeScalar::Zero);
+        let s = partial_signatures.into_iter().sum::<PartialSignature>()
+            + (challenge * tweak_sum).negate_if(aggregated_pub_key.parity());
+
+        // If the adapted nonce has odd y, the signers will have negated their nonces when signing:
+        let effective_nonce = if adapted_nonce.has_even_y() { final_nonce } else { -final_nonce };
+        Ok(UnverifiedSig {
+            sig: AdaptorSignature::new(final_nonce, s),
+            s,
+            effective_nonce,
+            challenge,
+            pub_key: aggregated_pub_key.to_even_y(),
+        })
+    }
+
+    /// Aggregate the partial signatures of each of the given signing contexts, checking the final
+    /// signatures all at once. This shares the point multiplications of the checks between them:
+    /// the base point multiplication, and the one by each distinct aggregated pubkey (of which a
+    /// trade has just two). The final signatures are only kept if they all pass the check. Should
+    /// the batch fail, the peer's partial signatures are checked one by one, to find which is bad.
+    ///
+    /// # Errors
+    ///
+    /// Fails if any of the partial signatures are missing, or any final signature is invalid, with
+    /// the field of the peer's partial signature at fault, given along with each signing context.
+    fn aggregate_partial_signatures_batched<const N: usize>(batch: [(&mut Self, &KeyCtx, &'static str); N]) -> Result<()> {
+        let unverified_sigs = batch.iter()
+            .map(|(sig_ctx, key_ctx, _)| sig_ctx.aggregate_partial_signatures_unverified(key_ctx))
+            .collect::<Result<Vec<_>>>()?;
+        if UnverifiedSig::verify_batch(&unverified_sigs).is_err() {
+            if let Some((.., field)) = batch.iter().find(|(sig_ctx, key_ctx, _)| !sig_ctx.peers_partial_sig_is_valid(key_ctx)) {
+                return Err(ProtocolErrorKind::InvalidPeerPartialSig(field));
+            }
+            // Then it can only be our own partial signature (from a faulty signer) that is bad:
+            return Err(VerifyError::BadSignature.into());
+        }
+        for ((sig_ctx, ..), unverified_sig) in batch.into_iter().zip(unverified_sigs) {
+            sig_ctx.aggregated_sig = Some(unverified_sig.sig);
+        }
+        Ok(())
+    }
+
+    /// Whether the peer's partial signature is valid, checked on its own against the peer's key &
+    /// nonce shares, as it is only worth doing to find which signature spoilt a failed batch.
+    fn peers_partial_sig_is_valid(&self, key_ctx: &KeyCtx) -> bool {
+        let (Some(key_agg_ctx), Some(aggregated_nonce), Some(peers_partial_sig), Some(peers_nonce_share), Some(message)) = (
+            key_ctx.key_agg_ctx.as_ref(), self.aggregated_nonce.as_ref(), self.peers_partial_sig,
+            self.peers_nonce_share.as_ref(), self.message.as_ref()) else { return false };
+        let Some(peers_key_share) = key_ctx.peers_key_share() else { return false };
+        musig2::adaptor::verify_partial(key_agg_ctx, peers_partial_sig, aggregated_nonce, self.adaptor_point,
+            peers_key_share.pub_key, peers_nonce_share, &message[..]).is_ok()
+    }
+}
+
+/// A final signature aggregated from partial signatures but not yet checked, with the parts of the
+/// check `s*G == R + e*P` it must pass: its scalar `s`, the nonce `R` (negated if the adapted nonce
+/// has odd y), the challenge `e` and the (even y) aggregated pubkey `P`.
+struct UnverifiedSig {
+    sig: AdaptorSignature,
+    s: MaybeScalar,
+    effective_nonce: Point,
+    challenge: MaybeScalar,
+    pub_key: Point,
+}
+
+impl UnverifiedSig {
+    /// Check the given signatures all at once, as in BIP340 batch verification: with random weights
+    /// `a_i` (the first being one), check `(sum a_i*s_i)*G == sum a_i*R_i + sum a_i*e_i*P_i`, which
+    /// holds for all but a negligible fraction of weights unless every signature is valid. The last
+    /// sum is taken by pubkey, with one point multiplication for each distinct pubkey.
+    fn verify_batch(unverified_sigs: &[Self]) -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let mut lhs = MaybeScalar::Zero;
+        let mut rhs_terms = Vec::with_capacity(unverified_sigs.len() + 2);
+        let mut challenge_sums: Vec<(Point, MaybeScalar)> = Vec::with_capacity(2);
+        for (i, unverified_sig) in unverified_sigs.iter().enumerate() {
+            let weight = if i == 0 { Scalar::one() } else { Scalar::random(&mut rng) };
+            lhs += unverified_sig.s * weight;
+            rhs_terms.push(MaybePoint::Valid(if i == 0 {
+                unverified_sig.effective_nonce
+            } else {
+                unverified_sig.effective_nonce * weight
+            }));
+            let weighted_challenge = unverified_sig.challenge * weight;
+            match challenge_sums.iter_mut().find(|(pub_key, _)| *pub_key == unverified_sig.pub_key) {
+                Some((_, challenge_sum)) => *challenge_sum += weighted_challenge,
+                None => challenge_sums.push((unverified_sig.pub_key, weighted_challenge)),
+            }
+        }
+        rhs_terms.extend(challenge_sums.into_iter().map(|(pub_key, challenge_sum)| challenge_sum * pub_key));
+        if lhs * G != MaybePoint::sum(rhs_terms) {
+            return Err(VerifyError::BadSignature.into());
+        }
+        Ok(())
+    }
+}
+
+/// The message to sign for the given tx in the given signing session. Those of the first session are
+/// the bare tx, as they were before sessions could be reset.
+fn session_message(tx: &[u8], signing_session: u32) -> Vec<u8> {
+    let mut message = tx.to_vec();
+    if signing_session != 0 {
+        message.extend_from_slice(format!(" (signing session {})", signing_session).as_bytes());
+    }
+    message
+}
+
+/// The message to sign for the swap tx re-signed at the given fee rate, with which no signature on
+/// the swap tx at any other fee rate can be confused.
+fn swap_tx_fee_bump_message(tx: &[u8], signing_session: u32, swap_tx_fee_rate: f64) -> Vec<u8> {
+    let mut message = session_message(tx, signing_session);
+    message.extend_from_slice(format!(" (swap tx fee rate {})", swap_tx_fee_rate).as_bytes());
+    message
+}
+
+/// The message to sign for the given tx re-signed at the given prepared tx fee rate, with which no
+/// signature on the tx at any other fee rate can be confused.
+fn fee_rate_change_message(tx: &[u8], signing_session: u32, prepared_tx_fee_rate: f64) -> Vec<u8> {
+    let mut message = session_message(tx, signing_session);
+    message.extend_from_slice(format!(" (prepared tx fee rate {})", prepared_tx_fee_rate).as_bytes());
+    message
+}
+
+const NONCE_COMMITMENT_TAG: &[u8] = b"MuSigTradeProtocol/nonce commitment";
+
+/// The commitment to a public nonce share: a tagged hash of its serialization, to be sent to the
+/// peer ahead of the nonce share itself when committing to nonces.
+#[must_use]
+pub fn nonce_commitment(pub_nonce: &PubNonce) -> [u8; 32] {
+    let tag_hash = Sha256::digest(NONCE_COMMITMENT_TAG);
+    Sha256::new().chain_update(tag_hash).chain_update(tag_hash).chain_update(pub_nonce.serialize()).finalize().into()
+}
+
+/// Partially sign each of the given tx inputs (named by the tx & input, to get the message to sign
+/// from), with the signing context of the input and the key context of the output it spends. Every
+/// input is checked to be ready to sign first, so that none of the secret nonces is used up should
+/// any input fail the check. The inputs are then signed on the rayon thread pool (of a thread per
+/// core, shared by every trade), as signing takes long enough to be worth spreading over the cores,
+/// and far longer with a remote signer, whose calls are then made at once as far as the pool allows.
+/// (The inputs are signed in turn on the calling thread instead if the pool has just the one thread,
+/// and the final signatures are checked in a batch, on one thread.)
+///
+/// # Errors
+///
+/// Fails with [`ProtocolErrorKind::SigningInput`] for the first input to fail (in the given order),
+/// once every input has been signed (or failed to be).
+fn sign_inputs<const N: usize>(mut inputs: [(&'static str, &mut SigCtx, &KeyCtx); N],
+                               message: impl Fn(&str) -> Vec<u8> + Sync, signer: &dyn Signer) -> Result<()> {
+    let at_input = |index, input, source| ProtocolErrorKind::SigningInput { index, input, source: Box::new(source) };
+    for (index, (input, sig_ctx, key_ctx)) in inputs.iter().enumerate() {
+        sig_ctx.check_ready_to_sign(key_ctx).map_err(|e| at_input(index, input, e))?;
+    }
+    let sign = |(input, sig_ctx, key_ctx): &mut (&'static str, &mut SigCtx, &KeyCtx)|
+        sig_ctx.sign_partial(key_ctx, message(input), signer).map(drop);
+    // Handing the inputs to a pool of just the one thread would only add the wait for it to wake:
+    let results: Vec<_> = if rayon::current_num_threads() > 1 {
+        inputs.par_iter_mut().map(sign).collect()
+    } else {
+        inputs.iter_mut().map(sign).collect()
+    };
+    results.into_iter().zip(inputs).enumerate()
+        .try_for_each(|(index, (result, (input, ..)))| result.map_err(|e| at_input(index, input, e)))
+}
+
+fn signer_or_default(signer: Option<&Arc<dyn Signer>>) -> &dyn Signer {
+    signer.map_or(&LocalSigner, |signer| &**signer)
+}
+
+type Result<T> = std::result::Result<T, ProtocolErrorKind>;
+
+/// Why a protocol step failed: either some input or earlier step it depends on is missing, or one
+/// of the `MuSig` primitives rejected what it was given (typically something sent by the peer).
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum ProtocolErrorKind {
+    #[error("missing key share")]
+    MissingKeyShare,
+    #[error("missing nonce share")]
+    MissingNonceShare,
+    #[error("missing partial signature")]
+    MissingPartialSig,
+    #[error("missing aggregated pubkey")]
+    MissingAggPubKey,
+    #[error("missing aggregated nonce")]
+    MissingAggSig,
+    #[error("missing aggregated signature")]
+    MissingAggNonce,
+    #[error("missing nonce commitment")]
+    MissingNonceCommitment,
+    #[error("nonce share doesn't match its commitment")]
+    MismatchedNonceCommitment,
+    #[error("peer's nonce commitments have already been set to different ones")]
+    ChangedNonceCommitment,
+    #[error("signing session cannot be reset in phase {0:?}")]
+    SigningSessionClosed(TradePhase),
+    #[error("trade cannot be cancelled in phase {0:?}, as its deposit tx is published")]
+    CancellationClosed(TradePhase),
+    #[error("prepared tx fee rate cannot be changed in phase {0:?}")]
+    FeeRateChangeClosed(TradePhase),
+    #[error("no fee rate change is under way")]
+    MissingFeeRateChange,
+    #[error("swap tx cannot be re-signed in phase {0:?}")]
+    SwapTxFeeBumpClosed(TradePhase),
+    #[error("swap tx fee rate must exceed the current {0}")]
+    SwapTxFeeRateNotRaised(f64),
+    #[error("no swap tx fee bump is under way")]
+    MissingSwapTxFeeBump,
+    #[error("nonce has already been used")]
+    NonceReuse,
+    #[error("{source} (signing tx input {index}, the {input})")]
+    SigningInput { index: usize, input: &'static str, source: Box<ProtocolErrorKind> },
+    #[error("nonce is zero")]
+    ZeroNonce,
+    #[error("public-private key mismatch")]
+    MismatchedKeyPair,
+    #[error("mismatched adaptor and final signature")]
+    MismatchedSigs,
+    #[error("missing identity key")]
+    MissingIdentityKey,
+    #[error("peer's identity key has already been set to a different key")]
+    ChangedIdentityKey,
+    #[error("invalid peer signature on {0:?} payload")]
+    InvalidPeerSignature(PayloadKind),
+    #[error("invalid peer partial signature")]
+    InvalidPeerPartialSig(&'static str),
+    #[error("invalid mediator signature on redirect tx receivers")]
+    InvalidMediatorSignature,
+    #[error("peer's role {peers:?} does not complement our role {mine:?}")]
+    MismatchedPeerRole { mine: Role, peers: Role },
+    #[error("missing trade amounts")]
+    MissingAmounts,
+    #[error("funding input {0} is spent twice")]
+    DuplicateFundingInput(usize),
+    #[error("invalid ownership proof of funding input {0}")]
+    InvalidOwnershipProof(usize),
+    #[error("funding input {0} spends an outpoint to avoid")]
+    AvoidedFundingInput(usize),
+    #[error("outpoint {0} to use is not spent by the funding inputs")]
+    UnusedCoinControlOutpoint(usize),
+    #[error("outpoint {0} to avoid is also one to use")]
+    ConflictingCoinControl(usize),
+    #[error("funding inputs of {funded} sats short of the {needed} sats needed")]
+    InsufficientFunding { needed: u64, funded: u64 },
+    #[error("{0} failed re-validation before broadcast, as {1}")]
+    InvalidTxToPublish(&'static str, TxCheckFailure),
+    #[error("signer failed: {0}")]
+    Signer(Box<dyn std::error::Error + Send + Sync>),
+    KeyAgg(#[from] musig2::errors::KeyAggError),
+    Signing(#[from] musig2::errors::SigningError),
+    Verify(#[from] musig2::errors::VerifyError),
+    InvalidSecretKeys(#[from] musig2::errors::InvalidSecretKeysError),
+    ZeroScalar(#[from] secp::errors::ZeroScalarError),
+}
+
+impl ProtocolErrorKind {
+    /// This failure, as having occurred in the given trade, in its current phase.
+    #[must_use]
+    pub fn in_trade(self, trade_model: &TradeModel) -> ProtocolError {
+        ProtocolError { trade: Some((trade_model.trade_id.clone(), trade_model.phase())), ..self.into() }
+    }
+
+    /// The proto path of the input at fault for this failure, where it is known by the kind alone,
+    /// relative to the message holding it.
+    fn input(&self) -> Option<String> {
+        match self {
+            Self::DuplicateFundingInput(i) | Self::InvalidOwnershipProof(i) | Self::AvoidedFundingInput(i) =>
+                Some(format!("fundingInputs[{}]", i)),
+            Self::UnusedCoinControlOutpoint(i) => Some(format!("coinControl.useOutpoints[{}]", i)),
+            Self::ConflictingCoinControl(i) => Some(format!("coinControl.avoidOutpoints[{}]", i)),
+            Self::ChangedIdentityKey => Some("peersIdentityPubKey".to_owned()),
+            Self::InvalidPeerPartialSig(field) => Some((*field).to_owned()),
+            Self::SigningInput { source, .. } => source.input(),
+            Self::MismatchedPeerRole { .. } => Some("peersRole".to_owned()),
+            _ => None,
+        }
+    }
+}
+
+/// A protocol step failure, with where it occurred as far as known: the trade (and its phase at
+/// the time) and the input at fault, by its proto path. This is carried into the failure's gRPC
+/// status (in an `ErrorInfo`) and its display, so that the failure may be pinpointed from either.
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+    pub trade: Option<(String, TradePhase)>,
+    pub input: Option<String>,
+}
+
+impl ProtocolError {
+    /// This failure, as down to the input with the given proto path.
+    #[must_use]
+    pub fn at_input(mut self, input: impl Into<String>) -> Self {
+        self.input = Some(input.into());
+        self
+    }
+
+    /// Prefix the path of the input at fault (if known) with that of the message field it was
+    /// nested in.
+    #[must_use]
+    pub fn in_field(mut self, parent: &str) -> Self {
+        self.input = self.input.map(|input| format!("{}.{}", parent, input));
+        self
+    }
+}
+
+impl From<ProtocolErrorKind> for ProtocolError {
+    fn from(kind: ProtocolErrorKind) -> Self {
+        let input = kind.input();
+        Self { kind, trade: None, input }
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some((trade_id, phase)) = &self.trade {
+            write!(f, " in trade {} at phase {:?}", trade_id, phase)?;
+        }
+        if let Some(input) = &self.input {
+            write!(f, " (input: {})", input)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+
+fn check_funding_inputs(inputs: &[FundingInput], trade_id: &str, others: &[FundingInput]) -> Result<()> {
+    for (i, input) in inputs.iter().enumerate() {
+        let outpoint = (input.txid, input.vout);
+        if inputs[..i].iter().chain(others).any(|other| (other.txid, other.vout) == outpoint) {
+            return Err(ProtocolErrorKind::DuplicateFundingInput(i));
+        }
+        let message = identity::funding_input_ownership_message(trade_id, &input.script_pub_key(), &input.txid,
+            input.vout, input.amount);
+        musig2::verify_single(input.owner_pub_key, input.ownership_proof, message)
+            .map_err(|_| ProtocolErrorKind::InvalidOwnershipProof(i))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use musig2::adaptor;
+    use rand::prelude::*;
+    use std::mem;
+
+    use super::*;
+
+    const PROPERTY_TEST_CASES: usize = 32;
+
+    type Step = fn(&mut TradeModel) -> Result<()>;
+
+    /// One party of a trade between two in-process trade models, recording each distinct phase its
+    /// trade model passes through.
+    struct Party {
+        trade_model: TradeModel,
+        phases: Vec<TradePhase>,
+    }
+
+    impl Party {
+        fn new(role: Role) -> Self {
+            let trade_model = TradeModel::new("trade".to_owned(), role);
+            Self { phases: vec![trade_model.phase()], trade_model }
+        }
+
+        fn step<T>(&mut self, step: impl FnOnce(&mut TradeModel) -> Result<T>) -> T {
+            let value = step(&mut self.trade_model).unwrap();
+            let phase = self.trade_model.phase();
+            if self.phases.last() != Some(&phase) {
+                self.phases.push(phase);
+            }
+            value
+        }
+
+        /// Try each of the given steps (in random order) before its inputs are ready, checking that
+        /// it fails without moving the trade model on, or wasting anything a later retry needs.
+        fn try_out_of_turn(&mut self, rng: &mut impl Rng, steps: &mut [Step]) {
+            steps.shuffle(rng);
+            for step in steps {
+                let phase = self.trade_model.phase();
+                assert!(step(&mut self.trade_model).is_err());
+                assert_eq!(self.trade_model.phase(), phase);
+            }
+        }
+    }
+
+    /// Each signing context of the trade model, with the key context of the output it spends.
+    fn sig_ctxs(trade_model: &TradeModel) -> [(&SigCtx, &KeyCtx); 7] {
+        let [buyer_key_ctx, seller_key_ctx] = [&trade_model.buyer_output_key_ctx, &trade_model.seller_output_key_ctx];
+        [
+            (&trade_model.swap_tx_input_sig_ctx, seller_key_ctx),
+            (&trade_model.buyers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx),
+            (&trade_model.buyers_warning_tx_seller_input_sig_ctx, seller_key_ctx),
+            (&trade_model.sellers_warning_tx_buyer_input_sig_ctx, buyer_key_ctx),
+            (&trade_model.sellers_warning_tx_seller_input_sig_ctx, seller_key_ctx),
+            (&trade_model.buyers_redirect_tx_input_sig_ctx, buyer_key_ctx),
+            (&trade_model.sellers_redirect_tx_input_sig_ctx, seller_key_ctx),
+        ]
+    }
+
+    fn aggregated_pub_keys(trade_model: &TradeModel) -> [Point; 2] {
+        [&trade_model.buyer_output_key_ctx, &trade_model.seller_output_key_ctx]
+            .map(|ctx| ctx.aggregated_key.as_ref().unwrap().pub_key)
+    }
+
+    /// Check our partial signature on every tx input, and the peer's on each input of our own txs.
+    fn check_partial_signatures(trade_model: &TradeModel) {
+        for (sig_ctx, key_ctx) in sig_ctxs(trade_model) {
+            let key_agg_ctx = key_ctx.key_agg_ctx.as_ref().unwrap();
+            let aggregated_nonce = sig_ctx.aggregated_nonce.as_ref().unwrap();
+            let message = sig_ctx.message.as_ref().unwrap();
+            let my_key_share = key_ctx.my_key_share().unwrap().pub_key;
+            let my_nonce_share = &sig_ctx.my_nonce_share.as_ref().unwrap().pub_nonce;
+            adaptor::verify_partial(key_agg_ctx, sig_ctx.my_partial_sig.unwrap(), aggregated_nonce,
+                sig_ctx.adaptor_point, my_key_share, my_nonce_share, message).unwrap();
+            if let Some(peers_partial_sig) = sig_ctx.peers_partial_sig {
+                let peers_key_share = key_ctx.peers_key_share().unwrap().pub_key;
+                adaptor::verify_partial(key_agg_ctx, peers_partial_sig, aggregated_nonce, sig_ctx.adaptor_point,
+                    peers_key_share, sig_ctx.peers_nonce_share.as_ref().unwrap(), message).unwrap();
+            }
+        }
+    }
+
+    fn exchange_key_and_nonce_shares(rng: &mut impl Rng, buyer: &mut Party, seller: &mut Party) {
+        for party in [&mut *buyer, &mut *seller] {
+            party.step(TradeModel::init_my_key_shares);
+            party.try_out_of_turn(rng, &mut [TradeModel::aggregate_key_shares, TradeModel::init_my_nonce_shares,
+                TradeModel::aggregate_nonce_shares, TradeModel::sign_partial]);
+        }
+        let [b1, b2] = buyer.trade_model.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = seller.trade_model.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        buyer.trade_model.set_peer_key_shares(s1, s2);
+        seller.trade_model.set_peer_key_shares(b1, b2);
+        for party in [&mut *buyer, &mut *seller] {
+            party.step(TradeModel::aggregate_key_shares);
+            party.step(TradeModel::init_my_nonce_shares);
+            party.try_out_of_turn(rng, &mut [TradeModel::aggregate_nonce_shares, TradeModel::sign_partial,
+                TradeModel::aggregate_partial_signatures]);
+        }
+        assert_eq!(aggregated_pub_keys(&buyer.trade_model), aggregated_pub_keys(&seller.trade_model));
+
+        seller.trade_model.peer_nonce_shares_mut().set(buyer.trade_model.get_my_nonce_shares().unwrap().cloned());
+        buyer.trade_model.peer_nonce_shares_mut().set(seller.trade_model.get_my_nonce_shares().unwrap().cloned());
+    }
+
+    fn exchange_partial_signatures(rng: &mut impl Rng, buyer: &mut Party, seller: &mut Party) {
+        for party in [&mut *buyer, &mut *seller] {
+            party.step(TradeModel::aggregate_nonce_shares);
+            party.step(TradeModel::sign_partial);
+            party.try_out_of_turn(rng, &mut [TradeModel::aggregate_partial_signatures,
+                TradeModel::aggregate_swap_tx_partial_signatures, |t| t.aggregate_private_keys_for_my_output().map(drop)]);
+        }
+        // The buyer redacts its swap tx partial signature until payment is started:
+        let mut buyer_sigs = buyer.trade_model.get_my_partial_signatures_on_peer_txs().unwrap().cloned();
+        let buyers_swap_tx_sig = mem::replace(&mut buyer_sigs.swap_tx_input_partial_signature, Redactable::Redacted)
+            .value().unwrap();
+        assert_eq!(seller.trade_model.peers_swap_tx_signature_state(), SwapTxSignatureState::NotExchanged);
+        seller.trade_model.peer_partial_signatures_on_my_txs_mut().set(buyer_sigs);
+        assert_eq!(seller.trade_model.peers_swap_tx_signature_state(), SwapTxSignatureState::Promised);
+        let seller_sigs = seller.trade_model.get_my_partial_signatures_on_peer_txs().unwrap().cloned();
+        buyer.trade_model.peer_partial_signatures_on_my_txs_mut().set(seller_sigs);
+        for party in [&mut *buyer, &mut *seller] {
+            party.step(TradeModel::aggregate_partial_signatures);
+            check_partial_signatures(&party.trade_model);
+            party.step(|t| { t.set_deposit_tx_published(); Ok(()) });
+        }
+        seller.try_out_of_turn(rng, &mut [TradeModel::aggregate_swap_tx_partial_signatures]);
+        seller.trade_model.set_swap_tx_input_peers_partial_signature(buyers_swap_tx_sig);
+        assert_eq!(seller.trade_model.peers_swap_tx_signature_state(), SwapTxSignatureState::Revealed);
+        seller.step(TradeModel::aggregate_swap_tx_partial_signatures);
+    }
+
+    fn close_trade(buyer: &mut Party, seller: &mut Party, via_swap_tx: bool) {
+        let sellers_key_share = seller.step(|t| t.get_my_private_key_share_for_peer_output());
+        if via_swap_tx {
+            let swap_tx_signature = seller.step(|t| t.compute_swap_tx_input_signature());
+            let seller_output_pub_key = aggregated_pub_keys(&seller.trade_model)[1];
+            musig2::verify_single(seller_output_pub_key, swap_tx_signature, b"swap tx input").unwrap();
+            buyer.step(|t| t.recover_seller_private_key_share_for_buyer_output(&swap_tx_signature));
+            let recovered_key_share = buyer.trade_model.buyer_output_key_ctx.peers_key_share()
+                .and_then(|k| k.prv_key.as_ref()).unwrap();
+            assert_eq!(*recovered_key_share.expose_secret(), sellers_key_share);
+        } else {
+            buyer.step(|t| t.set_peer_private_key_share_for_my_output(sellers_key_share));
+            let buyers_key_share = buyer.step(|t| t.get_my_private_key_share_for_peer_output());
+            seller.step(|t| t.set_peer_private_key_share_for_my_output(buyers_key_share));
+            let sellers_key = seller.step(|t| t.aggregate_private_keys_for_my_output().copied());
+            assert_eq!(sellers_key.base_point_mul(), aggregated_pub_keys(&seller.trade_model)[1]);
+        }
+        let buyers_key = buyer.step(|t| t.aggregate_private_keys_for_my_output().copied());
+        assert_eq!(buyers_key.base_point_mul(), aggregated_pub_keys(&buyer.trade_model)[0]);
+        for party in [buyer, seller] {
+            party.step(|t| { t.set_closed(); Ok(()) });
+        }
+    }
+
+    /// Play many whole trades between fresh trade models (so with random key & nonce shares each
+    /// time), with random roles and closure, trying their fallible steps out of turn along the way.
+    #[test]
+    fn random_trades_uphold_protocol_invariants() {
+        let mut rng = thread_rng();
+        for _ in 0..PROPERTY_TEST_CASES {
+            let roles = [(Role::BuyerAsMaker, Role::SellerAsTaker), (Role::BuyerAsTaker, Role::SellerAsMaker)];
+            let &(buyer_role, seller_role) = roles.choose(&mut rng).unwrap();
+            let (mut buyer, mut seller) = (Party::new(buyer_role), Party::new(seller_role));
+            exchange_key_and_nonce_shares(&mut rng, &mut buyer, &mut seller);
+            exchange_partial_signatures(&mut rng, &mut buyer, &mut seller);
+            close_trade(&mut buyer, &mut seller, rng.gen());
+
+            // Only the seller signs the swap tx, so the buyer's trade model never reaches that phase:
+            let phases = [TradePhase::Initialized, TradePhase::KeySharesGenerated, TradePhase::NonceSharesGenerated,
+                TradePhase::PartialSignaturesGenerated, TradePhase::DepositTxSigned, TradePhase::DepositTxPublished,
+                TradePhase::SwapTxSigned, TradePhase::Closed];
+            assert_eq!(seller.phases, phases);
+            assert_eq!(buyer.phases, phases.into_iter().filter(|&p| p != TradePhase::SwapTxSigned).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn key_ctxs_of_more_than_two_signers_aggregate_alike() -> Result<()> {
+        let mut ctxs: Vec<_> = (0..3).map(|index| KeyCtx::new(3, index)).collect();
+        for ctx in &mut ctxs {
+            ctx.init_my_key_share(&LocalSigner)?;
+        }
+        let key_shares: Vec<_> = ctxs.iter()
+            .map(|ctx| ctx.my_key_share().map(|k| (k.pub_key, *k.prv_key.as_ref().unwrap().expose_secret())).unwrap())
+            .collect();
+        for ctx in &mut ctxs {
+            for (index, &(pub_key, prv_key)) in key_shares.iter().enumerate() {
+                if index != ctx.my_index {
+                    ctx.key_shares[index] = Some(KeyPair::from_public(pub_key));
+                    ctx.set_others_prv_key_share(index, prv_key)?;
+                }
+            }
+            ctx.aggregate_key_shares()?;
+        }
+        let aggregated_pub_key = ctxs[0].aggregated_key.as_ref().unwrap().pub_key;
+        for ctx in &mut ctxs {
+            assert_eq!(ctx.aggregated_key.as_ref().unwrap().pub_key, aggregated_pub_key);
+            assert_eq!(ctx.aggregate_prv_key_shares(&LocalSigner)?.base_point_mul(), aggregated_pub_key);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nonce_shares_must_match_peers_commitments() -> Result<()> {
+        let mut trade_models = [Role::BuyerAsTaker, Role::SellerAsMaker]
+            .map(|role| TradeModel::builder("trade".to_owned(), role).with_my_key_shares().unwrap().build());
+        let [b1, b2] = trade_models[0].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = trade_models[1].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [buyer, seller] = &mut trade_models;
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.commit_to_nonces = true;
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+        }
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        assert!(matches!(buyer.aggregate_nonce_shares(), Err(ProtocolErrorKind::MissingNonceCommitment)));
+
+        // A nonce share changed after its commitment was sent (by a relay, say) is caught:
+        buyer.set_peer_nonce_commitments(&seller.get_my_nonce_commitments().unwrap())?;
+        let regrind = SecNonce::build(thread_rng().gen::<[u8; 32]>()).build().public_nonce();
+        buyer.peer_nonce_shares_mut().buyers_redirect_tx_input_nonce_share.replace(regrind.clone());
+        assert!(matches!(buyer.aggregate_nonce_shares(), Err(ProtocolErrorKind::MismatchedNonceCommitment)));
+
+        // Nor may the peer commit afresh, once it has seen our nonce shares:
+        let mut commitments = seller.get_my_nonce_commitments().unwrap();
+        commitments.buyers_redirect_tx_input_nonce_commitment = nonce_commitment(&regrind);
+        assert!(matches!(buyer.set_peer_nonce_commitments(&commitments), Err(ProtocolErrorKind::ChangedNonceCommitment)));
+
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        buyer.aggregate_nonce_shares()
+    }
+
+    #[test]
+    fn reset_signing_session_starts_afresh() -> Result<()> {
+        let mut trade_models = [Role::BuyerAsTaker, Role::SellerAsMaker]
+            .map(|role| TradeModel::builder("trade".to_owned(), role).with_my_key_shares().unwrap().build());
+        let [b1, b2] = trade_models[0].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = trade_models[1].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [buyer, seller] = &mut trade_models;
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        buyer.set_peer_identity_pub_key(seller.get_my_identity_pub_key().unwrap())?;
+        seller.set_peer_identity_pub_key(buyer.get_my_identity_pub_key().unwrap())?;
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+        }
+        seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+        seller.aggregate_nonce_shares()?;
+        seller.sign_partial()?;
+        let old_nonce = buyer.get_my_nonce_shares().unwrap().swap_tx_input_nonce_share.clone();
+        let old_signature = buyer.sign_payload(PayloadKind::NonceShares, &[b"nonce"])?;
+
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.reset_signing_session()?;
+            assert_eq!((trade_model.phase(), trade_model.signing_session()), (TradePhase::NonceSharesGenerated, 1));
+        }
+        assert_ne!(*buyer.get_my_nonce_shares().unwrap().swap_tx_input_nonce_share, old_nonce);
+        assert!(seller.get_my_partial_signatures_on_peer_txs().is_none());
+        // A payload of the old session no longer passes, but one of the new session does:
+        assert!(seller.verify_peer_payload(PayloadKind::NonceShares, &[b"nonce"], &old_signature).is_err());
+        let signature = buyer.sign_payload(PayloadKind::NonceShares, &[b"nonce"])?;
+        seller.verify_peer_payload(PayloadKind::NonceShares, &[b"nonce"], &signature)?;
+
+        seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_nonce_shares()?;
+            trade_model.sign_partial()?;
+        }
+        seller.peer_partial_signatures_on_my_txs_mut().set(buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        buyer.peer_partial_signatures_on_my_txs_mut().set(seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_partial_signatures()?;
+            assert!(matches!(trade_model.reset_signing_session(),
+                Err(ProtocolErrorKind::SigningSessionClosed(TradePhase::DepositTxSigned))));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_before_deposit_wipes_signing_session() -> Result<()> {
+        let mut trade_models = [Role::BuyerAsTaker, Role::SellerAsMaker]
+            .map(|role| TradeModel::builder("trade".to_owned(), role).with_my_key_shares().unwrap().build());
+        let [b1, b2] = trade_models[0].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = trade_models[1].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [buyer, seller] = &mut trade_models;
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+        }
+        seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_nonce_shares()?;
+            trade_model.sign_partial()?;
+        }
+        seller.peer_partial_signatures_on_my_txs_mut().set(buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        buyer.peer_partial_signatures_on_my_txs_mut().set(seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_partial_signatures()?;
+        }
+
+        // Nothing more can be signed once the trade is cancelled, though it keeps the phase it reached:
+        seller.cancel_before_deposit(Cancellation::Mutual)?;
+        assert!(seller.get_my_nonce_shares().is_none());
+        assert!(seller.get_my_partial_signatures_on_peer_txs().is_none());
+        assert!(seller.sign_partial().is_err());
+        let summary = seller.summarize(None);
+        assert_eq!((summary.phase, summary.cancellation), (TradePhase::DepositTxSigned, Some(Cancellation::Mutual)));
+
+        buyer.set_deposit_tx_published();
+        assert!(matches!(buyer.cancel_before_deposit(Cancellation::Unilateral),
+            Err(ProtocolErrorKind::CancellationClosed(TradePhase::DepositTxPublished))));
+        assert_eq!(buyer.cancellation, None);
+        Ok(())
+    }
+
+    #[test]
+    fn fee_rate_change_re_signs_prepared_txs() -> Result<()> {
+        let mut trade_models = [Role::BuyerAsTaker, Role::SellerAsMaker]
+            .map(|role| TradeModel::builder("trade".to_owned(), role).with_my_key_shares().unwrap()
+                .fee_rates(10.0, 5.0).build());
+        let [b1, b2] = trade_models[0].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = trade_models[1].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [buyer, seller] = &mut trade_models;
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+            assert!(matches!(trade_model.start_fee_rate_change(8.0),
+                Err(ProtocolErrorKind::FeeRateChangeClosed(TradePhase::NonceSharesGenerated))));
+        }
+        seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_nonce_shares()?;
+            trade_model.sign_partial()?;
+        }
+        seller.peer_partial_signatures_on_my_txs_mut().set(buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        buyer.peer_partial_signatures_on_my_txs_mut().set(seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_partial_signatures()?;
+            trade_model.start_fee_rate_change(8.0)?;
+        }
+        let old_sig = buyer.buyers_warning_tx_buyer_input_sig_ctx.aggregated_sig;
+
+        seller.fee_rate_change_peer_nonce_shares_mut().unwrap().set(buyer.get_my_fee_rate_change_nonce_shares().unwrap().cloned());
+        buyer.fee_rate_change_peer_nonce_shares_mut().unwrap().set(seller.get_my_fee_rate_change_nonce_shares().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.sign_fee_rate_change()?;
+        }
+        seller.fee_rate_change_peer_partial_signatures_on_my_txs_mut().unwrap()
+            .set(buyer.get_my_fee_rate_change_partial_signatures_on_peer_txs().unwrap().cloned());
+        buyer.fee_rate_change_peer_partial_signatures_on_my_txs_mut().unwrap()
+            .set(seller.get_my_fee_rate_change_partial_signatures_on_peer_txs().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.complete_fee_rate_change()?;
+            assert_eq!((trade_model.prepared_tx_fee_rate, trade_model.pending_prepared_tx_fee_rate()), (Some(8.0), None));
+            assert_eq!(trade_model.phase(), TradePhase::DepositTxSigned);
+        }
+        assert_ne!(buyer.buyers_warning_tx_buyer_input_sig_ctx.aggregated_sig, old_sig);
+        assert!(matches!(buyer.complete_fee_rate_change(), Err(ProtocolErrorKind::MissingFeeRateChange)));
+        Ok(())
+    }
+
+    #[test]
+    fn batched_signature_aggregation_matches_musig2_and_keeps_nothing_on_failure() -> Result<()> {
+        let mut trade_models = [Role::BuyerAsTaker, Role::SellerAsMaker]
+            .map(|role| TradeModel::builder("trade".to_owned(), role).with_my_key_shares().unwrap().build());
+        let [b1, b2] = trade_models[0].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = trade_models[1].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [buyer, seller] = &mut trade_models;
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+        }
+        seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_nonce_shares()?;
+            trade_model.sign_partial()?;
+        }
+        seller.peer_partial_signatures_on_my_txs_mut().set(buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        buyer.peer_partial_signatures_on_my_txs_mut().set(seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+
+        // A single bad partial signature fails the whole batch, with none of the final signatures kept,
+        // and the bad one found by checking each in turn:
+        let peers_partial_sig = buyer.buyers_redirect_tx_input_sig_ctx.peers_partial_sig;
+        buyer.buyers_redirect_tx_input_sig_ctx.peers_partial_sig = buyer.swap_tx_input_sig_ctx.peers_partial_sig;
+        let err = buyer.aggregate_partial_signatures().unwrap_err();
+        assert!(matches!(err, ProtocolErrorKind::InvalidPeerPartialSig("peersRedirectTxInputPartialSignature")));
+        assert_eq!(ProtocolError::from(err).to_string(),
+            "invalid peer partial signature (input: peersRedirectTxInputPartialSignature)");
+        assert!(sig_ctxs(buyer).iter().all(|(sig_ctx, _)| sig_ctx.aggregated_sig.is_none()));
+        assert_eq!(buyer.phase(), TradePhase::PartialSignaturesGenerated);
+        buyer.buyers_redirect_tx_input_sig_ctx.peers_partial_sig = peers_partial_sig;
+
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_partial_signatures()?;
+            for (sig_ctx, key_ctx) in sig_ctxs(trade_model) {
+                let Some(sig) = sig_ctx.aggregated_sig else { continue };
+                let expected_sig = adaptor::aggregate_partial_signatures(key_ctx.key_agg_ctx.as_ref().unwrap(),
+                    sig_ctx.aggregated_nonce.as_ref().unwrap(), sig_ctx.adaptor_point,
+                    sig_ctx.get_partial_signatures().unwrap(), sig_ctx.message.as_ref().unwrap())?;
+                assert_eq!(sig, expected_sig);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn signing_checks_every_input_before_using_up_any_nonce() -> Result<()> {
+        let mut trade_models = [Role::BuyerAsTaker, Role::SellerAsMaker]
+            .map(|role| TradeModel::builder("trade".to_owned(), role).with_my_key_shares().unwrap().build());
+        let [b1, b2] = trade_models[0].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = trade_models[1].get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [buyer, seller] = &mut trade_models;
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        for trade_model in [&mut *buyer, &mut *seller] {
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+        }
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        buyer.aggregate_nonce_shares()?;
+
+        // An input not ready to sign fails the step, tagged with its index & name, before any of the
+        // secret nonces are used up, so the step may be retried once the input is ready:
+        let aggregated_nonce = buyer.sellers_redirect_tx_input_sig_ctx.aggregated_nonce.take();
+        let err = buyer.sign_partial().unwrap_err();
+        assert!(matches!(&err, ProtocolErrorKind::SigningInput { index: 6, input: "seller's redirect tx input", source }
+            if matches!(**source, ProtocolErrorKind::MissingAggNonce)));
+        assert_eq!(err.to_string(), format!("{} (signing tx input 6, the seller's redirect tx input)",
+            ProtocolErrorKind::MissingAggNonce));
+        assert!(sig_ctxs(buyer).iter().all(|(sig_ctx, _)| sig_ctx.my_partial_sig.is_none()
+            && sig_ctx.my_nonce_share.as_ref().is_some_and(|n| n.sec_nonce.is_some())));
+        buyer.sellers_redirect_tx_input_sig_ctx.aggregated_nonce = aggregated_nonce;
+
+        buyer.sign_partial()?;
+        assert!(sig_ctxs(buyer).iter().all(|(sig_ctx, _)| sig_ctx.my_partial_sig.is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn swap_tx_fee_bump_re_signs_just_the_swap_tx() -> Result<()> {
+        let mut rng = thread_rng();
+        let (mut buyer, mut seller) = (Party::new(Role::BuyerAsTaker), Party::new(Role::SellerAsMaker));
+        exchange_key_and_nonce_shares(&mut rng, &mut buyer, &mut seller);
+        exchange_partial_signatures(&mut rng, &mut buyer, &mut seller);
+        let (buyer, seller) = (&mut buyer.trade_model, &mut seller.trade_model);
+        assert!(matches!(buyer.start_swap_tx_fee_bump(20.0),
+            Err(ProtocolErrorKind::SwapTxFeeBumpClosed(TradePhase::DepositTxPublished))));
+        buyer.payment_receipts.push(PaymentReceipt { milestone: PaymentMilestone::Started, at: SystemTime::now(),
+            identity_signature: vec![] });
+        let old_swap_tx_signature = seller.compute_swap_tx_input_signature()?;
+        let old_warning_tx_sig = seller.sellers_warning_tx_seller_input_sig_ctx.aggregated_sig;
+
+        buyer.start_swap_tx_fee_bump(20.0)?;
+        seller.start_swap_tx_fee_bump(20.0)?;
+        *seller.swap_tx_fee_bump_peer_nonce_share_mut().unwrap() = buyer.get_my_swap_tx_fee_bump_nonce_share().cloned();
+        *buyer.swap_tx_fee_bump_peer_nonce_share_mut().unwrap() = seller.get_my_swap_tx_fee_bump_nonce_share().cloned();
+        // The buyer doesn't sign until it has the seller's partial signature, to complete the bump first:
+        assert!(matches!(buyer.sign_swap_tx_fee_bump(), Err(ProtocolErrorKind::MissingPartialSig)));
+        seller.sign_swap_tx_fee_bump()?;
+        *buyer.swap_tx_fee_bump_peer_partial_signature_mut().unwrap() = seller.get_my_swap_tx_fee_bump_partial_signature().copied();
+        buyer.sign_swap_tx_fee_bump()?;
+        *seller.swap_tx_fee_bump_peer_partial_signature_mut().unwrap() = buyer.get_my_swap_tx_fee_bump_partial_signature().copied();
+        buyer.complete_swap_tx_fee_bump()?;
+        seller.complete_swap_tx_fee_bump()?;
+        for trade_model in [&*buyer, &*seller] {
+            assert_eq!((trade_model.swap_tx_fee_rate, trade_model.pending_swap_tx_fee_rate()), (Some(20.0), None));
+        }
+        assert_eq!(seller.phase(), TradePhase::SwapTxSigned);
+        assert_eq!(seller.sellers_warning_tx_seller_input_sig_ctx.aggregated_sig, old_warning_tx_sig);
+        assert!(matches!(seller.start_swap_tx_fee_bump(15.0), Err(ProtocolErrorKind::SwapTxFeeRateNotRaised(_))));
+
+        let swap_tx_signature = seller.compute_swap_tx_input_signature()?;
+        let seller_output_pub_key = aggregated_pub_keys(seller)[1];
+        musig2::verify_single(seller_output_pub_key, swap_tx_signature, b"swap tx input (swap tx fee rate 20)").unwrap();
+        // The buyer may claim its output whichever version of the swap tx the seller publishes:
+        let sellers_key_share = seller.get_my_private_key_share_for_peer_output()?;
+        for signature in [swap_tx_signature, old_swap_tx_signature] {
+            buyer.buyer_output_key_ctx.peers_key_share_mut().as_mut().unwrap().prv_key = None;
+            buyer.recover_seller_private_key_share_for_buyer_output(&signature)?;
+            let recovered_key_share = buyer.buyer_output_key_ctx.peers_key_share()
+                .and_then(|k| k.prv_key.as_ref()).unwrap();
+            assert_eq!(*recovered_key_share.expose_secret(), sellers_key_share);
+        }
+        Ok(())
+    }
+}
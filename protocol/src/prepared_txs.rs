@@ -0,0 +1,151 @@
+//! The package of our own prepared txs, fully signed, for a trader to keep offline: our warning tx,
+//! our redirect tx (answering the peer's warning tx, should the peer publish it), and the output
+//! descriptor of the claim path of our warning tx's escrow output. With these, either tx may be
+//! broadcast (and the escrow claimed) from any wallet, should the daemon be lost mid-trade.
+//!
+//! As the txs themselves are still stand-ins, so are those packaged: each input is given by the
+//! message signed for it, with its final signature and the aggregated key it verifies against.
+
+use musig2::LiftedSignature;
+use secp::{MaybeScalar, Point};
+use std::fmt::Write as _;
+use std::prelude::rust_2021::*;
+
+use crate::{KeyCtx, ProtocolErrorKind, Role, SigCtx, TradeModel};
+
+/// The symbols of an output descriptor, in the order of their values for its checksum (BIP 380).
+const DESCRIPTOR_INPUT_CHARSET: &[u8] =
+    b"0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const DESCRIPTOR_CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Clone, Debug)]
+pub struct PreparedTxPackage {
+    pub trade_id: String,
+    pub my_role: Role,
+    pub prepared_tx_fee_rate: Option<f64>,
+    /// The inputs of our warning tx, spending the buyer's & the seller's deposit tx outputs.
+    pub warning_tx_buyer_input: SignedTxInput,
+    pub warning_tx_seller_input: SignedTxInput,
+    /// The input of our redirect tx, spending the peer's warning tx output.
+    pub redirect_tx_input: SignedTxInput,
+    /// The receivers of our redirect tx, by address & amount in sats.
+    pub redirect_receivers: Vec<(String, u64)>,
+    /// The descriptor (with its checksum) of our warning tx's escrow output, spendable by the key
+    /// path with both parties' consent, or by us alone by the claim path once its timelock expires.
+    /// This is only known given the claim timelock.
+    pub claim_descriptor: Option<String>,
+}
+
+/// A tx input signed with the peer: the message signed, its final (BIP 340) signature and the
+/// aggregated key of the output spent.
+#[derive(Clone, Debug)]
+pub struct SignedTxInput {
+    pub message: Vec<u8>,
+    pub signature: LiftedSignature,
+    pub pub_key: Point,
+}
+
+impl TradeModel {
+    /// The package of our own prepared txs, as last signed, with the claim descriptor of our warning
+    /// tx's escrow output given its timelock in blocks (if known).
+    ///
+    /// # Errors
+    ///
+    /// Fails if the warning & redirect txs haven't been signed yet, or their signatures have since
+    /// been discarded (as on cancellation).
+    pub fn prepared_tx_package(&self, claim_blocks: Option<u32>) -> Result<PreparedTxPackage, ProtocolErrorKind> {
+        let [buyer_key_ctx, seller_key_ctx] = [&self.buyer_output_key_ctx, &self.seller_output_key_ctx];
+        let (warning_tx_buyer_input, warning_tx_seller_input, redirect_tx_input, escrow_key_ctx, claim_key_ctx) =
+            if self.am_buyer() {
+                (&self.buyers_warning_tx_buyer_input_sig_ctx, &self.buyers_warning_tx_seller_input_sig_ctx,
+                    &self.buyers_redirect_tx_input_sig_ctx, seller_key_ctx, buyer_key_ctx)
+            } else {
+                (&self.sellers_warning_tx_buyer_input_sig_ctx, &self.sellers_warning_tx_seller_input_sig_ctx,
+                    &self.sellers_redirect_tx_input_sig_ctx, buyer_key_ctx, seller_key_ctx)
+            };
+        // As laid out in the protocol, our warning tx's escrow output has the aggregated key of the
+        // peer's deposit tx output, and a claim path with our key share for our own:
+        let claim_descriptor = claim_blocks.map(|claim_blocks| -> Result<_, ProtocolErrorKind> {
+            let escrow_key = escrow_key_ctx.aggregated_key.as_ref().ok_or(ProtocolErrorKind::MissingAggPubKey)?;
+            let claim_key = claim_key_ctx.my_key_share().ok_or(ProtocolErrorKind::MissingKeyShare)?;
+            Ok(claim_descriptor(escrow_key.pub_key, claim_key.pub_key, claim_blocks))
+        }).transpose()?;
+        Ok(PreparedTxPackage {
+            trade_id: self.trade_id.clone(),
+            my_role: self.my_role,
+            prepared_tx_fee_rate: self.prepared_tx_fee_rate,
+            warning_tx_buyer_input: warning_tx_buyer_input.signed_input(buyer_key_ctx)?,
+            warning_tx_seller_input: warning_tx_seller_input.signed_input(seller_key_ctx)?,
+            redirect_tx_input: redirect_tx_input.signed_input(if self.am_buyer() { buyer_key_ctx } else { seller_key_ctx })?,
+            redirect_receivers: self.redirect_receivers.clone(),
+            claim_descriptor,
+        })
+    }
+}
+
+impl SigCtx {
+    /// The input signed in this context, with its signature adapted with the zero secret, as the
+    /// warning & redirect txs are signed with no adaptor.
+    fn signed_input(&self, key_ctx: &KeyCtx) -> Result<SignedTxInput, ProtocolErrorKind> {
+        let adaptor_sig = self.aggregated_sig.ok_or(ProtocolErrorKind::MissingAggSig)?;
+        Ok(SignedTxInput {
+            message: self.message.clone().ok_or(ProtocolErrorKind::MissingAggSig)?,
+            signature: adaptor_sig.adapt(MaybeScalar::Zero).ok_or(ProtocolErrorKind::ZeroNonce)?,
+            pub_key: key_ctx.aggregated_key.as_ref().ok_or(ProtocolErrorKind::MissingAggPubKey)?.pub_key,
+        })
+    }
+}
+
+/// The descriptor of a taproot output with the given internal key, and a script path spendable with
+/// the given key alone once the output is the given number of blocks old, with its checksum.
+fn claim_descriptor(internal_key: Point, claim_key: Point, claim_blocks: u32) -> String {
+    let xonly_hex = |key: Point| key.serialize_xonly().iter().fold(String::with_capacity(64), |mut hex, b| {
+        write!(hex, "{:02x}", b).unwrap_or_default();
+        hex
+    });
+    with_descriptor_checksum(&format!("tr({},and_v(v:pk({}),older({})))",
+        xonly_hex(internal_key), xonly_hex(claim_key), claim_blocks))
+}
+
+/// The given output descriptor with its checksum appended, as per BIP 380, for wallets to import it.
+fn with_descriptor_checksum(descriptor: &str) -> String {
+    fn polymod(chk: u64, value: u64) -> u64 {
+        const GENERATOR: [u64; 5] = [0xf5_dee5_1989, 0xa9_fdca_3312, 0x1b_ab10_e32d, 0x37_06b1_677a, 0x64_4d62_6ffd];
+        let top = chk >> 35;
+        let chk = (chk & 0x7_ffff_ffff) << 5 ^ value;
+        GENERATOR.iter().enumerate().fold(chk, |chk, (i, g)| if top >> i & 1 == 1 { chk ^ g } else { chk })
+    }
+
+    let (mut chk, mut groups, mut group_count) = (1, 0, 0);
+    for c in descriptor.bytes() {
+        // Descriptors are only ever built of the symbols of the charset, so no other is looked for:
+        let value = DESCRIPTOR_INPUT_CHARSET.iter().position(|&s| s == c).unwrap_or_default() as u64;
+        chk = polymod(chk, value & 31);
+        groups = groups * 3 + (value >> 5);
+        group_count += 1;
+        if group_count == 3 {
+            chk = polymod(chk, groups);
+            (groups, group_count) = (0, 0);
+        }
+    }
+    if group_count > 0 {
+        chk = polymod(chk, groups);
+    }
+    chk = (0..8).fold(chk, |chk, _| polymod(chk, 0)) ^ 1;
+    let checksum: String = (0..8)
+        .map(|i| char::from(DESCRIPTOR_CHECKSUM_CHARSET[(chk >> (5 * (7 - i)) & 31) as usize]))
+        .collect();
+    format!("{}#{}", descriptor, checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_checksums_match_bip380() {
+        assert_eq!(with_descriptor_checksum("raw(deadbeef)"), "raw(deadbeef)#89f8spxm");
+        assert_eq!(with_descriptor_checksum("sh(multi(2,[00000000/111'/222]xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc,xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L/0))"),
+            "sh(multi(2,[00000000/111'/222]xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc,xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L/0))#ggrsrxfy");
+    }
+}
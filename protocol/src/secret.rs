@@ -0,0 +1,169 @@
+//! A wrapper for the secret scalars & nonces held in memory, keeping them out of swap (where
+//! supported), out of debug output and out of any encoding that doesn't explicitly ask for them.
+
+use musig2::SecNonce;
+use secp::Scalar;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem::{self, MaybeUninit};
+use std::prelude::rust_2021::*;
+use std::ptr;
+use std::sync::Mutex;
+
+/// A secret value, held on the heap in memory locked into RAM where the platform supports it, then
+/// wiped when dropped. Its [`Debug`](fmt::Debug) output is redacted, and it implements no encoding
+/// or conversion traits, so that the secret can only be read out with [`Self::expose_secret`] (or
+/// [`Self::into_inner`]) at the point where it is actually needed. Only the bytes of the value
+/// itself are locked & wiped, so it must be of an [`Inline`] type.
+// The box is always initialized, but is left as possibly uninitialized memory to be wiped on drop.
+pub struct Secret<T: Inline>(Box<MaybeUninit<T>>);
+
+/// A fixed-size type of secret, owning no memory beyond its own bytes, so that a [`Secret`] of it
+/// keeps the whole of it locked into RAM & wiped on drop (which it couldn't for a `Vec`, say).
+pub trait Inline: sealed::Sealed {}
+
+impl Inline for Scalar {}
+impl Inline for SecNonce {}
+impl<const N: usize> Inline for [u8; N] {}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::Scalar {}
+    impl Sealed for super::SecNonce {}
+    impl<const N: usize> Sealed for [u8; N] {}
+}
+
+impl<T: Inline> Secret<T> {
+    pub fn new(value: T) -> Self {
+        let boxed = Box::new(MaybeUninit::new(value));
+        page_locks::lock(boxed.as_ptr().cast(), mem::size_of::<T>());
+        Self(boxed)
+    }
+
+    #[must_use]
+    pub fn expose_secret(&self) -> &T {
+        // SAFETY: The box is initialized until the secret is dropped or moved out.
+        unsafe { self.0.assume_init_ref() }
+    }
+
+    /// Move the secret out, wiping (and unlocking) the memory it was held in.
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        let mut this = mem::ManuallyDrop::new(self);
+        // SAFETY: The box is initialized, and is only wiped & freed after the value is read out of
+        //  it, as the drop of the secret (which would drop the value in place) is skipped.
+        unsafe {
+            let value = this.0.assume_init_read();
+            wipe(ptr::read(&raw mut this.0));
+            value
+        }
+    }
+}
+
+impl<T: Inline> Drop for Secret<T> {
+    fn drop(&mut self) {
+        // SAFETY: The box is initialized, and is left as uninitialized memory once dropped in place.
+        unsafe { self.0.assume_init_drop() };
+        wipe_in_place(&mut self.0);
+    }
+}
+
+/// Zero the memory of the given box, then unlock & free it.
+fn wipe<T>(mut boxed: Box<MaybeUninit<T>>) {
+    wipe_in_place(&mut boxed);
+}
+
+fn wipe_in_place<T>(boxed: &mut MaybeUninit<T>) {
+    let bytes: *mut u8 = boxed.as_mut_ptr().cast();
+    for i in 0..mem::size_of::<T>() {
+        // Volatile, so that the compiler doesn't elide the writes to memory about to be freed.
+        // SAFETY: The byte is within the box, and any bit pattern is valid for 'MaybeUninit'.
+        unsafe { ptr::write_volatile(bytes.add(i), 0) };
+    }
+    page_locks::unlock(bytes, mem::size_of::<T>());
+}
+
+impl<T: Inline + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.expose_secret().clone())
+    }
+}
+
+impl<T: Inline> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+/// Memory locking of the pages the secrets are held in. As many secrets may share a page, and locks
+/// on a page don't nest, each page is only unlocked once every secret in it has been dropped.
+mod page_locks {
+    use super::{BTreeMap, Mutex};
+
+    static LOCK_COUNTS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+    #[cfg(unix)]
+    fn page_size() -> usize {
+        // SAFETY: Just a query of a system constant.
+        usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).unwrap_or(4096)
+    }
+
+    fn pages(addr: *const u8, len: usize) -> impl Iterator<Item=usize> {
+        #[cfg(unix)]
+        let page_size = page_size();
+        #[cfg(not(unix))]
+        let page_size = 4096;
+        let start = addr as usize / page_size * page_size;
+        let end = if len == 0 { start } else { addr as usize + len };
+        (start..end).step_by(page_size)
+    }
+
+    pub fn lock(addr: *const u8, len: usize) {
+        let mut lock_counts = LOCK_COUNTS.lock().unwrap();
+        for page in pages(addr, len) {
+            let count = lock_counts.entry(page).or_default();
+            if *count == 0 {
+                // Locking is best-effort, as it may fail (say) from exceeding RLIMIT_MEMLOCK, and a
+                // secret that may be swapped out is better than none at all:
+                #[cfg(unix)]
+                // SAFETY: The page is part of a live allocation, which mlock doesn't change.
+                unsafe { libc::mlock(page as *const libc::c_void, 1) };
+            }
+            *count += 1;
+        }
+        drop(lock_counts);
+    }
+
+    pub fn unlock(addr: *const u8, len: usize) {
+        let mut lock_counts = LOCK_COUNTS.lock().unwrap();
+        for page in pages(addr, len) {
+            if let Some(count) = lock_counts.get_mut(&page) {
+                *count -= 1;
+                if *count == 0 {
+                    lock_counts.remove(&page);
+                    #[cfg(unix)]
+                    // SAFETY: The page is still part of a live allocation, as it is yet to be freed.
+                    unsafe { libc::munlock(page as *const libc::c_void, 1) };
+                }
+            }
+        }
+        drop(lock_counts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_is_redacted_and_round_trips() {
+        let secret = Secret::new([42u8; 32]);
+        assert_eq!(format!("{:?}", secret), "Secret([REDACTED])");
+        assert_eq!(secret.clone().expose_secret(), &[42; 32]);
+        assert_eq!(secret.into_inner(), [42; 32]);
+
+        let secret = Secret::new([1, 2, 3]);
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(Secret([REDACTED]))");
+    }
+}
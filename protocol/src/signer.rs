@@ -0,0 +1,261 @@
+//! The signer abstraction, through which the trade model generates our key & nonce shares and makes
+//! our partial signatures, so that the secrets behind them needn't be held by the trade daemon.
+
+use musig2::{AggNonce, KeyAggContext, PartialSignature, PubNonce, SecNonce, SecNonceBuilder};
+use secp::{MaybePoint, Point, Scalar};
+use sha2::{Digest as _, Sha256};
+use std::prelude::rust_2021::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{KeyPair, NoncePair, ProtocolErrorKind, Result, Role, Secret};
+use crate::storage::ByOptVal;
+
+/// Where our key shares & nonce shares are generated and our partial signatures are made.
+///
+/// The default, [`LocalSigner`], does all this in-process, leaving the secrets in the trade model
+/// (to be stored along with it). A signer may instead keep the secrets to itself, in an HSM,
+/// hardware wallet or separate signing process, say, in which case it leaves them out of the key
+/// pairs & nonce pairs it returns and looks them up again by their public halves. Either way, the
+/// trade model passes back whatever it was given.
+pub trait Signer: Send + Sync {
+    /// Generate a fresh (random) key share.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the signer is unavailable.
+    fn new_key_share(&self) -> Result<KeyPair<ByOptVal>>;
+
+    /// Generate a fresh nonce share, for signing with the given key share of the given aggregated
+    /// public key.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the signer is unavailable, or doesn't know the key share.
+    fn new_nonce_share(&self, key_share: &KeyPair<ByOptVal>, aggregated_pub_key: Point) -> Result<NoncePair>;
+
+    /// Partially sign the message of the given session, with the given key share & nonce share. The
+    /// secret nonce (if any) has already been taken out of the trade model, so that it cannot be
+    /// used again. A signer keeping the secret nonce itself must likewise make sure of that.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the signer is unavailable, doesn't know the key share, or if the nonce share has
+    /// already been used (or discarded).
+    fn sign_partial(&self, session: &SigningSession<'_>, key_share: &KeyPair<ByOptVal>, pub_nonce: &PubNonce,
+                    sec_nonce: Option<Secret<SecNonce>>) -> Result<PartialSignature>;
+
+    /// Discard the secret nonce of the given nonce share if it is unused, so that it can never be
+    /// used to sign. This is a no-op for signers which leave the secret nonces in the trade model.
+    fn discard_nonce_share(&self, _pub_nonce: &PubNonce) {}
+
+    /// The private key of the given key share, for the protocol steps which reveal it: handing the
+    /// key share for the peer's output to the peer on cooperative closure, aggregating the private
+    /// key of our own output, and (for the seller) adapting the swap tx signature.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the signer is unavailable, or doesn't know the key share.
+    fn reveal_key_share(&self, key_share: &KeyPair<ByOptVal>) -> Result<Scalar>;
+}
+
+/// Everything but the key & nonce shares needed to make a partial (adaptor) signature.
+pub struct SigningSession<'a> {
+    pub key_agg_ctx: &'a KeyAggContext,
+    pub aggregated_nonce: &'a AggNonce,
+    pub adaptor_point: MaybePoint,
+    pub message: &'a [u8],
+}
+
+/// The default, in-process signer, which leaves every secret in the trade model.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalSigner;
+
+impl Signer for LocalSigner {
+    fn new_key_share(&self) -> Result<KeyPair<ByOptVal>> {
+        // TODO: Make the RNG configurable, to aid unit testing. (Also, we may not necessarily want
+        //  to use a nondeterministic random key share):
+        let prv_key = Scalar::random(&mut rand::thread_rng());
+        Ok(KeyPair { pub_key: prv_key.base_point_mul(), prv_key: Some(Secret::new(prv_key)) })
+    }
+
+    fn new_nonce_share(&self, _key_share: &KeyPair<ByOptVal>, aggregated_pub_key: Point) -> Result<NoncePair> {
+        // TODO: Make the RNG configurable, to aid unit testing:
+        let sec_nonce = SecNonceBuilder::new(&mut rand::thread_rng())
+            .with_aggregated_pubkey(aggregated_pub_key)
+            .build();
+        Ok(NoncePair { pub_nonce: sec_nonce.public_nonce(), sec_nonce: Some(Secret::new(sec_nonce)) })
+    }
+
+    fn sign_partial(&self, session: &SigningSession<'_>, key_share: &KeyPair<ByOptVal>, _pub_nonce: &PubNonce,
+                    sec_nonce: Option<Secret<SecNonce>>) -> Result<PartialSignature> {
+        let prv_key = self.reveal_key_share(key_share)?;
+        let sec_nonce = sec_nonce.ok_or(ProtocolErrorKind::NonceReuse)?.into_inner();
+        Ok(musig2::adaptor::sign_partial(session.key_agg_ctx, prv_key, sec_nonce, session.aggregated_nonce,
+            session.adaptor_point, session.message)?)
+    }
+
+    fn reveal_key_share(&self, key_share: &KeyPair<ByOptVal>) -> Result<Scalar> {
+        key_share.prv_key.as_ref().map(|k| *k.expose_secret()).ok_or(ProtocolErrorKind::MissingKeyShare)
+    }
+}
+
+const TEST_SIGNER_TAG: &[u8] = b"MuSigTradeProtocol/test signer";
+
+/// A signer for dry-run trades, which derives its key shares & nonce shares from the trade ID and
+/// our role in turn, instead of drawing on real entropy, so that a trade plays out the same each
+/// time. Anyone knowing the trade ID can work out its secrets, so it must never hold real funds.
+///
+/// Like [`LocalSigner`], it leaves every secret in the trade model. The secrets are numbered from
+/// the start again by each new signer, so a signer given to a trade model reloaded from a store
+/// draws nonces it may already have drawn: harmless for keys which are public anyway.
+#[derive(Debug)]
+pub struct TestSigner {
+    seed: [u8; 32],
+    next_index: AtomicU64,
+}
+
+impl TestSigner {
+    #[must_use]
+    pub fn for_trade(trade_id: &str, my_role: Role) -> Self {
+        let seed = Sha256::new()
+            .chain_update(TEST_SIGNER_TAG)
+            .chain_update((trade_id.len() as u64).to_be_bytes())
+            .chain_update(trade_id)
+            .chain_update([my_role as u8])
+            .finalize().into();
+        Self { seed, next_index: AtomicU64::new(0) }
+    }
+
+    /// The next secret of the given kind (key share or nonce share).
+    fn next_secret(&self, kind: u8) -> [u8; 32] {
+        let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+        Sha256::new()
+            .chain_update(self.seed)
+            .chain_update([kind])
+            .chain_update(index.to_be_bytes())
+            .finalize().into()
+    }
+}
+
+impl Signer for TestSigner {
+    fn new_key_share(&self) -> Result<KeyPair<ByOptVal>> {
+        let prv_key = Scalar::reduce_from(&self.next_secret(0));
+        Ok(KeyPair { pub_key: prv_key.base_point_mul(), prv_key: Some(Secret::new(prv_key)) })
+    }
+
+    fn new_nonce_share(&self, _key_share: &KeyPair<ByOptVal>, aggregated_pub_key: Point) -> Result<NoncePair> {
+        let sec_nonce = SecNonceBuilder::new(self.next_secret(1))
+            .with_aggregated_pubkey(aggregated_pub_key)
+            .build();
+        Ok(NoncePair { pub_nonce: sec_nonce.public_nonce(), sec_nonce: Some(Secret::new(sec_nonce)) })
+    }
+
+    fn sign_partial(&self, session: &SigningSession<'_>, key_share: &KeyPair<ByOptVal>, pub_nonce: &PubNonce,
+                    sec_nonce: Option<Secret<SecNonce>>) -> Result<PartialSignature> {
+        LocalSigner.sign_partial(session, key_share, pub_nonce, sec_nonce)
+    }
+
+    fn reveal_key_share(&self, key_share: &KeyPair<ByOptVal>) -> Result<Scalar> {
+        LocalSigner.reveal_key_share(key_share)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{Role, SecretFields, TradeModel, TradePhase};
+
+    /// A signer which keeps every secret to itself, as an external one would.
+    #[derive(Default)]
+    struct HoldingSigner {
+        prv_keys: Mutex<Vec<Scalar>>,
+        sec_nonces: Mutex<Vec<SecNonce>>,
+    }
+
+    impl Signer for HoldingSigner {
+        fn new_key_share(&self) -> Result<KeyPair<ByOptVal>> {
+            let key_share = LocalSigner.new_key_share()?;
+            self.prv_keys.lock().unwrap().push(LocalSigner.reveal_key_share(&key_share)?);
+            Ok(KeyPair { prv_key: None, ..key_share })
+        }
+
+        fn new_nonce_share(&self, key_share: &KeyPair<ByOptVal>, aggregated_pub_key: Point) -> Result<NoncePair> {
+            let nonce_share = LocalSigner.new_nonce_share(key_share, aggregated_pub_key)?;
+            self.sec_nonces.lock().unwrap().extend(nonce_share.sec_nonce.map(Secret::into_inner));
+            Ok(NoncePair { sec_nonce: None, ..nonce_share })
+        }
+
+        fn sign_partial(&self, session: &SigningSession<'_>, key_share: &KeyPair<ByOptVal>, pub_nonce: &PubNonce,
+                        _sec_nonce: Option<Secret<SecNonce>>) -> Result<PartialSignature> {
+            let mut sec_nonces = self.sec_nonces.lock().unwrap();
+            let index = sec_nonces.iter().position(|n| n.public_nonce() == *pub_nonce)
+                .ok_or(ProtocolErrorKind::NonceReuse)?;
+            let sec_nonce = sec_nonces.swap_remove(index);
+            drop(sec_nonces);
+            let key_share = KeyPair { prv_key: Some(Secret::new(self.reveal_key_share(key_share)?)), ..*key_share };
+            LocalSigner.sign_partial(session, &key_share, pub_nonce, Some(Secret::new(sec_nonce)))
+        }
+
+        fn discard_nonce_share(&self, pub_nonce: &PubNonce) {
+            self.sec_nonces.lock().unwrap().retain(|n| n.public_nonce() != *pub_nonce);
+        }
+
+        fn reveal_key_share(&self, key_share: &KeyPair<ByOptVal>) -> Result<Scalar> {
+            self.prv_keys.lock().unwrap().iter().copied().find(|k| k.base_point_mul() == key_share.pub_key)
+                .ok_or(ProtocolErrorKind::MissingKeyShare)
+        }
+    }
+
+    #[test]
+    fn trade_with_secrets_held_by_signer() -> Result<()> {
+        let signer = Arc::new(HoldingSigner::default());
+        let mut buyer = TradeModel::builder("trade".to_owned(), Role::BuyerAsTaker)
+            .signer(Arc::clone(&signer) as _).with_my_key_shares()?.build();
+        let mut seller = TradeModel::builder("trade".to_owned(), Role::SellerAsMaker).with_my_key_shares()?.build();
+
+        let [b1, b2] = buyer.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = seller.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        for trade_model in [&mut buyer, &mut seller] {
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+        }
+        seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        for trade_model in [&mut buyer, &mut seller] {
+            trade_model.aggregate_nonce_shares()?;
+            trade_model.sign_partial()?;
+        }
+        seller.peer_partial_signatures_on_my_txs_mut().set(buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        buyer.peer_partial_signatures_on_my_txs_mut().set(seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned());
+        buyer.aggregate_partial_signatures()?;
+        seller.aggregate_partial_signatures()?;
+
+        // None of the buyer's secrets were handed to the trade model, so none are stored with it:
+        assert_eq!(buyer.phase(), TradePhase::DepositTxSigned);
+        assert_eq!(buyer.encode_to_vec(SecretFields::Include), buyer.encode_to_vec(SecretFields::Omit));
+        assert!(signer.sec_nonces.lock().unwrap().is_empty());
+
+        seller.set_peer_private_key_share_for_my_output(buyer.get_my_private_key_share_for_peer_output()?)?;
+        seller.aggregate_private_keys_for_my_output()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_signer_makes_the_same_key_shares_for_the_same_trade_and_role() -> Result<()> {
+        let key_shares = |trade_id: &str, my_role| -> Result<[Point; 2]> {
+            let trade_model = TradeModel::builder(trade_id.to_owned(), my_role)
+                .signer(Arc::new(TestSigner::for_trade(trade_id, my_role))).with_my_key_shares()?.build();
+            Ok(trade_model.get_my_key_shares().unwrap().map(|k| k.pub_key))
+        };
+        let shares = key_shares("trade", Role::BuyerAsTaker)?;
+        assert_eq!(key_shares("trade", Role::BuyerAsTaker)?, shares);
+        assert_ne!(key_shares("trade", Role::SellerAsMaker)?, shares);
+        assert_ne!(key_shares("other-trade", Role::BuyerAsTaker)?, shares);
+        assert_ne!(shares[0], shares[1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+//! The rich error details carried by the gRPC statuses of failed protocol steps (as an encoded
+//! `google.rpc.Status` in their details): a `google.rpc.ErrorInfo` naming the kind of failure and
+//! where in the trade it occurred, and a `google.rpc.BadRequest` naming any request field at fault.
+
+use prost::Message as _;
+use std::collections::BTreeMap;
+use std::prelude::rust_2021::*;
+use tonic::{Code, Status};
+
+use crate::{ProtocolError, ProtocolErrorKind, TradePhase};
+
+/// The domain of the `ErrorInfo` of every status failing a protocol step.
+pub const ERROR_DOMAIN: &str = "musig-trade-protocol";
+/// The `ErrorInfo` metadata keys of where in the trade a step failed.
+pub const TRADE_ID_KEY: &str = "tradeId";
+pub const PHASE_KEY: &str = "phase";
+pub const STEP_KEY: &str = "step";
+pub const INPUT_KEY: &str = "input";
+
+const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
+const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+/// A `google.rpc.Status`, as carried (encoded) in the details of a `tonic` status.
+#[derive(Clone, PartialEq, prost::Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<Any>,
+}
+
+/// A `google.protobuf.Any`.
+#[derive(Clone, PartialEq, prost::Message)]
+struct Any {
+    #[prost(string, tag = "1")]
+    type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+}
+
+/// A `google.rpc.BadRequest`, listing the fields of a request which were rejected.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub field_violations: Vec<FieldViolation>,
+}
+
+/// A `google.rpc.BadRequest.FieldViolation`: the proto path of a rejected field & what was wrong.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FieldViolation {
+    #[prost(string, tag = "1")]
+    pub field: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+}
+
+/// A `google.rpc.ErrorInfo`: the kind of failure, as an `UPPER_SNAKE_CASE` reason, and where it
+/// occurred, in its metadata (by the `*_KEY` consts).
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ErrorInfo {
+    #[prost(string, tag = "1")]
+    pub reason: String,
+    #[prost(string, tag = "2")]
+    pub domain: String,
+    #[prost(btree_map = "string, string", tag = "3")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// The given status with a `google.rpc.BadRequest` in its details, holding the given violation.
+#[must_use]
+pub fn with_bad_request(code: Code, message: String, violation: FieldViolation) -> Status {
+    let details = RpcStatus {
+        code: code as i32,
+        message: message.clone(),
+        details: vec![Any {
+            type_url: BAD_REQUEST_TYPE_URL.to_owned(),
+            value: BadRequest { field_violations: vec![violation] }.encode_to_vec(),
+        }],
+    };
+    Status::with_details(code, message, details.encode_to_vec().into())
+}
+
+/// The `google.rpc.BadRequest` in the details of the given status, if it has one.
+#[must_use]
+pub fn bad_request(status: &Status) -> Option<BadRequest> {
+    detail(status, BAD_REQUEST_TYPE_URL)
+}
+
+/// The `google.rpc.ErrorInfo` in the details of the given status, if it has one.
+#[must_use]
+pub fn error_info(status: &Status) -> Option<ErrorInfo> {
+    detail(status, ERROR_INFO_TYPE_URL)
+}
+
+fn detail<M: prost::Message + Default>(status: &Status, type_url: &str) -> Option<M> {
+    RpcStatus::decode(status.details()).ok()?.details.into_iter()
+        .find(|any| any.type_url == type_url)
+        .and_then(|any| M::decode(&any.value[..]).ok())
+}
+
+/// The given status of a failed protocol step, with where in the trade it failed added to its
+/// `ErrorInfo` (one being added with the status code as its reason, should it have none), keeping
+/// any of the context it already has. The input at fault defaults to the field of any
+/// `google.rpc.BadRequest` in its details.
+#[must_use]
+pub fn with_trade_context(status: &Status, trade_id: &str, phase: TradePhase, step: &str) -> Status {
+    let mut details = RpcStatus::decode(status.details()).unwrap_or_else(|_| RpcStatus {
+        code: status.code() as i32, message: status.message().to_owned(), details: vec![],
+    });
+    let mut info = error_info(status).unwrap_or_else(|| ErrorInfo {
+        reason: upper_snake_case(&format!("{:?}", status.code())),
+        domain: ERROR_DOMAIN.to_owned(),
+        metadata: BTreeMap::new(),
+    });
+    let input = bad_request(status).and_then(|bad_request| bad_request.field_violations.into_iter().next())
+        .map(|violation| violation.field);
+    for (key, value) in [(TRADE_ID_KEY, Some(trade_id.to_owned())), (PHASE_KEY, Some(format!("{:?}", phase))),
+        (STEP_KEY, Some(step.to_owned())), (INPUT_KEY, input)]
+    {
+        if let Some(value) = value {
+            info.metadata.entry(key.to_owned()).or_insert(value);
+        }
+    }
+    details.details.retain(|any| any.type_url != ERROR_INFO_TYPE_URL);
+    details.details.push(Any { type_url: ERROR_INFO_TYPE_URL.to_owned(), value: info.encode_to_vec() });
+    Status::with_details_and_metadata(status.code(), status.message(), details.encode_to_vec().into(),
+        status.metadata().clone())
+}
+
+/// The status of a failed protocol step, by the kind of failure, with an `ErrorInfo` in its details
+/// giving the kind and where it occurred, as far as known.
+impl From<ProtocolError> for Status {
+    fn from(value: ProtocolError) -> Self {
+        let code = code(&value.kind);
+        let message = value.kind.to_string();
+        let mut metadata = BTreeMap::new();
+        if let Some((trade_id, phase)) = value.trade {
+            metadata.insert(TRADE_ID_KEY.to_owned(), trade_id);
+            metadata.insert(PHASE_KEY.to_owned(), format!("{:?}", phase));
+        }
+        if let Some(input) = value.input {
+            metadata.insert(INPUT_KEY.to_owned(), input);
+        }
+        let info = ErrorInfo { reason: reason(&value.kind), domain: ERROR_DOMAIN.to_owned(), metadata };
+        let details = RpcStatus {
+            code: code as i32,
+            message: message.clone(),
+            details: vec![Any { type_url: ERROR_INFO_TYPE_URL.to_owned(), value: info.encode_to_vec() }],
+        };
+        Self::with_details(code, message, details.encode_to_vec().into())
+    }
+}
+
+impl From<ProtocolErrorKind> for Status {
+    fn from(value: ProtocolErrorKind) -> Self {
+        ProtocolError::from(value).into()
+    }
+}
+
+fn code(kind: &ProtocolErrorKind) -> Code {
+    match kind {
+        // A failure to sign one of several tx inputs is down to whatever failed to sign it.
+        ProtocolErrorKind::SigningInput { source, .. } => code(source),
+        // These are down to what the peer sent (or what was done to it on the way), not us. (Our own
+        // partial signatures always verify, so an aggregate signature failing to is the peer's doing.)
+        // The coin control failures are down to the funding inputs given along with it.
+        ProtocolErrorKind::ChangedIdentityKey | ProtocolErrorKind::InvalidPeerSignature(_) | ProtocolErrorKind::InvalidPeerPartialSig(_)
+        | ProtocolErrorKind::InvalidMediatorSignature | ProtocolErrorKind::MismatchedNonceCommitment
+        | ProtocolErrorKind::ChangedNonceCommitment | ProtocolErrorKind::DuplicateFundingInput(_)
+        | ProtocolErrorKind::InvalidOwnershipProof(_) | ProtocolErrorKind::InsufficientFunding { .. }
+        | ProtocolErrorKind::MismatchedPeerRole { .. } | ProtocolErrorKind::SwapTxFeeRateNotRaised(_)
+        | ProtocolErrorKind::AvoidedFundingInput(_) | ProtocolErrorKind::UnusedCoinControlOutpoint(_)
+        | ProtocolErrorKind::ConflictingCoinControl(_) | ProtocolErrorKind::Verify(_) => Code::InvalidArgument,
+        ProtocolErrorKind::SigningSessionClosed(_) | ProtocolErrorKind::FeeRateChangeClosed(_)
+        | ProtocolErrorKind::CancellationClosed(_) | ProtocolErrorKind::MissingFeeRateChange | ProtocolErrorKind::SwapTxFeeBumpClosed(_)
+        | ProtocolErrorKind::MissingSwapTxFeeBump | ProtocolErrorKind::MissingAmounts => Code::FailedPrecondition,
+        _ => Code::Internal,
+    }
+}
+
+/// The reason for the `ErrorInfo` of the given kind of failure: the name of its variant, as given
+/// by its debug output up to any fields (or else that of the failure to sign a tx input).
+fn reason(kind: &ProtocolErrorKind) -> String {
+    if let ProtocolErrorKind::SigningInput { source, .. } = kind {
+        return reason(source);
+    }
+    let debug = format!("{:?}", kind);
+    upper_snake_case(debug.split(|c: char| !c.is_ascii_alphanumeric()).next().unwrap_or_default())
+}
+
+/// The given `UpperCamelCase` name in `UPPER_SNAKE_CASE`, as for the reason of an `ErrorInfo`.
+fn upper_snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if i > 0 && c.is_ascii_uppercase() {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_uppercase());
+    }
+    snake
+}
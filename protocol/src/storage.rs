@@ -0,0 +1,256 @@
+use std::convert::Infallible;
+use std::prelude::rust_2021::*;
+
+/// A simple utility trait to allow structs to be polymorphic over the storage type of their fields,
+/// to facilitate passing data to and from functions by reference or value, decided statically for
+/// maximum efficiency. (This avoids needless cloning of fields, the obvious alternative being to
+/// make each field a [`std::borrow::Cow`], but the latter is dynamic and wastes storage space.)
+pub trait Storage {
+    // It isn't ideal to make the lifetime a parameter of the GAT, instead of the [`ByRef`] storage
+    // type, but a bound like `T: 'a` in the latter's impl would be stricter than the trait allows.
+    type Store<'a, T: 'a>;
+}
+
+/// The same as [`Storage`] but for struct fields holding values only, thus without the lifetime
+/// parameter in its [`Self::Store`] GAT. It is derived from [`Storage`] by a blanket impl, so only
+/// the latter needs implementing for each storage type.
+pub trait ValStorage {
+    type Store<T: 'static>;
+}
+
+impl<S: Storage> ValStorage for S {
+    type Store<T: 'static> = <S as Storage>::Store<'static, T>;
+}
+
+/// Hold the struct fields by reference.
+pub struct ByRef(Infallible);
+
+/// Hold the struct fields by mutable reference to the (possibly empty) slots they are to be held
+/// in elsewhere, so that a setter-style struct can be populated in place, field by field.
+pub struct ByMutRef(Infallible);
+
+/// Hold the struct fields by value.
+pub struct ByVal(Infallible);
+
+/// Hold the struct fields by Option-wrapped value.
+pub struct ByOptVal(Infallible);
+
+impl Storage for ByRef {
+    type Store<'a, T: 'a> = &'a T;
+}
+
+impl Storage for ByMutRef {
+    type Store<'a, T: 'a> = &'a mut Option<T>;
+}
+
+impl Storage for ByVal {
+    type Store<'a, T: 'a> = T;
+}
+
+impl Storage for ByOptVal {
+    type Store<'a, T: 'a> = Option<T>;
+}
+
+/// A field which the sender may withhold for now, with the promise of revealing it later, as the
+/// buyer does its partial signature on the swap tx until it has started payment. Being redacted is
+/// thus told apart from being missing (which a message may not be), and from not being exchanged at
+/// all.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Redactable<T> {
+    /// The field isn't part of the exchange, as the swap tx partial signature isn't of a fee rate
+    /// change.
+    Omitted,
+    /// The field is withheld by the sender, to be revealed later.
+    Redacted,
+    Value(T),
+}
+
+impl<T> Redactable<T> {
+    pub const fn as_ref(&self) -> Redactable<&T> {
+        match self {
+            Self::Omitted => Redactable::Omitted,
+            Self::Redacted => Redactable::Redacted,
+            Self::Value(value) => Redactable::Value(value),
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Redactable<U> {
+        match self {
+            Self::Omitted => Redactable::Omitted,
+            Self::Redacted => Redactable::Redacted,
+            Self::Value(value) => Redactable::Value(f(value)),
+        }
+    }
+
+    /// The value of the field, unless it is omitted or redacted.
+    pub fn value(self) -> Option<T> {
+        match self {
+            Self::Value(value) => Some(value),
+            Self::Omitted | Self::Redacted => None,
+        }
+    }
+
+    pub const fn is_redacted(&self) -> bool {
+        matches!(self, Self::Redacted)
+    }
+}
+
+impl<T> Redactable<Option<T>> {
+    /// `None` if the field ought to hold a value but doesn't, otherwise the field with its value
+    /// (if any) unwrapped.
+    pub fn transpose(self) -> Option<Redactable<T>> {
+        match self {
+            Self::Omitted => Some(Redactable::Omitted),
+            Self::Redacted => Some(Redactable::Redacted),
+            Self::Value(value) => value.map(Redactable::Value),
+        }
+    }
+}
+
+/// Define a struct of like-typed fields, generic over their [`Storage`] type, together with the
+/// conversions between its views that would otherwise have to be written by hand for each struct:
+///
+/// - `by_ref`, borrowing a [`ByVal`] struct as a [`ByRef`] one;
+/// - `cloned`, copying the fields of a [`ByRef`] struct into a [`ByVal`] one;
+/// - `transpose`, turning a [`ByOptVal`] struct into a [`ByVal`] one if every field is present;
+/// - `set`, moving the fields of a [`ByVal`] struct into the slots of a [`ByMutRef`] one;
+/// - `for_each_field`, visiting each (present) field along with its name.
+///
+/// A field marked `: Option` is held as an `Option<S::Store<'a, T>>`, for one which may be left
+/// out. A missing optional field doesn't hold back `transpose`. A field marked `: Redactable` is held
+/// as a [`Redactable<S::Store<'a, T>>`](Redactable) instead, for one which the peer may redact. Only
+/// a missing unredacted value holds back `transpose`, and only a value is `set` (or visited).
+macro_rules! storage_struct {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident<$a:lifetime, $s:ident>($elem:ty) {
+            $($field_vis:vis $field:ident $(: $opt:ident)?),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name<$a, $s: $crate::storage::Storage> {
+            $($field_vis $field: $crate::storage::storage_struct!(@field $s, $a, $elem $(, $opt)?)),*
+        }
+
+        #[expect(clippy::allow_attributes, reason = "not every generated method is used by every struct")]
+        #[allow(dead_code, reason = "not every generated method is used by every struct")]
+        impl<$a, $s: $crate::storage::Storage> $name<$a, $s> {
+            pub fn for_each_field(&self, mut f: impl FnMut(&'static str, &$s::Store<$a, $elem>)) {
+                $($crate::storage::storage_struct!(@visit f, self.$field, $field $(, $opt)?);)*
+            }
+        }
+
+        #[expect(clippy::allow_attributes, reason = "not every generated method is used by every struct")]
+        #[allow(dead_code, reason = "not every generated method is used by every struct")]
+        impl $name<'_, $crate::storage::ByVal> {
+            pub fn by_ref(&self) -> $name<'_, $crate::storage::ByRef> {
+                $name { $($field: $crate::storage::storage_struct!(@by_ref self.$field $(, $opt)?)),* }
+            }
+        }
+
+        #[expect(clippy::allow_attributes, reason = "not every generated method is used by every struct")]
+        #[allow(dead_code, reason = "not every generated method is used by every struct")]
+        impl $name<'_, $crate::storage::ByRef> where $elem: Clone {
+            pub fn cloned(&self) -> $name<'static, $crate::storage::ByVal> {
+                $name { $($field: $crate::storage::storage_struct!(@cloned self.$field $(, $opt)?)),* }
+            }
+        }
+
+        #[expect(clippy::allow_attributes, reason = "not every generated method is used by every struct")]
+        #[allow(dead_code, reason = "not every generated method is used by every struct")]
+        impl $name<'_, $crate::storage::ByOptVal> {
+            pub fn transpose(self) -> Option<$name<'static, $crate::storage::ByVal>> {
+                Some($name { $($field: $crate::storage::storage_struct!(@transpose self.$field $(, $opt)?)),* })
+            }
+        }
+
+        #[expect(clippy::allow_attributes, reason = "not every generated method is used by every struct")]
+        #[allow(dead_code, reason = "not every generated method is used by every struct")]
+        impl $name<'_, $crate::storage::ByMutRef> {
+            pub fn set(self, values: $name<'_, $crate::storage::ByVal>) {
+                $($crate::storage::storage_struct!(@set self.$field, values.$field $(, $opt)?);)*
+            }
+        }
+    };
+    (@field $s:ident, $a:lifetime, $elem:ty) => { $s::Store<$a, $elem> };
+    (@field $s:ident, $a:lifetime, $elem:ty, Option) => { Option<$s::Store<$a, $elem>> };
+    (@field $s:ident, $a:lifetime, $elem:ty, Redactable) => { $crate::storage::Redactable<$s::Store<$a, $elem>> };
+    (@visit $f:ident, $value:expr, $field:ident) => { $f(stringify!($field), &$value) };
+    (@visit $f:ident, $value:expr, $field:ident, Option) => {
+        if let Some(value) = &$value { $f(stringify!($field), value) }
+    };
+    (@visit $f:ident, $value:expr, $field:ident, Redactable) => {
+        if let $crate::storage::Redactable::Value(value) = &$value { $f(stringify!($field), value) }
+    };
+    (@by_ref $value:expr) => { &$value };
+    (@by_ref $value:expr, Option) => { $value.as_ref() };
+    (@by_ref $value:expr, Redactable) => { $value.as_ref() };
+    (@cloned $value:expr) => { (*$value).clone() };
+    (@cloned $value:expr, Option) => { $value.cloned() };
+    (@cloned $value:expr, Redactable) => { $value.map(|value| (*value).clone()) };
+    (@transpose $value:expr) => { $value? };
+    (@transpose $value:expr, Option) => { match $value { Some(value) => Some(value?), None => None } };
+    (@transpose $value:expr, Redactable) => { $value.transpose()? };
+    (@set $slot:expr, $value:expr) => { *$slot = Some($value) };
+    (@set $slot:expr, $value:expr, Option) => {
+        if let Some(slot) = $slot { *slot = $value }
+    };
+    (@set $slot:expr, $value:expr, Redactable) => {
+        if let ($crate::storage::Redactable::Value(slot), $crate::storage::Redactable::Value(value)) = ($slot, $value) {
+            *slot = Some(value);
+        }
+    };
+}
+
+pub(crate) use storage_struct;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    storage_struct! {
+        struct Pair<'a, S>(u32) {
+            first,
+            second: Option,
+        }
+    }
+
+    storage_struct! {
+        struct Withheld<'a, S>(u32) {
+            shown,
+            withheld: Redactable,
+        }
+    }
+
+    #[test]
+    fn storage_struct_views() {
+        let (mut first, mut second) = (None, Some(1));
+        Pair::<ByMutRef> { first: &mut first, second: Some(&mut second) }.set(Pair { first: 2, second: None });
+        assert_eq!((first, second), (Some(2), None));
+
+        let pair = Pair::<ByOptVal> { first, second: Some(Some(3)) }.transpose().unwrap();
+        let mut fields = vec![];
+        pair.by_ref().for_each_field(|name, value| fields.push((name, **value)));
+        assert_eq!(fields, [("first", 2), ("second", 3)]);
+        assert_eq!(pair.by_ref().cloned().second, Some(3));
+
+        assert!(Pair::<ByOptVal> { first: Some(2), second: Some(None) }.transpose().is_none());
+        assert!(Pair::<ByOptVal> { first: Some(2), second: None }.transpose().is_some());
+    }
+
+    #[test]
+    fn redacted_fields_are_told_apart_from_missing_ones() {
+        let (mut shown, mut withheld) = (None, Some(1));
+        let redacted = Withheld::<ByOptVal> { shown: Some(2), withheld: Redactable::Redacted }.transpose().unwrap();
+        Withheld::<ByMutRef> { shown: &mut shown, withheld: Redactable::Value(&mut withheld) }.set(redacted);
+        assert_eq!((shown, withheld), (Some(2), Some(1)));
+
+        let mut fields = vec![];
+        let revealed = Withheld::<ByVal> { shown: 2, withheld: Redactable::Value(3) };
+        revealed.by_ref().for_each_field(|name, value| fields.push((name, **value)));
+        assert_eq!(fields, [("shown", 2), ("withheld", 3)]);
+        assert_eq!(revealed.by_ref().cloned().withheld, Redactable::Value(3));
+
+        assert!(Withheld::<ByOptVal> { shown: Some(2), withheld: Redactable::Value(None) }.transpose().is_none());
+    }
+}
@@ -0,0 +1,210 @@
+//! Test vectors of a whole trade, for cross-checking other implementations of the protocol (such as
+//! the Java one) and of `MuSig2` itself (such as the BIP 327 reference code) against this one: a
+//! trade between a buyer & a seller closed cooperatively, played through with [`TestSigner`]s (so
+//! with the same key & nonce shares every time), along with every intermediate value of each output
+//! & tx input, secrets included.
+
+use musig2::{AdaptorSignature, AggNonce, LiftedSignature, PartialSignature, PubNonce, SecNonce};
+use secp::{MaybePoint, MaybeScalar, Point, Scalar};
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+
+use crate::{KeyCtx, KeyPair, ProtocolErrorKind, Result, Role, SigCtx, TestSigner, TradeModel, SELLER_INDEX};
+use crate::storage::ByOptVal;
+
+/// The ID of the trade played through for the test vectors, from which (with the roles) the key &
+/// nonce shares of both parties are derived.
+pub const TEST_VECTOR_TRADE_ID: &str = "test-vectors";
+const BUYER_ROLE: Role = Role::BuyerAsTaker;
+const SELLER_ROLE: Role = Role::SellerAsMaker;
+
+type SigCtxOf = fn(&TradeModel) -> &SigCtx;
+
+/// The tx inputs signed by both parties, by name, with the output each spends (buyer's or not) and
+/// the signing context of each.
+const INPUTS: [(&str, bool, SigCtxOf); 7] = [
+    ("swap_tx_input", false, |t| &t.swap_tx_input_sig_ctx),
+    ("buyers_warning_tx_buyer_input", true, |t| &t.buyers_warning_tx_buyer_input_sig_ctx),
+    ("buyers_warning_tx_seller_input", false, |t| &t.buyers_warning_tx_seller_input_sig_ctx),
+    ("sellers_warning_tx_buyer_input", true, |t| &t.sellers_warning_tx_buyer_input_sig_ctx),
+    ("sellers_warning_tx_seller_input", false, |t| &t.sellers_warning_tx_seller_input_sig_ctx),
+    ("buyers_redirect_tx_input", true, |t| &t.buyers_redirect_tx_input_sig_ctx),
+    ("sellers_redirect_tx_input", false, |t| &t.sellers_redirect_tx_input_sig_ctx),
+];
+
+/// Every intermediate value of the trade played through for the test vectors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestVectors {
+    pub trade_id: String,
+    pub buyer_role: Role,
+    pub seller_role: Role,
+    /// The buyer's & seller's outputs, in that order.
+    pub outputs: [OutputVector; 2],
+    pub inputs: Vec<InputVector>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyVector {
+    pub prv_key: Scalar,
+    pub pub_key: Point,
+}
+
+/// The key shares of both parties for a 2-of-2 output, and their aggregates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputVector {
+    pub name: &'static str,
+    pub buyer_key_share: KeyVector,
+    pub seller_key_share: KeyVector,
+    /// The public key shares, in the order they are aggregated in.
+    pub key_agg_order: Vec<Point>,
+    /// The aggregated key, the private half of which the owner of the output gets on cooperative
+    /// closure.
+    pub aggregated_key: KeyVector,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NonceVector {
+    pub sec_nonce: SecNonce,
+    pub pub_nonce: PubNonce,
+}
+
+/// The nonce shares & partial signatures of both parties on a tx input, and their aggregates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputVector {
+    pub name: &'static str,
+    /// The name of the output the input spends.
+    pub output: &'static str,
+    /// The message signed (the sighash of the input, or for now, a stand-in for it).
+    pub message: Vec<u8>,
+    /// The adaptor point of the signature: the seller's key share for the buyer's output, for the
+    /// swap tx, and the point at infinity otherwise.
+    pub adaptor_point: MaybePoint,
+    pub buyer_nonce: NonceVector,
+    pub seller_nonce: NonceVector,
+    pub aggregated_nonce: AggNonce,
+    pub buyer_partial_signature: PartialSignature,
+    pub seller_partial_signature: PartialSignature,
+    pub adaptor_signature: AdaptorSignature,
+    /// The adaptor signature adapted with the adaptor secret, as published with the tx.
+    pub signature: LiftedSignature,
+}
+
+fn key_vector(key: Option<&KeyPair<ByOptVal>>) -> Result<KeyVector> {
+    let key = key.ok_or(ProtocolErrorKind::MissingKeyShare)?;
+    let prv_key = *key.prv_key.as_ref().ok_or(ProtocolErrorKind::MissingKeyShare)?.expose_secret();
+    Ok(KeyVector { prv_key, pub_key: key.pub_key })
+}
+
+fn nonce_vector(sig_ctx: &SigCtx) -> Result<NonceVector> {
+    let nonce_share = sig_ctx.my_nonce_share.as_ref().ok_or(ProtocolErrorKind::MissingNonceShare)?;
+    let sec_nonce = nonce_share.sec_nonce.as_ref().ok_or(ProtocolErrorKind::MissingNonceShare)?;
+    Ok(NonceVector { sec_nonce: sec_nonce.expose_secret().clone(), pub_nonce: nonce_share.pub_nonce.clone() })
+}
+
+/// The vector of an output, from the buyer's & seller's key contexts for it, the owner of the output
+/// being the buyer or not.
+fn output_vector(name: &'static str, buyers: &KeyCtx, sellers: &KeyCtx, owned_by_buyer: bool) -> Result<OutputVector> {
+    let owner = if owned_by_buyer { buyers } else { sellers };
+    Ok(OutputVector {
+        name,
+        buyer_key_share: key_vector(buyers.my_key_share())?,
+        seller_key_share: key_vector(sellers.my_key_share())?,
+        key_agg_order: owner.key_agg_ctx.as_ref().ok_or(ProtocolErrorKind::MissingAggPubKey)?.pubkeys().to_vec(),
+        aggregated_key: key_vector(owner.aggregated_key.as_ref())?,
+    })
+}
+
+/// Play a trade through between a buyer & a seller with test keys, closing it cooperatively (with
+/// the seller's swap tx signed too), and gather up every intermediate value along the way.
+///
+/// # Errors
+///
+/// Fails if any protocol step does, which would be a bug.
+pub fn generate() -> Result<TestVectors> {
+    let [mut buyer, mut seller] = [BUYER_ROLE, SELLER_ROLE].map(|role| TradeModel::builder(TEST_VECTOR_TRADE_ID.to_owned(), role)
+        .signer(Arc::new(TestSigner::for_trade(TEST_VECTOR_TRADE_ID, role)))
+        .build());
+    for trade_model in [&mut buyer, &mut seller] {
+        trade_model.init_my_key_shares()?;
+    }
+    let [b1, b2] = buyer.get_my_key_shares().ok_or(ProtocolErrorKind::MissingKeyShare)?.map(|k| k.pub_key);
+    let [s1, s2] = seller.get_my_key_shares().ok_or(ProtocolErrorKind::MissingKeyShare)?.map(|k| k.pub_key);
+    buyer.set_peer_key_shares(s1, s2);
+    seller.set_peer_key_shares(b1, b2);
+    for trade_model in [&mut buyer, &mut seller] {
+        trade_model.aggregate_key_shares()?;
+        trade_model.init_my_nonce_shares()?;
+    }
+    // The secret nonces are taken out of the trade models on signing, so are kept now:
+    let mut nonces = Vec::new();
+    for (_, _, sig_ctx) in INPUTS {
+        nonces.push([nonce_vector(sig_ctx(&buyer))?, nonce_vector(sig_ctx(&seller))?]);
+    }
+    seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().ok_or(ProtocolErrorKind::MissingNonceShare)?.cloned());
+    buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().ok_or(ProtocolErrorKind::MissingNonceShare)?.cloned());
+    for trade_model in [&mut buyer, &mut seller] {
+        trade_model.aggregate_nonce_shares()?;
+        trade_model.sign_partial()?;
+    }
+    let buyers_sigs = buyer.get_my_partial_signatures_on_peer_txs().ok_or(ProtocolErrorKind::MissingPartialSig)?.cloned();
+    let sellers_sigs = seller.get_my_partial_signatures_on_peer_txs().ok_or(ProtocolErrorKind::MissingPartialSig)?.cloned();
+    seller.peer_partial_signatures_on_my_txs_mut().set(buyers_sigs);
+    buyer.peer_partial_signatures_on_my_txs_mut().set(sellers_sigs);
+    buyer.aggregate_partial_signatures()?;
+    seller.aggregate_partial_signatures()?;
+    seller.aggregate_swap_tx_partial_signatures()?;
+    buyer.set_peer_private_key_share_for_my_output(seller.get_my_private_key_share_for_peer_output()?)?;
+    seller.set_peer_private_key_share_for_my_output(buyer.get_my_private_key_share_for_peer_output()?)?;
+    buyer.aggregate_private_keys_for_my_output()?;
+    seller.aggregate_private_keys_for_my_output()?;
+
+    let adaptor_secret = seller.buyer_output_key_ctx.get_prv_key_share(SELLER_INDEX, seller.signer())?;
+    let mut inputs = Vec::new();
+    for ((name, spends_buyer_output, sig_ctx), [buyer_nonce, seller_nonce]) in INPUTS.into_iter().zip(nonces) {
+        let [buyers, sellers] = [sig_ctx(&buyer), sig_ctx(&seller)];
+        // Each input is aggregated by the party whose tx it is (and the swap tx input by both):
+        let adaptor_signature = buyers.aggregated_sig.or(sellers.aggregated_sig).ok_or(ProtocolErrorKind::MissingAggSig)?;
+        let adaptor_secret = if buyers.adaptor_point.is_infinity() { MaybeScalar::Zero } else { adaptor_secret.into() };
+        inputs.push(InputVector {
+            name,
+            output: if spends_buyer_output { "buyer_output" } else { "seller_output" },
+            message: buyers.message.clone().ok_or(ProtocolErrorKind::MissingPartialSig)?,
+            adaptor_point: buyers.adaptor_point,
+            buyer_nonce,
+            seller_nonce,
+            aggregated_nonce: buyers.aggregated_nonce.clone().ok_or(ProtocolErrorKind::MissingAggNonce)?,
+            buyer_partial_signature: buyers.my_partial_sig.ok_or(ProtocolErrorKind::MissingPartialSig)?,
+            seller_partial_signature: sellers.my_partial_sig.ok_or(ProtocolErrorKind::MissingPartialSig)?,
+            adaptor_signature,
+            signature: adaptor_signature.adapt(adaptor_secret).ok_or(ProtocolErrorKind::ZeroNonce)?,
+        });
+    }
+
+    Ok(TestVectors {
+        trade_id: TEST_VECTOR_TRADE_ID.to_owned(),
+        buyer_role: BUYER_ROLE,
+        seller_role: SELLER_ROLE,
+        outputs: [
+            output_vector("buyer_output", &buyer.buyer_output_key_ctx, &seller.buyer_output_key_ctx, true)?,
+            output_vector("seller_output", &buyer.seller_output_key_ctx, &seller.seller_output_key_ctx, false)?,
+        ],
+        inputs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vectors_are_the_same_every_time_and_their_signatures_verify() -> Result<()> {
+        let vectors = generate()?;
+        assert_eq!(generate()?, vectors);
+        for input in &vectors.inputs {
+            let output = vectors.outputs.iter().find(|output| output.name == input.output).unwrap();
+            assert_eq!(output.aggregated_key.prv_key.base_point_mul(), output.aggregated_key.pub_key);
+            musig2::verify_single(output.aggregated_key.pub_key, input.signature, &input.message).unwrap();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+//! The transcript of a trade: the public data exchanged with the peer so far, that is, the identity
+//! keys, key shares, nonce shares & partial signatures, along with the messages signed. Signed with
+//! our identity key, it may be handed to a mediator or arbitrator as evidence of what passed
+//! between the parties, should the peer misbehave.
+//!
+//! A transcript may also be replayed, re-running the public half of each protocol step it got to on
+//! a fresh trade model and checking what that recomputes against the transcript, to find the step
+//! at which the two parties (say, this daemon and another implementation) first disagree. As only
+//! public values are recomputed, the replay draws no randomness, so is fully deterministic.
+
+use musig2::{PartialSignature, PubNonce};
+use secp::{MaybePoint, Point};
+use std::prelude::rust_2021::*;
+use thiserror::Error;
+
+use crate::{KeyCtx, KeyPair, NoncePair, ProtocolErrorKind, Role, SigCtx, TradeModel, TradePhase};
+
+/// The names of the tx inputs signed with the peer, in the order of [`TradeTranscript::inputs`].
+const INPUTS: [&str; 7] = [
+    "swap_tx_input",
+    "buyers_warning_tx_buyer_input",
+    "buyers_warning_tx_seller_input",
+    "sellers_warning_tx_buyer_input",
+    "sellers_warning_tx_seller_input",
+    "buyers_redirect_tx_input",
+    "sellers_redirect_tx_input",
+];
+
+/// The public data of our side of a trade, as exchanged with the peer so far. Nothing is included
+/// that wasn't handed to the peer: in particular, the buyer's partial signature on the swap tx is
+/// left out, as it may still be withheld from the seller.
+#[derive(Clone, Debug)]
+pub struct TradeTranscript {
+    pub trade_id: String,
+    pub my_role: Role,
+    pub phase: TradePhase,
+    pub trade_amount: Option<u64>,
+    pub buyers_security_deposit: Option<u64>,
+    pub sellers_security_deposit: Option<u64>,
+    pub my_identity_pub_key: Option<Point>,
+    pub peers_identity_pub_key: Option<Point>,
+    pub buyer_output_key: KeyTranscript,
+    pub seller_output_key: KeyTranscript,
+    pub swap_tx_input: SigTranscript,
+    pub buyers_warning_tx_buyer_input: SigTranscript,
+    pub buyers_warning_tx_seller_input: SigTranscript,
+    pub sellers_warning_tx_buyer_input: SigTranscript,
+    pub sellers_warning_tx_seller_input: SigTranscript,
+    pub buyers_redirect_tx_input: SigTranscript,
+    pub sellers_redirect_tx_input: SigTranscript,
+}
+
+/// The public key shares of both parties for an output, and their aggregate.
+#[derive(Clone, Debug, Default)]
+pub struct KeyTranscript {
+    pub my_key_share: Option<Point>,
+    pub peers_key_share: Option<Point>,
+    pub aggregated_key: Option<Point>,
+}
+
+/// The public nonce shares & partial signatures of both parties for a tx input, and the message
+/// signed. Our partial signature is only present if it was handed to the peer, that is, if the
+/// input is of one of the peer's txs (or of the swap tx, for the seller).
+#[derive(Clone, Debug, Default)]
+pub struct SigTranscript {
+    pub message: Option<Vec<u8>>,
+    pub my_nonce_share: Option<PubNonce>,
+    pub peers_nonce_share: Option<PubNonce>,
+    pub my_partial_signature: Option<PartialSignature>,
+    pub peers_partial_signature: Option<PartialSignature>,
+}
+
+/// A protocol step replayed from a transcript.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayStep {
+    /// The aggregation of both parties' key shares, checked against the aggregated keys.
+    KeyShares,
+    /// The aggregation of both parties' nonce shares, checked to be nonzero.
+    NonceShares,
+    /// The checking of each partial signature against the signing session it is for.
+    PartialSignatures,
+}
+
+/// Why a replayed step failed, naming the field of the transcript at fault.
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("{step:?} step: missing {field}")]
+    Missing { step: ReplayStep, field: String },
+    #[error("{step:?} step: recomputed {field} doesn't match the transcript")]
+    Mismatch { step: ReplayStep, field: String },
+    #[error("{step:?} step: {field} doesn't verify")]
+    InvalidPartialSignature { step: ReplayStep, field: String },
+    #[error("{step:?} step: {source}")]
+    Protocol { step: ReplayStep, source: ProtocolErrorKind },
+}
+
+fn required<T: Clone>(step: ReplayStep, value: Option<&T>, field: &str) -> Result<T, ReplayError> {
+    value.cloned().ok_or_else(|| ReplayError::Missing { step, field: field.to_owned() })
+}
+
+impl TradeTranscript {
+    /// The transcripts of each tx input signed with the peer, in the order of [`INPUTS`].
+    const fn inputs(&self) -> [&SigTranscript; 7] {
+        [
+            &self.swap_tx_input,
+            &self.buyers_warning_tx_buyer_input,
+            &self.buyers_warning_tx_seller_input,
+            &self.sellers_warning_tx_buyer_input,
+            &self.sellers_warning_tx_seller_input,
+            &self.buyers_redirect_tx_input,
+            &self.sellers_redirect_tx_input,
+        ]
+    }
+
+    /// Replay the transcript step by step, up to the furthest step it has the data for, returning
+    /// the steps replayed.
+    ///
+    /// # Errors
+    ///
+    /// Fails at the first step whose recomputed values don't match the transcript, or for which the
+    /// transcript is missing some (but not all) of the data.
+    pub fn replay(&self) -> Result<Vec<ReplayStep>, ReplayError> {
+        let mut trade_model = TradeModel::new(self.trade_id.clone(), self.my_role);
+        let mut steps = Vec::new();
+        for (step, replay) in [
+            (ReplayStep::KeyShares, Self::replay_key_shares as fn(&Self, &mut TradeModel) -> Result<bool, ReplayError>),
+            (ReplayStep::NonceShares, Self::replay_nonce_shares),
+            (ReplayStep::PartialSignatures, Self::replay_partial_signatures),
+        ] {
+            if !replay(self, &mut trade_model)? {
+                break;
+            }
+            steps.push(step);
+        }
+        Ok(steps)
+    }
+
+    fn replay_key_shares(&self, trade_model: &mut TradeModel) -> Result<bool, ReplayError> {
+        const STEP: ReplayStep = ReplayStep::KeyShares;
+        let (buyer_output, seller_output) = (&self.buyer_output_key, &self.seller_output_key);
+        if buyer_output.peers_key_share.is_none() && seller_output.peers_key_share.is_none() {
+            return Ok(false);
+        }
+        let my_buyer_output_key = required(STEP, buyer_output.my_key_share.as_ref(), "buyer_output_key.my_key_share")?;
+        let my_seller_output_key = required(STEP, seller_output.my_key_share.as_ref(), "seller_output_key.my_key_share")?;
+        *trade_model.buyer_output_key_ctx.my_key_share_mut() = Some(KeyPair::from_public(my_buyer_output_key));
+        *trade_model.seller_output_key_ctx.my_key_share_mut() = Some(KeyPair::from_public(my_seller_output_key));
+        if !trade_model.am_buyer() {
+            // As done on generating our key shares:
+            trade_model.swap_tx_input_sig_ctx.adaptor_point = MaybePoint::Valid(my_buyer_output_key);
+        }
+        trade_model.set_peer_key_shares(
+            required(STEP, buyer_output.peers_key_share.as_ref(), "buyer_output_key.peers_key_share")?,
+            required(STEP, seller_output.peers_key_share.as_ref(), "seller_output_key.peers_key_share")?);
+        trade_model.aggregate_key_shares().map_err(|source| ReplayError::Protocol { step: STEP, source })?;
+
+        for (key_ctx, key, field) in [
+            (&trade_model.buyer_output_key_ctx, buyer_output, "buyer_output_key.aggregated_key"),
+            (&trade_model.seller_output_key_ctx, seller_output, "seller_output_key.aggregated_key"),
+        ] {
+            let aggregated_key = required(STEP, key.aggregated_key.as_ref(), field)?;
+            if key_ctx.aggregated_key.as_ref().map(|k| k.pub_key) != Some(aggregated_key) {
+                return Err(ReplayError::Mismatch { step: STEP, field: field.to_owned() });
+            }
+        }
+        Ok(true)
+    }
+
+    fn replay_nonce_shares(&self, trade_model: &mut TradeModel) -> Result<bool, ReplayError> {
+        const STEP: ReplayStep = ReplayStep::NonceShares;
+        if self.inputs().iter().all(|input| input.peers_nonce_share.is_none()) {
+            return Ok(false);
+        }
+        for ((sig_ctx, _), (input, name)) in trade_model.sig_ctxs_mut().into_iter().zip(self.inputs().into_iter().zip(INPUTS)) {
+            let pub_nonce = required(STEP, input.my_nonce_share.as_ref(), &format!("{}.my_nonce_share", name))?;
+            sig_ctx.my_nonce_share = Some(NoncePair { pub_nonce, sec_nonce: None });
+            sig_ctx.peers_nonce_share = Some(required(STEP, input.peers_nonce_share.as_ref(), &format!("{}.peers_nonce_share", name))?);
+        }
+        trade_model.aggregate_nonce_shares().map_err(|source| ReplayError::Protocol { step: STEP, source })?;
+        Ok(true)
+    }
+
+    fn replay_partial_signatures(&self, trade_model: &mut TradeModel) -> Result<bool, ReplayError> {
+        const STEP: ReplayStep = ReplayStep::PartialSignatures;
+        if self.inputs().iter().all(|input| input.my_partial_signature.is_none() && input.peers_partial_signature.is_none()) {
+            return Ok(false);
+        }
+        for ((sig_ctx, key_ctx), (input, name)) in trade_model.sig_ctxs_mut().into_iter().zip(self.inputs().into_iter().zip(INPUTS)) {
+            let (Some(key_agg_ctx), Some(aggregated_nonce)) = (&key_ctx.key_agg_ctx, &sig_ctx.aggregated_nonce) else {
+                return Err(ReplayError::Protocol { step: STEP, source: ProtocolErrorKind::MissingAggNonce });
+            };
+            for (partial_sig, key_share, nonce_share, field) in [
+                (input.my_partial_signature, key_ctx.my_key_share(), sig_ctx.my_nonce_share.as_ref().map(|n| &n.pub_nonce), "my_partial_signature"),
+                (input.peers_partial_signature, key_ctx.peers_key_share(), sig_ctx.peers_nonce_share.as_ref(), "peers_partial_signature"),
+            ] {
+                let (Some(partial_sig), Some(key_share), Some(nonce_share)) = (partial_sig, key_share, nonce_share) else {
+                    continue;
+                };
+                let message = required(STEP, input.message.as_ref(), &format!("{}.message", name))?;
+                musig2::adaptor::verify_partial(key_agg_ctx, partial_sig, aggregated_nonce, sig_ctx.adaptor_point,
+                    key_share.pub_key, nonce_share, message)
+                    .map_err(|_| ReplayError::InvalidPartialSignature { step: STEP, field: format!("{}.{}", name, field) })?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl TradeModel {
+    /// The signing contexts of each tx input signed with the peer, along with the key context of
+    /// the output it spends, in the order of [`INPUTS`].
+    fn sig_ctxs_mut(&mut self) -> [(&mut SigCtx, &KeyCtx); 7] {
+        [
+            (&mut self.swap_tx_input_sig_ctx, &self.seller_output_key_ctx),
+            (&mut self.buyers_warning_tx_buyer_input_sig_ctx, &self.buyer_output_key_ctx),
+            (&mut self.buyers_warning_tx_seller_input_sig_ctx, &self.seller_output_key_ctx),
+            (&mut self.sellers_warning_tx_buyer_input_sig_ctx, &self.buyer_output_key_ctx),
+            (&mut self.sellers_warning_tx_seller_input_sig_ctx, &self.seller_output_key_ctx),
+            (&mut self.buyers_redirect_tx_input_sig_ctx, &self.buyer_output_key_ctx),
+            (&mut self.sellers_redirect_tx_input_sig_ctx, &self.seller_output_key_ctx),
+        ]
+    }
+
+    /// The transcript of the trade so far, which holds no secrets.
+    #[must_use]
+    pub fn transcript(&self) -> TradeTranscript {
+        let am_buyer = self.am_buyer();
+        TradeTranscript {
+            trade_id: self.trade_id.clone(),
+            my_role: self.my_role,
+            phase: self.phase,
+            trade_amount: self.trade_amount,
+            buyers_security_deposit: self.buyers_security_deposit,
+            sellers_security_deposit: self.sellers_security_deposit,
+            my_identity_pub_key: self.get_my_identity_pub_key(),
+            peers_identity_pub_key: self.peers_identity_pub_key,
+            buyer_output_key: self.buyer_output_key_ctx.transcript(),
+            seller_output_key: self.seller_output_key_ctx.transcript(),
+            swap_tx_input: self.swap_tx_input_sig_ctx.transcript(!am_buyer),
+            buyers_warning_tx_buyer_input: self.buyers_warning_tx_buyer_input_sig_ctx.transcript(!am_buyer),
+            buyers_warning_tx_seller_input: self.buyers_warning_tx_seller_input_sig_ctx.transcript(!am_buyer),
+            sellers_warning_tx_buyer_input: self.sellers_warning_tx_buyer_input_sig_ctx.transcript(am_buyer),
+            sellers_warning_tx_seller_input: self.sellers_warning_tx_seller_input_sig_ctx.transcript(am_buyer),
+            buyers_redirect_tx_input: self.buyers_redirect_tx_input_sig_ctx.transcript(!am_buyer),
+            sellers_redirect_tx_input: self.sellers_redirect_tx_input_sig_ctx.transcript(am_buyer),
+        }
+    }
+}
+
+impl KeyCtx {
+    fn transcript(&self) -> KeyTranscript {
+        KeyTranscript {
+            my_key_share: self.my_key_share().map(|k| k.pub_key),
+            peers_key_share: self.peers_key_share().map(|k| k.pub_key),
+            aggregated_key: self.aggregated_key.as_ref().map(|k| k.pub_key),
+        }
+    }
+}
+
+impl SigCtx {
+    fn transcript(&self, my_partial_sig_sent: bool) -> SigTranscript {
+        SigTranscript {
+            message: self.message.clone(),
+            my_nonce_share: self.my_nonce_share.as_ref().map(|n| n.pub_nonce.clone()),
+            peers_nonce_share: self.peers_nonce_share.clone(),
+            my_partial_signature: self.my_partial_sig.filter(|_| my_partial_sig_sent),
+            peers_partial_signature: self.peers_partial_sig,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Redactable;
+
+    type Result<T> = std::result::Result<T, ProtocolErrorKind>;
+
+    /// A buyer & seller taken through the protocol up to the exchange of partial signatures, with
+    /// the buyer's partial signature on the swap tx withheld, as it is at that point.
+    fn signed_trade_models() -> Result<[TradeModel; 2]> {
+        let mut buyer = TradeModel::builder("buyer-trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares()?.build();
+        let mut seller = TradeModel::builder("seller-trade".to_owned(), Role::SellerAsMaker).with_my_key_shares()?.build();
+        let [b1, b2] = buyer.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        let [s1, s2] = seller.get_my_key_shares().unwrap().map(|k| k.pub_key);
+        buyer.set_peer_key_shares(s1, s2);
+        seller.set_peer_key_shares(b1, b2);
+        for trade_model in [&mut buyer, &mut seller] {
+            trade_model.aggregate_key_shares()?;
+            trade_model.init_my_nonce_shares()?;
+        }
+        seller.peer_nonce_shares_mut().set(buyer.get_my_nonce_shares().unwrap().cloned());
+        buyer.peer_nonce_shares_mut().set(seller.get_my_nonce_shares().unwrap().cloned());
+        for trade_model in [&mut buyer, &mut seller] {
+            trade_model.aggregate_nonce_shares()?;
+            trade_model.sign_partial()?;
+        }
+        let mut buyers_sigs = buyer.get_my_partial_signatures_on_peer_txs().unwrap().cloned();
+        buyers_sigs.swap_tx_input_partial_signature = Redactable::Redacted;
+        let sellers_sigs = seller.get_my_partial_signatures_on_peer_txs().unwrap().cloned();
+        seller.peer_partial_signatures_on_my_txs_mut().set(buyers_sigs);
+        buyer.peer_partial_signatures_on_my_txs_mut().set(sellers_sigs);
+        Ok([buyer, seller])
+    }
+
+    #[test]
+    fn transcript_leaves_out_withheld_swap_tx_partial_signature() -> Result<()> {
+        let [buyer, seller] = signed_trade_models()?;
+        let buyer_transcript = buyer.transcript();
+        let seller_transcript = seller.transcript();
+        assert_eq!(buyer_transcript.buyer_output_key.aggregated_key, seller_transcript.buyer_output_key.aggregated_key);
+        assert_eq!(buyer_transcript.swap_tx_input.my_nonce_share, seller_transcript.swap_tx_input.peers_nonce_share);
+        assert!(buyer_transcript.swap_tx_input.my_partial_signature.is_none());
+        assert!(seller_transcript.swap_tx_input.my_partial_signature.is_some());
+        // Only the partial signatures on the peer's txs are handed out, and so transcribed:
+        assert!(buyer_transcript.sellers_warning_tx_buyer_input.my_partial_signature.is_some());
+        assert!(buyer_transcript.buyers_warning_tx_buyer_input.my_partial_signature.is_none());
+        assert!(seller_transcript.buyers_redirect_tx_input.my_partial_signature.is_some());
+        assert!(seller_transcript.sellers_redirect_tx_input.my_partial_signature.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn replay_finds_first_mismatched_step() -> Result<()> {
+        let [buyer, seller] = signed_trade_models()?;
+        let all_steps = [ReplayStep::KeyShares, ReplayStep::NonceShares, ReplayStep::PartialSignatures];
+        for trade_model in [&buyer, &seller] {
+            assert_eq!(trade_model.transcript().replay().unwrap(), all_steps);
+        }
+        let fresh = TradeModel::builder("trade".to_owned(), Role::SellerAsMaker).with_my_key_shares()?.build();
+        assert_eq!(fresh.transcript().replay().unwrap(), []);
+
+        // A partial signature on the wrong message (or made with the wrong nonce) fails to verify:
+        let mut transcript = buyer.transcript();
+        transcript.sellers_warning_tx_buyer_input.message = Some(b"some other tx".to_vec());
+        assert!(matches!(transcript.replay(), Err(ReplayError::InvalidPartialSignature { step: ReplayStep::PartialSignatures, field })
+            if field == "sellers_warning_tx_buyer_input.my_partial_signature"));
+        let mut transcript = seller.transcript();
+        transcript.swap_tx_input.peers_nonce_share = seller.transcript().buyers_redirect_tx_input.peers_nonce_share;
+        assert!(matches!(transcript.replay(), Err(ReplayError::InvalidPartialSignature { field, .. })
+            if field == "swap_tx_input.my_partial_signature"));
+
+        // A key share not matching the aggregated key fails the very first step:
+        let mut transcript = seller.transcript();
+        transcript.buyer_output_key.peers_key_share = transcript.seller_output_key.peers_key_share;
+        assert!(matches!(transcript.replay(), Err(ReplayError::Mismatch { step: ReplayStep::KeyShares, field })
+            if field == "buyer_output_key.aggregated_key"));
+        Ok(())
+    }
+}
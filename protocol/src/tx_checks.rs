@@ -0,0 +1,137 @@
+//! The independent re-validation of the txs of a trade, once fully assembled for broadcast, against
+//! the trade model alone: that a tx spends just the inputs it should, pays out to the scripts of the
+//! aggregated keys, pays a fee within tolerance of the rate agreed, and that the final signatures it
+//! rests on verify against the aggregated keys. This guards against a bug in the assembly of a tx
+//! (or a corrupted trade model), rather than anything the peer sends, which is checked as it comes
+//! in, so a tx failing it is never to be broadcast.
+
+use musig2::LiftedSignature;
+use secp::Point;
+use std::prelude::rust_2021::*;
+use thiserror::Error;
+
+use crate::tx_outputs::TxOutput;
+use crate::{KeyCtx, ProtocolErrorKind, TradeModel};
+
+/// How far the fee rate of an assembled tx may stray from the rate agreed with the peer, as a
+/// fraction of the latter, to allow for the estimate of its size made when it was agreed.
+pub const FEE_RATE_TOLERANCE: f64 = 0.1;
+
+/// A deposit tx, as assembled for broadcast: the outpoints it spends (with their amounts), its
+/// outputs, and its virtual size, if known.
+#[derive(Clone, Debug, Default)]
+pub struct AssembledDepositTx {
+    pub inputs: Vec<([u8; 32], u32, u64)>,
+    pub outputs: Vec<TxOutput>,
+    pub vsize: Option<u64>,
+}
+
+/// Why an assembled tx failed re-validation.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum TxCheckFailure {
+    #[error("it does not spend funding input {0}")]
+    MissingInput(usize),
+    #[error("its input {0} is not one of the funding inputs")]
+    UnexpectedInput(usize),
+    #[error("it has no output of the {0}")]
+    MissingOutput(&'static str),
+    #[error("its fee rate of {fee_rate} sat/vB is not within tolerance of the {agreed} sat/vB agreed")]
+    FeeRateOutOfTolerance { fee_rate: f64, agreed: f64 },
+    #[error("its outputs exceed its inputs")]
+    NegativeFee,
+    #[error("the final signature of the {0} doesn't verify")]
+    InvalidSignature(&'static str),
+}
+
+impl TradeModel {
+    /// Re-validate the given deposit tx, as assembled for broadcast: it must spend the funding inputs
+    /// of both parties (as far as known) and no others, have the buyer's & seller's payout outputs
+    /// to their aggregated keys (of the amounts agreed, if known), and pay a fee rate within
+    /// [`FEE_RATE_TOLERANCE`] of the rate agreed (should its size and every input amount be known).
+    /// As the deposit tx may not be published without them, the final signatures of our warning &
+    /// redirect txs must also verify.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolErrorKind::InvalidTxToPublish`] if any check fails, or if the aggregated
+    /// keys or signatures are missing.
+    pub fn check_deposit_tx(&self, tx: &AssembledDepositTx) -> Result<(), ProtocolErrorKind> {
+        let fail = |failure| ProtocolErrorKind::InvalidTxToPublish("deposit tx", failure);
+        let funding_inputs: Vec<_> = self.my_funding_inputs.iter().chain(&self.peers_funding_inputs).collect();
+        for (i, input) in funding_inputs.iter().enumerate() {
+            if !tx.inputs.iter().any(|&(txid, vout, _)| (txid, vout) == (input.txid, input.vout)) {
+                return Err(fail(TxCheckFailure::MissingInput(i)));
+            }
+        }
+        // Only with both halves funded by the daemon are all the inputs known:
+        let all_inputs_known = !self.my_funding_inputs.is_empty() && !self.peers_funding_inputs.is_empty();
+        if all_inputs_known {
+            if let Some(i) = tx.inputs.iter().position(|&(txid, vout, _)| !funding_inputs.iter()
+                .any(|input| (input.txid, input.vout) == (txid, vout)))
+            {
+                return Err(fail(TxCheckFailure::UnexpectedInput(i)));
+            }
+        }
+
+        let [buyer_script, seller_script] = self.deposit_tx_payout_scripts()?;
+        for (script_pub_key, buyer, output) in [(buyer_script, true, "buyer's payout"), (seller_script, false, "seller's payout")] {
+            let amount = self.funding_contribution(buyer);
+            if !tx.outputs.iter().any(|o| o.script_pub_key == script_pub_key && amount.is_none_or(|a| o.amount == a)) {
+                return Err(fail(TxCheckFailure::MissingOutput(output)));
+            }
+        }
+
+        if let (true, Some(vsize), Some(agreed)) = (all_inputs_known, tx.vsize, self.deposit_tx_fee_rate) {
+            let input_amount = tx.inputs.iter().map(|&(_, _, amount)| amount).sum::<u64>();
+            let output_amount = tx.outputs.iter().map(|o| o.amount).sum::<u64>();
+            let fee = input_amount.checked_sub(output_amount).ok_or_else(|| fail(TxCheckFailure::NegativeFee))?;
+            #[expect(clippy::cast_precision_loss, reason = "amounts & sizes are far below 2^52")]
+            let fee_rate = fee as f64 / vsize.max(1) as f64;
+            if (fee_rate - agreed).abs() > agreed * FEE_RATE_TOLERANCE {
+                return Err(fail(TxCheckFailure::FeeRateOutOfTolerance { fee_rate, agreed }));
+            }
+        }
+
+        let package = self.prepared_tx_package(None)?;
+        for (input, name) in [
+            (&package.warning_tx_buyer_input, "warning tx buyer input"),
+            (&package.warning_tx_seller_input, "warning tx seller input"),
+            (&package.redirect_tx_input, "redirect tx input"),
+        ] {
+            musig2::verify_single(input.pub_key, input.signature, &input.message)
+                .map_err(|_| fail(TxCheckFailure::InvalidSignature(name)))?;
+        }
+        Ok(())
+    }
+
+    /// The scriptPubKeys of the buyer's & seller's payout outputs of the deposit tx: taproot outputs
+    /// to their aggregated keys.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the key shares haven't been aggregated yet.
+    pub fn deposit_tx_payout_scripts(&self) -> Result<[Vec<u8>; 2], ProtocolErrorKind> {
+        let script_pub_key = |key_ctx: &KeyCtx| key_ctx.aggregated_key.as_ref()
+            .map(|key| p2tr_script_pub_key(key.pub_key)).ok_or(ProtocolErrorKind::MissingAggPubKey);
+        Ok([script_pub_key(&self.buyer_output_key_ctx)?, script_pub_key(&self.seller_output_key_ctx)?])
+    }
+
+    /// Re-validate the given final signature of the swap tx, as assembled for broadcast, against the
+    /// aggregated key of the seller's payout output it spends.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ProtocolErrorKind::InvalidTxToPublish`] if the signature doesn't verify, or if
+    /// the aggregated key or the message signed is missing.
+    pub fn check_swap_tx(&self, swap_tx_input_signature: LiftedSignature) -> Result<(), ProtocolErrorKind> {
+        let key = self.seller_output_key_ctx.aggregated_key.as_ref().ok_or(ProtocolErrorKind::MissingAggPubKey)?.pub_key;
+        let message = self.swap_tx_input_sig_ctx.message.as_ref().ok_or(ProtocolErrorKind::MissingAggSig)?;
+        musig2::verify_single(key, swap_tx_input_signature, message).map_err(|_|
+            ProtocolErrorKind::InvalidTxToPublish("swap tx", TxCheckFailure::InvalidSignature("swap tx input")))
+    }
+}
+
+/// The scriptPubKey of a taproot output to the given key: a segwit v1 program of the x-only key.
+fn p2tr_script_pub_key(key: Point) -> Vec<u8> {
+    [&[0x51, 0x20][..], &key.serialize_xonly()].concat()
+}
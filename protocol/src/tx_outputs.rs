@@ -0,0 +1,100 @@
+//! The ordering of the outputs of the txs built for a trade, and the blinding of change amounts, so
+//! that the txs of trades run by this daemon are harder to pick out on the chain: by outputs always
+//! in the same (builder-specific) order, say, or change amounts exactly matching the trade terms.
+//!
+//! Both parties must order the outputs of a tx they sign together the same way, so the order may
+//! only depend on what they both know: the outputs themselves (as by BIP 69), or a seed they share.
+//! A party's change amount is its own to blind, as whatever it gives up of it goes to the fee.
+
+use rand::Rng;
+use sha2::{Digest as _, Sha256};
+use std::prelude::rust_2021::*;
+
+const SHUFFLE_TAG: &[u8] = b"MuSigTradeProtocol/output shuffle";
+
+/// An output of a tx, by its scriptPubKey & amount in sats.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TxOutput {
+    pub script_pub_key: Vec<u8>,
+    pub amount: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputOrdering {
+    /// By amount, then by scriptPubKey, as per BIP 69: canonical, and used by many wallets.
+    Bip69,
+    /// Shuffled by the given seed, known to both parties (such as the aggregated key of the multisig
+    /// output of the tx), so that the order looks random to anyone else.
+    Shuffle([u8; 32]),
+}
+
+/// Put the given outputs in the given order, whatever order they are given in.
+pub fn order_outputs(outputs: &mut [TxOutput], ordering: OutputOrdering) {
+    outputs.sort_by(|a, b| a.amount.cmp(&b.amount).then_with(|| a.script_pub_key.cmp(&b.script_pub_key)));
+    if let OutputOrdering::Shuffle(seed) = ordering {
+        // A Fisher-Yates shuffle of the outputs in BIP 69 order, drawing each index from a tagged
+        // hash of the seed, so that every implementation gets the same order from the same seed:
+        let tag_hash = Sha256::digest(SHUFFLE_TAG);
+        for i in (1..outputs.len()).rev() {
+            let hash = Sha256::new()
+                .chain_update(tag_hash)
+                .chain_update(tag_hash)
+                .chain_update(seed)
+                .chain_update((i as u64).to_be_bytes())
+                .finalize();
+            let draw = hash[..8].iter().fold(0, |draw, &b| draw << 8 | u64::from(b));
+            outputs.swap(i, usize::try_from(draw % (i as u64 + 1)).unwrap_or(i));
+        }
+    }
+}
+
+/// The given change amount, less a random amount of up to the given number of sats (given up to the
+/// fee), so that it does not match the trade terms exactly. Change left at or below the dust limit
+/// is left be, and blinded change is never taken below it.
+pub fn blind_change_amount(change: u64, max_blinding: u64, dust_limit: u64, rng: &mut impl Rng) -> u64 {
+    let max_blinding = max_blinding.min(change.saturating_sub(dust_limit));
+    change - rng.gen_range(0..=max_blinding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(amounts: &[u64]) -> Vec<TxOutput> {
+        amounts.iter().zip(0..).map(|(&amount, i)| TxOutput { script_pub_key: vec![0x51, 0x20, i], amount }).collect()
+    }
+
+    #[test]
+    fn outputs_are_ordered_by_bip69_or_by_the_shared_seed_alone() {
+        let mut ordered = outputs(&[30_000, 10_000, 20_000, 10_000]);
+        order_outputs(&mut ordered, OutputOrdering::Bip69);
+        assert_eq!(ordered.iter().map(|output| (output.amount, output.script_pub_key[2])).collect::<Vec<_>>(),
+            [(10_000, 1), (10_000, 3), (20_000, 2), (30_000, 0)]);
+
+        // Either party shuffles the outputs the same way, whatever order it starts them in:
+        let mut shuffled = outputs(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        order_outputs(&mut shuffled, OutputOrdering::Shuffle([7; 32]));
+        let mut again = outputs(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        again.reverse();
+        order_outputs(&mut again, OutputOrdering::Shuffle([7; 32]));
+        assert_eq!(shuffled, again);
+        let mut other_seed = outputs(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        order_outputs(&mut other_seed, OutputOrdering::Shuffle([8; 32]));
+        assert_ne!(shuffled, other_seed);
+        shuffled.sort_by_key(|output| output.amount);
+        assert_eq!(shuffled, outputs(&[1, 2, 3, 4, 5, 6, 7, 8]));
+    }
+
+    #[test]
+    fn blinded_change_stays_within_tolerance_and_above_dust() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let blinded = blind_change_amount(100_000, 500, 330, &mut rng);
+            assert!((99_500..=100_000).contains(&blinded));
+        }
+        for _ in 0..100 {
+            assert!((330..=400).contains(&blind_change_amount(400, 500, 330, &mut rng)));
+        }
+        assert_eq!(blind_change_amount(300, 500, 330, &mut rng), 300);
+    }
+}
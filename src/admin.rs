@@ -0,0 +1,170 @@
+//! The admin service, holding the operational RPCs of the daemon (listing & aborting trades, stats,
+//! config & receiver registry reloads, snapshot export, and scripting of the mock chain, if any), so that they are kept off the `MuSig`
+//! service seen by its trading clients and the JSON gateway. It is only served on its own listen
+//! address (or admin listeners), and (if the env var named by `admin_token_env` is set) only to
+//! callers bearing the token in it, as `authorization: Bearer <token>` metadata. It may only be
+//! served on a non-loopback address with a token.
+
+use musig_proto::admin::{AbortTradeRequest, DaemonStats, ExportSnapshotRequest, ExportSnapshotResponse, GetStatsRequest,
+    MockChainInfo, ReceiverRegistryInfo, RefreshReceiverRegistryRequest, ReloadConfigRequest, ReloadConfigResponse,
+    ScriptMockChainRequest};
+use musig_proto::admin::script_mock_chain_request::Action;
+use musig_proto::helloworld::{self, ListTradesRequest, ListTradesResponse, TradePhaseCount};
+use musig_proto::helloworld::mu_sig_server::MuSig as _;
+use musig_trade_protocol::{TradeModelStore, TradePhase};
+use std::collections::BTreeMap;
+use std::mem;
+use std::prelude::rust_2021::*;
+use std::sync::PoisonError;
+use tonic::{Request, Response, Status};
+
+use crate::burningman::ReceiverSnapshot;
+use crate::cipher::MasterSecret;
+use crate::config::{Config, SecretKeySource};
+use crate::events::TradeEvent;
+use crate::{digest, logging, snapshot, MyMuSig};
+
+pub use musig_proto::admin::admin_server::{Admin, AdminServer};
+
+pub struct MyAdmin<S: TradeModelStore> {
+    musig: MyMuSig<S>,
+    /// Where to get the passphrase to encrypt exported snapshots with.
+    snapshot_passphrase: SecretKeySource,
+}
+
+impl<S: TradeModelStore + Send + Sync + 'static> MyAdmin<S> {
+    pub const fn new(musig: MyMuSig<S>, snapshot_passphrase: SecretKeySource) -> Self {
+        Self { musig, snapshot_passphrase }
+    }
+
+    fn receiver_registry_info(&self, snapshot: &ReceiverSnapshot) -> ReceiverRegistryInfo {
+        let active_set = self.musig.chain_tip.height().and_then(|height| snapshot.set_at(height));
+        ReceiverRegistryInfo {
+            version: snapshot.version,
+            activation_heights: snapshot.sets.iter().map(|set| set.activation_height).collect(),
+            active_activation_height: active_set.map(|set| set.activation_height),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: TradeModelStore + Send + Sync + 'static> Admin for MyAdmin<S> {
+    async fn list_trades(&self, request: Request<ListTradesRequest>) -> Result<Response<ListTradesResponse>, Status> {
+        self.musig.list_trades(request).await
+    }
+
+    async fn abort_trade(&self, request: Request<AbortTradeRequest>) -> Result<Response<helloworld::TradeSummary>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let request_digest = digest(&request);
+        let summary = self.musig.spawn_blocking(move |this| {
+            let summary = this.archive_trade_if("AbortTrade", &request.trade_id, request.expected_revision, request_digest,
+                |trade_model| if trade_model.phase() < TradePhase::DepositTxSigned {
+                    Ok(())
+                } else {
+                    Err(Status::failed_precondition(format!("trade with id {} has had its deposit tx signed, so must be closed",
+                        trade_model.trade_id())))
+                })?;
+            this.events.publish(TradeEvent::Aborted(summary.clone()));
+            Ok(summary)
+        }).await?;
+
+        Ok(Response::new(summary.into()))
+    }
+
+    async fn get_stats(&self, request: Request<GetStatsRequest>) -> Result<Response<DaemonStats>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let (live, archived) = self.musig.spawn_blocking(|this|
+            Ok((this.trade_model_store.list_trade_models(), this.trade_model_store.list_archived_trades()))).await?;
+        let mut counts = BTreeMap::<TradePhase, u64>::new();
+        for summary in &live {
+            *counts.entry(summary.phase).or_default() += 1;
+        }
+        Ok(Response::new(DaemonStats {
+            live_trades: live.len() as u64,
+            archived_trades: archived.len() as u64,
+            live_trades_by_phase: counts.into_iter()
+                .map(|(phase, trades)| TradePhaseCount { phase: helloworld::TradePhase::from(phase).into(), trades })
+                .collect(),
+        }))
+    }
+
+    async fn refresh_receiver_registry(&self, request: Request<RefreshReceiverRegistryRequest>)
+        -> Result<Response<ReceiverRegistryInfo>, Status>
+    {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let registry = self.musig.receiver_registry.clone()
+            .ok_or_else(|| Status::failed_precondition("no burning-man receiver registry is configured"))?;
+        // The old snapshot stays in force should the new one not load:
+        let snapshot = self.musig.spawn_blocking(move |_| registry.refresh()
+            .map_err(|e| Status::failed_precondition(e.to_string()))).await?;
+        Ok(Response::new(self.receiver_registry_info(&snapshot)))
+    }
+
+    async fn reload_config(&self, request: Request<ReloadConfigRequest>) -> Result<Response<ReloadConfigResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let path = self.musig.config_file.clone()
+            .ok_or_else(|| Status::failed_precondition("the daemon was not started with a config file"))?;
+        // Nothing is put into force until all of it has loaded, the receiver registry being last:
+        let (config, snapshot) = self.musig.spawn_blocking(move |this| {
+            let config = Config::from_file(&path).map_err(|e| Status::failed_precondition(
+                format!("could not reload config file {}: {}", path.display(), e)))?;
+            let snapshot = this.receiver_registry.as_ref().map(|registry| registry.refresh()).transpose()
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+            Ok((config, snapshot))
+        }).await?;
+
+        let mut changed_settings = Vec::new();
+        let mut trade_limits = self.musig.trade_limits.write().unwrap_or_else(PoisonError::into_inner);
+        if mem::replace(&mut *trade_limits, config.trade_limits) != config.trade_limits {
+            changed_settings.push("trade_limit_*".to_owned());
+        }
+        drop(trade_limits);
+        if self.musig.policy.set_config(config.policy) != config.policy {
+            changed_settings.push("policy_*".to_owned());
+        }
+        if logging::set_log_sensitive(config.log_sensitive) != config.log_sensitive {
+            changed_settings.push("log_sensitive".to_owned());
+        }
+        println!("Reloaded the config, changing: {}",
+            if changed_settings.is_empty() { "nothing".to_owned() } else { changed_settings.join(", ") });
+
+        Ok(Response::new(ReloadConfigResponse {
+            changed_settings,
+            receiver_registry: snapshot.map(|snapshot| self.receiver_registry_info(&snapshot)),
+        }))
+    }
+
+    async fn export_snapshot(&self, request: Request<ExportSnapshotRequest>) -> Result<Response<ExportSnapshotResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let source = self.snapshot_passphrase.clone();
+        let snapshot = self.musig.spawn_blocking(move |this| {
+            let passphrase = MasterSecret::fetch(&source).map_err(|e| Status::failed_precondition(e.to_string()))?;
+            Ok(snapshot::export(&*this.trade_model_store, &passphrase))
+        }).await?;
+        println!("Exported a snapshot of {} bytes", snapshot.len());
+
+        Ok(Response::new(ExportSnapshotResponse { snapshot }))
+    }
+
+    async fn script_mock_chain(&self, request: Request<ScriptMockChainRequest>) -> Result<Response<MockChainInfo>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let mock_chain = self.musig.mock_chain.as_ref()
+            .ok_or_else(|| Status::failed_precondition("the daemon is not following a mock chain"))?;
+        match request.into_inner().action.ok_or_else(|| Status::invalid_argument("missing mock chain action"))? {
+            Action::MineBlocks(blocks) => mock_chain.mine(blocks),
+            Action::ReorgDepth(depth) => mock_chain.reorg(depth),
+            Action::DoubleSpendTx(tx) => mock_chain.double_spend(&tx),
+            Action::ScheduleConfirmation(schedule) => mock_chain.schedule_confirmation(&schedule.tx, schedule.blocks),
+        }
+
+        Ok(Response::new(MockChainInfo { height: mock_chain.height() }))
+    }
+}
+
@@ -0,0 +1,204 @@
+//! Opt-in backup of the private key shares of each trade, as Shamir shares (over the secp256k1
+//! scalar field) each encrypted to one of a set of operator-configured recipients, so that losing
+//! the trade store mid-trade doesn't leave the funds recoverable only with the peer's cooperation.
+//!
+//! Any threshold number of recipients' secret keys suffice to restore the key shares of a trade
+//! from its backup file, with `server recover-key-shares <file> --output <file>`. Each share is
+//! encrypted with the store cipher, keyed by an ECDH secret between a fresh ephemeral key and the
+//! recipient's key.
+
+use musig_trade_protocol::{Secret, SecretCipher as _};
+use prost::Message as _;
+use secp::{MaybeScalar, Point, Scalar};
+use sha2::{Digest as _, Sha256};
+use std::io::{self, Write as _};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::prelude::rust_2021::*;
+use thiserror::Error;
+
+use crate::cipher::{MasterSecret, StoreCipher};
+use crate::config::BackupConfig;
+use crate::file_store::{hex_encode, write_atomically, write_atomically_with};
+
+const MAGIC: &[u8] = b"MUSIGKEYBACKUP1\n";
+const FILE_EXTENSION: &str = "backup";
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct BackupRecord {
+    #[prost(string, tag = "1")]
+    trade_id: String,
+    #[prost(uint32, tag = "2")]
+    threshold: u32,
+    /// The public keys of the backed-up key shares, to check the restored private keys against.
+    #[prost(bytes = "vec", repeated, tag = "3")]
+    pub_keys: Vec<Vec<u8>>,
+    #[prost(message, repeated, tag = "4")]
+    shares: Vec<EncryptedShareRecord>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct EncryptedShareRecord {
+    #[prost(uint32, tag = "1")]
+    index: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    recipient: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    ephemeral_key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    sealed: Vec<u8>,
+}
+
+type Result<T> = std::result::Result<T, BackupError>;
+
+/// A restored key share, as its public key & (secret) private key.
+pub type KeyShare = (Point, Secret<Scalar>);
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum BackupError {
+    #[error("not a key share backup")]
+    NotABackup,
+    #[error("only {found} of the {threshold} recipient keys needed could decrypt a share of the backup")]
+    NotEnoughShares { found: usize, threshold: usize },
+    #[error("the backup shares do not restore the key shares they were made from")]
+    Inconsistent,
+    Decode(#[from] prost::DecodeError),
+    Io(#[from] io::Error),
+}
+
+/// Writes a backup file for each new trade to the backup directory, once its key shares are made.
+pub struct KeyShareBackup {
+    dir: PathBuf,
+    recipients: Vec<Point>,
+    threshold: usize,
+}
+
+impl KeyShareBackup {
+    pub fn open(config: &BackupConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        Ok(Self { dir: config.dir.clone(), recipients: config.recipients.clone(), threshold: config.threshold })
+    }
+
+    /// Split each of the given private key shares (with the given public keys) of the trade into a
+    /// share per recipient, then write them out, encrypted, to the trade's backup file.
+    pub fn back_up(&self, trade_id: &str, key_shares: &[(Point, Scalar)]) -> io::Result<()> {
+        let shares_per_key: Vec<_> = key_shares.iter()
+            .map(|&(_, prv_key)| split(prv_key, self.threshold, self.recipients.len()))
+            .collect();
+        let shares = (1..).zip(&self.recipients).map(|(index, &recipient)| {
+            let plaintext: Vec<u8> = shares_per_key.iter()
+                .flat_map(|shares: &Vec<MaybeScalar>| shares[index as usize - 1].serialize())
+                .collect();
+            let ephemeral_prv_key = Scalar::random(&mut rand::thread_rng());
+            EncryptedShareRecord {
+                index,
+                recipient: recipient.serialize().into(),
+                ephemeral_key: ephemeral_prv_key.base_point_mul().serialize().into(),
+                sealed: share_cipher(recipient * ephemeral_prv_key)
+                    .seal(&plaintext, &associated_data(trade_id, index)),
+            }
+        }).collect();
+        let record = BackupRecord {
+            trade_id: trade_id.to_owned(),
+            threshold: u32::try_from(self.threshold).map_err(io::Error::other)?,
+            pub_keys: key_shares.iter().map(|(pub_key, _)| pub_key.serialize().into()).collect(),
+            shares,
+        };
+        let path = self.dir.join(hex_encode(trade_id)).with_extension(FILE_EXTENSION);
+        write_atomically(&path, &[MAGIC, &record.encode_to_vec()].concat())
+    }
+}
+
+/// Decrypt the shares of a backup file with as many of the given recipient secret keys as match,
+/// and restore from them the trade ID and the backed-up key shares, as public & private key pairs.
+pub fn recover(backup: &[u8], recipient_keys: &[Scalar]) -> Result<(String, Vec<KeyShare>)> {
+    let record = BackupRecord::decode(backup.strip_prefix(MAGIC).ok_or(BackupError::NotABackup)?)?;
+    let pub_keys = record.pub_keys.iter()
+        .map(|bytes| Point::try_from(&bytes[..]).map_err(|_| BackupError::NotABackup))
+        .collect::<Result<Vec<_>>>()?;
+    let threshold = record.threshold as usize;
+
+    let mut shares: Vec<(u32, Vec<MaybeScalar>)> = Vec::new();
+    for share in &record.shares {
+        let Some(&prv_key) = recipient_keys.iter().find(|k| k.base_point_mul().serialize()[..] == share.recipient) else {
+            continue;
+        };
+        let ephemeral_key = Point::try_from(&share.ephemeral_key[..]).map_err(|_| BackupError::NotABackup)?;
+        let plaintext = share_cipher(ephemeral_key * prv_key)
+            .open(&share.sealed, &associated_data(&record.trade_id, share.index))
+            .ok_or(BackupError::Inconsistent)?;
+        let ys = plaintext.chunks(32)
+            .map(|chunk| MaybeScalar::try_from(chunk).map_err(|_| BackupError::Inconsistent))
+            .collect::<Result<Vec<_>>>()?;
+        if share.index == 0 || ys.len() != pub_keys.len() || shares.iter().any(|(i, _)| *i == share.index) {
+            return Err(BackupError::Inconsistent);
+        }
+        shares.push((share.index, ys));
+    }
+    if shares.len() < threshold.max(1) {
+        return Err(BackupError::NotEnoughShares { found: shares.len(), threshold });
+    }
+    shares.truncate(threshold);
+
+    let key_shares = pub_keys.into_iter().enumerate().map(|(i, pub_key)| {
+        let points: Vec<_> = shares.iter().map(|(x, ys)| (*x, ys[i])).collect();
+        let prv_key = combine(&points).not_zero().map_err(|_| BackupError::Inconsistent)?;
+        if prv_key.base_point_mul() != pub_key {
+            return Err(BackupError::Inconsistent);
+        }
+        Ok((pub_key, Secret::new(prv_key)))
+    }).collect::<Result<_>>()?;
+    Ok((record.trade_id, key_shares))
+}
+
+/// Write the restored key shares to the given file (readable by the owner only), a public & private
+/// key pair in hex per line, with each line written straight to the file rather than buffered.
+pub fn write_key_shares(path: &Path, key_shares: &[KeyShare]) -> io::Result<()> {
+    write_atomically_with(path, |file| key_shares.iter()
+        .try_for_each(|(pub_key, prv_key)| writeln!(file, "{:x} {:x}", pub_key, prv_key.expose_secret())))
+}
+
+/// The cipher for a recipient's share, keyed from the ECDH secret shared with them.
+fn share_cipher(shared_point: Point) -> StoreCipher {
+    let key = Sha256::digest(shared_point.serialize()).into();
+    StoreCipher::derive(&MasterSecret::Key(key), &[])
+}
+
+fn associated_data(trade_id: &str, index: u32) -> Vec<u8> {
+    [MAGIC, trade_id.as_bytes(), &index.to_be_bytes()].concat()
+}
+
+/// Split the secret into `n` Shamir shares, any `threshold` of which determine it, as the values at
+/// x = 1, 2, ..., n of a random polynomial of degree `threshold - 1` with the secret as its constant term.
+fn split(secret: Scalar, threshold: usize, n: usize) -> Vec<MaybeScalar> {
+    let coefficients: Vec<_> = iter::once(secret)
+        .chain((1..threshold).map(|_| Scalar::random(&mut rand::thread_rng())))
+        .collect();
+    (1..=n as u128).map(|x| {
+        let x = Scalar::try_from(x).unwrap();
+        coefficients.iter().rev().fold(MaybeScalar::Zero, |acc, &c| acc * x + c)
+    }).collect()
+}
+
+/// Interpolate the Shamir shares (at distinct nonzero x) to recover the secret, the value at x = 0.
+fn combine(shares: &[(u32, MaybeScalar)]) -> MaybeScalar {
+    let to_scalar = |x: u32| Scalar::try_from(u128::from(x)).unwrap();
+    shares.iter().fold(MaybeScalar::Zero, |acc, &(x_i, y_i)| {
+        let basis = shares.iter().filter(|&&(x_j, _)| x_j != x_i).fold(Scalar::one(), |basis, &(x_j, _)| {
+            // Multiply by x_j / (x_j - x_i), which is well defined as the x-coordinates are distinct:
+            basis * to_scalar(x_j) * invert((to_scalar(x_j) - to_scalar(x_i)).unwrap())
+        });
+        acc + basis * y_i
+    })
+}
+
+/// The multiplicative inverse of the (nonzero) scalar, as x^(n - 2) by Fermat's little theorem, as
+/// the `secp` crate only provides inversion with backend features that we don't otherwise need.
+/// This isn't constant-time, which is fine for the (public) x-coordinates of the Shamir shares.
+fn invert(x: Scalar) -> Scalar {
+    let exponent = (Scalar::max() - Scalar::one()).unwrap().serialize();
+    exponent.iter()
+        .flat_map(|&byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+        .fold(Scalar::one(), |acc, bit| if bit { acc * acc * x } else { acc * acc })
+}
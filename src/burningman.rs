@@ -0,0 +1,173 @@
+//! The burning-man receiver registry: the receivers of the redirect tx of each trade, as taken from
+//! a snapshot of the DAO's burning-man set, signed by a configured key, rather than the receivers
+//! asked for in the request, which come from the peer by way of the untrusted front-ends.
+//!
+//! A snapshot is a JSON file of the form:
+//!
+//! ```json
+//! {"version": 7, "receiver_sets": [
+//!   {"activation_height": 900000, "receivers": [{"address": "bc1q...", "weight": 1500}, ...]}, ...]}
+//! ```
+//!
+//! in which the receiver set in force at a given block height is the one with the latest activation
+//! height not above it, paid out to in proportion to the receivers' weights. Alongside it must be a
+//! file named as the snapshot with `.sig` appended, holding the hex-encoded BIP 340 signature of a
+//! tagged hash of the snapshot file's bytes. A snapshot may be refreshed (as after the DAO updates
+//! it), but never replaced by one of an older version.
+
+use musig2::CompactSignature;
+use secp::Point;
+use sha2::{Digest as _, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, PoisonError, RwLock};
+use thiserror::Error;
+
+use crate::config::BurningmanConfig;
+use crate::json::{self, Json};
+
+const TAG: &[u8] = b"MuSigTradeProtocol/burningman snapshot";
+
+/// A set of receivers of the redirect tx, by address & weight, in force from the given height.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReceiverSet {
+    pub activation_height: u32,
+    pub receivers: Vec<(String, u64)>,
+}
+
+impl ReceiverSet {
+    /// The outputs of a redirect tx paying out the given amount (in sats) to this set, by address &
+    /// amount, split by weight. The sats left over from rounding down go to the first receiver.
+    pub fn outputs(&self, amount: u64) -> Vec<(String, u64)> {
+        let total_weight: u128 = self.receivers.iter().map(|&(_, weight)| u128::from(weight)).sum();
+        let mut outputs: Vec<_> = self.receivers.iter()
+            .map(|(address, weight)| {
+                let share = u128::from(amount) * u128::from(*weight) / total_weight;
+                (address.clone(), u64::try_from(share).unwrap())
+            })
+            .collect();
+        let paid: u64 = outputs.iter().map(|&(_, amount)| amount).sum();
+        outputs[0].1 += amount - paid;
+        outputs
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ReceiverSnapshot {
+    pub version: u64,
+    /// The receiver sets, in increasing order of activation height.
+    pub sets: Vec<ReceiverSet>,
+}
+
+impl ReceiverSnapshot {
+    /// Parse a snapshot from its JSON form, checking that every set pays someone something.
+    pub fn parse(s: &str) -> Result<Self, RegistryError> {
+        let malformed = |msg: &str| RegistryError::Malformed(msg.to_owned());
+        let json = json::parse(s).map_err(RegistryError::Malformed)?;
+        let version = json.get("version").and_then(Json::as_u64).ok_or_else(|| malformed("missing version"))?;
+        let mut sets = json.get("receiver_sets").and_then(Json::as_array).ok_or_else(|| malformed("missing receiver_sets"))?
+            .iter()
+            .map(|set| {
+                let activation_height = set.get("activation_height").and_then(Json::as_u64)
+                    .and_then(|height| height.try_into().ok())
+                    .ok_or_else(|| malformed("missing (or invalid) activation_height"))?;
+                let receivers = set.get("receivers").and_then(Json::as_array)
+                    .ok_or_else(|| malformed("missing receivers"))?
+                    .iter()
+                    .map(|receiver| Some((receiver.get("address")?.as_str()?.to_owned(),
+                        receiver.get("weight")?.as_u64().filter(|&weight| weight != 0)?)))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| malformed("receivers must each have an address & nonzero weight"))?;
+                if receivers.is_empty() {
+                    return Err(malformed("empty receiver set"));
+                }
+                Ok(ReceiverSet { activation_height, receivers })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        sets.sort_by_key(|set| set.activation_height);
+        if sets.windows(2).any(|pair| pair[0].activation_height == pair[1].activation_height) {
+            return Err(malformed("duplicate activation_height"));
+        }
+        Ok(Self { version, sets })
+    }
+
+    /// The receiver set in force at the given block height, if any.
+    pub fn set_at(&self, height: u32) -> Option<&ReceiverSet> {
+        self.sets.iter().rev().find(|set| set.activation_height <= height)
+    }
+}
+
+/// The message signed for a snapshot with the given bytes.
+pub fn snapshot_message(bytes: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(TAG);
+    Sha256::new().chain_update(tag_hash).chain_update(tag_hash).chain_update(bytes).finalize().into()
+}
+
+/// The burning-man receiver snapshot last loaded, reloadable from its file with [`Self::refresh`].
+pub struct ReceiverRegistry {
+    path: PathBuf,
+    pub_key: Point,
+    snapshot: RwLock<Arc<ReceiverSnapshot>>,
+}
+
+impl ReceiverRegistry {
+    /// Load the registry as configured, or return `None` if there is no snapshot file configured.
+    pub fn load(config: &BurningmanConfig) -> Result<Option<Self>, RegistryError> {
+        let Some(path) = config.snapshot_file.clone() else { return Ok(None) };
+        let pub_key = config.pub_key.ok_or(RegistryError::MissingPubKey)?;
+        let snapshot = RwLock::new(Arc::new(read_snapshot(&path, pub_key)?));
+        Ok(Some(Self { path, pub_key, snapshot }))
+    }
+
+    pub fn snapshot(&self) -> Arc<ReceiverSnapshot> {
+        Arc::clone(&self.snapshot.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Reload the snapshot from its file, keeping the old one should the new one fail to load or be
+    /// of an older version.
+    pub fn refresh(&self) -> Result<Arc<ReceiverSnapshot>, RegistryError> {
+        let new_snapshot = Arc::new(read_snapshot(&self.path, self.pub_key)?);
+        let mut snapshot = self.snapshot.write().unwrap_or_else(PoisonError::into_inner);
+        if new_snapshot.version < snapshot.version {
+            return Err(RegistryError::Rollback(new_snapshot.version, snapshot.version));
+        }
+        *snapshot = Arc::clone(&new_snapshot);
+        drop(snapshot);
+        Ok(new_snapshot)
+    }
+}
+
+fn read_snapshot(path: &Path, pub_key: Point) -> Result<ReceiverSnapshot, RegistryError> {
+    let bytes = fs::read(path)?;
+    let mut sig_path = path.as_os_str().to_owned();
+    sig_path.push(".sig");
+    let signature = decode_hex(fs::read_to_string(sig_path)?.trim())
+        .and_then(|bytes| CompactSignature::try_from(&bytes[..]).ok())
+        .ok_or(RegistryError::InvalidSignature)?;
+    musig2::verify_single(pub_key, signature, snapshot_message(&bytes))
+        .map_err(|_| RegistryError::InvalidSignature)?;
+    ReceiverSnapshot::parse(std::str::from_utf8(&bytes).map_err(|_| RegistryError::Malformed("not UTF-8".to_owned()))?)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("could not read receiver snapshot: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed receiver snapshot: {0}")]
+    Malformed(String),
+    #[error("invalid signature on receiver snapshot")]
+    InvalidSignature,
+    #[error("receiver snapshot version {0} is older than the loaded version {1}")]
+    Rollback(u64, u64),
+    #[error("a 'burningman_pub_key' must be set to load the receiver snapshot with")]
+    MissingPubKey,
+}
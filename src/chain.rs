@@ -0,0 +1,226 @@
+//! The daemon's view of the chain, as tracked off a chain backend: an Esplora-compatible HTTP API
+//! (as served by `electrs` or a mempool.space instance), polled for the height of its tip. With no
+//! chain backend set, the chain is simulated, with its tip held at a fixed height (or else mocked,
+//! as by [`crate::mock_chain`]). The timestamp of
+//! the tip is also fetched whenever it moves, by which to tell whether the backend is synced.
+
+use musig_proto::helloworld::HeightTriggerKind;
+use musig_trade_protocol::{DeadlineKind, PolicyActionKind, TradeModel};
+use std::io;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::http;
+use crate::tor::Socks5Proxy;
+
+/// The height of the tip of the simulated chain, for a daemon with no chain backend.
+pub const SIMULATED_TIP_HEIGHT: u32 = 900_000;
+/// The mean time between blocks, by which the age of the tip is counted in blocks.
+const BLOCK_INTERVAL: Duration = Duration::from_mins(10);
+/// How long to wait for each poll of the chain backend (of up to three requests) to be answered, so
+/// that a stalled backend cannot hold up the following of the chain.
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The height of the chain tip, as last seen by the daemon, if seen at all yet. Subscribers are
+/// told each time the tip moves up.
+#[derive(Clone)]
+pub struct ChainTip(Arc<watch::Sender<u32>>);
+
+impl Default for ChainTip {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(0).0))
+    }
+}
+
+impl ChainTip {
+    /// A chain tip held at the given height, as for a simulated chain.
+    pub fn fixed(height: u32) -> Self {
+        let chain_tip = Self::default();
+        chain_tip.observe(height);
+        chain_tip
+    }
+
+    pub fn observe(&self, height: u32) {
+        self.0.send_if_modified(|tip| {
+            let moved_up = height > *tip;
+            *tip = (*tip).max(height);
+            moved_up
+        });
+    }
+
+    pub fn height(&self) -> Option<u32> {
+        Some(*self.0.borrow()).filter(|&height| height != 0)
+    }
+
+    /// A receiver of the height of the tip (0 until first seen).
+    pub fn subscribe(&self) -> watch::Receiver<u32> {
+        self.0.subscribe()
+    }
+}
+
+/// Publishes the txs of the daemon's trades to the network. A broadcast is network I/O, which may
+/// take any time, so it is never made by a trade actor (holding the lock on the trade model), but
+/// between the two halves of its protocol step: one reading the tx to broadcast off the trade model,
+/// the other recording the tx as broadcast.
+#[tonic::async_trait]
+pub trait TxBroadcaster: Send + Sync {
+    /// Broadcast the given signed tx.
+    ///
+    /// # Errors
+    /// Why the tx could not be broadcast.
+    async fn broadcast(&self, tx: &[u8]) -> io::Result<()>;
+
+    /// Where the given tx, broadcast before, stands in the chain, as far as the broadcaster follows
+    /// it. By default it doesn't, so the tx is taken to be mined in the block at the tip when it
+    /// was broadcast.
+    fn tx_status(&self, _tx: &[u8]) -> TxStatus {
+        TxStatus::Untracked
+    }
+}
+
+/// Where a tx stands in the chain, as told by its [`TxBroadcaster`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxStatus {
+    Untracked,
+    Unconfirmed,
+    /// Mined in the block at the given height.
+    Mined(u32),
+}
+
+/// The broadcaster of the simulated chain, on which every tx is taken to be mined at once, in the
+/// block at the tip, so is sent nowhere.
+// TODO: Broadcast to the chain backend (by a POST to its '/tx' endpoint), once the txs are real ones.
+pub struct SimulatedBroadcaster;
+
+#[tonic::async_trait]
+impl TxBroadcaster for SimulatedBroadcaster {
+    async fn broadcast(&self, _tx: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How the chain backend was found when last polled, for the readiness checks of the daemon.
+#[derive(Clone, Default)]
+pub struct ChainBackendStatus(Arc<Mutex<BackendState>>);
+
+#[derive(Default)]
+struct BackendState {
+    /// When the backend was last polled successfully, if ever.
+    last_reached: Option<Instant>,
+    /// The height & timestamp of the tip of the backend, as last fetched.
+    tip: Option<(u32, SystemTime)>,
+}
+
+impl ChainBackendStatus {
+    fn state(&self) -> std::sync::MutexGuard<'_, BackendState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Check that the backend has been reached within the given time, and that it is synced, that
+    /// is, that its tip is no older than the given number of block intervals (unless that is 0).
+    ///
+    /// # Errors
+    /// Why the backend isn't fit to follow the chain by.
+    pub fn check(&self, max_silence: Duration, max_blocks_behind: u32) -> Result<(), String> {
+        let state = self.state();
+        let (last_reached, tip) = (state.last_reached, state.tip);
+        drop(state);
+        if last_reached.is_none_or(|at| at.elapsed() > max_silence) {
+            return Err(format!("chain backend not reached in the last {:?}", max_silence));
+        }
+        if max_blocks_behind == 0 {
+            return Ok(());
+        }
+        let (height, time) = tip.ok_or("chain backend tip not yet fetched")?;
+        let age = SystemTime::now().duration_since(time).unwrap_or_default();
+        if age > BLOCK_INTERVAL * max_blocks_behind {
+            return Err(format!("chain backend tip at height {} is {} minutes old, so not synced", height,
+                age.as_secs() / 60));
+        }
+        Ok(())
+    }
+}
+
+/// Poll the chain backend at the given base URL (through the given proxy, if any) for the height of
+/// its tip, recording it in the given chain tip, and how the backend was found in the given status.
+/// This never returns.
+pub async fn follow_chain(url: String, proxy: Option<Socks5Proxy>, chain_tip: ChainTip, status: ChainBackendStatus,
+                          poll_interval: Duration) {
+    let mut interval = time::interval(poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let result = time::timeout(POLL_TIMEOUT, poll_backend(&url, proxy, &chain_tip, &status)).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {:?}", POLL_TIMEOUT))));
+        if let Err(e) = result {
+            eprintln!("Could not fetch the chain tip from {}: {}", url, e);
+        }
+    }
+}
+
+async fn poll_backend(url: &str, proxy: Option<Socks5Proxy>, chain_tip: &ChainTip, status: &ChainBackendStatus)
+    -> io::Result<()>
+{
+    let url = url.trim_end_matches('/');
+    let height = fetch_tip_height(url, proxy).await?;
+    chain_tip.observe(height);
+    // The timestamp of the tip is only fetched once the tip moves:
+    let known_height = status.state().tip.map(|(height, _)| height);
+    if known_height != Some(height) {
+        let time = fetch_block_time(url, proxy, height).await?;
+        status.state().tip = Some((height, time));
+    }
+    status.state().last_reached = Some(Instant::now());
+    Ok(())
+}
+
+async fn fetch_tip_height(url: &str, proxy: Option<Socks5Proxy>) -> io::Result<u32> {
+    let body = fetch(&format!("{}/blocks/tip/height", url), proxy).await?;
+    body.parse().map_err(|_| invalid_data(format!("malformed tip height: {}", body)))
+}
+
+/// The timestamp of the block at the given height, as found in its (hex-encoded) header.
+async fn fetch_block_time(url: &str, proxy: Option<Socks5Proxy>, height: u32) -> io::Result<SystemTime> {
+    let hash = fetch(&format!("{}/block-height/{}", url, height), proxy).await?;
+    let header = fetch(&format!("{}/block/{}/header", url, hash), proxy).await?;
+    // The timestamp is the little-endian 32-bit field after the version, previous block hash and
+    // merkle root:
+    let timestamp = header.get(136..144)
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| invalid_data(format!("malformed block header: {}", header)))?
+        .swap_bytes();
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp.into()))
+}
+
+/// The trimmed body of a successful `GET` of the given URL.
+async fn fetch(url: &str, proxy: Option<Socks5Proxy>) -> io::Result<String> {
+    let (status, body) = http::request("GET", url, &[], &[], proxy).await?;
+    if status != 200 {
+        return Err(invalid_data(format!("unexpected HTTP status: {}", status)));
+    }
+    Ok(body.trim().to_owned())
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The block heights of interest of the trade, each of which the client may want to act on once the
+/// chain tip reaches it, given the depth at which the deposit tx counts as confirmed.
+pub fn height_triggers(trade_model: &TradeModel, deposit_confirmations: u32) -> Vec<(HeightTriggerKind, u32)> {
+    let mut triggers = Vec::new();
+    if let Some(height) = trade_model.deposit_tx_height {
+        triggers.push((HeightTriggerKind::DepositConfirmed, height.saturating_add(deposit_confirmations.max(1) - 1)));
+    }
+    triggers.extend(trade_model.deadlines.iter()
+        .filter(|deadline| deadline.kind == DeadlineKind::WarningTxClaim)
+        .filter_map(|deadline| Some((HeightTriggerKind::WarningTxClaimable, deadline.due.height?))));
+    // The first block our warning tx could have confirmed in:
+    triggers.extend(trade_model.policy_actions.iter()
+        .filter(|action| action.kind == PolicyActionKind::PublishWarningTx)
+        .filter_map(|action| Some((HeightTriggerKind::RedirectTxEligible, action.height?.saturating_add(1)))));
+    triggers
+}
@@ -0,0 +1,65 @@
+//! The chunked transfer of half deposit PSBTs too large to pass comfortably in one message, each as
+//! a stream of chunks, the last of which carries the SHA-256 hash of the whole PSBT, checked once it
+//! has been reassembled.
+
+use musig_proto::helloworld::{DepositPsbt, PsbtChunk, SignedDepositPsbtChunk, SignedDepositPsbtRequest};
+use sha2::{Digest as _, Sha256};
+use std::prelude::rust_2021::*;
+use tonic::{Status, Streaming};
+
+/// The length of each chunk of a PSBT sent by the daemon, bar the last.
+pub const CHUNK_LEN: usize = 64 * 1024;
+/// The longest PSBT the daemon reassembles from chunks.
+pub const MAX_PSBT_LEN: usize = 16 * 1024 * 1024;
+
+/// The given PSBT as chunks, with its hash in the last (which an empty PSBT is passed as alone).
+pub fn into_chunks(psbt: &[u8]) -> Vec<PsbtChunk> {
+    let mut chunks: Vec<_> = psbt.chunks(CHUNK_LEN)
+        .map(|data| PsbtChunk { data: data.to_vec(), psbt_sha256: vec![] })
+        .collect();
+    if chunks.is_empty() {
+        chunks.push(PsbtChunk::default());
+    }
+    if let Some(last) = chunks.last_mut() {
+        last.psbt_sha256 = Sha256::digest(psbt).to_vec();
+    }
+    chunks
+}
+
+/// Receive the signed half deposit PSBT streamed by the client, as the request it would otherwise
+/// have made with `SubmitSignedDepositPsbt`.
+///
+/// # Errors
+///
+/// Fails with `INVALID_ARGUMENT` if the stream is empty, switches trades, has chunks past the last
+/// or ends before it, with `RESOURCE_EXHAUSTED` if the PSBT is longer than [`MAX_PSBT_LEN`], and
+/// with `DATA_LOSS` if the PSBT reassembled doesn't match its hash.
+pub async fn receive_signed_psbt(chunks: &mut Streaming<SignedDepositPsbtChunk>) -> Result<SignedDepositPsbtRequest, Status> {
+    let first = chunks.message().await?
+        .ok_or_else(|| Status::invalid_argument("no chunks of the signed half deposit PSBT were sent"))?;
+    let (trade_id, expected_revision) = (first.trade_id, first.expected_revision);
+    let mut psbt = Vec::new();
+    let mut chunk = first.chunk.unwrap_or_default();
+    loop {
+        if psbt.len() + chunk.data.len() > MAX_PSBT_LEN {
+            return Err(Status::resource_exhausted(format!("signed half deposit PSBT is longer than {} bytes", MAX_PSBT_LEN)));
+        }
+        psbt.extend_from_slice(&chunk.data);
+        if !chunk.psbt_sha256.is_empty() {
+            if Sha256::digest(&psbt)[..] != chunk.psbt_sha256[..] {
+                return Err(Status::data_loss("signed half deposit PSBT does not match the hash in its last chunk"));
+            }
+            break;
+        }
+        let next = chunks.message().await?
+            .ok_or_else(|| Status::invalid_argument("signed half deposit PSBT stream ended before its last chunk"))?;
+        if !next.trade_id.is_empty() && next.trade_id != trade_id {
+            return Err(Status::invalid_argument("chunks of the signed half deposit PSBT are for different trades"));
+        }
+        chunk = next.chunk.unwrap_or_default();
+    }
+    if chunks.message().await?.is_some() {
+        return Err(Status::invalid_argument("chunk sent past the last of the signed half deposit PSBT"));
+    }
+    Ok(SignedDepositPsbtRequest { trade_id, signed_half_deposit_psbt: Some(DepositPsbt { deposit_psbt: psbt }), expected_revision })
+}
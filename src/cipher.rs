@@ -0,0 +1,124 @@
+//! Encryption of trade model secrets at rest, keyed from either a passphrase or a raw key fetched
+//! from an external key management service at startup.
+//!
+//! Secrets are sealed with ChaCha20-Poly1305 (RFC 8439) under a random nonce, authenticating the
+//! associated data alongside. A passphrase is first stretched into the master key with PBKDF2 with
+//! HMAC-SHA256 (RFC 8018), salted per store, and the cipher key & key check value are then derived
+//! from the master key with HMAC-SHA256.
+
+use chacha20poly1305::aead::{Aead as _, KeyInit as _, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hmac::{Hmac, Mac};
+use musig_trade_protocol::SecretCipher;
+use rand::RngCore as _;
+use sha2::Sha256;
+use std::io;
+use std::process::Command;
+use std::prelude::rust_2021::*;
+
+use crate::config::SecretKeySource;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const FORMAT_VERSION: u8 = 2;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// The secret from which the store cipher is derived.
+pub enum MasterSecret {
+    Passphrase(Vec<u8>),
+    Key([u8; 32]),
+}
+
+impl MasterSecret {
+    /// Read the passphrase from the configured environment variable, or run the configured command
+    /// (the external KMS hook) which must print a 32-byte key in hex to its stdout.
+    pub fn fetch(source: &SecretKeySource) -> io::Result<Self> {
+        match source {
+            SecretKeySource::PassphraseEnv(var) => {
+                let passphrase = std::env::var(var).map_err(|e| io::Error::new(io::ErrorKind::NotFound,
+                    format!("could not read store passphrase from env var {}: {}", var, e)))?;
+                Ok(Self::Passphrase(passphrase.into_bytes()))
+            }
+            SecretKeySource::Command(command) => {
+                let output = Command::new("sh").arg("-c").arg(command).output()?;
+                if !output.status.success() {
+                    return Err(io::Error::other(format!("store key command failed: {}", output.status)));
+                }
+                let key = std::str::from_utf8(&output.stdout).ok().and_then(|s| decode_hex_key(s.trim()))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                        "store key command must print a 32-byte key in hex"))?;
+                Ok(Self::Key(key))
+            }
+        }
+    }
+}
+
+pub struct StoreCipher {
+    aead: ChaCha20Poly1305,
+    key_check: [u8; 32],
+}
+
+impl StoreCipher {
+    /// Derive the cipher from the master secret. The salt only affects passphrase stretching.
+    pub fn derive(master_secret: &MasterSecret, salt: &[u8]) -> Self {
+        let master_key = match master_secret {
+            MasterSecret::Passphrase(passphrase) => stretch_passphrase(passphrase, salt, PBKDF2_ITERATIONS),
+            MasterSecret::Key(key) => *key,
+        };
+        Self {
+            aead: ChaCha20Poly1305::new(&hmac(&master_key, &[b"encryption key"]).into()),
+            key_check: hmac(&master_key, &[b"key check"]),
+        }
+    }
+
+    /// A value to store alongside the encrypted data, to detect a wrong passphrase or key up front.
+    pub const fn key_check(&self) -> [u8; 32] {
+        self.key_check
+    }
+}
+
+impl SecretCipher for StoreCipher {
+    fn seal(&self, plaintext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self.aead.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: associated_data })
+            .expect("plaintext is far below the ChaCha20-Poly1305 limit of 256 GiB");
+        [&[FORMAT_VERSION][..], &nonce, &ciphertext].concat()
+    }
+
+    fn open(&self, ciphertext: &[u8], associated_data: &[u8]) -> Option<Vec<u8>> {
+        let (&version, rest) = ciphertext.split_first()?;
+        if version != FORMAT_VERSION || rest.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        self.aead.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: associated_data }).ok()
+    }
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).unwrap();
+    for d in data {
+        mac.update(d);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Stretch a passphrase into a 32-byte key with PBKDF2 with HMAC-SHA256 (RFC 8018), run for the
+/// given number of iterations.
+pub fn stretch_passphrase(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(passphrase, salt, iterations)
+}
+
+fn decode_hex_key(hex: &str) -> Option<[u8; 32]> {
+    let mut key = [0; 32];
+    if hex.len() != 64 {
+        return None;
+    }
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
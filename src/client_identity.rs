@@ -0,0 +1,125 @@
+//! The identity of the client of each call: the cert it made its connection with, on a listener
+//! requiring client certs (mTLS), or else the IP address it connected from. It is worked out once
+//! per call, by a layer setting it in the request extensions, so that the rate limits, the call log,
+//! the audit log and the record of who opened each trade (which the trade quotas count by, and which
+//! only that client may make calls for) all tell the clients apart alike, with no handler having to
+//! look at the connection (or parse a cert) itself.
+
+use sha2::{Digest as _, Sha256};
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::task::{Context, Poll};
+use tonic::codegen::http::{Extensions, Request};
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo, UdsConnectInfo};
+use tonic::transport::CertificateDer;
+use tower_layer::Layer;
+use tower_service::Service;
+
+tokio::task_local! {
+    static CLIENT_IDENTITY: Option<ClientIdentity>;
+}
+
+thread_local! {
+    static BLOCKING_CLIENT_IDENTITY: RefCell<Option<ClientIdentity>> = const { RefCell::new(None) };
+}
+
+/// The identity of the client of the call being served by the current task, or by the blocking work
+/// being done on the current thread, if known.
+pub fn client_identity() -> Option<ClientIdentity> {
+    CLIENT_IDENTITY.try_with(Clone::clone).ok().flatten()
+        .or_else(|| BLOCKING_CLIENT_IDENTITY.with_borrow(Clone::clone))
+}
+
+/// Run the given (blocking) closure as part of the call of the client with the given identity, if
+/// known.
+pub fn in_call<T>(client_identity: Option<ClientIdentity>, f: impl FnOnce() -> T) -> T {
+    let old_identity = BLOCKING_CLIENT_IDENTITY.replace(client_identity);
+    let result = f();
+    BLOCKING_CLIENT_IDENTITY.set(old_identity);
+    result
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ClientIdentity {
+    /// The SHA-256 fingerprint of the client's (leaf) cert, as verified against the client CA.
+    Cert([u8; 32]),
+    Ip(IpAddr),
+}
+
+impl ClientIdentity {
+    /// The identity of the client of a connection made with the given verified cert chain (if by
+    /// mTLS) from the given address (if by TCP), if either.
+    pub fn of_connection(certs: Option<&[CertificateDer<'_>]>, remote_addr: Option<SocketAddr>) -> Option<Self> {
+        match certs.and_then(<[_]>::first) {
+            Some(cert) => Some(Self::Cert(Sha256::digest(cert).into())),
+            None => remote_addr.map(|addr| Self::Ip(addr.ip())),
+        }
+    }
+
+    /// The identity of the client of the call with the given request extensions, as set by the
+    /// server from its connection.
+    fn of_call(extensions: &Extensions) -> Option<Self> {
+        if let Some(info) = extensions.get::<TlsConnectInfo<TcpConnectInfo>>() {
+            return Self::of_connection(info.peer_certs().as_deref().map(Vec::as_slice), info.get_ref().remote_addr());
+        }
+        if let Some(info) = extensions.get::<TlsConnectInfo<UdsConnectInfo>>() {
+            return Self::of_connection(info.peer_certs().as_deref().map(Vec::as_slice), None);
+        }
+        Self::of_connection(None, extensions.get::<TcpConnectInfo>().and_then(TcpConnectInfo::remote_addr))
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cert(fingerprint) => {
+                f.write_str("cert:")?;
+                fingerprint.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+            Self::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// A layer setting the identity of the client of each call (if known) in the request extensions,
+/// for the inner layers & the service to key on, and as that of the call's task.
+#[derive(Clone, Copy)]
+pub struct ClientIdentityLayer;
+
+impl<S> Layer<S> for ClientIdentityLayer {
+    type Service = WithClientIdentity<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        WithClientIdentity { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct WithClientIdentity<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for WithClientIdentity<S>
+    where S: Service<Request<B>>,
+          S::Future: Send + 'static
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let identity = ClientIdentity::of_call(req.extensions());
+        if let Some(identity) = &identity {
+            req.extensions_mut().insert(identity.clone());
+        }
+        Box::pin(CLIENT_IDENTITY.scope(identity, self.inner.call(req)))
+    }
+}
@@ -0,0 +1,913 @@
+use secp::Point;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::prelude::rust_2021::*;
+use thiserror::Error;
+
+use crate::events::EVENT_KINDS;
+
+/// Server configuration, read from the (optional) config file passed with `--config <path>`. The
+/// file consists of `key = value` lines, with blank lines and `#` comments ignored, and with any
+/// keys not given taking their default values.
+pub struct Config {
+    pub listen_addr: SocketAddr,
+    /// Where to serve the peer service, for the daemons of our peers to deliver their payloads to
+    /// directly, if anywhere. Unlike `listen_addr`, this is meant to be reachable by the peers (as
+    /// a Tor onion service, say), so it is off by default.
+    pub peer_listen_addr: Option<SocketAddr>,
+    /// The address of our peer service to hand out to our peers, if it isn't published as an onion
+    /// service by us: e.g. an onion service set up in the Tor daemon's own config.
+    pub peer_public_address: Option<String>,
+    /// The onion service to publish the peer service as, through the control port of a Tor daemon.
+    pub onion_service: Option<OnionServiceConfig>,
+    /// The SOCKS5 proxy (such as the SOCKS port of a Tor daemon) to make every outbound connection
+    /// through, other than those to loopback addresses, if any.
+    pub socks_proxy: Option<SocketAddr>,
+    pub rate_limits: RateLimitConfig,
+    pub trade_quota: TradeQuotaConfig,
+    /// The most streaming subscriptions (height triggers & tx confirmations) which may be open at
+    /// once for any one trade, or `None` for no limit (set with a limit of 0).
+    pub max_subscriptions_per_trade: Option<usize>,
+    pub trade_limits: TradeLimitConfig,
+    pub rpc_timeouts: RpcTimeoutConfig,
+    pub decode_limits: DecodeLimitConfig,
+    /// Whether to log the byte fields of requests (keys, nonces, signatures & such) in full, rather
+    /// than just their lengths & hash prefixes.
+    pub log_sensitive: bool,
+    /// Where to serve the Prometheus metrics, if anywhere.
+    pub metrics_listen_addr: Option<SocketAddr>,
+    /// Where to serve the JSON gateway to the `MuSig` service, if anywhere. It doesn't authenticate
+    /// its clients, so it may only be a loopback address.
+    pub gateway_listen_addr: Option<SocketAddr>,
+    /// Where to serve the admin service, if anywhere, apart from the `MuSig` service. It may only be
+    /// a non-loopback address with an admin token set.
+    pub admin_listen_addr: Option<SocketAddr>,
+    /// The name of the env var holding the token the callers of the admin service must bear, if set.
+    pub admin_token_env: String,
+    /// The listeners to serve the services on besides those above, in the order first named.
+    pub listeners: Vec<ListenerConfig>,
+    pub grpc_web: GrpcWebConfig,
+    pub store: StoreConfig,
+    pub signer: SignerConfig,
+    /// How long a trade may stay in an early phase before it is aborted as stale, or `None` to
+    /// keep such trades indefinitely (set with `stale_trade_ttl_secs = 0`).
+    pub stale_trade_ttl: Option<Duration>,
+    pub stale_trade_scan_interval: Duration,
+    pub deadlines: DeadlineConfig,
+    pub policy: PolicyConfig,
+    pub chain: ChainConfig,
+    pub webhook: WebhookConfig,
+    /// The name of the env var holding the passphrase to encrypt or decrypt store snapshots with.
+    pub snapshot_passphrase_env: String,
+    /// Where & to whom to back up the private key shares of each new trade, if anywhere.
+    pub backup: Option<BackupConfig>,
+    /// The name of the env var holding the recipients' secret keys to recover key shares with.
+    pub backup_recipient_keys_env: String,
+    /// The public key of the mediator who must sign off the receivers of the redirect tx of each
+    /// trade before we sign it, if any. Without one, the receivers asked for are taken on trust.
+    pub mediator_pub_key: Option<Point>,
+    pub burningman: BurningmanConfig,
+    pub faults: FaultConfig,
+    pub nonce_reuse: NonceReuseConfig,
+    /// The file the config was read from, if any, to be re-read by `ReloadConfig`.
+    pub source_file: Option<PathBuf>,
+}
+
+/// What to do, as given by the (optional) subcommand on the command line.
+pub enum Command {
+    /// Run the gRPC server. This is the default.
+    Serve,
+    /// Write a snapshot of the trade store to the given file, then exit.
+    ExportSnapshot(PathBuf),
+    /// Add every trade in the given snapshot file to the trade store, then exit.
+    ImportSnapshot(PathBuf),
+    /// Restore the private key shares from the given trade backup file and write them to the given
+    /// output file (readable by the owner only), then exit.
+    RecoverKeyShares(PathBuf, PathBuf),
+    /// Replay the trade transcript in the given file (as exported with `ExportTradeTranscript`),
+    /// printing the steps which check out, then exit.
+    ReplayTranscript(PathBuf),
+    /// Play a trade through with fixed test keys and write every intermediate value of it to the
+    /// given file, as JSON test vectors, then exit.
+    ExportTestVectors(PathBuf),
+    /// Simulate trades between two daemons run in-process, driving both parties through every
+    /// protocol step, then exit.
+    SimulateTrade,
+}
+
+pub enum StoreConfig {
+    /// Keep trade models in memory only, losing them on shutdown.
+    Memory,
+    /// Persist trade models to the given directory, reloading them at startup. Their secrets are
+    /// encrypted with a key from the given source, if any, and otherwise stored in plaintext.
+    File { dir: PathBuf, secret_key_source: Option<SecretKeySource> },
+}
+
+pub enum SignerConfig {
+    /// Make our key shares, nonce shares & partial signatures in-process, keeping their secrets in
+    /// the trade models (and thus the store).
+    Local,
+    /// Call out to the external signing service at the given URL, which keeps the secrets.
+    Remote { url: String },
+}
+
+/// A listener to serve one of the services on, set with `listener.<name>.<key>` lines, alongside
+/// `listen_addr`, `peer_listen_addr` & `admin_listen_addr`: e.g. a unix socket for admin tooling.
+pub struct ListenerConfig {
+    pub name: String,
+    pub addr: ListenAddr,
+    pub service: ListenerService,
+    /// The TLS identity to serve with, as PEM files of the cert chain & private key, if any, or
+    /// else plaintext.
+    pub tls: Option<TlsConfig>,
+    /// The name of the env var holding the token the callers must bear, if set. An admin listener
+    /// falls back to `admin_token_env`.
+    pub token_env: Option<String>,
+    /// Whether the onion service (if configured) forwards to this listener, in place of any
+    /// `peer_listen_addr`.
+    pub onion: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// A unix socket at the given path, given as `unix:<path>`.
+    Unix(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ListenerService {
+    MuSig,
+    Peer,
+    Admin,
+}
+
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// The CA (as a PEM file) whose certs the clients must connect with, if any (for mTLS).
+    pub client_ca: Option<PathBuf>,
+}
+
+/// The Shamir backup of the private key shares of each new trade, split into a share per recipient
+/// (encrypted to their public key), any `threshold` of which suffice to restore the key shares.
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    pub recipients: Vec<Point>,
+    pub threshold: usize,
+}
+
+/// The number of calls to the `MuSig` service each client (by IP address) may make per minute, in
+/// bursts of up to as many, or `None` for no limit (set with a limit of 0).
+#[derive(Clone, Copy)]
+pub struct RateLimitConfig {
+    pub init_trade_per_min: Option<u32>,
+    /// The limit on all other calls, `InitTrade` calls being counted apart.
+    pub rpc_per_min: Option<u32>,
+}
+
+/// The server-side timeouts of the calls to the `MuSig` service, by RPC name, each `None` for no
+/// timeout beyond any set by the client (set with a timeout of 0). RPCs not named take the default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RpcTimeoutConfig {
+    pub default: Option<Duration>,
+    pub per_rpc: Vec<(String, Option<Duration>)>,
+}
+
+impl RpcTimeoutConfig {
+    pub fn timeout(&self, rpc: &str) -> Option<Duration> {
+        self.per_rpc.iter().find(|(name, _)| name == rpc).map_or(self.default, |&(_, timeout)| timeout)
+    }
+}
+
+/// The most trades which may be open (live) at once, overall and per client, or `None` for no limit
+/// (set with a limit of 0).
+#[derive(Clone, Copy)]
+pub struct TradeQuotaConfig {
+    pub max_open_trades: Option<usize>,
+    pub max_open_trades_per_client: Option<usize>,
+}
+
+/// The bounds on the amounts of the trades the daemon takes part in, each `None` for no bound (set
+/// with a bound of 0): on the trade amount, in sats, and on each party's security deposit, as a
+/// whole percentage of the trade amount.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TradeLimitConfig {
+    pub min_trade_amount: Option<u64>,
+    pub max_trade_amount: Option<u64>,
+    pub min_security_deposit_pct: Option<u64>,
+    pub max_security_deposit_pct: Option<u64>,
+}
+
+/// The protocol deadlines tracked for each trade, with any not to be tracked set to `None` (set with
+/// a duration or number of blocks of 0).
+#[derive(Clone, Copy)]
+pub struct DeadlineConfig {
+    /// How long the peer may take over each protocol step, up until the deposit tx is published.
+    pub peer_response_timeout: Option<Duration>,
+    /// How long the buyer has to pay, once the deposit tx is published.
+    pub payment_window: Option<Duration>,
+    /// How many blocks after the deposit tx's confirmation a warning tx may be claimed.
+    pub warning_tx_claim_blocks: Option<u32>,
+    /// How long the peer may go without being seen, up until the swap tx is signed, before it is
+    /// announced as unresponsive (set with a duration of 0, as by default, never to announce it).
+    pub peer_unresponsive_after: Option<Duration>,
+    pub scan_interval: Duration,
+}
+
+impl DeadlineConfig {
+    pub const fn any(self) -> bool {
+        self.peer_response_timeout.is_some() || self.payment_window.is_some() || self.warning_tx_claim_blocks.is_some()
+            || self.peer_unresponsive_after.is_some()
+    }
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        Self {
+            peer_response_timeout: Some(Duration::from_mins(10)),
+            payment_window: Some(Duration::from_hours(24)),
+            warning_tx_claim_blocks: Some(720),
+            peer_unresponsive_after: None,
+            scan_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The limits on the size of the requests to the daemon's services, checked on the wire before they
+/// are decoded, so that no crafted request can make the daemon allocate far more than it holds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DecodeLimitConfig {
+    /// The longest request message, as encoded.
+    pub max_message_len: usize,
+    /// The longest PSBT, chunk of one, or payload sealed for the peer (which may hold one).
+    pub max_psbt_len: usize,
+    pub max_tx_len: usize,
+    /// The longest of any other bytes or string field: key material, signatures, IDs, addresses &
+    /// such.
+    pub max_field_len: usize,
+    /// The most memory a request message may take once decoded, by a conservative estimate.
+    pub allocation_budget: usize,
+}
+
+impl Default for DecodeLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_message_len: 4 * 1024 * 1024,
+            max_psbt_len: 1024 * 1024,
+            // The most a standard tx may weigh, at one weight unit per byte:
+            max_tx_len: 400_000,
+            max_field_len: 4096,
+            allocation_budget: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// The catching of peers reusing a public nonce across trades, by an index of the nonces seen.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NonceReuseConfig {
+    /// How many of the nonces seen most recently to index, or 0 to not check for reuse.
+    pub index_capacity: usize,
+    /// Whether to refuse to take a trade any further once its peer is found reusing a nonce, rather
+    /// than just flagging the trade.
+    pub refuse: bool,
+}
+
+impl Default for NonceReuseConfig {
+    fn default() -> Self {
+        Self { index_capacity: 65_536, refuse: false }
+    }
+}
+
+/// The daemon's policy of automatic protocol responses, taken as the deadlines of each trade pass,
+/// which may be overridden for each trade. No responses are taken by default.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PolicyConfig {
+    /// How many blocks past a missed payment deadline to publish our warning tx, if at all.
+    pub warning_tx_after_blocks: Option<u32>,
+    /// Whether to claim the deposit once the timelock of our published warning tx expires.
+    pub auto_claim: bool,
+    /// Whether to publish our warning tx as soon as the peer is announced unresponsive, once the
+    /// deposit tx is published, rather than waiting out the payment deadline.
+    pub warning_tx_on_unresponsive_peer: bool,
+    /// Whether to only announce the responses which would be taken, without taking them.
+    pub dry_run: bool,
+}
+
+/// Where the daemon follows the chain from, if anywhere, rather than simulating it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChainConfig {
+    /// The base URL of an Esplora-compatible HTTP API (plain `http` only) to poll for the chain tip.
+    pub backend_url: Option<String>,
+    pub poll_interval: Duration,
+    /// How many block intervals old the tip of the backend may be for the daemon to be ready to
+    /// serve trades, or 0 to not check.
+    pub max_blocks_behind: u32,
+    pub mock: MockChainConfig,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        Self {
+            backend_url: None, poll_interval: Duration::from_secs(30), max_blocks_behind: 6,
+            mock: MockChainConfig::default(),
+        }
+    }
+}
+
+/// Whether to follow a mock chain in place of a chain backend, mining a block every interval (if
+/// any), each tx broadcast being mined the given number of blocks after the tip.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MockChainConfig {
+    pub enabled: bool,
+    pub block_interval: Option<Duration>,
+    pub confirmation_blocks: u32,
+}
+
+/// Where to load the burning-man receiver registry from, if anywhere, and the key its snapshots must
+/// be signed with. Both must be set for the registry to be used.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BurningmanConfig {
+    pub snapshot_file: Option<PathBuf>,
+    pub pub_key: Option<Point>,
+}
+
+/// Whether to serve gRPC-web calls (over HTTP/1.1) as well as plain gRPC ones, for browser-based
+/// tools, and from which web page origins to let them through.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GrpcWebConfig {
+    pub enabled: bool,
+    /// The origins (such as `https://dashboard.example`) allowed to call the daemon from a browser,
+    /// or `*` for any. Calls made outside a browser, with no origin, are always let through.
+    pub allowed_origins: Vec<String>,
+}
+
+/// Which trade events to POST to which webhooks, if any, and how to sign them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WebhookConfig {
+    /// The (plain `http`) URLs to POST each event to.
+    pub urls: Vec<String>,
+    /// The kinds of event to notify of, out of [`EVENT_KINDS`].
+    pub events: Vec<&'static str>,
+    /// The name of the env var holding the secret key to sign the payloads with.
+    pub secret_env: String,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            events: vec!["aborted", "step_failed", "deadline_passed", "closed"],
+            secret_env: "WEBHOOK_SECRET".to_owned(),
+        }
+    }
+}
+
+/// The faults to inject into our payloads for the peer, to test the peer's checks of them. None are
+/// injected by default, and none should ever be for real trades.
+#[derive(Clone, Copy, Default)]
+pub struct FaultConfig {
+    /// Hand out nonce shares, one of which is malformed.
+    pub corrupt_nonce_shares: bool,
+    /// Hand out partial signatures, one of which is wrong.
+    pub wrong_partial_signatures: bool,
+    /// Hand out the nonce shares handed out for the previous trade, in place of the trade's own.
+    pub replay_nonce_shares: bool,
+}
+
+impl FaultConfig {
+    pub const fn any(self) -> bool {
+        self.corrupt_nonce_shares || self.wrong_partial_signatures || self.replay_nonce_shares
+    }
+}
+
+pub struct OnionServiceConfig {
+    pub control_addr: SocketAddr,
+    /// The Tor daemon's auth cookie file, if it uses cookie authentication.
+    pub cookie_file: Option<PathBuf>,
+    /// Where the private key of the onion service is kept.
+    pub key_file: PathBuf,
+    /// The (virtual) port of the onion service.
+    pub port: u16,
+}
+
+#[derive(Clone)]
+pub enum SecretKeySource {
+    /// Stretch a passphrase read from the named environment variable at startup.
+    PassphraseEnv(String),
+    /// Run the given shell command at startup, which must print a 32-byte key in hex, for example
+    /// by fetching it from a key management service.
+    Command(String),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: ([127, 0, 0, 1], 50051).into(),
+            peer_listen_addr: None,
+            peer_public_address: None,
+            onion_service: None,
+            socks_proxy: None,
+            rate_limits: RateLimitConfig { init_trade_per_min: Some(30), rpc_per_min: Some(600) },
+            trade_quota: TradeQuotaConfig { max_open_trades: Some(1000), max_open_trades_per_client: Some(100) },
+            max_subscriptions_per_trade: Some(16),
+            trade_limits: TradeLimitConfig::default(),
+            rpc_timeouts: RpcTimeoutConfig::default(),
+            decode_limits: DecodeLimitConfig::default(),
+            log_sensitive: false,
+            metrics_listen_addr: None,
+            gateway_listen_addr: None,
+            admin_listen_addr: None,
+            admin_token_env: "ADMIN_TOKEN".to_owned(),
+            listeners: Vec::new(),
+            grpc_web: GrpcWebConfig::default(),
+            store: StoreConfig::Memory,
+            signer: SignerConfig::Local,
+            stale_trade_ttl: Some(Duration::from_hours(24)),
+            stale_trade_scan_interval: Duration::from_mins(1),
+            deadlines: DeadlineConfig::default(),
+            policy: PolicyConfig::default(),
+            chain: ChainConfig::default(),
+            webhook: WebhookConfig::default(),
+            snapshot_passphrase_env: "SNAPSHOT_PASSPHRASE".to_owned(),
+            backup: None,
+            backup_recipient_keys_env: "BACKUP_RECIPIENT_KEYS".to_owned(),
+            mediator_pub_key: None,
+            burningman: BurningmanConfig::default(),
+            faults: FaultConfig::default(),
+            nonce_reuse: NonceReuseConfig::default(),
+            source_file: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(mut args: impl Iterator<Item=String>) -> Result<(Self, Command)> {
+        let mut config = Self::default();
+        let mut command = Command::Serve;
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--config" => {
+                    let path = args.next().ok_or(ConfigError::MissingArgValue(arg))?;
+                    config = Self::from_file(path.as_ref())?;
+                }
+                "export-snapshot" | "import-snapshot" | "recover-key-shares" | "replay-transcript" | "export-test-vectors"
+                if matches!(command, Command::Serve) => {
+                    let path = args.next().ok_or_else(|| ConfigError::MissingArgValue(arg.clone()))?.into();
+                    command = match &arg[..] {
+                        "export-snapshot" => Command::ExportSnapshot(path),
+                        "import-snapshot" => Command::ImportSnapshot(path),
+                        "recover-key-shares" => {
+                            // The key shares go to a file only ever given explicitly, never to stdout:
+                            let output = args.next().filter(|a| a == "--output").and_then(|_| args.next())
+                                .ok_or_else(|| ConfigError::MissingOutputArg(arg.clone()))?;
+                            Command::RecoverKeyShares(path, output.into())
+                        }
+                        "replay-transcript" => Command::ReplayTranscript(path),
+                        _ => Command::ExportTestVectors(path),
+                    };
+                }
+                "simulate-trade" if matches!(command, Command::Serve) => command = Command::SimulateTrade,
+                _ => return Err(ConfigError::UnknownArg(arg)),
+            }
+        }
+        Ok((config, command))
+    }
+
+    /// Read the config from the given file, as with `--config`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut config = Self::parse(&fs::read_to_string(path)?)?;
+        config.source_file = Some(path.to_owned());
+        Ok(config)
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        let mut store_kind = "memory".to_owned();
+        let mut store_dir = PathBuf::from("trades");
+        let mut secret_key_source = None;
+        let mut signer_kind = "local".to_owned();
+        let mut remote_signer_url = "http://127.0.0.1:50052".to_owned();
+        let mut backup_dir = PathBuf::from("backups");
+        let mut backup_recipients = Vec::new();
+        let mut backup_threshold = None;
+        let mut tor_control_addr = None;
+        let mut tor_control_cookie_file = None;
+        let mut onion_key_file = PathBuf::from("onion_key");
+        let mut onion_port = 50053;
+        let mut listeners: Vec<(String, ListenerKeys)> = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(l, _)| l).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: &str| ConfigError::Parse { line: i + 1, msg: msg.to_owned() };
+            let (key, value) = line.split_once('=').ok_or_else(|| err("expected 'key = value'"))?;
+            let value = value.trim().trim_matches('"');
+            match key.trim() {
+                "listen_addr" => config.listen_addr = value.parse().map_err(|_| err("invalid socket address"))?,
+                "peer_listen_addr" => config.peer_listen_addr = Some(value.parse().map_err(|_| err("invalid socket address"))?),
+                "peer_public_address" => config.peer_public_address = Some(value.to_owned()),
+                "socks_proxy" => config.socks_proxy = Some(value.parse().map_err(|_| err("invalid socket address"))?),
+                "tor_control_addr" => tor_control_addr = Some(value.parse().map_err(|_| err("invalid socket address"))?),
+                "tor_control_cookie_file" => tor_control_cookie_file = Some(value.into()),
+                "onion_key_file" => onion_key_file = value.into(),
+                "onion_port" => onion_port = value.parse().map_err(|_| err("invalid port"))?,
+                "init_trade_rate_limit_per_min" | "rpc_rate_limit_per_min" | "max_open_trades" | "max_open_trades_per_client" =>
+                    parse_limits(&mut config.rate_limits, &mut config.trade_quota, key.trim(), value).map_err(err)?,
+                "max_subscriptions_per_trade" => config.max_subscriptions_per_trade = parse_limit(value)
+                    .map_err(|_| err("invalid number of subscriptions"))?,
+                "log_sensitive" => config.log_sensitive = value.parse().map_err(|_| err("expected 'true' or 'false'"))?,
+                "metrics_listen_addr" => config.metrics_listen_addr = Some(value.parse().map_err(|_| err("invalid socket address"))?),
+                "gateway_listen_addr" => config.gateway_listen_addr = Some(parse_loopback_addr(value).map_err(err)?),
+                "admin_listen_addr" => config.admin_listen_addr = Some(value.parse().map_err(|_| err("invalid socket address"))?),
+                "admin_token_env" => value.clone_into(&mut config.admin_token_env),
+                "store" => value.clone_into(&mut store_kind),
+                "store_dir" => store_dir = value.into(),
+                "store_passphrase_env" | "store_key_command" if secret_key_source.is_some() =>
+                    return Err(err("only one of 'store_passphrase_env' & 'store_key_command' may be set")),
+                "store_passphrase_env" => secret_key_source = Some(SecretKeySource::PassphraseEnv(value.to_owned())),
+                "store_key_command" => secret_key_source = Some(SecretKeySource::Command(value.to_owned())),
+                "signer" => value.clone_into(&mut signer_kind),
+                "remote_signer_url" => value.clone_into(&mut remote_signer_url),
+                "stale_trade_ttl_secs" => config.stale_trade_ttl = parse_limit(value)
+                    .map_err(|_| err("invalid number of seconds"))?.map(Duration::from_secs),
+                "snapshot_passphrase_env" => value.clone_into(&mut config.snapshot_passphrase_env),
+                "backup_dir" => backup_dir = value.into(),
+                "backup_recipients" => backup_recipients = value.split(',')
+                    .map(|hex| Point::from_hex(hex.trim()).map_err(|_| err("invalid recipient public key")))
+                    .collect::<Result<_>>()?,
+                "backup_threshold" => backup_threshold = Some(value.parse().ok().filter(|&t| t != 0)
+                    .ok_or_else(|| err("invalid (or zero) threshold"))?),
+                "backup_recipient_keys_env" => value.clone_into(&mut config.backup_recipient_keys_env),
+                "mediator_pub_key" => config.mediator_pub_key = Some(Point::from_hex(value).map_err(|_| err("invalid mediator public key"))?),
+                "peer_response_timeout_secs" | "payment_window_secs" | "warning_tx_claim_blocks" | "peer_unresponsive_after_secs"
+                | "deadline_scan_interval_secs" => parse_deadline(&mut config.deadlines, key.trim(), value).map_err(err)?,
+                key if key.starts_with("listener.") => parse_listener(&mut listeners, key, value).map_err(err)?,
+                key if key.starts_with("trade_limit_") => parse_trade_limits(&mut config.trade_limits, key, value).map_err(err)?,
+                key if key.starts_with("policy_") => parse_policy(&mut config.policy, key, value).map_err(err)?,
+                key if key.starts_with("chain_") => parse_chain(&mut config.chain, key, value).map_err(err)?,
+                key if key.starts_with("burningman_") => parse_burningman(&mut config.burningman, key, value).map_err(err)?,
+                key if key.starts_with("rpc_timeout") => parse_rpc_timeouts(&mut config.rpc_timeouts, key, value).map_err(err)?,
+                key if key.starts_with("grpc_web") => parse_grpc_web(&mut config.grpc_web, key, value).map_err(err)?,
+                key if key.starts_with("webhook_") => parse_webhook(&mut config.webhook, key, value).map_err(err)?,
+                key if key.starts_with("decode_") => parse_decode_limits(&mut config.decode_limits, key, value).map_err(err)?,
+                key if key.starts_with("nonce_reuse_") => parse_nonce_reuse(&mut config.nonce_reuse, key, value).map_err(err)?,
+                "inject_faults" => config.faults = parse_faults(value).ok_or_else(|| err("unknown fault"))?,
+                "stale_trade_scan_interval_secs" => config.stale_trade_scan_interval = parse_interval(value).map_err(err)?,
+                _ => return Err(err("unknown key")),
+            }
+        }
+        config.store = match &store_kind[..] {
+            "memory" => StoreConfig::Memory,
+            "file" => StoreConfig::File { dir: store_dir, secret_key_source },
+            _ => return Err(ConfigError::UnknownStore(store_kind)),
+        };
+        config.signer = match &signer_kind[..] {
+            "local" => SignerConfig::Local,
+            "remote" => SignerConfig::Remote { url: remote_signer_url },
+            _ => return Err(ConfigError::UnknownSigner(signer_kind)),
+        };
+        config.onion_service = tor_control_addr.map(|control_addr| OnionServiceConfig {
+            control_addr, cookie_file: tor_control_cookie_file, key_file: onion_key_file, port: onion_port,
+        });
+        config.listeners = listeners.into_iter().map(|(name, keys)| keys.into_listener(name)).collect::<Result<_>>()?;
+        config.check_onion_service()?;
+        if !backup_recipients.is_empty() {
+            // Default to a majority of the recipients:
+            let threshold = backup_threshold.unwrap_or(backup_recipients.len() / 2 + 1);
+            if threshold > backup_recipients.len() {
+                return Err(ConfigError::InvalidBackupThreshold(threshold, backup_recipients.len()));
+            }
+            config.backup = Some(BackupConfig { dir: backup_dir, recipients: backup_recipients, threshold });
+        }
+        Ok(config)
+    }
+
+    /// Check that the onion service (if any) has exactly one peer listener to forward to: the
+    /// listener published as an onion service, of which there may only be one, or else the
+    /// `peer_listen_addr`.
+    fn check_onion_service(&self) -> Result<()> {
+        let mut onion_listeners = self.listeners.iter().filter(|listener| listener.onion);
+        let onion_listener = onion_listeners.next();
+        if onion_listeners.next().is_some() {
+            return Err(ConfigError::MultipleOnionListeners);
+        }
+        match (&self.onion_service, onion_listener) {
+            (None, Some(listener)) =>
+                Err(ConfigError::InvalidListener(listener.name.clone(), "is published as an onion service, with no 'tor_control_addr'")),
+            (Some(_), _) if (self.peer_listen_addr.is_none() && onion_listener.is_none()) || self.peer_public_address.is_some() =>
+                Err(ConfigError::InvalidOnionService),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the peer service is served anywhere, for the daemons of our peers to deliver to.
+    pub fn serves_peer_service(&self) -> bool {
+        self.peer_listen_addr.is_some() || self.listeners.iter().any(|listener| listener.service == ListenerService::Peer)
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("empty unix socket path"),
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => s.parse().map(Self::Tcp).map_err(|_| "invalid socket address (or 'unix:<path>')"),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The keys given so far for a listener, by `listener.<name>.<key>` lines.
+#[derive(Default)]
+struct ListenerKeys {
+    addr: Option<ListenAddr>,
+    service: Option<ListenerService>,
+    tls_cert_file: Option<PathBuf>,
+    tls_key_file: Option<PathBuf>,
+    tls_client_ca_file: Option<PathBuf>,
+    token_env: Option<String>,
+    onion: bool,
+}
+
+impl ListenerKeys {
+    fn into_listener(self, name: String) -> Result<ListenerConfig> {
+        let service = self.service.unwrap_or(ListenerService::MuSig);
+        let invalid = |msg| Err(ConfigError::InvalidListener(name.clone(), msg));
+        let Some(addr) = self.addr else { return invalid("has no 'addr'") };
+        let tls = match (self.tls_cert_file, self.tls_key_file) {
+            (Some(cert_file), Some(key_file)) =>
+                Some(TlsConfig { cert_file, key_file, client_ca: self.tls_client_ca_file }),
+            (None, None) if self.tls_client_ca_file.is_some() => return invalid("needs a TLS identity for its client CA"),
+            (None, None) => None,
+            _ => return invalid("needs both 'tls_cert_file' & 'tls_key_file', or neither"),
+        };
+        if self.onion && (service != ListenerService::Peer || !matches!(addr, ListenAddr::Tcp(_))) {
+            return invalid("may only be published as an onion service if it serves the peer service over TCP");
+        }
+        Ok(ListenerConfig { name, addr, service, tls, token_env: self.token_env, onion: self.onion })
+    }
+}
+
+fn parse_listener(listeners: &mut Vec<(String, ListenerKeys)>, key: &str, value: &str)
+    -> std::result::Result<(), &'static str>
+{
+    let (name, key) = key["listener.".len()..].rsplit_once('.').filter(|(name, _)| !name.is_empty())
+        .ok_or("expected 'listener.<name>.<key>'")?;
+    let i = listeners.iter().position(|(n, _)| n == name).unwrap_or_else(|| {
+        listeners.push((name.to_owned(), ListenerKeys::default()));
+        listeners.len() - 1
+    });
+    let keys = &mut listeners[i].1;
+    match key {
+        "addr" => keys.addr = Some(value.parse()?),
+        "service" => keys.service = Some(match value {
+            "musig" => ListenerService::MuSig,
+            "peer" => ListenerService::Peer,
+            "admin" => ListenerService::Admin,
+            _ => return Err("expected 'musig', 'peer' or 'admin'"),
+        }),
+        "tls_cert_file" => keys.tls_cert_file = Some(value.into()),
+        "tls_key_file" => keys.tls_key_file = Some(value.into()),
+        "tls_client_ca_file" => keys.tls_client_ca_file = Some(value.into()),
+        "token_env" => keys.token_env = Some(value.to_owned()),
+        "onion" => keys.onion = value.parse().map_err(|_| "expected 'true' or 'false'")?,
+        _ => return Err("unknown listener key"),
+    }
+    Ok(())
+}
+
+/// Parse a limit, where 0 means no limit.
+fn parse_limit<T: FromStr + Default + PartialEq>(value: &str) -> std::result::Result<Option<T>, T::Err> {
+    let limit = value.parse()?;
+    Ok((limit != T::default()).then_some(limit))
+}
+
+/// Parse the address of a service which doesn't authenticate its clients (the JSON gateway), so may
+/// only be served at a loopback address.
+fn parse_loopback_addr(value: &str) -> std::result::Result<SocketAddr, &'static str> {
+    let addr: SocketAddr = value.parse().map_err(|_| "invalid socket address")?;
+    if !addr.ip().is_loopback() {
+        return Err("not a loopback address, as the JSON gateway doesn't authenticate its clients");
+    }
+    Ok(addr)
+}
+
+/// Parse the value of the given rate limit or trade quota setting into the config.
+fn parse_limits(rate_limits: &mut RateLimitConfig, trade_quota: &mut TradeQuotaConfig, key: &str, value: &str)
+    -> std::result::Result<(), &'static str>
+{
+    let calls = || parse_limit(value).map_err(|_| "invalid number of calls");
+    let trades = || parse_limit(value).map_err(|_| "invalid number of trades");
+    match key {
+        "init_trade_rate_limit_per_min" => rate_limits.init_trade_per_min = calls()?,
+        "rpc_rate_limit_per_min" => rate_limits.rpc_per_min = calls()?,
+        "max_open_trades" => trade_quota.max_open_trades = trades()?,
+        _ => trade_quota.max_open_trades_per_client = trades()?,
+    }
+    Ok(())
+}
+
+/// Parse the value of the given trade amount or security deposit bound into the config.
+fn parse_trade_limits(limits: &mut TradeLimitConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    let amount = || parse_limit(value).map_err(|_| "invalid number of sats");
+    let pct = || parse_limit::<u64>(value).ok().filter(|pct| pct.is_none_or(|pct| pct <= 100))
+        .ok_or("expected a whole percentage, up to 100");
+    match key {
+        "trade_limit_min_amount_sats" => limits.min_trade_amount = amount()?,
+        "trade_limit_max_amount_sats" => limits.max_trade_amount = amount()?,
+        "trade_limit_min_deposit_pct" => limits.min_security_deposit_pct = pct()?,
+        "trade_limit_max_deposit_pct" => limits.max_security_deposit_pct = pct()?,
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse the value of the given RPC timeout setting into the config: either the default timeout or a
+/// comma-separated list of `<RPC name>:<seconds>` timeouts, where 0 seconds means none.
+fn parse_rpc_timeouts(timeouts: &mut RpcTimeoutConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    let secs = |value: &str| parse_limit::<u64>(value).map(|secs| secs.map(Duration::from_secs))
+        .map_err(|_| "invalid number of seconds");
+    match key {
+        "rpc_timeout_secs" => timeouts.default = secs(value)?,
+        "rpc_timeouts" => timeouts.per_rpc = value.split(',').map(|entry| {
+            let (rpc, value) = entry.split_once(':').ok_or("expected '<RPC name>:<seconds>' timeouts")?;
+            Ok((rpc.trim().to_owned(), secs(value.trim())?))
+        }).collect::<std::result::Result<_, &str>>()?,
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse the (nonzero) number of seconds between runs of a periodic task.
+fn parse_interval(value: &str) -> std::result::Result<Duration, &'static str> {
+    value.parse().ok().filter(|&secs| secs != 0).map(Duration::from_secs).ok_or("invalid (or zero) number of seconds")
+}
+
+/// Parse the value of the given deadline setting into the config.
+fn parse_deadline(deadlines: &mut DeadlineConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    let secs = || parse_limit(value).map(|secs| secs.map(Duration::from_secs)).map_err(|_| "invalid number of seconds");
+    match key {
+        "peer_response_timeout_secs" => deadlines.peer_response_timeout = secs()?,
+        "payment_window_secs" => deadlines.payment_window = secs()?,
+        "peer_unresponsive_after_secs" => deadlines.peer_unresponsive_after = secs()?,
+        "warning_tx_claim_blocks" => deadlines.warning_tx_claim_blocks = parse_limit(value)
+            .map_err(|_| "invalid number of blocks")?,
+        _ => deadlines.scan_interval = parse_interval(value)?,
+    }
+    Ok(())
+}
+
+/// Parse the value of the given policy setting into the config.
+fn parse_policy(policy: &mut PolicyConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    let flag = || value.parse().map_err(|_| "expected 'true' or 'false'");
+    match key {
+        "policy_warning_tx_after_blocks" => policy.warning_tx_after_blocks = parse_limit(value)
+            .map_err(|_| "invalid number of blocks")?,
+        "policy_auto_claim" => policy.auto_claim = flag()?,
+        "policy_warning_tx_on_unresponsive_peer" => policy.warning_tx_on_unresponsive_peer = flag()?,
+        "policy_dry_run" => policy.dry_run = flag()?,
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse the value of the given request decoding limit into the config.
+fn parse_decode_limits(limits: &mut DecodeLimitConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    let bytes = || value.parse().ok().filter(|&n| n != 0).ok_or("invalid (or zero) number of bytes");
+    match key {
+        "decode_max_message_bytes" => limits.max_message_len = bytes()?,
+        "decode_max_psbt_bytes" => limits.max_psbt_len = bytes()?,
+        "decode_max_tx_bytes" => limits.max_tx_len = bytes()?,
+        "decode_max_field_bytes" => limits.max_field_len = bytes()?,
+        "decode_allocation_budget_bytes" => limits.allocation_budget = bytes()?,
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse the value of the given nonce reuse setting into the config.
+fn parse_nonce_reuse(nonce_reuse: &mut NonceReuseConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    match key {
+        "nonce_reuse_index_capacity" => nonce_reuse.index_capacity = value.parse().map_err(|_| "invalid number of nonces")?,
+        "nonce_reuse_refuse" => nonce_reuse.refuse = value.parse().map_err(|_| "expected 'true' or 'false'")?,
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse the value of the given chain backend setting into the config.
+fn parse_chain(chain: &mut ChainConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    match key {
+        "chain_backend_url" if value.starts_with("http://") => chain.backend_url = Some(value.to_owned()),
+        "chain_backend_url" => return Err("expected an 'http://' URL"),
+        "chain_poll_interval_secs" => chain.poll_interval = parse_interval(value)?,
+        "chain_max_blocks_behind" => chain.max_blocks_behind = value.parse().map_err(|_| "expected a block count")?,
+        "chain_mock" => chain.mock.enabled = value.parse().map_err(|_| "expected 'true' or 'false'")?,
+        "chain_mock_block_interval_secs" => chain.mock.block_interval = parse_limit(value)
+            .map_err(|_| "invalid number of seconds")?.map(Duration::from_secs),
+        "chain_mock_confirmation_blocks" => chain.mock.confirmation_blocks = value.parse()
+            .map_err(|_| "expected a block count")?,
+        _ => return Err("unknown key"),
+    }
+    if chain.mock.enabled && chain.backend_url.is_some() {
+        return Err("only one of 'chain_backend_url' & 'chain_mock' may be set");
+    }
+    Ok(())
+}
+
+/// Parse the value of the given burning-man receiver registry setting into the config.
+fn parse_burningman(burningman: &mut BurningmanConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    match key {
+        "burningman_snapshot_file" => burningman.snapshot_file = Some(value.into()),
+        "burningman_pub_key" => burningman.pub_key = Some(Point::from_hex(value).map_err(|_| "invalid public key")?),
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse the value of the given webhook setting into the config.
+fn parse_webhook(webhook: &mut WebhookConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    let list = || value.split(',').map(str::trim).filter(|item| !item.is_empty());
+    match key {
+        "webhook_urls" => webhook.urls = list().map(|url| url.starts_with("http://").then(|| url.to_owned()))
+            .collect::<Option<_>>().ok_or("expected 'http://' URLs")?,
+        "webhook_events" => webhook.events = list().map(|kind| EVENT_KINDS.into_iter().find(|&k| k == kind))
+            .collect::<Option<_>>().ok_or("unknown trade event")?,
+        "webhook_secret_env" => value.clone_into(&mut webhook.secret_env),
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse the value of the given gRPC-web setting into the config.
+fn parse_grpc_web(grpc_web: &mut GrpcWebConfig, key: &str, value: &str) -> std::result::Result<(), &'static str> {
+    match key {
+        "grpc_web" => grpc_web.enabled = value.parse().map_err(|_| "expected 'true' or 'false'")?,
+        "grpc_web_allowed_origins" => grpc_web.allowed_origins = value.split(',').map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| (origin == "*" || origin.starts_with("http://") || origin.starts_with("https://"))
+                .then(|| origin.trim_end_matches('/').to_owned()))
+            .collect::<Option<_>>().ok_or("expected '*' or 'http(s)://' origins")?,
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+/// Parse a comma-separated list of the names of the faults to inject, or `None` if any is unknown.
+fn parse_faults(value: &str) -> Option<FaultConfig> {
+    let mut faults = FaultConfig::default();
+    for fault in value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        match fault {
+            "corrupt_nonce_shares" => faults.corrupt_nonce_shares = true,
+            "wrong_partial_signatures" => faults.wrong_partial_signatures = true,
+            "replay_nonce_shares" => faults.replay_nonce_shares = true,
+            _ => return None,
+        }
+    }
+    Some(faults)
+}
+
+type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum ConfigError {
+    #[error("unknown command line argument: {0}")]
+    UnknownArg(String),
+    #[error("missing value for command line argument: {0}")]
+    MissingArgValue(String),
+    #[error("missing '--output <file>' after the file of command line argument: {0}")]
+    MissingOutputArg(String),
+    #[error("config parse error at line {line}: {msg}")]
+    Parse { line: usize, msg: String },
+    #[error("unknown store kind: {0}")]
+    UnknownStore(String),
+    #[error("unknown signer kind: {0}")]
+    UnknownSigner(String),
+    #[error("an onion service needs a 'peer_listen_addr' (or an onion listener) to forward to, and no 'peer_public_address'")]
+    InvalidOnionService,
+    #[error("listener '{0}' {1}")]
+    InvalidListener(String, &'static str),
+    #[error("only one listener may be published as the onion service")]
+    MultipleOnionListeners,
+    #[error("backup threshold of {0} exceeds the number of backup recipients, {1}")]
+    InvalidBackupThreshold(usize, usize),
+    Io(#[from] io::Error),
+}
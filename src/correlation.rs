@@ -0,0 +1,99 @@
+//! The correlation IDs of the calls to the `MuSig` service, by which the front-end's log lines about
+//! a call may be joined up with ours: taken from the `x-correlation-id` metadata of the call, or
+//! generated (as a version 7 UUID) if it has none, and echoed back in the response metadata. The ID
+//! is logged with the call, and kept in the audit log entry of any protocol step run for it and in
+//! any trade event it gives rise to.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::task::{Context, Poll};
+use tonic::codegen::http::{HeaderValue, Request, Response};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::trade_id;
+
+pub const CORRELATION_ID_KEY: &str = "x-correlation-id";
+/// The longest correlation ID taken from a client, any longer one being replaced with our own.
+const MAX_CORRELATION_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+thread_local! {
+    static BLOCKING_CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The correlation ID of the call being served by the current task, or by the blocking work being
+/// done on the current thread, if any.
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(Clone::clone).ok()
+        .or_else(|| BLOCKING_CORRELATION_ID.with_borrow(Clone::clone))
+}
+
+/// Run the given (blocking) closure as part of the call with the given correlation ID, if any.
+pub fn in_call<T>(correlation_id: Option<String>, f: impl FnOnce() -> T) -> T {
+    let old_id = BLOCKING_CORRELATION_ID.replace(correlation_id);
+    let result = f();
+    BLOCKING_CORRELATION_ID.set(old_id);
+    result
+}
+
+/// The given correlation ID from a client, if valid, or else a new one.
+pub fn given_or_new(correlation_id: Option<&[u8]>) -> String {
+    correlation_id.filter(|id| is_valid(id))
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .map_or_else(trade_id::new_trade_id, str::to_owned)
+}
+
+/// Whether the given correlation ID from a client is safe to log & echo back: 1 to
+/// [`MAX_CORRELATION_ID_LEN`] printable ASCII characters, with no spaces.
+fn is_valid(correlation_id: &[u8]) -> bool {
+    (1..=MAX_CORRELATION_ID_LEN).contains(&correlation_id.len()) && correlation_id.iter().all(u8::is_ascii_graphic)
+}
+
+/// A layer giving each call its correlation ID, set in the request metadata (for the inner layers
+/// & the service to log) and in the response metadata.
+#[derive(Clone, Copy)]
+pub struct CorrelationLayer;
+
+impl<S> Layer<S> for CorrelationLayer {
+    type Service = Correlation<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Correlation { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct Correlation<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for Correlation<S>
+    where S: Service<Request<B>, Response=Response<ResBody>>,
+          S::Future: Send + 'static
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let correlation_id = given_or_new(req.headers().get(CORRELATION_ID_KEY).map(HeaderValue::as_bytes));
+        let value = HeaderValue::from_str(&correlation_id).unwrap();
+        req.headers_mut().insert(CORRELATION_ID_KEY, value.clone());
+        let response = self.inner.call(req);
+        Box::pin(CORRELATION_ID.scope(correlation_id, async move {
+            let mut response = response.await?;
+            response.headers_mut().insert(CORRELATION_ID_KEY, value);
+            Ok(response)
+        }))
+    }
+}
@@ -0,0 +1,170 @@
+use musig_trade_protocol::{lock_trade_model, Deadline, DeadlineDue, DeadlineKind, DeadlineState, TradeModel, TradeModelStore,
+    TradePhase};
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::chain::ChainTip;
+use crate::config::DeadlineConfig;
+use crate::events::{TradeEvent, TradeEventBus};
+use crate::policy::PolicyEngine;
+
+/// The expected time between blocks, for a wall clock estimate of a deadline counted in blocks.
+pub const BLOCK_INTERVAL: Duration = Duration::from_mins(10);
+
+/// How far through its window a deadline is announced as approaching, in percent.
+const WARN_AFTER_PERCENT: u32 = 80;
+
+/// Periodically bring the protocol deadlines of every open trade up to date with its phase, and
+/// publish a [`TradeEvent::DeadlineApproaching`] or [`TradeEvent::DeadlinePassed`] as each deadline
+/// reaches either point, and a [`TradeEvent::PeerUnresponsive`] as the peer goes unseen for too
+/// long, then take any automatic protocol responses now due under the given policy engine. This
+/// never returns.
+///
+/// The deadlines are saved with their trade models, along with how far each has got, so that they
+/// keep their due times across restarts of the daemon, and are not announced twice.
+pub async fn schedule_deadlines<S>(store: Arc<S>, events: TradeEventBus, chain_tip: ChainTip, config: DeadlineConfig,
+                                   policy: Arc<PolicyEngine>)
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let mut interval = time::interval(config.scan_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // As for the stale trade scan, keep this off the async worker threads:
+        let (store, events, policy, height) = (Arc::clone(&store), events.clone(), Arc::clone(&policy), chain_tip.height());
+        if let Err(e) = tokio::task::spawn_blocking(move ||
+            update_deadlines(&*store, &events, &config, &policy, SystemTime::now(), height)).await
+        {
+            eprintln!("Deadline scan failed: {}", e);
+        }
+    }
+}
+
+/// Bring the deadlines of every open trade up to date as of the given time & block height (if
+/// known), and take the automatic responses due, saving each trade model with changed deadlines
+/// (or responses taken, or its peer newly unresponsive) before publishing its events.
+pub fn update_deadlines(store: &impl TradeModelStore, events: &TradeEventBus, config: &DeadlineConfig,
+                        policy: &PolicyEngine, now: SystemTime, height: Option<u32>) {
+    for summary in store.list_trade_models() {
+        let Some(trade_model) = store.get_trade_model(&summary.trade_id) else { continue };
+        let mut trade_model = lock_trade_model(&trade_model);
+        let (old_deadlines, old_actions) = (trade_model.deadlines.clone(), trade_model.policy_actions.clone());
+        let was_unresponsive = trade_model.peer_unresponsive;
+        let mut trade_events = advance_deadlines(&mut trade_model, config, now, height);
+        trade_events.extend(check_peer_responsiveness(&mut trade_model, config, now));
+        trade_events.extend(policy.apply(&mut trade_model, config.warning_tx_claim_blocks, now, height));
+        if trade_events.is_empty() && trade_model.deadlines == old_deadlines {
+            continue;
+        }
+        if let Err(e) = store.save_trade_model(&trade_model) {
+            eprintln!("Could not save deadlines of trade with id {}: {}", summary.trade_id, e);
+            // Leave the deadlines to be moved on (and announced) by the next scan:
+            trade_model.deadlines = old_deadlines;
+            trade_model.policy_actions = old_actions;
+            trade_model.peer_unresponsive = was_unresponsive;
+            continue;
+        }
+        drop(trade_model);
+        for event in trade_events {
+            events.publish(event);
+        }
+    }
+}
+
+/// Replace the deadlines set for an earlier phase of the trade with those of its current phase,
+/// then move on each deadline which has reached its warning (or due) point, returning the events
+/// announcing them.
+fn advance_deadlines(trade_model: &mut TradeModel, config: &DeadlineConfig, now: SystemTime, height: Option<u32>)
+    -> Vec<TradeEvent>
+{
+    let phase = trade_model.phase();
+    let kinds = deadline_kinds(phase, config);
+    trade_model.deadlines.retain(|deadline| deadline.phase == phase && kinds.contains(&deadline.kind));
+    for kind in kinds {
+        if !trade_model.deadlines.iter().any(|deadline| deadline.kind == kind) {
+            trade_model.deadlines.extend(new_deadline(kind, phase, config, now, height));
+        }
+    }
+
+    let trade_id = trade_model.trade_id().to_owned();
+    let mut events = Vec::new();
+    for deadline in &mut trade_model.deadlines {
+        let state = if deadline.due.is_reached(now, height) {
+            DeadlineState::Passed
+        } else if deadline.warn_at.is_reached(now, height) {
+            DeadlineState::Approaching
+        } else {
+            DeadlineState::Pending
+        };
+        if state > deadline.state {
+            deadline.state = state;
+            let (trade_id, deadline) = (trade_id.clone(), *deadline);
+            events.push(if state == DeadlineState::Passed {
+                TradeEvent::DeadlinePassed { trade_id, deadline }
+            } else {
+                TradeEvent::DeadlineApproaching { trade_id, deadline }
+            });
+        }
+    }
+    events
+}
+
+/// Mark the peer of the trade as unresponsive, once it has gone unseen for the configured window
+/// since it was last seen (or the trade was opened), returning the event announcing it, if newly so.
+fn check_peer_responsiveness(trade_model: &mut TradeModel, config: &DeadlineConfig, now: SystemTime)
+    -> Option<TradeEvent>
+{
+    let window = config.peer_unresponsive_after?;
+    if trade_model.peer_unresponsive || trade_model.phase() >= TradePhase::SwapTxSigned {
+        return None;
+    }
+    let since = trade_model.peer_last_seen.or_else(|| trade_model.created_at())?;
+    if since.checked_add(window).is_none_or(|due| now < due) {
+        return None;
+    }
+    trade_model.peer_unresponsive = true;
+    Some(TradeEvent::PeerUnresponsive { trade_id: trade_model.trade_id().to_owned(), last_seen: trade_model.peer_last_seen })
+}
+
+/// The kinds of deadline (as configured) which apply to a trade in the given phase.
+fn deadline_kinds(phase: TradePhase, config: &DeadlineConfig) -> Vec<DeadlineKind> {
+    let mut kinds = Vec::new();
+    if (TradePhase::KeySharesGenerated..=TradePhase::DepositTxSigned).contains(&phase) {
+        kinds.extend(config.peer_response_timeout.map(|_| DeadlineKind::PeerResponse));
+    }
+    if phase == TradePhase::DepositTxPublished {
+        kinds.extend(config.payment_window.map(|_| DeadlineKind::Payment));
+        kinds.extend(config.warning_tx_claim_blocks.map(|_| DeadlineKind::WarningTxClaim));
+    }
+    kinds
+}
+
+/// A fresh deadline of the given kind, counted from the given time & block height. A deadline
+/// counted in blocks is also estimated by wall clock, so that it still falls due should the chain
+/// tip go unseen (as when the chain backend is unreachable).
+fn new_deadline(kind: DeadlineKind, phase: TradePhase, config: &DeadlineConfig, now: SystemTime, height: Option<u32>)
+    -> Option<Deadline>
+{
+    let (window, blocks) = match kind {
+        DeadlineKind::PeerResponse => (config.peer_response_timeout?, None),
+        DeadlineKind::Payment => (config.payment_window?, None),
+        DeadlineKind::WarningTxClaim => {
+            let blocks = config.warning_tx_claim_blocks?;
+            (BLOCK_INTERVAL.checked_mul(blocks)?, Some(blocks))
+        }
+    };
+    let after = |window: Duration, blocks: Option<u32>| DeadlineDue {
+        at: now.checked_add(window),
+        height: height.zip(blocks).map(|(height, blocks)| height.saturating_add(blocks)),
+    };
+    Some(Deadline {
+        kind,
+        phase,
+        warn_at: after(window.checked_mul(WARN_AFTER_PERCENT)? / 100,
+            blocks.map(|blocks| blocks.saturating_mul(WARN_AFTER_PERCENT) / 100)),
+        due: after(window, blocks),
+        state: DeadlineState::Pending,
+    })
+}
@@ -0,0 +1,177 @@
+//! Strict limits on the requests to the daemon's services, checked on the wire (by the descriptors
+//! of their messages) before tonic has prost decode them: a cap on the length of each message, and
+//! of each bytes & string field in it, by what it holds (PSBTs, txs, or key material & the like),
+//! and a budget for the memory it may take once decoded, which a crafted message (of a great many
+//! empty repeated submessages, say) could otherwise blow up to many times its own length. Every
+//! message of a streaming request is checked as it arrives. A request breaking a limit fails with
+//! `RESOURCE_EXHAUSTED`, or with `INVALID_ARGUMENT`, should it not even be well-formed.
+
+use http_body::{Body, Frame, SizeHint};
+use musig_proto::FILE_DESCRIPTOR_SET;
+use std::mem;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::codegen::Bytes;
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::config::DecodeLimitConfig;
+use crate::transcode::{Descriptors, TranscodeError, WireLimits};
+
+/// The length of the prefix of each gRPC message: a compression flag, then the message length.
+const GRPC_PREFIX_LEN: usize = 5;
+
+/// A layer holding the requests to the services it wraps to the given limits.
+#[derive(Clone)]
+pub struct DecodeLimitLayer {
+    limits: DecodeLimitConfig,
+    descriptors: Arc<Descriptors>,
+}
+
+impl DecodeLimitLayer {
+    /// # Errors
+    ///
+    /// Fails if the compiled-in descriptors of the messages do not decode.
+    pub fn new(limits: DecodeLimitConfig) -> Result<Self, prost::DecodeError> {
+        Ok(Self { limits, descriptors: Arc::new(Descriptors::load(FILE_DESCRIPTOR_SET)?) })
+    }
+}
+
+impl<S> Layer<S> for DecodeLimitLayer {
+    type Service = DecodeLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DecodeLimit { inner, layer: self.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct DecodeLimit<S> {
+    inner: S,
+    layer: DecodeLimitLayer,
+}
+
+impl<S> Service<Request<BoxBody>> for DecodeLimit<S>
+    where S: Service<Request<BoxBody>, Response=Response<BoxBody>>
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        // The request message type of the RPC, by its path of '/{package}.{service}/{method}':
+        let input_type = req.uri().path().strip_prefix('/')
+            .and_then(|path| path.split_once('/'))
+            .and_then(|(service, method)| self.layer.descriptors.method(service, method))
+            .map(|method| method.input_type().to_owned());
+        let Some(input_type) = input_type else { return self.inner.call(req) };
+        let layer = self.layer.clone();
+        self.inner.call(req.map(|body| tonic::body::boxed(Limited { body, layer, input_type, buf: Vec::new(), held: None })))
+    }
+}
+
+/// A request body passed on a whole message at a time, once each is checked against the limits.
+struct Limited {
+    body: BoxBody,
+    layer: DecodeLimitLayer,
+    input_type: String,
+    /// The data read but not yet passed on, short of a whole message.
+    buf: Vec<u8>,
+    /// A frame (of trailers) read after a partial message, to pass on after it.
+    held: Option<Frame<Bytes>>,
+}
+
+impl Limited {
+    /// The next whole message buffered (with its prefix), if any, once checked.
+    fn next_message(&mut self) -> Result<Option<Vec<u8>>, Status> {
+        let Some(prefix) = self.buf.first_chunk::<GRPC_PREFIX_LEN>() else { return Ok(None) };
+        let (compressed, len) = (prefix[0] != 0, u32::from_be_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize);
+        let limits = &self.layer.limits;
+        if len > limits.max_message_len {
+            return Err(Status::resource_exhausted(format!("request message is {} bytes long, over the limit of {} bytes",
+                len, limits.max_message_len)));
+        }
+        if self.buf.len() < GRPC_PREFIX_LEN + len {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(GRPC_PREFIX_LEN + len);
+        let message = mem::replace(&mut self.buf, rest);
+        // Compressed messages are left for tonic to turn away, as the daemon accepts none:
+        if !compressed {
+            let max_field_len = |message: &str, field: &str| max_field_len(limits, message, field);
+            let wire_limits = WireLimits { max_field_len: &max_field_len, allocation_budget: limits.allocation_budget };
+            self.layer.descriptors.check_limits(&self.input_type, &message[GRPC_PREFIX_LEN..], &wire_limits)
+                .map_err(|e| match e {
+                    TranscodeError::FieldTooLong { .. } | TranscodeError::OverAllocationBudget(_) =>
+                        Status::resource_exhausted(format!("request message too large: {}", e)),
+                    e => Status::invalid_argument(format!("request message malformed: {}", e)),
+                })?;
+        }
+        Ok(Some(message))
+    }
+}
+
+impl Body for Limited {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            match self.next_message() {
+                Ok(Some(message)) => return Poll::Ready(Some(Ok(Frame::data(message.into())))),
+                Ok(None) => {}
+                Err(status) => return Poll::Ready(Some(Err(status))),
+            }
+            if let Some(frame) = self.held.take() {
+                return Poll::Ready(Some(Ok(frame)));
+            }
+            let frame = match ready!(Pin::new(&mut self.body).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(status)) => return Poll::Ready(Some(Err(status))),
+                // Pass on any partial message left, for tonic to find it truncated:
+                None if !self.buf.is_empty() => return Poll::Ready(Some(Ok(Frame::data(mem::take(&mut self.buf).into())))),
+                None => return Poll::Ready(None),
+            };
+            match frame.into_data() {
+                Ok(data) => self.buf.extend_from_slice(&data),
+                Err(frame) if self.buf.is_empty() => return Poll::Ready(Some(Ok(frame))),
+                Err(frame) => {
+                    self.held = Some(frame);
+                    return Poll::Ready(Some(Ok(Frame::data(mem::take(&mut self.buf).into()))));
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buf.is_empty() && self.held.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = self.body.size_hint();
+        hint.set_lower(hint.lower().saturating_add(self.buf.len() as u64));
+        hint.set_upper(hint.upper().unwrap_or(u64::MAX).saturating_add(self.buf.len() as u64));
+        hint
+    }
+}
+
+/// The cap on the length of the given bytes or string field of the given message, by what it holds.
+fn max_field_len(limits: &DecodeLimitConfig, message: &str, field: &str) -> usize {
+    let field = field.to_ascii_lowercase();
+    if field.ends_with("psbt") || field.starts_with("sealed") || message.ends_with("PsbtChunk") {
+        limits.max_psbt_len
+    } else if field.ends_with("tx") {
+        limits.max_tx_len
+    } else {
+        limits.max_field_len
+    }
+}
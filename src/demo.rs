@@ -0,0 +1,111 @@
+//! The hello-world demo services, only included with the `demo` feature.
+
+use futures::stream;
+use musig_proto::convert::{decode_duration, to_millis};
+use musig_proto::helloworld::{ClockRequest, HelloReply, HelloRequest, ServerTimeRequest, ServerTimeResponse, TickEvent};
+use rand::Rng as _;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time::{self, Duration, Instant};
+use tonic::{Request, Response, Status};
+
+use crate::subscriptions::{Subscription, SubscriptionRegistry};
+
+pub use musig_proto::helloworld::greeter_server::{Greeter, GreeterServer};
+
+/// The most clock subscriptions any one client may have open at once.
+const MAX_CLOCKS_PER_CLIENT: usize = 4;
+
+#[derive(Debug)]
+pub struct MyGreeter {
+    started_at: Instant,
+    clocks: Arc<SubscriptionRegistry>,
+}
+
+impl Default for MyGreeter {
+    fn default() -> Self {
+        Self { started_at: Instant::now(), clocks: Arc::new(SubscriptionRegistry::new(Some(MAX_CLOCKS_PER_CLIENT))) }
+    }
+}
+
+#[tonic::async_trait]
+impl Greeter for MyGreeter {
+    async fn say_hello(&self, request: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let reply = HelloReply {
+            message: format!("Hello, {}!", request.into_inner().name)
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    type SubscribeClockStream = Pin<Box<dyn stream::Stream<Item=Result<TickEvent, Status>> + Send>>;
+
+    async fn subscribe_clock(&self, request: Request<ClockRequest>) -> Result<Response<Self::SubscribeClockStream>, Status> {
+        println!("Got a request: {:?}", request);
+
+        let client = request.remote_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+        let subscription = self.clocks.subscribe(&client)?;
+        let request = request.into_inner();
+        // The old field is still honoured, for older clients:
+        let period = decode_duration(request.tick_period, "tick_period")?
+            .unwrap_or_else(|| Duration::from_millis(u64::from(request.tick_period_millis)));
+        let clock = Clock {
+            period,
+            jitter: decode_duration(request.jitter, "jitter")?.unwrap_or_default(),
+            ticks_left: request.max_ticks,
+            next_tick_due: Instant::now(),
+            ticks_sent: 0,
+            _subscription: subscription,
+        };
+
+        Ok(Response::new(Box::pin(stream::unfold(clock, Clock::next))))
+    }
+
+    async fn get_server_time(&self, request: Request<ServerTimeRequest>) -> Result<Response<ServerTimeResponse>, Status> {
+        println!("Got a request: {:?}", request);
+
+        Ok(Response::new(ServerTimeResponse {
+            current_time: Some(SystemTime::now().into()),
+            uptime: self.started_at.elapsed().try_into().ok(),
+        }))
+    }
+}
+
+/// The state of a clock subscription: the ticks are due a period apart from the start, each sent
+/// up to the jitter later, until the stream ends or the client cancels the call (dropping it, and
+/// with it the place of the subscription among those of the client).
+struct Clock {
+    period: Duration,
+    jitter: Duration,
+    ticks_left: Option<u32>,
+    next_tick_due: Instant,
+    ticks_sent: u64,
+    _subscription: Subscription,
+}
+
+impl Clock {
+    async fn next(mut self) -> Option<(Result<TickEvent, Status>, Self)> {
+        if self.ticks_left == Some(0) {
+            return None;
+        }
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+        time::sleep_until(self.next_tick_due + jitter).await;
+        self.next_tick_due += self.period;
+        self.ticks_left = self.ticks_left.map(|n| n - 1);
+        self.ticks_sent += 1;
+        let now = SystemTime::now();
+        let tick = TickEvent { current_time_millis: to_millis(now), current_time: Some(now.into()) };
+        Some((Ok(tick), self))
+    }
+}
+
+impl Drop for Clock {
+    fn drop(&mut self) {
+        let reason = if self.ticks_left == Some(0) { "after its last tick" } else { "by the client" };
+        println!("Clock subscription ended {} ({} ticks sent)", reason, self.ticks_sent);
+    }
+}
@@ -0,0 +1,86 @@
+//! The protocol steps of each role, in order, with the RPC of the `MuSig` service running each, so
+//! that a front-end may show the progress of a trade without hard-coding the sequence of steps.
+
+use musig_trade_protocol::{AuditEntry, Role, TradePhase};
+use std::prelude::rust_2021::*;
+
+/// A protocol step, run by a call to the named RPC.
+pub struct Step {
+    pub rpc: &'static str,
+    /// The phase the step moves the trade on to, if it moves it on at all.
+    pub phase: Option<TradePhase>,
+    /// Whether the step may be left out, as those for a wallet kept off the daemon (or for a trade
+    /// exchanging its peer payloads directly with the peer's daemon) may be.
+    pub optional: bool,
+}
+
+const fn required(rpc: &'static str, phase: TradePhase) -> Step {
+    Step { rpc, phase: Some(phase), optional: false }
+}
+
+/// A step which doesn't move the trade on, but must still be done, as it gates those after it.
+const fn gate(rpc: &'static str) -> Step {
+    Step { rpc, phase: None, optional: false }
+}
+
+const fn optional(rpc: &'static str) -> Step {
+    Step { rpc, phase: None, optional: true }
+}
+
+const SELLER_STEPS: &[Step] = &[
+    required("InitTrade", TradePhase::KeySharesGenerated),
+    required("GetNonceShares", TradePhase::NonceSharesGenerated),
+    required("GetPartialSignatures", TradePhase::PartialSignaturesGenerated),
+    required("SignDepositTx", TradePhase::DepositTxSigned),
+    optional("GetUnsignedDepositPsbt"),
+    optional("SubmitSignedDepositPsbt"),
+    // The seller may leave the publication of the deposit tx to the buyer, its only publisher so far:
+    Step { rpc: "PublishDepositTx", phase: Some(TradePhase::DepositTxPublished), optional: true },
+    gate("ConfirmPaymentReceived"),
+    required("SignSwapTx", TradePhase::SwapTxSigned),
+    required("CloseTrade", TradePhase::Closed),
+    optional("ArchiveTrade"),
+];
+
+const BUYER_STEPS: &[Step] = &[
+    required("InitTrade", TradePhase::KeySharesGenerated),
+    required("GetNonceShares", TradePhase::NonceSharesGenerated),
+    required("GetPartialSignatures", TradePhase::PartialSignaturesGenerated),
+    required("SignDepositTx", TradePhase::DepositTxSigned),
+    optional("GetUnsignedDepositPsbt"),
+    optional("SubmitSignedDepositPsbt"),
+    required("PublishDepositTx", TradePhase::DepositTxPublished),
+    gate("ConfirmPaymentStarted"),
+    optional("ReleaseSwapTxSignature"),
+    required("CloseTrade", TradePhase::Closed),
+    optional("ArchiveTrade"),
+];
+
+/// The steps of the given role, in the order they are run.
+pub const fn steps(role: Role) -> &'static [Step] {
+    match role {
+        Role::SellerAsMaker | Role::SellerAsTaker => SELLER_STEPS,
+        Role::BuyerAsMaker | Role::BuyerAsTaker => BUYER_STEPS,
+    }
+}
+
+/// The step of the given role expected to be done before a call to the given RPC, for a trade at the
+/// given phase with the given audit log: the first step ahead of the RPC's own which must be done,
+/// but hasn't been. `None` if the call is in sequence (or a retry of a step already done), or isn't
+/// to a protocol step of the role at all.
+pub fn expected_before(role: Role, rpc: &str, phase: TradePhase, audit_log: &[AuditEntry]) -> Option<&'static Step> {
+    let steps = steps(role);
+    let i = steps.iter().position(|step| step.rpc == rpc)?;
+    steps[..i].iter().find(|step| !step.optional && !step.is_done(phase, audit_log))
+}
+
+impl Step {
+    /// Whether the step has been done for a trade at the given phase, with the given audit log. A
+    /// step which moves the trade on is done once the trade reaches its phase (even if the audit
+    /// log has no record of it, as for a trade started before audit logging); any other step is
+    /// done once the audit log records it succeeding.
+    pub fn is_done(&self, phase: TradePhase, audit_log: &[AuditEntry]) -> bool {
+        self.phase.is_some_and(|p| phase >= p)
+            || audit_log.iter().any(|entry| entry.step == self.rpc && entry.error.is_none())
+    }
+}
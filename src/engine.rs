@@ -0,0 +1,137 @@
+use musig_trade_protocol::{lock_trade_model, TradeModel, TradeModelStore};
+use std::collections::HashMap;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration};
+use tonic::Status;
+
+use crate::client_identity::{self, ClientIdentity};
+use crate::{correlation, timeout};
+
+const COMMAND_QUEUE_LEN: usize = 16;
+const ACTOR_IDLE_TIMEOUT: Duration = Duration::from_mins(1);
+
+pub type Reply<T> = oneshot::Sender<Result<T, Status>>;
+
+/// A typed command for a trade actor to run on its trade model, holding the sender with which to
+/// reply to the caller.
+pub trait TradeCommand<S>: Send + 'static {
+    /// Run the command on the trade model and send back the reply. This may block.
+    fn execute(self, store: &S, trade_model: &mut TradeModel);
+
+    /// Reply with the given error instead, without running the command.
+    fn reject(self, status: Status);
+}
+
+/// Runs the protocol steps of each live trade in its own actor task, which owns (the lock on) the
+/// trade model. Commands for a trade are queued and run strictly in order, one at a time, on the
+/// blocking thread pool, so that a slow step cannot stall the async worker threads.
+///
+/// Actors are spawned on demand and stop once idle for a while, or once their trade is archived.
+pub struct TradeEngine<S, C> {
+    store: Arc<S>,
+    actors: Mutex<HashMap<String, mpsc::Sender<Queued<C>>>>,
+}
+
+/// A command queued for a trade actor, with the deadline of the call it was sent for, if any, past
+/// which it is rejected rather than run, and the correlation ID of that call.
+struct Queued<C> {
+    command: C,
+    deadline: Option<Instant>,
+    correlation_id: Option<String>,
+    client_identity: Option<ClientIdentity>,
+}
+
+impl<S, C> TradeEngine<S, C>
+    where S: TradeModelStore + Send + Sync + 'static, C: TradeCommand<S>
+{
+    pub fn new(store: Arc<S>) -> Self {
+        Self { store, actors: Mutex::default() }
+    }
+
+    /// Send a command to the actor of the given trade, spawning one if necessary, then wait for and
+    /// return its reply. The command is rejected with `DEADLINE_EXCEEDED` if the given call deadline
+    /// passes before the actor gets to it.
+    pub async fn call<T>(&self, trade_id: &str, deadline: Option<Instant>, command: impl FnOnce(Reply<T>) -> C)
+        -> Result<T, Status>
+    {
+        let (reply, response) = oneshot::channel();
+        let mut queued = Queued {
+            command: command(reply), deadline,
+            correlation_id: correlation::correlation_id(),
+            client_identity: client_identity::client_identity(),
+        };
+        // An actor which was found to be running may go idle and stop before accepting the command,
+        // so try once more with a fresh actor if that happens:
+        for _ in 0..2 {
+            match self.actor(trade_id)?.send(queued).await {
+                Ok(()) => return response.await
+                    .map_err(|_| Status::internal(format!("trade actor for id {} failed", trade_id)))?,
+                Err(mpsc::error::SendError(returned)) => queued = returned,
+            }
+        }
+        Err(Status::unavailable(format!("trade actor for id {} keeps stopping", trade_id)))
+    }
+
+    fn actor(&self, trade_id: &str) -> Result<mpsc::Sender<Queued<C>>, Status> {
+        let mut actors = self.actors.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(actor) = actors.get(trade_id).filter(|actor| !actor.is_closed()) {
+            return Ok(actor.clone());
+        }
+        let trade_model = self.store.get_trade_model(trade_id)
+            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+        let (actor, commands) = mpsc::channel(COMMAND_QUEUE_LEN);
+        tokio::spawn(run_actor(Arc::clone(&self.store), trade_id.to_owned(), trade_model, commands));
+        actors.retain(|_, actor| !actor.is_closed());
+        actors.insert(trade_id.to_owned(), actor.clone());
+        drop(actors);
+        Ok(actor)
+    }
+}
+
+async fn run_actor<S, C>(store: Arc<S>, trade_id: String, trade_model: Arc<Mutex<TradeModel>>,
+                         mut commands: mpsc::Receiver<Queued<C>>)
+    where S: TradeModelStore + Send + Sync + 'static, C: TradeCommand<S>
+{
+    loop {
+        let Queued { command, deadline, correlation_id, client_identity } = match time::timeout(ACTOR_IDLE_TIMEOUT, commands.recv()).await {
+            Ok(Some(queued)) => queued,
+            Ok(None) => return,
+            Err(_) => {
+                // Stop accepting commands, but still run any that were queued in the meantime:
+                commands.close();
+                continue;
+            }
+        };
+        if !store.get_trade_model(&trade_id).is_some_and(|m| Arc::ptr_eq(&m, &trade_model)) {
+            // The trade has been archived (and possibly replaced), so this actor is defunct:
+            command.reject(Status::not_found(format!("missing trade with id: {}", trade_id)));
+            commands.close();
+            continue;
+        }
+        // The client may have given up on the call while the command was queued behind others:
+        if let Err(status) = timeout::check_deadline(deadline, "the protocol step") {
+            command.reject(status);
+            continue;
+        }
+        let (store, trade_model) = (Arc::clone(&store), Arc::clone(&trade_model));
+        let result = tokio::task::spawn_blocking(move || {
+            let mut trade_model = lock_trade_model(&trade_model);
+            if let Some(failure) = trade_model.failure() {
+                let msg = format!("trade with id {} has failed: {}", trade_model.trade_id(), failure);
+                drop(trade_model);
+                command.reject(Status::failed_precondition(msg));
+                return;
+            }
+            correlation::in_call(correlation_id, || client_identity::in_call(client_identity, ||
+                command.execute(&*store, &mut trade_model)));
+        }).await;
+        if let Err(e) = result {
+            // The reply is dropped along with the command, so the caller will get an error. Should
+            // the command have panicked, the trade is failed once its poisoned lock is next taken.
+            eprintln!("Trade actor command for id {} failed: {}", trade_id, e);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use musig_trade_protocol::{Deadline, PolicyAction, TradeSummary};
+use std::prelude::rust_2021::*;
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+use tonic::Code;
+
+const CAPACITY: usize = 64;
+
+/// The names of each kind of [`TradeEvent`], as given by [`TradeEvent::kind`].
+pub const EVENT_KINDS: [&str; 8] = ["aborted", "deadline_approaching", "deadline_passed", "peer_unresponsive",
+    "peer_nonce_reused", "policy_action", "step_failed", "closed"];
+
+/// A notable change in the life of a trade, not directly caused by an RPC from the client (though
+/// the failure or closing of a trade is, and carries the correlation ID of that call).
+#[derive(Clone, Debug)]
+pub enum TradeEvent {
+    /// The trade was abandoned in an early phase, so it was aborted and archived, and its secrets
+    /// wiped, by the stale trade collector, the admin, or a cancellation before its deposit tx was
+    /// published.
+    Aborted(TradeSummary),
+    /// A protocol deadline of the trade is approaching, with the trade still in the phase it was
+    /// in when the deadline was set.
+    DeadlineApproaching { trade_id: String, deadline: Deadline },
+    /// A protocol deadline of the trade has passed, with the trade still in the phase it was in
+    /// when the deadline was set.
+    DeadlinePassed { trade_id: String, deadline: Deadline },
+    /// The peer has not been seen within the configured response window, since it was last seen (if
+    /// ever), so may have gone offline. This is announced once, until the peer is seen again.
+    PeerUnresponsive { trade_id: String, last_seen: Option<SystemTime> },
+    /// The peer has sent a public nonce which it already sent in the trade with the given id, which
+    /// endangers its key shares, so it is not to be trusted with the trade.
+    PeerNonceReused { trade_id: String, reused_from: String },
+    /// An automatic protocol response was taken for the trade by the policy engine (or would have
+    /// been, in dry-run mode), for the front-end to carry out.
+    PolicyAction { trade_id: String, action: PolicyAction, dry_run: bool },
+    /// A protocol step of the trade failed, leaving the trade as it was.
+    StepFailed { trade_id: String, step: &'static str, code: Code, message: String, correlation_id: Option<String> },
+    /// We closed the trade, with the peer's private key share in hand.
+    Closed { trade_id: String, correlation_id: Option<String> },
+}
+
+impl TradeEvent {
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Aborted(_) => "aborted",
+            Self::DeadlineApproaching { .. } => "deadline_approaching",
+            Self::DeadlinePassed { .. } => "deadline_passed",
+            Self::PeerUnresponsive { .. } => "peer_unresponsive",
+            Self::PeerNonceReused { .. } => "peer_nonce_reused",
+            Self::PolicyAction { .. } => "policy_action",
+            Self::StepFailed { .. } => "step_failed",
+            Self::Closed { .. } => "closed",
+        }
+    }
+
+    pub fn trade_id(&self) -> &str {
+        match self {
+            Self::Aborted(summary) => &summary.trade_id,
+            Self::DeadlineApproaching { trade_id, .. } | Self::DeadlinePassed { trade_id, .. }
+            | Self::PeerUnresponsive { trade_id, .. } | Self::PeerNonceReused { trade_id, .. } | Self::PolicyAction { trade_id, .. }
+            | Self::StepFailed { trade_id, .. } | Self::Closed { trade_id, .. } => trade_id,
+        }
+    }
+
+    /// The correlation ID of the call which gave rise to the event, if any.
+    pub fn correlation_id(&self) -> Option<&str> {
+        match self {
+            Self::StepFailed { correlation_id, .. } | Self::Closed { correlation_id, .. } => correlation_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// A broadcast channel of [`TradeEvent`]s. Events published while there are no subscribers are
+/// dropped, as are the oldest events not yet received by a subscriber that lags too far behind.
+#[derive(Clone)]
+pub struct TradeEventBus(broadcast::Sender<TradeEvent>);
+
+impl Default for TradeEventBus {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl TradeEventBus {
+    pub fn publish(&self, event: TradeEvent) {
+        // An error here just means that nobody is listening:
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TradeEvent> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,57 @@
+//! Deliberate misbehaviour towards the peer, for testing only: with faults injected, the daemon
+//! hands out corrupted, wrong or stale payloads for the peer (duly signed with its identity key, so
+//! that they get past the peer's identity checks), to exercise the checks of the peer's daemon or
+//! of another implementation of the protocol, and how it reports who is to blame.
+
+use musig_proto::helloworld::{NonceSharesMessage, PartialSignaturesMessage};
+use secp::Scalar;
+use std::prelude::rust_2021::*;
+use std::sync::{Mutex, PoisonError};
+
+use crate::config::FaultConfig;
+
+#[derive(Default)]
+pub struct FaultInjector {
+    config: FaultConfig,
+    /// The nonce shares last handed out, for any trade, to replay for the next.
+    last_nonce_shares: Mutex<Option<NonceSharesMessage>>,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Self {
+        Self { config, ..Default::default() }
+    }
+
+    /// Make one of our nonce shares malformed, before the message is signed.
+    pub fn corrupt_nonce_shares(&self, trade_id: &str, message: &mut NonceSharesMessage) {
+        if self.config.corrupt_nonce_shares {
+            println!("Injecting fault: corrupting the swap tx input nonce share of trade {}", trade_id);
+            // No point (nor pub nonce) encoding starts with this byte:
+            if let Some(prefix) = message.swap_tx_input_nonce_share.first_mut() {
+                *prefix = 0xff;
+            }
+        }
+    }
+
+    /// Swap one of our partial signatures for a random scalar, well-formed but wrong, before the
+    /// message is signed.
+    pub fn corrupt_partial_signatures(&self, trade_id: &str, message: &mut PartialSignaturesMessage) {
+        if self.config.wrong_partial_signatures {
+            println!("Injecting fault: replacing the warning tx buyer input partial signature of trade {}", trade_id);
+            message.peers_warning_tx_buyer_input_partial_signature = Scalar::random(&mut rand::thread_rng()).serialize().into();
+        }
+    }
+
+    /// The nonce shares handed out last time (for another trade), in place of the given ones, which
+    /// are kept to replay next time. The first time, the given ones are handed out as they are.
+    pub fn replay_nonce_shares(&self, trade_id: &str, message: NonceSharesMessage) -> NonceSharesMessage {
+        if !self.config.replay_nonce_shares {
+            return message;
+        }
+        let last_message = self.last_nonce_shares.lock().unwrap_or_else(PoisonError::into_inner).replace(message.clone());
+        if last_message.is_some() {
+            println!("Injecting fault: replaying stale nonce shares for trade {}", trade_id);
+        }
+        last_message.unwrap_or(message)
+    }
+}
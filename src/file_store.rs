@@ -0,0 +1,315 @@
+use musig_trade_protocol::{lock_trade_model, trade_exists, AuditEntry, Intent, SecretCipher, SecretFields, TradeModel,
+    TradeModelMemoryStore, TradeModelStore, TradeSummary};
+use rand::RngCore as _;
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::cipher::{MasterSecret, StoreCipher};
+use crate::trade_id::MAX_TRADE_ID_LEN;
+
+const FILE_PREFIX: &str = "trade_";
+const ARCHIVED_FILE_PREFIX: &str = "archived_";
+const AUDIT_LOG_FILE_PREFIX: &str = "audit_";
+const FILE_EXTENSION: &str = "bin";
+const INTENT_LOG_FILE_NAME: &str = "intents.log";
+const ENCRYPTION_PARAMS_FILE_NAME: &str = "encryption.params";
+const SALT_LEN: usize = 16;
+
+// The longest file name built from a trade ID must be within the usual limit of 255 bytes:
+const _: () = assert!(ARCHIVED_FILE_PREFIX.len() + 2 * MAX_TRADE_ID_LEN + 1 + FILE_EXTENSION.len() <= 255);
+
+/// A trade model store which keeps every trade model in memory, like [`TradeModelMemoryStore`],
+/// but also writes each one out to its own file in the store directory whenever it is added or
+/// saved, so that in-flight trades (and their key material) survive a restart of the server.
+///
+/// Irreversible steps are additionally recorded in an append-only intent log in the same directory.
+/// Any steps found to be incomplete when the store is next opened are recovered from (currently by
+/// burning all the unused secret nonces of the affected trades) before the log is cleared.
+///
+/// Archiving a trade replaces its file with one holding just its (secret-free) summary. The audit
+/// log of each trade is appended to a file of its own, which is kept once the trade is archived.
+///
+/// If the store is opened with a master secret, the secret key shares & nonces are encrypted at
+/// rest. The passphrase salt and a key check value are kept in the store directory, so that once
+/// encrypted, the store can't be opened without the same secret.
+pub struct TradeModelFileStore {
+    dir: PathBuf,
+    trade_models: TradeModelMemoryStore,
+    intent_log: Mutex<File>,
+    cipher: Option<StoreCipher>,
+    /// Held while adding a trade model, so that no two with the same ID can both be written out.
+    adding: Mutex<()>,
+}
+
+impl TradeModelFileStore {
+    /// Open the store in the given directory, creating it if necessary, and load every trade model
+    /// previously written there. Any plaintext secrets are encrypted if a master secret is given.
+    pub fn open(dir: impl Into<PathBuf>, master_secret: Option<&MasterSecret>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let (cipher, newly_encrypted) = open_cipher(&dir.join(ENCRYPTION_PARAMS_FILE_NAME), master_secret)?;
+        let trade_models = TradeModelMemoryStore::default();
+        let mut live_trade_paths = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != FILE_EXTENSION) {
+                continue;
+            }
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            if file_name.starts_with(FILE_PREFIX) {
+                live_trade_paths.push(path);
+            } else if file_name.starts_with(ARCHIVED_FILE_PREFIX) {
+                trade_models.add_archived_trade(TradeSummary::decode(&fs::read(&path)?).map_err(invalid_data(&path))?)?;
+            }
+        }
+        // Load the live trade models once every archived ID is known, as the live file of a trade is
+        // left behind if archiving it fails (or the server stops) just after writing its archive:
+        for path in live_trade_paths {
+            let cipher = cipher.as_ref().map(|c| c as &dyn SecretCipher);
+            let trade_model = TradeModel::decode(&fs::read(&path)?, cipher).map_err(invalid_data(&path))?;
+            if trade_models.contains_trade(trade_model.trade_id()) {
+                fs::remove_file(&path)?;
+            } else {
+                trade_models.add_trade_model(trade_model)?;
+            }
+        }
+        let intent_log_path = dir.join(INTENT_LOG_FILE_NAME);
+        let incomplete_intents = read_incomplete_intents(&intent_log_path)?;
+        let intent_log = Mutex::new(create_file(&intent_log_path, false)?);
+        let store = Self { dir, trade_models, intent_log, cipher, adding: Mutex::new(()) };
+        if newly_encrypted {
+            for summary in store.list_trade_models() {
+                let Some(trade_model) = store.get_trade_model(&summary.trade_id) else { continue };
+                store.write(&lock_trade_model(&trade_model))?;
+            }
+        }
+        for (trade_id, intent) in incomplete_intents {
+            let Some(trade_model) = store.get_trade_model(&trade_id) else { continue };
+            let mut trade_model = lock_trade_model(&trade_model);
+            match intent {
+                Intent::ConsumeNonces => trade_model.discard_unused_sec_nonces(),
+            }
+            store.write(&trade_model)?;
+            drop(trade_model);
+        }
+        // Every previously logged step is now either complete or recovered from, so start afresh:
+        store.intent_log.lock().unwrap_or_else(PoisonError::into_inner).set_len(0)?;
+        Ok(store)
+    }
+
+    fn path(&self, prefix: &str, trade_id: &str) -> PathBuf {
+        // Hex-encode the trade ID, so that it can't be used to escape the store directory:
+        self.dir.join(prefix.to_owned() + &hex_encode(trade_id)).with_extension(FILE_EXTENSION)
+    }
+
+    fn write(&self, trade_model: &TradeModel) -> io::Result<()> {
+        let secret_fields = self.cipher.as_ref().map_or(SecretFields::Include, |c| SecretFields::Encrypt(c));
+        write_atomically(&self.path(FILE_PREFIX, trade_model.trade_id()), &trade_model.encode_to_vec(secret_fields))
+    }
+
+    fn append_to_intent_log(&self, entry_kind: &str, trade_id: &str, intent: Intent) -> io::Result<()> {
+        let line = format!("{} {} {}\n", entry_kind, hex_encode(trade_id), intent_name(intent));
+        let mut intent_log = self.intent_log.lock().unwrap_or_else(PoisonError::into_inner);
+        intent_log.write_all(line.as_bytes())?;
+        intent_log.sync_data()
+    }
+}
+
+impl TradeModelStore for TradeModelFileStore {
+    fn add_trade_model(&self, trade_model: TradeModel) -> io::Result<()> {
+        // Check for the ID before writing out the file, which would otherwise overwrite that of a
+        // live trade model with the same ID:
+        let adding = self.adding.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.trade_models.contains_trade(trade_model.trade_id()) {
+            return Err(trade_exists(trade_model.trade_id()));
+        }
+        self.write(&trade_model)?;
+        self.trade_models.add_trade_model(trade_model)?;
+        drop(adding);
+        Ok(())
+    }
+
+    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>> {
+        self.trade_models.get_trade_model(trade_id)
+    }
+
+    fn contains_trade(&self, trade_id: &str) -> bool {
+        self.trade_models.contains_trade(trade_id)
+    }
+
+    fn save_trade_model(&self, trade_model: &TradeModel) -> io::Result<()> {
+        // Don't resurrect the file of a trade model archived while it was being mutated:
+        if self.trade_models.get_trade_model(trade_model.trade_id()).is_none() {
+            return Ok(());
+        }
+        self.write(trade_model)
+    }
+
+    fn log_intent(&self, trade_id: &str, intent: Intent) -> io::Result<()> {
+        self.append_to_intent_log("begin", trade_id, intent)
+    }
+
+    fn log_completion(&self, trade_id: &str, intent: Intent) -> io::Result<()> {
+        self.append_to_intent_log("end", trade_id, intent)
+    }
+
+    fn list_trade_models(&self) -> Vec<TradeSummary> {
+        self.trade_models.list_trade_models()
+    }
+
+    fn archive_trade_model_if(&self, trade_id: &str, condition: impl FnOnce(&TradeModel) -> bool)
+        -> io::Result<Option<TradeSummary>>
+    {
+        let Some(summary) = self.trade_models.archive_trade_model_if(trade_id, condition)? else {
+            return Ok(None);
+        };
+        write_atomically(&self.path(ARCHIVED_FILE_PREFIX, trade_id), &summary.encode_to_vec())?;
+        fs::remove_file(self.path(FILE_PREFIX, trade_id))?;
+        Ok(Some(summary))
+    }
+
+    fn list_archived_trades(&self) -> Vec<TradeSummary> {
+        self.trade_models.list_archived_trades()
+    }
+
+    fn add_archived_trade(&self, summary: TradeSummary) -> io::Result<()> {
+        write_atomically(&self.path(ARCHIVED_FILE_PREFIX, &summary.trade_id), &summary.encode_to_vec())?;
+        self.trade_models.add_archived_trade(summary)
+    }
+
+    fn log_audit_entry(&self, trade_id: &str, entry: &AuditEntry) -> io::Result<()> {
+        let mut audit_log = create_file(&self.path(AUDIT_LOG_FILE_PREFIX, trade_id), false)?;
+        audit_log.write_all(&entry.encode_length_delimited_to_vec())?;
+        audit_log.sync_data()
+    }
+
+    fn get_audit_log(&self, trade_id: &str) -> io::Result<Vec<AuditEntry>> {
+        let path = self.path(AUDIT_LOG_FILE_PREFIX, trade_id);
+        match fs::read(&path) {
+            Ok(bytes) => AuditEntry::decode_log(&bytes).map_err(invalid_data(&path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Derive the store cipher from the master secret, if any, and the salt in the encryption params
+/// file, checking the secret against the key check value there. If the file doesn't exist yet, it
+/// is created with a fresh salt, and `true` is returned to indicate that encryption is new.
+fn open_cipher(params_path: &Path, master_secret: Option<&MasterSecret>) -> io::Result<(Option<StoreCipher>, bool)> {
+    let params = match fs::read(params_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        params => Some(params?),
+    };
+    match (master_secret, params) {
+        (None, None) => Ok((None, false)),
+        (None, Some(_)) => Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            "trade model store is encrypted, but no passphrase or key was configured")),
+        (Some(master_secret), None) => {
+            let mut salt = [0; SALT_LEN];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let cipher = StoreCipher::derive(master_secret, &salt);
+            write_atomically(params_path, &[&salt[..], &cipher.key_check()].concat())?;
+            Ok((Some(cipher), true))
+        }
+        (Some(master_secret), Some(params)) => {
+            let (salt, key_check) = params.split_at_checked(SALT_LEN).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData, format!("{}: truncated", params_path.display())))?;
+            let cipher = StoreCipher::derive(master_secret, salt);
+            if cipher.key_check()[..] != *key_check {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                    "wrong passphrase or key for trade model store"));
+            }
+            Ok((Some(cipher), false))
+        }
+    }
+}
+
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write_atomically_with(path, |file| file.write_all(bytes))
+}
+
+/// Write the file as with [`write_atomically`], but straight from the given writer, for contents
+/// best not gathered into a buffer first (such as secrets).
+pub fn write_atomically_with(path: &Path, write: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+    // Write to a temporary file first, then rename it, so that a crash mid-write can never leave a
+    // truncated file on disk in place of the last good one.
+    let tmp_path = path.with_extension("tmp");
+    let mut file = create_file(&tmp_path, true)?;
+    write(&mut file)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+fn invalid_data<E: std::fmt::Display>(path: &Path) -> impl Fn(E) -> io::Error + '_ {
+    move |e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
+
+fn create_file(path: &Path, truncate: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    if truncate {
+        options.write(true).truncate(true);
+    } else {
+        options.append(true);
+    }
+    options.create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+pub fn hex_encode(s: &str) -> String {
+    let mut hex = String::with_capacity(s.len() * 2);
+    for b in s.bytes() {
+        write!(hex, "{:02x}", b).unwrap();
+    }
+    hex
+}
+
+fn hex_decode(hex: &str) -> Option<String> {
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+const fn intent_name(intent: Intent) -> &'static str {
+    match intent {
+        Intent::ConsumeNonces => "consume_nonces",
+    }
+}
+
+fn intent_from_name(name: &str) -> Option<Intent> {
+    Some(match name {
+        "consume_nonces" => Intent::ConsumeNonces,
+        _ => return None,
+    })
+}
+
+/// Replay the intent log, if present, returning every logged step without a completion entry.
+fn read_incomplete_intents(path: &Path) -> io::Result<BTreeSet<(String, Intent)>> {
+    let log = match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        log => log?,
+    };
+    let mut incomplete = BTreeSet::new();
+    for line in log.lines() {
+        let mut fields = line.split(' ');
+        let (Some(entry_kind), Some(trade_id), Some(intent)) = (fields.next(),
+            fields.next().and_then(hex_decode), fields.next().and_then(intent_from_name)) else {
+            // A malformed entry can only be a torn final write. If it was a 'begin' entry then the
+            // step never started, and if it was an 'end' entry then skipping it errs on the safe side.
+            continue;
+        };
+        match entry_kind {
+            "begin" => incomplete.insert((trade_id, intent)),
+            _ => incomplete.remove(&(trade_id, intent)),
+        };
+    }
+    Ok(incomplete)
+}
@@ -0,0 +1,309 @@
+//! A JSON gateway to the `MuSig` service, served over plain HTTP, for monitoring dashboards and
+//! scripting tools to query trade state (or drive trades) without any protobuf tooling. Each unary
+//! RPC may be called with a `POST` to `/v1/<Rpc>` with its request message as a JSON body, or with
+//! a `GET` to that path with the request fields as query parameters (each repeated for a repeated
+//! field), and the trade introspection RPCs have routes of their own:
+//!
+//! * `GET /v1/trades` calls `ListTrades`;
+//! * `GET /v1/trades/<id>` calls `GetTradeState`;
+//! * `GET /v1/trades/<id>/audit-log` calls `GetTradeAuditLog`.
+//!
+//! The replies are the response messages as JSON, as transcoded by [`crate::transcode`], or else
+//! the failed status as `{"code": <gRPC code>, "message": <message>}` with a matching HTTP status.
+//! Streaming RPCs are not served, failing with `501 Not Implemented`. Any `x-correlation-id` header
+//! is passed on to the call as its correlation ID, and the ID echoed back in that header. The calls
+//! are made to the service in-process, with the address each client connected from, so that they
+//! may be logged, rate-limited & charged to the client's trade quota as by the listeners. The
+//! gateway doesn't authenticate its clients, though, so it may only be served at a loopback
+//! address (or behind a proxy which does).
+
+use http_body::{Body, Frame, SizeHint};
+use musig_proto::FILE_DESCRIPTOR_SET;
+use std::convert::Infallible;
+use std::future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::io;
+use tokio::io::{AsyncBufReadExt as _, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader};
+use tokio::net::TcpListener;
+use tonic::body::BoxBody;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::codegen::http::{Request, Response};
+use tonic::codegen::Bytes;
+use tonic::{Code, Status};
+use tower_service::Service;
+
+use crate::correlation::{self, CORRELATION_ID_KEY};
+use crate::json::{self, Json};
+use crate::transcode::Descriptors;
+
+const SERVICE: &str = "helloworld.MuSig";
+/// The most bytes of request head read, so that a client cannot hold us up by sending one without
+/// end.
+const MAX_REQUEST_HEAD_LEN: u64 = 8192;
+/// The longest request body read, which is ample for any request message of the service.
+const MAX_REQUEST_BODY_LEN: usize = 1024 * 1024;
+/// The length of the prefix of each gRPC message: a compression flag, then the message length.
+const GRPC_PREFIX_LEN: usize = 5;
+
+/// An HTTP reply: its status code & reason phrase, and its JSON body.
+struct Reply(u16, &'static str, Json);
+
+impl Reply {
+    fn error(code: Code, message: impl Into<String>) -> Self {
+        let (status, reason) = http_status(code);
+        Self(status, reason, Json::Object(vec![
+            ("code".to_owned(), Json::Number((code as i32).to_string())),
+            ("message".to_owned(), Json::String(message.into())),
+        ]))
+    }
+}
+
+impl From<Status> for Reply {
+    fn from(status: Status) -> Self {
+        Self::error(status.code(), status.message())
+    }
+}
+
+/// The HTTP status of a failed call with the given gRPC code, as mapped by the gRPC HTTP gateways.
+const fn http_status(code: Code) -> (u16, &'static str) {
+    match code {
+        Code::Ok => (200, "OK"),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => (400, "Bad Request"),
+        Code::Unauthenticated => (401, "Unauthorized"),
+        Code::PermissionDenied => (403, "Forbidden"),
+        Code::NotFound => (404, "Not Found"),
+        Code::AlreadyExists | Code::Aborted => (409, "Conflict"),
+        Code::ResourceExhausted => (429, "Too Many Requests"),
+        Code::Cancelled => (499, "Client Closed Request"),
+        Code::Unimplemented => (501, "Not Implemented"),
+        Code::Unavailable => (503, "Service Unavailable"),
+        Code::DeadlineExceeded => (504, "Gateway Timeout"),
+        Code::Unknown | Code::Internal | Code::DataLoss => (500, "Internal Server Error"),
+    }
+}
+
+/// Serve the gateway to the given `MuSig` service at the given address. This only returns if the
+/// listener fails.
+pub async fn serve_gateway<Svc, ResBody>(addr: SocketAddr, service: Svc) -> io::Result<()>
+    where Svc: Service<Request<BoxBody>, Response=Response<ResBody>, Error=Infallible> + Clone + Send + 'static,
+          Svc::Future: Send,
+          ResBody: Body<Data=Bytes, Error=Status> + Unpin + Send
+{
+    let descriptors = Arc::new(Descriptors::load(FILE_DESCRIPTOR_SET)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, remote_addr) = listener.accept().await?;
+        let descriptors = Arc::clone(&descriptors);
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, Some(remote_addr), &descriptors, service).await {
+                println!("Could not serve gateway request: {}", e);
+            }
+        });
+    }
+}
+
+/// Read one request from the given connection (from the given address, if known) and reply to it,
+/// closing the connection after.
+pub async fn handle_connection<IO, Svc, ResBody>(io: IO, remote_addr: Option<SocketAddr>, descriptors: &Descriptors,
+                                                 service: Svc) -> io::Result<()>
+    where IO: AsyncRead + AsyncWrite + Unpin,
+          Svc: Service<Request<BoxBody>, Response=Response<ResBody>, Error=Infallible>,
+          ResBody: Body<Data=Bytes, Error=Status> + Unpin
+{
+    let mut io = BufReader::new(io);
+    let mut head = (&mut io).take(MAX_REQUEST_HEAD_LEN);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).await?;
+    let (mut content_len, mut correlation_id) = (0, None);
+    let mut line = String::new();
+    while head.read_line(&mut line).await? != 0 && line != "\r\n" && line != "\n" {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_len = value.trim().parse().unwrap_or(usize::MAX);
+            } else if name.eq_ignore_ascii_case(CORRELATION_ID_KEY) {
+                correlation_id = Some(value.trim().to_owned());
+            }
+        }
+        line.clear();
+    }
+    let correlation_id = correlation::given_or_new(correlation_id.as_deref().map(str::as_bytes));
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let reply = if content_len > MAX_REQUEST_BODY_LEN {
+        Reply::error(Code::ResourceExhausted, format!("request body longer than {} bytes", MAX_REQUEST_BODY_LEN))
+    } else {
+        let mut body = vec![0; content_len];
+        io.read_exact(&mut body).await?;
+        match route(method, target, &body) {
+            Ok((rpc, request)) => call(descriptors, service, &rpc, &request, &correlation_id, remote_addr).await
+                .unwrap_or_else(Reply::from),
+            Err(reply) => reply,
+        }
+    };
+    let Reply(status, reason, body) = reply;
+    let body = body.to_string();
+    let response = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+        {}: {}\r\nConnection: close\r\n\r\n{}", status, reason, body.len(), CORRELATION_ID_KEY, correlation_id, body);
+    io.get_mut().write_all(response.as_bytes()).await?;
+    io.get_mut().shutdown().await
+}
+
+/// The RPC to call for the given request, with its request message as JSON.
+fn route(method: &str, target: &str, body: &[u8]) -> Result<(String, Json), Reply> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let segments: Vec<_> = path.strip_prefix("/v1/").unwrap_or_default().split('/').map(percent_decode).collect();
+    let with_trade_id = |rpc: &str, trade_id: &str| {
+        let mut request = query_json(query);
+        if let Json::Object(entries) = &mut request {
+            entries.push(("tradeId".to_owned(), Json::String(trade_id.to_owned())));
+        }
+        Ok((rpc.to_owned(), request))
+    };
+    match (method, &segments.iter().map(String::as_str).collect::<Vec<_>>()[..]) {
+        ("GET", ["trades"]) => Ok(("ListTrades".to_owned(), query_json(query))),
+        ("GET", ["trades", trade_id]) => with_trade_id("GetTradeState", trade_id),
+        ("GET", ["trades", trade_id, "audit-log"]) => with_trade_id("GetTradeAuditLog", trade_id),
+        ("GET", [rpc]) if !rpc.is_empty() && *rpc != "trades" => Ok(((*rpc).to_owned(), query_json(query))),
+        ("POST", [rpc]) if !rpc.is_empty() && *rpc != "trades" => {
+            let body = std::str::from_utf8(body).map_err(|_| Reply::error(Code::InvalidArgument, "body not valid UTF-8"))?;
+            let request = if body.trim().is_empty() { Json::Object(Vec::new()) } else {
+                json::parse(body).map_err(|e| Reply::error(Code::InvalidArgument, format!("malformed JSON body: {}", e)))?
+            };
+            Ok(((*rpc).to_owned(), request))
+        }
+        (_, [_] | ["trades", _] | ["trades", _, "audit-log"]) if !segments[0].is_empty() =>
+            Err(Reply(405, "Method Not Allowed", Reply::error(Code::Unimplemented, format!("{} not allowed", method)).2)),
+        _ => Err(Reply::error(Code::NotFound, format!("no such route: {}", path))),
+    }
+}
+
+/// The query parameters as a JSON object of string values, with those of a repeated key collected
+/// into an array.
+fn query_json(query: &str) -> Json {
+    let mut entries: Vec<(String, Json)> = Vec::new();
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let (key, value) = (percent_decode(key), Json::String(percent_decode(value)));
+        match entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, Json::Array(items))) => items.push(value),
+            Some((_, existing)) => *existing = Json::Array(vec![existing.clone(), value]),
+            None => entries.push((key, value)),
+        }
+    }
+    Json::Object(entries)
+}
+
+/// Decode the `%XX` escapes (and `+` for a space) of a URL path segment or query parameter, leaving
+/// any malformed escapes as they are.
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (b, hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+            (b'+', _) => bytes.push(b' '),
+            (b, _) => bytes.push(b),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Call the given unary RPC of the service with the given request, transcoding it from JSON and
+/// the response back. The call is given the client's address as its connect info, as a call over
+/// TCP to a listener would be, for the client to be identified by.
+async fn call<Svc, ResBody>(descriptors: &Descriptors, mut service: Svc, rpc: &str, request: &Json, correlation_id: &str,
+                            remote_addr: Option<SocketAddr>) -> Result<Reply, Status>
+    where Svc: Service<Request<BoxBody>, Response=Response<ResBody>, Error=Infallible>,
+          ResBody: Body<Data=Bytes, Error=Status> + Unpin
+{
+    let method = descriptors.method(SERVICE, rpc)
+        .ok_or_else(|| Status::not_found(format!("no such RPC: {}", rpc)))?;
+    if method.client_streaming() || method.server_streaming() {
+        return Err(Status::unimplemented(format!("streaming RPC {} is not served by the JSON gateway", rpc)));
+    }
+    let message = descriptors.encode(method.input_type(), request)
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    let mut frame = Vec::with_capacity(GRPC_PREFIX_LEN + message.len());
+    frame.push(0);
+    frame.extend_from_slice(&u32::try_from(message.len()).map_err(|_| Status::invalid_argument("request too long"))?
+        .to_be_bytes());
+    frame.extend_from_slice(&message);
+    let request = Request::post(format!("/{}/{}", SERVICE, rpc))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .header(CORRELATION_ID_KEY, correlation_id)
+        .extension(TcpConnectInfo { local_addr: None, remote_addr })
+        .body(tonic::body::boxed(Unary(Some(frame.into()))))
+        .map_err(|e| Status::internal(e.to_string()))?;
+    future::poll_fn(|cx| service.poll_ready(cx)).await.unwrap_or_else(|e| match e {});
+    let response = service.call(request).await.unwrap_or_else(|e| match e {});
+    let message = read_unary_response(response).await?;
+    let response = descriptors.decode(method.output_type(), &message)
+        .map_err(|e| Status::internal(format!("could not transcode response: {}", e)))?;
+    Ok(Reply(200, "OK", response))
+}
+
+/// Read the single message of a unary response, or the failed status of the call.
+async fn read_unary_response<ResBody>(response: Response<ResBody>) -> Result<Vec<u8>, Status>
+    where ResBody: Body<Data=Bytes, Error=Status> + Unpin
+{
+    let check = |status: Option<Status>| match status {
+        Some(status) if status.code() != Code::Ok => Err(status),
+        status => Ok(status.is_some()),
+    };
+    // A call failed up front has its status in the headers, and no body:
+    if check(Status::from_header_map(response.headers()))? {
+        return Err(Status::internal("call ended without a response"));
+    }
+    let mut body = response.into_body();
+    let mut data = Vec::new();
+    let mut ended = false;
+    while let Some(frame) = future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+        match frame?.into_data() {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(frame) => if let Some(trailers) = frame.trailers_ref() {
+                ended = check(Status::from_header_map(trailers))?;
+            },
+        }
+    }
+    if !ended {
+        return Err(Status::internal("call ended without a status"));
+    }
+    match data.split_first_chunk::<GRPC_PREFIX_LEN>() {
+        Some((&[0, a, b, c, d], message)) if message.len() == u32::from_be_bytes([a, b, c, d]) as usize =>
+            Ok(message.to_vec()),
+        _ => Err(Status::internal("call ended without a single uncompressed response")),
+    }
+}
+
+/// A request body of a single (already framed) gRPC message.
+struct Unary(Option<Bytes>);
+
+impl Body for Unary {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Poll::Ready(self.0.take().map(|data| Ok(Frame::data(data))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.0.as_ref().map_or(0, |data| data.len() as u64))
+    }
+}
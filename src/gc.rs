@@ -0,0 +1,48 @@
+use musig_trade_protocol::{TradeModel, TradeModelStore, TradePhase};
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::events::{TradeEvent, TradeEventBus};
+
+/// Periodically abort every trade which has been stuck in an early phase for longer than the given
+/// time to live, by archiving it (which wipes its secrets and frees its trade model), publishing a
+/// [`TradeEvent::Aborted`] for each. This never returns.
+///
+/// Only trades which have yet to have their deposit tx signed are considered early enough to abort,
+/// as no funds can be committed to the trade before then. Later trades must be closed as normal.
+pub async fn collect_stale_trades<S>(store: Arc<S>, events: TradeEventBus, ttl: Duration, scan_interval: Duration)
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let mut interval = time::interval(scan_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(cutoff) = SystemTime::now().checked_sub(ttl) else { continue };
+        // The scan waits for trade model locks (and maybe does file I/O), so keep it off the async
+        // worker threads:
+        let (store, events) = (Arc::clone(&store), events.clone());
+        if let Err(e) = tokio::task::spawn_blocking(move || abort_stale_trades(&*store, &events, cutoff)).await {
+            eprintln!("Stale trade scan failed: {}", e);
+        }
+    }
+}
+
+fn abort_stale_trades(store: &impl TradeModelStore, events: &TradeEventBus, cutoff: SystemTime) {
+    for summary in store.list_trade_models() {
+        if summary.phase >= TradePhase::DepositTxSigned {
+            continue;
+        }
+        match store.archive_trade_model_if(&summary.trade_id, |m| is_stale(m, cutoff)) {
+            Ok(Some(summary)) => events.publish(TradeEvent::Aborted(summary)),
+            Ok(None) => {}
+            Err(e) => eprintln!("Could not abort stale trade with id {}: {}", summary.trade_id, e),
+        }
+    }
+}
+
+fn is_stale(trade_model: &TradeModel, cutoff: SystemTime) -> bool {
+    trade_model.phase() < TradePhase::DepositTxSigned
+        && trade_model.created_at().is_some_and(|created_at| created_at <= cutoff)
+}
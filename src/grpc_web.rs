@@ -0,0 +1,280 @@
+//! gRPC-web support, for browser-based tools (such as an operator dashboard) to call the services
+//! directly, with no proxy in between to translate. A gRPC-web call (over HTTP/1.1, as browsers
+//! make them) is turned into a plain gRPC call, and its response back, with the trailers sent as a
+//! last message flagged as such, and with every message base64-encoded for the text variant of the
+//! protocol. Server streaming works as in plain gRPC, but client streaming is left out, as in any
+//! gRPC-web client. Calls from web pages are only let through for the configured origins, with the
+//! CORS preflight requests of the browsers answered accordingly.
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use http_body::{Body, Frame, SizeHint};
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use tonic::codegen::{Bytes, StdError};
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::config::GrpcWebConfig;
+
+const GRPC_WEB_CONTENT_TYPE: &str = "application/grpc-web";
+const GRPC_WEB_TEXT_CONTENT_TYPE: &str = "application/grpc-web-text";
+/// The flag of the last message of a gRPC-web response, holding the trailers as an HTTP/1 header block.
+const TRAILERS_FLAG: u8 = 0x80;
+const ALLOWED_HEADERS: &str = "content-type, grpc-timeout, x-correlation-id, x-grpc-web, x-user-agent";
+const EXPOSED_HEADERS: &str = "grpc-status, grpc-message, grpc-status-details-bin, expected-next-rpc, x-correlation-id";
+/// How long browsers may cache the answer to a preflight request, in seconds.
+const PREFLIGHT_MAX_AGE_SECS: &str = "600";
+
+/// A layer serving gRPC-web calls to the inner service, if enabled in the given config.
+#[derive(Clone)]
+pub struct GrpcWebLayer(Arc<GrpcWebConfig>);
+
+impl GrpcWebLayer {
+    pub fn new(config: GrpcWebConfig) -> Self {
+        Self(Arc::new(config))
+    }
+}
+
+impl<S> Layer<S> for GrpcWebLayer {
+    type Service = GrpcWeb<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcWeb { inner, config: Arc::clone(&self.0) }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcWeb<S> {
+    inner: S,
+    config: Arc<GrpcWebConfig>,
+}
+
+impl<S> GrpcWeb<S> {
+    /// Whether calls from a web page of the given origin (if a browser made the call) are allowed.
+    fn allows(&self, origin: Option<&HeaderValue>) -> bool {
+        origin.is_none_or(|origin| self.config.allowed_origins.iter()
+            .any(|allowed| allowed == "*" || origin.as_bytes() == allowed.as_bytes()))
+    }
+}
+
+impl<S, ResBody> Service<Request<BoxBody>> for GrpcWeb<S>
+    where S: Service<Request<BoxBody>, Response=Response<ResBody>>,
+          S::Error: Send + 'static,
+          S::Future: Send + 'static,
+          ResBody: Body<Data=Bytes> + Send + Unpin + 'static,
+          ResBody::Error: Into<StdError>
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<BoxBody>) -> Self::Future {
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        let encoding = req.headers().get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Encoding::of_content_type);
+        let is_preflight = req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if !self.config.enabled || (!is_preflight && encoding.is_none()) {
+            let response = self.inner.call(req);
+            return Box::pin(async move { Ok(response.await?.map(tonic::body::boxed)) });
+        }
+        if !self.allows(origin.as_ref()) {
+            let status = Status::permission_denied("gRPC-web calls are not allowed from this origin");
+            return Box::pin(future::ready(Ok(status.into_http())));
+        }
+        let Some(encoding) = encoding.filter(|_| !is_preflight) else {
+            let mut response = Response::new(tonic::body::empty_body());
+            *response.status_mut() = StatusCode::NO_CONTENT;
+            let headers = response.headers_mut();
+            add_cors_headers(headers, origin);
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("POST"));
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static(ALLOWED_HEADERS));
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(PREFLIGHT_MAX_AGE_SECS));
+            return Box::pin(future::ready(Ok(response)));
+        };
+
+        let headers = req.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        headers.remove(header::CONTENT_LENGTH);
+        let req = match encoding {
+            Encoding::Binary => req,
+            Encoding::Text => req.map(|body| tonic::body::boxed(Base64Decoded { inner: body, pending: Vec::new() })),
+        };
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(encoding.content_type()));
+            add_cors_headers(headers, origin);
+            Ok(response.map(|inner| tonic::body::boxed(GrpcWebBody { inner, encoding, ended: false })))
+        })
+    }
+}
+
+fn add_cors_headers(headers: &mut HeaderMap, origin: Option<HeaderValue>) {
+    if let Some(origin) = origin {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(EXPOSED_HEADERS));
+        headers.insert(header::VARY, HeaderValue::from_static("origin"));
+    }
+}
+
+/// How the messages of a gRPC-web call are sent: as is, or base64-encoded (for browsers unable to
+/// read a binary response as it streams in).
+#[derive(Clone, Copy)]
+enum Encoding {
+    Binary,
+    Text,
+}
+
+impl Encoding {
+    fn of_content_type(content_type: &str) -> Option<Self> {
+        let (media_type, subtype) = content_type.split_once('+').unwrap_or((content_type, "proto"));
+        match (media_type, subtype) {
+            (GRPC_WEB_CONTENT_TYPE, "proto") => Some(Self::Binary),
+            (GRPC_WEB_TEXT_CONTENT_TYPE, "proto") => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    const fn content_type(self) -> &'static str {
+        match self {
+            Self::Binary => "application/grpc-web+proto",
+            Self::Text => "application/grpc-web-text+proto",
+        }
+    }
+}
+
+/// A request body of base64-encoded messages, decoded. The encoded messages may each be padded, so
+/// the body is decoded one run of 4-character quads (up to & including any padded one) at a time.
+struct Base64Decoded {
+    inner: BoxBody,
+    pending: Vec<u8>,
+}
+
+impl Base64Decoded {
+    fn decode_pending(&mut self) -> Result<Vec<u8>, Status> {
+        let complete_len = self.pending.len() / 4 * 4;
+        let mut decoded = Vec::with_capacity(complete_len / 4 * 3);
+        let mut start = 0;
+        for end in (4..=complete_len).step_by(4) {
+            if end == complete_len || self.pending[end - 1] == b'=' {
+                STANDARD.decode_vec(&self.pending[start..end], &mut decoded)
+                    .map_err(|e| Status::invalid_argument(format!("malformed base64 request body: {}", e)))?;
+                start = end;
+            }
+        }
+        self.pending.drain(..complete_len);
+        Ok(decoded)
+    }
+}
+
+impl Body for Base64Decoded {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(None) if !self.pending.is_empty() =>
+                    return Poll::Ready(Some(Err(Status::invalid_argument("truncated base64 request body")))),
+                poll => return poll,
+            };
+            match frame.into_data() {
+                Ok(chunk) => {
+                    self.pending.extend_from_slice(&chunk);
+                    let decoded = self.decode_pending()?;
+                    if !decoded.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(decoded.into()))));
+                    }
+                }
+                Err(frame) => return Poll::Ready(Some(Ok(frame))),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_empty() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // The decoded length is only known roughly, from the encoded length:
+        let mut hint = SizeHint::new();
+        if let Some(upper) = self.inner.size_hint().upper() {
+            hint.set_upper((upper + self.pending.len() as u64) / 4 * 3);
+        }
+        hint
+    }
+}
+
+/// A gRPC response body as a gRPC-web one: with the trailers as a last message, and with every
+/// message base64-encoded for the text variant.
+struct GrpcWebBody<B> {
+    inner: B,
+    encoding: Encoding,
+    ended: bool,
+}
+
+impl<B: Body<Data=Bytes> + Unpin> Body for GrpcWebBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        let data = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                Ok(data) => data,
+                Err(frame) => {
+                    let Ok(trailers) = frame.into_trailers() else { return Poll::Ready(None) };
+                    self.ended = true;
+                    trailers_message(&trailers)
+                }
+            },
+            poll => return poll,
+        };
+        let data = match self.encoding {
+            Encoding::Binary => data,
+            Encoding::Text => STANDARD.encode(data).into(),
+        };
+        Poll::Ready(Some(Ok(Frame::data(data))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.ended
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        // The trailers make for extra data of unknown length:
+        SizeHint::new()
+    }
+}
+
+/// The trailers as the last message of a gRPC-web response, being the header block of them.
+fn trailers_message(trailers: &HeaderMap) -> Bytes {
+    let mut block = Vec::new();
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.push(b':');
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    let mut message = Vec::with_capacity(5 + block.len());
+    message.push(TRAILERS_FLAG);
+    message.extend_from_slice(&u32::try_from(block.len()).unwrap_or(u32::MAX).to_be_bytes());
+    message.extend_from_slice(&block);
+    message.into()
+}
@@ -0,0 +1,126 @@
+//! The standard gRPC health service, for orchestrators to probe the daemon by. The `liveness`
+//! service is reported serving for as long as the daemon answers at all, as restarting it would do
+//! nothing for a broken dependency. The readiness of the daemon to serve trades, reported for the
+//! `MuSig` service (and the daemon as a whole, by the empty service name), instead depends on its
+//! dependencies: that the trade store is writable, and that the chain backend (if any) is reachable
+//! and synced, so that no trades are routed to a daemon unable to carry them through.
+
+use futures::stream::{self, Stream};
+use musig_proto::health::health_check_response::ServingStatus;
+use musig_proto::health::health_server::Health;
+use musig_proto::health::{HealthCheckRequest, HealthCheckResponse};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time;
+use tonic::{Request, Response, Status};
+
+use crate::chain::ChainBackendStatus;
+use crate::file_store::write_atomically;
+
+pub const LIVENESS_SERVICE: &str = "liveness";
+const READINESS_SERVICES: [&str; 2] = ["", "helloworld.MuSig"];
+/// How often the status of a watched service is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// The file written to (and removed from) the store directory, to check it is writable.
+const PROBE_FILE_NAME: &str = "health.probe";
+
+/// The dependencies of the daemon to check for readiness.
+#[derive(Clone, Default)]
+pub struct ReadinessChecks {
+    /// The directory of the trade store, to check is writable, unless the store is in memory.
+    pub store_dir: Option<PathBuf>,
+    /// The status of the chain backend, if any, with the longest time it may go unreached and how
+    /// many block intervals old its tip may be.
+    pub chain_backend: Option<(ChainBackendStatus, Duration, u32)>,
+}
+
+impl ReadinessChecks {
+    /// Check every dependency in turn.
+    ///
+    /// # Errors
+    /// Why the daemon isn't ready, by the first dependency found broken.
+    pub async fn check(&self) -> Result<(), String> {
+        if let Some(dir) = self.store_dir.clone() {
+            tokio::task::spawn_blocking(move || check_writable(&dir)).await
+                .map_err(|e| format!("trade store check failed: {}", e))??;
+        }
+        if let Some((status, max_silence, max_blocks_behind)) = &self.chain_backend {
+            status.check(*max_silence, *max_blocks_behind)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let path = dir.join(PROBE_FILE_NAME);
+    write_atomically(&path, b"").and_then(|()| fs::remove_file(&path))
+        .map_err(|e| format!("trade store at {} not writable: {}", dir.display(), e))
+}
+
+#[derive(Clone)]
+pub struct MyHealth {
+    checks: Arc<ReadinessChecks>,
+    /// The outcome of the last readiness check, so that only the changes to it are logged.
+    last_readiness: Arc<Mutex<Option<Result<(), String>>>>,
+}
+
+impl MyHealth {
+    pub fn new(checks: ReadinessChecks) -> Self {
+        Self { checks: Arc::new(checks), last_readiness: Arc::default() }
+    }
+
+    async fn status(&self, service: &str) -> ServingStatus {
+        if service == LIVENESS_SERVICE {
+            return ServingStatus::Serving;
+        }
+        if !READINESS_SERVICES.contains(&service) {
+            return ServingStatus::ServiceUnknown;
+        }
+        let readiness = self.checks.check().await;
+        let status = if readiness.is_ok() { ServingStatus::Serving } else { ServingStatus::NotServing };
+        let mut last_readiness = self.last_readiness.lock().unwrap_or_else(PoisonError::into_inner);
+        if last_readiness.as_ref() != Some(&readiness) {
+            match &readiness {
+                Ok(()) => println!("Ready to serve trades"),
+                Err(reason) => println!("Not ready to serve trades: {}", reason),
+            }
+            *last_readiness = Some(readiness);
+        }
+        drop(last_readiness);
+        status
+    }
+}
+
+#[tonic::async_trait]
+impl Health for MyHealth {
+    async fn check(&self, request: Request<HealthCheckRequest>) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match self.status(&service).await {
+            ServingStatus::ServiceUnknown => Err(Status::not_found(format!("unknown service: {}", service))),
+            status => Ok(Response::new(HealthCheckResponse { status: status.into() })),
+        }
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item=Result<HealthCheckResponse, Status>> + Send>>;
+
+    async fn watch(&self, request: Request<HealthCheckRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let watch = stream::unfold((self.clone(), service, None), |(this, service, last_status)| async move {
+            loop {
+                if last_status.is_some() {
+                    time::sleep(WATCH_INTERVAL).await;
+                }
+                let status = this.status(&service).await;
+                if last_status != Some(status) {
+                    let response = HealthCheckResponse { status: status.into() };
+                    return Some((Ok(response), (this, service, Some(status))));
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(watch)))
+    }
+}
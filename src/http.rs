@@ -0,0 +1,53 @@
+//! A minimal HTTP/1.0 client, for the few plain `http` calls the daemon makes out (to the chain
+//! backend & to webhooks), which don't warrant a full HTTP client. HTTP/1.0 keeps the response body
+//! unchunked, with the connection closed at its end. Like every other outbound connection, it is
+//! made through the SOCKS proxy (Tor), if one is configured, unless to a loopback address.
+
+use std::fmt::Write as _;
+use std::io;
+use std::prelude::rust_2021::*;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpStream;
+use tonic::codegen::http::Uri;
+
+use crate::tor::{self, Socks5Proxy};
+
+/// The most bytes of a response read, as the daemon only ever expects short ones.
+const MAX_RESPONSE_LEN: u64 = 8192;
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Make a request of the given method & headers, with the given body (if not empty), to the given
+/// `http` URL, through the given proxy (if any), returning the status code and body of the response.
+pub async fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8], proxy: Option<Socks5Proxy>)
+    -> io::Result<(u16, String)>
+{
+    let uri: Uri = url.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let host = uri.host().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing host"))?;
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let mut head = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, host);
+    for (name, value) in headers {
+        write!(head, "{}: {}\r\n", name, value).unwrap();
+    }
+    if !body.is_empty() {
+        write!(head, "Content-Length: {}\r\n", body.len()).unwrap();
+    }
+    head += "\r\n";
+
+    let port = uri.port_u16().unwrap_or(80);
+    let mut stream = match proxy {
+        Some(proxy) if !tor::is_loopback(&uri) => proxy.connect(host, port, None).await?,
+        _ => TcpStream::connect((host, port)).await?,
+    };
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    let mut response = String::new();
+    stream.take(MAX_RESPONSE_LEN).read_to_string(&mut response).await?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| invalid_data("malformed HTTP response"))?;
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line.split(' ').nth(1).and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid_data(format!("malformed HTTP status line: {}", status_line)))?;
+    Ok((status, body.to_owned()))
+}
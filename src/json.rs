@@ -0,0 +1,250 @@
+//! A minimal JSON reader & writer, for the few JSON documents the daemon takes in (such as the
+//! burning-man receiver snapshot) or hands out (through the JSON gateway), which don't warrant a
+//! full JSON dependency. Numbers are kept as their text, to be parsed as whichever type the reader
+//! expects.
+
+use std::fmt::{self, Write as _};
+use std::prelude::rust_2021::*;
+
+/// How deeply arrays & objects may nest, so that a hostile document cannot exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The value of the given key, if this is an object with that key.
+    pub fn get(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Self]> {
+        match self {
+            Self::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// The value as a compact JSON document.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Number(n) => f.write_str(n),
+            Self::String(s) => write_string(f, s),
+            Self::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Self::Object(entries) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Parse the given JSON document, failing with a description of (and the byte offset of) the first
+/// error found.
+pub fn parse(s: &str) -> Result<Json, String> {
+    let mut parser = Parser { s, pos: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos != s.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at byte {}", msg, self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() != Some(b) {
+            return Err(self.error(&format!("expected '{}'", char::from(b))));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => {
+                self.pos += 1;
+                let items = self.list(b']', |parser| parser.value(depth + 1))?;
+                Ok(Json::Array(items))
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => Ok(Json::Number(self.number()?)),
+            _ => {
+                for (literal, value) in [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+                    if self.s[self.pos..].starts_with(literal) {
+                        self.pos += literal.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, String> {
+        self.pos += 1;
+        let entries = self.list(b'}', |parser| {
+            parser.skip_whitespace();
+            let key = parser.string()?;
+            parser.expect(b':')?;
+            Ok((key, parser.value(depth + 1)?))
+        })?;
+        Ok(Json::Object(entries))
+    }
+
+    /// The comma-separated items up to the given closing bracket, just after the opening one.
+    fn list<T>(&mut self, close: u8, mut item: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b) if b == close => {
+                    self.pos += 1;
+                    return Ok(items);
+                }
+                _ => return Err(self.error(&format!("expected ',' or '{}'", char::from(close)))),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        let number = &self.s[start..self.pos];
+        if number.parse::<f64>().is_err() {
+            return Err(self.error("malformed number"));
+        }
+        Ok(number.to_owned())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut string = String::new();
+        loop {
+            let c = self.s[self.pos..].chars().next().ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    string.push(match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        // Only characters of the Basic Multilingual Plane, as no surrogate pairs are expected:
+                        b'u' => {
+                            let hex = self.s.get(self.pos..self.pos + 4).ok_or_else(|| self.error("malformed escape"))?;
+                            self.pos += 4;
+                            u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+                                .ok_or_else(|| self.error("malformed escape"))?
+                        }
+                        _ => return Err(self.error("malformed escape")),
+                    });
+                }
+                c if c.is_control() => return Err(self.error("unescaped control character")),
+                c => string.push(c),
+            }
+        }
+    }
+}
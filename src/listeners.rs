@@ -0,0 +1,307 @@
+//! The listeners the services are served on: the `listen_addr`, `peer_listen_addr` &
+//! `admin_listen_addr` of old, and any number of further `listener.<name>` ones, each a TCP socket
+//! or a unix socket, with TLS and a token for its callers to bear if configured. So the `MuSig`
+//! service may be served on a loopback address for a local client, say, the admin service on a
+//! unix socket for the operators' tooling, and the peer service on an address published as an
+//! onion service, all at once.
+//!
+//! A listener with a client CA takes only clients with a cert it issued (mTLS), each then known by
+//! its cert: see [`crate::client_identity`].
+//!
+//! As the TLS identity is set per server, each listener gets a server of its own, serving its own
+//! copy of the services, which share their state (including the rate limits of each client).
+
+use futures::Stream;
+use sha2::{Digest as _, Sha256};
+use std::error::Error;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::server::{Connected, TcpIncoming};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Status};
+
+use musig_proto::health::health_server::HealthServer;
+use musig_proto::helloworld::mu_sig_server::MuSigServer;
+use musig_proto::peer::mu_sig_peer_server::MuSigPeerServer;
+use musig_trade_protocol::TradeModelStore;
+
+use crate::admin::{AdminServer, MyAdmin};
+use crate::client_identity::ClientIdentityLayer;
+use crate::config::{Config, ListenAddr, ListenerConfig, ListenerService, SecretKeySource};
+use crate::correlation::CorrelationLayer;
+use crate::decode_limits::DecodeLimitLayer;
+use crate::grpc_web::GrpcWebLayer;
+use crate::health::MyHealth;
+use crate::logging::LogLayer;
+use crate::ownership::OwnershipLayer;
+use crate::peer::MyMuSigPeer;
+use crate::rate_limit::RateLimitLayer;
+use crate::step_order::StepOrderLayer;
+use crate::timeout::TimeoutLayer;
+use crate::MyMuSig;
+
+const AUTHORIZATION_KEY: &str = "authorization";
+/// How often an idle client connection is pinged, and how long the ping may go unanswered before
+/// the connection is taken for dead, and its streams (with their subscriptions) dropped.
+const HTTP2_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const HTTP2_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type ServeFuture<'a> = Pin<Box<dyn Future<Output=Result<(), tonic::transport::Error>> + Send + 'a>>;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind to the given address. A socket file left at a unix socket path by a daemon no longer
+    /// running (so that nothing answers on it) is replaced.
+    ///
+    /// # Errors
+    /// If the address can't be bound to.
+    pub async fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix(path) => {
+                if path.exists() && UnixStream::connect(path).is_err() {
+                    fs::remove_file(path)?;
+                }
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    /// The local address of a TCP listener, for an onion service to forward to.
+    ///
+    /// # Errors
+    /// If the listener is a unix socket, or its address can't be had.
+    pub fn tcp_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            Self::Unix(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a TCP listener")),
+        }
+    }
+}
+
+/// The services served at the listeners, cloned for each.
+pub struct Services<S: TradeModelStore> {
+    pub musig: MyMuSig<S>,
+    pub peer: MyMuSigPeer<S>,
+    pub health: MyHealth,
+    pub decode_limits: DecodeLimitLayer,
+    pub rate_limit: RateLimitLayer,
+}
+
+impl<S: TradeModelStore> Clone for Services<S> {
+    fn clone(&self) -> Self {
+        Self {
+            musig: self.musig.clone(),
+            peer: self.peer.clone(),
+            health: self.health.clone(),
+            decode_limits: self.decode_limits.clone(),
+            rate_limit: self.rate_limit.clone(),
+        }
+    }
+}
+
+/// The check of the token borne by each call, if one is set.
+#[derive(Clone)]
+pub struct TokenAuth {
+    /// The hash of the token, compared with that of the token borne, so that the comparison takes
+    /// no time dependent on how much of the token was guessed right.
+    token_hash: Option<[u8; 32]>,
+}
+
+impl TokenAuth {
+    pub fn new(token: Option<&str>) -> Self {
+        Self { token_hash: token.map(|token| Sha256::digest(token).into()) }
+    }
+
+    /// The check of the token in the given env var, if set, for the admin service served at the
+    /// given address.
+    ///
+    /// # Errors
+    /// If the address isn't a loopback address (or a unix socket), but the env var isn't set.
+    pub fn for_admin_from_env(var: &str, addr: &ListenAddr) -> io::Result<Self> {
+        let token = token_from_env(var);
+        if token.is_none() && matches!(addr, ListenAddr::Tcp(addr) if !addr.ip().is_loopback()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "the admin service may only be served on non-loopback address {} with a token, in env var {}", addr, var)));
+        }
+        Ok(Self::new(token.as_deref()))
+    }
+
+    /// The check of the token in the given env var, which must be set, for the named listener.
+    ///
+    /// # Errors
+    /// If the env var isn't set.
+    pub fn required_from_env(var: &str, listener: &str) -> io::Result<Self> {
+        let token = token_from_env(var).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "listener '{}' is to be served with a token, but none is set in env var {}", listener, var)))?;
+        Ok(Self::new(Some(&token)))
+    }
+}
+
+fn token_from_env(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|token| !token.is_empty())
+}
+
+impl Interceptor for TokenAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(token_hash) = self.token_hash else { return Ok(request) };
+        let token = request.metadata().get(AUTHORIZATION_KEY)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if token.is_some_and(|token| <[u8; 32]>::from(Sha256::digest(token)) == token_hash) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("missing or wrong token"))
+        }
+    }
+}
+
+/// Serve the configured service at the given listener, as bound for the given listener config,
+/// with its TLS identity and token (if any).
+///
+/// # Errors
+/// If the TLS identity can't be read (or is invalid), or the token is missing.
+pub fn serve_listener<'a, S>(config: &'a Config, listener_config: &ListenerConfig, listener: Listener, services: &Services<S>)
+    -> Result<ServeFuture<'a>, Box<dyn Error>>
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let server = match &listener_config.tls {
+        Some(tls) => {
+            let tls_config = ServerTlsConfig::new()
+                .identity(Identity::from_pem(fs::read(&tls.cert_file)?, fs::read(&tls.key_file)?));
+            // With a client CA, every client must connect with a cert it issued (mTLS):
+            let tls_config = match &tls.client_ca {
+                Some(client_ca) => tls_config.client_ca_root(Certificate::from_pem(fs::read(client_ca)?)),
+                None => tls_config,
+            };
+            Server::builder().tls_config(tls_config)?
+        }
+        None => Server::builder(),
+    };
+    let auth = match (&listener_config.token_env, listener_config.service) {
+        (Some(var), _) => TokenAuth::required_from_env(var, &listener_config.name)?,
+        (None, ListenerService::Admin) => TokenAuth::for_admin_from_env(&config.admin_token_env, &listener_config.addr)?,
+        (None, _) => TokenAuth::new(None),
+    };
+    let (service, services) = (listener_config.service, services.clone());
+    println!("Serving the {:?} service at {} (listener '{}')", service, listener_config.addr, listener_config.name);
+    Ok(match listener {
+        Listener::Tcp(listener) => serve_service(config, server, services, auth, service, tcp_incoming(listener)?),
+        Listener::Unix(listener) => serve_service(config, server, services, auth, service, unix_incoming(listener)),
+    })
+}
+
+/// Serve the given service on the given incoming connections, with the given server.
+pub fn serve_service<'a, S, I, IO, IE>(config: &'a Config, server: Server, services: Services<S>, auth: TokenAuth,
+                                       service: ListenerService, incoming: I) -> ServeFuture<'a>
+    where S: TradeModelStore + Send + Sync + 'static,
+          I: Stream<Item=Result<IO, IE>> + Send + 'a,
+          IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+          IO::ConnectInfo: Clone + Send + Sync + 'static,
+          IE: Into<Box<dyn Error + Send + Sync>> + 'a
+{
+    match service {
+        ListenerService::MuSig => Box::pin(serve_musig(config, server, services, auth, incoming)),
+        ListenerService::Peer => Box::pin(serve_peer(config, server, services, auth, incoming)),
+        ListenerService::Admin => Box::pin(serve_admin(config, server, services.musig, auth, incoming)),
+    }
+}
+
+/// Serve the `MuSig` service, with the health service (open to all callers, for probes), wrapped in
+/// every layer for its clients.
+async fn serve_musig<S, I, IO, IE>(config: &Config, server: Server, services: Services<S>, auth: TokenAuth, incoming: I)
+    -> Result<(), tonic::transport::Error>
+    where S: TradeModelStore + Send + Sync + 'static,
+          I: Stream<Item=Result<IO, IE>>,
+          IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+          IO::ConnectInfo: Clone + Send + Sync + 'static,
+          IE: Into<Box<dyn Error + Send + Sync>>
+{
+    let trade_model_store = Arc::clone(&services.musig.trade_model_store);
+    let musig = MuSigServer::new(services.musig).max_decoding_message_size(config.decode_limits.max_message_len);
+    // Log calls turned away by the rate limit too:
+    let router = server
+        .accept_http1(config.grpc_web.enabled)
+        .http2_keepalive_interval(Some(HTTP2_KEEPALIVE_INTERVAL))
+        .http2_keepalive_timeout(Some(HTTP2_KEEPALIVE_TIMEOUT))
+        .layer(GrpcWebLayer::new(config.grpc_web.clone()))
+        .layer(ClientIdentityLayer)
+        .layer(CorrelationLayer)
+        .layer(LogLayer)
+        .layer(services.rate_limit)
+        .layer(TimeoutLayer::new(config.rpc_timeouts.clone()))
+        .layer(services.decode_limits)
+        .layer(OwnershipLayer::new(Arc::clone(&trade_model_store)))
+        .layer(StepOrderLayer::new(trade_model_store))
+        .add_service(InterceptedService::new(musig, auth))
+        .add_service(HealthServer::new(services.health));
+    #[cfg(feature = "demo")]
+    let router = router.add_service(crate::demo::GreeterServer::new(crate::demo::MyGreeter::default()));
+    router.serve_with_incoming(incoming).await
+}
+
+/// Serve the peer service apart from the `MuSig` service, as it must be reachable by our peers
+/// (held to the same decoding limits, as they are no more to be trusted).
+async fn serve_peer<S, I, IO, IE>(config: &Config, server: Server, services: Services<S>, auth: TokenAuth, incoming: I)
+    -> Result<(), tonic::transport::Error>
+    where S: TradeModelStore + Send + Sync + 'static,
+          I: Stream<Item=Result<IO, IE>>,
+          IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+          IO::ConnectInfo: Clone + Send + Sync + 'static,
+          IE: Into<Box<dyn Error + Send + Sync>>
+{
+    let peer = MuSigPeerServer::new(services.peer).max_decoding_message_size(config.decode_limits.max_message_len);
+    server
+        .layer(services.decode_limits)
+        .add_service(InterceptedService::new(peer, auth))
+        .serve_with_incoming(incoming)
+        .await
+}
+
+/// Serve the admin service apart from the `MuSig` service, as it is for the operators alone.
+async fn serve_admin<S, I, IO, IE>(config: &Config, server: Server, musig: MyMuSig<S>, auth: TokenAuth, incoming: I)
+    -> Result<(), tonic::transport::Error>
+    where S: TradeModelStore + Send + Sync + 'static,
+          I: Stream<Item=Result<IO, IE>>,
+          IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+          IO::ConnectInfo: Clone + Send + Sync + 'static,
+          IE: Into<Box<dyn Error + Send + Sync>>
+{
+    let admin = MyAdmin::new(musig, SecretKeySource::PassphraseEnv(config.snapshot_passphrase_env.clone()));
+    server
+        .layer(ClientIdentityLayer)
+        .layer(CorrelationLayer)
+        .layer(LogLayer)
+        .add_service(AdminServer::with_interceptor(admin, auth))
+        .serve_with_incoming(incoming)
+        .await
+}
+
+/// The incoming connections of the given TCP listener.
+///
+/// # Errors
+/// If the listener's address can't be had.
+pub fn tcp_incoming(listener: TcpListener) -> Result<TcpIncoming, Box<dyn Error>> {
+    TcpIncoming::from_listener(listener, false, None).map_err(|e| e as Box<dyn Error>)
+}
+
+fn unix_incoming(listener: UnixListener) -> impl Stream<Item=io::Result<tokio::net::UnixStream>> + Send {
+    futures::stream::unfold(listener, |listener| async move {
+        Some((listener.accept().await.map(|(stream, _)| stream), listener))
+    })
+}
@@ -0,0 +1,234 @@
+//! A load test of a running daemon (or a pair of them, for the buyer & seller), driving many trades
+//! at once through every step of the `MuSig` service, each closed cooperatively with its peer
+//! payloads relayed by the load test, then archived. It reports the throughput, the latency of each
+//! RPC at the median & tail, the failed calls and, given the address of the daemon's metrics, how
+//! much its memory grew over the run.
+//!
+//! The daemon's rate limits & trade quotas should be lifted first, else most calls will fail with
+//! `RESOURCE_EXHAUSTED`, as they would for a client misbehaving in earnest.
+
+use musig_trade_client::{ClientError, CloseTrade, GetNonceShares, GetPartialSignatures, InitTrade, PublishDepositTx,
+    RetryPolicy, SignDepositTx, SignSwapTx, TradeClient};
+use musig_trade_protocol::Role;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+use std::prelude::rust_2021::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::TcpStream;
+
+type Result<T, E = Box<dyn Error>> = std::result::Result<T, E>;
+
+const USAGE: &str = "usage: loadtest [--url <buyer's daemon>] [--seller-url <seller's daemon>] [--trades <n>] \
+    [--concurrency <n>] [--metrics-addr <host:port>]";
+
+struct Args {
+    buyer_url: String,
+    seller_url: Option<String>,
+    trades: u32,
+    concurrency: u32,
+    metrics_addr: Option<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item=String>) -> Result<Self> {
+        let mut parsed = Self {
+            buyer_url: "http://127.0.0.1:50051".to_owned(),
+            seller_url: None,
+            trades: 1000,
+            concurrency: 100,
+            metrics_addr: None,
+        };
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| format!("missing value for {}\n{}", arg, USAGE))?;
+            match &arg[..] {
+                "--url" => parsed.buyer_url = value,
+                "--seller-url" => parsed.seller_url = Some(value),
+                "--trades" => parsed.trades = value.parse()?,
+                "--concurrency" => parsed.concurrency = value.parse()?,
+                "--metrics-addr" => parsed.metrics_addr = Some(value),
+                _ => return Err(format!("unknown argument: {}\n{}", arg, USAGE).into()),
+            }
+        }
+        if parsed.concurrency == 0 {
+            return Err("concurrency must be at least 1".into());
+        }
+        Ok(parsed)
+    }
+}
+
+/// A failed call of the given RPC, ending the trade making it.
+struct Failure {
+    rpc: &'static str,
+    error: ClientError,
+}
+
+/// The latencies of the successful calls of each RPC, and the failed calls, of one or more trades.
+#[derive(Default)]
+struct Stats {
+    latencies: BTreeMap<&'static str, Vec<Duration>>,
+    failures: BTreeMap<(&'static str, String), u32>,
+    completed_trades: u32,
+}
+
+impl Stats {
+    async fn timed<T>(&mut self, rpc: &'static str, call: impl Future<Output=Result<T, ClientError>>) -> Result<T, Failure> {
+        let start = Instant::now();
+        let result = call.await;
+        let elapsed = start.elapsed();
+        match result {
+            Ok(value) => {
+                self.latencies.entry(rpc).or_default().push(elapsed);
+                Ok(value)
+            }
+            Err(error) => Err(Failure { rpc, error }),
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (rpc, latencies) in other.latencies {
+            self.latencies.entry(rpc).or_default().extend(latencies);
+        }
+        for (key, count) in other.failures {
+            *self.failures.entry(key).or_default() += count;
+        }
+        self.completed_trades += other.completed_trades;
+    }
+
+    fn record_failure(&mut self, failure: &Failure) {
+        let reason = match &failure.error {
+            ClientError::Status(status) => format!("{:?}", status.code()),
+            error => error.to_string(),
+        };
+        *self.failures.entry((failure.rpc, reason)).or_default() += 1;
+    }
+
+    fn report(&mut self, elapsed: Duration) {
+        println!("Completed {} trades in {:.2}s ({:.1} trades/s)", self.completed_trades, elapsed.as_secs_f64(),
+            f64::from(self.completed_trades) / elapsed.as_secs_f64());
+        println!("{:<22} {:>8} {:>10} {:>10} {:>10} {:>10}", "RPC", "calls", "p50 (ms)", "p90 (ms)", "p99 (ms)", "max (ms)");
+        for (rpc, latencies) in &mut self.latencies {
+            latencies.sort_unstable();
+            let ms = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+            println!("{:<22} {:>8} {:>10.2} {:>10.2} {:>10.2} {:>10.2}", rpc, latencies.len(), ms(50), ms(90), ms(99), ms(100));
+        }
+        for ((rpc, reason), count) in &self.failures {
+            println!("{} calls of {} failed: {}", count, rpc, reason);
+        }
+    }
+}
+
+/// Run a whole trade between the buyer's & seller's daemons, closed cooperatively then archived.
+/// The two sides get their own trade IDs, as they may be on the same daemon.
+async fn run_trade(buyer: &TradeClient, seller: &TradeClient, trade_id: &str, stats: &mut Stats) -> Result<(), Failure> {
+    let buyer_id = format!("{}-buyer", trade_id);
+    let seller_id = format!("{}-seller", trade_id);
+    let (buyer_id, seller_id) = (&buyer_id[..], &seller_id[..]);
+
+    let buyer_keys = stats.timed("InitTrade", buyer.init_trade(InitTrade::new(buyer_id, Role::BuyerAsTaker))).await?;
+    let seller_keys = stats.timed("InitTrade", seller.init_trade(InitTrade::new(seller_id, Role::SellerAsMaker))).await?;
+
+    let terms = |step: GetNonceShares| step.fee_rates(50.0, 40.0).amounts(200_000, 30_000, 30_000);
+    let step = terms(GetNonceShares::new(buyer_id).peers_key_shares(&seller_keys));
+    let buyer_nonces = stats.timed("GetNonceShares", buyer.get_nonce_shares(step)).await?;
+    let step = terms(GetNonceShares::new(seller_id).peers_key_shares(&buyer_keys));
+    let seller_nonces = stats.timed("GetNonceShares", seller.get_nonce_shares(step)).await?;
+
+    let step = GetPartialSignatures::new(buyer_id).peers_nonce_shares(&seller_nonces);
+    let buyer_sigs = stats.timed("GetPartialSignatures", buyer.get_partial_signatures(step)).await?;
+    let step = GetPartialSignatures::new(seller_id).peers_nonce_shares(&buyer_nonces);
+    let seller_sigs = stats.timed("GetPartialSignatures", seller.get_partial_signatures(step)).await?;
+
+    let step = SignDepositTx::new(seller_id).peers_partial_signatures(&buyer_sigs.redacted());
+    stats.timed("SignDepositTx", seller.sign_deposit_tx(step)).await?;
+    let step = SignDepositTx::new(buyer_id).peers_partial_signatures(&seller_sigs);
+    let deposit_psbt = stats.timed("SignDepositTx", buyer.sign_deposit_tx(step)).await?;
+
+    stats.timed("PublishDepositTx", async {
+        let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new(buyer_id).deposit_psbt(deposit_psbt)).await?;
+        while confirmations.message().await?.is_some() {}
+        Ok(())
+    }).await?;
+
+    stats.timed("ConfirmPaymentStarted", buyer.confirm_payment_started(buyer_id, None)).await?;
+    stats.timed("ConfirmPaymentReceived", seller.confirm_payment_received(seller_id, None)).await?;
+    let step = SignSwapTx::new(seller_id).peers_partial_signatures(&buyer_sigs);
+    let swap_tx = stats.timed("SignSwapTx", seller.sign_swap_tx(step)).await?;
+    let step = CloseTrade::new(buyer_id).peers_prv_key_share(&swap_tx.peer_output_prv_key_share);
+    let buyer_key = stats.timed("CloseTrade", buyer.close_trade(step)).await?;
+    stats.timed("CloseTrade", seller.close_trade(CloseTrade::new(seller_id).peers_prv_key_share(&buyer_key))).await?;
+
+    stats.timed("ArchiveTrade", buyer.archive_trade(buyer_id, None)).await?;
+    stats.timed("ArchiveTrade", seller.archive_trade(seller_id, None)).await?;
+    Ok(())
+}
+
+/// Run trades one after another, taking the next free trade number each time, until all the
+/// trades have been taken.
+async fn run_worker(buyer: TradeClient, seller: TradeClient, run_id: u64, next_trade: Arc<AtomicU32>, trades: u32) -> Stats {
+    let mut stats = Stats::default();
+    loop {
+        let i = next_trade.fetch_add(1, Ordering::Relaxed);
+        if i >= trades {
+            return stats;
+        }
+        let trade_id = format!("loadtest-{}-{}", run_id, i);
+        match Box::pin(run_trade(&buyer, &seller, &trade_id, &mut stats)).await {
+            Ok(()) => stats.completed_trades += 1,
+            Err(failure) => stats.record_failure(&failure),
+        }
+    }
+}
+
+/// Scrape the resident memory size of the daemon from its metrics, if it reports it.
+async fn resident_memory_bytes(metrics_addr: &str) -> Result<Option<u64>> {
+    let mut stream = TcpStream::connect(metrics_addr).await?;
+    stream.write_all(format!("GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", metrics_addr).as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response.lines()
+        .find_map(|line| line.strip_prefix("process_resident_memory_bytes "))
+        .map(str::parse)
+        .transpose()?)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse(std::env::args().skip(1))?;
+    // Fail fast, so that the latencies are those of the daemon rather than of the backoff:
+    let buyer = TradeClient::connect(args.buyer_url.clone()).await?.with_retry_policy(RetryPolicy::never());
+    let seller = match args.seller_url {
+        Some(url) => TradeClient::connect(url).await?.with_retry_policy(RetryPolicy::never()),
+        None => buyer.clone(),
+    };
+    let memory_before = match &args.metrics_addr {
+        Some(addr) => resident_memory_bytes(addr).await?,
+        None => None,
+    };
+    let run_id = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    println!("Running {} trades, {} at a time, against {}", args.trades, args.concurrency, args.buyer_url);
+
+    let start = Instant::now();
+    let next_trade = Arc::new(AtomicU32::new(0));
+    let workers: Vec<_> = (0..args.concurrency.min(args.trades))
+        .map(|_| tokio::spawn(run_worker(buyer.clone(), seller.clone(), run_id, Arc::clone(&next_trade), args.trades)))
+        .collect();
+    let mut stats = Stats::default();
+    for worker in workers {
+        stats.merge(worker.await?);
+    }
+    stats.report(start.elapsed());
+
+    if let (Some(addr), Some(before)) = (&args.metrics_addr, memory_before) {
+        if let Some(after) = resident_memory_bytes(addr).await? {
+            #[expect(clippy::cast_precision_loss, reason = "only printed to the nearest tenth of a MiB")]
+            let mib = |bytes: u64| bytes as f64 / f64::from(1 << 20);
+            println!("Daemon's resident memory grew from {:.1} MiB to {:.1} MiB ({:+.1} MiB)", mib(before), mib(after),
+                mib(after) - mib(before));
+        }
+    }
+    Ok(())
+}
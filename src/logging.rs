@@ -0,0 +1,230 @@
+//! Logging of the calls to the `MuSig` service: a layer logging the method, correlation ID, client,
+//! outcome & duration of every call, and the redaction of the byte fields of any logged request,
+//! these being the keys, nonces & signatures (or the txs & PSBTs) of the trades. Unless
+//! `log_sensitive` is set, each is shown only by its length and a hash prefix, enough to match up
+//! equal values across log lines.
+
+use http_body::{Body, Frame, SizeHint};
+use musig_trade_protocol::status;
+use sha2::{Digest as _, Sha256};
+use std::fmt::{self, Write as _};
+use std::future::Future;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::codegen::http::{Request, Response};
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::client_identity::ClientIdentity;
+use crate::correlation::CORRELATION_ID_KEY;
+
+static LOG_SENSITIVE: AtomicBool = AtomicBool::new(false);
+
+/// Set whether to log the byte fields of requests in full (as hex), rather than redacted, returning
+/// whether they were before.
+pub fn set_log_sensitive(log_sensitive: bool) -> bool {
+    LOG_SENSITIVE.swap(log_sensitive, Ordering::Relaxed)
+}
+
+/// The debug output of the given value (such as a gRPC request), with every byte field redacted.
+///
+/// The byte fields are found in the output itself, as every list of integers in the range of a
+/// byte, as that is how a `Vec<u8>` is shown. This may catch a list of small integers of another
+/// type, but ensures that no byte field of any message is ever missed.
+pub fn debug_for_log(value: &impl fmt::Debug) -> String {
+    let debug = format!("{:?}", value);
+    let log_sensitive = LOG_SENSITIVE.load(Ordering::Relaxed);
+    let mut out = String::with_capacity(debug.len());
+    let mut rest = &debug[..];
+    while let Some(i) = rest.find(['[', '"']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let len = if rest.starts_with('"') {
+            // Pass string literals through untouched, as any brackets in them aren't lists:
+            let len = string_literal_len(rest);
+            out.push_str(&rest[..len]);
+            len
+        } else if let Some((bytes, len)) = parse_byte_list(rest) {
+            write_bytes(&mut out, &bytes, log_sensitive);
+            len
+        } else {
+            out.push('[');
+            1
+        };
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn string_literal_len(s: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// Parse a nonempty list of bytes at the start of the given string, as shown by `{:?}`, returning
+/// the bytes and the length of the list.
+fn parse_byte_list(s: &str) -> Option<(Vec<u8>, usize)> {
+    let end = s.find(']')?;
+    let bytes = s[1..end].split(", ").map(|n| n.parse().ok()).collect::<Option<Vec<u8>>>()?;
+    Some((bytes, end + 1))
+}
+
+fn write_bytes(out: &mut String, bytes: &[u8], log_sensitive: bool) {
+    if log_sensitive {
+        out.push_str("0x");
+        for b in bytes {
+            write!(out, "{:02x}", b).unwrap();
+        }
+    } else {
+        write!(out, "<{} bytes, sha256: ", bytes.len()).unwrap();
+        for b in &Sha256::digest(bytes)[..4] {
+            write!(out, "{:02x}", b).unwrap();
+        }
+        out.push('>');
+    }
+}
+
+/// A layer logging every call, once its outcome is known: when the response is sent, for a failure
+/// without a response message, and otherwise when the (possibly streamed) response is finished.
+#[derive(Clone, Copy)]
+pub struct LogLayer;
+
+impl<S> Layer<S> for LogLayer {
+    type Service = Log<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Log { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct Log<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for Log<S>
+    where S: Service<Request<B>, Response=Response<ResBody>>,
+          S::Future: Send + 'static
+{
+    type Response = Response<LoggedBody<ResBody>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let call = Call {
+            method: req.uri().path().to_owned(),
+            client: req.extensions().get::<ClientIdentity>().cloned(),
+            correlation_id: req.headers().get(CORRELATION_ID_KEY).and_then(|value| value.to_str().ok()).map(str::to_owned),
+            started: Instant::now(),
+        };
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let response = response.await;
+            // A response with the status in its headers has no message, so the call is over:
+            let status = response.as_ref().map_or_else(|_| Some(Status::unknown("service error")),
+                |response| Status::from_header_map(response.headers()));
+            let call = if let Some(status) = status {
+                call.finish_with(Some(status));
+                None
+            } else {
+                Some(call)
+            };
+            response.map(|response| response.map(|inner| LoggedBody { inner, call }))
+        })
+    }
+}
+
+struct Call {
+    method: String,
+    client: Option<ClientIdentity>,
+    correlation_id: Option<String>,
+    started: Instant,
+}
+
+impl Call {
+    fn finish_with(&self, status: Option<Status>) {
+        match status {
+            Some(status) => self.finish(&format!("{:?}", status.code()), &message_in_context(&status)),
+            None => self.finish("no status", ""),
+        }
+    }
+
+    fn finish(&self, outcome: &str, msg: &str) {
+        let client = self.client.as_ref().map_or_else(|| "unknown client".to_owned(), ToString::to_string);
+        let msg = if msg.is_empty() { String::new() } else { format!(" ({})", msg) };
+        let correlation_id = self.correlation_id.as_ref().map_or_else(String::new, |id| format!(" [{}]", id));
+        println!("Call to {}{} from {}: {}{} in {:?}", self.method, correlation_id, client, outcome, msg,
+            self.started.elapsed());
+    }
+}
+
+/// The message of the given status, followed by where in the trade the call failed, as given by
+/// the `ErrorInfo` in its details (if any), to be pinpointed from the log line alone.
+fn message_in_context(status: &Status) -> String {
+    let Some(info) = status::error_info(status) else { return status.message().to_owned() };
+    let context: Vec<_> = [status::TRADE_ID_KEY, status::PHASE_KEY, status::INPUT_KEY].into_iter()
+        .filter_map(|key| Some(format!("{} {}", key, info.metadata.get(key)?)))
+        .collect();
+    format!("{} [{}; {}]", status.message(), info.reason, context.join(", "))
+}
+
+/// A response body logging the call it is for once finished, by the status in its trailers.
+pub struct LoggedBody<B> {
+    inner: B,
+    call: Option<Call>,
+}
+
+impl<B: Body + Unpin> Body for LoggedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        let call = match &frame {
+            Poll::Ready(Some(Ok(frame))) if frame.is_data() => None,
+            Poll::Ready(_) => self.call.take(),
+            Poll::Pending => None,
+        };
+        if let Some(call) = call {
+            match &frame {
+                Poll::Ready(Some(Ok(frame))) => call.finish_with(frame.trailers_ref().and_then(Status::from_header_map)),
+                Poll::Ready(Some(Err(_))) => call.finish("failed", "response body error"),
+                _ => call.finish_with(None),
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for LoggedBody<B> {
+    fn drop(&mut self) {
+        if let Some(call) = &self.call {
+            call.finish("cancelled", "");
+        }
+    }
+}
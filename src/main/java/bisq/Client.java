@@ -1,7 +1,9 @@
 package bisq;
 
+import com.google.protobuf.Duration;
+import com.google.protobuf.Timestamp;
 import helloworld.GreeterGrpc;
-import helloworld.Helloworld;
+import helloworld.GreeterProto;
 import io.grpc.Grpc;
 import io.grpc.InsecureChannelCredentials;
 
@@ -16,17 +18,31 @@ public class Client {
         ).build();
 
         var stub = GreeterGrpc.newBlockingStub(channel);
-        var reply = stub.sayHello(Helloworld.HelloRequest.newBuilder()
+        var reply = stub.sayHello(GreeterProto.HelloRequest.newBuilder()
                 .setName("Hello from Java")
                 .build());
         System.out.println("Got reply: " + reply);
 
-        var iter = stub.subscribeClock(Helloworld.ClockRequest.newBuilder()
-                .setTickPeriodMillis(5000)
+        // Take the server's wall-clock time as read halfway through the call:
+        long sentAt = System.currentTimeMillis();
+        var serverTime = stub.getServerTime(GreeterProto.ServerTimeRequest.getDefaultInstance());
+        long receivedAt = System.currentTimeMillis();
+        long serverTimeMillis = toInstant(serverTime.getCurrentTime()).toEpochMilli();
+        System.out.println("Server clock offset: " +
+                (serverTimeMillis - (sentAt + receivedAt) / 2) + " ms, give or take " +
+                (receivedAt - sentAt + 1) / 2 + " ms");
+
+        var iter = stub.subscribeClock(GreeterProto.ClockRequest.newBuilder()
+                .setTickPeriod(Duration.newBuilder().setSeconds(5))
+                .setMaxTicks(5)
                 .build());
         iter.forEachRemaining(tickEvent -> System.out.println("Got tick: " +
-                Instant.ofEpochMilli(tickEvent.getCurrentTimeMillis())));
+                toInstant(tickEvent.getCurrentTime())));
 
         System.out.println("Hello, world!");
     }
+
+    private static Instant toInstant(Timestamp timestamp) {
+        return Instant.ofEpochSecond(timestamp.getSeconds(), timestamp.getNanos());
+    }
 }
@@ -17,6 +17,11 @@ public class TradeProtocolClient {
         testMusigService_twoParties(musigStub, 0, ClosureType.COOPERATIVE);
         testMusigService_twoParties(musigStub, 1, ClosureType.UNCOOPERATIVE);
 
+        var archivedTrades = musigStub.listTrades(Helloworld.ListTradesRequest.newBuilder()
+                .setArchived(true)
+                .build());
+        System.out.println("Got reply: " + archivedTrades);
+
         channel.shutdown();
     }
 
@@ -59,6 +64,8 @@ public class TradeProtocolClient {
                 .setTradeId(sellerTradeId)
                 .setBuyerOutputPeersPubKeyShare(buyerPubKeyShareResponse.getBuyerOutputPubKeyShare())
                 .setSellerOutputPeersPubKeyShare(buyerPubKeyShareResponse.getSellerOutputPubKeyShare())
+                .setPeersIdentityPubKey(buyerPubKeyShareResponse.getIdentityPubKey())
+                .setPeersPubKeySharesIdentitySignature(buyerPubKeyShareResponse.getIdentitySignature())
                 .setDepositTxFeeRate(12.5)
                 .setPreparedTxFeeRate(10.0)
                 .setTradeAmount(200000)
@@ -73,6 +80,8 @@ public class TradeProtocolClient {
                 .setTradeId(buyerTradeId)
                 .setBuyerOutputPeersPubKeyShare(sellerPubKeyShareResponse.getBuyerOutputPubKeyShare())
                 .setSellerOutputPeersPubKeyShare(sellerPubKeyShareResponse.getSellerOutputPubKeyShare())
+                .setPeersIdentityPubKey(sellerPubKeyShareResponse.getIdentityPubKey())
+                .setPeersPubKeySharesIdentitySignature(sellerPubKeyShareResponse.getIdentitySignature())
                 .setDepositTxFeeRate(12.5)
                 .setPreparedTxFeeRate(10.0)
                 .setTradeAmount(200000)
@@ -97,8 +106,10 @@ public class TradeProtocolClient {
 
         var sellerDepositPsbt = stub.signDepositTx(Helloworld.DepositTxSignatureRequest.newBuilder()
                 .setTradeId(sellerTradeId)
-                // REDACT buyer's swapTxInputPartialSignature:
-                .setPeersPartialSignatures(buyerPartialSignatureMessage.toBuilder().clearSwapTxInputPartialSignature())
+                // REDACT buyer's swapTxInputPartialSignature (and its identity signature):
+                .setPeersPartialSignatures(buyerPartialSignatureMessage.toBuilder()
+                        .setSwapTxInputPartialSignatureRedacted(Helloworld.Redacted.getDefaultInstance())
+                        .clearSwapTxInputIdentitySignature())
                 .build());
         System.out.println("Got reply: " + sellerDepositPsbt);
 
@@ -117,12 +128,23 @@ public class TradeProtocolClient {
         depositTxConfirmationIter.forEachRemaining(reply -> System.out.println("Got reply: " + reply));
         // ***********************************
 
+        var paymentStartedReceipt = stub.confirmPaymentStarted(Helloworld.ConfirmPaymentRequest.newBuilder()
+                .setTradeId(buyerTradeId)
+                .build());
+        System.out.println("Got reply: " + paymentStartedReceipt);
+
         // Buyer sends Message E to seller.
 
+        var paymentReceivedReceipt = stub.confirmPaymentReceived(Helloworld.ConfirmPaymentRequest.newBuilder()
+                .setTradeId(sellerTradeId)
+                .build());
+        System.out.println("Got reply: " + paymentReceivedReceipt);
+
         var swapTxSignatureResponse = stub.signSwapTx(Helloworld.SwapTxSignatureRequest.newBuilder()
                 .setTradeId(sellerTradeId)
                 // NOW send the redacted buyer's swapTxInputPartialSignature:
                 .setSwapTxInputPeersPartialSignature(buyerPartialSignatureMessage.getSwapTxInputPartialSignature())
+                .setSwapTxInputPeersIdentitySignature(buyerPartialSignatureMessage.getSwapTxInputIdentitySignature())
                 .build());
         System.out.println("Got reply: " + swapTxSignatureResponse);
 
@@ -168,5 +190,13 @@ public class TradeProtocolClient {
             System.out.println("Got reply: " + buyersCloseTradeResponse);
             // **************************
         }
+
+        // Both traders archive their closed trades, discarding all the secret key material.
+        for (String tradeId : new String[]{buyerTradeId, sellerTradeId}) {
+            var tradeSummary = stub.archiveTrade(Helloworld.ArchiveTradeRequest.newBuilder()
+                    .setTradeId(tradeId)
+                    .build());
+            System.out.println("Got reply: " + tradeSummary);
+        }
     }
 }
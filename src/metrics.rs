@@ -0,0 +1,151 @@
+//! Metrics for operators to scrape and alert on, served over plain HTTP in the Prometheus text
+//! exposition format. Every request gets the metrics in reply, whatever its method or path.
+
+use musig_trade_protocol::{TradeModel, TradeModelStore};
+use std::fmt::Write as _;
+use std::{fs, io};
+use std::net::SocketAddr;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::quota::QuotaStore;
+
+/// The most bytes of request head read before replying, so that a client cannot hold us up by
+/// sending one without end.
+const MAX_REQUEST_HEAD_LEN: u64 = 8192;
+
+/// The upper bounds (in seconds) of the buckets of the trade duration histograms, from a second to a
+/// week, as trades wait on their traders (and the off-chain payment) as much as on the daemon.
+const DURATION_BUCKETS: [f64; 10] = [1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14_400.0, 86_400.0, 604_800.0];
+
+/// The milestones of a trade timed from its opening, by the name & help of their histograms.
+const MILESTONES: [(&str, &str); 3] = [
+    ("musig_trade_nonce_exchange_seconds", "Time from opening a trade to exchanging nonce shares with the peer."),
+    ("musig_trade_deposit_confirmation_seconds", "Time from opening a trade to its deposit tx being taken as mined."),
+    ("musig_trade_close_seconds", "Time from opening a trade to closing it."),
+];
+
+/// The time taken by the trades run by the daemon to reach each milestone, as observed on the first
+/// completion of the steps reaching them.
+pub static TRADE_DURATIONS: TradeDurations = TradeDurations::new();
+
+pub struct TradeDurations {
+    histograms: Mutex<[Histogram; MILESTONES.len()]>,
+}
+
+#[derive(Clone, Copy)]
+struct Histogram {
+    /// The number of observations in each bucket (not cumulatively), then of those over every bound.
+    counts: [u64; DURATION_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl TradeDurations {
+    pub const fn new() -> Self {
+        Self { histograms: Mutex::new([Histogram { counts: [0; DURATION_BUCKETS.len() + 1], sum: 0.0 }; MILESTONES.len()]) }
+    }
+
+    /// Observe the time the given trade took to reach the milestone of the given step, which it has
+    /// just completed for the first time, if that step reaches one. Dry runs are left out, not
+    /// being trades between real traders.
+    pub fn observe(&self, trade_model: &TradeModel, step: &str) {
+        let milestone = match step {
+            // Nonce shares committed to are only exchanged once revealed:
+            "GetNonceShares" if !trade_model.commit_to_nonces => 0,
+            "RevealNonceShares" => 0,
+            // Until the deposit tx is followed on the chain, it is taken to be mined once published:
+            "PublishDepositTx" => 1,
+            "CloseTrade" => 2,
+            _ => return,
+        };
+        let completed_at = trade_model.step_completed_at.iter().find(|(completed, _)| completed == step);
+        let (Some(created_at), Some((_, completed_at))) = (trade_model.created_at(), completed_at) else { return };
+        if trade_model.dry_run {
+            return;
+        }
+        let seconds = completed_at.duration_since(created_at).unwrap_or_default().as_secs_f64();
+        let bucket = DURATION_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(DURATION_BUCKETS.len());
+        let mut histograms = self.histograms.lock().unwrap_or_else(PoisonError::into_inner);
+        histograms[milestone].counts[bucket] += 1;
+        histograms[milestone].sum += seconds;
+        drop(histograms);
+    }
+
+    pub fn write(&self, out: &mut String) {
+        let histograms = *self.histograms.lock().unwrap_or_else(PoisonError::into_inner);
+        for ((name, help), histogram) in MILESTONES.iter().zip(histograms) {
+            writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name).unwrap();
+            let mut count = 0;
+            for (bound, bucket_count) in DURATION_BUCKETS.iter().zip(histogram.counts) {
+                count += bucket_count;
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+            }
+            count += histogram.counts[DURATION_BUCKETS.len()];
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}\n{}_sum {}\n{}_count {}", name, count, name, histogram.sum, name, count)
+                .unwrap();
+        }
+    }
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value).unwrap();
+}
+
+/// The resident set size of the process in bytes, where the OS reports it (in `/proc`, on Linux).
+fn resident_memory_bytes() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?.trim().strip_suffix("kB")?;
+    Some(kib.trim().parse::<usize>().ok()? * 1024)
+}
+
+fn render<S: TradeModelStore>(store: &QuotaStore<S>) -> String {
+    let mut out = String::new();
+    write_gauge(&mut out, "musig_open_trades", "The number of open (live) trades.", store.open_trade_count());
+    write_gauge(&mut out, "musig_max_open_trades_per_client", "The most open trades of any one client.",
+        store.max_open_trade_count_per_client());
+    let config = store.config();
+    if let Some(max) = config.max_open_trades {
+        write_gauge(&mut out, "musig_open_trades_limit", "The limit on open trades.", max);
+    }
+    if let Some(max) = config.max_open_trades_per_client {
+        write_gauge(&mut out, "musig_open_trades_per_client_limit", "The limit on open trades per client.", max);
+    }
+    if let Some(bytes) = resident_memory_bytes() {
+        write_gauge(&mut out, "process_resident_memory_bytes", "The resident memory size in bytes.", bytes);
+    }
+    TRADE_DURATIONS.write(&mut out);
+    out
+}
+
+/// Serve the metrics at the given address. This only returns if the listener fails.
+pub async fn serve_metrics<S>(addr: SocketAddr, store: Arc<QuotaStore<S>>) -> io::Result<()>
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let store = Arc::clone(&store);
+        tokio::spawn(async move {
+            if let Err(e) = reply(stream, &store).await {
+                println!("Could not serve metrics: {}", e);
+            }
+        });
+    }
+}
+
+async fn reply<S: TradeModelStore>(stream: TcpStream, store: &QuotaStore<S>) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    // Skip the request line & headers, up to the blank line ending them:
+    let mut head = (&mut stream).take(MAX_REQUEST_HEAD_LEN);
+    let mut line = String::new();
+    while head.read_line(&mut line).await? != 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+    let body = render(store);
+    let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}", body.len(), body);
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
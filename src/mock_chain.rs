@@ -0,0 +1,152 @@
+//! A mock chain backend, for tests, demos and dry-run trades: a chain held in memory, its blocks
+//! mined on demand (or on a timer), on which each tx broadcast is mined a set number of blocks
+//! later, and into which reorgs & double-spends may be injected. It stands in for both halves of a
+//! real chain backend: the chain tip followed by the daemon, and the broadcaster of its txs.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::chain::{ChainTip, TxBroadcaster, TxStatus};
+
+pub struct MockChainBackend {
+    chain_tip: ChainTip,
+    chain: Mutex<MockChain>,
+}
+
+struct MockChain {
+    height: u32,
+    /// The number of blocks after the tip at broadcast that each tx is mined in, unless scheduled
+    /// otherwise: 0 to mine it in the block at the tip, as on the simulated chain.
+    confirmation_blocks: u32,
+    /// The number of blocks to mine particular txs in once broadcast, in place of the default.
+    schedules: HashMap<Vec<u8>, u32>,
+    /// The txs broadcast and not double-spent, by the height they are (or are to be) mined at.
+    txs: HashMap<Vec<u8>, u32>,
+    double_spent: HashSet<Vec<u8>>,
+}
+
+impl MockChainBackend {
+    /// A mock chain with its tip at the given height, mining each tx broadcast the given number of
+    /// blocks after the tip.
+    pub fn new(height: u32, confirmation_blocks: u32) -> Self {
+        let chain = MockChain {
+            height, confirmation_blocks,
+            schedules: HashMap::new(),
+            txs: HashMap::new(),
+            double_spent: HashSet::new(),
+        };
+        Self { chain_tip: ChainTip::fixed(height), chain: Mutex::new(chain) }
+    }
+
+    fn chain(&self) -> MutexGuard<'_, MockChain> {
+        self.chain.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The tip of the mock chain, for the daemon to follow.
+    pub fn chain_tip(&self) -> ChainTip {
+        self.chain_tip.clone()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.chain().height
+    }
+
+    /// Mine the given number of (empty, but for the txs due) blocks on top of the tip.
+    pub fn mine(&self, blocks: u32) {
+        let height = {
+            let mut chain = self.chain();
+            chain.height = chain.height.saturating_add(blocks);
+            chain.height
+        };
+        self.chain_tip.observe(height);
+    }
+
+    /// Mine the given tx the given number of blocks after it is broadcast, rather than after the
+    /// default number. If it is already broadcast and unconfirmed, it is mined that number of blocks
+    /// after the tip instead.
+    pub fn schedule_confirmation(&self, tx: &[u8], blocks: u32) {
+        let mut chain = self.chain();
+        let height = chain.height;
+        if let Some(mined_at) = chain.txs.get_mut(tx).filter(|mined_at| **mined_at > height) {
+            *mined_at = height.saturating_add(blocks);
+        }
+        chain.schedules.insert(tx.to_vec(), blocks);
+    }
+
+    /// Replace the given number of blocks at the tip with one more new ones, as for a reorg onto a
+    /// longer fork. The txs mined in the blocks replaced drop back into the mempool, losing all of
+    /// their confirmations, to be mined again in the next block.
+    pub fn reorg(&self, depth: u32) {
+        let height = {
+            let mut chain = self.chain();
+            let fork_height = chain.height.saturating_sub(depth);
+            chain.height = fork_height.saturating_add(depth + 1);
+            let next_height = chain.height.saturating_add(1);
+            for mined_at in chain.txs.values_mut().filter(|mined_at| **mined_at > fork_height) {
+                *mined_at = (*mined_at).max(next_height);
+            }
+            chain.height
+        };
+        self.chain_tip.observe(height);
+    }
+
+    /// Double-spend the given tx: a tx conflicting with it is mined in a new block at once, so that
+    /// it drops out of the chain (or mempool), if there, and any later broadcast of it fails.
+    pub fn double_spend(&self, tx: &[u8]) {
+        let height = {
+            let mut chain = self.chain();
+            chain.txs.remove(tx);
+            chain.double_spent.insert(tx.to_vec());
+            chain.height = chain.height.saturating_add(1);
+            chain.height
+        };
+        self.chain_tip.observe(height);
+    }
+
+    /// Take in the given tx, as by [`TxBroadcaster::broadcast`], but without waiting. Broadcasting a
+    /// tx again leaves it be.
+    ///
+    /// # Errors
+    /// If the tx has been double-spent.
+    pub fn submit(&self, tx: &[u8]) -> io::Result<()> {
+        let mut chain = self.chain();
+        if chain.double_spent.contains(tx) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "tx conflicts with one already mined"));
+        }
+        let blocks = chain.schedules.get(tx).copied().unwrap_or(chain.confirmation_blocks);
+        let mined_at = chain.height.saturating_add(blocks);
+        chain.txs.entry(tx.to_vec()).or_insert(mined_at);
+        drop(chain);
+        Ok(())
+    }
+
+    /// Mine a block every given interval, for demos of a chain moving on by itself. This never
+    /// returns.
+    pub async fn produce_blocks(self: Arc<Self>, interval: Duration) {
+        let mut interval = time::interval_at(time::Instant::now() + interval, interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.mine(1);
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl TxBroadcaster for MockChainBackend {
+    async fn broadcast(&self, tx: &[u8]) -> io::Result<()> {
+        self.submit(tx)
+    }
+
+    fn tx_status(&self, tx: &[u8]) -> TxStatus {
+        let chain = self.chain();
+        match chain.txs.get(tx) {
+            Some(&mined_at) if mined_at <= chain.height => TxStatus::Mined(mined_at),
+            _ => TxStatus::Unconfirmed,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! A Noise channel between our daemon and the peer's, for the payloads relayed between them by the
+//! front-ends, so that these only see opaque blobs which they can neither read nor alter.
+//!
+//! Each payload is sealed as the one message of a `Noise_K_25519_ChaChaPoly_SHA256` handshake
+//! (`-> e, es, ss`), from the sender's static key to the receiver's, both known in advance. The
+//! static keys are X25519 keys, each derived from the party's identity key and handed out with its
+//! key shares, signed by the identity key. The handshake is run by `snow`, to the Noise spec, so a
+//! peer may just as well seal & open payloads with any other Noise implementation.
+
+use snow::params::{DHChoice, NoiseParams};
+use snow::resolvers::{CryptoResolver as _, DefaultResolver};
+use snow::{Builder, Error};
+use std::prelude::rust_2021::*;
+
+const PARAMS: &str = "Noise_K_25519_ChaChaPoly_SHA256";
+/// The most a Noise message may hold, payload, ephemeral key & tag included.
+const MAX_MESSAGE_LEN: usize = 65_535;
+
+fn builder(prologue: &[u8]) -> Builder<'_> {
+    let params: NoiseParams = PARAMS.parse().expect("valid Noise protocol name");
+    Builder::new(params).prologue(prologue)
+}
+
+/// The X25519 public key of the given static private key, to hand out to the peer.
+pub fn static_pub_key(prv_key: &[u8; 32]) -> [u8; 32] {
+    let mut dh = DefaultResolver.resolve_dh(&DHChoice::Curve25519).expect("X25519 is supported");
+    dh.set(prv_key);
+    dh.pubkey().try_into().expect("X25519 public keys are 32 bytes")
+}
+
+/// Seal the payload for the holder of `their_static`, from the holder of `my_static` (our static
+/// private key). The prologue (which says what the payload is) must match the one it is opened
+/// with.
+///
+/// # Errors
+///
+/// Fails if the payload is too large for a single Noise message, of at most 64 KiB.
+pub fn seal(prologue: &[u8], my_static: &[u8; 32], their_static: &[u8; 32], payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut handshake = builder(prologue).local_private_key(my_static).remote_public_key(their_static)
+        .build_initiator()?;
+    let mut message = vec![0; MAX_MESSAGE_LEN];
+    let len = handshake.write_message(payload, &mut message)?;
+    message.truncate(len);
+    Ok(message)
+}
+
+/// Open a payload sealed by [`seal`], from the holder of `their_static` to that of `my_static` (our
+/// static private key), returning `None` if it is malformed or isn't authentic.
+pub fn open(prologue: &[u8], my_static: &[u8; 32], their_static: &[u8; 32], message: &[u8]) -> Option<Vec<u8>> {
+    let mut handshake = builder(prologue).local_private_key(my_static).remote_public_key(their_static)
+        .build_responder().ok()?;
+    let mut payload = vec![0; message.len()];
+    let len = handshake.read_message(message, &mut payload).ok()?;
+    payload.truncate(len);
+    Some(payload)
+}
@@ -0,0 +1,59 @@
+//! A rolling index of the public nonces our peers have sent us, across every trade, to catch a peer
+//! sending the same nonce twice: a sign of a broken (or malicious) implementation, which leaks its
+//! key shares through the signatures made with a reused nonce (and with them, the protocol's
+//! assumptions). It is held in memory only, so starts out empty each time the daemon is run.
+
+use musig2::PubNonce;
+use std::collections::{HashMap, VecDeque};
+use std::prelude::rust_2021::*;
+use std::sync::{Mutex, PoisonError};
+
+pub struct PeerNonceIndex {
+    capacity: usize,
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    /// The trade each nonce was first seen in, by the serialized nonce.
+    trade_ids: HashMap<[u8; 66], String>,
+    /// The nonces in the order seen, to forget the oldest first.
+    order: VecDeque<[u8; 66]>,
+}
+
+impl PeerNonceIndex {
+    /// An index remembering up to the given number of the nonces seen most recently, forgetting the
+    /// oldest to make room for more.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, seen: Mutex::default() }
+    }
+
+    /// Note the given nonces as sent by the peer of the given trade, returning the id of another
+    /// trade in which one of them was already seen, if any. Nonces seen again in the same trade
+    /// (as when a step is retried with the same payload) are left be.
+    pub fn record<'a>(&self, trade_id: &str, nonces: impl IntoIterator<Item=&'a PubNonce>) -> Option<String> {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut reused_from = None;
+        for nonce in nonces {
+            let nonce = nonce.serialize();
+            match seen.trade_ids.get(&nonce) {
+                Some(first_trade_id) if first_trade_id != trade_id => {
+                    reused_from.get_or_insert_with(|| first_trade_id.clone());
+                }
+                Some(_) => {}
+                None if self.capacity == 0 => {}
+                None => {
+                    if seen.order.len() == self.capacity {
+                        if let Some(oldest) = seen.order.pop_front() {
+                            seen.trade_ids.remove(&oldest);
+                        }
+                    }
+                    seen.trade_ids.insert(nonce, trade_id.to_owned());
+                    seen.order.push_back(nonce);
+                }
+            }
+        }
+        drop(seen);
+        reused_from
+    }
+}
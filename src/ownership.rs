@@ -0,0 +1,125 @@
+//! The ownership of the trades by the clients opening them: a call for a live trade opened by some
+//! other client (as told apart by their [`ClientIdentity`]) is turned away at the RPC layer with
+//! `PERMISSION_DENIED`, before the service looks the trade up. A trade opened by a client of unknown
+//! identity (over a Unix socket without mTLS, say) may be called for by any client, as may a trade
+//! once archived, as its summary doesn't record who opened it.
+
+use musig_proto::helloworld::ProtocolDescriptorRequest;
+use musig_trade_protocol::{lock_trade_model, TradeModelStore};
+use prost::Message as _;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::client_identity::ClientIdentity;
+use crate::step_order::{self, TradeIdOnly};
+
+const SERVICE_PATH_PREFIX: &str = "/helloworld.MuSig/";
+/// The RPCs of the `MuSig` service which aren't for any one live trade. Every other has the ID of
+/// its trade as the first field of its request message, bar `GetProtocolDescriptor`.
+const TRADELESS_RPCS: [&str; 4] = ["InitTrade", "ListTrades", "GetServiceInfo", "GetWalletStatus"];
+
+/// A layer turning away calls for the trades opened by other clients, by the record of who opened
+/// each trade in the given store.
+pub struct OwnershipLayer<S>(Arc<S>);
+
+impl<S> OwnershipLayer<S> {
+    pub const fn new(store: Arc<S>) -> Self {
+        Self(store)
+    }
+}
+
+impl<S> Clone for OwnershipLayer<S> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<Svc, S> Layer<Svc> for OwnershipLayer<S> {
+    type Service = Ownership<Svc, S>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        Ownership { inner, store: Arc::clone(&self.0) }
+    }
+}
+
+pub struct Ownership<Svc, S> {
+    inner: Svc,
+    store: Arc<S>,
+}
+
+impl<Svc: Clone, S> Clone for Ownership<Svc, S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), store: Arc::clone(&self.store) }
+    }
+}
+
+impl<Svc, S> Service<Request<BoxBody>> for Ownership<Svc, S>
+    where Svc: Service<Request<BoxBody>, Response=Response<BoxBody>> + Clone + Send + 'static,
+          Svc::Future: Send,
+          S: TradeModelStore + Send + Sync + 'static
+{
+    type Response = Response<BoxBody>;
+    type Error = Svc::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        let Some(rpc) = req.uri().path().strip_prefix(SERVICE_PATH_PREFIX).map(str::to_owned)
+            .filter(|rpc| !TRADELESS_RPCS.contains(&&rpc[..])) else
+        {
+            return Box::pin(self.inner.call(req));
+        };
+        let client = req.extensions().get::<ClientIdentity>().map(ToString::to_string);
+        // Call the service made ready by 'poll_ready', rather than a fresh clone of it:
+        let clone = self.inner.clone();
+        let mut inner = mem::replace(&mut self.inner, clone);
+        let store = Arc::clone(&self.store);
+        Box::pin(async move {
+            let (parts, mut body) = req.into_parts();
+            let (frames, message) = match step_order::read_first_message(&mut body).await {
+                Ok(read) => read,
+                Err(status) => return Ok(status.into_http()),
+            };
+            if let Some(trade_id) = message.and_then(|message| trade_id(&rpc, &message)) {
+                let check = tokio::task::spawn_blocking(move || check_owner(&*store, &trade_id, client.as_deref()));
+                if let Ok(Err(status)) = check.await {
+                    return Ok(status.into_http());
+                }
+            }
+            inner.call(Request::from_parts(parts, step_order::replay(frames, body))).await
+        })
+    }
+}
+
+/// The ID of the trade the given (first) request message of the given RPC is for, if any.
+fn trade_id(rpc: &str, message: &[u8]) -> Option<String> {
+    if rpc == "GetProtocolDescriptor" {
+        ProtocolDescriptorRequest::decode(message).ok()?.trade_id
+    } else {
+        Some(TradeIdOnly::decode(message).ok()?.trade_id)
+    }
+}
+
+/// Check that the trade, if live, was opened by the client with the given identity, if by any known
+/// client at all.
+fn check_owner(store: &impl TradeModelStore, trade_id: &str, client: Option<&str>) -> Result<(), Status> {
+    let Some(trade_model) = store.get_trade_model(trade_id) else { return Ok(()) };
+    let opened_by = lock_trade_model(&trade_model).opened_by.clone();
+    match opened_by {
+        Some(owner) if client != Some(&owner[..]) => Err(Status::permission_denied(format!(
+            "trade with id {} was opened by another client", trade_id))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,166 @@
+//! Direct exchange of the peer payloads between our daemon and the peer's, over the `MuSigPeer`
+//! service (as defined by `peer.proto`), for the trades given a peer endpoint, so that neither
+//! party's client need relay the payloads, or ever see the secrets among them.
+
+use musig_proto::helloworld::{NonceSharesMessage, PartialSignaturesMessage};
+use musig_proto::peer::mu_sig_peer_client::MuSigPeerClient;
+use musig_proto::peer::mu_sig_peer_server::MuSigPeer;
+use musig_proto::peer::peer_payload::Payload;
+use musig_proto::peer::{DeliverAck, PeerPayload, PrvKeyShare, SwapTxInputPartialSignature};
+use musig_trade_protocol::{lock_trade_model, PeerEndpoint, TradeModelStore};
+use std::collections::HashMap;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+use thiserror::Error;
+use tokio::time::{self, Duration};
+use tonic::transport::Endpoint;
+use tonic::{Request, Response, Status};
+
+use crate::tor::{self, Socks5Proxy};
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+const DELIVERY_ATTEMPTS: u32 = 8;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_mins(1);
+
+/// The latest payload of each kind delivered by the peer of a trade.
+#[derive(Clone, Default)]
+pub struct Delivered {
+    pub nonce_shares: Option<NonceSharesMessage>,
+    pub partial_signatures: Option<PartialSignaturesMessage>,
+    pub swap_tx_input_partial_signature: Option<SwapTxInputPartialSignature>,
+    pub prv_key_share: Option<PrvKeyShare>,
+}
+
+/// The payloads delivered by the peers of each trade (by our ID for it), held until the protocol
+/// steps taking them in are run.
+// TODO: Persist these, or have the peer redeliver them on request, so that none are lost should we
+//  restart before the steps are run.
+#[derive(Default)]
+pub struct PeerInbox(Mutex<HashMap<String, Delivered>>);
+
+impl PeerInbox {
+    pub fn get(&self, trade_id: &str) -> Delivered {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).get(trade_id).cloned().unwrap_or_default()
+    }
+
+    fn put(&self, trade_id: String, payload: Payload) {
+        let mut inbox = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let delivered = inbox.entry(trade_id).or_default();
+        match payload {
+            Payload::NonceShares(m) => delivered.nonce_shares = Some(m),
+            Payload::PartialSignatures(m) => delivered.partial_signatures = Some(m),
+            Payload::SwapTxInputPartialSignature(m) => delivered.swap_tx_input_partial_signature = Some(m),
+            Payload::PrvKeyShare(m) => delivered.prv_key_share = Some(m),
+        }
+        drop(inbox);
+    }
+
+    pub fn remove(&self, trade_id: &str) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).remove(trade_id);
+    }
+}
+
+const fn payload_name(payload: &Payload) -> &'static str {
+    match payload {
+        Payload::NonceShares(_) => "nonce shares",
+        Payload::PartialSignatures(_) => "partial signatures",
+        Payload::SwapTxInputPartialSignature(_) => "swap tx partial signature",
+        Payload::PrvKeyShare(_) => "private key share",
+    }
+}
+
+/// The peer service, taking in the payloads delivered by the daemons of our peers. Each is checked
+/// against the trade model before it is accepted, so that a forged delivery (from anyone able to
+/// reach the service) cannot displace a genuine one.
+pub struct MyMuSigPeer<S> {
+    trade_model_store: Arc<S>,
+    inbox: Arc<PeerInbox>,
+}
+
+impl<S> Clone for MyMuSigPeer<S> {
+    fn clone(&self) -> Self {
+        Self { trade_model_store: Arc::clone(&self.trade_model_store), inbox: Arc::clone(&self.inbox) }
+    }
+}
+
+impl<S> MyMuSigPeer<S> {
+    pub const fn new(trade_model_store: Arc<S>, inbox: Arc<PeerInbox>) -> Self {
+        Self { trade_model_store, inbox }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: TradeModelStore + Send + Sync + 'static> MuSigPeer for MyMuSigPeer<S> {
+    async fn deliver(&self, request: Request<PeerPayload>) -> Result<Response<DeliverAck>, Status> {
+        println!("Got a peer request: {}", crate::logging::debug_for_log(&request));
+
+        let PeerPayload { trade_id, payload } = request.into_inner();
+        let payload = payload.ok_or_else(|| Status::invalid_argument("missing request.payload"))?;
+        let trade_model_store = Arc::clone(&self.trade_model_store);
+        let (trade_id, payload) = tokio::task::spawn_blocking(move || {
+            let trade_model = trade_model_store.get_trade_model(&trade_id)
+                .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+            let trade_model = lock_trade_model(&trade_model);
+            if trade_model.peer_endpoint.is_none() {
+                return Err(Status::failed_precondition(format!(
+                    "trade with id {} doesn't exchange its peer payloads directly", trade_id)));
+            }
+            crate::check_peer_payload(&trade_model, &payload)?;
+            drop(trade_model);
+            Ok((trade_id, payload))
+        }).await.map_err(|e| Status::internal(format!("trade model task failed: {}", e)))??;
+        self.inbox.put(trade_id, payload);
+
+        Ok(Response::new(DeliverAck {}))
+    }
+}
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+enum DeliveryError {
+    Transport(#[from] tonic::transport::Error),
+    Status(#[from] Status),
+}
+
+/// How we reach the daemons of our peers, and how they reach us.
+#[derive(Default)]
+pub struct PeerTransport {
+    pub inbox: Arc<PeerInbox>,
+    /// The address of our peer service, to hand out to each peer along with our key shares.
+    pub my_address: Option<String>,
+    pub socks_proxy: Option<Socks5Proxy>,
+}
+
+impl PeerTransport {
+    /// Deliver the payload for our trade with the given ID to the peer's daemon in the background,
+    /// retrying with backoff until it is accepted or we give up. It may well be rejected at first,
+    /// if the peer's trade has yet to reach the step which lets it check the payload.
+    pub fn spawn_delivery(&self, trade_id: String, endpoint: PeerEndpoint, payload: Payload) {
+        let socks_proxy = self.socks_proxy;
+        tokio::spawn(async move {
+            let name = payload_name(&payload);
+            let request = PeerPayload { trade_id: endpoint.trade_id, payload: Some(payload) };
+            let mut delay = FIRST_RETRY_DELAY;
+            for attempt in 1..=DELIVERY_ATTEMPTS {
+                match deliver(&endpoint.address, socks_proxy, &trade_id, request.clone()).await {
+                    Ok(()) => return,
+                    Err(e) => println!("Could not deliver {} to peer of trade with id {} (attempt {}): {}",
+                        name, trade_id, attempt, e),
+                }
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            println!("Gave up delivering {} to peer of trade with id {}", name, trade_id);
+        });
+    }
+}
+
+/// Deliver the payload over a fresh connection, which (if made through Tor) is kept to circuits of
+/// its own, apart from those of any other trade.
+async fn deliver(address: &str, socks_proxy: Option<Socks5Proxy>, trade_id: &str, request: PeerPayload) -> Result<(), DeliveryError> {
+    let endpoint = Endpoint::from_shared(address.to_owned())?.timeout(CALL_TIMEOUT);
+    let channel = tor::connect(&endpoint, socks_proxy, Some(trade_id)).await?;
+    MuSigPeerClient::new(channel).deliver(request).await?;
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use musig_trade_protocol::{DeadlineDue, DeadlineKind, DeadlineState, PolicyAction, PolicyActionKind, PolicyOverrides,
+    TradeModel, TradePhase};
+use std::collections::HashMap;
+use std::mem;
+use std::prelude::rust_2021::*;
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::SystemTime;
+
+use crate::config::PolicyConfig;
+use crate::deadlines::BLOCK_INTERVAL;
+use crate::events::TradeEvent;
+
+/// Takes the automatic protocol responses of each trade as its deadlines pass, under the daemon's
+/// policy as overridden for the trade, so that an unattended daemon still protects its funds.
+///
+/// There is no chain backend (nor are there real txs) yet, so taking a response means announcing it
+/// with a [`TradeEvent::PolicyAction`], for the front-end to carry out, and recording it with the
+/// trade, so that it is only ever taken once. In dry-run mode, a response is announced as such and
+/// only remembered in memory, so that it is announced once per run of the daemon.
+#[derive(Default)]
+pub struct PolicyEngine {
+    config: RwLock<PolicyConfig>,
+    dry_run_actions: Mutex<HashMap<String, Vec<PolicyAction>>>,
+}
+
+impl PolicyEngine {
+    pub fn new(config: PolicyConfig) -> Self {
+        Self { config: RwLock::new(config), ..Default::default() }
+    }
+
+    /// Put the given policy into force in place of the daemon's current one, as on a config reload,
+    /// returning the old one. The responses already taken under the old policy stand.
+    pub fn set_config(&self, config: PolicyConfig) -> PolicyConfig {
+        mem::replace(&mut self.config.write().unwrap_or_else(PoisonError::into_inner), config)
+    }
+
+    /// The policy the given trade is under: the daemon's, with the trade's overrides.
+    pub fn policy_for(&self, overrides: &PolicyOverrides) -> PolicyConfig {
+        let config = *self.config.read().unwrap_or_else(PoisonError::into_inner);
+        PolicyConfig {
+            warning_tx_after_blocks: overrides.warning_tx_after_blocks
+                .map_or(config.warning_tx_after_blocks, |blocks| (blocks != 0).then_some(blocks)),
+            auto_claim: overrides.auto_claim.unwrap_or(config.auto_claim),
+            warning_tx_on_unresponsive_peer: config.warning_tx_on_unresponsive_peer,
+            dry_run: overrides.dry_run.unwrap_or(config.dry_run),
+        }
+    }
+
+    /// Take the responses due for the trade as of the given time & block height (if known), given
+    /// the timelock of the warning txs in blocks (if tracked), returning the events announcing them.
+    pub fn apply(&self, trade_model: &mut TradeModel, claim_blocks: Option<u32>, now: SystemTime, height: Option<u32>)
+        -> Vec<TradeEvent>
+    {
+        if trade_model.phase() >= TradePhase::SwapTxSigned {
+            return Vec::new();
+        }
+        let policy = self.policy_for(&trade_model.policy_overrides);
+        let trade_id = trade_model.trade_id().to_owned();
+        // The peer has been announced unresponsive since the deposit tx was published:
+        let peer_offline = trade_model.peer_unresponsive && trade_model.phase() >= TradePhase::DepositTxPublished;
+        let mut dry_run_actions = self.dry_run_actions.lock().unwrap_or_else(PoisonError::into_inner);
+        let actions = if policy.dry_run {
+            dry_run_actions.entry(trade_id.clone()).or_default()
+        } else {
+            &mut trade_model.policy_actions
+        };
+        let taken = |actions: &[PolicyAction], kind| actions.iter().find(|action| action.kind == kind).copied();
+
+        let mut kinds = Vec::new();
+        if let Some(blocks) = policy.warning_tx_after_blocks {
+            // The peer has been unresponsive for long enough past the buyer's payment deadline:
+            let peer_unresponsive = trade_model.deadlines.iter().any(|deadline| deadline.kind == DeadlineKind::Payment
+                && deadline.state == DeadlineState::Passed
+                && blocks_after(deadline.due, blocks).is_reached(now, height));
+            if peer_unresponsive && taken(actions, PolicyActionKind::PublishWarningTx).is_none() {
+                kinds.push(PolicyActionKind::PublishWarningTx);
+            }
+        }
+        if policy.warning_tx_on_unresponsive_peer && peer_offline
+            && taken(actions, PolicyActionKind::PublishWarningTx).is_none() && !kinds.contains(&PolicyActionKind::PublishWarningTx)
+        {
+            kinds.push(PolicyActionKind::PublishWarningTx);
+        }
+        if let (true, Some(claim_blocks), Some(published)) =
+            (policy.auto_claim, claim_blocks, taken(actions, PolicyActionKind::PublishWarningTx))
+        {
+            let timelock_expired = blocks_after(DeadlineDue { at: Some(published.at), height: published.height },
+                claim_blocks).is_reached(now, height);
+            if timelock_expired && taken(actions, PolicyActionKind::ClaimWarningTx).is_none() {
+                kinds.push(PolicyActionKind::ClaimWarningTx);
+            }
+        }
+
+        kinds.into_iter().map(|kind| {
+            let action = PolicyAction { kind, at: now, height };
+            actions.push(action);
+            TradeEvent::PolicyAction { trade_id: trade_id.clone(), action, dry_run: policy.dry_run }
+        }).collect()
+    }
+}
+
+/// The given moment, put off by the given number of blocks (also estimated by wall clock).
+fn blocks_after(due: DeadlineDue, blocks: u32) -> DeadlineDue {
+    DeadlineDue {
+        at: due.at.zip(BLOCK_INTERVAL.checked_mul(blocks)).and_then(|(at, delay)| at.checked_add(delay)),
+        height: due.height.map(|height| height.saturating_add(blocks)),
+    }
+}
@@ -0,0 +1,163 @@
+//! Quotas on the number of trades open at once, overall and per client, so that the store cannot be
+//! filled up with live trade models by a misbehaving (or buggy) client, or by many of them.
+
+use musig_trade_protocol::{lock_trade_model, trade_exists, AuditEntry, Intent, TradeModel, TradeModelStore, TradeSummary};
+use std::collections::HashMap;
+use std::io;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::config::TradeQuotaConfig;
+
+#[derive(Default)]
+struct OpenTrades {
+    /// The client which opened each open trade, if known, by trade ID.
+    clients: HashMap<String, Option<String>>,
+    counts_per_client: HashMap<String, usize>,
+}
+
+impl OpenTrades {
+    fn insert(&mut self, trade_id: String, client: Option<String>) {
+        if let Some(client) = &client {
+            *self.counts_per_client.entry(client.clone()).or_default() += 1;
+        }
+        self.clients.insert(trade_id, client);
+    }
+
+    fn remove(&mut self, trade_id: &str) {
+        if let Some(client) = self.clients.remove(trade_id) {
+            self.remove_client(client);
+        }
+    }
+
+    fn remove_client(&mut self, client: Option<String>) {
+        if let Some(client) = client {
+            if let Some(count) = self.counts_per_client.get_mut(&client) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts_per_client.remove(&client);
+                }
+            }
+        }
+    }
+}
+
+/// A trade model store wrapping another, refusing to add a trade model (with an error of kind
+/// [`io::ErrorKind::QuotaExceeded`]) that would put the number of live trades, or those opened by
+/// its client (as given by [`TradeModel::opened_by`]), over the configured limit.
+pub struct QuotaStore<S> {
+    inner: S,
+    config: TradeQuotaConfig,
+    open: Mutex<OpenTrades>,
+}
+
+impl<S: TradeModelStore> QuotaStore<S> {
+    /// Wrap the given store, counting the trades already live in it against the quotas.
+    pub fn new(inner: S, config: TradeQuotaConfig) -> Self {
+        let mut open = OpenTrades::default();
+        for summary in inner.list_trade_models() {
+            if let Some(trade_model) = inner.get_trade_model(&summary.trade_id) {
+                let client = lock_trade_model(&trade_model).opened_by.clone();
+                open.insert(summary.trade_id, client);
+            }
+        }
+        Self { inner, config, open: Mutex::new(open) }
+    }
+
+    pub const fn config(&self) -> &TradeQuotaConfig {
+        &self.config
+    }
+
+    pub fn open_trade_count(&self) -> usize {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner).clients.len()
+    }
+
+    /// The most trades open for any one client.
+    pub fn max_open_trade_count_per_client(&self) -> usize {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner).counts_per_client.values().copied().max().unwrap_or_default()
+    }
+}
+
+fn quota_exceeded(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::QuotaExceeded, msg)
+}
+
+impl<S: TradeModelStore> TradeModelStore for QuotaStore<S> {
+    fn add_trade_model(&self, trade_model: TradeModel) -> io::Result<()> {
+        let trade_id = trade_model.trade_id().to_owned();
+        let client = trade_model.opened_by.clone();
+        // Keep the open trades locked until the trade model is added, so that concurrent adds can't
+        // both take the last place left in a quota:
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        // The inner store refuses an ID already archived, but one already open mustn't count either:
+        if open.clients.contains_key(&trade_id) {
+            return Err(trade_exists(&trade_id));
+        }
+        if let Some(max) = self.config.max_open_trades {
+            if open.clients.len() >= max {
+                return Err(quota_exceeded(format!("too many open trades, limit is {}", max)));
+            }
+        }
+        if let (Some(max), Some(client)) = (self.config.max_open_trades_per_client, &client) {
+            let count = open.counts_per_client.get(client).copied().unwrap_or_default();
+            if count >= max {
+                return Err(quota_exceeded(format!("too many open trades for client {}, limit is {}", client, max)));
+            }
+        }
+        self.inner.add_trade_model(trade_model)?;
+        open.insert(trade_id, client);
+        drop(open);
+        Ok(())
+    }
+
+    fn get_trade_model(&self, trade_id: &str) -> Option<Arc<Mutex<TradeModel>>> {
+        self.inner.get_trade_model(trade_id)
+    }
+
+    fn contains_trade(&self, trade_id: &str) -> bool {
+        self.inner.contains_trade(trade_id)
+    }
+
+    fn save_trade_model(&self, trade_model: &TradeModel) -> io::Result<()> {
+        self.inner.save_trade_model(trade_model)
+    }
+
+    fn log_intent(&self, trade_id: &str, intent: Intent) -> io::Result<()> {
+        self.inner.log_intent(trade_id, intent)
+    }
+
+    fn log_completion(&self, trade_id: &str, intent: Intent) -> io::Result<()> {
+        self.inner.log_completion(trade_id, intent)
+    }
+
+    fn list_trade_models(&self) -> Vec<TradeSummary> {
+        self.inner.list_trade_models()
+    }
+
+    fn archive_trade_model_if(&self, trade_id: &str, condition: impl FnOnce(&TradeModel) -> bool)
+        -> io::Result<Option<TradeSummary>>
+    {
+        let summary = self.inner.archive_trade_model_if(trade_id, condition);
+        // A failure may still have removed the live trade model, before writing out the archive:
+        if !matches!(summary, Ok(None)) && self.inner.get_trade_model(trade_id).is_none() {
+            self.open.lock().unwrap_or_else(PoisonError::into_inner).remove(trade_id);
+        }
+        summary
+    }
+
+    fn list_archived_trades(&self) -> Vec<TradeSummary> {
+        self.inner.list_archived_trades()
+    }
+
+    fn add_archived_trade(&self, summary: TradeSummary) -> io::Result<()> {
+        self.inner.add_archived_trade(summary)
+    }
+
+    fn log_audit_entry(&self, trade_id: &str, entry: &AuditEntry) -> io::Result<()> {
+        self.inner.log_audit_entry(trade_id, entry)
+    }
+
+    fn get_audit_log(&self, trade_id: &str) -> io::Result<Vec<AuditEntry>> {
+        self.inner.get_audit_log(trade_id)
+    }
+}
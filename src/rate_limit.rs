@@ -0,0 +1,157 @@
+//! Per-client rate limiting of the calls to the `MuSig` service, so that a misbehaving (or buggy)
+//! client cannot flood the store with trade models, or tie up the server with calls. The clients are
+//! told apart by their [`ClientIdentity`]: their client cert, over mTLS, or else their IP address.
+
+use futures::future::{self, Either, Ready};
+use std::collections::HashMap;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::client_identity::ClientIdentity;
+use crate::config::RateLimitConfig;
+
+const INIT_TRADE_PATH: &str = "/helloworld.MuSig/InitTrade";
+/// How many clients to track at least, before forgetting those idle long enough to be refilled.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// A token bucket holding up to a minute's worth of calls, refilled continuously.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(per_min: u32, now: Instant) -> Self {
+        Self { tokens: f64::from(per_min), updated: now }
+    }
+
+    fn refill(&mut self, per_min: u32, now: Instant) {
+        let per_min = f64::from(per_min);
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_min / 60.0).min(per_min);
+        self.updated = now;
+    }
+
+    fn try_take(&mut self, per_min: u32, now: Instant) -> bool {
+        self.refill(per_min, now);
+        let has_token = self.tokens >= 1.0;
+        if has_token {
+            self.tokens -= 1.0;
+        }
+        has_token
+    }
+
+    fn is_full(&mut self, per_min: u32, now: Instant) -> bool {
+        self.refill(per_min, now);
+        self.tokens >= f64::from(per_min)
+    }
+}
+
+/// The budgets of a client, with `InitTrade` calls (which each add a trade model to the store)
+/// counted apart from (and not against) the budget for all other calls.
+struct ClientBudgets {
+    init_trade: Bucket,
+    other: Bucket,
+}
+
+#[derive(Default)]
+struct Clients {
+    budgets: HashMap<ClientIdentity, ClientBudgets>,
+    /// How many clients to track before next pruning those with full budgets.
+    prune_len: usize,
+}
+
+struct Limiter {
+    config: RateLimitConfig,
+    clients: Mutex<Clients>,
+}
+
+impl Limiter {
+    fn try_acquire(&self, client: &ClientIdentity, is_init_trade: bool) -> bool {
+        let RateLimitConfig { init_trade_per_min, rpc_per_min } = self.config;
+        let Some(per_min) = (if is_init_trade { init_trade_per_min } else { rpc_per_min }) else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        if clients.budgets.len() >= clients.prune_len.max(MIN_PRUNE_LEN) {
+            self.prune(&mut clients, now);
+        }
+        let budgets = clients.budgets.entry(client.clone()).or_insert_with(|| ClientBudgets {
+            init_trade: Bucket::full(init_trade_per_min.unwrap_or_default(), now),
+            other: Bucket::full(rpc_per_min.unwrap_or_default(), now),
+        });
+        let bucket = if is_init_trade { &mut budgets.init_trade } else { &mut budgets.other };
+        let acquired = bucket.try_take(per_min, now);
+        drop(clients);
+        acquired
+    }
+
+    /// Forget the clients whose budgets are full again, as they would be given the same budgets
+    /// afresh. The length to prune at next is twice the length left, so that pruning stays cheap
+    /// (amortized) however many clients there are.
+    fn prune(&self, clients: &mut Clients, now: Instant) {
+        let RateLimitConfig { init_trade_per_min, rpc_per_min } = self.config;
+        clients.budgets.retain(|_, b| !(b.init_trade.is_full(init_trade_per_min.unwrap_or_default(), now)
+            && b.other.is_full(rpc_per_min.unwrap_or_default(), now)));
+        clients.prune_len = clients.budgets.len() * 2;
+    }
+}
+
+/// A layer rate limiting the calls of each client, with the configured budgets.
+#[derive(Clone)]
+pub struct RateLimitLayer(Arc<Limiter>);
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self(Arc::new(Limiter { config, clients: Mutex::default() }))
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit { inner, limiter: Arc::clone(&self.0) }
+    }
+}
+
+/// A service failing with `RESOURCE_EXHAUSTED` any call that would put its client over budget.
+/// Calls from clients of no known identity (over a unix socket with no client cert, so only local)
+/// are never limited.
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+    where S: Service<Request<B>, Response=Response<BoxBody>>
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Self::Response, Self::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        if let Some(client) = req.extensions().get::<ClientIdentity>() {
+            let is_init_trade = req.uri().path() == INIT_TRADE_PATH;
+            if !self.limiter.try_acquire(client, is_init_trade) {
+                let status = Status::resource_exhausted(format!("rate limit exceeded for {} calls from {}",
+                    if is_init_trade { "InitTrade" } else { "RPC" }, client));
+                return Either::Left(future::ok(status.into_http()));
+            }
+        }
+        Either::Right(self.inner.call(req))
+    }
+}
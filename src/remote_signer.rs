@@ -0,0 +1,100 @@
+//! A [`Signer`] which calls out to an external signing service over gRPC (as defined by
+//! `signer.proto`), so that the secret key shares & nonces of the trades never reach this host.
+
+use musig2::{PartialSignature, PubNonce, SecNonce};
+use musig_proto::convert::{decode, ConvertError};
+use musig_proto::signer::remote_signer_client::RemoteSignerClient;
+use musig_proto::signer::{DiscardNonceShareRequest, NewKeyShareRequest, NewNonceShareRequest,
+    RevealKeyShareRequest, SignPartialRequest};
+use musig_trade_protocol::storage::ByOptVal;
+use musig_trade_protocol::{KeyPair, NoncePair, ProtocolErrorKind, Secret, Signer, SigningSession};
+use secp::{Point, Scalar};
+use std::future::Future;
+use std::prelude::rust_2021::*;
+use tokio::runtime::Handle;
+use tokio::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Response, Status};
+
+use crate::tor::{self, Socks5Proxy};
+
+/// How long to wait for each call to the signing service, which may be slow to respond if backed
+/// by an HSM or hardware wallet, say.
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Result<T> = std::result::Result<T, ProtocolErrorKind>;
+
+/// The client of an external signing service. As the [`Signer`] methods are blocking, they must be
+/// called from outside the async runtime the client was connected in, e.g. on its blocking thread
+/// pool, as every protocol step is.
+pub struct RemoteSigner {
+    client: RemoteSignerClient<Channel>,
+    runtime: Handle,
+}
+
+impl RemoteSigner {
+    pub async fn connect(url: String, socks_proxy: Option<Socks5Proxy>) -> std::result::Result<Self, tonic::transport::Error> {
+        let channel = tor::connect(&Endpoint::from_shared(url)?.timeout(CALL_TIMEOUT), socks_proxy, None).await?;
+        Ok(Self { client: RemoteSignerClient::new(channel), runtime: Handle::current() })
+    }
+
+    fn call<T, F>(&self, call: impl FnOnce(RemoteSignerClient<Channel>) -> F) -> Result<T>
+        where F: Future<Output=std::result::Result<Response<T>, Status>>
+    {
+        self.runtime.block_on(call(self.client.clone()))
+            .map(Response::into_inner)
+            .map_err(|e| ProtocolErrorKind::Signer(e.into()))
+    }
+}
+
+fn invalid_response(e: ConvertError) -> ProtocolErrorKind {
+    ProtocolErrorKind::Signer(e.in_field("signer_response").into())
+}
+
+impl Signer for RemoteSigner {
+    fn new_key_share(&self) -> Result<KeyPair<ByOptVal>> {
+        let response = self.call(|mut client| async move {
+            client.new_key_share(NewKeyShareRequest {}).await
+        })?;
+        let pub_key = decode(&response.pub_key, "pub_key").map_err(invalid_response)?;
+        Ok(KeyPair { pub_key, prv_key: None })
+    }
+
+    fn new_nonce_share(&self, key_share: &KeyPair<ByOptVal>, aggregated_pub_key: Point) -> Result<NoncePair> {
+        let request = NewNonceShareRequest {
+            pub_key: key_share.pub_key.serialize().into(),
+            aggregated_pub_key: aggregated_pub_key.serialize().into(),
+        };
+        let response = self.call(|mut client| async move { client.new_nonce_share(request).await })?;
+        let pub_nonce = decode(&response.pub_nonce, "pub_nonce").map_err(invalid_response)?;
+        Ok(NoncePair { pub_nonce, sec_nonce: None })
+    }
+
+    fn sign_partial(&self, session: &SigningSession<'_>, key_share: &KeyPair<ByOptVal>, pub_nonce: &PubNonce,
+                    _sec_nonce: Option<Secret<SecNonce>>) -> Result<PartialSignature> {
+        let request = SignPartialRequest {
+            pub_key: key_share.pub_key.serialize().into(),
+            pub_nonce: pub_nonce.serialize().into(),
+            key_agg_ctx: session.key_agg_ctx.serialize(),
+            aggregated_nonce: session.aggregated_nonce.serialize().into(),
+            adaptor_point: session.adaptor_point.serialize().into(),
+            sighash: session.message.into(),
+        };
+        let response = self.call(|mut client| async move { client.sign_partial(request).await })?;
+        decode(&response.partial_signature, "partial_signature").map_err(invalid_response)
+    }
+
+    fn discard_nonce_share(&self, pub_nonce: &PubNonce) {
+        let request = DiscardNonceShareRequest { pub_nonce: pub_nonce.serialize().into() };
+        if let Err(e) = self.call(|mut client| async move { client.discard_nonce_share(request).await }) {
+            // Nothing more can be done, but the nonce will never be used by us again anyway:
+            eprintln!("Could not discard nonce share with signer: {}", e);
+        }
+    }
+
+    fn reveal_key_share(&self, key_share: &KeyPair<ByOptVal>) -> Result<Scalar> {
+        let request = RevealKeyShareRequest { pub_key: key_share.pub_key.serialize().into() };
+        let response = self.call(|mut client| async move { client.reveal_key_share(request).await })?;
+        decode(&response.prv_key, "prv_key").map_err(invalid_response)
+    }
+}
@@ -1,363 +1,2974 @@
-mod protocol;
-mod storage;
+mod admin;
+mod backup;
+mod burningman;
+mod chain;
+mod chunked;
+mod cipher;
+mod client_identity;
+mod config;
+mod correlation;
+mod deadlines;
+mod decode_limits;
+#[cfg(feature = "demo")]
+mod demo;
+mod descriptor;
+mod engine;
+mod events;
+mod fault;
+mod file_store;
+mod gateway;
+mod gc;
+mod grpc_web;
+mod health;
+mod http;
+mod json;
+mod listeners;
+mod logging;
+mod metrics;
+mod mock_chain;
+mod noise;
+mod nonce_index;
+mod ownership;
+mod peer;
+mod policy;
+mod quota;
+mod rate_limit;
+mod remote_signer;
+mod simulate;
+mod snapshot;
+mod step_order;
+mod subscriptions;
+mod test_vectors;
+#[cfg(test)]
+mod tests;
+mod timeout;
+mod tor;
+mod trade_id;
+mod transcode;
+mod wallet;
+mod webhook;
 
 use futures::stream;
-use helloworld::{ClockRequest, CloseTradeRequest, CloseTradeResponse, DepositPsbt,
-    DepositTxSignatureRequest, HelloReply, HelloRequest, NonceSharesMessage, NonceSharesRequest,
-    PartialSignaturesMessage, PartialSignaturesRequest, PubKeySharesRequest, PubKeySharesResponse,
-    PublishDepositTxRequest, SwapTxSignatureRequest, SwapTxSignatureResponse, TickEvent,
-    TxConfirmationStatus};
-use helloworld::greeter_server::{Greeter, GreeterServer};
-use helloworld::mu_sig_server::{MuSig, MuSigServer};
-use musig2::{LiftedSignature, PubNonce};
-use prost::UnknownEnumValue;
-use secp::{Point, MaybeScalar, Scalar};
-use std::iter;
+use http_body::Body;
+use musig2::PubNonce;
+use prost::Message as _;
+use musig_proto::convert::{self, decode, decode_coin_control, decode_funding_inputs, decode_half_deposit_psbt, decode_opt, decode_role,
+    encode_half_deposit_psbt, fee_rate_change_partial_signatures, fee_rate_change_signed_fields, swap_tx_fee_bump_signed_fields, to_millis, ConvertError,
+    SignedPayload as _, PSBT_MAGIC};
+use musig_proto::helloworld;
+use musig_proto::helloworld::{ArchiveTradeRequest, CancelBeforeDepositRequest, CancelBeforeDepositResponse, CancellationMessage, CloseTradeRequest, CloseTradeResponse, ConfirmPaymentRequest,
+    DepositPsbt, DepositTxSignatureRequest, ExportPreparedTxPackageRequest, ExportPreparedTxPackageResponse, ExportTradeTranscriptRequest, ExportTradeTranscriptResponse,
+    FeeRateChangeMessage, FeeRateChangeRequest,
+    GetServiceInfoRequest, GetTradeAuditLogRequest, GetTradeAuditLogResponse, GetTradeStateRequest, GetWalletStatusRequest, HeightTrigger, HeightTriggersRequest, ListTradesRequest, ListTradesResponse, NonceCommitmentsMessage, NonceSharesMessage,
+    NonceSharesRequest, PartialSignaturesMessage, PartialSignaturesRequest, ProtocolDescriptor, PsbtChunk,
+    ProtocolDescriptorRequest, ProtocolStep, PubKeySharesRequest,
+    ResetSigningSessionRequest, ResumeTradeRequest, ResumeTradeResponse, RevealNonceSharesRequest,
+    PubKeySharesResponse, PublishDepositTxRequest, ReleaseSwapTxSignatureRequest,
+    ReleaseSwapTxSignatureResponse, SetTradePolicyRequest, SignedDepositPsbtChunk, SignedDepositPsbtRequest, SignedPartialSignature,
+    SwapTxFeeBumpMessage, SwapTxFeeBumpRequest, SwapTxSignatureRequest,
+    StepStatus, SwapTxSignatureResponse, TxConfirmationEventKind, TxConfirmationStatus, UnsignedDepositPsbtRequest, WalletStatus};
+use musig_proto::helloworld::mu_sig_server::{MuSig, MuSigServer};
+use musig_proto::helloworld::partial_signatures_message::SwapTxInput;
+use musig_proto::peer::peer_payload::Payload;
+use musig_proto::peer::{PrvKeyShare, SwapTxInputPartialSignature};
+use musig_trade_protocol::{lock_trade_model, AssembledDepositTx, AuditEntry, Cancellation, ExchangedNonces, ExchangedPreparedTxNonces, ExchangedSigs, Intent, LocalSigner, PayloadKind, PaymentMilestone, PaymentReceipt, PeerEndpoint,
+    PolicyOverrides, ProtocolErrorKind, Role, PROTOCOL_VERSION, Signer, TestSigner,
+    trade_exists, TradeModel, TradeModelMemoryStore, TradeModelStore, TradePhase, TradeSummary, TradeTranscript};
+use musig_trade_protocol::status;
+use musig_trade_protocol::storage::ByVal;
+use musig_trade_protocol::tx_outputs::TxOutput;
+use secp::{Point, Scalar};
+use sha2::{Digest as _, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::prelude::rust_2021::*;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::time::Duration;
-use tokio_stream::StreamExt as _;
-use tonic::{Request, Response, Status};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use tokio::time;
+use tonic::{Code, Request, Response, Status, Streaming};
 use tonic::transport::Server;
+use tonic::body::BoxBody;
+use tonic::codegen::Bytes;
+use tonic::codegen::http::{Request as HttpRequest, Response as HttpResponse};
+use tonic::transport::server::TcpIncoming;
+use tower_layer::Layer as _;
+use tower_service::Service;
 
-use crate::protocol::{ExchangedNonces, ExchangedSigs, ProtocolErrorKind, Role, TradeModel,
-    TradeModelStore as _, TRADE_MODELS};
+use crate::backup::KeyShareBackup;
+use crate::burningman::{ReceiverRegistry, ReceiverSet};
+use crate::chain::{ChainBackendStatus, ChainTip, SimulatedBroadcaster, TxBroadcaster, TxStatus, SIMULATED_TIP_HEIGHT};
+use crate::cipher::MasterSecret;
+use crate::client_identity::{ClientIdentity, ClientIdentityLayer};
+use crate::config::{ChainConfig, Command, Config, ListenAddr, ListenerService, NonceReuseConfig, SecretKeySource, SignerConfig, StoreConfig, TradeLimitConfig};
+use crate::correlation::CorrelationLayer;
+use crate::decode_limits::DecodeLimitLayer;
+use crate::engine::{Reply, TradeCommand, TradeEngine};
+use crate::events::{TradeEvent, TradeEventBus};
+use crate::nonce_index::PeerNonceIndex;
+use crate::fault::FaultInjector;
+use crate::health::{MyHealth, ReadinessChecks};
+use crate::file_store::{write_atomically, TradeModelFileStore};
+use crate::listeners::{tcp_incoming, Listener, Services, TokenAuth};
+use crate::mock_chain::MockChainBackend;
+use crate::ownership::OwnershipLayer;
+use crate::peer::{MyMuSigPeer, PeerTransport};
+use crate::policy::PolicyEngine;
+use crate::quota::QuotaStore;
+use crate::rate_limit::RateLimitLayer;
+use crate::step_order::StepOrderLayer;
+use crate::subscriptions::{Subscription, SubscriptionRegistry};
+use crate::timeout::TimeoutLayer;
+use crate::remote_signer::RemoteSigner;
+use crate::tor::{OnionService, Socks5Proxy};
+use crate::wallet::UtxoReservations;
+use crate::webhook::WebhookNotifier;
 
-pub mod helloworld {
-    #![allow(clippy::all, clippy::pedantic, clippy::restriction, clippy::nursery)]
-    tonic::include_proto!("helloworld");
+
+/// The Noise prologues of each kind of sealed peer payload, so that none may pass for another.
+const NONCE_SHARES_PROLOGUE: &[u8] = b"MuSigTradeProtocol/sealed/nonce shares";
+const PARTIAL_SIGNATURES_PROLOGUE: &[u8] = b"MuSigTradeProtocol/sealed/partial signatures";
+const SWAP_TX_INPUT_PARTIAL_SIGNATURE_PROLOGUE: &[u8] = b"MuSigTradeProtocol/sealed/swap tx input partial signature";
+const PRV_KEY_SHARE_PROLOGUE: &[u8] = b"MuSigTradeProtocol/sealed/prv key share";
+const FEE_RATE_CHANGE_PROLOGUE: &[u8] = b"MuSigTradeProtocol/sealed/fee rate change";
+const SWAP_TX_FEE_BUMP_PROLOGUE: &[u8] = b"MuSigTradeProtocol/sealed/swap tx fee bump";
+
+/// How often the trades watched by a height trigger subscription are checked for new triggers, in
+/// between moves of the chain tip, as their triggers are set while they progress.
+const HEIGHT_TRIGGER_RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// The longest a stream of tx confirmations goes without a message, before a heartbeat is sent.
+const TX_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+pub struct MyMuSig<S: TradeModelStore = TradeModelMemoryStore> {
+    trade_model_store: Arc<S>,
+    engine: Arc<TradeEngine<S, MuSigCommand>>,
+    signer: Arc<dyn Signer>,
+    backup: Option<Arc<KeyShareBackup>>,
+    peers: Arc<PeerTransport>,
+    faults: Arc<FaultInjector>,
+    peer_nonces: Arc<PeerNonceIndex>,
+    refuse_peer_nonce_reuse: bool,
+    chain_tip: ChainTip,
+    tx_broadcaster: Arc<dyn TxBroadcaster>,
+    mock_chain: Option<Arc<MockChainBackend>>,
+    tx_heartbeat_interval: Duration,
+    subscriptions: Arc<SubscriptionRegistry>,
+    policy: Arc<PolicyEngine>,
+    events: TradeEventBus,
+    mediator_pub_key: Option<Point>,
+    receiver_registry: Option<Arc<ReceiverRegistry>>,
+    service_info: Arc<helloworld::ServiceInfo>,
+    trade_limits: Arc<RwLock<TradeLimitConfig>>,
+    config_file: Option<PathBuf>,
+    utxo_reservations: Arc<UtxoReservations>,
+    /// Held while a new trade is backed up & added, once its ID is checked to be free.
+    opening_trade: Arc<Mutex<()>>,
+    warning_tx_claim_blocks: Option<u32>,
+}
+
+impl<S: TradeModelStore> Clone for MyMuSig<S> {
+    fn clone(&self) -> Self {
+        Self {
+            trade_model_store: Arc::clone(&self.trade_model_store),
+            engine: Arc::clone(&self.engine),
+            signer: Arc::clone(&self.signer),
+            backup: self.backup.clone(),
+            peers: Arc::clone(&self.peers),
+            faults: Arc::clone(&self.faults),
+            peer_nonces: Arc::clone(&self.peer_nonces),
+            refuse_peer_nonce_reuse: self.refuse_peer_nonce_reuse,
+            chain_tip: self.chain_tip.clone(),
+            tx_broadcaster: Arc::clone(&self.tx_broadcaster),
+            mock_chain: self.mock_chain.clone(),
+            tx_heartbeat_interval: self.tx_heartbeat_interval,
+            subscriptions: Arc::clone(&self.subscriptions),
+            policy: Arc::clone(&self.policy),
+            events: self.events.clone(),
+            mediator_pub_key: self.mediator_pub_key,
+            receiver_registry: self.receiver_registry.clone(),
+            service_info: Arc::clone(&self.service_info),
+            trade_limits: Arc::clone(&self.trade_limits),
+            config_file: self.config_file.clone(),
+            utxo_reservations: Arc::clone(&self.utxo_reservations),
+            opening_trade: Arc::clone(&self.opening_trade),
+            warning_tx_claim_blocks: self.warning_tx_claim_blocks,
+        }
+    }
+}
+
+impl<S: TradeModelStore + Send + Sync + 'static> MyMuSig<S> {
+    pub fn new(trade_model_store: Arc<S>, signer: Arc<dyn Signer>, backup: Option<KeyShareBackup>,
+               peers: Arc<PeerTransport>) -> Self {
+        let engine = Arc::new(TradeEngine::new(Arc::clone(&trade_model_store)));
+        Self {
+            trade_model_store, engine, signer, backup: backup.map(Arc::new), peers,
+            faults: Arc::default(),
+            peer_nonces: Arc::new(PeerNonceIndex::new(Config::default().nonce_reuse.index_capacity)),
+            refuse_peer_nonce_reuse: false,
+            chain_tip: ChainTip::fixed(SIMULATED_TIP_HEIGHT),
+            tx_broadcaster: Arc::new(SimulatedBroadcaster),
+            mock_chain: None,
+            tx_heartbeat_interval: TX_HEARTBEAT_INTERVAL,
+            subscriptions: Arc::new(SubscriptionRegistry::new(Config::default().max_subscriptions_per_trade)),
+            policy: Arc::default(),
+            events: TradeEventBus::default(),
+            mediator_pub_key: None,
+            receiver_registry: None,
+            service_info: Arc::new(service_info(&Config::default())),
+            trade_limits: Arc::default(),
+            config_file: None,
+            utxo_reservations: Arc::default(),
+            opening_trade: Arc::default(),
+            warning_tx_claim_blocks: Config::default().deadlines.warning_tx_claim_blocks,
+        }
+    }
+
+    /// Take the block heights handed out to the client from the given chain tip (shared with the
+    /// deadline scheduler), rather than from a simulated chain.
+    #[must_use]
+    pub fn with_chain_tip(mut self, chain_tip: ChainTip) -> Self {
+        self.chain_tip = chain_tip;
+        self
+    }
+
+    /// Broadcast the txs of the trades with the given broadcaster, rather than taking them to be
+    /// mined at once on a simulated chain.
+    #[must_use]
+    pub fn with_tx_broadcaster(mut self, tx_broadcaster: Arc<dyn TxBroadcaster>) -> Self {
+        self.tx_broadcaster = tx_broadcaster;
+        self
+    }
+
+    /// Follow the given mock chain, broadcasting the txs of the trades to it, and let the admin service
+    /// script it.
+    #[must_use]
+    pub fn with_mock_chain(mut self, mock_chain: Arc<MockChainBackend>) -> Self {
+        self.chain_tip = mock_chain.chain_tip();
+        self.tx_broadcaster = Arc::clone(&mock_chain) as Arc<dyn TxBroadcaster>;
+        self.mock_chain = Some(mock_chain);
+        self
+    }
+
+    /// Send a heartbeat on each stream of tx confirmations gone without a message for the given time,
+    /// rather than for the default 15 seconds (as documented to clients).
+    #[must_use]
+    pub const fn with_tx_heartbeat_interval(mut self, tx_heartbeat_interval: Duration) -> Self {
+        self.tx_heartbeat_interval = tx_heartbeat_interval;
+        self
+    }
+
+    /// Allow at most the given number of streaming subscriptions to be open at once for any one trade
+    /// (or `None` for any number), rather than the default 16.
+    #[must_use]
+    pub fn with_max_subscriptions_per_trade(mut self, max_subscriptions_per_trade: Option<usize>) -> Self {
+        self.subscriptions = Arc::new(SubscriptionRegistry::new(max_subscriptions_per_trade));
+        self
+    }
+
+    /// Use the given policy engine, shared with the deadline scheduler, for the policies of the
+    /// trades.
+    #[must_use]
+    pub fn with_policy(mut self, policy: Arc<PolicyEngine>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Publish the failed protocol steps and closes of trades on the given event bus.
+    #[must_use]
+    pub fn with_events(mut self, events: TradeEventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Only sign the redirect tx of a trade once its receivers have been signed off by the mediator
+    /// with the given public key.
+    #[must_use]
+    pub const fn with_mediator(mut self, mediator_pub_key: Point) -> Self {
+        self.mediator_pub_key = Some(mediator_pub_key);
+        self
+    }
+
+    /// Pay the redirect tx of each trade out to the receiver set in force in the given registry,
+    /// rather than to the receivers asked for.
+    #[must_use]
+    pub fn with_receiver_registry(mut self, receiver_registry: Arc<ReceiverRegistry>) -> Self {
+        self.receiver_registry = Some(receiver_registry);
+        self
+    }
+
+    /// Hand out the given build & capability metadata from `GetServiceInfo`, in place of that of
+    /// the default config.
+    #[must_use]
+    pub fn with_service_info(mut self, service_info: helloworld::ServiceInfo) -> Self {
+        self.service_info = Arc::new(service_info);
+        self
+    }
+
+    /// Only take part in trades of amounts within the given bounds.
+    #[must_use]
+    pub fn with_trade_limits(mut self, trade_limits: TradeLimitConfig) -> Self {
+        self.trade_limits = Arc::new(RwLock::new(trade_limits));
+        self
+    }
+
+    /// Keep an index of up to the given number of the public nonces sent by our peers, to catch a
+    /// peer reusing one across trades, refusing to go on with its trade when it does, if so
+    /// configured, rather than just flagging it.
+    #[must_use]
+    pub fn with_nonce_reuse(mut self, config: NonceReuseConfig) -> Self {
+        self.peer_nonces = Arc::new(PeerNonceIndex::new(config.index_capacity));
+        self.refuse_peer_nonce_reuse = config.refuse;
+        self
+    }
+
+    /// Give the claim path of our warning tx's escrow output (in the exported prepared tx packages)
+    /// the given timelock in blocks, or none at all, rather than the default 720 blocks.
+    #[must_use]
+    pub const fn with_warning_tx_claim_blocks(mut self, warning_tx_claim_blocks: Option<u32>) -> Self {
+        self.warning_tx_claim_blocks = warning_tx_claim_blocks;
+        self
+    }
+
+    /// The check of the public nonces taken in from the peers against the index of those seen.
+    fn peer_nonce_check(&self) -> PeerNonceCheck {
+        PeerNonceCheck {
+            index: Arc::clone(&self.peer_nonces),
+            events: self.events.clone(),
+            refuse: self.refuse_peer_nonce_reuse,
+        }
+    }
+
+    /// Re-read the given config file on `ReloadConfig`, putting its reloadable settings into force.
+    #[must_use]
+    pub fn with_config_file(mut self, config_file: PathBuf) -> Self {
+        self.config_file = Some(config_file);
+        self
+    }
+
+    /// The burning-man receiver set in force at the chain tip, if the daemon has a receiver registry.
+    fn active_receiver_set(&self) -> Result<Option<ReceiverSet>, Status> {
+        let Some(registry) = &self.receiver_registry else { return Ok(None) };
+        let height = self.chain_tip.height().ok_or_else(|| Status::unavailable("chain tip not yet known"))?;
+        let receiver_set = registry.snapshot().set_at(height).cloned().ok_or_else(||
+            Status::failed_precondition(format!("no burning-man receiver set is in force yet at height {}", height)))?;
+        Ok(Some(receiver_set))
+    }
+
+    /// Archive the given trade, should it pass the given check and be at the expected revision (if
+    /// any), logging it in the audit log as the given step (with the digest of its request).
+    fn archive_trade_if(&self, step: &str, trade_id: &str, expected_revision: Option<u64>, request_digest: [u8; 32],
+                        check: impl FnOnce(&TradeModel) -> Result<(), Status>) -> Result<TradeSummary, Status>
+    {
+        let trade_model = self.trade_model_store.get_trade_model(trade_id)
+            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+        let revision = {
+            let trade_model = lock_trade_model(&trade_model);
+            check(&trade_model)?;
+            check_revision(&trade_model, expected_revision)?;
+            trade_model.revision()
+        };
+        let summary = self.trade_model_store.archive_trade_model_if(trade_id, |m| m.revision() == revision)
+            .map_err(|e| Status::internal(format!("could not archive trade model: {}", e)))?
+            .ok_or_else(|| Status::aborted(format!("trade with id {} was changed while archiving", trade_id)))?;
+        self.peers.inbox.remove(trade_id);
+        log_audit_entry(&*self.trade_model_store, trade_id, &AuditEntry {
+            step: step.to_owned(),
+            at: SystemTime::now(),
+            request_digest,
+            response_digest: Some(digest(&helloworld::TradeSummary::from(summary.clone()))),
+            phase: summary.phase,
+            error: None,
+            note: None,
+            correlation_id: correlation::correlation_id(),
+            client: client_identity::client_identity().map(|client| client.to_string()),
+        });
+        Ok(summary)
+    }
+
+    /// Inject the given faults into our payloads for the peer, for testing.
+    #[must_use]
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = Arc::new(faults);
+        self
+    }
+
+    /// Run the given protocol step of the trade on the trade engine, by the deadline of the call,
+    /// publishing a [`TradeEvent::StepFailed`] should it fail, unless with `NOT_FOUND`, for a missing
+    /// trade (or request field), or `DEADLINE_EXCEEDED`, which are down to the caller rather than the
+    /// trade.
+    async fn call_step<T>(&self, trade_id: &str, step: &'static str, command: impl FnOnce(Reply<T>) -> MuSigCommand)
+        -> Result<T, Status>
+    {
+        let result = self.engine.call(trade_id, timeout::call_deadline(), command).await;
+        if let Err(status) = &result {
+            if !matches!(status.code(), Code::NotFound | Code::DeadlineExceeded) {
+                self.events.publish(TradeEvent::StepFailed {
+                    trade_id: trade_id.to_owned(), step, code: status.code(), message: status.message().to_owned(),
+                    correlation_id: correlation::correlation_id(),
+                });
+            }
+        }
+        result
+    }
+
+    /// Broadcast the given tx for the named protocol step of the trade, publishing a
+    /// [`TradeEvent::StepFailed`] should it fail, as for the step itself. This is to be called with
+    /// no trade model lock held, between the steps of the trade engine reading the tx off the trade
+    /// model and recording it as broadcast, so that the trade actor isn't tied up for the broadcast.
+    async fn broadcast_tx(&self, trade_id: &str, step: &'static str, tx: &[u8]) -> Result<(), Status> {
+        let result = self.tx_broadcaster.broadcast(tx).await
+            .map_err(|e| Status::unavailable(format!("could not broadcast the tx: {}", e)));
+        if let Err(status) = &result {
+            self.events.publish(TradeEvent::StepFailed {
+                trade_id: trade_id.to_owned(), step, code: status.code(), message: status.message().to_owned(),
+                correlation_id: correlation::correlation_id(),
+            });
+        }
+        result
+    }
+
+    /// Run the given closure on tokio's blocking thread pool. Any work which may wait for a trade
+    /// model lock or do file I/O, outside of the trade engine, should be done this way, so that it
+    /// cannot tie up the async worker threads and stall every other RPC. The closure isn't run if
+    /// the deadline of the call has passed by the time a blocking thread gets to it.
+    async fn spawn_blocking<T, F>(&self, f: F) -> Result<T, Status>
+        where T: Send + 'static, F: FnOnce(&Self) -> Result<T, Status> + Send + 'static
+    {
+        let this = self.clone();
+        let deadline = timeout::call_deadline();
+        let (correlation_id, client_identity) = (correlation::correlation_id(), client_identity::client_identity());
+        tokio::task::spawn_blocking(move || {
+            timeout::check_deadline(deadline, "the blocking task")?;
+            correlation::in_call(correlation_id, || client_identity::in_call(client_identity, || f(&this)))
+        }).await
+            .map_err(|e| Status::internal(format!("trade model task failed: {}", e)))?
+    }
+
+    /// Our key shares, signed for the peer, as handed out by `InitTrade`.
+    fn my_key_shares_response(&self, trade_model: &TradeModel) -> Result<PubKeySharesResponse, Status> {
+        let my_key_shares = trade_model.get_my_key_shares()
+            .ok_or_else(|| Status::internal("missing key shares"))?;
+        let identity_pub_key = trade_model.get_my_identity_pub_key()
+            .ok_or_else(|| Status::internal("missing identity key"))?;
+        let [buyer_output_pub_key_share, seller_output_pub_key_share] = my_key_shares.map(|k| k.pub_key.serialize());
+        let half_deposit_psbt = encode_half_deposit_psbt(trade_model.my_funding_inputs());
+        let my_role = helloworld::Role::from(trade_model.my_role()).into();
+        let noise_static_pub_key = if trade_model.seal_peer_payloads {
+            noise::static_pub_key(&trade_model.noise_static_key()?).into()
+        } else {
+            vec![]
+        };
+        let fields: &[&[u8]] = &[&buyer_output_pub_key_share, &seller_output_pub_key_share, &half_deposit_psbt,
+            trade_model.trade_id().as_bytes(), &i32::to_be_bytes(my_role), &noise_static_pub_key];
+        Ok(PubKeySharesResponse {
+            identity_signature: sign_payload(trade_model, PayloadKind::KeyShares, key_shares_fields(fields))?,
+            buyer_output_pub_key_share: buyer_output_pub_key_share.into(),
+            seller_output_pub_key_share: seller_output_pub_key_share.into(),
+            current_block_height: self.chain_tip.height().unwrap_or_default(),
+            identity_pub_key: identity_pub_key.serialize().into(),
+            my_peer_address: self.peers.my_address.clone().unwrap_or_default(),
+            half_deposit_psbt,
+            trade_id: trade_model.trade_id().to_owned(),
+            my_role,
+            noise_static_pub_key,
+        })
+    }
+
+    /// The trade's peer endpoint, if it exchanges its peer payloads with the peer's daemon directly,
+    /// along with whether we are the buyer.
+    async fn direct_peer(&self, trade_id: &str) -> Result<Option<(PeerEndpoint, bool)>, Status> {
+        let trade_id = trade_id.to_owned();
+        self.spawn_blocking(move |this| Ok(this.trade_model_store.get_trade_model(&trade_id).and_then(|trade_model| {
+            let trade_model = lock_trade_model(&trade_model);
+            Some((trade_model.peer_endpoint.clone()?, trade_model.am_buyer()))
+        }))).await
+    }
+
+    /// The height triggers of the given trade (or of every live and unclosed trade, if the trade ID
+    /// is empty) reached at the given height.
+    fn reached_height_triggers(&self, trade_id: &str, deposit_confirmations: u32, height: u32)
+        -> Result<Vec<HeightTrigger>, Status>
+    {
+        let watch_all = trade_id.is_empty();
+        let trade_ids = if watch_all {
+            self.trade_model_store.list_trade_models().into_iter()
+                .filter(|summary| summary.phase < TradePhase::Closed)
+                .map(|summary| summary.trade_id)
+                .collect()
+        } else {
+            vec![trade_id.to_owned()]
+        };
+        let mut triggers = Vec::new();
+        for trade_id in trade_ids {
+            let Some(trade_model) = self.trade_model_store.get_trade_model(&trade_id) else {
+                // A trade archived since it was listed is no longer watched:
+                if watch_all { continue; }
+                return Err(Status::not_found(format!("missing trade with id: {}", trade_id)));
+            };
+            let trade_model = lock_trade_model(&trade_model);
+            triggers.extend(chain::height_triggers(&trade_model, deposit_confirmations).into_iter()
+                .filter(|&(_, at)| at <= height)
+                .map(|(kind, at)| HeightTrigger {
+                    trade_id: trade_id.clone(),
+                    kind: kind.into(),
+                    height: at,
+                    current_block_height: height,
+                }));
+        }
+        Ok(triggers)
+    }
+}
+
+/// The state of a height trigger subscription: the chain tip it waits on, the triggers found but not
+/// yet sent, and those already sent, so that each is only sent once.
+struct HeightTriggerWatch<S: TradeModelStore> {
+    musig: MyMuSig<S>,
+    trade_id: String,
+    deposit_confirmations: u32,
+    tip: watch::Receiver<u32>,
+    sent: HashSet<(String, i32, u32)>,
+    pending: VecDeque<HeightTrigger>,
+    _subscription: Subscription,
+}
+
+impl<S: TradeModelStore + Send + Sync + 'static> HeightTriggerWatch<S> {
+    /// Queue up any triggers newly reached at the current tip, failing if the watched trade is missing.
+    async fn check(&mut self) -> Result<(), Status> {
+        let height = *self.tip.borrow_and_update();
+        if height == 0 {
+            return Ok(());
+        }
+        let (trade_id, deposit_confirmations) = (self.trade_id.clone(), self.deposit_confirmations);
+        let triggers = self.musig.spawn_blocking(move |this|
+            this.reached_height_triggers(&trade_id, deposit_confirmations, height)).await?;
+        for trigger in triggers {
+            if self.sent.insert((trigger.trade_id.clone(), trigger.kind, trigger.height)) {
+                self.pending.push_back(trigger);
+            }
+        }
+        Ok(())
+    }
+
+    /// The next trigger reached, once it is, or `None` once the watched trade has been archived.
+    async fn next(mut self) -> Option<(Result<HeightTrigger, Status>, Self)> {
+        loop {
+            if let Some(trigger) = self.pending.pop_front() {
+                return Some((Ok(trigger), self));
+            }
+            // Recheck once the tip moves, or every so often, as the trades set their triggers:
+            if let Ok(Err(_)) = time::timeout(HEIGHT_TRIGGER_RECHECK_INTERVAL, self.tip.changed()).await {
+                return None;
+            }
+            self.check().await.ok()?;
+        }
+    }
+}
+
+/// The state of a stream of the confirmations of a published tx: the chain tip it waits on, the
+/// height the tx was mined at (if known), and the confirmations last sent, and when, so that a
+/// heartbeat is sent whenever nothing else has been for a while.
+struct TxConfirmationWatch<S: TradeModelStore> {
+    musig: MyMuSig<S>,
+    trade_id: String,
+    tx: Vec<u8>,
+    mined_at: Option<u32>,
+    target_confirmations: u32,
+    /// The broadcaster of the tx, from which to follow where it stands in the chain, if it can tell.
+    broadcaster: Arc<dyn TxBroadcaster>,
+    tip: watch::Receiver<u32>,
+    last_sent: Option<(u32, Instant)>,
+    ended: bool,
+    _subscription: Subscription,
+}
+
+impl<S: TradeModelStore + Send + Sync + 'static> TxConfirmationWatch<S> {
+    fn confirmations(&mut self, height: u32) -> u32 {
+        match self.broadcaster.tx_status(&self.tx) {
+            TxStatus::Untracked => {}
+            TxStatus::Unconfirmed => self.mined_at = None,
+            TxStatus::Mined(mined_at) => self.mined_at = Some(mined_at),
+        }
+        self.mined_at.filter(|&mined_at| height >= mined_at).map_or(0, |mined_at| height - mined_at + 1)
+    }
+
+    /// The next message of the stream: once the confirmations change, or as a heartbeat otherwise,
+    /// or `None` once a message of a final kind has been sent.
+    async fn next(mut self) -> Option<(Result<TxConfirmationStatus, Status>, Self)> {
+        if self.ended {
+            return None;
+        }
+        loop {
+            let height = *self.tip.borrow_and_update();
+            let confirmations = self.confirmations(height);
+            let kind = if self.musig.trade_model_store.get_trade_model(&self.trade_id).is_none() {
+                TxConfirmationEventKind::TxFollowAbandoned
+            } else if confirmations >= self.target_confirmations {
+                TxConfirmationEventKind::TxConfirmed
+            } else if let Some((_, sent_at)) = self.last_sent.filter(|&(sent, _)| sent == confirmations) {
+                // Recheck once the tip moves, or else send a heartbeat once one is due:
+                let heartbeat_at = time::Instant::from_std(sent_at + self.musig.tx_heartbeat_interval);
+                match time::timeout_at(heartbeat_at, self.tip.changed()).await {
+                    Ok(Ok(())) => continue,
+                    // The tip is no longer followed (as the daemon is shutting down), so just keep the heartbeat:
+                    Ok(Err(_)) => time::sleep_until(heartbeat_at).await,
+                    Err(_) => {}
+                }
+                TxConfirmationEventKind::TxHeartbeat
+            } else {
+                TxConfirmationEventKind::TxConfirmationsChanged
+            };
+            self.ended = matches!(kind, TxConfirmationEventKind::TxConfirmed | TxConfirmationEventKind::TxFollowAbandoned);
+            self.last_sent = Some((confirmations, Instant::now()));
+            let status = TxConfirmationStatus {
+                tx: self.tx.clone(),
+                current_block_height: height,
+                num_confirmations: confirmations,
+                kind: kind.into(),
+            };
+            return Some((Ok(status), self));
+        }
+    }
+}
+
+/// The protocol steps run by the trade engine, one per mutating RPC on an existing trade.
+enum MuSigCommand {
+    GetNonceShares(NonceSharesRequest, Arc<FaultInjector>, Reply<NonceSharesMessage>),
+    RevealNonceShares(RevealNonceSharesRequest, Arc<FaultInjector>, Reply<NonceSharesMessage>),
+    ResetSigningSession(ResetSigningSessionRequest, Arc<FaultInjector>, Reply<NonceSharesMessage>),
+    GetPartialSignatures(Box<PartialSignaturesRequest>, Option<ReceiverSet>, Option<Point>, Arc<FaultInjector>,
+        PeerNonceCheck, Reply<PartialSignaturesMessage>),
+    SignDepositTx(DepositTxSignatureRequest, Reply<DepositPsbt>),
+    GetUnsignedDepositPsbt(UnsignedDepositPsbtRequest, Reply<DepositPsbt>),
+    SubmitSignedDepositPsbt(SignedDepositPsbtRequest, Reply<DepositPsbt>),
+    CancelBeforeDeposit(CancelBeforeDepositRequest, Reply<CancelBeforeDepositResponse>),
+    GetDepositTxToPublish(PublishDepositTxRequest, Reply<(Vec<u8>, bool)>),
+    PublishDepositTx(PublishDepositTxRequest, Option<u32>, Reply<()>),
+    ProposeFeeRateChange(FeeRateChangeRequest, PeerNonceCheck, Reply<FeeRateChangeMessage>),
+    AcceptFeeRateChange(FeeRateChangeRequest, PeerNonceCheck, Reply<FeeRateChangeMessage>),
+    ConfirmPayment(ConfirmPaymentRequest, PaymentMilestone, Reply<helloworld::PaymentReceipt>),
+    SignSwapTx(SwapTxSignatureRequest, Reply<SwapTxSignatureResponse>),
+    ProposeSwapTxFeeBump(SwapTxFeeBumpRequest, PeerNonceCheck, Reply<SwapTxFeeBumpMessage>),
+    AcceptSwapTxFeeBump(SwapTxFeeBumpRequest, PeerNonceCheck, Reply<SwapTxFeeBumpMessage>),
+    GetSwapTxInputPartialSignature(ReleaseSwapTxSignatureRequest, Reply<SwapTxInputPartialSignature>),
+    GetSwapTxToPublish(CloseTradeRequest, Reply<(Vec<u8>, bool)>),
+    CloseTrade(CloseTradeRequest, Reply<CloseTradeResponse>),
+}
+
+impl<S: TradeModelStore> TradeCommand<S> for MuSigCommand {
+    fn execute(self, store: &S, trade_model: &mut TradeModel) {
+        match self {
+            Self::GetNonceShares(request, faults, reply) => run_step(store, trade_model, "GetNonceShares", request, reply,
+                |store, trade_model, request| get_nonce_shares(store, trade_model, &request, &faults)),
+            Self::RevealNonceShares(request, faults, reply) => run_step(store, trade_model, "RevealNonceShares", request, reply,
+                |store, trade_model, request| reveal_nonce_shares(store, trade_model, request, &faults)),
+            Self::ResetSigningSession(request, faults, reply) => run_step(store, trade_model, "ResetSigningSession", request, reply,
+                |store, trade_model, request| reset_signing_session(store, trade_model, &request, &faults)),
+            Self::GetPartialSignatures(request, receiver_set, mediator_pub_key, faults, nonce_check, reply) => run_step(store,
+                trade_model, "GetPartialSignatures", request, reply, |store, trade_model, request| get_partial_signatures(store,
+                    trade_model, *request, receiver_set.as_ref(), mediator_pub_key, &faults, &nonce_check)),
+            Self::SignDepositTx(request, reply) => run_step(store, trade_model, "SignDepositTx", request, reply,
+                sign_deposit_tx),
+            Self::ProposeFeeRateChange(request, nonce_check, reply) => run_noted_step(store, trade_model, "ProposeFeeRateChange",
+                request, reply, |store, trade_model, request| propose_fee_rate_change(store, trade_model, request, &nonce_check)),
+            Self::AcceptFeeRateChange(request, nonce_check, reply) => run_noted_step(store, trade_model, "AcceptFeeRateChange",
+                request, reply, |store, trade_model, request| accept_fee_rate_change(store, trade_model, request, &nonce_check)),
+            Self::GetUnsignedDepositPsbt(request, reply) => run_step(store, trade_model, "GetUnsignedDepositPsbt", request, reply,
+                |_, trade_model, _| get_unsigned_deposit_psbt(trade_model)),
+            Self::SubmitSignedDepositPsbt(request, reply) => run_step(store, trade_model, "SubmitSignedDepositPsbt", request, reply,
+                submit_signed_deposit_psbt),
+            Self::CancelBeforeDeposit(request, reply) => run_noted_step(store, trade_model, "CancelBeforeDeposit", request, reply,
+                |store, trade_model, request| cancel_before_deposit(store, trade_model, &request)),
+            // Reading a tx to broadcast off the trade model changes nothing, so isn't audited:
+            Self::GetDepositTxToPublish(request, reply) => { let _ = reply.send(deposit_tx_to_publish(store, trade_model, &request)); }
+            Self::PublishDepositTx(request, height, reply) => run_step(store, trade_model, "PublishDepositTx", request, reply,
+                |store, trade_model, request| publish_deposit_tx(store, trade_model, &request, height)),
+            Self::ConfirmPayment(request, milestone, reply) => run_step(store, trade_model, confirm_payment_rpc(milestone),
+                request, reply, |store, trade_model, request| confirm_payment(store, trade_model, &request, milestone)),
+            Self::SignSwapTx(request, reply) => run_step(store, trade_model, "SignSwapTx", request, reply,
+                |store, trade_model, request| sign_swap_tx(store, trade_model, &request)),
+            Self::ProposeSwapTxFeeBump(request, nonce_check, reply) => run_noted_step(store, trade_model, "ProposeSwapTxFeeBump",
+                request, reply, |store, trade_model, request| propose_swap_tx_fee_bump(store, trade_model, request, &nonce_check)),
+            Self::AcceptSwapTxFeeBump(request, nonce_check, reply) => run_noted_step(store, trade_model, "AcceptSwapTxFeeBump",
+                request, reply, |store, trade_model, request| accept_swap_tx_fee_bump(store, trade_model, request, &nonce_check)),
+            Self::GetSwapTxInputPartialSignature(request, reply) => run_step(store, trade_model, "ReleaseSwapTxSignature", request, reply,
+                |_, trade_model, _| get_swap_tx_input_partial_signature(trade_model)),
+            Self::GetSwapTxToPublish(request, reply) => { let _ = reply.send(swap_tx_to_publish(store, trade_model, &request)); }
+            Self::CloseTrade(request, reply) => run_step(store, trade_model, "CloseTrade", request, reply,
+                |store, trade_model, request| close_trade(store, trade_model, &request)),
+        }
+    }
+
+    fn reject(self, status: Status) {
+        match self {
+            Self::GetNonceShares(_, _, reply) | Self::RevealNonceShares(_, _, reply) | Self::ResetSigningSession(_, _, reply) => {
+                let _ = reply.send(Err(status));
+            }
+            Self::GetPartialSignatures(_, _, _, _, _, reply) => { let _ = reply.send(Err(status)); }
+            Self::SignDepositTx(_, reply) | Self::SubmitSignedDepositPsbt(_, reply) | Self::GetUnsignedDepositPsbt(_, reply) => {
+                let _ = reply.send(Err(status));
+            }
+            Self::CancelBeforeDeposit(_, reply) => { let _ = reply.send(Err(status)); }
+            Self::GetDepositTxToPublish(_, reply) | Self::GetSwapTxToPublish(_, reply) => { let _ = reply.send(Err(status)); }
+            Self::PublishDepositTx(_, _, reply) => { let _ = reply.send(Err(status)); }
+            Self::ProposeFeeRateChange(_, _, reply) | Self::AcceptFeeRateChange(_, _, reply) => { let _ = reply.send(Err(status)); }
+            Self::ConfirmPayment(_, _, reply) => { let _ = reply.send(Err(status)); }
+            Self::SignSwapTx(_, reply) => { let _ = reply.send(Err(status)); }
+            Self::ProposeSwapTxFeeBump(_, _, reply) | Self::AcceptSwapTxFeeBump(_, _, reply) => { let _ = reply.send(Err(status)); }
+            Self::GetSwapTxInputPartialSignature(_, reply) => { let _ = reply.send(Err(status)); }
+            Self::CloseTrade(_, reply) => { let _ = reply.send(Err(status)); }
+        }
+    }
+}
+
+/// Run the named protocol step on the trade model, recording it (whether or not it succeeds) in the
+/// trade's audit log, then reply with its result.
+fn run_step<S, R, T>(store: &S, trade_model: &mut TradeModel, step: &str, request: R, reply: Reply<T>,
+                     step_fn: impl FnOnce(&S, &mut TradeModel, R) -> Result<T, Status>)
+    where S: TradeModelStore, R: prost::Message, T: prost::Message
+{
+    run_noted_step(store, trade_model, step, request, reply,
+        |store, trade_model, request| Ok((step_fn(store, trade_model, request)?, None)));
+}
+
+/// Run the named protocol step as [`run_step`] does, with the note the step returns along with its
+/// response (if any) kept in its audit log entry.
+fn run_noted_step<S, R, T>(store: &S, trade_model: &mut TradeModel, step: &str, request: R, reply: Reply<T>,
+                           step_fn: impl FnOnce(&S, &mut TradeModel, R) -> Result<(T, Option<String>), Status>)
+    where S: TradeModelStore, R: prost::Message, T: prost::Message
+{
+    let request_digest = digest(&request);
+    // Mark the peer as seen before running a step taking in its payload, for the step to save:
+    let peer_seen_before = (trade_model.peer_last_seen, trade_model.peer_unresponsive);
+    if PEER_PAYLOAD_STEPS.contains(&step) {
+        (trade_model.peer_last_seen, trade_model.peer_unresponsive) = (Some(SystemTime::now()), false);
+    }
+    // Likewise the first completion of the step, should it succeed:
+    let first_completion = !trade_model.step_completed_at.iter().any(|(completed, _)| completed == step);
+    if first_completion {
+        trade_model.step_completed_at.push((step.to_owned(), SystemTime::now()));
+    }
+    let (result, note) = match step_fn(store, trade_model, request) {
+        Ok((response, note)) => {
+            if first_completion {
+                metrics::TRADE_DURATIONS.observe(trade_model, step);
+            }
+            (Ok(response), note)
+        }
+        Err(status) => {
+            (trade_model.peer_last_seen, trade_model.peer_unresponsive) = peer_seen_before;
+            if first_completion {
+                trade_model.step_completed_at.pop();
+            }
+            (Err(status::with_trade_context(&status, trade_model.trade_id(), trade_model.phase(), step)), None)
+        }
+    };
+    log_audit_entry(store, trade_model.trade_id(), &AuditEntry {
+        step: step.to_owned(),
+        at: SystemTime::now(),
+        request_digest,
+        response_digest: result.as_ref().ok().map(digest),
+        phase: trade_model.phase(),
+        error: result.as_ref().err().map(|status| format!("{:?}: {}", status.code(), status.message())),
+        note,
+        correlation_id: correlation::correlation_id(),
+        client: client_identity::client_identity().map(|client| client.to_string()),
+    });
+    // A send error just means that the caller has gone away (e.g. the RPC was cancelled).
+    let _ = reply.send(result);
+}
+
+/// Check the amounts of the trade against the configured bounds, before we commit to any nonces for
+/// it, naming the config key of the first bound found broken.
+fn check_trade_limits(limits: &TradeLimitConfig, request: &NonceSharesRequest) -> Result<(), Status> {
+    let amount = request.trade_amount;
+    if let Some(min) = limits.min_trade_amount.filter(|&min| amount < min) {
+        return Err(Status::out_of_range(format!(
+            "trade amount of {} sats is below the minimum of {} sats (trade_limit_min_amount_sats)", amount, min)));
+    }
+    if let Some(max) = limits.max_trade_amount.filter(|&max| amount > max) {
+        return Err(Status::out_of_range(format!(
+            "trade amount of {} sats is above the maximum of {} sats (trade_limit_max_amount_sats)", amount, max)));
+    }
+    // Compare each deposit to the bounds as 100 times the deposit against each percentage of the
+    // trade amount, to stay in whole numbers:
+    let pct_of_amount = |pct: u64| u128::from(pct) * u128::from(amount);
+    for (party, deposit) in [("buyer", request.buyers_security_deposit), ("seller", request.sellers_security_deposit)] {
+        let deposit_pct = u128::from(deposit) * 100;
+        if let Some(min) = limits.min_security_deposit_pct.filter(|&min| deposit_pct < pct_of_amount(min)) {
+            return Err(Status::out_of_range(format!("{}'s security deposit of {} sats is below the minimum of {}% \
+                of the trade amount (trade_limit_min_deposit_pct)", party, deposit, min)));
+        }
+        if let Some(max) = limits.max_security_deposit_pct.filter(|&max| deposit_pct > pct_of_amount(max)) {
+            return Err(Status::out_of_range(format!("{}'s security deposit of {} sats is above the maximum of {}% \
+                of the trade amount (trade_limit_max_deposit_pct)", party, deposit, max)));
+        }
+    }
+    Ok(())
+}
+
+fn digest(message: &impl prost::Message) -> [u8; 32] {
+    Sha256::digest(message.encode_to_vec()).into()
+}
+
+/// Append the entry to the trade's audit log. As the step it records has already been done (and
+/// saved), a failure to do so is only logged, rather than failing the step.
+fn log_audit_entry(store: &impl TradeModelStore, trade_id: &str, entry: &AuditEntry) {
+    if let Err(e) = store.log_audit_entry(trade_id, entry) {
+        eprintln!("Could not log audit entry for step {} of trade with id {}: {}", entry.step, trade_id, e);
+    }
+}
+
+/// The steps of the given role, with the status of each for the trade at the given phase (and with
+/// the given audit log), if any.
+/// The networks which the daemon may run trades on. This is only regtest for now, as the txs signed
+/// are not yet real (see the README).
+const SUPPORTED_NETWORKS: &[&str] = &["regtest"];
+/// The protocol steps taking in a payload from the peer, by which the peer is seen to be responsive.
+const PEER_PAYLOAD_STEPS: &[&str] = &["GetNonceShares", "RevealNonceShares", "AcceptFeeRateChange", "GetPartialSignatures",
+    "SignDepositTx", "SignSwapTx", "AcceptSwapTxFeeBump", "CloseTrade"];
+
+/// The build & capability metadata of a daemon run with the given config.
+fn service_info(config: &Config) -> helloworld::ServiceInfo {
+    let (store, store_encrypted) = match &config.store {
+        StoreConfig::Memory => (helloworld::StoreKind::StoreMemory, false),
+        StoreConfig::File { secret_key_source, .. } => (helloworld::StoreKind::StoreFile, secret_key_source.is_some()),
+    };
+    let chain_backend = match config.chain.backend_url {
+        Some(_) => helloworld::ChainBackendKind::ChainEsplora,
+        None if config.chain.mock.enabled => helloworld::ChainBackendKind::ChainMock,
+        None => helloworld::ChainBackendKind::ChainSimulated,
+    };
+    let signer = match config.signer {
+        SignerConfig::Local => helloworld::SignerKind::SignerLocal,
+        SignerConfig::Remote { .. } => helloworld::SignerKind::SignerRemote,
+    };
+    let features = helloworld::ServiceFeatures {
+        store: store.into(),
+        store_encrypted,
+        chain_backend: chain_backend.into(),
+        signer: signer.into(),
+        peer_service: config.serves_peer_service(),
+        key_share_backup: config.backup.is_some(),
+        mediator: config.mediator_pub_key.is_some(),
+        receiver_registry: config.burningman.snapshot_file.is_some(),
+        demo: cfg!(feature = "demo"),
+    };
+    let limits = helloworld::ServiceLimits {
+        init_trade_per_min: config.rate_limits.init_trade_per_min,
+        rpc_per_min: config.rate_limits.rpc_per_min,
+        max_open_trades: config.trade_quota.max_open_trades.map(|n| n as u64),
+        max_open_trades_per_client: config.trade_quota.max_open_trades_per_client.map(|n| n as u64),
+        max_subscriptions_per_trade: config.max_subscriptions_per_trade.map(|n| n as u64),
+    };
+    helloworld::ServiceInfo {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        git_commit: option_env!("GIT_COMMIT").unwrap_or_default().to_owned(),
+        supported_networks: SUPPORTED_NETWORKS.iter().map(|&network| network.to_owned()).collect(),
+        supported_protocol_versions: vec![PROTOCOL_VERSION],
+        features: Some(features),
+        limits: Some(limits),
+    }
+}
+
+fn protocol_descriptor(role: Role, trade: Option<(TradePhase, &[AuditEntry])>) -> ProtocolDescriptor {
+    let steps = descriptor::steps(role).iter().map(|step| ProtocolStep {
+        rpc: step.rpc.to_owned(),
+        phase: step.phase.map(|phase| helloworld::TradePhase::from(phase).into()),
+        optional: step.optional,
+        status: match trade {
+            None => StepStatus::Unknown,
+            Some((phase, audit_log)) if step.is_done(phase, audit_log) => StepStatus::Done,
+            Some(_) => StepStatus::Pending,
+        }.into(),
+    }).collect();
+    ProtocolDescriptor { role: helloworld::Role::from(role).into(), steps }
+}
+
+/// Check that the trade model is at the revision the request expects (if any), so that a step sent
+/// by a client with a stale view of the trade is rejected, rather than interleaved with another.
+fn check_revision(trade_model: &TradeModel, expected_revision: Option<u64>) -> Result<(), Status> {
+    match expected_revision {
+        Some(expected) if expected != trade_model.revision() => Err(Status::aborted(format!(
+            "trade with id {} is at revision {}, not the expected revision {}",
+            trade_model.trade_id(), trade_model.revision(), expected))),
+        _ => Ok(())
+    }
+}
+
+/// Save the trade model once a protocol step has changed it, moving it on to the next revision.
+fn save_trade_model(store: &impl TradeModelStore, trade_model: &mut TradeModel) -> Result<(), Status> {
+    trade_model.bump_revision();
+    store.save_trade_model(trade_model)
+        .map_err(|e| Status::internal(format!("could not save trade model: {}", e)))
+}
+
+fn log_intent(store: &impl TradeModelStore, trade_id: &str, intent: Intent) -> Result<(), Status> {
+    store.log_intent(trade_id, intent)
+        .map_err(|e| Status::internal(format!("could not log intent: {}", e)))
+}
+
+fn log_completion(store: &impl TradeModelStore, trade_id: &str, intent: Intent) -> Result<(), Status> {
+    store.log_completion(trade_id, intent)
+        .map_err(|e| Status::internal(format!("could not log completion: {}", e)))
+}
+
+/// Sign the payload of the given kind & fields with our identity key, for the peer to check.
+fn sign_payload(trade_model: &TradeModel, kind: PayloadKind, fields: &[&[u8]]) -> Result<Vec<u8>, Status> {
+    Ok(trade_model.sign_payload(kind, fields)?.serialize().into())
+}
+
+/// Check the peer's identity signature (held in the given field) on the payload of the given kind &
+/// fields, before any of the payload is taken into the trade model.
+fn verify_peer_payload(trade_model: &TradeModel, kind: PayloadKind, fields: &[&[u8]], signature: &[u8], field: &str) -> Result<(), Status> {
+    trade_model.verify_peer_payload(kind, fields, &decode(signature, field)?)
+        .map_err(|e| e.in_trade(trade_model).at_input(convert::proto_field_path(field)))?;
+    Ok(())
+}
+
+/// The fields of a key shares payload signed for the peer: the static key for the Noise channel
+/// (the last field) is only signed if handed out, as it is only when the trade seals its payloads.
+fn key_shares_fields<'a>(fields: &'a [&'a [u8]]) -> &'a [&'a [u8]] {
+    match fields.split_last() {
+        Some((&[], rest)) => rest,
+        _ => fields,
+    }
+}
+
+fn decode_noise_static_pub_key(bytes: &[u8]) -> Result<[u8; 32], Status> {
+    bytes.try_into().map_err(|_| Status::invalid_argument(format!(
+        "could not decode peers_noise_static_pub_key: expected a 32-byte X25519 key, got {} bytes", bytes.len())))
+}
+
+/// Seal the payload for the peer, with a Noise channel from our static key to the peer's.
+fn seal_for_peer(trade_model: &TradeModel, prologue: &[u8], payload: &[u8]) -> Result<Vec<u8>, Status> {
+    let their_static = trade_model.get_peer_noise_static_pub_key()
+        .ok_or_else(|| Status::failed_precondition("missing peer's static key, as it doesn't seal its payloads"))?;
+    noise::seal(prologue, &trade_model.noise_static_key()?, &their_static, payload)
+        .map_err(|e| Status::internal(format!("could not seal payload for the peer: {}", e)))
+}
+
+/// Open a payload sealed by the peer with [`seal_for_peer`], held in the given field.
+fn open_from_peer(trade_model: &TradeModel, prologue: &[u8], sealed: &[u8], field: &str) -> Result<Vec<u8>, Status> {
+    let their_static = trade_model.get_peer_noise_static_pub_key()
+        .ok_or_else(|| Status::failed_precondition("missing peer's static key, as it doesn't seal its payloads"))?;
+    noise::open(prologue, &trade_model.noise_static_key()?, &their_static, sealed)
+        .ok_or_else(|| Status::invalid_argument(format!("could not open {}: not sealed by the peer", field)))
+}
+
+/// Open a message sealed by the peer, held in the given field.
+fn open_message_from_peer<M: prost::Message + Default>(trade_model: &TradeModel, prologue: &[u8], sealed: &[u8], field: &str) -> Result<M, Status> {
+    M::decode(&*open_from_peer(trade_model, prologue, sealed, field)?)
+        .map_err(|e| Status::invalid_argument(format!("could not decode {}: {}", field, e)))
+}
+
+/// Check that a payload from the peer may be taken in the clear, as it may unless the trade seals
+/// its peer payloads, in which case it should have come sealed in the given field instead.
+fn check_may_be_unsealed(trade_model: &TradeModel, sealed_field: &str) -> Result<(), Status> {
+    if trade_model.seal_peer_payloads {
+        return Err(Status::invalid_argument(format!("missing {}, as the trade seals its peer payloads", sealed_field)));
+    }
+    Ok(())
+}
+
+/// The message from the peer, opened from the given sealed field if set, or else as it is.
+fn open_peer_message<M: prost::Message + Default>(trade_model: &TradeModel, prologue: &[u8], message: M,
+                                                   sealed: Option<&[u8]>, sealed_field: &str) -> Result<M, Status> {
+    if let Some(sealed) = sealed {
+        return open_message_from_peer(trade_model, prologue, sealed, sealed_field);
+    }
+    check_may_be_unsealed(trade_model, sealed_field)?;
+    Ok(message)
+}
+
+/// Our private key share for the peer's output, for the plain field of the response, or else the
+/// sealed one if the trade seals its peer payloads.
+fn my_prv_key_share_for_peer_output(trade_model: &TradeModel) -> Result<(Vec<u8>, Option<Vec<u8>>), Status> {
+    let prv_key_share = trade_model.get_my_private_key_share_for_peer_output()?.serialize();
+    Ok(if trade_model.seal_peer_payloads {
+        (vec![], Some(seal_for_peer(trade_model, PRV_KEY_SHARE_PROLOGUE, &prv_key_share)?))
+    } else {
+        (prv_key_share.into(), None)
+    })
+}
+
+/// The check of the public nonces taken in from the peer of a trade against those seen in its
+/// other trades, as passed to the protocol steps taking them in.
+#[derive(Clone)]
+struct PeerNonceCheck {
+    index: Arc<PeerNonceIndex>,
+    events: TradeEventBus,
+    refuse: bool,
+}
+
+impl PeerNonceCheck {
+    /// Note the given public nonces of the peer in the index, flagging the trade (and publishing a
+    /// [`TradeEvent::PeerNonceReused`]) should the peer have sent one of them in another trade, then
+    /// refusing to take them in, if so configured.
+    fn check<'a>(&self, store: &impl TradeModelStore, trade_model: &mut TradeModel,
+                 nonces: impl IntoIterator<Item=&'a PubNonce>) -> Result<(), Status> {
+        let Some(reused_from) = self.index.record(trade_model.trade_id(), nonces) else { return Ok(()) };
+        if trade_model.peer_nonce_reused_from.is_none() {
+            trade_model.peer_nonce_reused_from = Some(reused_from.clone());
+            save_trade_model(store, trade_model)?;
+        }
+        let trade_id = trade_model.trade_id().to_owned();
+        self.events.publish(TradeEvent::PeerNonceReused { trade_id: trade_id.clone(), reused_from: reused_from.clone() });
+        if self.refuse {
+            return Err(Status::failed_precondition(format!(
+                "the peer of trade with id {} has reused a nonce it sent in trade with id {}", trade_id, reused_from)));
+        }
+        Ok(())
+    }
+}
+
+/// The peer's nonce shares, opened (if sealed) and checked against the peer's identity key.
+fn open_peer_nonce_shares(trade_model: &TradeModel, peer_nonce_shares: NonceSharesMessage) -> Result<NonceSharesMessage, Status> {
+    let sealed = peer_nonce_shares.sealed_payload.clone();
+    let peer_nonce_shares = open_peer_message(trade_model, NONCE_SHARES_PROLOGUE, peer_nonce_shares,
+        sealed.as_deref(), "peers_nonce_shares.sealed_payload")?;
+    verify_peer_payload(trade_model, NonceSharesMessage::KIND, &peer_nonce_shares.signed_fields(),
+        &peer_nonce_shares.identity_signature, "peers_nonce_shares.identity_signature")?;
+    Ok(peer_nonce_shares)
+}
+
+/// The peer's partial signatures, opened (if sealed) and checked against the peer's identity key,
+/// along with the swap tx partial signature among them, unless it has been redacted.
+fn open_peer_partial_signatures(trade_model: &TradeModel, peers_partial_signatures: PartialSignaturesMessage) -> Result<PartialSignaturesMessage, Status> {
+    let sealed = peers_partial_signatures.sealed_payload.clone();
+    let swap_tx_input = peers_partial_signatures.swap_tx_input.clone();
+    let mut peers_partial_signatures = open_peer_message(trade_model, PARTIAL_SIGNATURES_PROLOGUE,
+        peers_partial_signatures, sealed.as_deref(), "peers_partial_signatures.sealed_payload")?;
+    peers_partial_signatures.swap_tx_input = swap_tx_input;
+    if let Some(SwapTxInput::SealedSwapTxInputPartialSignature(sealed)) = &peers_partial_signatures.swap_tx_input {
+        let signed_sig: SignedPartialSignature = open_message_from_peer(trade_model, SWAP_TX_INPUT_PARTIAL_SIGNATURE_PROLOGUE,
+            sealed, "peers_partial_signatures.sealed_swap_tx_input_partial_signature")?;
+        peers_partial_signatures.swap_tx_input = Some(SwapTxInput::SwapTxInputPartialSignature(signed_sig.partial_signature));
+        peers_partial_signatures.swap_tx_input_identity_signature = Some(signed_sig.identity_signature);
+    }
+    verify_peer_payload(trade_model, PartialSignaturesMessage::KIND, &peers_partial_signatures.signed_fields(),
+        &peers_partial_signatures.identity_signature, "peers_partial_signatures.identity_signature")?;
+    if let Some(SwapTxInput::SwapTxInputPartialSignature(sig)) = &peers_partial_signatures.swap_tx_input {
+        // This is redacted (along with its signature) when the buyer's partial signatures reach the seller:
+        verify_peer_payload(trade_model, PayloadKind::SwapTxInputPartialSignature, &[sig],
+            peers_partial_signatures.swap_tx_input_identity_signature.as_deref().unwrap_or_default(),
+            "peers_partial_signatures.swap_tx_input_identity_signature")?;
+    }
+    Ok(peers_partial_signatures)
+}
+
+/// The peer's partial signature on the swap tx, opened from the given sealed field if set, and
+/// checked against the peer's identity key.
+fn open_peer_swap_tx_input_partial_signature(trade_model: &TradeModel, plain_sig: SignedPartialSignature,
+                                             sealed: Option<&[u8]>) -> Result<SignedPartialSignature, Status> {
+    let signed_sig = open_peer_message(trade_model, SWAP_TX_INPUT_PARTIAL_SIGNATURE_PROLOGUE, plain_sig,
+        sealed, "sealed_swap_tx_input_peers_partial_signature")?;
+    verify_peer_payload(trade_model, PayloadKind::SwapTxInputPartialSignature,
+        &[&signed_sig.partial_signature], &signed_sig.identity_signature, "swap_tx_input_peers_identity_signature")?;
+    Ok(signed_sig)
+}
+
+/// The peer's private key share for our output, if given, opened from the given sealed field if
+/// set. (It needs no identity signature, as it is checked against the peer's public key share.)
+fn open_peer_prv_key_share(trade_model: &TradeModel, plain: Option<Vec<u8>>, sealed: Option<&[u8]>) -> Result<Option<Vec<u8>>, Status> {
+    if let Some(sealed) = sealed {
+        return Ok(Some(open_from_peer(trade_model, PRV_KEY_SHARE_PROLOGUE, sealed, "sealed_my_output_peers_prv_key_share")?));
+    }
+    if plain.is_some() {
+        check_may_be_unsealed(trade_model, "sealed_my_output_peers_prv_key_share")?;
+    }
+    Ok(plain)
+}
+
+/// Check a payload delivered directly by the peer's daemon, as far as may be done before the step
+/// taking it in is run.
+fn check_peer_payload(trade_model: &TradeModel, payload: &Payload) -> Result<(), Status> {
+    match payload {
+        Payload::NonceShares(m) => { open_peer_nonce_shares(trade_model, m.clone())?; }
+        Payload::PartialSignatures(m) => { open_peer_partial_signatures(trade_model, m.clone())?; }
+        Payload::SwapTxInputPartialSignature(m) => {
+            let plain_sig = SignedPartialSignature {
+                partial_signature: m.partial_signature.clone(),
+                identity_signature: m.identity_signature.clone(),
+            };
+            open_peer_swap_tx_input_partial_signature(trade_model, plain_sig, m.sealed_partial_signature.as_deref())?;
+        }
+        Payload::PrvKeyShare(m) => {
+            let prv_key_share = open_peer_prv_key_share(trade_model, Some(m.prv_key_share.clone()),
+                m.sealed_prv_key_share.as_deref())?;
+            decode::<Scalar>(&prv_key_share.unwrap_or_default(), "prv_key_share")?;
+        }
+    }
+    Ok(())
+}
+
+fn get_nonce_shares(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &NonceSharesRequest,
+                    faults: &FaultInjector) -> Result<NonceSharesMessage, Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    // Checked ahead of the signature, which would otherwise fail first between two buyers or sellers:
+    trade_model.check_peer_role(decode_role(request.peers_role, "peers_role")?)?;
+    trade_model.set_peer_identity_pub_key(decode(&request.peers_identity_pub_key, "peers_identity_pub_key")?)?;
+    verify_peer_payload(trade_model, PayloadKind::KeyShares, key_shares_fields(&[
+        &request.buyer_output_peers_pub_key_share, &request.seller_output_peers_pub_key_share,
+        &request.peers_half_deposit_psbt, request.peers_trade_id.as_bytes(), &request.peers_role.to_be_bytes(),
+        &request.peers_noise_static_pub_key,
+    ]), &request.peers_pub_key_shares_identity_signature, "peers_pub_key_shares_identity_signature")?;
+    if !request.peers_noise_static_pub_key.is_empty() {
+        trade_model.set_peer_noise_static_pub_key(decode_noise_static_pub_key(&request.peers_noise_static_pub_key)?)?;
+    } else if trade_model.seal_peer_payloads {
+        return Err(Status::failed_precondition("the trade seals its peer payloads, but the peer doesn't"));
+    }
+    trade_model.set_peer_funding_inputs(&request.peers_trade_id,
+        decode_half_deposit_psbt(&request.peers_half_deposit_psbt, "peers_half_deposit_psbt")?)
+        .map_err(|e| e.in_trade(trade_model).in_field("peersHalfDepositPsbt"))?;
+    trade_model.set_peer_key_shares(
+        decode(&request.buyer_output_peers_pub_key_share, "buyer_output_peers_pub_key_share")?,
+        decode(&request.seller_output_peers_pub_key_share, "seller_output_peers_pub_key_share")?);
+    trade_model.aggregate_key_shares()?;
+    trade_model.init_my_nonce_shares()?;
+    trade_model.trade_amount = Some(request.trade_amount);
+    trade_model.buyers_security_deposit = Some(request.buyers_security_deposit);
+    trade_model.sellers_security_deposit = Some(request.sellers_security_deposit);
+    trade_model.deposit_tx_fee_rate = Some(request.deposit_tx_fee_rate);
+    trade_model.prepared_tx_fee_rate = Some(request.prepared_tx_fee_rate);
+    trade_model.check_funding()?;
+    save_trade_model(store, trade_model)?;
+    my_nonce_shares_or_commitments(trade_model, faults)
+}
+
+/// Discard the trade's signing session, so that the protocol may be rerun from our fresh nonce
+/// shares, with the fee rates renegotiated if given.
+fn reset_signing_session(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &ResetSigningSessionRequest,
+                         faults: &FaultInjector) -> Result<NonceSharesMessage, Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    trade_model.reset_signing_session()?;
+    if let Some(fee_rate) = request.deposit_tx_fee_rate {
+        trade_model.deposit_tx_fee_rate = Some(fee_rate);
+    }
+    if let Some(fee_rate) = request.prepared_tx_fee_rate {
+        trade_model.prepared_tx_fee_rate = Some(fee_rate);
+    }
+    save_trade_model(store, trade_model)?;
+    my_nonce_shares_or_commitments(trade_model, faults)
+}
+
+/// Our nonce shares for the peer, or just our commitments to them if the trade commits to nonces.
+fn my_nonce_shares_or_commitments(trade_model: &TradeModel, faults: &FaultInjector) -> Result<NonceSharesMessage, Status> {
+    if trade_model.commit_to_nonces {
+        let my_nonce_commitments = trade_model.get_my_nonce_commitments()
+            .ok_or_else(|| Status::internal("missing nonce shares"))?;
+        let mut nonce_commitments = NonceCommitmentsMessage::from(my_nonce_commitments);
+        nonce_commitments.identity_signature = sign_payload(trade_model, NonceCommitmentsMessage::KIND,
+            &nonce_commitments.signed_fields())?;
+        return Ok(NonceSharesMessage { nonce_commitments: Some(nonce_commitments), ..Default::default() });
+    }
+    my_nonce_shares_message(trade_model, faults)
+}
+
+/// Take in the peer's nonce commitments, then hand out the nonce shares withheld by
+/// [`get_nonce_shares`] until now.
+fn reveal_nonce_shares(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: RevealNonceSharesRequest,
+                       faults: &FaultInjector) -> Result<NonceSharesMessage, Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    if !trade_model.commit_to_nonces {
+        return Err(Status::failed_precondition(format!("trade with id {} does not commit to its nonces",
+            trade_model.trade_id())));
+    }
+    let peers_nonce_commitments = request.peers_nonce_commitments
+        .ok_or_else(|| Status::not_found("missing request.peers_nonce_commitments"))?;
+    verify_peer_payload(trade_model, NonceCommitmentsMessage::KIND, &peers_nonce_commitments.signed_fields(),
+        &peers_nonce_commitments.identity_signature, "peers_nonce_commitments.identity_signature")?;
+    trade_model.set_peer_nonce_commitments(&peers_nonce_commitments.try_into()
+        .map_err(|e: ConvertError| e.in_field("peers_nonce_commitments"))?)?;
+    save_trade_model(store, trade_model)?;
+    my_nonce_shares_message(trade_model, faults)
+}
+
+/// Our nonce shares, signed (and sealed, if the trade seals its peer payloads) for the peer.
+fn my_nonce_shares_message(trade_model: &TradeModel, faults: &FaultInjector) -> Result<NonceSharesMessage, Status> {
+    let my_nonce_shares = trade_model.get_my_nonce_shares()
+        .ok_or_else(|| Status::internal("missing nonce shares"))?;
+    let mut message = NonceSharesMessage {
+        warning_tx_fee_bump_address: "address1".to_owned(),
+        redirect_tx_fee_bump_address: "address2".to_owned(),
+        half_deposit_psbt: vec![],
+        ..my_nonce_shares.into()
+    };
+    faults.corrupt_nonce_shares(trade_model.trade_id(), &mut message);
+    message.identity_signature = sign_payload(trade_model, NonceSharesMessage::KIND, &message.signed_fields())?;
+    if trade_model.seal_peer_payloads {
+        message = NonceSharesMessage {
+            sealed_payload: Some(seal_for_peer(trade_model, NONCE_SHARES_PROLOGUE, &message.encode_to_vec())?),
+            ..Default::default()
+        };
+    }
+    Ok(faults.replay_nonce_shares(trade_model.trade_id(), message))
+}
+
+fn get_partial_signatures(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: PartialSignaturesRequest,
+                          receiver_set: Option<&ReceiverSet>, mediator_pub_key: Option<Point>, faults: &FaultInjector,
+                          nonce_check: &PeerNonceCheck)
+    -> Result<PartialSignaturesMessage, Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    let redirect_receivers = redirect_receivers(trade_model, &request, receiver_set, mediator_pub_key)?;
+    let peer_nonce_shares: ExchangedNonces<ByVal> = open_peer_nonce_shares(trade_model, request.peers_nonce_shares
+        .ok_or_else(|| Status::not_found("missing request.peers_nonce_shares"))?)?
+        .try_into().map_err(|e: ConvertError| e.in_field("peers_nonce_shares"))?;
+    let mut nonces = Vec::new();
+    peer_nonce_shares.for_each_field(|_, nonce| nonces.push(nonce.clone()));
+    // Signing consumes our secret nonces, so a repeated call (as by a client retrying after losing
+    // the response) may only be answered with the partial signatures already made:
+    if trade_model.get_my_partial_signatures_on_peer_txs().is_some() {
+        check_same_signing_inputs(trade_model, &nonces, &redirect_receivers)?;
+        return my_partial_signatures_message(trade_model, faults);
+    }
+    nonce_check.check(store, trade_model, &nonces)?;
+    trade_model.peer_nonce_shares_mut().set(peer_nonce_shares);
+    trade_model.aggregate_nonce_shares()?;
+    log_intent(store, &request.trade_id, Intent::ConsumeNonces)?;
+    trade_model.sign_partial()?;
+    trade_model.redirect_receivers = redirect_receivers;
+    save_trade_model(store, trade_model)?;
+    log_completion(store, &request.trade_id, Intent::ConsumeNonces)?;
+    my_partial_signatures_message(trade_model, faults)
+}
+
+/// Check that a repeated call to sign the trade is for the same peer nonce shares & redirect tx
+/// receivers as were signed for, failing with `ABORTED` if not, as the trade can then only be signed
+/// again with fresh nonces, in a new signing session.
+fn check_same_signing_inputs(trade_model: &mut TradeModel, nonces: &[PubNonce], redirect_receivers: &[(String, u64)])
+    -> Result<(), Status>
+{
+    let mut signed_nonces = Vec::new();
+    trade_model.peer_nonce_shares_mut().for_each_field(|_, nonce| signed_nonces.extend((*nonce).clone()));
+    let differs = if signed_nonces != nonces {
+        "peer nonce shares"
+    } else if trade_model.redirect_receivers != redirect_receivers {
+        "redirect tx receivers"
+    } else {
+        return Ok(());
+    };
+    Err(Status::aborted(format!("trade with id {} has already been signed for other {}, using up our secret nonces: \
+        reset the signing session (ResetSigningSession) to sign for new ones", trade_model.trade_id(), differs)))
+}
+
+/// Our partial signatures on the peer's txs, signed (and sealed, if the trade seals its peer
+/// payloads) for the peer.
+fn my_partial_signatures_message(trade_model: &TradeModel, faults: &FaultInjector) -> Result<PartialSignaturesMessage, Status> {
+    let my_partial_signatures = trade_model.get_my_partial_signatures_on_peer_txs()
+        .ok_or_else(|| Status::internal("missing partial signatures"))?;
+    let mut message = PartialSignaturesMessage::from(my_partial_signatures);
+    faults.corrupt_partial_signatures(trade_model.trade_id(), &mut message);
+    message.identity_signature = sign_payload(trade_model, PartialSignaturesMessage::KIND, &message.signed_fields())?;
+    let Some(SwapTxInput::SwapTxInputPartialSignature(swap_tx_input_partial_signature)) = message.swap_tx_input.take() else {
+        return Err(Status::internal("missing swap tx partial signature"));
+    };
+    let swap_tx_input_identity_signature = sign_payload(trade_model, PayloadKind::SwapTxInputPartialSignature,
+        &[&swap_tx_input_partial_signature])?;
+    if trade_model.seal_peer_payloads {
+        let swap_tx_input_partial_signature = SignedPartialSignature {
+            partial_signature: swap_tx_input_partial_signature,
+            identity_signature: swap_tx_input_identity_signature,
+        };
+        message = PartialSignaturesMessage {
+            sealed_payload: Some(seal_for_peer(trade_model, PARTIAL_SIGNATURES_PROLOGUE, &message.encode_to_vec())?),
+            swap_tx_input: Some(SwapTxInput::SealedSwapTxInputPartialSignature(seal_for_peer(trade_model,
+                SWAP_TX_INPUT_PARTIAL_SIGNATURE_PROLOGUE, &swap_tx_input_partial_signature.encode_to_vec())?)),
+            ..Default::default()
+        };
+    } else {
+        message.swap_tx_input = Some(SwapTxInput::SwapTxInputPartialSignature(swap_tx_input_partial_signature));
+        message.swap_tx_input_identity_signature = Some(swap_tx_input_identity_signature);
+    }
+    Ok(message)
+}
+
+/// The receivers of the redirect tx to sign, by address & amount: those of the given burning-man
+/// receiver set, if the daemon has a receiver registry, paid the whole of the deposit tx's amount,
+/// otherwise those asked for in the request (signed off by the mediator, if the daemon has one).
+fn redirect_receivers(trade_model: &TradeModel, request: &PartialSignaturesRequest, receiver_set: Option<&ReceiverSet>,
+                      mediator_pub_key: Option<Point>) -> Result<Vec<(String, u64)>, Status> {
+    let requested: Vec<_> = request.receivers.iter().map(|r| (r.address.clone(), r.amount)).collect();
+    if let Some(receiver_set) = receiver_set {
+        let amount = [trade_model.trade_amount, trade_model.buyers_security_deposit, trade_model.sellers_security_deposit]
+            .into_iter().try_fold(0u64, |sum, amount| sum.checked_add(amount?))
+            .ok_or_else(|| Status::failed_precondition(format!("trade with id {} has no (valid) amounts set",
+                trade_model.trade_id())))?;
+        let outputs = receiver_set.outputs(amount);
+        // A front-end may still pass the receivers it expects, but they must then be the registry's:
+        if !requested.is_empty() && requested != outputs {
+            return Err(Status::invalid_argument(format!("request.receivers differ from the burning-man receiver set in \
+                force from height {}", receiver_set.activation_height)));
+        }
+        return Ok(outputs);
+    }
+    if let Some(mediator_pub_key) = mediator_pub_key {
+        check_redirect_receivers(trade_model, mediator_pub_key, request)?;
+    }
+    Ok(requested)
+}
+
+/// Check that the receivers of the redirect tx asked for have been signed off by the mediator,
+/// before we sign the redirect tx paying them, so that the peer cannot slip in receivers of its own.
+fn check_redirect_receivers(trade_model: &TradeModel, mediator_pub_key: Point, request: &PartialSignaturesRequest)
+    -> Result<(), Status>
+{
+    if request.receivers.is_empty() {
+        return Err(Status::invalid_argument("missing request.receivers, which must be signed by the mediator"));
+    }
+    let signature = decode(&request.receivers_mediator_signature, "receivers_mediator_signature")?;
+    let receivers: Vec<_> = request.receivers.iter().map(|r| (&r.address[..], r.amount)).collect();
+    trade_model.verify_redirect_receivers(mediator_pub_key, &receivers, &signature)?;
+    Ok(())
+}
+
+/// Propose a change of the prepared tx fee rate to the peer or, given the peer's acceptance of the
+/// change proposed, complete it. Either way, the message returned for the peer holds our consent to
+/// the new fee rate, noted (along with the peer's, if given) for the audit log.
+fn propose_fee_rate_change(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: FeeRateChangeRequest,
+                           nonce_check: &PeerNonceCheck) -> Result<(FeeRateChangeMessage, Option<String>), Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    let Some(peers_message) = request.peers_message else {
+        let fee_rate = request.prepared_tx_fee_rate
+            .ok_or_else(|| Status::not_found("missing request.prepared_tx_fee_rate"))?;
+        trade_model.start_fee_rate_change(fee_rate)?;
+        save_trade_model(store, trade_model)?;
+        let (message, my_signature) = my_fee_rate_change_message(trade_model)?;
+        return Ok((message, Some(fee_rate_consent_note("prepared tx", fee_rate, Some(&my_signature), None))));
+    };
+    let pending_fee_rate = trade_model.pending_prepared_tx_fee_rate()
+        .ok_or(ProtocolErrorKind::MissingFeeRateChange)?;
+    let peers_message = open_peer_fee_rate_change(trade_model, peers_message, request.prepared_tx_fee_rate)?;
+    check_fee_rate_agreed(pending_fee_rate, peers_message.prepared_tx_fee_rate, "peers_message.prepared_tx_fee_rate")?;
+    let peers_partial_signatures = fee_rate_change_partial_signatures(&peers_message)
+        .map_err(|e| e.in_field("peers_message"))?
+        .ok_or_else(|| Status::invalid_argument("missing request.peers_message partial signatures, as the peer has \
+            yet to accept the change"))?;
+    set_fee_rate_change_peer_nonce_shares(store, trade_model, &peers_message, nonce_check)?;
+    log_intent(store, &request.trade_id, Intent::ConsumeNonces)?;
+    trade_model.sign_fee_rate_change()?;
+    // Our partial signatures are moved into place with the rest of the change as it completes:
+    let (message, my_signature) = my_fee_rate_change_message(trade_model)?;
+    trade_model.fee_rate_change_peer_partial_signatures_on_my_txs_mut()
+        .ok_or(ProtocolErrorKind::MissingFeeRateChange)?
+        .set(peers_partial_signatures);
+    trade_model.complete_fee_rate_change()
+        .map_err(|e| e.in_trade(trade_model).in_field("peersMessage"))?;
+    save_trade_model(store, trade_model)?;
+    log_completion(store, &request.trade_id, Intent::ConsumeNonces)?;
+    let note = fee_rate_consent_note("prepared tx", pending_fee_rate, Some(&my_signature), Some(&peers_message.identity_signature));
+    Ok((message, Some(note)))
+}
+
+/// Accept the change of the prepared tx fee rate proposed by the peer, or, given the peer's partial
+/// signatures on our re-signed txs, complete it, noting the consent of either party (as for
+/// [`propose_fee_rate_change`]). The message returned on completion holds just the fee rate, as
+/// there is nothing more to pass on to the peer.
+fn accept_fee_rate_change(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: FeeRateChangeRequest,
+                          nonce_check: &PeerNonceCheck) -> Result<(FeeRateChangeMessage, Option<String>), Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    let peers_message = open_peer_fee_rate_change(trade_model, request.peers_message
+        .ok_or_else(|| Status::not_found("missing request.peers_message"))?, request.prepared_tx_fee_rate)?;
+    let fee_rate = peers_message.prepared_tx_fee_rate;
+    let peers_consent = Some(&peers_message.identity_signature[..]);
+    let Some(peers_partial_signatures) = fee_rate_change_partial_signatures(&peers_message)
+        .map_err(|e| e.in_field("peers_message"))? else
+    {
+        // Accepting a proposal needs our own consent to the fee rate, not just the peer's:
+        if request.prepared_tx_fee_rate.is_none() {
+            return Err(Status::not_found("missing request.prepared_tx_fee_rate"));
+        }
+        trade_model.start_fee_rate_change(fee_rate)?;
+        set_fee_rate_change_peer_nonce_shares(store, trade_model, &peers_message, nonce_check)?;
+        log_intent(store, &request.trade_id, Intent::ConsumeNonces)?;
+        trade_model.sign_fee_rate_change()?;
+        save_trade_model(store, trade_model)?;
+        log_completion(store, &request.trade_id, Intent::ConsumeNonces)?;
+        let (message, my_signature) = my_fee_rate_change_message(trade_model)?;
+        return Ok((message, Some(fee_rate_consent_note("prepared tx", fee_rate, Some(&my_signature), peers_consent))));
+    };
+    let pending_fee_rate = trade_model.pending_prepared_tx_fee_rate()
+        .ok_or(ProtocolErrorKind::MissingFeeRateChange)?;
+    check_fee_rate_agreed(pending_fee_rate, fee_rate, "peers_message.prepared_tx_fee_rate")?;
+    trade_model.fee_rate_change_peer_partial_signatures_on_my_txs_mut()
+        .ok_or(ProtocolErrorKind::MissingFeeRateChange)?
+        .set(peers_partial_signatures);
+    trade_model.complete_fee_rate_change()
+        .map_err(|e| e.in_trade(trade_model).in_field("peersMessage"))?;
+    save_trade_model(store, trade_model)?;
+    let message = FeeRateChangeMessage { prepared_tx_fee_rate: fee_rate, ..Default::default() };
+    Ok((message, Some(fee_rate_consent_note("prepared tx", fee_rate, None, peers_consent))))
+}
+
+/// The peer's fee rate change message, opened (if sealed) and checked against the peer's identity
+/// key, and against the fee rate we consent to, if given.
+fn open_peer_fee_rate_change(trade_model: &TradeModel, peers_message: FeeRateChangeMessage, fee_rate: Option<f64>)
+    -> Result<FeeRateChangeMessage, Status>
+{
+    let sealed = peers_message.sealed_payload.clone();
+    let peers_message = open_peer_message(trade_model, FEE_RATE_CHANGE_PROLOGUE, peers_message,
+        sealed.as_deref(), "peers_message.sealed_payload")?;
+    let peers_fee_rate = peers_message.prepared_tx_fee_rate.to_be_bytes();
+    verify_peer_payload(trade_model, PayloadKind::FeeRateChange, &fee_rate_change_signed_fields(&peers_message, &peers_fee_rate),
+        &peers_message.identity_signature, "peers_message.identity_signature")?;
+    if let Some(fee_rate) = fee_rate {
+        check_fee_rate_agreed(fee_rate, peers_message.prepared_tx_fee_rate, "peers_message.prepared_tx_fee_rate")?;
+    }
+    Ok(peers_message)
+}
+
+fn check_fee_rate_agreed(expected: f64, fee_rate: f64, field: &str) -> Result<(), Status> {
+    #[expect(clippy::float_cmp, reason = "the peer must send back exactly the fee rate agreed")]
+    if fee_rate != expected {
+        return Err(Status::invalid_argument(format!("{} is {}, not the agreed fee rate {}", field, fee_rate, expected)));
+    }
+    Ok(())
+}
+
+fn set_fee_rate_change_peer_nonce_shares(store: &impl TradeModelStore, trade_model: &mut TradeModel,
+                                         peers_message: &FeeRateChangeMessage, nonce_check: &PeerNonceCheck) -> Result<(), Status> {
+    let peer_nonce_shares: ExchangedPreparedTxNonces<ByVal> = peers_message.try_into()
+        .map_err(|e: ConvertError| e.in_field("peers_message"))?;
+    let mut nonces = Vec::new();
+    peer_nonce_shares.for_each_field(|_, nonce| nonces.push(nonce.clone()));
+    nonce_check.check(store, trade_model, &nonces)?;
+    trade_model.fee_rate_change_peer_nonce_shares_mut()
+        .ok_or(ProtocolErrorKind::MissingFeeRateChange)?
+        .set(peer_nonce_shares);
+    Ok(())
+}
+
+/// Our fee rate change message for the peer, with our partial signatures on the peer's re-signed
+/// txs once made, signed (and sealed, if the trade seals its peer payloads), along with our
+/// identity signature on it, which is our consent to the new fee rate.
+fn my_fee_rate_change_message(trade_model: &TradeModel) -> Result<(FeeRateChangeMessage, Vec<u8>), Status> {
+    let fee_rate = trade_model.pending_prepared_tx_fee_rate()
+        .ok_or(ProtocolErrorKind::MissingFeeRateChange)?;
+    let my_nonce_shares = trade_model.get_my_fee_rate_change_nonce_shares()
+        .ok_or_else(|| Status::internal("missing nonce shares"))?;
+    let mut message = FeeRateChangeMessage { prepared_tx_fee_rate: fee_rate, ..my_nonce_shares.into() };
+    if let Some(sigs) = trade_model.get_my_fee_rate_change_partial_signatures_on_peer_txs() {
+        message.peers_warning_tx_buyer_input_partial_signature = Some(sigs.peers_warning_tx_buyer_input_partial_signature.serialize().into());
+        message.peers_warning_tx_seller_input_partial_signature = Some(sigs.peers_warning_tx_seller_input_partial_signature.serialize().into());
+        message.peers_redirect_tx_input_partial_signature = Some(sigs.peers_redirect_tx_input_partial_signature.serialize().into());
+    }
+    let fee_rate = fee_rate.to_be_bytes();
+    message.identity_signature = sign_payload(trade_model, PayloadKind::FeeRateChange,
+        &fee_rate_change_signed_fields(&message, &fee_rate))?;
+    let my_signature = message.identity_signature.clone();
+    if trade_model.seal_peer_payloads {
+        message = FeeRateChangeMessage {
+            sealed_payload: Some(seal_for_peer(trade_model, FEE_RATE_CHANGE_PROLOGUE, &message.encode_to_vec())?),
+            ..Default::default()
+        };
+    }
+    Ok((message, my_signature))
+}
+
+/// Propose re-signing the swap tx at a higher fee rate to the seller or, given the seller's
+/// acceptance, complete the bump, noting the consent of either party as for
+/// [`propose_fee_rate_change`]. Only the buyer may propose a bump, so that it holds the adaptor
+/// signature on the re-signed swap tx before its own partial signature on it goes out.
+fn propose_swap_tx_fee_bump(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: SwapTxFeeBumpRequest,
+                            nonce_check: &PeerNonceCheck) -> Result<(SwapTxFeeBumpMessage, Option<String>), Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    if !trade_model.am_buyer() {
+        return Err(Status::failed_precondition(format!(
+            "trade with id {} is the seller's, which may only accept a swap tx fee bump", trade_model.trade_id())));
+    }
+    let Some(peers_message) = request.peers_message else {
+        let fee_rate = request.swap_tx_fee_rate
+            .ok_or_else(|| Status::not_found("missing request.swap_tx_fee_rate"))?;
+        trade_model.start_swap_tx_fee_bump(fee_rate)?;
+        save_trade_model(store, trade_model)?;
+        let (message, my_signature) = my_swap_tx_fee_bump_message(trade_model)?;
+        return Ok((message, Some(fee_rate_consent_note("swap tx", fee_rate, Some(&my_signature), None))));
+    };
+    let pending_fee_rate = trade_model.pending_swap_tx_fee_rate()
+        .ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)?;
+    let peers_message = open_peer_swap_tx_fee_bump(trade_model, peers_message, request.swap_tx_fee_rate)?;
+    check_fee_rate_agreed(pending_fee_rate, peers_message.swap_tx_fee_rate, "peers_message.swap_tx_fee_rate")?;
+    let peers_partial_signature = decode_opt(peers_message.swap_tx_input_partial_signature.as_deref(),
+        "peers_message.swap_tx_input_partial_signature")?
+        .ok_or_else(|| Status::invalid_argument("missing request.peers_message.swap_tx_input_partial_signature, as the \
+            peer has yet to accept the bump"))?;
+    set_swap_tx_fee_bump_peer_nonce_share(store, trade_model, &peers_message, nonce_check)?;
+    *trade_model.swap_tx_fee_bump_peer_partial_signature_mut()
+        .ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)? = Some(peers_partial_signature);
+    log_intent(store, &request.trade_id, Intent::ConsumeNonces)?;
+    trade_model.sign_swap_tx_fee_bump()?;
+    // Our partial signature is moved into place with the rest of the bump as it completes:
+    let (message, my_signature) = my_swap_tx_fee_bump_message(trade_model)?;
+    trade_model.complete_swap_tx_fee_bump()?;
+    save_trade_model(store, trade_model)?;
+    log_completion(store, &request.trade_id, Intent::ConsumeNonces)?;
+    let note = fee_rate_consent_note("swap tx", pending_fee_rate, Some(&my_signature), Some(&peers_message.identity_signature));
+    Ok((message, Some(note)))
+}
+
+/// Accept the buyer's proposal to re-sign the swap tx at a higher fee rate, or, given the buyer's
+/// partial signature on the re-signed swap tx, complete the bump, noting the consent of either party
+/// (as for [`propose_fee_rate_change`]). The message returned on completion holds the fee rate and
+/// the re-signed swap tx, to publish in place of the old one, with nothing to pass on to the peer.
+fn accept_swap_tx_fee_bump(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: SwapTxFeeBumpRequest,
+                           nonce_check: &PeerNonceCheck) -> Result<(SwapTxFeeBumpMessage, Option<String>), Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    if trade_model.am_buyer() {
+        return Err(Status::failed_precondition(format!(
+            "trade with id {} is the buyer's, which may only propose a swap tx fee bump", trade_model.trade_id())));
+    }
+    let peers_message = open_peer_swap_tx_fee_bump(trade_model, request.peers_message
+        .ok_or_else(|| Status::not_found("missing request.peers_message"))?, request.swap_tx_fee_rate)?;
+    let fee_rate = peers_message.swap_tx_fee_rate;
+    let peers_consent = Some(&peers_message.identity_signature[..]);
+    let Some(peers_partial_signature) = decode_opt(peers_message.swap_tx_input_partial_signature.as_deref(),
+        "peers_message.swap_tx_input_partial_signature")? else
+    {
+        // Accepting a proposal needs our own consent to the fee rate, not just the peer's:
+        if request.swap_tx_fee_rate.is_none() {
+            return Err(Status::not_found("missing request.swap_tx_fee_rate"));
+        }
+        trade_model.start_swap_tx_fee_bump(fee_rate)?;
+        set_swap_tx_fee_bump_peer_nonce_share(store, trade_model, &peers_message, nonce_check)?;
+        log_intent(store, &request.trade_id, Intent::ConsumeNonces)?;
+        trade_model.sign_swap_tx_fee_bump()?;
+        save_trade_model(store, trade_model)?;
+        log_completion(store, &request.trade_id, Intent::ConsumeNonces)?;
+        let (message, my_signature) = my_swap_tx_fee_bump_message(trade_model)?;
+        return Ok((message, Some(fee_rate_consent_note("swap tx", fee_rate, Some(&my_signature), peers_consent))));
+    };
+    let pending_fee_rate = trade_model.pending_swap_tx_fee_rate()
+        .ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)?;
+    check_fee_rate_agreed(pending_fee_rate, fee_rate, "peers_message.swap_tx_fee_rate")?;
+    *trade_model.swap_tx_fee_bump_peer_partial_signature_mut()
+        .ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)? = Some(peers_partial_signature);
+    trade_model.complete_swap_tx_fee_bump()?;
+    save_trade_model(store, trade_model)?;
+    let sig = trade_model.compute_swap_tx_input_signature()?;
+    let message = SwapTxFeeBumpMessage {
+        swap_tx_fee_rate: fee_rate,
+        // As for SignSwapTx, the (final) swap tx signature stands in for the actual signed tx:
+        swap_tx: Some(sig.serialize().into()),
+        ..Default::default()
+    };
+    Ok((message, Some(fee_rate_consent_note("swap tx", fee_rate, None, peers_consent))))
+}
+
+/// The peer's swap tx fee bump message, opened (if sealed) and checked against the peer's identity
+/// key, and against the fee rate we consent to, if given.
+fn open_peer_swap_tx_fee_bump(trade_model: &TradeModel, peers_message: SwapTxFeeBumpMessage, fee_rate: Option<f64>)
+    -> Result<SwapTxFeeBumpMessage, Status>
+{
+    let sealed = peers_message.sealed_payload.clone();
+    let peers_message = open_peer_message(trade_model, SWAP_TX_FEE_BUMP_PROLOGUE, peers_message,
+        sealed.as_deref(), "peers_message.sealed_payload")?;
+    let peers_fee_rate = peers_message.swap_tx_fee_rate.to_be_bytes();
+    verify_peer_payload(trade_model, PayloadKind::SwapTxFeeBump, &swap_tx_fee_bump_signed_fields(&peers_message, &peers_fee_rate),
+        &peers_message.identity_signature, "peers_message.identity_signature")?;
+    if let Some(fee_rate) = fee_rate {
+        check_fee_rate_agreed(fee_rate, peers_message.swap_tx_fee_rate, "peers_message.swap_tx_fee_rate")?;
+    }
+    Ok(peers_message)
+}
+
+fn set_swap_tx_fee_bump_peer_nonce_share(store: &impl TradeModelStore, trade_model: &mut TradeModel,
+                                         peers_message: &SwapTxFeeBumpMessage, nonce_check: &PeerNonceCheck) -> Result<(), Status> {
+    let peer_nonce_share = decode(&peers_message.swap_tx_input_nonce_share, "peers_message.swap_tx_input_nonce_share")?;
+    nonce_check.check(store, trade_model, [&peer_nonce_share])?;
+    *trade_model.swap_tx_fee_bump_peer_nonce_share_mut()
+        .ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)? = Some(peer_nonce_share);
+    Ok(())
+}
+
+/// Our swap tx fee bump message for the peer, with our partial signature on the re-signed swap tx
+/// once made, signed & sealed as for [`my_fee_rate_change_message`].
+fn my_swap_tx_fee_bump_message(trade_model: &TradeModel) -> Result<(SwapTxFeeBumpMessage, Vec<u8>), Status> {
+    let fee_rate = trade_model.pending_swap_tx_fee_rate()
+        .ok_or(ProtocolErrorKind::MissingSwapTxFeeBump)?;
+    let my_nonce_share = trade_model.get_my_swap_tx_fee_bump_nonce_share()
+        .ok_or_else(|| Status::internal("missing nonce share"))?;
+    let mut message = SwapTxFeeBumpMessage {
+        swap_tx_fee_rate: fee_rate,
+        swap_tx_input_nonce_share: my_nonce_share.serialize().into(),
+        swap_tx_input_partial_signature: trade_model.get_my_swap_tx_fee_bump_partial_signature()
+            .map(|sig| sig.serialize().into()),
+        ..Default::default()
+    };
+    let fee_rate = fee_rate.to_be_bytes();
+    message.identity_signature = sign_payload(trade_model, PayloadKind::SwapTxFeeBump,
+        &swap_tx_fee_bump_signed_fields(&message, &fee_rate))?;
+    let my_signature = message.identity_signature.clone();
+    if trade_model.seal_peer_payloads {
+        message = SwapTxFeeBumpMessage {
+            sealed_payload: Some(seal_for_peer(trade_model, SWAP_TX_FEE_BUMP_PROLOGUE, &message.encode_to_vec())?),
+            ..Default::default()
+        };
+    }
+    Ok((message, my_signature))
+}
+
+fn trade_state(trade_model: &TradeModel) -> helloworld::TradeState {
+    helloworld::TradeState {
+        summary: Some(trade_model.summarize(None).into()),
+        payment_receipts: trade_model.payment_receipts.iter().cloned().map(Into::into).collect(),
+        signing_session: trade_model.signing_session(),
+        peers_swap_tx_signature: helloworld::SwapTxSignatureState::from(trade_model.peers_swap_tx_signature_state()).into(),
+        failure: trade_model.failure().map(str::to_owned),
+        peer_last_seen_millis: trade_model.peer_last_seen.map(to_millis),
+        peer_unresponsive: trade_model.peer_unresponsive,
+        peer_nonce_reused_from: trade_model.peer_nonce_reused_from.clone(),
+        step_completions: trade_model.step_completed_at.iter()
+            .map(|(step, at)| helloworld::StepCompletion { step: step.clone(), at_millis: to_millis(*at) })
+            .collect(),
+    }
+}
+
+/// The audit log note of the consent to the given fee rate of the given tx(s) given by us and/or the
+/// peer, by way of our identity signatures (in hex) on our fee rate change (or bump) messages.
+fn fee_rate_consent_note(tx: &str, fee_rate: f64, my_signature: Option<&[u8]>, peers_signature: Option<&[u8]>) -> String {
+    consent_note(&format!("{} fee rate {} sat/vB", tx, fee_rate), my_signature, peers_signature)
+}
+
+/// A note of the consent of either party (or both) to the given change to the trade, with the
+/// identity signature of each, for the audit log.
+fn consent_note(change: &str, my_signature: Option<&[u8]>, peers_signature: Option<&[u8]>) -> String {
+    let hex = |sig: &[u8]| sig.iter().fold(String::new(), |mut hex, b| {
+        write!(hex, "{:02x}", b).unwrap();
+        hex
+    });
+    let consents: Vec<_> = [("us", my_signature), ("the peer", peers_signature)].into_iter()
+        .filter_map(|(party, sig)| Some(format!("{} (identity signature {})", party, hex(sig?))))
+        .collect();
+    format!("{} consented to by {}", change, consents.join(" and "))
+}
+
+fn sign_deposit_tx(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: DepositTxSignatureRequest) -> Result<DepositPsbt, Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    let peers_partial_signatures = open_peer_partial_signatures(trade_model, request.peers_partial_signatures
+        .ok_or_else(|| Status::not_found("missing request.peers_partial_signatures"))?)?;
+    let peers_partial_signatures: ExchangedSigs<ByVal> = peers_partial_signatures.try_into()
+        .map_err(|e: ConvertError| e.in_field("peers_partial_signatures"))?;
+    // Only the buyer may withhold its swap tx partial signature, as it needs the seller's up front, to
+    // be sure of getting the seller's key share should the swap tx be published:
+    if trade_model.am_buyer() && peers_partial_signatures.swap_tx_input_partial_signature.is_redacted() {
+        return Err(Status::invalid_argument("the seller's swap tx partial signature may not be redacted"));
+    }
+    trade_model.peer_partial_signatures_on_my_txs_mut().set(peers_partial_signatures);
+    trade_model.aggregate_partial_signatures()
+        .map_err(|e| e.in_trade(trade_model).in_field("peersPartialSignatures"))?;
+    save_trade_model(store, trade_model)?;
+    Ok(DepositPsbt {
+        deposit_psbt: b"deposit_psbt".into()
+    })
+}
+
+/// Check that the deposit tx has been signed but not yet published, so that our half of it may be
+/// handed off for its funding inputs to be signed (by a hardware wallet, say), now that the deposit
+/// would be safely recoverable.
+fn check_awaiting_deposit_tx_publication(trade_model: &TradeModel) -> Result<(), Status> {
+    match trade_model.phase() {
+        TradePhase::DepositTxSigned => Ok(()),
+        phase => Err(Status::failed_precondition(format!(
+            "trade with id {} is in phase {:?}, not awaiting deposit tx publication", trade_model.trade_id(), phase))),
+    }
 }
 
-#[derive(Default, Debug)]
-pub struct MyGreeter {}
+fn get_unsigned_deposit_psbt(trade_model: &TradeModel) -> Result<DepositPsbt, Status> {
+    check_awaiting_deposit_tx_publication(trade_model)?;
+    if !trade_model.my_funding_inputs().is_empty() {
+        return Ok(DepositPsbt { deposit_psbt: encode_half_deposit_psbt(trade_model.my_funding_inputs()) });
+    }
+    // TODO: Build our half of the deposit PSBT from the wallet's UTXOs, with BDK or similar, passing
+    //  `trade_model.coin_control` to its coin selector (as must-spend & unspendable UTXOs, a coin
+    //  selection algorithm by the fee strategy, and the change policy):
+    Ok(DepositPsbt {
+        deposit_psbt: [PSBT_MAGIC, b"unsigned_half_deposit_psbt"].concat()
+    })
+}
 
-#[tonic::async_trait]
-impl Greeter for MyGreeter {
-    async fn say_hello(&self, request: Request<HelloRequest>) -> Result<Response<HelloReply>, Status> {
-        println!("Got a request: {:?}", request);
+fn submit_signed_deposit_psbt(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: SignedDepositPsbtRequest) -> Result<DepositPsbt, Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    check_awaiting_deposit_tx_publication(trade_model)?;
+    let signed_psbt = request.signed_half_deposit_psbt
+        .ok_or_else(|| Status::not_found("missing request.signed_half_deposit_psbt"))?.deposit_psbt;
+    // TODO: Check that every funding input is signed, once the PSBT is a real one:
+    if !signed_psbt.starts_with(PSBT_MAGIC) {
+        return Err(Status::invalid_argument("could not decode signed_half_deposit_psbt: malformed PSBT"));
+    }
+    if !trade_model.my_funding_inputs().is_empty()
+        && decode_half_deposit_psbt(&signed_psbt, "signed_half_deposit_psbt")? != trade_model.my_funding_inputs() {
+        return Err(Status::invalid_argument("signed_half_deposit_psbt does not spend the funding inputs handed out"));
+    }
+    trade_model.my_signed_half_deposit_psbt = Some(signed_psbt);
+    save_trade_model(store, trade_model)?;
+    Ok(DepositPsbt {
+        deposit_psbt: b"deposit_psbt".into()
+    })
+}
 
-        let reply = HelloReply {
-            message: format!("Hello, {}!", request.into_inner().name)
-        };
+/// Cancel the trade before its deposit tx is published, handing out our consent to the cancellation
+/// for the peer, should the request have the peer's consent, our funding inputs have been spent
+/// elsewhere, or the deposit tx be unsigned, so that nothing can be published for the trade. Else
+/// just our consent is handed out, to wait for the peer's. The consent of either party is noted in
+/// the audit log. The handler archives the trade, once cancelled.
+fn cancel_before_deposit(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &CancelBeforeDepositRequest)
+    -> Result<(CancelBeforeDepositResponse, Option<String>), Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    if trade_model.phase() >= TradePhase::DepositTxPublished {
+        return Err(ProtocolErrorKind::CancellationClosed(trade_model.phase()).into());
+    }
+    let peers_signature = request.peers_cancellation.as_ref().map(|m| &m.identity_signature[..]);
+    if peers_signature.is_some() && trade_model.get_peer_identity_pub_key().is_none() {
+        return Err(Status::failed_precondition("the peer's consent cannot be checked before its key shares are in"));
+    }
+    if let Some(signature) = peers_signature {
+        verify_peer_payload(trade_model, PayloadKind::Cancellation, &[], signature, "peers_cancellation.identity_signature")?;
+    }
+    let cancellation = if peers_signature.is_some() {
+        Some(Cancellation::Mutual)
+    } else if trade_model.phase() < TradePhase::DepositTxSigned || request.my_funding_inputs_spent {
+        Some(Cancellation::Unilateral)
+    } else {
+        None
+    };
+    let identity_signature = sign_payload(trade_model, PayloadKind::Cancellation, &[])?;
+    let mut note = consent_note("cancellation", Some(&identity_signature), peers_signature);
+    if let Some(cancellation) = cancellation {
+        trade_model.cancel_before_deposit(cancellation)?;
+        save_trade_model(store, trade_model)?;
+        if request.my_funding_inputs_spent {
+            note.push_str(", our funding inputs having been spent elsewhere");
+        }
+    }
+    let response = CancelBeforeDepositResponse {
+        my_cancellation: Some(CancellationMessage { identity_signature }),
+        trade: Some(trade_model.summarize(None).into()),
+    };
+    Ok((response, Some(note)))
+}
+
+/// The signed deposit tx, for the handler to broadcast before [`publish_deposit_tx`] records it,
+/// unless the trade is a dry run (as also returned). The tx is first re-validated against the trade
+/// model, failing the trade should it not pass.
+fn deposit_tx_to_publish(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &PublishDepositTxRequest)
+    -> Result<(Vec<u8>, bool), Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    check_not_cancelled(trade_model)?;
+    // TODO: Finalize the deposit tx from the signed deposit PSBTs, once they are real ones, with its
+    //  outputs put in the order agreed with the peer by `tx_outputs::order_outputs` (and our change
+    //  blinded by `tx_outputs::blind_change_amount`, as our half is built).
+    let assembled = assembled_deposit_tx(trade_model)?;
+    check_tx_to_publish(store, trade_model, |trade_model| trade_model.check_deposit_tx(&assembled))?;
+    Ok((b"signed_deposit_tx".to_vec(), trade_model.dry_run))
+}
+
+/// The deposit tx as assembled for broadcast, for [`TradeModel::check_deposit_tx`].
+fn assembled_deposit_tx(trade_model: &TradeModel) -> Result<AssembledDepositTx, Status> {
+    // TODO: Read these off the finalized deposit tx, once it is a real one. Until then, our inputs
+    //  are those of our signed half (if submitted), and the outputs & size are left as they should be:
+    let my_inputs = match &trade_model.my_signed_half_deposit_psbt {
+        Some(psbt) if !trade_model.my_funding_inputs().is_empty() =>
+            decode_half_deposit_psbt(psbt, "my_signed_half_deposit_psbt")?,
+        _ => trade_model.my_funding_inputs().to_vec(),
+    };
+    let inputs = my_inputs.iter().chain(trade_model.peers_funding_inputs())
+        .map(|input| (input.txid, input.vout, input.amount))
+        .collect();
+    let outputs = trade_model.deposit_tx_payout_scripts()?.into_iter().zip([true, false])
+        .map(|(script_pub_key, buyer)| TxOutput { script_pub_key, amount: trade_model.funding_contribution(buyer).unwrap_or_default() })
+        .collect();
+    Ok(AssembledDepositTx { inputs, outputs, vsize: None })
+}
+
+/// Run the given re-validation of a tx assembled for broadcast, failing the trade (so that nothing
+/// further is done with it, pending investigation) should the tx not pass.
+fn check_tx_to_publish(store: &impl TradeModelStore, trade_model: &mut TradeModel,
+                       check: impl FnOnce(&TradeModel) -> Result<(), ProtocolErrorKind>) -> Result<(), Status>
+{
+    if let Err(e) = check(trade_model) {
+        trade_model.fail(e.to_string());
+        save_trade_model(store, trade_model)?;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Record the deposit tx as published, once broadcast. The revision is checked again, so that the
+/// trade cannot have moved on in between.
+fn publish_deposit_tx(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &PublishDepositTxRequest,
+                      height: Option<u32>) -> Result<(), Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    check_not_cancelled(trade_model)?;
+    trade_model.set_deposit_tx_published();
+    // Until the tx is followed on the chain, take it to be mined at once, in the block at the tip:
+    trade_model.deposit_tx_height = height;
+    save_trade_model(store, trade_model)
+}
+
+/// Check that the trade hasn't been cancelled (and left to be archived), as it might have been while
+/// its deposit tx was broadcast.
+fn check_not_cancelled(trade_model: &TradeModel) -> Result<(), Status> {
+    if trade_model.cancellation.is_some() {
+        return Err(Status::failed_precondition(format!("trade with id {} has been cancelled", trade_model.trade_id())));
+    }
+    Ok(())
+}
+
+const fn confirm_payment_rpc(milestone: PaymentMilestone) -> &'static str {
+    match milestone {
+        PaymentMilestone::Started => "ConfirmPaymentStarted",
+        PaymentMilestone::Received => "ConfirmPaymentReceived",
+    }
+}
 
-        Ok(Response::new(reply))
+fn confirm_payment(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &ConfirmPaymentRequest,
+                   milestone: PaymentMilestone) -> Result<helloworld::PaymentReceipt, Status> {
+    // A retried confirmation gets the receipt already given, whatever the revision now is:
+    if let Some(receipt) = trade_model.payment_receipt(milestone) {
+        return Ok(receipt.clone().into());
     }
+    check_revision(trade_model, request.expected_revision)?;
+    let by_buyer = milestone == PaymentMilestone::Started;
+    if trade_model.am_buyer() != by_buyer {
+        return Err(Status::failed_precondition(format!("only the {} may call {}",
+            if by_buyer { "buyer" } else { "seller" }, confirm_payment_rpc(milestone))));
+    }
+    // The seller doesn't publish the deposit tx itself, so may only have it signed:
+    let awaiting_from = if by_buyer { TradePhase::DepositTxPublished } else { TradePhase::DepositTxSigned };
+    if !(awaiting_from..TradePhase::SwapTxSigned).contains(&trade_model.phase()) {
+        return Err(Status::failed_precondition(format!(
+            "trade with id {} is in phase {:?}, not awaiting payment", trade_model.trade_id(), trade_model.phase())));
+    }
+    let at = SystemTime::now();
+    let [milestone_field, at_field] = PaymentReceipt::signed_fields(milestone, to_millis(at));
+    let identity_signature = sign_payload(trade_model, PayloadKind::PaymentReceipt, &[&milestone_field, &at_field])?;
+    let receipt = PaymentReceipt { milestone, at, identity_signature };
+    trade_model.payment_receipts.push(receipt.clone());
+    save_trade_model(store, trade_model)?;
+    Ok(receipt.into())
+}
+
+/// Check that the given payment milestone has been confirmed, before releasing the secret it gates.
+fn check_payment_receipt(trade_model: &TradeModel, milestone: PaymentMilestone) -> Result<(), Status> {
+    if trade_model.payment_receipt(milestone).is_none() {
+        return Err(Status::failed_precondition(format!("trade with id {} awaits {} first",
+            trade_model.trade_id(), confirm_payment_rpc(milestone))));
+    }
+    Ok(())
+}
 
-    type SubscribeClockStream = Pin<Box<dyn stream::Stream<Item=Result<TickEvent, Status>> + Send>>;
+fn sign_swap_tx(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &SwapTxSignatureRequest) -> Result<SwapTxSignatureResponse, Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    if request.swap_tx_input_peers_partial_signature.is_empty() && request.sealed_swap_tx_input_peers_partial_signature.is_none() {
+        return Err(Status::not_found("missing request.swap_tx_input_peers_partial_signature"));
+    }
+    check_payment_receipt(trade_model, PaymentMilestone::Received)?;
+    let plain_sig = SignedPartialSignature {
+        partial_signature: request.swap_tx_input_peers_partial_signature.clone(),
+        identity_signature: request.swap_tx_input_peers_identity_signature.clone(),
+    };
+    let signed_sig = open_peer_swap_tx_input_partial_signature(trade_model, plain_sig,
+        request.sealed_swap_tx_input_peers_partial_signature.as_deref())?;
+    trade_model.set_swap_tx_input_peers_partial_signature(decode(&signed_sig.partial_signature,
+        "swap_tx_input_peers_partial_signature")?);
+    trade_model.aggregate_swap_tx_partial_signatures()?;
+    save_trade_model(store, trade_model)?;
+    let sig = trade_model.compute_swap_tx_input_signature()?;
+    let (peer_output_prv_key_share, sealed_peer_output_prv_key_share) = my_prv_key_share_for_peer_output(trade_model)?;
+    Ok(SwapTxSignatureResponse {
+        // For now, just set 'swap_tx' to be the (final) swap tx signature, rather than the actual signed tx:
+        swap_tx: sig.serialize().into(),
+        peer_output_prv_key_share,
+        sealed_peer_output_prv_key_share,
+    })
+}
 
-    async fn subscribe_clock(&self, request: Request<ClockRequest>) -> Result<Response<Self::SubscribeClockStream>, Status> {
-        println!("Got a request: {:?}", request);
+/// Our (the buyer's) partial signature on the swap tx, signed & sealed for the peer as it would
+/// have been among our partial signatures, to be delivered to the peer now that payment is to start.
+fn get_swap_tx_input_partial_signature(trade_model: &TradeModel) -> Result<SwapTxInputPartialSignature, Status> {
+    if trade_model.phase() < TradePhase::DepositTxPublished {
+        return Err(Status::failed_precondition(format!(
+            "trade with id {} is in phase {:?}, before the deposit tx is published", trade_model.trade_id(), trade_model.phase())));
+    }
+    check_payment_receipt(trade_model, PaymentMilestone::Started)?;
+    let partial_signature = trade_model.get_my_partial_signatures_on_peer_txs()
+        .and_then(|sigs| sigs.swap_tx_input_partial_signature.value())
+        .map(|sig| sig.serialize().to_vec())
+        .ok_or_else(|| Status::failed_precondition(format!(
+            "trade with id {} has no swap tx partial signature to release", trade_model.trade_id())))?;
+    let identity_signature = sign_payload(trade_model, PayloadKind::SwapTxInputPartialSignature, &[&partial_signature])?;
+    Ok(if trade_model.seal_peer_payloads {
+        let signed_sig = SignedPartialSignature { partial_signature, identity_signature };
+        SwapTxInputPartialSignature {
+            sealed_partial_signature: Some(seal_for_peer(trade_model, SWAP_TX_INPUT_PARTIAL_SIGNATURE_PROLOGUE,
+                &signed_sig.encode_to_vec())?),
+            ..Default::default()
+        }
+    } else {
+        SwapTxInputPartialSignature { partial_signature, identity_signature, sealed_partial_signature: None }
+    })
+}
 
-        let period = Duration::from_millis(u64::from(request.into_inner().tick_period_millis));
+/// Whether the close of the trade is forced, by the seller publishing the swap tx, the request having
+/// neither the peer's private key share for our output nor the swap tx published by the peer.
+const fn is_force_close(request: &CloseTradeRequest) -> bool {
+    request.my_output_peers_prv_key_share.is_none() && request.sealed_my_output_peers_prv_key_share.is_none()
+        && request.swap_tx.is_none()
+}
 
-        Ok(Response::new(Box::pin(stream::repeat(())
-            .throttle(period)
-            .map(|()| Ok(TickEvent {
-                current_time_millis: u64::try_from(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()).unwrap()
-            })))))
+/// The signed swap tx, for the handler of a forced close to broadcast before [`close_trade`] records
+/// the trade closed, unless the trade is a dry run (as also returned).
+fn swap_tx_to_publish(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &CloseTradeRequest)
+    -> Result<(Vec<u8>, bool), Status>
+{
+    check_revision(trade_model, request.expected_revision)?;
+    if trade_model.am_buyer() {
+        return Err(Status::failed_precondition("only the seller may force-close a trade, by publishing the swap tx"));
     }
+    // For now, the swap tx is stood in for by its (final) signature, as handed out by 'SignSwapTx':
+    let swap_tx_input_signature = trade_model.compute_swap_tx_input_signature()?;
+    check_tx_to_publish(store, trade_model, |trade_model| trade_model.check_swap_tx(swap_tx_input_signature))?;
+    Ok((swap_tx_input_signature.serialize().to_vec(), trade_model.dry_run))
 }
 
-#[derive(Default, Debug)]
-pub struct MyMuSig {}
+fn close_trade(store: &impl TradeModelStore, trade_model: &mut TradeModel, request: &CloseTradeRequest) -> Result<CloseTradeResponse, Status> {
+    check_revision(trade_model, request.expected_revision)?;
+    let peer_prv_key_share = open_peer_prv_key_share(trade_model, request.my_output_peers_prv_key_share.clone(),
+        request.sealed_my_output_peers_prv_key_share.as_deref())?;
+    if let Some(peer_prv_key_share) = decode_opt(peer_prv_key_share.as_deref(), "my_output_peers_prv_key_share")? {
+        // Trader receives the private key share from a cooperative peer, closing our trade.
+        trade_model.set_peer_private_key_share_for_my_output(peer_prv_key_share)?;
+        trade_model.aggregate_private_keys_for_my_output()?;
+    } else if let Some(swap_tx_input_signature) = decode_opt(request.swap_tx.as_deref(), "swap_tx")? {
+        // Buyer supplies a signed swap tx to the Rust server, to close our trade. (Mainly for
+        // testing -- normally the tx would be picked up from the bitcoin network by the server.)
+        trade_model.recover_seller_private_key_share_for_buyer_output(&swap_tx_input_signature)?;
+        trade_model.aggregate_private_keys_for_my_output()?;
+    } else {
+        // Peer unresponsive -- force-close our trade, the swap tx having been broadcast by the handler.
+    }
+    trade_model.set_closed();
+    save_trade_model(store, trade_model)?;
+    let (peer_output_prv_key_share, sealed_peer_output_prv_key_share) = my_prv_key_share_for_peer_output(trade_model)?;
+    Ok(CloseTradeResponse {
+        peer_output_prv_key_share,
+        sealed_peer_output_prv_key_share,
+    })
+}
 
-// FIXME: At present, the MuSig service passes some fields to the Java client that should be kept
-//  secret for a time before passing them to the peer, namely the buyer's partial signature on the
-//  swap tx and the seller's private key share for the buyer payout. Premature revelation of those
-//  secrets would allow the seller to close the trade before the buyer starts payment, or the buyer
-//  to close the trade before the seller had a chance to confirm receipt of payment (but after the
-//  buyer starts payment), respectively. This should probably be changed, as the Java client should
-//  never hold secrets which directly control funds (but doing so makes the RPC interface a little
-//  bigger and less symmetrical.)
-#[expect(clippy::significant_drop_tightening, reason = "will refactor duplicated mutex code later (possibly with a macro)")] //TODO
+// FIXME: Unless the trade seals its peer payloads (see 'noise') or exchanges them with the peer's
+//  daemon directly (see 'peer'), the MuSig service passes some fields to the Java client in the
+//  clear that should be kept secret for a time before passing them to the peer, namely the buyer's
+//  partial signature on the swap tx and the seller's private key share for the buyer payout.
+//  Premature revelation of those secrets would allow the seller to close the trade before the buyer
+//  starts payment, or the buyer to close the trade before the seller had a chance to confirm receipt
+//  of payment (but after the buyer starts payment), respectively. One of the two should probably
+//  become mandatory, as the Java client should never hold secrets which directly control funds.
 #[tonic::async_trait]
-impl MuSig for MyMuSig {
+impl<S: TradeModelStore + Send + Sync + 'static> MuSig for MyMuSig<S> {
     async fn init_trade(&self, request: Request<PubKeySharesRequest>) -> Result<Response<PubKeySharesResponse>, Status> {
-        println!("Got a request: {:?}", request);
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
+        let client = request.extensions().get::<ClientIdentity>().map(ToString::to_string);
         let request = request.into_inner();
-        let mut trade_model = TradeModel::new(request.trade_id, request.my_role.my_try_into()?);
-        trade_model.init_my_key_shares();
-        let my_key_shares = trade_model.get_my_key_shares()
-            .ok_or_else(|| Status::internal("missing key shares"))?;
-        let response = PubKeySharesResponse {
-            buyer_output_pub_key_share: my_key_shares[0].pub_key.serialize().into(),
-            seller_output_pub_key_share: my_key_shares[1].pub_key.serialize().into(),
-            current_block_height: 900_000,
-        };
-        TRADE_MODELS.add_trade_model(trade_model);
+        let request_digest = digest(&request);
+        let my_role = decode_role(request.my_role, "my_role")?;
+        if request.commit_to_nonces && request.peer.is_some() {
+            return Err(Status::invalid_argument("commit_to_nonces cannot yet be combined with a peer endpoint"));
+        }
+        let funding_inputs = decode_funding_inputs(&request.funding_inputs, "funding_inputs")?;
+        let coin_control = request.coin_control.as_ref()
+            .map(|coin_control| decode_coin_control(coin_control, "coin_control")).transpose()?.unwrap_or_default();
+        let generate_trade_id = request.trade_id.is_empty();
+        if generate_trade_id && !funding_inputs.is_empty() {
+            return Err(Status::invalid_argument("funding_inputs need a trade_id from the client, for their ownership proofs"));
+        }
+        if !generate_trade_id {
+            trade_id::check_trade_id(&request.trade_id, "trade_id")?;
+        }
+        if let Some(peer) = &request.peer {
+            trade_id::check_trade_id(&peer.trade_id, "peer.trade_id")?;
+        }
+        let response = self.spawn_blocking(move |this| {
+            let mut trade_id = request.trade_id;
+            while generate_trade_id && (trade_id.is_empty() || this.trade_model_store.contains_trade(&trade_id)) {
+                trade_id = trade_id::new_trade_id();
+            }
+            // No need to generate key shares for a trade which can't be added:
+            if this.trade_model_store.contains_trade(&trade_id) {
+                return Err(Status::already_exists(trade_exists(&trade_id).to_string()));
+            }
+            // A dry-run trade gets test keys, whatever the signer, so that no real entropy is drawn for it:
+            let signer: Arc<dyn Signer> = if request.dry_run {
+                Arc::new(TestSigner::for_trade(&trade_id, my_role))
+            } else {
+                Arc::clone(&this.signer)
+            };
+            let mut trade_model = TradeModel::builder(trade_id, my_role)
+                .signer(signer)
+                .with_my_key_shares()?
+                .build();
+            trade_model.dry_run = request.dry_run;
+            trade_model.seal_peer_payloads = request.seal_peer_payloads;
+            trade_model.commit_to_nonces = request.commit_to_nonces;
+            trade_model.peer_endpoint = request.peer.map(Into::into);
+            trade_model.opened_by = client;
+            trade_model.coin_control = coin_control;
+            trade_model.set_my_funding_inputs(funding_inputs).map_err(|e| e.in_trade(&trade_model))?;
+            let response = this.my_key_shares_response(&trade_model)?;
+            let my_key_shares = trade_model.get_my_key_shares()
+                .ok_or_else(|| Status::internal("missing key shares"))?;
+            // Check again, now that no other trade can be opened, before backing up over the key
+            // shares of any trade opened with the same ID in the meantime:
+            let opening_trade = this.opening_trade.lock().unwrap_or_else(PoisonError::into_inner);
+            if this.trade_model_store.contains_trade(trade_model.trade_id()) {
+                return Err(Status::already_exists(trade_exists(trade_model.trade_id()).to_string()));
+            }
+            if let Some(backup) = this.backup.as_ref().filter(|_| !trade_model.dry_run) {
+                // Key shares held by an external signer are for it to back up, so only ours are:
+                let key_shares: Vec<_> = my_key_shares.iter()
+                    .filter_map(|k| Some((k.pub_key, *k.prv_key.as_ref()?.expose_secret())))
+                    .collect();
+                if !key_shares.is_empty() {
+                    backup.back_up(trade_model.trade_id(), &key_shares)
+                        .map_err(|e| Status::internal(format!("could not back up key shares: {}", e)))?;
+                }
+            }
+            let (trade_id, phase) = (trade_model.trade_id().to_owned(), trade_model.phase());
+            let funding_inputs = if trade_model.dry_run { vec![] } else { trade_model.my_funding_inputs().to_vec() };
+            this.utxo_reservations.reserve(&*this.trade_model_store, &funding_inputs, ||
+                this.trade_model_store.add_trade_model(trade_model).map_err(|e| match e.kind() {
+                    io::ErrorKind::QuotaExceeded => Status::resource_exhausted(e.to_string()),
+                    io::ErrorKind::AlreadyExists => Status::already_exists(e.to_string()),
+                    _ => Status::internal(format!("could not add trade model: {}", e)),
+                }))?;
+            drop(opening_trade);
+            log_audit_entry(&*this.trade_model_store, &trade_id, &AuditEntry {
+                step: "InitTrade".to_owned(),
+                at: SystemTime::now(),
+                request_digest,
+                response_digest: Some(digest(&response)),
+                phase,
+                error: None,
+                note: None,
+                correlation_id: correlation::correlation_id(),
+                client: client_identity::client_identity().map(|client| client.to_string()),
+            });
+            Ok(response)
+        }).await?;
 
         Ok(Response::new(response))
     }
 
     async fn get_nonce_shares(&self, request: Request<NonceSharesRequest>) -> Result<Response<NonceSharesMessage>, Status> {
-        println!("Got a request: {:?}", request);
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
         let request = request.into_inner();
-        let trade_model = TRADE_MODELS.get_trade_model(&request.trade_id)
-            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id)))?;
-        let mut trade_model = trade_model.lock().unwrap();
-        trade_model.set_peer_key_shares(
-            request.buyer_output_peers_pub_key_share.my_try_into()?,
-            request.seller_output_peers_pub_key_share.my_try_into()?);
-        trade_model.aggregate_key_shares()?;
-        trade_model.init_my_nonce_shares()?;
-        trade_model.trade_amount = Some(request.trade_amount);
-        trade_model.buyers_security_deposit = Some(request.buyers_security_deposit);
-        trade_model.sellers_security_deposit = Some(request.sellers_security_deposit);
-        trade_model.deposit_tx_fee_rate = Some(request.deposit_tx_fee_rate);
-        trade_model.prepared_tx_fee_rate = Some(request.prepared_tx_fee_rate);
-        let my_nonce_shares = trade_model.get_my_nonce_shares()
-            .ok_or_else(|| Status::internal("missing nonce shares"))?;
-        let response = NonceSharesMessage {
-            warning_tx_fee_bump_address: "address1".to_owned(),
-            redirect_tx_fee_bump_address: "address2".to_owned(),
-            half_deposit_psbt: vec![],
-            swap_tx_input_nonce_share:
-            my_nonce_shares.swap_tx_input_nonce_share.serialize().into(),
-            buyers_warning_tx_buyer_input_nonce_share:
-            my_nonce_shares.buyers_warning_tx_buyer_input_nonce_share.serialize().into(),
-            buyers_warning_tx_seller_input_nonce_share:
-            my_nonce_shares.buyers_warning_tx_seller_input_nonce_share.serialize().into(),
-            sellers_warning_tx_buyer_input_nonce_share:
-            my_nonce_shares.sellers_warning_tx_buyer_input_nonce_share.serialize().into(),
-            sellers_warning_tx_seller_input_nonce_share:
-            my_nonce_shares.sellers_warning_tx_seller_input_nonce_share.serialize().into(),
-            buyers_redirect_tx_input_nonce_share:
-            my_nonce_shares.buyers_redirect_tx_input_nonce_share.serialize().into(),
-            sellers_redirect_tx_input_nonce_share:
-            my_nonce_shares.sellers_redirect_tx_input_nonce_share.serialize().into(),
-        };
+        let trade_limits = *self.trade_limits.read().unwrap_or_else(PoisonError::into_inner);
+        check_trade_limits(&trade_limits, &request)?;
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "GetNonceShares", |reply| MuSigCommand::GetNonceShares(request, Arc::clone(&self.faults), reply)).await?;
+        if let Some((endpoint, _)) = self.direct_peer(&trade_id).await? {
+            self.peers.spawn_delivery(trade_id, endpoint, Payload::NonceShares(response.clone()));
+        }
 
         Ok(Response::new(response))
     }
 
-    async fn get_partial_signatures(&self, request: Request<PartialSignaturesRequest>) -> Result<Response<PartialSignaturesMessage>, Status> {
-        println!("Got a request: {:?}", request);
+    async fn reset_signing_session(&self, request: Request<ResetSigningSessionRequest>) -> Result<Response<NonceSharesMessage>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
         let request = request.into_inner();
-        let trade_model = TRADE_MODELS.get_trade_model(&request.trade_id)
-            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id)))?;
-        let mut trade_model = trade_model.lock().unwrap();
-        let peer_nonce_shares = request.peers_nonce_shares
-            .ok_or_else(|| Status::not_found("missing request.peers_nonce_shares"))?;
-        trade_model.set_peer_nonce_shares(ExchangedNonces {
-            swap_tx_input_nonce_share:
-            peer_nonce_shares.swap_tx_input_nonce_share.my_try_into()?,
-            buyers_warning_tx_buyer_input_nonce_share:
-            peer_nonce_shares.buyers_warning_tx_buyer_input_nonce_share.my_try_into()?,
-            buyers_warning_tx_seller_input_nonce_share:
-            peer_nonce_shares.buyers_warning_tx_seller_input_nonce_share.my_try_into()?,
-            sellers_warning_tx_buyer_input_nonce_share:
-            peer_nonce_shares.sellers_warning_tx_buyer_input_nonce_share.my_try_into()?,
-            sellers_warning_tx_seller_input_nonce_share:
-            peer_nonce_shares.sellers_warning_tx_seller_input_nonce_share.my_try_into()?,
-            buyers_redirect_tx_input_nonce_share:
-            peer_nonce_shares.buyers_redirect_tx_input_nonce_share.my_try_into()?,
-            sellers_redirect_tx_input_nonce_share:
-            peer_nonce_shares.sellers_redirect_tx_input_nonce_share.my_try_into()?,
-        });
-        trade_model.aggregate_nonce_shares()?;
-        trade_model.sign_partial()?;
-        let my_partial_signatures = trade_model.get_my_partial_signatures_on_peer_txs()
-            .ok_or_else(|| Status::internal("missing partial signatures"))?;
-        let response = PartialSignaturesMessage {
-            peers_warning_tx_buyer_input_partial_signature:
-            my_partial_signatures.peers_warning_tx_buyer_input_partial_signature.serialize().into(),
-            peers_warning_tx_seller_input_partial_signature:
-            my_partial_signatures.peers_warning_tx_seller_input_partial_signature.serialize().into(),
-            peers_redirect_tx_input_partial_signature:
-            my_partial_signatures.peers_redirect_tx_input_partial_signature.serialize().into(),
-            swap_tx_input_partial_signature:
-            my_partial_signatures.swap_tx_input_partial_signature.map(|s| s.serialize().into()),
-        };
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "ResetSigningSession", |reply| MuSigCommand::ResetSigningSession(request, Arc::clone(&self.faults), reply)).await?;
+        if let Some((endpoint, _)) = self.direct_peer(&trade_id).await? {
+            self.peers.spawn_delivery(trade_id, endpoint, Payload::NonceShares(response.clone()));
+        }
+
+        Ok(Response::new(response))
+    }
+
+    async fn propose_fee_rate_change(&self, request: Request<FeeRateChangeRequest>) -> Result<Response<FeeRateChangeMessage>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "ProposeFeeRateChange", |reply| MuSigCommand::ProposeFeeRateChange(request, self.peer_nonce_check(),
+            reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn accept_fee_rate_change(&self, request: Request<FeeRateChangeRequest>) -> Result<Response<FeeRateChangeMessage>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "AcceptFeeRateChange", |reply| MuSigCommand::AcceptFeeRateChange(request, self.peer_nonce_check(),
+            reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn propose_swap_tx_fee_bump(&self, request: Request<SwapTxFeeBumpRequest>) -> Result<Response<SwapTxFeeBumpMessage>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "ProposeSwapTxFeeBump", |reply| MuSigCommand::ProposeSwapTxFeeBump(request, self.peer_nonce_check(),
+            reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn accept_swap_tx_fee_bump(&self, request: Request<SwapTxFeeBumpRequest>) -> Result<Response<SwapTxFeeBumpMessage>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "AcceptSwapTxFeeBump", |reply| MuSigCommand::AcceptSwapTxFeeBump(request, self.peer_nonce_check(),
+            reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn reveal_nonce_shares(&self, request: Request<RevealNonceSharesRequest>) -> Result<Response<NonceSharesMessage>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "RevealNonceShares", |reply| MuSigCommand::RevealNonceShares(request, Arc::clone(&self.faults), reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_partial_signatures(&self, request: Request<PartialSignaturesRequest>) -> Result<Response<PartialSignaturesMessage>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let mut request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        request.peers_nonce_shares = request.peers_nonce_shares.or_else(|| self.peers.inbox.get(&trade_id).nonce_shares);
+        let receiver_set = self.active_receiver_set()?;
+        let mut response = self.call_step(&trade_id, "GetPartialSignatures", |reply| MuSigCommand::GetPartialSignatures(Box::new(request),
+            receiver_set, self.mediator_pub_key, Arc::clone(&self.faults), self.peer_nonce_check(), reply)).await?;
+        if let Some((endpoint, am_buyer)) = self.direct_peer(&trade_id).await? {
+            // The buyer's partial signature on the swap tx is withheld until ReleaseSwapTxSignature:
+            if am_buyer {
+                response.redact_swap_tx_input_partial_signature();
+            }
+            self.peers.spawn_delivery(trade_id, endpoint, Payload::PartialSignatures(response.clone()));
+        }
 
         Ok(Response::new(response))
     }
 
     async fn sign_deposit_tx(&self, request: Request<DepositTxSignatureRequest>) -> Result<Response<DepositPsbt>, Status> {
-        println!("Got a request: {:?}", request);
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let mut request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        request.peers_partial_signatures = request.peers_partial_signatures
+            .or_else(|| self.peers.inbox.get(&trade_id).partial_signatures);
+        let response = self.call_step(&trade_id, "SignDepositTx", |reply| MuSigCommand::SignDepositTx(request, reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_unsigned_deposit_psbt(&self, request: Request<UnsignedDepositPsbtRequest>) -> Result<Response<DepositPsbt>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
         let request = request.into_inner();
-        let trade_model = TRADE_MODELS.get_trade_model(&request.trade_id)
-            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id)))?;
-        let mut trade_model = trade_model.lock().unwrap();
-        let peers_partial_signatures = request.peers_partial_signatures
-            .ok_or_else(|| Status::not_found("missing request.peers_partial_signatures"))?;
-        trade_model.set_peer_partial_signatures_on_my_txs(&ExchangedSigs {
-            peers_warning_tx_buyer_input_partial_signature:
-            peers_partial_signatures.peers_warning_tx_buyer_input_partial_signature.my_try_into()?,
-            peers_warning_tx_seller_input_partial_signature:
-            peers_partial_signatures.peers_warning_tx_seller_input_partial_signature.my_try_into()?,
-            peers_redirect_tx_input_partial_signature:
-            peers_partial_signatures.peers_redirect_tx_input_partial_signature.my_try_into()?,
-            swap_tx_input_partial_signature:
-            peers_partial_signatures.swap_tx_input_partial_signature.my_try_into()?,
-        });
-        trade_model.aggregate_partial_signatures()?;
-        let response = DepositPsbt {
-            deposit_psbt: b"deposit_psbt".into()
-        };
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "GetUnsignedDepositPsbt", |reply| MuSigCommand::GetUnsignedDepositPsbt(request, reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn submit_signed_deposit_psbt(&self, request: Request<SignedDepositPsbtRequest>) -> Result<Response<DepositPsbt>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "SubmitSignedDepositPsbt", |reply| MuSigCommand::SubmitSignedDepositPsbt(request, reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    type GetUnsignedDepositPsbtChunksStream = Pin<Box<dyn stream::Stream<Item=Result<PsbtChunk, Status>> + Send>>;
+
+    async fn get_unsigned_deposit_psbt_chunks(&self, request: Request<UnsignedDepositPsbtRequest>)
+        -> Result<Response<Self::GetUnsignedDepositPsbtChunksStream>, Status>
+    {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let psbt = self.call_step(&trade_id, "GetUnsignedDepositPsbt", |reply| MuSigCommand::GetUnsignedDepositPsbt(request, reply)).await?;
+
+        Ok(Response::new(Box::pin(stream::iter(chunked::into_chunks(&psbt.deposit_psbt).into_iter().map(Ok)))))
+    }
+
+    async fn submit_signed_deposit_psbt_chunks(&self, request: Request<Streaming<SignedDepositPsbtChunk>>)
+        -> Result<Response<DepositPsbt>, Status>
+    {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = chunked::receive_signed_psbt(&mut request.into_inner()).await?;
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "SubmitSignedDepositPsbt", |reply| MuSigCommand::SubmitSignedDepositPsbt(request, reply)).await?;
 
         Ok(Response::new(response))
     }
 
     type PublishDepositTxStream = Pin<Box<dyn stream::Stream<Item=Result<TxConfirmationStatus, Status>> + Send>>;
 
-    async fn publish_deposit_tx(&self, request: Request<PublishDepositTxRequest>) -> Result<Response<Self::PublishDepositTxStream>, Status> {
-        println!("Got a request: {:?}", request);
+    async fn cancel_before_deposit(&self, request: Request<CancelBeforeDepositRequest>) -> Result<Response<CancelBeforeDepositResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
         let request = request.into_inner();
-        let trade_model = TRADE_MODELS.get_trade_model(&request.trade_id)
-            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id)))?;
-        let mut _trade_model = trade_model.lock().unwrap();
+        let request_digest = digest(&request);
+        let trade_id = request.trade_id.clone();
+        let mut response = self.call_step(&trade_id, "CancelBeforeDeposit", |reply| MuSigCommand::CancelBeforeDeposit(request, reply)).await?;
+        if response.trade.as_ref().is_some_and(|trade| trade.cancellation() != helloworld::Cancellation::NotCancelled) {
+            let summary = self.spawn_blocking(move |this| {
+                let summary = this.archive_trade_if("ArchiveTrade", &trade_id, None, request_digest, |trade_model|
+                    if trade_model.cancellation.is_some() {
+                        Ok(())
+                    } else {
+                        Err(Status::failed_precondition(format!("trade with id {} is not cancelled", trade_model.trade_id())))
+                    })?;
+                this.events.publish(TradeEvent::Aborted(summary.clone()));
+                Ok(summary)
+            }).await?;
+            response.trade = Some(summary.into());
+        }
 
-        // TODO: *** BROADCAST DEPOSIT TX ***
+        Ok(Response::new(response))
+    }
+
+    async fn publish_deposit_tx(&self, request: Request<PublishDepositTxRequest>) -> Result<Response<Self::PublishDepositTxStream>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
-        let confirmation_event = TxConfirmationStatus {
-            tx: b"signed_deposit_tx".into(),
-            current_block_height: 900_001,
-            num_confirmations: 1,
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let target_confirmations = request.confirmations.unwrap_or(1).max(1);
+        // Take a place for the stream up front, so that no tx is published for a stream turned away:
+        let subscription = self.subscriptions.subscribe(&trade_id)?;
+        // The trade actor is only called on either side of the broadcast, so that it isn't tied up by it:
+        let (tx, dry_run) = self.call_step(&trade_id, "PublishDepositTx", |reply|
+            MuSigCommand::GetDepositTxToPublish(request.clone(), reply)).await?;
+        let (broadcaster, chain_tip) = if dry_run {
+            // A dry-run tx goes nowhere, but is mined at the tip of a mock chain of its own, moved on
+            // at once by as many blocks as there are confirmations to wait for:
+            let mock_chain = MockChainBackend::new(self.chain_tip.height().unwrap_or(SIMULATED_TIP_HEIGHT), 0);
+            mock_chain.submit(&tx).map_err(|e| Status::internal(format!("could not mine the dry-run tx: {}", e)))?;
+            mock_chain.mine(target_confirmations - 1);
+            let chain_tip = mock_chain.chain_tip();
+            (Arc::new(mock_chain) as Arc<dyn TxBroadcaster>, chain_tip)
+        } else {
+            self.broadcast_tx(&trade_id, "PublishDepositTx", &tx).await?;
+            (Arc::clone(&self.tx_broadcaster), self.chain_tip.clone())
         };
+        // The tx is recorded as mined at the tip, unless its broadcaster can tell where it really is:
+        let height = match broadcaster.tx_status(&tx) {
+            TxStatus::Mined(height) => Some(height),
+            TxStatus::Untracked | TxStatus::Unconfirmed => chain_tip.height(),
+        };
+        self.call_step(&trade_id, "PublishDepositTx", |reply| MuSigCommand::PublishDepositTx(request, height, reply)).await?;
 
-        Ok(Response::new(Box::pin(stream::iter(iter::once(Ok(confirmation_event))))))
+        let watch = TxConfirmationWatch {
+            musig: self.clone(),
+            trade_id,
+            tx,
+            mined_at: height,
+            target_confirmations,
+            broadcaster,
+            tip: chain_tip.subscribe(),
+            last_sent: None,
+            ended: false,
+            _subscription: subscription,
+        };
+        Ok(Response::new(Box::pin(stream::unfold(watch, TxConfirmationWatch::next))))
     }
 
     async fn sign_swap_tx(&self, request: Request<SwapTxSignatureRequest>) -> Result<Response<SwapTxSignatureResponse>, Status> {
-        println!("Got a request: {:?}", request);
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let mut request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        if request.swap_tx_input_peers_partial_signature.is_empty() && request.sealed_swap_tx_input_peers_partial_signature.is_none() {
+            if let Some(sig) = self.peers.inbox.get(&trade_id).swap_tx_input_partial_signature {
+                request.swap_tx_input_peers_partial_signature = sig.partial_signature;
+                request.swap_tx_input_peers_identity_signature = sig.identity_signature;
+                request.sealed_swap_tx_input_peers_partial_signature = sig.sealed_partial_signature;
+            }
+        }
+        let mut response = self.call_step(&trade_id, "SignSwapTx", |reply| MuSigCommand::SignSwapTx(request, reply)).await?;
+        if let Some((endpoint, _)) = self.direct_peer(&trade_id).await? {
+            self.peers.spawn_delivery(trade_id, endpoint, Payload::PrvKeyShare(PrvKeyShare {
+                prv_key_share: std::mem::take(&mut response.peer_output_prv_key_share),
+                sealed_prv_key_share: response.sealed_peer_output_prv_key_share.take(),
+            }));
+        }
+
+        Ok(Response::new(response))
+    }
+
+    async fn confirm_payment_started(&self, request: Request<ConfirmPaymentRequest>) -> Result<Response<helloworld::PaymentReceipt>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
         let request = request.into_inner();
-        let trade_model = TRADE_MODELS.get_trade_model(&request.trade_id)
-            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id)))?;
-        let mut trade_model = trade_model.lock().unwrap();
-        trade_model.set_swap_tx_input_peers_partial_signature(request.swap_tx_input_peers_partial_signature.my_try_into()?);
-        trade_model.aggregate_swap_tx_partial_signatures()?;
-        let sig = trade_model.compute_swap_tx_input_signature()?;
-        let prv_key_share = trade_model.get_my_private_key_share_for_peer_output()
-            .ok_or_else(|| Status::internal("missing private key share"))?;
-        let response = SwapTxSignatureResponse {
-            // For now, just set 'swap_tx' to be the (final) swap tx signature, rather than the actual signed tx:
-            swap_tx: sig.serialize().into(),
-            peer_output_prv_key_share: prv_key_share.serialize().into(),
-        };
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "ConfirmPaymentStarted", |reply|
+            MuSigCommand::ConfirmPayment(request, PaymentMilestone::Started, reply)).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn confirm_payment_received(&self, request: Request<ConfirmPaymentRequest>) -> Result<Response<helloworld::PaymentReceipt>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let response = self.call_step(&trade_id, "ConfirmPaymentReceived", |reply|
+            MuSigCommand::ConfirmPayment(request, PaymentMilestone::Received, reply)).await?;
 
         Ok(Response::new(response))
     }
 
+    async fn release_swap_tx_signature(&self, request: Request<ReleaseSwapTxSignatureRequest>) -> Result<Response<ReleaseSwapTxSignatureResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        let sig = self.call_step(&trade_id, "ReleaseSwapTxSignature", |reply| MuSigCommand::GetSwapTxInputPartialSignature(request, reply)).await?;
+        let (endpoint, _) = self.direct_peer(&trade_id).await?.ok_or_else(|| Status::failed_precondition(format!(
+            "trade with id {} doesn't exchange its peer payloads directly", trade_id)))?;
+        self.peers.spawn_delivery(trade_id, endpoint, Payload::SwapTxInputPartialSignature(sig));
+
+        Ok(Response::new(ReleaseSwapTxSignatureResponse {}))
+    }
+
     async fn close_trade(&self, request: Request<CloseTradeRequest>) -> Result<Response<CloseTradeResponse>, Status> {
-        println!("Got a request: {:?}", request);
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let mut request = request.into_inner();
+        let trade_id = request.trade_id.clone();
+        if is_force_close(&request) {
+            if let Some(prv_key_share) = self.peers.inbox.get(&trade_id).prv_key_share {
+                request.my_output_peers_prv_key_share = Some(prv_key_share.prv_key_share).filter(|k| !k.is_empty());
+                request.sealed_my_output_peers_prv_key_share = prv_key_share.sealed_prv_key_share;
+            }
+        }
+        if is_force_close(&request) {
+            let (swap_tx, dry_run) = self.call_step(&trade_id, "CloseTrade", |reply|
+                MuSigCommand::GetSwapTxToPublish(request.clone(), reply)).await?;
+            if !dry_run {
+                self.broadcast_tx(&trade_id, "CloseTrade", &swap_tx).await?;
+            }
+        }
+        let mut response = self.call_step(&trade_id, "CloseTrade", |reply| MuSigCommand::CloseTrade(request, reply)).await?;
+        let correlation_id = correlation::correlation_id();
+        self.events.publish(TradeEvent::Closed { trade_id: trade_id.clone(), correlation_id });
+        if let Some((endpoint, am_buyer)) = self.direct_peer(&trade_id).await? {
+            let prv_key_share = PrvKeyShare {
+                prv_key_share: std::mem::take(&mut response.peer_output_prv_key_share),
+                sealed_prv_key_share: response.sealed_peer_output_prv_key_share.take(),
+            };
+            // The seller has already delivered its key share for the buyer's output, on signing the swap tx:
+            if am_buyer {
+                self.peers.spawn_delivery(trade_id, endpoint, Payload::PrvKeyShare(prv_key_share));
+            }
+        }
+
+        Ok(Response::new(response))
+    }
+
+    async fn archive_trade(&self, request: Request<ArchiveTradeRequest>) -> Result<Response<helloworld::TradeSummary>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let request_digest = digest(&request);
+        let summary = self.spawn_blocking(move |this| {
+            this.archive_trade_if("ArchiveTrade", &request.trade_id, request.expected_revision, request_digest, |trade_model|
+                if trade_model.phase() == TradePhase::Closed {
+                    Ok(())
+                } else {
+                    Err(Status::failed_precondition(format!("trade with id {} is not closed", trade_model.trade_id())))
+                })
+        }).await?;
+
+        Ok(Response::new(summary.into()))
+    }
+
+    async fn list_trades(&self, request: Request<ListTradesRequest>) -> Result<Response<ListTradesResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
         let request = request.into_inner();
-        let trade_model = TRADE_MODELS.get_trade_model(&request.trade_id)
-            .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", request.trade_id)))?;
-        let mut trade_model = trade_model.lock().unwrap();
-        if let Some(peer_prv_key_share) = request.my_output_peers_prv_key_share.my_try_into()? {
-            // Trader receives the private key share from a cooperative peer, closing our trade.
-            trade_model.set_peer_private_key_share_for_my_output(peer_prv_key_share)?;
-            trade_model.aggregate_private_keys_for_my_output()?;
-        } else if let Some(swap_tx_input_signature) = request.swap_tx.my_try_into()? {
-            // Buyer supplies a signed swap tx to the Rust server, to close our trade. (Mainly for
-            // testing -- normally the tx would be picked up from the bitcoin network by the server.)
-            trade_model.recover_seller_private_key_share_for_buyer_output(&swap_tx_input_signature)?;
-            trade_model.aggregate_private_keys_for_my_output()?;
+        let summaries = self.spawn_blocking(move |this| Ok(if request.archived {
+            this.trade_model_store.list_archived_trades()
         } else {
-            // Peer unresponsive -- force-close our trade by publishing the swap tx. For seller only.
-            // TODO: *** BROADCAST SWAP TX ***
-        }
-        let my_prv_key_share = trade_model.get_my_private_key_share_for_peer_output()
-            .ok_or_else(|| Status::internal("missing private key share"))?;
-        let response = CloseTradeResponse {
-            peer_output_prv_key_share: my_prv_key_share.serialize().into(),
+            this.trade_model_store.list_trade_models()
+        })).await?;
+        let response = ListTradesResponse {
+            trades: summaries.into_iter().map(Into::into).collect(),
         };
 
         Ok(Response::new(response))
     }
-}
 
-impl From<helloworld::Role> for Role {
-    fn from(value: helloworld::Role) -> Self {
-        match value {
-            helloworld::Role::SellerAsMaker => Self::SellerAsMaker,
-            helloworld::Role::SellerAsTaker => Self::SellerAsTaker,
-            helloworld::Role::BuyerAsMaker => Self::BuyerAsMaker,
-            helloworld::Role::BuyerAsTaker => Self::BuyerAsTaker
-        }
+    async fn get_trade_state(&self, request: Request<GetTradeStateRequest>) -> Result<Response<helloworld::TradeState>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let trade_id = request.into_inner().trade_id;
+        let response = self.spawn_blocking(move |this| {
+            let trade_model = this.trade_model_store.get_trade_model(&trade_id)
+                .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+            let trade_state = trade_state(&lock_trade_model(&trade_model));
+            Ok(trade_state)
+        }).await?;
+
+        Ok(Response::new(response))
     }
-}
 
-impl From<ProtocolErrorKind> for Status {
-    fn from(value: ProtocolErrorKind) -> Self {
-        Self::internal(value.to_string())
+    async fn resume_trade(&self, request: Request<ResumeTradeRequest>) -> Result<Response<ResumeTradeResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let trade_id = request.into_inner().trade_id;
+        let response = self.spawn_blocking(move |this| {
+            let trade_model = this.trade_model_store.get_trade_model(&trade_id)
+                .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+            // The audit log is read under the lock, to match the phase of the trade model:
+            let trade_model = lock_trade_model(&trade_model);
+            let audit_log = this.trade_model_store.get_audit_log(&trade_id)
+                .map_err(|e| Status::internal(format!("could not read audit log: {}", e)))?;
+            let mut transcript = helloworld::TradeTranscript::from(trade_model.transcript());
+            transcript.audit_log = audit_log.iter().cloned().map(Into::into).collect();
+            let key_shares = trade_model.get_my_key_shares().is_some()
+                .then(|| this.my_key_shares_response(&trade_model)).transpose()?;
+            let nonce_shares = trade_model.get_my_nonce_shares().is_some()
+                .then(|| if trade_model.commit_to_nonces && trade_model.has_peer_nonce_commitments() {
+                    my_nonce_shares_message(&trade_model, &this.faults)
+                } else {
+                    my_nonce_shares_or_commitments(&trade_model, &this.faults)
+                }).transpose()?;
+            let mut partial_signatures = trade_model.get_my_partial_signatures_on_peer_txs().is_some()
+                .then(|| my_partial_signatures_message(&trade_model, &this.faults)).transpose()?;
+            // As for GetPartialSignatures, a buyer exchanging payloads directly with the peer withholds
+            // its swap tx signature, for ReleaseSwapTxSignature to hand out:
+            if let Some(message) = partial_signatures.as_mut().filter(|_| trade_model.peer_endpoint.is_some() && trade_model.am_buyer()) {
+                message.redact_swap_tx_input_partial_signature();
+            }
+            let fee_rate_change = trade_model.pending_prepared_tx_fee_rate().is_some()
+                .then(|| my_fee_rate_change_message(&trade_model)).transpose()?
+                .map(|(message, _)| message);
+            let swap_tx_fee_bump = trade_model.pending_swap_tx_fee_rate().is_some()
+                .then(|| my_swap_tx_fee_bump_message(&trade_model)).transpose()?
+                .map(|(message, _)| message);
+            Ok(ResumeTradeResponse {
+                state: Some(trade_state(&trade_model)),
+                transcript: Some(transcript),
+                descriptor: Some(protocol_descriptor(trade_model.my_role(), Some((trade_model.phase(), &audit_log)))),
+                key_shares,
+                nonce_shares,
+                partial_signatures,
+                fee_rate_change,
+                swap_tx_fee_bump,
+            })
+        }).await?;
+
+        Ok(Response::new(response))
     }
-}
 
-trait MyTryInto<T> {
-    fn my_try_into(self) -> Result<T, Status>;
-}
+    async fn get_trade_audit_log(&self, request: Request<GetTradeAuditLogRequest>) -> Result<Response<GetTradeAuditLogResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let trade_id = request.into_inner().trade_id;
+        let entries = self.spawn_blocking(move |this| {
+            if this.trade_model_store.get_trade_model(&trade_id).is_none()
+                && !this.trade_model_store.list_archived_trades().iter().any(|s| s.trade_id == trade_id) {
+                return Err(Status::not_found(format!("missing trade with id: {}", trade_id)));
+            }
+            this.trade_model_store.get_audit_log(&trade_id)
+                .map_err(|e| Status::internal(format!("could not read audit log: {}", e)))
+        }).await?;
+        let response = GetTradeAuditLogResponse {
+            entries: entries.into_iter().map(Into::into).collect(),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn export_trade_transcript(&self, request: Request<ExportTradeTranscriptRequest>) -> Result<Response<ExportTradeTranscriptResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let trade_id = request.into_inner().trade_id;
+        let response = self.spawn_blocking(move |this| {
+            let trade_model = this.trade_model_store.get_trade_model(&trade_id)
+                .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+            // The audit log is read under the lock too, as each step is logged before the lock is let go:
+            let trade_model = lock_trade_model(&trade_model);
+            let identity_pub_key = trade_model.get_my_identity_pub_key()
+                .ok_or_else(|| Status::internal("missing identity key"))?;
+            let mut transcript = helloworld::TradeTranscript::from(trade_model.transcript());
+            transcript.exported_at_millis = to_millis(SystemTime::now());
+            transcript.audit_log = this.trade_model_store.get_audit_log(&trade_id)
+                .map_err(|e| Status::internal(format!("could not read audit log: {}", e)))?
+                .into_iter().map(Into::into).collect();
+            let transcript = transcript.encode_to_vec();
+            let identity_signature = sign_payload(&trade_model, PayloadKind::Transcript, &[&transcript])?;
+            drop(trade_model);
+            Ok(ExportTradeTranscriptResponse {
+                transcript,
+                identity_pub_key: identity_pub_key.serialize().into(),
+                identity_signature,
+            })
+        }).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn export_prepared_tx_package(&self, request: Request<ExportPreparedTxPackageRequest>) -> Result<Response<ExportPreparedTxPackageResponse>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let trade_id = request.into_inner().trade_id;
+        let response = self.spawn_blocking(move |this| {
+            let trade_model = this.trade_model_store.get_trade_model(&trade_id)
+                .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+            let trade_model = lock_trade_model(&trade_model);
+            if trade_model.phase() < TradePhase::DepositTxSigned || trade_model.cancellation.is_some() {
+                return Err(Status::failed_precondition(format!("no signed prepared txs for trade in phase {:?}{}",
+                    trade_model.phase(), if trade_model.cancellation.is_some() { ", being cancelled" } else { "" })));
+            }
+            let identity_pub_key = trade_model.get_my_identity_pub_key()
+                .ok_or_else(|| Status::internal("missing identity key"))?;
+            let mut package = helloworld::PreparedTxPackage::from(trade_model.prepared_tx_package(this.warning_tx_claim_blocks)?);
+            package.exported_at_millis = to_millis(SystemTime::now());
+            let package = package.encode_to_vec();
+            let identity_signature = sign_payload(&trade_model, PayloadKind::PreparedTxPackage, &[&package])?;
+            drop(trade_model);
+            Ok(ExportPreparedTxPackageResponse {
+                package,
+                identity_pub_key: identity_pub_key.serialize().into(),
+                identity_signature,
+            })
+        }).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_protocol_descriptor(&self, request: Request<ProtocolDescriptorRequest>) -> Result<Response<ProtocolDescriptor>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
 
-impl MyTryInto<Point> for &[u8] {
-    fn my_try_into(self) -> Result<Point, Status> {
-        self.try_into().map_err(|_| Status::invalid_argument("could not decode point"))
+        let request = request.into_inner();
+        let role = decode_role(request.role, "role")?;
+        let Some(trade_id) = request.trade_id else {
+            return Ok(Response::new(protocol_descriptor(role, None)));
+        };
+        let response = self.spawn_blocking(move |this| {
+            let store = &this.trade_model_store;
+            let (role, phase) = if let Some(trade_model) = store.get_trade_model(&trade_id) {
+                let trade_model = lock_trade_model(&trade_model);
+                (trade_model.my_role(), trade_model.phase())
+            } else {
+                let summary = store.list_archived_trades().into_iter().find(|s| s.trade_id == trade_id)
+                    .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+                (summary.my_role, summary.phase)
+            };
+            let audit_log = store.get_audit_log(&trade_id)
+                .map_err(|e| Status::internal(format!("could not read audit log: {}", e)))?;
+            Ok(protocol_descriptor(role, Some((phase, &audit_log))))
+        }).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_service_info(&self, request: Request<GetServiceInfoRequest>) -> Result<Response<helloworld::ServiceInfo>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        Ok(Response::new((*self.service_info).clone()))
+    }
+
+    async fn get_wallet_status(&self, request: Request<GetWalletStatusRequest>) -> Result<Response<WalletStatus>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let wallet_utxos = request.into_inner().wallet_utxos;
+        let response = self.spawn_blocking(move |this| Ok(wallet::wallet_status(&*this.trade_model_store, &wallet_utxos))).await?;
+
+        Ok(Response::new(response))
+    }
+
+    async fn set_trade_policy(&self, request: Request<SetTradePolicyRequest>) -> Result<Response<helloworld::TradePolicy>, Status> {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        let overrides = request.overrides.unwrap_or_default();
+        let overrides = PolicyOverrides {
+            warning_tx_after_blocks: overrides.warning_tx_after_blocks,
+            auto_claim: overrides.auto_claim,
+            dry_run: overrides.dry_run,
+        };
+        let trade_id = request.trade_id;
+        let policy = self.spawn_blocking(move |this| {
+            let trade_model = this.trade_model_store.get_trade_model(&trade_id)
+                .ok_or_else(|| Status::not_found(format!("missing trade with id: {}", trade_id)))?;
+            let mut trade_model = lock_trade_model(&trade_model);
+            let old_overrides = mem::replace(&mut trade_model.policy_overrides, overrides);
+            if let Err(e) = this.trade_model_store.save_trade_model(&trade_model) {
+                trade_model.policy_overrides = old_overrides;
+                return Err(Status::internal(format!("could not save trade model: {}", e)));
+            }
+            drop(trade_model);
+            Ok(this.policy.policy_for(&overrides))
+        }).await?;
+        let response = helloworld::TradePolicy {
+            warning_tx_after_blocks: Some(policy.warning_tx_after_blocks.unwrap_or_default()),
+            auto_claim: Some(policy.auto_claim),
+            dry_run: Some(policy.dry_run),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    type SubscribeHeightTriggersStream = Pin<Box<dyn stream::Stream<Item=Result<HeightTrigger, Status>> + Send>>;
+
+    async fn subscribe_height_triggers(&self, request: Request<HeightTriggersRequest>)
+        -> Result<Response<Self::SubscribeHeightTriggersStream>, Status>
+    {
+        println!("Got a request: {}", logging::debug_for_log(&request));
+
+        let request = request.into_inner();
+        // Subscriptions to every trade (with no trade ID) are counted together:
+        let subscription = self.subscriptions.subscribe(&request.trade_id)?;
+        let mut watch = HeightTriggerWatch {
+            musig: self.clone(),
+            trade_id: request.trade_id,
+            deposit_confirmations: request.deposit_confirmations.unwrap_or(1),
+            tip: self.chain_tip.subscribe(),
+            sent: HashSet::new(),
+            pending: VecDeque::new(),
+            _subscription: subscription,
+        };
+        // Fail the call up front if the trade is missing, rather than end the stream at once:
+        watch.check().await?;
+
+        Ok(Response::new(Box::pin(stream::unfold(watch, HeightTriggerWatch::next))))
     }
 }
 
-impl MyTryInto<PubNonce> for &[u8] {
-    fn my_try_into(self) -> Result<PubNonce, Status> {
-        self.try_into().map_err(|_| Status::invalid_argument("could not decode pub nonce"))
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (config, command) = Config::from_args(std::env::args().skip(1))?;
+    let file_store = match &config.store {
+        StoreConfig::Memory => None,
+        StoreConfig::File { dir, secret_key_source } => {
+            let master_secret = secret_key_source.as_ref().map(MasterSecret::fetch).transpose()?;
+            if master_secret.is_none() {
+                println!("WARNING: No store passphrase or key configured, so secrets will be stored in plaintext");
+            }
+            Some(TradeModelFileStore::open(dir, master_secret.as_ref())?)
+        }
+    };
+    let snapshot_passphrase = || MasterSecret::fetch(&SecretKeySource::PassphraseEnv(config.snapshot_passphrase_env.clone()));
+    match (command, file_store) {
+        (Command::RecoverKeyShares(path, output), _) => {
+            let var = &config.backup_recipient_keys_env;
+            let recipient_keys = std::env::var(var).map_err(|e| format!("could not read recipient keys from env var {}: {}", var, e))?
+                .split(',')
+                .map(|hex| Scalar::from_hex(hex.trim()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let (trade_id, key_shares) = backup::recover(&fs::read(&path)?, &recipient_keys)?;
+            backup::write_key_shares(&output, &key_shares)?;
+            println!("Recovered {} key shares of trade with id {} to {}", key_shares.len(), trade_id, output.display());
+        }
+        (Command::ReplayTranscript(path), _) => {
+            let transcript = TradeTranscript::try_from(helloworld::TradeTranscript::decode(&fs::read(&path)?[..])?)?;
+            println!("Replaying transcript of trade with id {}:", transcript.trade_id);
+            for step in transcript.replay()? {
+                println!("{:?} step: ok", step);
+            }
+        }
+        (Command::ExportTestVectors(path), _) => {
+            let vectors = musig_trade_protocol::test_vectors::generate()?;
+            write_atomically(&path, test_vectors::to_json(&vectors).to_string().as_bytes())?;
+            println!("Exported test vectors of trade with id {} to {}", vectors.trade_id, path.display());
+        }
+        (Command::SimulateTrade, _) => {
+            let count = Box::pin(simulate::simulate_trades()).await?;
+            println!("Simulated {} trades end-to-end: ok", count);
+        }
+        (Command::Serve, None) => serve(&config, TradeModelMemoryStore::default()).await?,
+        (Command::Serve, Some(file_store)) => serve(&config, file_store).await?,
+        (_, None) => return Err("snapshots may only be exported from or imported to a file store".into()),
+        (Command::ExportSnapshot(path), Some(file_store)) => {
+            write_atomically(&path, &snapshot::export(&file_store, &snapshot_passphrase()?))?;
+            println!("Exported snapshot to {}", path.display());
+        }
+        (Command::ImportSnapshot(path), Some(file_store)) => {
+            let (live, archived) = snapshot::import(&file_store, &fs::read(&path)?, &snapshot_passphrase()?)?;
+            println!("Imported {} live and {} archived trades from {}", live, archived, path.display());
+        }
     }
+    Ok(())
 }
 
-impl MyTryInto<Scalar> for &[u8] {
-    fn my_try_into(self) -> Result<Scalar, Status> {
-        self.try_into().map_err(|_| Status::invalid_argument("could not decode scalar"))
+async fn serve<S>(config: &Config, trade_model_store: S) -> Result<(), Box<dyn std::error::Error>>
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let listener = TcpListener::bind(config.listen_addr).await?;
+    let peer_listener = match config.peer_listen_addr {
+        Some(peer_listen_addr) => Some(TcpListener::bind(peer_listen_addr).await?),
+        None => None,
+    };
+    let mut listeners = Vec::with_capacity(config.listeners.len());
+    for listener_config in &config.listeners {
+        listeners.push(Listener::bind(&listener_config.addr).await?);
     }
+    serve_on(config, trade_model_store, listener, peer_listener, listeners).await
 }
 
-impl MyTryInto<MaybeScalar> for &[u8] {
-    fn my_try_into(self) -> Result<MaybeScalar, Status> {
-        self.try_into().map_err(|_| Status::invalid_argument("could not decode scalar"))
+/// Give the trade models loaded from the store the configured signer (or test keys, for a dry run),
+/// as they don't record their signer.
+fn set_trade_signers(store: &impl TradeModelStore, signer: &Arc<dyn Signer>) {
+    for summary in store.list_trade_models() {
+        if let Some(trade_model) = store.get_trade_model(&summary.trade_id) {
+            let mut trade_model = lock_trade_model(&trade_model);
+            let trade_signer: Arc<dyn Signer> = if trade_model.dry_run {
+                Arc::new(TestSigner::for_trade(&summary.trade_id, summary.my_role))
+            } else {
+                Arc::clone(signer)
+            };
+            trade_model.set_signer(trade_signer);
+        }
     }
 }
 
-impl MyTryInto<LiftedSignature> for &[u8] {
-    fn my_try_into(self) -> Result<LiftedSignature, Status> {
-        self.try_into().map_err(|_| Status::invalid_argument("could not decode signature"))
+/// Serve the `MuSig` service with the given listener (and the peer service with the given peer
+/// listener, if any, and the configured `listener.<name>` services with the given listeners, bound
+/// for them in order), in place of binding to the configured listen addresses.
+async fn serve_on<S>(config: &Config, trade_model_store: S, listener: TcpListener, peer_listener: Option<TcpListener>,
+                     listeners: Vec<Listener>) -> Result<(), Box<dyn std::error::Error>>
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let trade_model_store = Arc::new(QuotaStore::new(trade_model_store, config.trade_quota));
+    let socks_proxy = config.socks_proxy.map(|addr| Socks5Proxy { addr });
+    let signer: Arc<dyn Signer> = match &config.signer {
+        SignerConfig::Local => Arc::new(LocalSigner),
+        SignerConfig::Remote { url } => Arc::new(RemoteSigner::connect(url.clone(), socks_proxy).await?),
+    };
+    set_trade_signers(&*trade_model_store, &signer);
+    let events = TradeEventBus::default();
+    tokio::spawn(log_trade_events(events.clone()));
+    if !config.webhook.urls.is_empty() {
+        let notifier = WebhookNotifier::from_env(&config.webhook)?.with_proxy(socks_proxy);
+        tokio::spawn(webhook::notify_webhooks(events.clone(), Arc::new(notifier)));
+    }
+    if let Some(ttl) = config.stale_trade_ttl {
+        tokio::spawn(gc::collect_stale_trades(Arc::clone(&trade_model_store), events.clone(), ttl,
+            config.stale_trade_scan_interval));
+    }
+    let (chain_tip, chain_backend, mock_chain) = spawn_chain_follower(&config.chain, socks_proxy);
+    let policy = Arc::new(PolicyEngine::new(config.policy));
+    if config.deadlines.any() {
+        tokio::spawn(deadlines::schedule_deadlines(Arc::clone(&trade_model_store), events.clone(), chain_tip.clone(),
+            config.deadlines, Arc::clone(&policy)));
+    }
+    if config.policy.dry_run {
+        println!("Policy engine in dry-run mode: automatic protocol responses will only be announced");
+    }
+
+    let backup = config.backup.as_ref().map(KeyShareBackup::open).transpose()?;
+    // Keep hold of the onion service (if any), as it is taken down once dropped:
+    let onion_target = match config.listeners.iter().position(|listener_config| listener_config.onion) {
+        Some(i) => Some(listeners[i].tcp_addr()?),
+        None => peer_listener.as_ref().map(TcpListener::local_addr).transpose()?,
+    };
+    let (onion_service, my_peer_address) = publish_peer_service(config, onion_target).await?;
+    let peers = Arc::new(PeerTransport { my_address: my_peer_address, socks_proxy, ..Default::default() });
+    let peer_service = MyMuSigPeer::new(Arc::clone(&trade_model_store), Arc::clone(&peers.inbox));
+    if config.faults.any() {
+        println!("WARNING: Injecting faults into the payloads for our peers, which is for testing only");
+    }
+    let musig = MyMuSig::new(Arc::clone(&trade_model_store), signer, backup, peers)
+        .with_faults(FaultInjector::new(config.faults))
+        .with_policy(policy)
+        .with_events(events)
+        .with_service_info(service_info(config))
+        .with_max_subscriptions_per_trade(config.max_subscriptions_per_trade)
+        .with_trade_limits(config.trade_limits)
+        .with_nonce_reuse(config.nonce_reuse)
+        .with_warning_tx_claim_blocks(config.deadlines.warning_tx_claim_blocks);
+    let musig = match config.mediator_pub_key {
+        Some(mediator_pub_key) => musig.with_mediator(mediator_pub_key),
+        None => musig,
+    };
+    let musig = match mock_chain {
+        Some(mock_chain) => musig.with_mock_chain(mock_chain),
+        None => musig.with_chain_tip(chain_tip),
+    };
+    let musig = match ReceiverRegistry::load(&config.burningman)? {
+        Some(registry) => musig.with_receiver_registry(Arc::new(registry)),
+        None => musig,
+    };
+    let musig = match &config.source_file {
+        Some(path) => musig.with_config_file(path.clone()),
+        None => musig,
+    };
+
+    let decode_limits = DecodeLimitLayer::new(config.decode_limits)?;
+    let rate_limit = RateLimitLayer::new(config.rate_limits);
+    spawn_http_servers(config, &trade_model_store, &musig, &decode_limits, &rate_limit);
+    let admin_auth = config.admin_listen_addr
+        .map(|addr| TokenAuth::for_admin_from_env(&config.admin_token_env, &ListenAddr::Tcp(addr))).transpose()?;
+
+    logging::set_log_sensitive(config.log_sensitive);
+    let services = Services {
+        musig, peer: peer_service, health: MyHealth::new(readiness_checks(config, chain_backend)), decode_limits,
+        rate_limit,
+    };
+    let mut servers = vec![listeners::serve_service(config, Server::builder(), services.clone(), TokenAuth::new(None),
+        ListenerService::MuSig, tcp_incoming(listener)?)];
+    if let Some(peer_listener) = peer_listener {
+        servers.push(listeners::serve_service(config, Server::builder(), services.clone(), TokenAuth::new(None),
+            ListenerService::Peer, tcp_incoming(peer_listener)?));
+    }
+    if let (Some(addr), Some(auth)) = (config.admin_listen_addr, admin_auth) {
+        let incoming = TcpIncoming::new(addr, false, None).map_err(|e| e as Box<dyn std::error::Error>)?;
+        servers.push(listeners::serve_service(config, Server::builder(), services.clone(), auth, ListenerService::Admin, incoming));
+    }
+    for (listener_config, listener) in config.listeners.iter().zip(listeners) {
+        servers.push(listeners::serve_listener(config, listener_config, listener, &services)?);
     }
+    futures::future::try_join_all(servers).await?;
+    drop(onion_service);
+
+    Ok(())
 }
 
-impl MyTryInto<Role> for i32 {
-    fn my_try_into(self) -> Result<Role, Status> {
-        TryInto::<helloworld::Role>::try_into(self)
-            .map_err(|UnknownEnumValue(i)| Status::out_of_range(format!("unknown enum value: {}", i)))
-            .map(Into::into)
+/// Follow the chain tip off the configured chain backend (through the given proxy), if any,
+/// returning the tip and the status of the backend, or else mock the chain if configured, returning
+/// the mock chain to broadcast the txs to, or else simulate the chain.
+fn spawn_chain_follower(config: &ChainConfig, socks_proxy: Option<Socks5Proxy>)
+    -> (ChainTip, Option<ChainBackendStatus>, Option<Arc<MockChainBackend>>)
+{
+    if config.mock.enabled {
+        println!("Following a mock chain: no tx is broadcast");
+        let mock_chain = Arc::new(MockChainBackend::new(SIMULATED_TIP_HEIGHT, config.mock.confirmation_blocks));
+        if let Some(interval) = config.mock.block_interval {
+            tokio::spawn(Arc::clone(&mock_chain).produce_blocks(interval));
+        }
+        return (mock_chain.chain_tip(), None, Some(mock_chain));
     }
+    let Some(url) = &config.backend_url else { return (ChainTip::fixed(SIMULATED_TIP_HEIGHT), None, None) };
+    let (chain_tip, status) = (ChainTip::default(), ChainBackendStatus::default());
+    tokio::spawn(chain::follow_chain(url.clone(), socks_proxy, chain_tip.clone(), status.clone(), config.poll_interval));
+    (chain_tip, Some(status), None)
 }
 
-impl<T> MyTryInto<T> for Vec<u8> where for<'a> &'a [u8]: MyTryInto<T> {
-    fn my_try_into(self) -> Result<T, Status> { (&self[..]).my_try_into() }
+/// The dependencies to check for readiness: the store directory, and the chain backend (if any),
+/// which may go unreached for a couple of failed polls before the daemon is no longer ready.
+fn readiness_checks(config: &Config, chain_backend: Option<ChainBackendStatus>) -> ReadinessChecks {
+    ReadinessChecks {
+        store_dir: match &config.store {
+            StoreConfig::Memory => None,
+            StoreConfig::File { dir, .. } => Some(dir.clone()),
+        },
+        chain_backend: chain_backend
+            .map(|status| (status, config.chain.poll_interval * 3, config.chain.max_blocks_behind)),
+    }
 }
 
-impl<T, S: MyTryInto<T>> MyTryInto<Option<T>> for Option<S> {
-    fn my_try_into(self) -> Result<Option<T>, Status> {
-        Ok(match self {
-            None => None,
-            Some(x) => Some(x.my_try_into()?)
-        })
+/// Publish the peer service as an onion service forwarding to the given address, if configured,
+/// returning it and the address of the peer service to hand out to our peers, if any.
+async fn publish_peer_service(config: &Config, onion_target: Option<SocketAddr>)
+    -> Result<(Option<OnionService>, Option<String>), Box<dyn std::error::Error>>
+{
+    let onion_service = match (&config.onion_service, onion_target) {
+        (Some(onion_config), Some(onion_target)) => Some(tor::publish_onion_service(onion_config, onion_target).await?),
+        _ => None,
+    };
+    let my_peer_address = onion_service.as_ref()
+        .map(|service| format!("http://{}:{}", service.address, config.onion_service.as_ref().map_or(0, |c| c.port)))
+        .or_else(|| config.peer_public_address.clone());
+    if let Some(address) = &my_peer_address {
+        println!("Serving the peer service at {}", address);
     }
+    Ok((onion_service, my_peer_address))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:50051".parse()?;
-    let greeter = MyGreeter::default();
-    let musig = MyMuSig::default();
+/// Serve the metrics and the JSON gateway, where configured.
+fn spawn_http_servers<S>(config: &Config, trade_model_store: &Arc<QuotaStore<S>>, musig: &MyMuSig<QuotaStore<S>>,
+                         decode_limits: &DecodeLimitLayer, rate_limit: &RateLimitLayer)
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    if let Some(metrics_listen_addr) = config.metrics_listen_addr {
+        let trade_model_store = Arc::clone(trade_model_store);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_metrics(metrics_listen_addr, trade_model_store).await {
+                eprintln!("Metrics server failed: {}", e);
+            }
+        });
+    }
+    if let Some(gateway_listen_addr) = config.gateway_listen_addr {
+        let service = gateway_service(config, musig, decode_limits, rate_limit);
+        tokio::spawn(async move {
+            if let Err(e) = gateway::serve_gateway(gateway_listen_addr, service).await {
+                eprintln!("JSON gateway failed: {}", e);
+            }
+        });
+    }
+}
 
-    Server::builder()
-        .add_service(GreeterServer::new(greeter))
-        .add_service(MuSigServer::new(musig))
-        .serve(addr)
-        .await?;
+/// The `MuSig` service for the JSON gateway, wrapped in the same layers as on any listener (bar the
+/// gRPC-web layer), so that the calls through the gateway are logged, rate-limited, charged to the
+/// trade quota of their client and held to the same timeouts, decoding limits, trade ownership &
+/// step order.
+fn gateway_service<S>(config: &Config, musig: &MyMuSig<S>, decode_limits: &DecodeLimitLayer, rate_limit: &RateLimitLayer)
+    -> impl Service<HttpRequest<BoxBody>, Response=HttpResponse<impl Body<Data=Bytes, Error=Status> + Unpin + Send>,
+        Error=Infallible, Future: Send> + Clone + Send + 'static
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let musig_server = MuSigServer::new(musig.clone()).max_decoding_message_size(config.decode_limits.max_message_len);
+    ClientIdentityLayer.layer(CorrelationLayer.layer(logging::LogLayer.layer(rate_limit.layer(
+        TimeoutLayer::new(config.rpc_timeouts.clone()).layer(decode_limits.layer(
+            OwnershipLayer::new(Arc::clone(&musig.trade_model_store)).layer(
+                StepOrderLayer::new(Arc::clone(&musig.trade_model_store)).layer(musig_server))))))))
+}
 
-    Ok(())
+async fn log_trade_events(events: TradeEventBus) {
+    let mut events = events.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(TradeEvent::Aborted(summary)) => println!("Aborted trade with id {} in phase {:?}",
+                summary.trade_id, summary.phase),
+            Ok(TradeEvent::DeadlineApproaching { trade_id, deadline }) => println!(
+                "{:?} deadline of trade with id {} is approaching, in phase {:?}", deadline.kind, trade_id, deadline.phase),
+            Ok(TradeEvent::DeadlinePassed { trade_id, deadline }) => println!(
+                "{:?} deadline of trade with id {} has passed, in phase {:?}", deadline.kind, trade_id, deadline.phase),
+            Ok(TradeEvent::PeerUnresponsive { trade_id, .. }) => println!("Peer of trade with id {} is unresponsive", trade_id),
+            Ok(TradeEvent::PeerNonceReused { trade_id, reused_from }) => println!(
+                "WARNING: Peer of trade with id {} reused a nonce it sent in trade with id {}", trade_id, reused_from),
+            Ok(TradeEvent::PolicyAction { trade_id, action, dry_run }) => println!("{} {:?} for trade with id {}",
+                if dry_run { "Would take (dry run)" } else { "Taking" }, action.kind, trade_id),
+            // Already logged by the log layer, as a failed call:
+            Ok(TradeEvent::StepFailed { .. }) => {}
+            Ok(TradeEvent::Closed { trade_id, .. }) => println!("Closed trade with id {}", trade_id),
+            Err(RecvError::Lagged(n)) => println!("Missed {} trade events", n),
+            Err(RecvError::Closed) => break,
+        }
+    }
 }
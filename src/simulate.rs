@@ -0,0 +1,253 @@
+//! A simulation of whole trades between two daemons run in-process, one for the buyer and one for
+//! the seller, with a client playing both parties through every RPC of the `MuSig` service, as an
+//! executable check that the protocol round-trips. Each trade is closed either cooperatively or via
+//! the swap tx, with its peer payloads relayed by the client (plain or sealed) or exchanged by the
+//! daemons directly. The warning path has no RPCs yet, so it is only checked as far as the peers'
+//! partial signatures on the warning txs, by replaying the transcript of each side of the trade.
+
+use musig_proto::helloworld::{self, ExportTradeTranscriptRequest, ProtocolDescriptorRequest, SignedDepositPsbtRequest,
+    StepStatus, UnsignedDepositPsbtRequest};
+use musig_trade_client::{ClientError, CloseTrade, GetNonceShares, GetPartialSignatures, InitTrade,
+    PrvKeyShareForPeer, PublishDepositTx, SignDepositTx, SignSwapTx, TradeClient};
+use musig_trade_protocol::{PeerEndpoint, ReplayStep, Role, TradeModelMemoryStore, TradeTranscript};
+use prost::Message as _;
+use secp::Point;
+use std::error::Error;
+use std::future::Future;
+use std::prelude::rust_2021::*;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tonic::Code;
+
+use crate::config::Config;
+
+/// How the peer payloads of a simulated trade are passed between the daemons.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Exchange {
+    Relayed,
+    Sealed,
+    Direct,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Close {
+    Cooperative,
+    SwapTx,
+}
+
+/// The trades to simulate. A cooperative close of a trade exchanging its peer payloads directly is
+/// left out, as the buyer's daemon would force-close the trade if the seller's key share for the
+/// buyer's output hadn't been delivered yet, with nothing for the client to wait on.
+const TRADES: [(Exchange, Close); 5] = [
+    (Exchange::Relayed, Close::Cooperative),
+    (Exchange::Relayed, Close::SwapTx),
+    (Exchange::Sealed, Close::Cooperative),
+    (Exchange::Sealed, Close::SwapTx),
+    (Exchange::Direct, Close::SwapTx),
+];
+
+/// How many times to try a step of a trade exchanging its peer payloads directly, which fails as
+/// `NOT_FOUND` until the peer's payload for it has been delivered, and how long to wait in between.
+const DELIVERY_ATTEMPTS: u32 = 50;
+const DELIVERY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+type Result<T, E = Box<dyn Error>> = std::result::Result<T, E>;
+
+struct Daemon {
+    client: TradeClient,
+    peer_address: String,
+}
+
+fn check(condition: bool, msg: impl FnOnce() -> String) -> Result<()> {
+    if condition { Ok(()) } else { Err(msg().into()) }
+}
+
+/// Simulate every trade in [`TRADES`], returning the number of trades simulated, or else the first
+/// failure.
+pub async fn simulate_trades() -> Result<usize> {
+    let (buyer_config, buyer_listener, buyer_peer_listener) = daemon_config().await?;
+    let (seller_config, seller_listener, seller_peer_listener) = daemon_config().await?;
+    let buyer_url = format!("http://{}", buyer_listener.local_addr()?);
+    let seller_url = format!("http://{}", seller_listener.local_addr()?);
+    let buyer_daemon = crate::serve_on(&buyer_config, TradeModelMemoryStore::default(), buyer_listener,
+        Some(buyer_peer_listener), Vec::new());
+    let seller_daemon = crate::serve_on(&seller_config, TradeModelMemoryStore::default(), seller_listener,
+        Some(seller_peer_listener), Vec::new());
+    let simulation = async {
+        let buyer = Daemon {
+            client: TradeClient::connect(buyer_url).await?,
+            peer_address: buyer_config.peer_public_address.clone().unwrap_or_default(),
+        };
+        let seller = Daemon {
+            client: TradeClient::connect(seller_url).await?,
+            peer_address: seller_config.peer_public_address.clone().unwrap_or_default(),
+        };
+        for (i, (exchange, close)) in TRADES.into_iter().enumerate() {
+            let trade_id = format!("simulated-trade-{}", i);
+            println!("Simulating trade with id {} ({:?} exchange, {:?} close)", trade_id, exchange, close);
+            simulate_trade(&buyer, &seller, &trade_id, exchange, close).await
+                .map_err(|e| format!("simulated trade with id {} failed: {}", trade_id, e))?;
+        }
+        Ok::<_, Box<dyn Error>>(TRADES.len())
+    };
+    tokio::select! {
+        result = buyer_daemon => result.and(Err("buyer's daemon stopped".into())),
+        result = seller_daemon => result.and(Err("seller's daemon stopped".into())),
+        result = simulation => result,
+    }
+}
+
+/// The config of a daemon serving on the loopback interface, at ports picked by the OS, with the
+/// listeners bound to them.
+async fn daemon_config() -> Result<(Config, TcpListener, TcpListener)> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let peer_listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let config = Config {
+        peer_public_address: Some(format!("http://{}", peer_listener.local_addr()?)),
+        stale_trade_ttl: None,
+        ..Config::default()
+    };
+    Ok((config, listener, peer_listener))
+}
+
+/// Make the given call until it no longer fails as `NOT_FOUND`, for a step waiting on a payload
+/// delivered by the peer's daemon.
+async fn until_delivered<T, F, Fut>(mut call: F) -> Result<T, ClientError>
+    where F: FnMut() -> Fut,
+          Fut: Future<Output=Result<T, ClientError>>
+{
+    for _ in 1..DELIVERY_ATTEMPTS {
+        match call().await {
+            Err(ClientError::Status(status)) if status.code() == Code::NotFound =>
+                tokio::time::sleep(DELIVERY_POLL_INTERVAL).await,
+            result => return result,
+        }
+    }
+    call().await
+}
+
+async fn simulate_trade(buyer: &Daemon, seller: &Daemon, trade_id: &str, exchange: Exchange, close: Close) -> Result<()> {
+    let direct = exchange == Exchange::Direct;
+    let init_trade = |role, peer: &Daemon| {
+        let step = InitTrade::new(trade_id, role);
+        match exchange {
+            Exchange::Relayed => step,
+            Exchange::Sealed => step.seal_peer_payloads(),
+            Exchange::Direct => step.peer(PeerEndpoint { address: peer.peer_address.clone(), trade_id: trade_id.to_owned() }),
+        }
+    };
+    let buyer_keys = buyer.client.init_trade(init_trade(Role::BuyerAsTaker, seller)).await?;
+    let seller_keys = seller.client.init_trade(init_trade(Role::SellerAsMaker, buyer)).await?;
+
+    let get_nonce_shares = |peers_keys| GetNonceShares::new(trade_id)
+        .peers_key_shares(peers_keys)
+        .fee_rates(50.0, 40.0)
+        .amounts(200_000, 30_000, 30_000);
+    let buyer_nonces = buyer.client.get_nonce_shares(get_nonce_shares(&seller_keys)).await?;
+    let seller_nonces = seller.client.get_nonce_shares(get_nonce_shares(&buyer_keys)).await?;
+    check(buyer_nonces.nonce_shares.is_some() == (exchange != Exchange::Sealed),
+        || "nonce shares sealed (or not) against the trade's setting".to_owned())?;
+
+    let mut step = GetPartialSignatures::new(trade_id);
+    if !direct {
+        step = step.peers_nonce_shares(&seller_nonces);
+    }
+    let buyer_sigs = until_delivered(|| buyer.client.get_partial_signatures(step.clone())).await?;
+    let mut step = GetPartialSignatures::new(trade_id);
+    if !direct {
+        step = step.peers_nonce_shares(&buyer_nonces);
+    }
+    let seller_sigs = until_delivered(|| seller.client.get_partial_signatures(step.clone())).await?;
+
+    let mut step = SignDepositTx::new(trade_id);
+    if !direct {
+        step = step.peers_partial_signatures(&buyer_sigs.redacted());
+    }
+    until_delivered(|| seller.client.sign_deposit_tx(step.clone())).await?;
+    let mut step = SignDepositTx::new(trade_id);
+    if !direct {
+        step = step.peers_partial_signatures(&seller_sigs);
+    }
+    let deposit_psbt = until_delivered(|| buyer.client.sign_deposit_tx(step.clone())).await?;
+    sign_half_deposit_psbt_externally(&seller.client, trade_id).await?;
+
+    let mut confirmations = buyer.client.publish_deposit_tx(PublishDepositTx::new(trade_id).deposit_psbt(deposit_psbt)).await?;
+    while confirmations.message().await?.is_some() {}
+
+    // Once the buyer has started payment, and the seller received it:
+    buyer.client.confirm_payment_started(trade_id, None).await?;
+    let mut step = SignSwapTx::new(trade_id);
+    if direct {
+        buyer.client.release_swap_tx_signature(trade_id).await?;
+    } else {
+        step = step.peers_partial_signatures(&buyer_sigs);
+    }
+    seller.client.confirm_payment_received(trade_id, None).await?;
+    let swap_tx = until_delivered(|| seller.client.sign_swap_tx(step.clone())).await?;
+
+    match close {
+        Close::Cooperative => {
+            let key_share = &swap_tx.peer_output_prv_key_share;
+            check_prv_key_share(key_share, seller_keys.buyer_output_pub_key_share, "buyer")?;
+            let key_share = buyer.client.close_trade(CloseTrade::new(trade_id).peers_prv_key_share(key_share)).await?;
+            check_prv_key_share(&key_share, buyer_keys.seller_output_pub_key_share, "seller")?;
+            seller.client.close_trade(CloseTrade::new(trade_id).peers_prv_key_share(&key_share)).await?;
+        }
+        Close::SwapTx => {
+            seller.client.close_trade(CloseTrade::new(trade_id)).await?;
+            buyer.client.close_trade(CloseTrade::new(trade_id).swap_tx(swap_tx.swap_tx)).await?;
+        }
+    }
+    check_closed_trades(buyer, seller, trade_id).await
+}
+
+/// Sign our half of the deposit PSBT with a wallet kept off the daemon, as a hardware wallet would,
+/// for the seller of a simulated trade.
+async fn sign_half_deposit_psbt_externally(client: &TradeClient, trade_id: &str) -> Result<()> {
+    let unsigned_psbt = client.inner().clone().get_unsigned_deposit_psbt(UnsignedDepositPsbtRequest { trade_id: trade_id.to_owned() })
+        .await?.into_inner();
+    client.inner().clone().submit_signed_deposit_psbt(SignedDepositPsbtRequest {
+        trade_id: trade_id.to_owned(),
+        signed_half_deposit_psbt: Some(unsigned_psbt),
+        expected_revision: None,
+    }).await?;
+    Ok(())
+}
+
+/// Check that a plain private key share handed out for the peer's output is that of the given
+/// public key share, as handed out with the key shares of the party closing the trade.
+fn check_prv_key_share(key_share: &PrvKeyShareForPeer, pub_key_share: Point, output_owner: &str) -> Result<()> {
+    if let PrvKeyShareForPeer::Plain(prv_key_share) = key_share {
+        check(prv_key_share.base_point_mul() == pub_key_share, || format!(
+            "private key share for the {}'s output doesn't match its public key share", output_owner))?;
+    }
+    Ok(())
+}
+
+/// Check that both sides of the trade have done every required step of their role, replay their
+/// transcripts and check that they agree on the aggregated keys, then archive them.
+async fn check_closed_trades(buyer: &Daemon, seller: &Daemon, trade_id: &str) -> Result<()> {
+    let mut aggregated_keys = Vec::new();
+    for daemon in [buyer, seller] {
+        // The role is taken from the trade:
+        let descriptor = daemon.client.inner().clone().get_protocol_descriptor(ProtocolDescriptorRequest {
+            trade_id: Some(trade_id.to_owned()),
+            ..Default::default()
+        }).await?.into_inner();
+        for step in descriptor.steps {
+            check(step.optional || step.status() == StepStatus::Done, || format!("{} step not done", step.rpc))?;
+        }
+
+        let request = ExportTradeTranscriptRequest { trade_id: trade_id.to_owned() };
+        let response = daemon.client.inner().clone().export_trade_transcript(request).await?.into_inner();
+        let transcript = TradeTranscript::try_from(helloworld::TradeTranscript::decode(&response.transcript[..])?)?;
+        let steps = transcript.replay()?;
+        check(steps == [ReplayStep::KeyShares, ReplayStep::NonceShares, ReplayStep::PartialSignatures],
+            || format!("transcript of {:?} only replayed up to: {:?}", transcript.my_role, steps))?;
+        aggregated_keys.push([transcript.buyer_output_key.aggregated_key, transcript.seller_output_key.aggregated_key]);
+
+        daemon.client.archive_trade(trade_id, None).await?;
+    }
+    check(aggregated_keys[0].iter().all(Option::is_some) && aggregated_keys[0] == aggregated_keys[1],
+        || "buyer and seller disagree on the aggregated keys".to_owned())
+}
@@ -0,0 +1,95 @@
+//! Export & import of the whole trade store (both live and archived trades) as a single snapshot,
+//! for backup or migration to another machine. The snapshot is encrypted with its own passphrase,
+//! independent of the key (if any) that the store encrypts its secrets at rest with.
+
+use musig_trade_protocol::{lock_trade_model, CodecError, SecretCipher as _, SecretFields, TradeModel, TradeModelStore,
+    TradeSummary};
+use prost::Message as _;
+use rand::RngCore as _;
+use std::collections::BTreeSet;
+use std::io;
+use std::prelude::rust_2021::*;
+use thiserror::Error;
+
+use crate::cipher::{MasterSecret, StoreCipher};
+
+const MAGIC: &[u8] = b"MUSIGSNAPSHOT1\n";
+const SALT_LEN: usize = 16;
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SnapshotRecord {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    trade_models: Vec<Vec<u8>>,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    archived_trades: Vec<Vec<u8>>,
+}
+
+type Result<T> = std::result::Result<T, SnapshotError>;
+
+#[derive(Error, Debug)]
+#[error(transparent)]
+pub enum SnapshotError {
+    #[error("not a trade store snapshot")]
+    NotASnapshot,
+    #[error("could not decrypt snapshot (is the passphrase correct?)")]
+    Undecryptable,
+    #[error("trade with id {0} is already present in the store")]
+    DuplicateTrade(String),
+    Decode(#[from] prost::DecodeError),
+    Codec(#[from] CodecError),
+    Io(#[from] io::Error),
+}
+
+/// Encode every trade in the store, secrets included, into a snapshot encrypted with the given
+/// passphrase. Trades mid-step are waited for, so that they are captured in a consistent state.
+pub fn export(store: &impl TradeModelStore, passphrase: &MasterSecret) -> Vec<u8> {
+    let record = SnapshotRecord {
+        trade_models: store.list_trade_models().iter()
+            .filter_map(|summary| store.get_trade_model(&summary.trade_id))
+            .map(|trade_model| lock_trade_model(&trade_model).encode_to_vec(SecretFields::Include))
+            .collect(),
+        archived_trades: store.list_archived_trades().iter().map(TradeSummary::encode_to_vec).collect(),
+    };
+    let mut salt = [0; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let sealed = StoreCipher::derive(passphrase, &salt).seal(&record.encode_to_vec(), &[MAGIC, &salt].concat());
+    [MAGIC, &salt, &sealed].concat()
+}
+
+/// Decrypt a snapshot from [`export`] and add all its trades to the store, returning the number of
+/// live and archived trades imported. Nothing is imported unless the whole snapshot is valid and
+/// none of its trades are already in the store (live or archived).
+pub fn import(store: &impl TradeModelStore, snapshot: &[u8], passphrase: &MasterSecret) -> Result<(usize, usize)> {
+    let rest = snapshot.strip_prefix(MAGIC).ok_or(SnapshotError::NotASnapshot)?;
+    let (salt, sealed) = rest.split_at_checked(SALT_LEN).ok_or(SnapshotError::NotASnapshot)?;
+    let record = StoreCipher::derive(passphrase, salt).open(sealed, &[MAGIC, salt].concat())
+        .ok_or(SnapshotError::Undecryptable)?;
+    let record = SnapshotRecord::decode(&record[..])?;
+
+    let trade_models = record.trade_models.iter()
+        .map(|bytes| TradeModel::decode(bytes, None))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let archived_trades = record.archived_trades.iter()
+        .map(|bytes| TradeSummary::decode(bytes))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let mut trade_ids: BTreeSet<_> = store.list_trade_models().into_iter()
+        .chain(store.list_archived_trades())
+        .map(|summary| summary.trade_id)
+        .collect();
+    let new_trade_ids = trade_models.iter().map(TradeModel::trade_id)
+        .chain(archived_trades.iter().map(|summary| &summary.trade_id[..]));
+    for trade_id in new_trade_ids {
+        if !trade_ids.insert(trade_id.to_owned()) {
+            return Err(SnapshotError::DuplicateTrade(trade_id.to_owned()));
+        }
+    }
+
+    let counts = (trade_models.len(), archived_trades.len());
+    for trade_model in trade_models {
+        store.add_trade_model(trade_model)?;
+    }
+    for summary in archived_trades {
+        store.add_archived_trade(summary)?;
+    }
+    Ok(counts)
+}
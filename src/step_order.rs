@@ -0,0 +1,202 @@
+//! The enforcement of the order of the protocol steps at the RPC layer, ahead of the checks of each
+//! step on the trade model: a call to a step of the trade's role, made before some earlier step it
+//! depends on has been done, is turned away with `FAILED_PRECONDITION`, naming the RPC of the step
+//! expected next (also given in the `expected-next-rpc` metadata of the status).
+
+use http_body::{Body, Frame, SizeHint};
+use musig_trade_protocol::{lock_trade_model, Role, TradeModelStore};
+use prost::Message as _;
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::mem;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::codegen::Bytes;
+use tonic::metadata::MetadataValue;
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::descriptor;
+
+/// The length of the prefix of each gRPC message: a compression flag, then the message length.
+const GRPC_PREFIX_LEN: usize = 5;
+/// The longest request message read to find the trade it is for, being the most tonic will decode
+/// by default. A longer one is left to tonic to turn away.
+const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+const EXPECTED_NEXT_RPC_KEY: &str = "expected-next-rpc";
+
+/// Just the trade ID of a protocol step request, which every one has as its first field.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TradeIdOnly {
+    #[prost(string, tag = "1")]
+    pub trade_id: String,
+}
+
+/// A layer turning away calls to the protocol steps of a trade out of sequence, by the trade's phase
+/// & audit log in the given store.
+pub struct StepOrderLayer<S>(Arc<S>);
+
+impl<S> StepOrderLayer<S> {
+    pub const fn new(store: Arc<S>) -> Self {
+        Self(store)
+    }
+}
+
+impl<S> Clone for StepOrderLayer<S> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<Svc, S> Layer<Svc> for StepOrderLayer<S> {
+    type Service = StepOrder<Svc, S>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        StepOrder { inner, store: Arc::clone(&self.0) }
+    }
+}
+
+pub struct StepOrder<Svc, S> {
+    inner: Svc,
+    store: Arc<S>,
+}
+
+impl<Svc: Clone, S> Clone for StepOrder<Svc, S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), store: Arc::clone(&self.store) }
+    }
+}
+
+impl<Svc, S> Service<Request<BoxBody>> for StepOrder<Svc, S>
+    where Svc: Service<Request<BoxBody>, Response=Response<BoxBody>> + Clone + Send + 'static,
+          Svc::Future: Send,
+          S: TradeModelStore + Send + Sync + 'static
+{
+    type Response = Response<BoxBody>;
+    type Error = Svc::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        let rpc = req.uri().path().rsplit('/').next().unwrap_or_default().to_owned();
+        if !is_ordered_step(&rpc) {
+            return Box::pin(self.inner.call(req));
+        }
+        // Call the service made ready by 'poll_ready', rather than a fresh clone of it:
+        let clone = self.inner.clone();
+        let mut inner = mem::replace(&mut self.inner, clone);
+        let store = Arc::clone(&self.store);
+        Box::pin(async move {
+            let (parts, mut body) = req.into_parts();
+            let (frames, message) = match read_first_message(&mut body).await {
+                Ok(read) => read,
+                Err(status) => return Ok(status.into_http()),
+            };
+            if let Some(trade_id) = message.and_then(|message| TradeIdOnly::decode(&message[..]).ok()) {
+                let check = tokio::task::spawn_blocking(move || check_step_order(&*store, &trade_id.trade_id, &rpc));
+                if let Ok(Err(status)) = check.await {
+                    return Ok(status.into_http());
+                }
+            }
+            inner.call(Request::from_parts(parts, replay(frames, body))).await
+        })
+    }
+}
+
+/// Whether the RPC runs a protocol step which must wait for earlier ones, of either role.
+fn is_ordered_step(rpc: &str) -> bool {
+    [Role::SellerAsMaker, Role::BuyerAsMaker].into_iter()
+        .any(|role| descriptor::steps(role).iter().skip(1).any(|step| step.rpc == rpc))
+}
+
+/// Check that the call to the given RPC is in sequence for the trade, if it exists at all. Whether
+/// it does, and whatever else the step requires, is left to the step itself.
+fn check_step_order(store: &impl TradeModelStore, trade_id: &str, rpc: &str) -> Result<(), Status> {
+    let Some(trade_model) = store.get_trade_model(trade_id) else { return Ok(()) };
+    let (role, phase) = {
+        let trade_model = lock_trade_model(&trade_model);
+        (trade_model.my_role(), trade_model.phase())
+    };
+    let Ok(audit_log) = store.get_audit_log(trade_id) else { return Ok(()) };
+    let Some(expected) = descriptor::expected_before(role, rpc, phase, &audit_log) else { return Ok(()) };
+    let mut status = Status::failed_precondition(format!(
+        "{} called out of sequence for trade with id {}: expected {} next", rpc, trade_id, expected.rpc));
+    status.metadata_mut().insert(EXPECTED_NEXT_RPC_KEY, MetadataValue::from_static(expected.rpc));
+    Err(status)
+}
+
+/// Read the frames of the request body up to the end of its first message, returning them and the
+/// message, unless it is compressed (or too long, or the body ends first).
+pub async fn read_first_message(body: &mut BoxBody) -> Result<(VecDeque<Frame<Bytes>>, Option<Vec<u8>>), Status> {
+    let mut frames = VecDeque::new();
+    let mut data = Vec::new();
+    loop {
+        if let Some(prefix) = data.first_chunk::<GRPC_PREFIX_LEN>() {
+            let len = u32::from_be_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize;
+            if prefix[0] != 0 || len > MAX_MESSAGE_LEN {
+                return Ok((frames, None));
+            }
+            if data.len() >= GRPC_PREFIX_LEN + len {
+                data.truncate(GRPC_PREFIX_LEN + len);
+                data.drain(..GRPC_PREFIX_LEN);
+                return Ok((frames, Some(data)));
+            }
+        }
+        match future::poll_fn(|cx| Pin::new(&mut *body).poll_frame(cx)).await {
+            Some(Ok(frame)) => {
+                if let Some(chunk) = frame.data_ref() {
+                    data.extend_from_slice(chunk);
+                }
+                frames.push_back(frame);
+            }
+            Some(Err(status)) => return Err(status),
+            None => return Ok((frames, None)),
+        }
+    }
+}
+
+/// The request body with the given frames already read from it put back in front.
+pub fn replay(frames: VecDeque<Frame<Bytes>>, rest: BoxBody) -> BoxBody {
+    tonic::body::boxed(Replayed { frames, rest })
+}
+
+/// A request body with the frames already read from it put back in front.
+struct Replayed {
+    frames: VecDeque<Frame<Bytes>>,
+    rest: BoxBody,
+}
+
+impl Body for Replayed {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.frames.pop_front() {
+            Some(frame) => Poll::Ready(Some(Ok(frame))),
+            None => Pin::new(&mut self.rest).poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.frames.is_empty() && self.rest.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered = self.frames.iter().filter_map(Frame::data_ref).map(|data| data.len() as u64).sum::<u64>();
+        let rest = self.rest.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + buffered);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + buffered);
+        }
+        hint
+    }
+}
@@ -0,0 +1,56 @@
+//! The accounting of the streaming subscriptions open at once, per trade (or per client, for the
+//! demo clock), so that a client stuck in a reconnect loop cannot pile up watchers without end. Each
+//! subscription holds a [`Subscription`] for as long as its stream lives, which gives its place back
+//! once the stream is dropped, as it is when the client cancels the call or its connection goes.
+
+use std::collections::HashMap;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex, PoisonError};
+use tonic::Status;
+
+/// The subscriptions open at once, by key, at most the given number per key (if limited).
+#[derive(Debug)]
+pub struct SubscriptionRegistry {
+    max_per_key: Option<usize>,
+    active: Mutex<HashMap<String, usize>>,
+}
+
+impl SubscriptionRegistry {
+    pub fn new(max_per_key: Option<usize>) -> Self {
+        Self { max_per_key, active: Mutex::default() }
+    }
+
+    /// Open a subscription under the given key, to be held by its stream.
+    ///
+    /// # Errors
+    /// `RESOURCE_EXHAUSTED` if the key already has as many subscriptions open as allowed.
+    pub fn subscribe(self: &Arc<Self>, key: &str) -> Result<Subscription, Status> {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(max) = self.max_per_key.filter(|&max| active.get(key).copied().unwrap_or_default() >= max) {
+            return Err(Status::resource_exhausted(format!("too many open subscriptions, limit is {}", max)));
+        }
+        *active.entry(key.to_owned()).or_default() += 1;
+        drop(active);
+        Ok(Subscription { registry: Arc::clone(self), key: key.to_owned() })
+    }
+}
+
+/// An open subscription, closed once dropped, with the key left out of the registry once it has
+/// none open.
+#[derive(Debug)]
+pub struct Subscription {
+    registry: Arc<SubscriptionRegistry>,
+    key: String,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut active = self.registry.active.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! The JSON form of the protocol crate's test vectors, as written by `server export-test-vectors
+//! <file>`, for the Java implementation (or the BIP 327 reference code) to check itself against.
+//! Every key, nonce & signature is in hex, in its BIP 327 (or BIP 340) serialization: points are
+//! compressed, with the point at infinity as 33 zero bytes, and secret nonces are `k1 || k2`.
+
+use musig_trade_protocol::test_vectors::{InputVector, KeyVector, NonceVector, OutputVector, TestVectors};
+use musig_trade_protocol::PROTOCOL_VERSION;
+use std::fmt::Write as _;
+use std::prelude::rust_2021::*;
+
+use crate::json::Json;
+
+fn hex(bytes: &[u8]) -> Json {
+    Json::String(bytes.iter().fold(String::new(), |mut hex, b| {
+        write!(hex, "{:02x}", b).unwrap();
+        hex
+    }))
+}
+
+fn object<const N: usize>(entries: [(&str, Json); N]) -> Json {
+    Json::Object(entries.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+}
+
+fn key_json(key: &KeyVector) -> Json {
+    object([
+        ("prv_key", hex(&key.prv_key.serialize())),
+        ("pub_key", hex(&key.pub_key.serialize())),
+    ])
+}
+
+fn nonce_json(nonce: &NonceVector) -> Json {
+    object([
+        ("sec_nonce", hex(&nonce.sec_nonce.serialize())),
+        ("pub_nonce", hex(&nonce.pub_nonce.serialize())),
+    ])
+}
+
+fn output_json(output: &OutputVector) -> Json {
+    object([
+        ("name", Json::String(output.name.to_owned())),
+        ("buyer_key_share", key_json(&output.buyer_key_share)),
+        ("seller_key_share", key_json(&output.seller_key_share)),
+        ("key_agg_order", Json::Array(output.key_agg_order.iter().map(|key| hex(&key.serialize())).collect())),
+        ("aggregated_key", key_json(&output.aggregated_key)),
+    ])
+}
+
+fn input_json(input: &InputVector) -> Json {
+    object([
+        ("name", Json::String(input.name.to_owned())),
+        ("output", Json::String(input.output.to_owned())),
+        ("message", hex(&input.message)),
+        ("adaptor_point", hex(&input.adaptor_point.serialize())),
+        ("buyer_nonce", nonce_json(&input.buyer_nonce)),
+        ("seller_nonce", nonce_json(&input.seller_nonce)),
+        ("aggregated_nonce", hex(&input.aggregated_nonce.serialize())),
+        ("buyer_partial_signature", hex(&input.buyer_partial_signature.serialize())),
+        ("seller_partial_signature", hex(&input.seller_partial_signature.serialize())),
+        ("adaptor_signature", hex(&input.adaptor_signature.serialize())),
+        ("signature", hex(&input.signature.serialize())),
+    ])
+}
+
+pub fn to_json(vectors: &TestVectors) -> Json {
+    object([
+        ("protocol_version", Json::Number(PROTOCOL_VERSION.to_string())),
+        ("trade_id", Json::String(vectors.trade_id.clone())),
+        ("buyer_role", Json::String(format!("{:?}", vectors.buyer_role))),
+        ("seller_role", Json::String(format!("{:?}", vectors.seller_role))),
+        ("outputs", Json::Array(vectors.outputs.iter().map(output_json).collect())),
+        ("inputs", Json::Array(vectors.inputs.iter().map(input_json).collect())),
+    ])
+}
@@ -0,0 +1,2950 @@
+//! Integration tests of the `MuSig` service, mounted on an in-memory duplex transport (so with no
+//! sockets) and called through a tonic client, just as by a front-end over the network.
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use chacha20poly1305::aead::{Aead as _, KeyInit as _, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use futures::{future, stream, Stream, StreamExt as _};
+use hyper_util::rt::TokioIo;
+use musig_proto::helloworld::{self, ArchiveTradeRequest, CloseTradeRequest, GetTradeAuditLogRequest, GetTradeStateRequest, HeightTriggerKind,
+    HeightTriggersRequest, ListTradesRequest, NonceSharesRequest, PartialSignaturesRequest, PsbtChunk, PubKeySharesRequest,
+    SetTradePolicyRequest, SignedDepositPsbtChunk, TxConfirmationEventKind, UnsignedDepositPsbtRequest};
+use musig_proto::admin::{AbortTradeRequest, ExportSnapshotRequest, GetStatsRequest, RefreshReceiverRegistryRequest,
+    MockTxSchedule, ReloadConfigRequest, ScriptMockChainRequest};
+use musig_proto::admin::script_mock_chain_request::Action;
+use musig_proto::admin::admin_client::AdminClient;
+use musig_proto::convert::{self, decode_half_deposit_psbt};
+use musig_proto::health::health_check_response::ServingStatus;
+use musig_proto::health::health_server::Health;
+use musig_proto::health::HealthCheckRequest;
+use musig_proto::helloworld::mu_sig_client::MuSigClient;
+use musig_proto::helloworld::mu_sig_server::MuSigServer;
+use musig_proto::FILE_DESCRIPTOR_SET;
+use musig_trade_client::{AcceptFeeRateChange, AcceptSwapTxFeeBump, CancelBeforeDeposit, ClientError, CloseTrade, GetNonceShares, GetPartialSignatures, InitTrade, KeyShares,
+    NonceShares, PartialSignatures, ProposeFeeRateChange, ProposeSwapTxFeeBump, PrvKeyShareForPeer, PublishDepositTx, ResetSigningSession, RetryPolicy, RevealNonceShares, SignDepositTx, SignSwapTx, TradeClient};
+use musig_trade_protocol::{funding_input_ownership_message, lock_trade_model, ChangePolicy, CoinControl, Deadline, DeadlineDue, DeadlineKind, DeadlineState, FeeStrategy, FundingInput,
+    Intent, LocalSigner, PolicyAction, PolicyActionKind,
+    PolicyOverrides, ProtocolErrorKind, redirect_receivers_message, Role, SecretCipher as _, PROTOCOL_VERSION, TradeModel, TradeModelMemoryStore, TradeModelStore};
+use musig2::{CompactSignature, LiftedSignature, SecNonce};
+use prost::Message as _;
+use secp::{Point, Scalar};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::iter;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::time;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request as HttpRequest, Response as HttpResponse, Uri};
+use tonic::transport::{CertificateDer, Channel, Endpoint, Server};
+use tonic::Code;
+use tower_layer::Layer as _;
+use tower_service::Service;
+
+use crate::admin::{AdminServer, MyAdmin};
+use crate::backup::{self, KeyShareBackup};
+use crate::burningman::{self, ReceiverRegistry, RegistryError};
+use crate::chain::{self, ChainBackendStatus, ChainTip, TxBroadcaster, TxStatus, SIMULATED_TIP_HEIGHT};
+use crate::cipher::{self, MasterSecret, StoreCipher};
+use crate::client_identity::ClientIdentity;
+use crate::config::{BackupConfig, BurningmanConfig, ChainConfig, Command, Config, ConfigError, DeadlineConfig, DecodeLimitConfig, FaultConfig, GrpcWebConfig, NonceReuseConfig, PolicyConfig,
+    RateLimitConfig, RpcTimeoutConfig, SecretKeySource, TradeLimitConfig, TradeQuotaConfig, WebhookConfig};
+use crate::correlation::{CorrelationLayer, CORRELATION_ID_KEY};
+use crate::decode_limits::DecodeLimitLayer;
+use crate::deadlines;
+use crate::events::{TradeEvent, TradeEventBus};
+use crate::fault::FaultInjector;
+use crate::file_store::TradeModelFileStore;
+use crate::gateway;
+use crate::gc;
+use crate::grpc_web::GrpcWebLayer;
+use crate::health::{MyHealth, ReadinessChecks};
+use crate::json::{self, Json};
+use crate::listeners::{Listener, TokenAuth};
+use crate::logging;
+use crate::metrics::TradeDurations;
+use crate::mock_chain::MockChainBackend;
+use crate::noise;
+use crate::nonce_index::PeerNonceIndex;
+use crate::policy::PolicyEngine;
+use crate::quota::QuotaStore;
+use crate::rate_limit::{RateLimit, RateLimitLayer};
+use crate::snapshot;
+use crate::step_order::StepOrderLayer;
+use crate::test_vectors;
+use crate::timeout::TimeoutLayer;
+use crate::tor::Socks5Proxy;
+use crate::trade_id;
+use crate::transcode::Descriptors;
+use crate::webhook::{self, WebhookNotifier};
+use crate::MyMuSig;
+
+#[cfg(feature = "regtest-tests")]
+mod regtest;
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A connector handing out the client end of a duplex stream, once, in place of a TCP connection.
+struct DuplexConnector(Option<DuplexStream>);
+
+impl Service<Uri> for DuplexConnector {
+    type Response = TokioIo<DuplexStream>;
+    type Error = io::Error;
+    type Future = future::Ready<io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: Uri) -> Self::Future {
+        future::ready(self.0.take().map(TokioIo::new)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "duplex stream already taken")))
+    }
+}
+
+/// A connector to the unix socket at the given path, in place of a TCP connection.
+struct UnixConnector(PathBuf);
+
+impl Service<Uri> for UnixConnector {
+    type Response = TokioIo<UnixStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output=io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: Uri) -> Self::Future {
+        let path = self.0.clone();
+        Box::pin(async move { UnixStream::connect(path).await.map(TokioIo::new) })
+    }
+}
+
+/// Serve a daemon with an in-memory store on one end of a duplex stream, returning a channel to it
+/// over the other end.
+async fn spawn_daemon() -> Channel {
+    spawn_faulty_daemon(FaultConfig::default()).await
+}
+
+/// Serve a daemon as by [`spawn_daemon`], injecting the given faults into its payloads for the peer.
+async fn spawn_faulty_daemon(faults: FaultConfig) -> Channel {
+    serve(new_musig().with_faults(FaultInjector::new(faults))).await
+}
+
+fn new_musig() -> MyMuSig {
+    MyMuSig::new(Arc::new(TradeModelMemoryStore::default()), Arc::new(LocalSigner), None, Arc::default())
+}
+
+/// Serve the given service on one end of a duplex stream, returning a channel to it over the other.
+async fn serve<S: TradeModelStore + Send + Sync + 'static>(musig: MyMuSig<S>) -> Channel {
+    let (incoming, channel) = duplex();
+    tokio::spawn(Server::builder().add_service(MuSigServer::new(musig)).serve_with_incoming(incoming));
+    channel.await
+}
+
+/// Serve the given service as by [`serve`], with the given server-side RPC timeouts.
+async fn serve_with_rpc_timeouts(musig: MyMuSig, rpc_timeouts: RpcTimeoutConfig) -> Channel {
+    let (incoming, channel) = duplex();
+    tokio::spawn(Server::builder()
+        .layer(TimeoutLayer::new(rpc_timeouts))
+        .add_service(MuSigServer::new(musig))
+        .serve_with_incoming(incoming));
+    channel.await
+}
+
+/// Serve the given service as by [`serve`], turning away protocol steps called out of sequence.
+async fn serve_in_step_order(musig: MyMuSig) -> Channel {
+    let (incoming, channel) = duplex();
+    tokio::spawn(Server::builder()
+        .layer(StepOrderLayer::new(Arc::clone(&musig.trade_model_store)))
+        .add_service(MuSigServer::new(musig))
+        .serve_with_incoming(incoming));
+    channel.await
+}
+
+/// Serve the given service as by [`serve`], holding its requests to the given decoding limits.
+async fn serve_with_decode_limits(musig: MyMuSig, limits: DecodeLimitConfig) -> Channel {
+    let (incoming, channel) = duplex();
+    tokio::spawn(Server::builder()
+        .layer(DecodeLimitLayer::new(limits).unwrap())
+        .add_service(MuSigServer::new(musig).max_decoding_message_size(limits.max_message_len))
+        .serve_with_incoming(incoming));
+    channel.await
+}
+
+/// Serve the given service as by [`serve`], giving each call a correlation ID.
+async fn serve_with_correlation_ids(musig: MyMuSig) -> Channel {
+    let (incoming, channel) = duplex();
+    tokio::spawn(Server::builder()
+        .layer(CorrelationLayer)
+        .add_service(MuSigServer::new(musig))
+        .serve_with_incoming(incoming));
+    channel.await
+}
+
+/// The hex key (as printed by a key command) the snapshots exported by [`serve_admin`] are encrypted with.
+fn admin_snapshot_passphrase() -> SecretKeySource {
+    SecretKeySource::Command(format!("echo {}", "ab".repeat(32)))
+}
+
+/// Serve the admin service of the given service on one end of a duplex stream, only to callers
+/// bearing the given token (if any), returning a client of it over the other end.
+async fn serve_admin(musig: MyMuSig, token: Option<&str>) -> AdminClient<Channel> {
+    let (incoming, channel) = duplex();
+    let admin = MyAdmin::new(musig, admin_snapshot_passphrase());
+    tokio::spawn(Server::builder()
+        .add_service(AdminServer::with_interceptor(admin, TokenAuth::new(token)))
+        .serve_with_incoming(incoming));
+    AdminClient::new(channel.await)
+}
+
+/// The given admin request, bearing the given token.
+fn with_admin_token<T>(message: T, token: &str) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+    request
+}
+
+/// The incoming connections of a server, being one end of a duplex stream, and (once awaited) a
+/// channel to it over the other end.
+fn duplex() -> (impl Stream<Item=io::Result<DuplexStream>>, impl Future<Output=Channel>) {
+    let (client_io, server_io) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    let channel = async {
+        // The URL is only used for the request headers, as the connector ignores it:
+        Endpoint::from_static("http://musig.test")
+            .connect_with_connector(DuplexConnector(Some(client_io)))
+            .await
+            .unwrap()
+    };
+    (futures::stream::iter(iter::once(Ok(server_io))), channel)
+}
+
+async fn spawn_client() -> TradeClient {
+    TradeClient::new(spawn_daemon().await).with_retry_policy(RetryPolicy::never())
+}
+
+async fn spawn_faulty_client(faults: FaultConfig) -> TradeClient {
+    TradeClient::new(spawn_faulty_daemon(faults).await).with_retry_policy(RetryPolicy::never())
+}
+
+fn code<T>(result: Result<T, ClientError>) -> Code {
+    match result {
+        Err(ClientError::Status(status)) => status.code(),
+        Err(e) => panic!("expected a failed call, got: {}", e),
+        Ok(_) => panic!("expected a failed call"),
+    }
+}
+
+fn get_nonce_shares(trade_id: &str, peers_keys: &KeyShares) -> GetNonceShares {
+    GetNonceShares::new(trade_id)
+        .peers_key_shares(peers_keys)
+        .fee_rates(50.0, 40.0)
+        .amounts(200_000, 30_000, 30_000)
+}
+
+#[tokio::test]
+async fn trade_closes_cooperatively() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+    let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt))
+        .await.unwrap();
+    while confirmations.message().await.unwrap().is_some() {}
+
+    // The seller's key share for the buyer's output is withheld until it has received payment:
+    let started = buyer.confirm_payment_started("trade", None).await.unwrap();
+    assert_eq!(code(seller.confirm_payment_started("trade", None).await), Code::FailedPrecondition);
+    let result = seller.sign_swap_tx(SignSwapTx::new("trade").peers_partial_signatures(&buyer_sigs)).await;
+    assert_eq!(code(result), Code::FailedPrecondition);
+    let received = seller.confirm_payment_received("trade", None).await.unwrap();
+    assert_eq!(received.milestone(), helloworld::PaymentMilestone::PaymentReceived);
+    // A confirmation may be retried, getting the same receipt:
+    assert_eq!(buyer.confirm_payment_started("trade", Some(0)).await.unwrap(), started);
+    let state = buyer.get_trade_state("trade").await.unwrap();
+    assert_eq!(state.payment_receipts, [started]);
+
+    let swap_tx = seller.sign_swap_tx(SignSwapTx::new("trade").peers_partial_signatures(&buyer_sigs)).await.unwrap();
+    let PrvKeyShareForPeer::Plain(seller_key_share) = swap_tx.peer_output_prv_key_share else {
+        panic!("expected a plain private key share");
+    };
+    assert_eq!(seller_key_share.base_point_mul(), seller_keys.buyer_output_pub_key_share);
+    let buyer_key_share = buyer.close_trade(CloseTrade::new("trade")
+        .peers_prv_key_share(&swap_tx.peer_output_prv_key_share)).await.unwrap();
+    seller.close_trade(CloseTrade::new("trade").peers_prv_key_share(&buyer_key_share)).await.unwrap();
+
+    for client in [&buyer, &seller] {
+        let trades = client.list_trades(false).await.unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].phase(), helloworld::TradePhase::Closed);
+        client.archive_trade("trade", None).await.unwrap();
+        assert_eq!(client.list_trades(true).await.unwrap().len(), 1);
+    }
+}
+
+/// A tx broadcaster checking, on every broadcast, that no trade model of the daemon is locked, so
+/// that the trade actors are free to run other steps of the trades in the meantime.
+struct UnlockedTradesBroadcaster {
+    store: Arc<TradeModelMemoryStore>,
+    fail: AtomicBool,
+    txs: Mutex<Vec<Vec<u8>>>,
+}
+
+impl UnlockedTradesBroadcaster {
+    fn txs(&self) -> Vec<Vec<u8>> {
+        self.txs.lock().unwrap().clone()
+    }
+}
+
+#[tonic::async_trait]
+impl TxBroadcaster for UnlockedTradesBroadcaster {
+    async fn broadcast(&self, tx: &[u8]) -> io::Result<()> {
+        for summary in self.store.list_trade_models() {
+            let trade_model = self.store.get_trade_model(&summary.trade_id).unwrap();
+            assert!(trade_model.try_lock().is_ok(), "trade with id {} locked during a broadcast", summary.trade_id);
+        }
+        time::sleep(Duration::from_millis(10)).await;
+        if self.fail.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "chain backend down"));
+        }
+        self.txs.lock().unwrap().push(tx.to_vec());
+        Ok(())
+    }
+}
+
+/// Spawn a client of a daemon broadcasting its txs with an [`UnlockedTradesBroadcaster`].
+async fn spawn_broadcasting_client() -> (TradeClient, Arc<UnlockedTradesBroadcaster>) {
+    let musig = new_musig();
+    let broadcaster = Arc::new(UnlockedTradesBroadcaster {
+        store: Arc::clone(&musig.trade_model_store), fail: AtomicBool::new(false), txs: Mutex::default(),
+    });
+    let channel = serve(musig.with_tx_broadcaster(Arc::clone(&broadcaster) as Arc<dyn TxBroadcaster>)).await;
+    (TradeClient::new(channel).with_retry_policy(RetryPolicy::never()), broadcaster)
+}
+
+#[tokio::test]
+async fn txs_are_broadcast_with_no_trade_locked() {
+    let ((buyer, buyer_broadcaster), (seller, seller_broadcaster)) =
+        (spawn_broadcasting_client().await, spawn_broadcasting_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+
+    // The deposit tx is only recorded as published once broadcast:
+    let step = PublishDepositTx::new("trade").deposit_psbt(deposit_psbt);
+    buyer_broadcaster.fail.store(true, Ordering::Relaxed);
+    assert_eq!(code(buyer.publish_deposit_tx(step.clone()).await), Code::Unavailable);
+    assert_eq!(buyer.list_trades(false).await.unwrap()[0].phase(), helloworld::TradePhase::DepositTxSigned);
+    buyer_broadcaster.fail.store(false, Ordering::Relaxed);
+    let mut confirmations = buyer.publish_deposit_tx(step).await.unwrap();
+    while confirmations.message().await.unwrap().is_some() {}
+    assert_eq!(buyer_broadcaster.txs(), [b"signed_deposit_tx".to_vec()]);
+
+    buyer.confirm_payment_started("trade", None).await.unwrap();
+    seller.confirm_payment_received("trade", None).await.unwrap();
+    let swap_tx = seller.sign_swap_tx(SignSwapTx::new("trade").peers_partial_signatures(&buyer_sigs)).await.unwrap().swap_tx;
+    // Only the seller may force-close the trade, by publishing the swap tx:
+    assert_eq!(code(buyer.close_trade(CloseTrade::new("trade")).await), Code::FailedPrecondition);
+    seller.close_trade(CloseTrade::new("trade")).await.unwrap();
+    assert_eq!(seller_broadcaster.txs(), [swap_tx.serialize().to_vec()]);
+    buyer.close_trade(CloseTrade::new("trade").swap_tx(swap_tx)).await.unwrap();
+    assert_eq!(buyer_broadcaster.txs().len(), 1);
+    drop((buyer, seller));
+}
+
+#[tokio::test]
+async fn height_triggers_are_sent_once_the_chain_tip_reaches_them() {
+    let chain_tip = ChainTip::fixed(100);
+    let channel = serve(new_musig().with_chain_tip(chain_tip.clone())).await;
+    let mut client = MuSigClient::new(channel.clone());
+    let buyer = TradeClient::new(channel).with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    assert_eq!(buyer_keys.current_block_height, 100);
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+
+    let request = |trade_id: &str| HeightTriggersRequest { trade_id: trade_id.into(), deposit_confirmations: Some(3) };
+    let missing = client.subscribe_height_triggers(request("missing")).await;
+    assert_eq!(missing.err().map(|status| status.code()), Some(Code::NotFound));
+    let mut triggers = client.subscribe_height_triggers(request("trade")).await.unwrap().into_inner();
+    drop(client);
+
+    let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt))
+        .await.unwrap();
+    drop((buyer, seller));
+    assert_eq!(confirmations.message().await.unwrap().unwrap().current_block_height, 100);
+    // The deposit tx is taken to be mined in the block at the tip, so has its third confirmation at 102:
+    chain_tip.observe(101);
+    chain_tip.observe(102);
+    chain_tip.observe(103);
+    let trigger = triggers.message().await.unwrap().unwrap();
+    assert_eq!((&trigger.trade_id[..], trigger.kind(), trigger.height), ("trade", HeightTriggerKind::DepositConfirmed, 102));
+    assert!(trigger.current_block_height >= 102);
+}
+
+#[tokio::test]
+async fn subscriptions_are_capped_per_trade_and_freed_once_their_streams_are_dropped() {
+    let mut client = MuSigClient::new(serve(new_musig().with_max_subscriptions_per_trade(Some(2))).await);
+    let request = |trade_id: &str| HeightTriggersRequest { trade_id: trade_id.into(), deposit_confirmations: None };
+    // A subscription failing up front (for a missing trade) gives its place back at once:
+    for _ in 0..3 {
+        let missing = client.subscribe_height_triggers(request("missing")).await;
+        assert_eq!(missing.err().map(|status| status.code()), Some(Code::NotFound));
+    }
+    let first = client.subscribe_height_triggers(request("")).await.unwrap().into_inner();
+    let second = client.subscribe_height_triggers(request("")).await.unwrap().into_inner();
+    let third = client.subscribe_height_triggers(request("")).await;
+    assert_eq!(third.err().map(|status| status.code()), Some(Code::ResourceExhausted));
+
+    // The server drops the stream (and with it the subscription) once the client has cancelled it:
+    drop(first);
+    let third = time::timeout(Duration::from_secs(5), async {
+        loop {
+            match client.subscribe_height_triggers(request("")).await {
+                Ok(stream) => break stream.into_inner(),
+                Err(status) => assert_eq!(status.code(), Code::ResourceExhausted),
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("subscription was never freed");
+    drop((client, second, third));
+}
+
+/// The kind, block height & confirmations of the next message of the given stream of confirmations,
+/// skipping any heartbeats if asked to.
+async fn next_confirmation_status(confirmations: &mut tonic::Streaming<helloworld::TxConfirmationStatus>, skip_heartbeats: bool)
+    -> (TxConfirmationEventKind, u32, u32)
+{
+    loop {
+        let status = confirmations.message().await.unwrap().unwrap();
+        if !(skip_heartbeats && status.kind() == TxConfirmationEventKind::TxHeartbeat) {
+            return (status.kind(), status.current_block_height, status.num_confirmations);
+        }
+    }
+}
+
+#[tokio::test]
+async fn deposit_tx_confirmations_are_streamed_with_heartbeats_until_final() {
+    let chain_tip = ChainTip::fixed(100);
+    let musig = new_musig().with_chain_tip(chain_tip.clone()).with_tx_heartbeat_interval(Duration::from_millis(50));
+    let buyer = TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+    let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt)
+        .confirmations(3)).await.unwrap();
+    drop((buyer, seller));
+
+    // The deposit tx is taken to be mined at once, in the block at the tip, after which the stream
+    // keeps beating until the tip moves:
+    assert_eq!(next_confirmation_status(&mut confirmations, false).await, (TxConfirmationEventKind::TxConfirmationsChanged, 100, 1));
+    assert_eq!(next_confirmation_status(&mut confirmations, false).await, (TxConfirmationEventKind::TxHeartbeat, 100, 1));
+    chain_tip.observe(101);
+    assert_eq!(next_confirmation_status(&mut confirmations, true).await, (TxConfirmationEventKind::TxConfirmationsChanged, 101, 2));
+    chain_tip.observe(102);
+    assert_eq!(next_confirmation_status(&mut confirmations, true).await, (TxConfirmationEventKind::TxConfirmed, 102, 3));
+    assert!(confirmations.message().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn dry_run_trade_is_played_through_with_test_keys_and_instant_confirmations() {
+    let ((buyer, buyer_broadcaster), (seller, seller_broadcaster)) =
+        (spawn_broadcasting_client().await, spawn_broadcasting_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker).dry_run()).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker).dry_run()).await.unwrap();
+
+    // The same trade ID & role give the same test keys on any daemon, but never those of a live trade:
+    let other_daemon = spawn_client().await;
+    let keys = other_daemon.init_trade(InitTrade::new("trade", Role::BuyerAsTaker).dry_run()).await.unwrap();
+    assert_eq!(keys.buyer_output_pub_key_share, buyer_keys.buyer_output_pub_key_share);
+    assert_eq!(keys.identity_pub_key, buyer_keys.identity_pub_key);
+    let keys = other_daemon.init_trade(InitTrade::new("live-trade", Role::BuyerAsTaker)).await.unwrap();
+    assert_ne!(keys.buyer_output_pub_key_share, buyer_keys.buyer_output_pub_key_share);
+
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+
+    // The deposit tx isn't broadcast, but has every confirmation asked for at once:
+    let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt)
+        .confirmations(3)).await.unwrap();
+    assert_eq!(next_confirmation_status(&mut confirmations, false).await,
+        (TxConfirmationEventKind::TxConfirmed, SIMULATED_TIP_HEIGHT + 2, 3));
+    assert!(confirmations.message().await.unwrap().is_none());
+
+    buyer.confirm_payment_started("trade", None).await.unwrap();
+    seller.confirm_payment_received("trade", None).await.unwrap();
+    let swap_tx = seller.sign_swap_tx(SignSwapTx::new("trade").peers_partial_signatures(&buyer_sigs)).await.unwrap().swap_tx;
+    seller.close_trade(CloseTrade::new("trade")).await.unwrap();
+    buyer.close_trade(CloseTrade::new("trade").swap_tx(swap_tx)).await.unwrap();
+    assert!(buyer_broadcaster.txs().is_empty());
+    assert!(seller_broadcaster.txs().is_empty());
+    drop((buyer, seller, other_daemon));
+}
+
+/// Script the mock chain of the daemon served by the given admin client, returning its new height.
+async fn script_mock_chain(admin: &mut AdminClient<Channel>, action: Action) -> u32 {
+    admin.script_mock_chain(ScriptMockChainRequest { action: Some(action) }).await.unwrap().into_inner().height
+}
+
+#[tokio::test]
+async fn deposit_tx_is_followed_through_the_blocks_reorgs_and_double_spends_of_a_mock_chain() {
+    let mock_chain = Arc::new(MockChainBackend::new(100, 1));
+    let musig = new_musig().with_mock_chain(Arc::clone(&mock_chain));
+    let mut admin = serve_admin(musig.clone(), None).await;
+    let buyer = TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    assert_eq!(buyer_keys.current_block_height, 100);
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+    let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt)
+        .confirmations(3)).await.unwrap();
+    drop((buyer, seller));
+
+    // The deposit tx is mined in the block after the tip, and loses its confirmations in a reorg:
+    assert_eq!(next_confirmation_status(&mut confirmations, true).await, (TxConfirmationEventKind::TxConfirmationsChanged, 100, 0));
+    assert_eq!(script_mock_chain(&mut admin, Action::MineBlocks(2)).await, 102);
+    assert_eq!(next_confirmation_status(&mut confirmations, true).await, (TxConfirmationEventKind::TxConfirmationsChanged, 102, 2));
+    assert_eq!(script_mock_chain(&mut admin, Action::ReorgDepth(2)).await, 103);
+    assert_eq!(next_confirmation_status(&mut confirmations, true).await, (TxConfirmationEventKind::TxConfirmationsChanged, 103, 0));
+    assert_eq!(script_mock_chain(&mut admin, Action::MineBlocks(1)).await, 104);
+    assert_eq!(next_confirmation_status(&mut confirmations, true).await, (TxConfirmationEventKind::TxConfirmationsChanged, 104, 1));
+
+    // Once double-spent, it drops out of the chain for good, and can't be broadcast again:
+    let tx = b"signed_deposit_tx";
+    assert_eq!(script_mock_chain(&mut admin, Action::DoubleSpendTx(tx.to_vec())).await, 105);
+    assert_eq!(next_confirmation_status(&mut confirmations, true).await, (TxConfirmationEventKind::TxConfirmationsChanged, 105, 0));
+    assert_eq!(mock_chain.broadcast(tx).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(script_mock_chain(&mut admin, Action::MineBlocks(3)).await, 108);
+    assert_eq!(mock_chain.tx_status(tx), TxStatus::Unconfirmed);
+    drop(confirmations);
+
+    // A tx may be scheduled to be mined later than the default, even once broadcast:
+    mock_chain.broadcast(b"tx").await.unwrap();
+    let schedule = MockTxSchedule { tx: b"tx".to_vec(), blocks: 3 };
+    assert_eq!(script_mock_chain(&mut admin, Action::ScheduleConfirmation(schedule)).await, 108);
+    mock_chain.mine(2);
+    assert_eq!(mock_chain.tx_status(b"tx"), TxStatus::Unconfirmed);
+    mock_chain.mine(1);
+    assert_eq!(mock_chain.tx_status(b"tx"), TxStatus::Mined(111));
+
+    // A daemon following a real chain (or none) can't be scripted:
+    let result = serve_admin(new_musig(), None).await
+        .script_mock_chain(ScriptMockChainRequest { action: Some(Action::MineBlocks(1)) }).await;
+    assert_eq!(result.unwrap_err().code(), Code::FailedPrecondition);
+}
+
+#[tokio::test]
+async fn out_of_order_calls_are_rejected_without_changing_trade() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+
+    // Before the buyer has taken in the seller's key shares (and identity key):
+    let result = buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)).await;
+    assert_eq!(code(result), Code::Internal);
+    let mut inner = buyer.inner().clone();
+    let result = inner.get_unsigned_deposit_psbt(UnsignedDepositPsbtRequest { trade_id: "trade".to_owned() }).await;
+    assert_eq!(result.unwrap_err().code(), Code::FailedPrecondition);
+    let result = inner.archive_trade(ArchiveTradeRequest { trade_id: "trade".to_owned(), expected_revision: None }).await;
+    assert_eq!(result.unwrap_err().code(), Code::FailedPrecondition);
+    drop(inner);
+    assert_eq!(code(buyer.sign_swap_tx(SignSwapTx::new("trade")).await), Code::NotFound);
+    assert_eq!(code(buyer.close_trade(CloseTrade::new("missing")).await), Code::NotFound);
+    assert_eq!(buyer.list_trades(false).await.unwrap()[0].revision, 0);
+
+    // The trade then carries on as normal:
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys).expected_revision(0)).await.unwrap();
+    buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)
+        .expected_revision(1)).await.unwrap();
+    let result = buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)
+        .expected_revision(1)).await;
+    assert_eq!(code(result), Code::Aborted);
+}
+
+#[tokio::test]
+async fn repeated_signing_is_answered_alike_unless_for_other_peer_nonces() {
+    let seller_musig = new_musig();
+    let seller_store = Arc::clone(&seller_musig.trade_model_store);
+    let seller = TradeClient::new(serve(seller_musig).await).with_retry_policy(RetryPolicy::never());
+    let buyer = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces))
+        .await.unwrap();
+
+    // A retry, as by a client which lost the response, gets the very same partial signatures:
+    let retried_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces))
+        .await.unwrap();
+    let sigs = |sigs: &PartialSignatures| (sigs.message.peers_warning_tx_buyer_input_partial_signature.clone(),
+        sigs.message.peers_redirect_tx_input_partial_signature.clone(), sigs.message.swap_tx_input.clone());
+    assert_eq!(sigs(&retried_sigs), sigs(&buyer_sigs));
+
+    // Fresh nonces (duly signed by the peer) can't be signed for, our secret nonces being spent:
+    lock_trade_model(&seller_store.get_trade_model("trade").unwrap()).init_my_nonce_shares().unwrap();
+    let fresh_nonces = NonceShares::try_from(seller.resume_trade("trade").await.unwrap().nonce_shares.unwrap()).unwrap();
+    drop(seller);
+    assert_ne!(fresh_nonces.message.swap_tx_input_nonce_share, seller_nonces.message.swap_tx_input_nonce_share);
+    let Err(ClientError::Status(status)) = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&fresh_nonces)).await else { panic!("expected a failed call") };
+    assert_eq!(status.code(), Code::Aborted);
+    assert!(status.message().contains("reset the signing session (ResetSigningSession)"), "{}", status.message());
+    let retried_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces))
+        .await.unwrap();
+    drop(buyer);
+    assert_eq!(sigs(&retried_sigs), sigs(&buyer_sigs));
+}
+
+#[tokio::test]
+async fn trades_outside_configured_limits_are_turned_away_before_committing_to_nonces() {
+    let limits = TradeLimitConfig {
+        min_trade_amount: Some(100_000),
+        max_trade_amount: Some(1_000_000),
+        min_security_deposit_pct: Some(15),
+        max_security_deposit_pct: Some(50),
+    };
+    let seller = TradeClient::new(serve(new_musig().with_trade_limits(limits)).await)
+        .with_retry_policy(RetryPolicy::never());
+    let buyer = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let message = |trade_amount, buyers_security_deposit, sellers_security_deposit| {
+        let request = get_nonce_shares("trade", &buyer_keys)
+            .amounts(trade_amount, buyers_security_deposit, sellers_security_deposit);
+        let seller = &seller;
+        async move {
+            match seller.get_nonce_shares(request).await {
+                Err(ClientError::Status(status)) if status.code() == Code::OutOfRange => status.message().to_owned(),
+                result => panic!("expected an out-of-range amount, got: {:?}", result.map(drop)),
+            }
+        }
+    };
+    assert_eq!(message(50_000, 10_000, 10_000).await,
+        "trade amount of 50000 sats is below the minimum of 100000 sats (trade_limit_min_amount_sats)");
+    assert_eq!(message(2_000_000, 300_000, 300_000).await,
+        "trade amount of 2000000 sats is above the maximum of 1000000 sats (trade_limit_max_amount_sats)");
+    assert_eq!(message(200_000, 29_999, 30_000).await, "buyer's security deposit of 29999 sats is below the minimum \
+        of 15% of the trade amount (trade_limit_min_deposit_pct)");
+    assert_eq!(message(200_000, 30_000, 100_001).await, "seller's security deposit of 100001 sats is above the \
+        maximum of 50% of the trade amount (trade_limit_max_deposit_pct)");
+    // The trade is as it was, so may go ahead within the limits:
+    seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    drop((buyer, seller));
+}
+
+#[tokio::test]
+async fn steps_called_out_of_sequence_are_turned_away_naming_the_step_expected_next() {
+    let buyer = TradeClient::new(serve_in_step_order(new_musig()).await).with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    drop(seller);
+
+    // Turned away ahead of the step itself, which would fail on the missing nonce shares:
+    let mut inner = buyer.inner().clone();
+    let status = inner.get_partial_signatures(PartialSignaturesRequest { trade_id: "trade".to_owned(), ..Default::default() })
+        .await.unwrap_err();
+    assert_eq!((status.code(), status.message()), (Code::FailedPrecondition,
+        "GetPartialSignatures called out of sequence for trade with id trade: expected GetNonceShares next"));
+    assert_eq!(status.metadata().get("expected-next-rpc").unwrap(), "GetNonceShares");
+    // A missing trade is left to the step:
+    let result = inner.get_unsigned_deposit_psbt(UnsignedDepositPsbtRequest { trade_id: "missing".to_owned() }).await;
+    assert_eq!(result.unwrap_err().code(), Code::NotFound);
+    drop(inner);
+
+    // Once the step expected is done, the next is let through:
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)).await.unwrap();
+    assert_eq!(buyer.list_trades(false).await.unwrap()[0].phase(), helloworld::TradePhase::PartialSignaturesGenerated);
+    drop(buyer);
+}
+
+#[tokio::test]
+async fn trade_poisoned_by_panic_is_failed_while_daemon_carries_on() {
+    let musig = new_musig();
+    let store = Arc::clone(&musig.trade_model_store);
+    let buyer = TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    drop(seller);
+
+    // A bug panicking mid-step, with the trade model locked:
+    let trade_model = store.get_trade_model("trade").unwrap();
+    std::thread::spawn(move || {
+        let _trade_model = trade_model.lock().unwrap();
+        panic!("bug in protocol step");
+    }).join().unwrap_err();
+
+    assert_eq!(buyer.list_trades(false).await.unwrap().len(), 1);
+    let state = buyer.get_trade_state("trade").await.unwrap();
+    assert_eq!(state.failure.as_deref(), Some("a step on the trade was interrupted by a panic"));
+    assert_eq!(code(buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await), Code::FailedPrecondition);
+    // Other trades are unaffected:
+    buyer.init_trade(InitTrade::new("other", Role::BuyerAsTaker)).await.unwrap();
+    assert!(buyer.get_trade_state("other").await.unwrap().failure.is_none());
+    drop(buyer);
+}
+
+#[tokio::test]
+async fn peers_must_take_complementary_roles() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    for (trade_id, peers_role) in [("two-takers", Role::SellerAsTaker), ("two-buyers", Role::BuyerAsMaker)] {
+        buyer.init_trade(InitTrade::new(trade_id, Role::BuyerAsTaker)).await.unwrap();
+        let peers_keys = seller.init_trade(InitTrade::new(trade_id, peers_role)).await.unwrap();
+        let Err(ClientError::Status(status)) = buyer.get_nonce_shares(get_nonce_shares(trade_id, &peers_keys)).await else {
+            panic!("expected a failed call");
+        };
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert!(status.message().starts_with("peer's role"), "{}", status.message());
+    }
+    // Nor may the peer's role be changed on the way, to pass for the counterpart of ours:
+    buyer.init_trade(InitTrade::new("two-makers", Role::BuyerAsMaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("two-makers", Role::SellerAsMaker)).await.unwrap();
+    let relabelled_keys = KeyShares { role: Role::SellerAsTaker, ..seller_keys };
+    let Err(ClientError::Status(status)) = buyer.get_nonce_shares(get_nonce_shares("two-makers", &relabelled_keys)).await else {
+        panic!("expected a failed call");
+    };
+    assert_eq!(status.message(), "invalid peer signature on KeyShares payload");
+}
+
+#[test]
+fn payloads_are_sealed_as_noise_k_handshake_messages() {
+    let key = |hex: &str| -> [u8; 32] { unhex(hex).try_into().unwrap() };
+    // The Noise_K_25519_ChaChaPoly_SHA256 vector of the Cacophony test suite (its first message):
+    let prologue = unhex("4a6f686e2047616c74");
+    let (init_static, resp_static) = (key("e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1"),
+        key("4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893"));
+    let (init_static_pub, resp_static_pub) = (noise::static_pub_key(&init_static), noise::static_pub_key(&resp_static));
+    assert_eq!(init_static_pub, key("6bc3822a2aa7f4e6981d6538692b3cdf3e6df9eea6ed269eb41d93c22757b75a"));
+    assert_eq!(resp_static_pub, key("31e0303fd6418d2f8c0e78b91f22e8caed0fbe48656dcf4767e4834f701b8f62"));
+    let message = unhex("ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c794418467a8f8358c37e189cac4aa41dadaa\
+        6573febe24d52f366661eaa09018ab2c");
+    assert_eq!(noise::open(&prologue, &resp_static, &init_static_pub, &message).unwrap(), unhex("4c756477696720766f6e204d69736573"));
+
+    // Only the holder of the receiver's static key may open a payload, and only from the sender's,
+    // with the prologue it was sealed with:
+    let sealed = noise::seal(b"payload", &init_static, &resp_static_pub, b"secret").unwrap();
+    assert_eq!(noise::open(b"payload", &resp_static, &init_static_pub, &sealed).unwrap(), b"secret");
+    assert_eq!(noise::open(b"other payload", &resp_static, &init_static_pub, &sealed), None);
+    assert_eq!(noise::open(b"payload", &init_static, &init_static_pub, &sealed), None);
+    assert_eq!(noise::open(b"payload", &resp_static, &resp_static_pub, &sealed), None);
+    let mut tampered = sealed;
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(noise::open(b"payload", &resp_static, &init_static_pub, &tampered), None);
+}
+
+#[tokio::test]
+async fn sealed_trade_needs_peer_static_key_signed_with_its_key_shares() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("sealed", Role::BuyerAsTaker).seal_peer_payloads()).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("sealed", Role::SellerAsMaker).seal_peer_payloads()).await.unwrap();
+    assert_eq!((buyer_keys.noise_static_pub_key.len(), seller_keys.noise_static_pub_key.len()), (32, 32));
+
+    // The static key may neither be swapped for another, nor left out for the payloads to go unsealed:
+    let swapped_keys = KeyShares { noise_static_pub_key: buyer_keys.noise_static_pub_key.clone(), ..seller_keys.clone() };
+    let Err(ClientError::Status(status)) = buyer.get_nonce_shares(get_nonce_shares("sealed", &swapped_keys)).await else {
+        panic!("expected a failed call");
+    };
+    assert_eq!(status.message(), "invalid peer signature on KeyShares payload");
+    buyer.init_trade(InitTrade::new("half-sealed", Role::BuyerAsTaker).seal_peer_payloads()).await.unwrap();
+    let unsealed_keys = seller.init_trade(InitTrade::new("half-sealed", Role::SellerAsMaker)).await.unwrap();
+    assert!(unsealed_keys.noise_static_pub_key.is_empty());
+    let Err(ClientError::Status(status)) = buyer.get_nonce_shares(get_nonce_shares("half-sealed", &unsealed_keys)).await else {
+        panic!("expected a failed call");
+    };
+    assert_eq!(status.message(), "the trade seals its peer payloads, but the peer doesn't");
+
+    let nonces = buyer.get_nonce_shares(get_nonce_shares("sealed", &seller_keys)).await.unwrap();
+    assert!(nonces.nonce_shares.is_none() && nonces.message.sealed_payload.is_some());
+}
+
+/// The reason & metadata of the `ErrorInfo` in the details of the given failed call.
+fn error_info<T>(result: Result<T, ClientError>) -> (String, BTreeMap<String, String>) {
+    let Err(ClientError::Status(status)) = result else { panic!("expected a failed call") };
+    let info = convert::error_info(&status).unwrap();
+    assert_eq!(info.domain, "musig-trade-protocol");
+    (info.reason, info.metadata)
+}
+
+#[tokio::test]
+async fn failed_steps_give_their_trade_phase_and_input_in_error_details() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let result = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)
+        .funding_inputs(&funding_inputs("trade", &[10_000, 25_000]).into_iter().rev().chain(funding_inputs("other-trade", &[1]))
+            .collect::<Vec<_>>())).await;
+    let metadata = BTreeMap::from([("tradeId", "trade"), ("phase", "KeySharesGenerated"), ("input", "fundingInputs[2]")]
+        .map(|(key, value)| (key.to_owned(), value.to_owned())));
+    assert_eq!(error_info(result), ("INVALID_OWNERSHIP_PROOF".to_owned(), metadata));
+
+    buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let peers_keys = seller.init_trade(InitTrade::new("trade", Role::BuyerAsMaker)).await.unwrap();
+    let (reason, metadata) = error_info(buyer.get_nonce_shares(get_nonce_shares("trade", &peers_keys)).await);
+    assert_eq!(reason, "MISMATCHED_PEER_ROLE");
+    assert_eq!([&metadata["step"], &metadata["phase"], &metadata["input"]], ["GetNonceShares", "KeySharesGenerated", "peersRole"]);
+    let relabelled_keys = KeyShares { role: Role::SellerAsMaker, ..peers_keys };
+    let (reason, metadata) = error_info(buyer.get_nonce_shares(get_nonce_shares("trade", &relabelled_keys)).await);
+    assert_eq!((&reason[..], &metadata["input"][..]), ("INVALID_PEER_SIGNATURE", "peersPubKeySharesIdentitySignature"));
+
+    // A malformed request field is given as the input at fault, along with the field's own violation:
+    let mut inner = buyer.inner().clone();
+    drop(buyer);
+    let result = inner.get_nonce_shares(NonceSharesRequest {
+        trade_id: "trade".to_owned(),
+        peers_identity_pub_key: vec![2; 32],
+        peers_role: helloworld::Role::SellerAsMaker.into(),
+        ..Default::default()
+    }).await;
+    drop(inner);
+    let status = result.unwrap_err();
+    let info = convert::error_info(&status).unwrap();
+    assert_eq!((&info.reason[..], &info.metadata["input"][..]), ("INVALID_ARGUMENT", "peersIdentityPubKey"));
+    assert_eq!(info.metadata["tradeId"], "trade");
+    assert_eq!(convert::bad_request(&status).unwrap().field_violations[0].field, "peersIdentityPubKey");
+}
+
+#[tokio::test]
+async fn trade_opened_without_id_is_given_a_uuid_and_malformed_ids_are_rejected() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::with_generated_id(Role::BuyerAsTaker)).await.unwrap();
+    let other_keys = buyer.init_trade(InitTrade::with_generated_id(Role::BuyerAsTaker)).await.unwrap();
+    let trade_id = buyer_keys.trade_id.clone();
+    assert_eq!((trade_id.len(), &trade_id[14..15], &trade_id[8..9]), (36, "7", "-"), "{}", trade_id);
+    assert_ne!(trade_id, other_keys.trade_id);
+
+    // The trade goes on under the generated ID, which the peer is told of with the key shares:
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    buyer.get_nonce_shares(get_nonce_shares(&trade_id, &seller_keys)).await.unwrap();
+    seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+
+    for trade_id in ["../../etc/passwd", "trade\nGot a request: forged", ".trade", &"a".repeat(trade_id::MAX_TRADE_ID_LEN + 1)] {
+        assert_eq!(code(buyer.init_trade(InitTrade::new(trade_id, Role::BuyerAsTaker)).await), Code::InvalidArgument);
+    }
+    // Funding inputs are proven for the trade ID given by the client, so cannot go with a generated one:
+    let result = buyer.init_trade(InitTrade::with_generated_id(Role::BuyerAsTaker)
+        .funding_inputs(&funding_inputs("", &[10_000]))).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn trade_ids_already_live_or_archived_are_never_reused() {
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let musig = MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default());
+    let client = TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never());
+    let keys = client.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let my_pub_key_shares = |trade_model: Option<Arc<Mutex<TradeModel>>>| {
+        let trade_model = trade_model.unwrap();
+        let trade_model = lock_trade_model(&trade_model);
+        (trade_model.my_role(), trade_model.get_my_key_shares().unwrap().map(|k| k.pub_key))
+    };
+
+    // Opening the trade again doesn't replace it (or its key shares), nor count as another open trade:
+    let result = client.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await;
+    assert_eq!(code(result), Code::AlreadyExists);
+    let expected = (Role::BuyerAsTaker, [keys.buyer_output_pub_key_share, keys.seller_output_pub_key_share]);
+    assert_eq!(my_pub_key_shares(store.get_trade_model("trade")), expected);
+    assert_eq!(client.list_trades(false).await.unwrap().len(), 1);
+
+    // Nor may an archived trade be opened again:
+    store.add_archived_trade(TradeModel::new("archived".to_owned(), Role::SellerAsMaker).summarize(Some(SystemTime::now())))
+        .unwrap();
+    let result = client.init_trade(InitTrade::new("archived", Role::SellerAsMaker)).await;
+    assert_eq!(code(result), Code::AlreadyExists);
+    assert_eq!(client.list_trades(false).await.unwrap().len(), 1);
+    drop(client);
+
+    // Likewise in a file store, which leaves the file of the trade untouched:
+    let dir = std::env::temp_dir().join(format!("musig-trade-id-reuse-test-{}", std::process::id()));
+    let file_store = TradeModelFileStore::open(&dir, None).unwrap();
+    let trade_model = TradeModel::builder("trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares().unwrap().build();
+    let expected = (Role::BuyerAsTaker, trade_model.get_my_key_shares().unwrap().map(|k| k.pub_key));
+    file_store.add_trade_model(trade_model).unwrap();
+    let duplicate = TradeModel::builder("trade".to_owned(), Role::SellerAsMaker).with_my_key_shares().unwrap().build();
+    assert_eq!(file_store.add_trade_model(duplicate).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    drop(file_store);
+    let file_store = TradeModelFileStore::open(&dir, None).unwrap();
+    assert_eq!(my_pub_key_shares(file_store.get_trade_model("trade")), expected);
+    file_store.archive_trade_model("trade").unwrap();
+    let trade_model = TradeModel::new("trade".to_owned(), Role::BuyerAsTaker);
+    assert_eq!(file_store.add_trade_model(trade_model).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    drop(file_store);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Open the file store in the given dir, with the given master secret (if any), and serve a daemon
+/// on it, returning the store and a client of the daemon.
+async fn spawn_file_store_client(dir: &Path, master_secret: Option<&MasterSecret>)
+    -> (Arc<TradeModelFileStore>, TradeClient)
+{
+    let store = Arc::new(TradeModelFileStore::open(dir, master_secret).unwrap());
+    let musig = MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default());
+    (store, TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never()))
+}
+
+#[tokio::test]
+async fn trades_go_on_from_a_reopened_file_store_with_their_secrets_encrypted_at_rest() {
+    let dir = std::env::temp_dir().join(format!("musig-file-store-restart-test-{}", std::process::id()));
+    let master_secret = MasterSecret::Key([7; 32]);
+    let (store, buyer) = spawn_file_store_client(&dir, Some(&master_secret)).await;
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let prv_key_shares: Vec<_> = {
+        let trade_model = store.get_trade_model("trade").unwrap();
+        let trade_model = lock_trade_model(&trade_model);
+        trade_model.get_my_key_shares().unwrap().iter()
+            .map(|k| k.prv_key.as_ref().unwrap().expose_secret().serialize())
+            .collect()
+    };
+    drop((store, buyer));
+
+    // No secret key share is to be found on disk, and the store can't be opened without the secret:
+    for entry in fs::read_dir(&dir).unwrap() {
+        let contents = fs::read(entry.unwrap().path()).unwrap();
+        for prv_key_share in &prv_key_shares {
+            assert!(!contents.windows(prv_key_share.len()).any(|w| w == prv_key_share));
+        }
+    }
+    assert!(TradeModelFileStore::open(&dir, None).is_err());
+    assert!(TradeModelFileStore::open(&dir, Some(&MasterSecret::Key([8; 32]))).is_err());
+
+    // Restarted, the buyer's daemon goes on with the trade, with its (decrypted) key shares, and
+    // again with its secret nonces:
+    let (store, buyer) = spawn_file_store_client(&dir, Some(&master_secret)).await;
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    drop((store, buyer));
+    let (store, buyer) = spawn_file_store_client(&dir, Some(&master_secret)).await;
+    buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)).await.unwrap();
+    assert_eq!(buyer.list_trades(false).await.unwrap()[0].phase(), helloworld::TradePhase::PartialSignaturesGenerated);
+    drop((store, buyer, seller));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn trades_interrupted_while_signing_are_never_signed_with_their_nonces_again() {
+    let dir = std::env::temp_dir().join(format!("musig-intent-log-test-{}", std::process::id()));
+    let (store, buyer) = spawn_file_store_client(&dir, None).await;
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+
+    // The daemon stops after it logs its intent to sign, but before it logs the signing complete:
+    store.log_intent("trade", Intent::ConsumeNonces).unwrap();
+    drop((store, buyer));
+
+    // Restarted, it discards the secret nonces, which may have been used, so it can't sign again:
+    let (store, buyer) = spawn_file_store_client(&dir, None).await;
+    let trade_model = store.get_trade_model("trade").unwrap();
+    assert!(lock_trade_model(&trade_model).get_my_partial_signatures_on_peer_txs().is_none());
+    drop(trade_model);
+    let request = GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces);
+    let Err(ClientError::Status(status)) = buyer.get_partial_signatures(request).await else { panic!("expected a failed call") };
+    assert_eq!(status.code(), Code::Internal);
+    assert!(status.message().contains(&ProtocolErrorKind::NonceReuse.to_string()), "{}", status.message());
+    assert_eq!(buyer.list_trades(false).await.unwrap()[0].phase(), helloworld::TradePhase::NonceSharesGenerated);
+    drop((store, buyer, seller));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn trades_with_ids_of_the_most_allowed_length_are_written_out_and_archived() {
+    let dir = std::env::temp_dir().join(format!("musig-long-trade-id-test-{}", std::process::id()));
+    let store = Arc::new(TradeModelFileStore::open(&dir, None).unwrap());
+    let client = TradeClient::new(serve(MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default())).await)
+        .with_retry_policy(RetryPolicy::never());
+    let long_id = "a".repeat(trade_id::MAX_TRADE_ID_LEN);
+    client.init_trade(InitTrade::new(&long_id, Role::BuyerAsTaker)).await.unwrap();
+    assert!(!store.get_audit_log(&long_id).unwrap().is_empty());
+    drop(client);
+
+    store.archive_trade_model(&long_id).unwrap().unwrap();
+    drop(store);
+    let store = TradeModelFileStore::open(&dir, None).unwrap();
+    assert!(store.list_archived_trades().iter().any(|summary| summary.trade_id == long_id));
+    drop(store);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn redirect_tx_is_only_signed_for_receivers_signed_by_mediator() {
+    let mediator_key = Scalar::random(&mut rand::thread_rng());
+    let buyer = TradeClient::new(serve(new_musig().with_mediator(mediator_key.base_point_mul())).await)
+        .with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    drop(seller);
+    let message = redirect_receivers_message(buyer_keys.identity_pub_key, seller_keys.identity_pub_key,
+        &[("bc1qmediated", 230_000)]);
+    let signature: CompactSignature = musig2::sign_solo(mediator_key, message, rand::random::<[u8; 32]>());
+    let signature = signature.serialize();
+    let step = GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces);
+
+    // Neither unsigned receivers, nor receivers other than those the mediator signed, may be paid:
+    assert_eq!(code(buyer.get_partial_signatures(step.clone()).await), Code::InvalidArgument);
+    let result = buyer.get_partial_signatures(step.clone().receiver("bc1qpeer", 230_000)
+        .receivers_mediator_signature(signature)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    assert_eq!(buyer.list_trades(false).await.unwrap()[0].revision, 1);
+
+    buyer.get_partial_signatures(step.receiver("bc1qmediated", 230_000).receivers_mediator_signature(signature))
+        .await.unwrap();
+    drop(buyer);
+}
+
+/// Write the given receiver snapshot to the given file, signed with the given key.
+fn write_receiver_snapshot(path: &Path, key: Scalar, snapshot: &str) {
+    let signature: CompactSignature = musig2::sign_solo(key, burningman::snapshot_message(snapshot.as_bytes()),
+        rand::random::<[u8; 32]>());
+    let mut hex = String::new();
+    for b in signature.serialize() {
+        write!(hex, "{:02x}", b).unwrap();
+    }
+    fs::write(path, snapshot).unwrap();
+    fs::write(path.with_extension("json.sig"), hex).unwrap();
+}
+
+#[tokio::test]
+async fn redirect_tx_pays_burningman_receiver_set_in_force() {
+    let dir = std::env::temp_dir().join(format!("musig-burningman-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (path, key) = (dir.join("snapshot.json"), Scalar::random(&mut rand::thread_rng()));
+    write_receiver_snapshot(&path, key, r#"{"version": 1, "receiver_sets": [
+        {"activation_height": 200, "receivers": [{"address": "bc1qlater", "weight": 1}]},
+        {"activation_height": 50, "receivers": [{"address": "bc1qa", "weight": 3}, {"address": "bc1qb", "weight": 1}]}]}"#);
+    let config = BurningmanConfig { snapshot_file: Some(path.clone()), pub_key: Some(key.base_point_mul()) };
+    let registry = Arc::new(ReceiverRegistry::load(&config).unwrap().unwrap());
+    // A snapshot not signed by the configured key is refused:
+    let wrong_config = BurningmanConfig { pub_key: Some(Scalar::random(&mut rand::thread_rng()).base_point_mul()), ..config };
+    assert!(matches!(ReceiverRegistry::load(&wrong_config), Err(RegistryError::InvalidSignature)));
+
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let musig = MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default())
+        .with_chain_tip(ChainTip::fixed(100))
+        .with_receiver_registry(registry);
+    let mut admin = serve_admin(musig.clone(), None).await;
+    let channel = serve(musig).await;
+    let buyer = TradeClient::new(channel).with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    drop(seller);
+    let step = GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces);
+
+    // The peer's own receivers are refused, and the registry's receivers paid in their place:
+    let result = buyer.get_partial_signatures(step.clone().receiver("bc1qpeer", 260_000)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    buyer.get_partial_signatures(step).await.unwrap();
+    drop(buyer);
+    let trade_model = store.get_trade_model("trade").unwrap();
+    assert_eq!(trade_model.lock().unwrap().redirect_receivers,
+        [("bc1qa".to_owned(), 195_000), ("bc1qb".to_owned(), 65_000)]);
+
+    // A newer snapshot may be loaded, but not then an older one:
+    write_receiver_snapshot(&path, key, r#"{"version": 2, "receiver_sets": [
+        {"activation_height": 90, "receivers": [{"address": "bc1qc", "weight": 1}]}]}"#);
+    let info = admin.refresh_receiver_registry(RefreshReceiverRegistryRequest {}).await.unwrap().into_inner();
+    assert_eq!((info.version, &info.activation_heights[..], info.active_activation_height), (2, &[90][..], Some(90)));
+    write_receiver_snapshot(&path, key, r#"{"version": 1, "receiver_sets": []}"#);
+    let result = admin.refresh_receiver_registry(RefreshReceiverRegistryRequest {}).await;
+    assert_eq!(result.unwrap_err().code(), Code::FailedPrecondition);
+    drop(admin);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn failed_steps_are_posted_to_webhooks_signed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = WebhookConfig {
+        urls: vec![format!("http://{}/hook", listener.local_addr().unwrap())],
+        events: vec!["step_failed"],
+        ..WebhookConfig::default()
+    };
+    let notifier = Arc::new(WebhookNotifier::new(&config, b"webhook secret".to_vec()));
+    let events = TradeEventBus::default();
+    tokio::spawn(webhook::notify_webhooks(events.clone(), Arc::clone(&notifier)));
+    let channel = serve(new_musig().with_events(events.clone())).await;
+    let buyer = TradeClient::new(channel).with_retry_policy(RetryPolicy::never());
+    buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    // Not of a kind to notify of:
+    events.publish(TradeEvent::Closed { trade_id: "other trade".to_owned(), correlation_id: None });
+    let mut inner = buyer.inner().clone();
+    let result = inner.get_unsigned_deposit_psbt(UnsignedDepositPsbtRequest { trade_id: "trade".to_owned() }).await;
+    assert_eq!(result.unwrap_err().code(), Code::FailedPrecondition);
+    drop((inner, buyer));
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let (head, body) = loop {
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "truncated request");
+        request.extend_from_slice(&buf[..n]);
+        let request = String::from_utf8(request.clone()).unwrap();
+        if let Some((head, body)) = request.split_once("\r\n\r\n") {
+            let len: usize = head.lines().find_map(|line| line.strip_prefix("Content-Length: ")).unwrap().parse().unwrap();
+            if body.len() == len {
+                break (head.to_owned(), body.to_owned());
+            }
+        }
+    };
+    stream.write_all(b"HTTP/1.0 204 No Content\r\n\r\n").await.unwrap();
+    drop(stream);
+
+    assert!(head.starts_with("POST /hook HTTP/1.0\r\n"));
+    let signature = head.lines().find_map(|line| line.strip_prefix("X-MuSig-Signature: ")).unwrap();
+    assert_eq!(signature, notifier.sign(&body));
+    assert!(body.starts_with(r#"{"event":"step_failed","trade_id":"trade","at":"#), "{}", body);
+    assert!(body.contains(r#""step":"GetUnsignedDepositPsbt","code":"FailedPrecondition","message":""#), "{}", body);
+}
+
+#[tokio::test]
+async fn proxied_webhooks_are_only_posted_through_the_socks_proxy() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    let (proxy, connections) = spawn_socks_proxy(target).await;
+    // The host doesn't resolve, so the webhook can only be reached through the proxy:
+    let config = WebhookConfig {
+        urls: vec![format!("http://hooks.invalid:{}/hook", target.port())],
+        events: vec!["step_failed"],
+        ..WebhookConfig::default()
+    };
+    let notifier = WebhookNotifier::new(&config, b"webhook secret".to_vec()).with_proxy(Some(proxy));
+    let events = TradeEventBus::default();
+    tokio::spawn(webhook::notify_webhooks(events.clone(), Arc::new(notifier)));
+    // Let the notifier subscribe before publishing:
+    tokio::task::yield_now().await;
+    events.publish(TradeEvent::StepFailed {
+        trade_id: "trade".to_owned(),
+        step: "GetUnsignedDepositPsbt",
+        code: Code::FailedPrecondition,
+        message: String::new(),
+        correlation_id: None,
+    });
+
+    let (mut stream, _) = time::timeout(Duration::from_secs(5), listener.accept()).await.unwrap().unwrap();
+    let mut request = Vec::new();
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "truncated request");
+        request.extend_from_slice(&buf[..n]);
+    }
+    stream.write_all(b"HTTP/1.0 204 No Content\r\n\r\n").await.unwrap();
+    assert!(request.starts_with(b"POST /hook HTTP/1.0\r\n"));
+    assert_eq!(*connections.lock().unwrap(), [format!("hooks.invalid:{}", target.port())]);
+}
+
+#[tokio::test]
+async fn correlation_ids_are_echoed_and_kept_in_audit_log() {
+    let mut client = MuSigClient::new(serve_with_correlation_ids(new_musig()).await);
+    let mut request = tonic::Request::new(PubKeySharesRequest {
+        trade_id: "trade".to_owned(),
+        my_role: helloworld::Role::BuyerAsTaker.into(),
+        ..Default::default()
+    });
+    request.metadata_mut().insert(CORRELATION_ID_KEY, "java-side-42".parse().unwrap());
+    let response = client.init_trade(request).await.unwrap();
+    assert_eq!(response.metadata().get(CORRELATION_ID_KEY).unwrap(), "java-side-42");
+
+    // A call without a correlation ID (or with one unfit to log) is given a fresh one, even if it fails:
+    let mut request = tonic::Request::new(UnsignedDepositPsbtRequest { trade_id: "trade".to_owned() });
+    request.metadata_mut().insert(CORRELATION_ID_KEY, "has spaces".parse().unwrap());
+    let status = client.get_unsigned_deposit_psbt(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    let generated_id = status.metadata().get(CORRELATION_ID_KEY).unwrap().to_str().unwrap().to_owned();
+    assert!(trade_id::check_trade_id(&generated_id, "correlation ID").is_ok(), "{}", generated_id);
+
+    let audit_log = client.get_trade_audit_log(GetTradeAuditLogRequest { trade_id: "trade".to_owned() })
+        .await.unwrap().into_inner().entries;
+    drop(client);
+    let correlation_ids: Vec<_> = audit_log.iter().map(|entry| entry.correlation_id.as_deref()).collect();
+    assert_eq!(correlation_ids, [Some("java-side-42"), Some(&generated_id[..])]);
+}
+
+#[tokio::test]
+async fn malformed_byte_fields_are_rejected_by_name() {
+    let mut buyer = MuSigClient::new(spawn_daemon().await);
+    let assert_rejected = |result: Result<_, tonic::Status>, code, msg: &str| {
+        let status = result.unwrap_err();
+        assert_eq!((status.code(), status.message()), (code, msg));
+    };
+    let result = buyer.init_trade(PubKeySharesRequest { trade_id: "trade".to_owned(), my_role: 4, ..Default::default() }).await;
+    assert_rejected(result.map(drop), Code::OutOfRange, "could not decode my_role: unknown enum value: 4");
+
+    let keys = buyer.init_trade(PubKeySharesRequest {
+        trade_id: "trade".to_owned(),
+        my_role: helloworld::Role::BuyerAsTaker.into(),
+        ..Default::default()
+    }).await.unwrap().into_inner();
+    let result = buyer.get_nonce_shares(NonceSharesRequest {
+        trade_id: "trade".to_owned(),
+        peers_identity_pub_key: keys.identity_pub_key[..32].to_vec(),
+        ..Default::default()
+    }).await;
+    assert_rejected(result.map(drop), Code::InvalidArgument,
+        "could not decode peers_identity_pub_key: malformed point: expected a 33-byte compressed point, got 32 bytes");
+
+    // The key shares aren't ours to sign, so a tampered key share fails on its identity signature:
+    let mut buyer_output_peers_pub_key_share = keys.buyer_output_pub_key_share.clone();
+    buyer_output_peers_pub_key_share[1] ^= 1;
+    let result = buyer.get_nonce_shares(NonceSharesRequest {
+        trade_id: "trade".to_owned(),
+        buyer_output_peers_pub_key_share,
+        seller_output_peers_pub_key_share: keys.seller_output_pub_key_share,
+        peers_identity_pub_key: keys.identity_pub_key,
+        peers_pub_key_shares_identity_signature: keys.identity_signature,
+        ..Default::default()
+    }).await;
+    assert_eq!(result.unwrap_err().code(), Code::InvalidArgument);
+
+    let result = buyer.get_partial_signatures(PartialSignaturesRequest {
+        trade_id: "trade".to_owned(),
+        ..Default::default()
+    }).await;
+    assert_rejected(result.map(drop), Code::NotFound, "missing request.peers_nonce_shares");
+    let result = buyer.close_trade(CloseTradeRequest {
+        trade_id: "trade".to_owned(),
+        my_output_peers_prv_key_share: Some(vec![0xff; 32]),
+        ..Default::default()
+    }).await;
+    drop(buyer);
+    let status = result.unwrap_err();
+    assert_eq!((status.code(), status.message()), (Code::InvalidArgument, "could not decode my_output_peers_prv_key_share: \
+        malformed scalar: expected a 32-byte big-endian nonzero scalar below the curve order, got 32 bytes"));
+    let violation = &convert::bad_request(&status).unwrap().field_violations[0];
+    assert_eq!(violation.field, "myOutputPeersPrvKeyShare");
+}
+
+#[tokio::test]
+async fn requests_over_decoding_limits_are_turned_away_before_they_are_decoded() {
+    let limits = DecodeLimitConfig {
+        max_message_len: 256 * 1024,
+        max_psbt_len: 1024,
+        allocation_budget: 64 * 1024,
+        ..DecodeLimitConfig::default()
+    };
+    let mut buyer = MuSigClient::new(serve_with_decode_limits(new_musig(), limits).await);
+    let init_trade = |trade_id: &str, inputs: &[FundingInput]| PubKeySharesRequest {
+        trade_id: trade_id.to_owned(),
+        my_role: helloworld::Role::BuyerAsTaker.into(),
+        funding_inputs: inputs.iter().map(Into::into).collect(),
+        ..Default::default()
+    };
+    let keys = buyer.init_trade(init_trade("trade", &funding_inputs("trade", &[10_000, 25_000])))
+        .await.unwrap().into_inner();
+    assert!(keys.half_deposit_psbt.len() <= limits.max_psbt_len);
+
+    // A message with a great many empty submessages, each taking far more memory decoded than on the wire:
+    let request = PubKeySharesRequest { funding_inputs: vec![helloworld::FundingInput::default(); 10_000], ..init_trade("trade2", &[]) };
+    assert!(request.encoded_len() < limits.allocation_budget);
+    let status = buyer.init_trade(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert!(status.message().contains("bytes of memory once decoded"), "{}", status.message());
+
+    let request = NonceSharesRequest {
+        trade_id: "trade".to_owned(),
+        peers_half_deposit_psbt: vec![0; limits.max_psbt_len + 1],
+        ..Default::default()
+    };
+    let status = buyer.get_nonce_shares(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    assert!(status.message().contains("peersHalfDepositPsbt"), "{}", status.message());
+    let request = NonceSharesRequest {
+        trade_id: "trade".to_owned(),
+        peers_identity_pub_key: vec![2; limits.max_field_len + 1],
+        ..Default::default()
+    };
+    assert_eq!(buyer.get_nonce_shares(request).await.unwrap_err().code(), Code::ResourceExhausted);
+    let request = init_trade(&"a".repeat(limits.max_message_len), &[]);
+    assert_eq!(buyer.init_trade(request).await.unwrap_err().code(), Code::ResourceExhausted);
+
+    // None of which touched the trade:
+    let trades = buyer.list_trades(ListTradesRequest::default()).await.unwrap().into_inner().trades;
+    drop(buyer);
+    assert_eq!(trades.len(), 1);
+}
+
+/// The request for the nonce shares, as made by the client's [`GetNonceShares`] for the given peer's
+/// key shares, for making the call without the client checking the response.
+fn nonce_shares_request(trade_id: &str, peers_keys: &KeyShares) -> NonceSharesRequest {
+    NonceSharesRequest {
+        trade_id: trade_id.to_owned(),
+        buyer_output_peers_pub_key_share: peers_keys.buyer_output_pub_key_share.serialize().into(),
+        seller_output_peers_pub_key_share: peers_keys.seller_output_pub_key_share.serialize().into(),
+        peers_identity_pub_key: peers_keys.identity_pub_key.serialize().into(),
+        peers_pub_key_shares_identity_signature: peers_keys.identity_signature.serialize().into(),
+        peers_half_deposit_psbt: peers_keys.half_deposit_psbt.clone(),
+        peers_trade_id: peers_keys.trade_id.clone(),
+        peers_role: helloworld::Role::from(peers_keys.role).into(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn corrupt_nonce_shares_are_caught_by_client_and_peer() {
+    let faults = FaultConfig { corrupt_nonce_shares: true, ..FaultConfig::default() };
+    let (buyer, seller) = (spawn_faulty_client(faults).await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_nonces = buyer.inner().clone().get_nonce_shares(nonce_shares_request("trade", &seller_keys))
+        .await.unwrap().into_inner();
+    assert!(NonceShares::try_from(buyer_nonces.clone()).is_err());
+
+    // Were the buyer's client to relay the payload unchecked, the seller's daemon would reject it:
+    let result = seller.inner().clone().get_partial_signatures(PartialSignaturesRequest {
+        trade_id: "trade".to_owned(),
+        peers_nonce_shares: Some(buyer_nonces),
+        ..Default::default()
+    }).await;
+    let status = result.unwrap_err();
+    assert_eq!((status.code(), status.message()), (Code::InvalidArgument,
+        "could not decode peers_nonce_shares.swap_tx_input_nonce_share: \
+        malformed pub nonce: expected a 66-byte pair of compressed points, got 66 bytes"));
+}
+
+/// Assert that none of the given byte fields appears in the given log line, whether as a list of
+/// bytes (as shown by `{:?}`) or as hex.
+fn assert_redacted(log_line: &str, fields: &[&[u8]]) {
+    for &field in fields {
+        assert!(!field.is_empty());
+        let hex = field.iter().fold(String::new(), |mut hex, b| {
+            write!(hex, "{:02x}", b).unwrap();
+            hex
+        });
+        assert!(!log_line.contains(&format!("{:?}", field)) && !log_line.contains(&hex), "{:?} in: {}", field, log_line);
+    }
+    assert!(!log_line.contains("[0") && !log_line.contains("[1") && !log_line.contains("[2"), "{}", log_line);
+}
+
+#[tokio::test]
+async fn keys_nonces_and_signatures_are_redacted_from_logged_messages() {
+    assert!(!logging::set_log_sensitive(false));
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let init_trade = PubKeySharesRequest {
+        trade_id: "trade".to_owned(),
+        my_role: helloworld::Role::BuyerAsTaker.into(),
+        funding_inputs: funding_inputs("trade", &[10_000]).iter().map(Into::into).collect(),
+        ..Default::default()
+    };
+    let log_line = logging::debug_for_log(&tonic::Request::new(init_trade.clone()));
+    let input = &init_trade.funding_inputs[0];
+    assert_redacted(&log_line, &[&input.txid, &input.owner_pub_key, &input.ownership_proof]);
+    assert!(log_line.contains("ownership_proof: <64 bytes, sha256: "), "{}", log_line);
+
+    let buyer_keys = KeyShares::try_from(buyer.inner().clone().init_trade(init_trade).await.unwrap().into_inner()).unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let seller_nonces = seller.inner().clone().get_nonce_shares(nonce_shares_request("trade", &buyer_keys))
+        .await.unwrap().into_inner();
+    buyer.inner().clone().get_nonce_shares(nonce_shares_request("trade", &seller_keys)).await.unwrap();
+    let log_line = logging::debug_for_log(&seller_nonces);
+    assert_redacted(&log_line, &[
+        &seller_nonces.swap_tx_input_nonce_share,
+        &seller_nonces.buyers_warning_tx_buyer_input_nonce_share,
+        &seller_nonces.sellers_warning_tx_seller_input_nonce_share,
+        &seller_nonces.buyers_redirect_tx_input_nonce_share,
+        &seller_nonces.identity_signature,
+    ]);
+
+    let buyer_sigs = buyer.inner().clone().get_partial_signatures(PartialSignaturesRequest {
+        trade_id: "trade".to_owned(),
+        peers_nonce_shares: Some(seller_nonces),
+        ..Default::default()
+    }).await.unwrap().into_inner();
+    let log_line = logging::debug_for_log(&buyer_sigs);
+    assert_redacted(&log_line, &[
+        &buyer_sigs.peers_warning_tx_buyer_input_partial_signature,
+        &buyer_sigs.peers_warning_tx_seller_input_partial_signature,
+        &buyer_sigs.peers_redirect_tx_input_partial_signature,
+        &buyer_sigs.identity_signature,
+    ]);
+    assert!(log_line.contains("identity_signature: <64 bytes, sha256: "), "{}", log_line);
+}
+
+#[tokio::test]
+async fn wrong_partial_signatures_are_caught_by_peer() {
+    let faults = FaultConfig { wrong_partial_signatures: true, ..FaultConfig::default() };
+    let (buyer, seller) = (spawn_faulty_client(faults).await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    seller.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&buyer_nonces)).await.unwrap();
+
+    let result = seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    assert_eq!(seller.list_trades(false).await.unwrap()[0].phase(), helloworld::TradePhase::PartialSignaturesGenerated);
+}
+
+#[tokio::test]
+async fn replayed_nonce_shares_are_caught_by_peer() {
+    let faults = FaultConfig { replay_nonce_shares: true, ..FaultConfig::default() };
+    let buyer = spawn_faulty_client(faults).await;
+    for (trade_id, replayed) in [("trade1", false), ("trade2", true)] {
+        let seller = spawn_client().await;
+        let buyer_keys = buyer.init_trade(InitTrade::new(trade_id, Role::BuyerAsTaker)).await.unwrap();
+        let seller_keys = seller.init_trade(InitTrade::new(trade_id, Role::SellerAsMaker)).await.unwrap();
+        seller.get_nonce_shares(get_nonce_shares(trade_id, &buyer_keys)).await.unwrap();
+        let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares(trade_id, &seller_keys)).await.unwrap();
+
+        // The second trade gets the nonce shares of the first, signed with the wrong identity key:
+        let result = seller.get_partial_signatures(GetPartialSignatures::new(trade_id).peers_nonce_shares(&buyer_nonces)).await;
+        if replayed {
+            assert_eq!(code(result), Code::InvalidArgument);
+        } else {
+            result.unwrap();
+        }
+        drop(seller);
+    }
+    drop(buyer);
+}
+
+#[tokio::test]
+async fn peer_nonces_reused_across_trades_are_flagged_then_refused_if_configured() {
+    for refuse in [false, true] {
+        let events = TradeEventBus::default();
+        let mut receiver = events.subscribe();
+        let musig = new_musig().with_events(events)
+            .with_nonce_reuse(NonceReuseConfig { refuse, ..NonceReuseConfig::default() });
+        let seller = TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never());
+        let buyer = spawn_client().await;
+
+        // A broken peer sends the very same nonce shares (duly signed) for two of our trades:
+        let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+        let mut seller_keys = Vec::new();
+        for trade_id in ["trade1", "trade2"] {
+            seller_keys.push(seller.init_trade(InitTrade::new(trade_id, Role::SellerAsMaker)).await.unwrap());
+            seller.get_nonce_shares(get_nonce_shares(trade_id, &buyer_keys)).await.unwrap();
+        }
+        let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys[0])).await.unwrap();
+        seller.get_partial_signatures(GetPartialSignatures::new("trade1").peers_nonce_shares(&buyer_nonces)).await.unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        let result = seller.get_partial_signatures(GetPartialSignatures::new("trade2").peers_nonce_shares(&buyer_nonces)).await;
+        let state = seller.get_trade_state("trade2").await.unwrap();
+        let phase = state.summary.unwrap().phase();
+        if refuse {
+            assert_eq!(code(result), Code::FailedPrecondition);
+            assert_eq!(phase, helloworld::TradePhase::NonceSharesGenerated);
+        } else {
+            result.unwrap();
+            assert_eq!(phase, helloworld::TradePhase::PartialSignaturesGenerated);
+        }
+        assert!(matches!(receiver.try_recv(), Ok(TradeEvent::PeerNonceReused { trade_id, reused_from })
+            if trade_id == "trade2" && reused_from == "trade1"));
+        assert_eq!(state.peer_nonce_reused_from.as_deref(), Some("trade1"));
+        assert_eq!(seller.get_trade_state("trade1").await.unwrap().peer_nonce_reused_from, None);
+        drop((buyer, seller));
+    }
+}
+
+#[test]
+fn peer_nonce_index_forgets_oldest_nonces_once_full() {
+    let index = PeerNonceIndex::new(2);
+    let nonces: Vec<_> = iter::repeat_with(|| SecNonce::random(&mut rand::thread_rng()).public_nonce()).take(3).collect();
+    assert_eq!(index.record("trade1", &nonces[..2]), None);
+    // Sending a nonce again in the same trade isn't reuse:
+    assert_eq!(index.record("trade1", &nonces[..1]), None);
+    assert_eq!(index.record("trade2", &nonces[1..]), Some("trade1".to_owned()));
+    // The first nonce has now been forgotten, to make room for the third:
+    assert_eq!(index.record("trade3", &nonces[..1]), None);
+    assert_eq!(index.record("trade4", &nonces[2..]), Some("trade2".to_owned()));
+}
+
+#[tokio::test]
+async fn nonce_shares_are_only_revealed_for_peers_commitments() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker).commit_to_nonces()).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker).commit_to_nonces()).await.unwrap();
+    let buyer_commitments = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_commitments = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    assert!(buyer_commitments.nonce_shares.is_none());
+    assert!(buyer_commitments.message.swap_tx_input_nonce_share.is_empty());
+    let seller_nonces = seller.reveal_nonce_shares(RevealNonceShares::new("trade")
+        .peers_nonce_commitments(&buyer_commitments)).await.unwrap();
+    assert!(seller_nonces.nonce_shares.is_some());
+
+    // The buyer may not take in the seller's nonce shares until it has their commitments (and has
+    // revealed its own nonce shares in turn), nor reveal them for another trade's commitments:
+    let step = GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces);
+    assert_eq!(code(buyer.get_partial_signatures(step.clone()).await), Code::Internal);
+    let result = buyer.reveal_nonce_shares(RevealNonceShares::new("trade").peers_nonce_commitments(&buyer_commitments)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    assert_eq!(buyer.list_trades(false).await.unwrap()[0].revision, 1);
+
+    let buyer_nonces = buyer.reveal_nonce_shares(RevealNonceShares::new("trade")
+        .peers_nonce_commitments(&seller_commitments)).await.unwrap();
+    buyer.get_partial_signatures(step).await.unwrap();
+    seller.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    drop(buyer);
+    drop(seller);
+}
+
+#[tokio::test]
+async fn reset_signing_session_reruns_exchange_without_new_trade() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let old_buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let old_seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    seller.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&old_buyer_nonces))
+        .await.unwrap();
+
+    // After both parties reset (the seller having already signed), the old nonce shares are refused:
+    let buyer_nonces = buyer.reset_signing_session(ResetSigningSession::new("trade").fee_rates(60.0, 45.0))
+        .await.unwrap();
+    let seller_nonces = seller.reset_signing_session(ResetSigningSession::new("trade")).await.unwrap();
+    let result = buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&old_seller_nonces)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    assert_eq!(buyer.get_trade_state("trade").await.unwrap().signing_session, 1);
+
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+    // Once the deposit tx is signed, the session can no longer be reset:
+    assert_eq!(code(buyer.reset_signing_session(ResetSigningSession::new("trade")).await), Code::FailedPrecondition);
+    drop(buyer);
+    drop(seller);
+}
+
+#[tokio::test]
+async fn trade_is_cancelled_before_deposit_alone_until_signed_then_only_with_peers_consent() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let cancellation = |response: &helloworld::CancelBeforeDepositResponse| {
+        let trade = response.trade.as_ref().unwrap();
+        (trade.cancellation(), trade.archived_at_millis.is_some())
+    };
+
+    // Before the deposit tx is signed, either party may cancel on its own, the other then doing so
+    // with its consent:
+    let buyer_keys = buyer.init_trade(InitTrade::new("early", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("early", Role::SellerAsMaker)).await.unwrap();
+    buyer.get_nonce_shares(get_nonce_shares("early", &seller_keys)).await.unwrap();
+    seller.get_nonce_shares(get_nonce_shares("early", &buyer_keys)).await.unwrap();
+    let buyers_cancellation = buyer.cancel_before_deposit(CancelBeforeDeposit::new("early")).await.unwrap();
+    assert_eq!(cancellation(&buyers_cancellation), (helloworld::Cancellation::CancelledUnilaterally, true));
+    assert_eq!(buyer.list_trades(true).await.unwrap()[0].cancellation(), helloworld::Cancellation::CancelledUnilaterally);
+    let sellers_cancellation = seller.cancel_before_deposit(CancelBeforeDeposit::new("early")
+        .peers_cancellation(&buyers_cancellation)).await.unwrap();
+    assert_eq!(cancellation(&sellers_cancellation), (helloworld::Cancellation::CancelledMutually, true));
+
+    // Once it is signed, a cancellation takes the peer's consent, which each party hands the other:
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+    let buyers_cancellation = buyer.cancel_before_deposit(CancelBeforeDeposit::new("trade")).await.unwrap();
+    assert_eq!(cancellation(&buyers_cancellation), (helloworld::Cancellation::NotCancelled, false));
+    let sellers_cancellation = seller.cancel_before_deposit(CancelBeforeDeposit::new("trade")
+        .peers_cancellation(&buyers_cancellation)).await.unwrap();
+    assert_eq!(cancellation(&sellers_cancellation), (helloworld::Cancellation::CancelledMutually, true));
+    // (Our own consent doesn't pass for the peer's:)
+    let result = buyer.cancel_before_deposit(CancelBeforeDeposit::new("trade")
+        .peers_cancellation(&buyers_cancellation)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    let buyers_cancellation = buyer.cancel_before_deposit(CancelBeforeDeposit::new("trade")
+        .peers_cancellation(&sellers_cancellation)).await.unwrap();
+    assert_eq!(cancellation(&buyers_cancellation), (helloworld::Cancellation::CancelledMutually, true));
+    let result = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt)).await;
+    assert_eq!(code(result), Code::NotFound);
+    drop(buyer);
+    drop(seller);
+}
+
+#[tokio::test]
+async fn fee_rate_change_re_signs_prepared_txs_with_both_parties_consent() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let step = ProposeFeeRateChange::new("trade", 12.5);
+    assert_eq!(code(buyer.propose_fee_rate_change(step.clone()).await), Code::FailedPrecondition);
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+
+    let proposal = buyer.propose_fee_rate_change(step).await.unwrap();
+    // The seller must consent to the very fee rate proposed:
+    let result = seller.accept_fee_rate_change(AcceptFeeRateChange::new("trade", 10.0, &proposal)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    let acceptance = seller.accept_fee_rate_change(AcceptFeeRateChange::new("trade", 12.5, &proposal)).await.unwrap();
+    let confirmation = buyer.propose_fee_rate_change(ProposeFeeRateChange::new("trade", 12.5)
+        .peers_acceptance(&acceptance)).await.unwrap();
+    seller.accept_fee_rate_change(AcceptFeeRateChange::new("trade", 12.5, &confirmation)).await.unwrap();
+
+    for client in [&buyer, &seller] {
+        let audit_log = client.inner().clone().get_trade_audit_log(GetTradeAuditLogRequest { trade_id: "trade".to_owned() })
+            .await.unwrap().into_inner().entries;
+        let last_note = audit_log.iter().rev().find_map(|entry| entry.note.as_deref()).unwrap();
+        assert!(last_note.starts_with("prepared tx fee rate 12.5 sat/vB consented to by "), "{}", last_note);
+        assert!(last_note.contains("the peer (identity signature "), "{}", last_note);
+    }
+    // A completed change cannot be completed again:
+    let result = seller.accept_fee_rate_change(AcceptFeeRateChange::new("trade", 12.5, &confirmation)).await;
+    assert_eq!(code(result), Code::FailedPrecondition);
+    drop(buyer);
+    drop(seller);
+}
+
+#[tokio::test]
+async fn swap_tx_is_re_signed_at_higher_fee_rate_proposed_by_buyer() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+    let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt))
+        .await.unwrap();
+    while confirmations.message().await.unwrap().is_some() {}
+    // The swap tx may only be re-signed once the buyer would hand out its partial signature on it anyway:
+    let step = ProposeSwapTxFeeBump::new("trade", 60.0);
+    assert_eq!(code(buyer.propose_swap_tx_fee_bump(step.clone()).await), Code::FailedPrecondition);
+    buyer.confirm_payment_started("trade", None).await.unwrap();
+    seller.confirm_payment_received("trade", None).await.unwrap();
+    let old_swap_tx = seller.sign_swap_tx(SignSwapTx::new("trade").peers_partial_signatures(&buyer_sigs)).await.unwrap().swap_tx;
+
+    // Only the buyer proposes a bump, and only the seller accepts one:
+    assert_eq!(code(seller.propose_swap_tx_fee_bump(step.clone()).await), Code::FailedPrecondition);
+    let proposal = buyer.propose_swap_tx_fee_bump(step).await.unwrap();
+    let result = buyer.accept_swap_tx_fee_bump(AcceptSwapTxFeeBump::new("trade", 60.0, &proposal)).await;
+    assert_eq!(code(result), Code::FailedPrecondition);
+    let acceptance = seller.accept_swap_tx_fee_bump(AcceptSwapTxFeeBump::new("trade", 60.0, &proposal)).await.unwrap();
+    let confirmation = buyer.propose_swap_tx_fee_bump(ProposeSwapTxFeeBump::new("trade", 60.0)
+        .peers_acceptance(&acceptance)).await.unwrap();
+    let completion = seller.accept_swap_tx_fee_bump(AcceptSwapTxFeeBump::new("trade", 60.0, &confirmation)).await.unwrap();
+    let swap_tx: LiftedSignature = convert::decode(&completion.swap_tx.unwrap(), "swap_tx").unwrap();
+    assert_ne!(swap_tx, old_swap_tx);
+
+    for client in [&buyer, &seller] {
+        let audit_log = client.inner().clone().get_trade_audit_log(GetTradeAuditLogRequest { trade_id: "trade".to_owned() })
+            .await.unwrap().into_inner().entries;
+        let last_note = audit_log.iter().rev().find_map(|entry| entry.note.as_deref()).unwrap();
+        assert!(last_note.starts_with("swap tx fee rate 60 sat/vB consented to by "), "{}", last_note);
+    }
+    // A re-signed swap tx may only be re-signed again at a higher fee rate still:
+    let result = buyer.propose_swap_tx_fee_bump(ProposeSwapTxFeeBump::new("trade", 50.0)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    // The buyer claims its output from the re-signed swap tx, as published by the seller:
+    buyer.close_trade(CloseTrade::new("trade").swap_tx(swap_tx)).await.unwrap();
+    drop((buyer, seller));
+}
+
+/// Funding inputs of the given amounts, each from a fresh wallet key, proven for the trade with the
+/// given ID.
+fn funding_inputs(trade_id: &str, amounts: &[u64]) -> Vec<FundingInput> {
+    amounts.iter().zip(0..).map(|(&amount, vout)| {
+        let key = Scalar::random(&mut rand::thread_rng());
+        let mut input = FundingInput {
+            txid: rand::random(), vout, amount, owner_pub_key: key.base_point_mul(),
+            ownership_proof: musig2::sign_solo(key, [0; 32], rand::random::<[u8; 32]>()),
+        };
+        let message = funding_input_ownership_message(trade_id, &input.script_pub_key(), &input.txid, vout, amount);
+        input.ownership_proof = musig2::sign_solo(key, message, rand::random::<[u8; 32]>());
+        input
+    }).collect()
+}
+
+#[tokio::test]
+async fn deposit_tx_may_be_funded_from_several_owned_inputs_per_party() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    // Neither party may claim inputs proven for any other trade:
+    let result = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)
+        .funding_inputs(&funding_inputs("other-trade", &[10_000, 25_000]))).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    let buyer_inputs = funding_inputs("trade", &[10_000, 25_000]);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker).funding_inputs(&buyer_inputs))
+        .await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)
+        .funding_inputs(&funding_inputs("trade", &[100_000, 100_000, 30_000]))).await.unwrap();
+
+    // The seller's inputs must cover the trade amount and its security deposit, before the buyer
+    // commits to the trade:
+    buyer.init_trade(InitTrade::new("short-trade", Role::BuyerAsTaker)).await.unwrap();
+    let short_seller_keys = seller.init_trade(InitTrade::new("short-trade", Role::SellerAsMaker)
+        .funding_inputs(&funding_inputs("short-trade", &[100_000, 100_000, 29_999]))).await.unwrap();
+    let result = buyer.get_nonce_shares(get_nonce_shares("short-trade", &short_seller_keys)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    // Nor may the inputs be swapped on the way:
+    let swapped_keys = KeyShares { half_deposit_psbt: short_seller_keys.half_deposit_psbt, ..seller_keys.clone() };
+    assert_eq!(code(buyer.get_nonce_shares(get_nonce_shares("trade", &swapped_keys)).await), Code::InvalidArgument);
+
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+    let psbt = buyer.inner().clone().get_unsigned_deposit_psbt(UnsignedDepositPsbtRequest { trade_id: "trade".to_owned() })
+        .await.unwrap().into_inner().deposit_psbt;
+    assert_eq!(decode_half_deposit_psbt(&psbt, "deposit_psbt").unwrap(), buyer_inputs);
+}
+
+#[tokio::test]
+async fn deposit_tx_failing_re_validation_is_not_broadcast_and_fails_the_trade() {
+    let ((buyer, broadcaster), seller) = (spawn_broadcasting_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)
+        .funding_inputs(&funding_inputs("trade", &[10_000, 25_000]))).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)
+        .funding_inputs(&funding_inputs("trade", &[100_000, 100_000, 30_000]))).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+
+    // A bug leaving our signed half spending some inputs other than those agreed with the peer:
+    lock_trade_model(&broadcaster.store.get_trade_model("trade").unwrap()).my_signed_half_deposit_psbt =
+        Some(convert::encode_half_deposit_psbt(&funding_inputs("trade", &[10_000, 25_000])));
+    let result = buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt)).await;
+    assert_eq!(code(result), Code::Internal);
+    assert!(broadcaster.txs().is_empty());
+    let state = buyer.get_trade_state("trade").await.unwrap();
+    assert_eq!(state.failure.as_deref(), Some("deposit tx failed re-validation before broadcast, as it does not spend funding input 0"));
+    assert_eq!(code(buyer.confirm_payment_started("trade", None).await), Code::FailedPrecondition);
+}
+
+#[tokio::test]
+async fn funding_inputs_must_keep_to_the_coin_control_asked_for() {
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let client = TradeClient::new(serve(MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default())).await);
+    let inputs = funding_inputs("trade", &[10_000, 25_000]);
+    let outpoint = |input: &FundingInput| (input.txid, input.vout);
+    let input_at_fault = |coin_control: CoinControl| {
+        let client = &client;
+        let inputs = &inputs;
+        async move {
+            let result = client.init_trade(InitTrade::new("trade", Role::SellerAsMaker).funding_inputs(inputs)
+                .coin_control(&coin_control)).await;
+            let Err(ClientError::Status(status)) = result else { panic!("expected a failed call") };
+            assert_eq!(status.code(), Code::InvalidArgument);
+            convert::error_info(&status).unwrap().metadata["input"].clone()
+        }
+    };
+    assert_eq!(input_at_fault(CoinControl { avoid_outpoints: vec![outpoint(&inputs[1])], ..CoinControl::default() }).await,
+        "fundingInputs[1]");
+    assert_eq!(input_at_fault(CoinControl { use_outpoints: vec![([9; 32], 0)], ..CoinControl::default() }).await,
+        "coinControl.useOutpoints[0]");
+    assert_eq!(input_at_fault(CoinControl {
+        use_outpoints: vec![outpoint(&inputs[0])], avoid_outpoints: vec![outpoint(&inputs[0])], ..CoinControl::default()
+    }).await, "coinControl.avoidOutpoints[0]");
+
+    // Coin control kept to is kept with the trade, for the wallet's coin selector:
+    let coin_control = CoinControl {
+        use_outpoints: vec![outpoint(&inputs[0])],
+        avoid_outpoints: vec![([9; 32], 0)],
+        fee_strategy: FeeStrategy::LowestFee,
+        change_policy: ChangePolicy::ToAddress("bc1qchange".to_owned()),
+    };
+    client.init_trade(InitTrade::new("trade", Role::SellerAsMaker).funding_inputs(&inputs).coin_control(&coin_control))
+        .await.unwrap();
+    assert_eq!(lock_trade_model(&store.get_trade_model("trade").unwrap()).coin_control, coin_control);
+    drop(client);
+}
+
+#[tokio::test]
+async fn wallet_utxos_funding_a_trade_are_reserved_until_it_is_cancelled() {
+    let client = spawn_client().await;
+    let key = Scalar::random(&mut rand::thread_rng());
+    // The same UTXO of the wallet, as proven for the trade with the given ID:
+    let utxo = |trade_id: &str| {
+        let mut input = FundingInput {
+            txid: [7; 32], vout: 1, amount: 50_000, owner_pub_key: key.base_point_mul(),
+            ownership_proof: musig2::sign_solo(key, [0; 32], rand::random::<[u8; 32]>()),
+        };
+        let message = funding_input_ownership_message(trade_id, &input.script_pub_key(), &input.txid, 1, 50_000);
+        input.ownership_proof = musig2::sign_solo(key, message, rand::random::<[u8; 32]>());
+        input
+    };
+    client.init_trade(InitTrade::new("trade", Role::SellerAsMaker).funding_inputs(&[utxo("trade")])).await.unwrap();
+    // A dry run reserves nothing, so needn't be turned away:
+    client.init_trade(InitTrade::new("dry-run", Role::SellerAsMaker).funding_inputs(&[utxo("dry-run")]).dry_run())
+        .await.unwrap();
+    let result = client.init_trade(InitTrade::new("other-trade", Role::SellerAsMaker)
+        .funding_inputs(&[utxo("other-trade")])).await;
+    let Err(ClientError::Status(status)) = result else { panic!("expected a failed call") };
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert_eq!(convert::bad_request(&status).unwrap().field_violations[0].field, "fundingInputs[0]");
+
+    let wallet_utxos = [helloworld::FundingInput::from(&utxo("")),
+        helloworld::FundingInput { txid: vec![8; 32], vout: 0, amount: 20_000, ..Default::default() }];
+    let wallet_status = client.get_wallet_status(wallet_utxos.to_vec()).await.unwrap();
+    assert_eq!((wallet_status.reserved_sats, wallet_status.available_sats), (50_000, 20_000));
+    assert_eq!(wallet_status.reserved_utxos.iter().map(|utxo| (&utxo.trade_id[..], utxo.vout, utxo.spent)).collect::<Vec<_>>(),
+        [("trade", 1, false)]);
+
+    // Cancelling the trade releases its UTXOs, for another trade to reserve:
+    client.cancel_before_deposit(CancelBeforeDeposit::new("trade")).await.unwrap();
+    let wallet_status = client.get_wallet_status(wallet_utxos.to_vec()).await.unwrap();
+    assert_eq!((wallet_status.reserved_sats, wallet_status.available_sats), (0, 70_000));
+    assert!(wallet_status.reserved_utxos.is_empty());
+    client.init_trade(InitTrade::new("other-trade", Role::SellerAsMaker).funding_inputs(&[utxo("other-trade")]))
+        .await.unwrap();
+    drop(client);
+}
+
+#[tokio::test]
+async fn large_half_deposit_psbt_is_passed_in_chunks_checked_against_its_hash() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_inputs = funding_inputs("trade", &[1_000; 600]);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker).funding_inputs(&buyer_inputs))
+        .await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+
+    let mut inner = buyer.inner().clone();
+    drop(buyer);
+    let mut stream = inner.get_unsigned_deposit_psbt_chunks(UnsignedDepositPsbtRequest { trade_id: "trade".to_owned() })
+        .await.unwrap().into_inner();
+    let mut chunks = vec![];
+    while let Some(chunk) = stream.message().await.unwrap() {
+        chunks.push(chunk);
+    }
+    assert_eq!(chunks.len(), 2);
+    let psbt: Vec<_> = chunks.iter().flat_map(|chunk| chunk.data.iter().copied()).collect();
+    assert_eq!(decode_half_deposit_psbt(&psbt, "deposit_psbt").unwrap(), buyer_inputs);
+
+    let signed_chunks = |chunks: &[PsbtChunk]| stream::iter(chunks.iter().cloned().zip(0..)
+        .map(|(chunk, i)| SignedDepositPsbtChunk {
+            trade_id: if i == 0 { "trade".to_owned() } else { String::new() },
+            expected_revision: None,
+            chunk: Some(chunk),
+        })
+        .collect::<Vec<_>>());
+    let mut tampered_chunks = chunks.clone();
+    tampered_chunks[1].data[0] ^= 1;
+    let result = inner.submit_signed_deposit_psbt_chunks(signed_chunks(&tampered_chunks)).await;
+    assert_eq!(result.unwrap_err().code(), Code::DataLoss);
+    let result = inner.submit_signed_deposit_psbt_chunks(signed_chunks(&chunks[..1])).await;
+    assert_eq!(result.unwrap_err().code(), Code::InvalidArgument);
+    inner.submit_signed_deposit_psbt_chunks(signed_chunks(&chunks)).await.unwrap();
+    drop(inner);
+}
+
+#[tokio::test]
+async fn resumed_trade_hands_out_the_peer_payloads_returned_so_far() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let resumed = buyer.resume_trade("trade").await.unwrap();
+    assert!(resumed.nonce_shares.is_none() && resumed.partial_signatures.is_none());
+    let old_buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)).await.unwrap();
+
+    // A buyer which lost track of what it sent the seller can get it all back, as good as new:
+    let resumed = buyer.resume_trade("trade").await.unwrap();
+    let resumed_keys = KeyShares::try_from(resumed.key_shares.unwrap()).unwrap();
+    assert_eq!(resumed_keys.buyer_output_pub_key_share, buyer_keys.buyer_output_pub_key_share);
+    assert_eq!(resumed_keys.seller_output_pub_key_share, buyer_keys.seller_output_pub_key_share);
+    let buyer_nonces = NonceShares::try_from(resumed.nonce_shares.unwrap()).unwrap();
+    assert_eq!(buyer_nonces.message.swap_tx_input_nonce_share, old_buyer_nonces.message.swap_tx_input_nonce_share);
+    let buyer_sigs = PartialSignatures::try_from(resumed.partial_signatures.unwrap()).unwrap();
+    assert_eq!(resumed.state.unwrap().summary.unwrap().phase(), helloworld::TradePhase::PartialSignaturesGenerated);
+    assert_eq!(resumed.transcript.unwrap().audit_log.len(), 3);
+    let steps = resumed.descriptor.unwrap().steps;
+    let done: Vec<_> = steps.iter().filter(|s| s.status() == helloworld::StepStatus::Done).map(|s| s.rpc.as_str()).collect();
+    assert_eq!(done, ["InitTrade", "GetNonceShares", "GetPartialSignatures"]);
+
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+    assert_eq!(code(buyer.resume_trade("no-such-trade").await), Code::NotFound);
+}
+
+#[tokio::test]
+async fn only_buyer_may_redact_swap_tx_signature_which_may_not_be_left_out() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+
+    let left_out = PartialSignatures {
+        message: helloworld::PartialSignaturesMessage { swap_tx_input: None, ..buyer_sigs.redacted().message },
+        partial_signatures: None,
+    };
+    let result = seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&left_out)).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+    let result = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs.redacted())).await;
+    assert_eq!(code(result), Code::InvalidArgument);
+
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+    let seller_state = seller.get_trade_state("trade").await.unwrap();
+    assert_eq!(seller_state.peers_swap_tx_signature(), helloworld::SwapTxSignatureState::Promised);
+    let buyer_state = buyer.get_trade_state("trade").await.unwrap();
+    assert_eq!(buyer_state.peers_swap_tx_signature(), helloworld::SwapTxSignatureState::Revealed);
+}
+
+#[tokio::test]
+async fn service_info_reports_build_and_configured_capabilities() {
+    let info = spawn_client().await.get_service_info().await.unwrap();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.supported_protocol_versions, [PROTOCOL_VERSION]);
+    let features = info.features.unwrap();
+    assert_eq!((features.store(), features.chain_backend()),
+        (helloworld::StoreKind::StoreMemory, helloworld::ChainBackendKind::ChainSimulated));
+    assert_eq!(info.limits.unwrap().max_open_trades, Some(1000));
+
+    let config = Config {
+        chain: ChainConfig { backend_url: Some("http://esplora.test".to_owned()), ..ChainConfig::default() },
+        trade_quota: TradeQuotaConfig { max_open_trades: None, max_open_trades_per_client: Some(5) },
+        ..Config::default()
+    };
+    let info = TradeClient::new(serve(new_musig().with_service_info(crate::service_info(&config))).await)
+        .get_service_info().await.unwrap();
+    assert_eq!(info.features.unwrap().chain_backend(), helloworld::ChainBackendKind::ChainEsplora);
+    let limits = info.limits.unwrap();
+    assert_eq!((limits.max_open_trades, limits.max_open_trades_per_client), (None, Some(5)));
+}
+
+/// Whether the given trade is under a dry-run policy, with no overrides of its own.
+async fn dry_run(client: &mut MuSigClient<Channel>, trade_id: &str) -> Option<bool> {
+    let request = SetTradePolicyRequest { trade_id: trade_id.to_owned(), overrides: None };
+    client.set_trade_policy(request).await.unwrap().into_inner().dry_run
+}
+
+#[tokio::test]
+async fn config_reload_puts_new_trade_limits_and_policy_into_force_with_no_restart() {
+    let result = serve_admin(new_musig(), None).await.reload_config(ReloadConfigRequest {}).await;
+    assert_eq!(result.unwrap_err().code(), Code::FailedPrecondition);
+
+    let dir = std::env::temp_dir().join(format!("musig-reload-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("daemon.conf");
+    fs::write(&path, "trade_limit_max_amount_sats = lots\npolicy_dry_run = true\n").unwrap();
+    let musig = new_musig().with_config_file(path.clone());
+    let mut admin = serve_admin(musig.clone(), None).await;
+    let channel = serve(musig).await;
+    let mut client = MuSigClient::new(channel.clone());
+    let seller = TradeClient::new(channel).with_retry_policy(RetryPolicy::never());
+    let buyer = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+
+    // A malformed config file is turned away, with nothing changed:
+    let result = admin.reload_config(ReloadConfigRequest {}).await;
+    assert_eq!(result.unwrap_err().code(), Code::FailedPrecondition);
+    assert_eq!(dry_run(&mut client, "trade").await, Some(false));
+
+    fs::write(&path, "trade_limit_max_amount_sats = 1000000\npolicy_dry_run = true\n").unwrap();
+    let response = admin.reload_config(ReloadConfigRequest {}).await.unwrap().into_inner();
+    assert_eq!(response.changed_settings, ["trade_limit_*", "policy_*"]);
+    assert_eq!(response.receiver_registry, None);
+    assert_eq!(dry_run(&mut client, "trade").await, Some(true));
+    let request = get_nonce_shares("trade", &buyer_keys).amounts(2_000_000, 300_000, 300_000);
+    assert_eq!(code(seller.get_nonce_shares(request).await), Code::OutOfRange);
+    seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+
+    let response = admin.reload_config(ReloadConfigRequest {}).await.unwrap().into_inner();
+    assert!(response.changed_settings.is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+    drop((admin, client, buyer, seller));
+}
+
+#[tokio::test]
+async fn admin_service_lists_counts_aborts_and_snapshots_trades_for_token_bearers() {
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let musig = MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default());
+    let mut admin = serve_admin(musig.clone(), Some("admin token")).await;
+    let buyer = TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    buyer.init_trade(InitTrade::new("other", Role::BuyerAsTaker)).await.unwrap();
+
+    let result = admin.get_stats(GetStatsRequest {}).await;
+    assert_eq!(result.unwrap_err().code(), Code::Unauthenticated);
+    let result = admin.get_stats(with_admin_token(GetStatsRequest {}, "wrong token")).await;
+    assert_eq!(result.unwrap_err().code(), Code::Unauthenticated);
+    let stats = admin.get_stats(with_admin_token(GetStatsRequest {}, "admin token")).await.unwrap().into_inner();
+    assert_eq!((stats.live_trades, stats.archived_trades), (2, 0));
+    let by_phase: Vec<_> = stats.live_trades_by_phase.iter().map(|count| (count.phase(), count.trades)).collect();
+    assert_eq!(by_phase, [(helloworld::TradePhase::KeySharesGenerated, 1), (helloworld::TradePhase::NonceSharesGenerated, 1)]);
+
+    let request = |trade_id: &str, expected_revision| with_admin_token(
+        AbortTradeRequest { trade_id: trade_id.to_owned(), expected_revision }, "admin token");
+    assert_eq!(admin.abort_trade(request("missing", None)).await.unwrap_err().code(), Code::NotFound);
+    assert_eq!(admin.abort_trade(request("trade", Some(0))).await.unwrap_err().code(), Code::Aborted);
+    let summary = admin.abort_trade(request("trade", Some(1))).await.unwrap().into_inner();
+    assert_eq!((&summary.trade_id[..], summary.phase()), ("trade", helloworld::TradePhase::NonceSharesGenerated));
+    let request = with_admin_token(ListTradesRequest { archived: true }, "admin token");
+    let archived = admin.list_trades(request).await.unwrap().into_inner().trades;
+    assert_eq!(archived.iter().map(|trade| &trade.trade_id[..]).collect::<Vec<_>>(), ["trade"]);
+    assert_eq!(code(buyer.get_partial_signatures(GetPartialSignatures::new("trade")).await), Code::NotFound);
+
+    // The snapshot is of the store as it is, for import elsewhere by the export's passphrase:
+    let request = with_admin_token(ExportSnapshotRequest {}, "admin token");
+    let snapshot = admin.export_snapshot(request).await.unwrap().into_inner().snapshot;
+    let imported = TradeModelMemoryStore::default();
+    let passphrase = MasterSecret::fetch(&admin_snapshot_passphrase()).unwrap();
+    assert_eq!(snapshot::import(&imported, &snapshot, &passphrase).unwrap(), (1, 1));
+    assert!(imported.get_trade_model("other").is_some());
+    drop((admin, buyer, seller));
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn store_cipher_matches_rfc_test_vectors_and_known_answers() {
+    // PBKDF2-HMAC-SHA256 vectors of RFC 7914, section 11 (to a single 32-byte block):
+    assert_eq!(cipher::stretch_passphrase(b"passwd", b"salt", 1)[..],
+        unhex("55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"));
+    assert_eq!(cipher::stretch_passphrase(b"Password", b"NaCl", 80_000)[..],
+        unhex("4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56"));
+
+    // The ChaCha20-Poly1305 AEAD vector of RFC 8439, section 2.8.2:
+    let key: [u8; 32] = std::array::from_fn(|i| 0x80 + u8::try_from(i).unwrap());
+    let nonce = unhex("070000004041424344454647");
+    let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, \
+        sunscreen would be it.";
+    let sealed = ChaCha20Poly1305::new(&key.into())
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad }).unwrap();
+    assert_eq!(sealed, unhex("d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca9671282fafb69da92728b\
+        1a71de0a9e060b2905d6a5b67ecd3b3692ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc3ff4def08e4b7a9de5\
+        76d26586cec64b61161ae10b594f09e26a7e902ecbd0600691"));
+
+    // The store cipher keys the AEAD & key check from the master key by HMAC-SHA256, and prefixes
+    // each sealed secret with the format version & nonce:
+    let cipher = StoreCipher::derive(&MasterSecret::Key([0xab; 32]), &[]);
+    assert_eq!(cipher.key_check()[..], unhex("1115cce7a63b84ee6ba5ba0d667e7bb10143b449dab5a4902a30842eed2efed6"));
+    let sealed = unhex("02000102030405060708090a0bd5a47116a226c91536a0cb61d13e2cc537a7c5b9645dc14f5bad09dd");
+    assert_eq!(cipher.open(&sealed, b"trade id").unwrap(), b"trade secret");
+    assert_eq!(cipher.open(&sealed, b"other trade id"), None);
+    assert_eq!(cipher.open(&cipher.seal(b"trade secret", b"trade id"), b"trade id").unwrap(), b"trade secret");
+}
+
+/// Serve the given bodies at the given paths of a fresh local HTTP server, as a stand-in for an
+/// Esplora API, returning its base URL.
+#[test]
+fn test_vectors_are_exported_as_json_with_every_input_signed() {
+    let json = test_vectors::to_json(&musig_trade_protocol::test_vectors::generate().unwrap());
+    assert_eq!(json::parse(&json.to_string()).unwrap(), json);
+    assert_eq!(json.get("trade_id").and_then(Json::as_str), Some("test-vectors"));
+    let inputs = json.get("inputs").and_then(Json::as_array).unwrap();
+    assert_eq!(inputs.len(), 7);
+    for input in inputs {
+        let field = |name| input.get(name).and_then(Json::as_str).unwrap();
+        // Only the swap tx input is adaptor-signed:
+        let is_swap_tx_input = field("name") == "swap_tx_input";
+        assert_eq!(field("adaptor_point") == "00".repeat(33), !is_swap_tx_input);
+        assert_eq!(field("signature").len(), 128);
+        assert_eq!(input.get("buyer_nonce").and_then(|n| n.get("sec_nonce")).and_then(Json::as_str).unwrap().len(), 128);
+    }
+}
+
+async fn spawn_esplora(routes: Vec<(String, String)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert_ne!(n, 0, "truncated request");
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let path = request.split_whitespace().nth(1).unwrap();
+            let reply = match routes.iter().find(|(route, _)| route == path) {
+                Some((_, body)) => format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body),
+                None => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned(),
+            };
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        }
+    });
+    url
+}
+
+/// The Esplora routes of a chain with its tip at height 100, mined at the given time.
+fn esplora_routes(tip_time: SystemTime) -> Vec<(String, String)> {
+    let timestamp = u32::try_from(tip_time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()).unwrap();
+    let mut header = "00".repeat(68);
+    for b in timestamp.to_le_bytes() {
+        write!(header, "{:02x}", b).unwrap();
+    }
+    header.push_str(&"00".repeat(8));
+    vec![
+        ("/blocks/tip/height".to_owned(), "100".to_owned()),
+        ("/block-height/100".to_owned(), "00000000000000000001".to_owned()),
+        ("/block/00000000000000000001/header".to_owned(), header),
+    ]
+}
+
+async fn serving_status(health: &MyHealth, service: &str) -> Result<ServingStatus, Code> {
+    let request = tonic::Request::new(HealthCheckRequest { service: service.to_owned() });
+    match Health::check(health, request).await {
+        Ok(response) => Ok(response.into_inner().status()),
+        Err(status) => Err(status.code()),
+    }
+}
+
+#[tokio::test]
+async fn daemon_is_only_ready_with_writable_store_and_synced_chain_backend() {
+    let dir = std::env::temp_dir().join(format!("musig-health-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let chain_backend = ChainBackendStatus::default();
+    let readiness_checks = |store_dir: &Path| ReadinessChecks {
+        store_dir: Some(store_dir.to_owned()),
+        chain_backend: Some((chain_backend.clone(), Duration::from_mins(1), 6)),
+    };
+    let health = MyHealth::new(readiness_checks(&dir));
+    assert_eq!(serving_status(&health, "liveness").await, Ok(ServingStatus::Serving));
+    assert_eq!(serving_status(&health, "helloworld.Unknown").await, Err(Code::NotFound));
+    // Not ready until the chain backend has been reached:
+    assert_eq!(serving_status(&health, "helloworld.MuSig").await, Ok(ServingStatus::NotServing));
+    let mut updates = Health::watch(&health, tonic::Request::new(HealthCheckRequest::default())).await.unwrap()
+        .into_inner();
+    assert_eq!(updates.next().await.unwrap().unwrap().status(), ServingStatus::NotServing);
+
+    let url = spawn_esplora(esplora_routes(SystemTime::now())).await;
+    tokio::spawn(chain::follow_chain(url, None, ChainTip::default(), chain_backend.clone(), Duration::from_mins(1)));
+    assert_eq!(updates.next().await.unwrap().unwrap().status(), ServingStatus::Serving);
+    drop(updates);
+    assert_eq!(serving_status(&health, "").await, Ok(ServingStatus::Serving));
+    // A store directory which cannot be written to breaks the daemon:
+    let unwritable = MyHealth::new(readiness_checks(&dir.join("missing")));
+    assert_eq!(serving_status(&unwritable, "").await, Ok(ServingStatus::NotServing));
+    fs::remove_dir_all(&dir).unwrap();
+
+    // A backend with a tip more than 6 blocks old isn't synced:
+    let stale_backend = ChainBackendStatus::default();
+    let url = spawn_esplora(esplora_routes(SystemTime::now() - Duration::from_hours(2))).await;
+    tokio::spawn(chain::follow_chain(url, None, ChainTip::default(), stale_backend.clone(), Duration::from_mins(1)));
+    let result = time::timeout(Duration::from_secs(5), async {
+        loop {
+            match stale_backend.check(Duration::from_mins(1), 6) {
+                Err(e) if e.contains("not synced") => break e,
+                _ => time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }).await;
+    assert!(result.unwrap().starts_with("chain backend tip at height 100 is 120 minutes old"));
+}
+
+/// Spawn a SOCKS5 proxy which forwards every connection to the given target, whatever the host
+/// asked for, returning the proxy and a record of the `host:port` of every connection made through it.
+async fn spawn_socks_proxy(target: SocketAddr) -> (Socks5Proxy, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = Socks5Proxy { addr: listener.local_addr().unwrap() };
+    let connections = Arc::new(Mutex::new(Vec::new()));
+    let recorded = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut header = [0; 5];
+            stream.read_exact(&mut header).await.unwrap();
+            assert_eq!(header[..4], [5, 1, 0, 3], "expected a CONNECT to a domain name");
+            let mut host_and_port = vec![0; usize::from(header[4]) + 2];
+            stream.read_exact(&mut host_and_port).await.unwrap();
+            let (host, port) = host_and_port.split_at(usize::from(header[4]));
+            let port = u16::from_be_bytes(port.try_into().unwrap());
+            recorded.lock().unwrap().push(format!("{}:{}", String::from_utf8_lossy(host), port));
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let mut target_stream = TcpStream::connect(target).await.unwrap();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut target_stream).await;
+            });
+        }
+    });
+    (proxy, connections)
+}
+
+#[tokio::test]
+async fn chain_backend_is_polled_through_the_socks_proxy() {
+    let url = spawn_esplora(esplora_routes(SystemTime::now())).await;
+    let target = url.strip_prefix("http://").unwrap().parse().unwrap();
+    let (proxy, connections) = spawn_socks_proxy(target).await;
+    // The host doesn't resolve, so the backend can only be reached through the proxy:
+    let port = target.port();
+    let chain_tip = ChainTip::default();
+    let status = ChainBackendStatus::default();
+    tokio::spawn(chain::follow_chain(format!("http://esplora.invalid:{}", port), Some(proxy), chain_tip.clone(),
+        status.clone(), Duration::from_mins(1)));
+    time::timeout(Duration::from_secs(5), async {
+        while status.check(Duration::from_mins(1), 6).is_err() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+    assert_eq!(chain_tip.height(), Some(100));
+    assert_eq!(*connections.lock().unwrap(), vec![format!("esplora.invalid:{}", port); 3]);
+}
+
+/// Make the given HTTP request of the JSON gateway to the given service (in the layers of the given
+/// config), from a client at the given (loopback) address, returning the status code & JSON body of
+/// the reply.
+async fn gateway_request_with<S>(config: &Config, musig: &MyMuSig<S>, from: &str, request: &str) -> (u16, Json)
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let descriptors = Descriptors::load(FILE_DESCRIPTOR_SET).unwrap();
+    let decode_limits = DecodeLimitLayer::new(config.decode_limits).unwrap();
+    let service = crate::gateway_service(config, musig, &decode_limits, &RateLimitLayer::new(config.rate_limits));
+    let (mut client_io, server_io) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    client_io.write_all(request.as_bytes()).await.unwrap();
+    let mut reply = String::new();
+    let (served, read) = tokio::join!(
+        gateway::handle_connection(server_io, Some(from.parse().unwrap()), &descriptors, service),
+        client_io.read_to_string(&mut reply));
+    served.unwrap();
+    read.unwrap();
+    let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+    (head[9..12].parse().unwrap(), json::parse(body).unwrap())
+}
+
+async fn gateway_request(musig: &MyMuSig, request: &str) -> (u16, Json) {
+    gateway_request_with(&Config::default(), musig, "127.0.0.1:4242", request).await
+}
+
+#[tokio::test]
+async fn trades_are_opened_and_queried_as_json_through_gateway() {
+    let musig = new_musig();
+    let body = r#"{"tradeId": "trade", "myRole": "SELLER_AS_MAKER", "commitToNonces": false}"#;
+    let request = format!("POST /v1/InitTrade HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let (status, keys) = gateway_request(&musig, &request).await;
+    assert_eq!(status, 200, "{}", keys);
+    // Byte fields are base64, so 44 characters for a 33-byte point:
+    assert_eq!(keys.get("buyerOutputPubKeyShare").and_then(Json::as_str).map(str::len), Some(44));
+
+    let (status, state) = gateway_request(&musig, "GET /v1/trades/trade HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 200, "{}", state);
+    let summary = state.get("summary").unwrap();
+    assert_eq!(summary.get("tradeId").and_then(Json::as_str), Some("trade"));
+    assert_eq!(summary.get("phase").and_then(Json::as_str), Some("KEY_SHARES_GENERATED"));
+    let (status, trades) = gateway_request(&musig, "GET /v1/trades?archived=false HTTP/1.1\r\n\r\n").await;
+    assert_eq!((status, trades.get("trades").and_then(Json::as_array).map(<[_]>::len)), (200, Some(1)));
+
+    let (status, error) = gateway_request(&musig, "GET /v1/trades/missing%2Dtrade HTTP/1.1\r\n\r\n").await;
+    assert_eq!((status, error.get("code").and_then(Json::as_u64)), (404, Some(Code::NotFound as u64)));
+    assert_eq!(error.get("message").and_then(Json::as_str), Some("missing trade with id: missing-trade"));
+    let (status, _) = gateway_request(&musig, "GET /v1/InitTrade?myRole=NO_SUCH_ROLE HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 400);
+    let (status, _) = gateway_request(&musig, "POST /v1/SubscribeHeightTriggers HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, 501);
+}
+
+#[tokio::test]
+async fn gateway_calls_are_charged_to_their_client_as_on_any_listener() {
+    let musig = new_musig();
+    let mut config = Config::default();
+    config.rate_limits.rpc_per_min = Some(1);
+    let body = r#"{"tradeId": "trade", "myRole": "SELLER_AS_MAKER", "commitToNonces": false}"#;
+    let request = format!("POST /v1/InitTrade HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    assert_eq!(gateway_request_with(&config, &musig, "127.0.0.1:4242", &request).await.0, 200);
+    let trade_model = musig.trade_model_store.get_trade_model("trade").unwrap();
+    assert_eq!(lock_trade_model(&trade_model).opened_by.as_deref(), Some("127.0.0.1"));
+
+    // Each call is made through a fresh rate limit layer here, so exhaust the budget within one:
+    let decode_limits = DecodeLimitLayer::new(config.decode_limits).unwrap();
+    let service = crate::gateway_service(&config, &musig, &decode_limits, &RateLimitLayer::new(config.rate_limits));
+    let descriptors = Descriptors::load(FILE_DESCRIPTOR_SET).unwrap();
+    let mut statuses = vec![];
+    for _ in 0..2 {
+        let (mut client_io, server_io) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        client_io.write_all(b"GET /v1/trades/trade HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut reply = String::new();
+        let (served, _) = tokio::join!(
+            gateway::handle_connection(server_io, Some("127.0.0.1:4242".parse().unwrap()), &descriptors, service.clone()),
+            client_io.read_to_string(&mut reply));
+        served.unwrap();
+        statuses.push(reply[9..12].to_owned());
+    }
+    assert_eq!(statuses, ["200", "429"]);
+
+    // As the gateway doesn't authenticate its clients, it may only be served locally:
+    assert!(Config::parse("gateway_listen_addr = 127.0.0.1:8080").is_ok());
+    assert!(matches!(Config::parse("gateway_listen_addr = 0.0.0.0:8080"), Err(ConfigError::Parse { line: 1, .. })));
+}
+
+/// Open a trade with the given ID through the JSON gateway, from the client at the given address,
+/// returning the status code of the reply.
+async fn init_trade_through_gateway<S>(musig: &MyMuSig<S>, from: &str, trade_id: &str) -> u16
+    where S: TradeModelStore + Send + Sync + 'static
+{
+    let body = format!(r#"{{"tradeId": "{}", "myRole": "SELLER_AS_MAKER", "commitToNonces": false}}"#, trade_id);
+    let request = format!("POST /v1/InitTrade HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    let (status, reply) = gateway_request_with(&Config::default(), musig, from, &request).await;
+    if status != 200 {
+        assert_eq!(reply.get("code").and_then(Json::as_u64), Some(Code::ResourceExhausted as u64), "{}", reply);
+    }
+    status
+}
+
+#[tokio::test]
+async fn open_trades_are_held_to_quotas_overall_and_per_client_until_archived() {
+    let config = TradeQuotaConfig { max_open_trades: Some(3), max_open_trades_per_client: Some(2) };
+    let store = Arc::new(QuotaStore::new(TradeModelMemoryStore::default(), config));
+    let musig = MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default());
+    let (client, other_client) = ("127.0.0.1:4242", "127.0.0.2:4242");
+
+    assert_eq!(init_trade_through_gateway(&musig, client, "trade1").await, 200);
+    assert_eq!(init_trade_through_gateway(&musig, client, "trade2").await, 200);
+    // Over the quota of the client, though not the overall quota:
+    assert_eq!(init_trade_through_gateway(&musig, client, "trade3").await, 429);
+    assert_eq!(init_trade_through_gateway(&musig, other_client, "trade3").await, 200);
+    // Over the overall quota, though not that of the client:
+    assert_eq!(init_trade_through_gateway(&musig, other_client, "trade4").await, 429);
+    assert_eq!((store.open_trade_count(), store.max_open_trade_count_per_client()), (3, 2));
+
+    // An archived trade no longer counts against either quota:
+    store.archive_trade_model("trade1").unwrap().unwrap();
+    assert_eq!(store.open_trade_count(), 2);
+    assert_eq!(init_trade_through_gateway(&musig, client, "trade4").await, 200);
+    assert_eq!(init_trade_through_gateway(&musig, client, "trade5").await, 429);
+
+    // The counts are rebuilt from the trades already in a store when it is wrapped afresh:
+    let inner = TradeModelMemoryStore::default();
+    for trade_id in ["trade1", "trade2"] {
+        let mut trade_model = TradeModel::new(trade_id.to_owned(), Role::SellerAsMaker);
+        trade_model.opened_by = Some("127.0.0.1".to_owned());
+        inner.add_trade_model(trade_model).unwrap();
+    }
+    inner.add_trade_model(TradeModel::new("trade3".to_owned(), Role::SellerAsMaker)).unwrap();
+    let store = Arc::new(QuotaStore::new(inner, config));
+    assert_eq!((store.open_trade_count(), store.max_open_trade_count_per_client()), (3, 2));
+    let musig = MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default());
+    assert_eq!(init_trade_through_gateway(&musig, client, "trade4").await, 429);
+}
+
+/// A service answering every call at once with an empty response.
+#[derive(Clone)]
+struct AnswerAll;
+
+impl Service<HttpRequest<BoxBody>> for AnswerAll {
+    type Response = HttpResponse<BoxBody>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: HttpRequest<BoxBody>) -> Self::Future {
+        future::ok(HttpResponse::new(tonic::body::empty_body()))
+    }
+}
+
+/// Call the given RPC through the given rate limit, as the client with the given identity,
+/// returning whether the call was let through.
+async fn rate_limited_call(service: &mut RateLimit<AnswerAll>, rpc: &str, client: &ClientIdentity) -> bool {
+    let mut req = HttpRequest::builder().uri(format!("/helloworld.MuSig/{}", rpc))
+        .body(tonic::body::empty_body()).unwrap();
+    req.extensions_mut().insert(client.clone());
+    let response = service.call(req).await.unwrap();
+    match response.headers().get("grpc-status") {
+        None => true,
+        Some(code) => {
+            assert_eq!(code, &(Code::ResourceExhausted as i32).to_string());
+            false
+        }
+    }
+}
+
+#[tokio::test]
+async fn rate_limits_are_kept_per_client_with_init_trade_apart_and_refilled_over_time() {
+    let config = RateLimitConfig { init_trade_per_min: Some(2), rpc_per_min: Some(600) };
+    let mut service = RateLimitLayer::new(config).layer(AnswerAll);
+    let client = ClientIdentity::Ip([127, 0, 0, 1].into());
+    let other_client = ClientIdentity::Cert([1; 32]);
+
+    // A client may make up to a minute's worth of calls at once, but no more:
+    let mut calls = 0;
+    while rate_limited_call(&mut service, "GetTradeState", &client).await {
+        calls += 1;
+        assert!(calls <= 601, "rate limit not enforced");
+    }
+    assert!(calls >= 600, "only {} calls let through", calls);
+
+    // Its InitTrade calls have a budget of their own, which likewise runs out:
+    assert!(rate_limited_call(&mut service, "InitTrade", &client).await);
+    assert!(rate_limited_call(&mut service, "InitTrade", &client).await);
+    assert!(!rate_limited_call(&mut service, "InitTrade", &client).await);
+    assert!(!rate_limited_call(&mut service, "GetTradeState", &client).await);
+
+    // Another client has budgets of its own:
+    assert!(rate_limited_call(&mut service, "GetTradeState", &other_client).await);
+    assert!(rate_limited_call(&mut service, "InitTrade", &other_client).await);
+
+    // The budget is refilled continuously, at 10 calls a second here:
+    time::sleep(Duration::from_millis(250)).await;
+    assert!(rate_limited_call(&mut service, "GetTradeState", &client).await);
+    assert!(rate_limited_call(&mut service, "GetTradeState", &client).await);
+    assert!(!rate_limited_call(&mut service, "InitTrade", &client).await);
+}
+
+#[tokio::test]
+async fn trades_may_only_be_called_for_by_the_client_which_opened_them() {
+    let musig = new_musig();
+    let config = Config::default();
+    let body = r#"{"tradeId": "trade", "myRole": "SELLER_AS_MAKER", "commitToNonces": false}"#;
+    let request = format!("POST /v1/InitTrade HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+    assert_eq!(gateway_request_with(&config, &musig, "127.0.0.1:4242", &request).await.0, 200);
+
+    let body = r#"{"tradeId": "trade"}"#;
+    for request in [
+        "GET /v1/trades/trade HTTP/1.1\r\n\r\n".to_owned(),
+        "GET /v1/trades/trade/audit-log HTTP/1.1\r\n\r\n".to_owned(),
+        format!("POST /v1/GetProtocolDescriptor HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body),
+        format!("POST /v1/ArchiveTrade HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body),
+    ] {
+        let (status, error) = gateway_request_with(&config, &musig, "127.0.0.2:4242", &request).await;
+        assert_eq!((status, error.get("code").and_then(Json::as_u64)), (403, Some(Code::PermissionDenied as u64)), "{}", request);
+    }
+    // The owner may call for it, from any port, with each of its calls recorded in the audit log:
+    let request = "GET /v1/trades/trade/audit-log HTTP/1.1\r\n\r\n";
+    let (status, audit_log) = gateway_request_with(&config, &musig, "127.0.0.1:4343", request).await;
+    assert_eq!(status, 200, "{}", audit_log);
+    let entries = audit_log.get("entries").and_then(Json::as_array).unwrap();
+    assert_eq!(entries[0].get("client").and_then(Json::as_str), Some("127.0.0.1"));
+
+    // Whereas a trade opened by a client of unknown identity is anyone's:
+    let client = TradeClient::new(serve(musig.clone()).await).with_retry_policy(RetryPolicy::never());
+    client.init_trade(InitTrade::new("unowned", Role::BuyerAsTaker)).await.unwrap();
+    drop(client);
+    let request = "GET /v1/trades/unowned HTTP/1.1\r\n\r\n";
+    assert_eq!(gateway_request_with(&config, &musig, "127.0.0.2:4242", request).await.0, 200);
+    assert_eq!(musig.trade_model_store.get_audit_log("unowned").unwrap()[0].client, None);
+}
+
+/// Serve the given service with gRPC-web calls let through from the given origins, returning the
+/// head of the HTTP/1.1 response to the given request (in lower case) and its body, de-chunked.
+async fn grpc_web_request(musig: MyMuSig, allowed_origins: &[&str], request: &str) -> (String, Vec<u8>) {
+    let config = GrpcWebConfig { enabled: true, allowed_origins: allowed_origins.iter().map(|&o| o.to_owned()).collect() };
+    let (mut client_io, server_io) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    tokio::spawn(Server::builder()
+        .accept_http1(true)
+        .layer(GrpcWebLayer::new(config))
+        .add_service(MuSigServer::new(musig))
+        .serve_with_incoming(stream::iter(iter::once(Ok::<_, io::Error>(server_io)))));
+    client_io.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    client_io.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    let (head, mut chunks) = response.split_once("\r\n\r\n").unwrap();
+    let head = head.to_lowercase();
+    if !head.contains("transfer-encoding: chunked") {
+        return (head, chunks.as_bytes().to_vec());
+    }
+    let mut body = Vec::new();
+    while let Some((len, rest)) = chunks.split_once("\r\n") {
+        let len = usize::from_str_radix(len, 16).unwrap();
+        body.extend_from_slice(&rest.as_bytes()[..len]);
+        chunks = &rest[len + 2..];
+    }
+    (head, body)
+}
+
+#[tokio::test]
+async fn browsers_may_call_from_allowed_origins_with_grpc_web() {
+    let musig = new_musig();
+    TradeClient::new(serve(musig.clone()).await).init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+
+    let preflight = "OPTIONS /helloworld.MuSig/GetTradeState HTTP/1.1\r\nHost: musig.test\r\nOrigin: https://dash.test\r\n\
+        Access-Control-Request-Method: POST\r\nConnection: close\r\n\r\n";
+    let (head, _) = grpc_web_request(musig.clone(), &["https://dash.test"], preflight).await;
+    assert!(head.starts_with("http/1.1 204") && head.contains("access-control-allow-origin: https://dash.test"), "{}", head);
+    let (head, _) = grpc_web_request(musig.clone(), &["https://other.test"], preflight).await;
+    assert!(!head.contains("access-control-allow-origin") && head.contains("grpc-status: 7"), "{}", head);
+
+    // The text variant, as used by browsers to read streamed responses, base64-encodes every message:
+    let message = GetTradeStateRequest { trade_id: "trade".to_owned() }.encode_to_vec();
+    let frame = [&[0][..], &u32::try_from(message.len()).unwrap().to_be_bytes(), &message].concat();
+    let body = STANDARD.encode(frame);
+    let request = format!("POST /helloworld.MuSig/GetTradeState HTTP/1.1\r\nHost: musig.test\r\nOrigin: https://dash.test\r\n\
+        Content-Type: application/grpc-web-text\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+    let (head, body) = grpc_web_request(musig, &["https://dash.test"], &request).await;
+    assert!(head.starts_with("http/1.1 200") && head.contains("content-type: application/grpc-web-text+proto"), "{}", head);
+    assert!(head.contains("access-control-expose-headers: grpc-status"), "{}", head);
+    let body = body.chunks(4).flat_map(|quad| STANDARD.decode(quad).unwrap()).collect::<Vec<_>>();
+    let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+    let state = helloworld::TradeState::decode(&body[5..5 + len]).unwrap();
+    assert_eq!(state.summary.unwrap().trade_id, "trade");
+    // The trailers come last, as a message of their own flagged as such:
+    let trailers = &body[5 + len..];
+    assert_eq!(trailers[0], 0x80);
+    assert!(String::from_utf8_lossy(&trailers[5..]).contains("grpc-status:0\r\n"));
+}
+
+#[tokio::test]
+async fn calls_past_their_server_side_timeout_fail_without_running_their_step() {
+    let rpc_timeouts = RpcTimeoutConfig { default: None, per_rpc: vec![("InitTrade".to_owned(), Some(Duration::ZERO))] };
+    let client = TradeClient::new(serve_with_rpc_timeouts(new_musig(), rpc_timeouts).await)
+        .with_retry_policy(RetryPolicy::never());
+
+    // The step is still queued for the trade actor at the deadline, which then turns it away:
+    let result = client.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await;
+    assert_eq!(code(result), Code::DeadlineExceeded);
+    tokio::task::yield_now().await;
+    assert!(client.list_trades(false).await.unwrap().is_empty());
+
+    // Other RPCs take the default, of no timeout:
+    assert!(client.get_service_info().await.is_ok());
+    drop(client);
+}
+
+#[test]
+fn deadlines_are_announced_once_as_they_approach_and_pass() {
+    let store = TradeModelMemoryStore::default();
+    let trade_model = TradeModel::builder("trade".to_owned(), Role::BuyerAsTaker).with_my_key_shares().unwrap().build();
+    store.add_trade_model(trade_model).unwrap();
+    let events = TradeEventBus::default();
+    let mut receiver = events.subscribe();
+    let config = DeadlineConfig { peer_response_timeout: Some(Duration::from_secs(100)), ..DeadlineConfig::default() };
+    let start = SystemTime::now();
+    let scan = |after_secs| deadlines::update_deadlines(&store, &events, &config, &PolicyEngine::default(), start + Duration::from_secs(after_secs), None);
+
+    let mut announced = Vec::new();
+    for after_secs in [0, 50, 80, 90, 100, 200] {
+        scan(after_secs);
+        while let Ok(event) = receiver.try_recv() {
+            match event {
+                TradeEvent::DeadlineApproaching { deadline, .. } => announced.push((after_secs, deadline.kind, "approaching")),
+                TradeEvent::DeadlinePassed { deadline, .. } => announced.push((after_secs, deadline.kind, "passed")),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+    }
+    assert_eq!(announced, [(80, DeadlineKind::PeerResponse, "approaching"), (100, DeadlineKind::PeerResponse, "passed")]);
+
+    let trade_model = store.get_trade_model("trade").unwrap();
+    let deadlines = trade_model.lock().unwrap().deadlines.clone();
+    assert_eq!(deadlines.len(), 1);
+    assert_eq!(deadlines[0].state, DeadlineState::Passed);
+    assert_eq!(deadlines[0].due.at, Some(start + Duration::from_secs(100)));
+}
+
+#[tokio::test]
+async fn trades_stuck_in_early_phases_past_their_ttl_are_aborted_but_later_ones_left_alone() {
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let buyer = TradeClient::new(serve(MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default())).await)
+        .with_retry_policy(RetryPolicy::never());
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+    buyer.init_trade(InitTrade::new("stuck", Role::BuyerAsTaker)).await.unwrap();
+
+    // Both trades outlive the TTL, but only the one yet to have its deposit tx signed is aborted,
+    // and not a trade in the same phase opened since:
+    let ttl = Duration::from_millis(200);
+    time::sleep(ttl.mul_f32(1.5)).await;
+    buyer.init_trade(InitTrade::new("fresh", Role::BuyerAsTaker)).await.unwrap();
+    let events = TradeEventBus::default();
+    let mut receiver = events.subscribe();
+    let gc = tokio::spawn(gc::collect_stale_trades(Arc::clone(&store), events, ttl, Duration::from_millis(10)));
+    let event = time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+    assert!(matches!(&event, TradeEvent::Aborted(summary) if summary.trade_id == "stuck"), "{:?}", event);
+    time::sleep(Duration::from_millis(50)).await;
+    gc.abort();
+    assert!(receiver.try_recv().is_err());
+    let live: Vec<_> = buyer.list_trades(false).await.unwrap().into_iter().map(|trade| trade.trade_id).collect();
+    assert_eq!(live, ["fresh", "trade"]);
+    drop((buyer, seller));
+    assert!(store.list_archived_trades().iter().any(|summary| summary.trade_id == "stuck"));
+}
+
+#[test]
+fn policy_publishes_warning_tx_past_missed_payment_deadline_then_claims() {
+    let now = SystemTime::now();
+    let trade_model = || {
+        let mut trade_model = TradeModel::builder("trade".to_owned(), Role::SellerAsMaker).with_my_key_shares().unwrap()
+            .build();
+        trade_model.deadlines.push(Deadline {
+            kind: DeadlineKind::Payment,
+            phase: trade_model.phase(),
+            warn_at: DeadlineDue::default(),
+            due: DeadlineDue { at: Some(now), height: Some(900_000) },
+            state: DeadlineState::Passed,
+        });
+        trade_model
+    };
+    let engine = PolicyEngine::new(PolicyConfig { warning_tx_after_blocks: Some(6), auto_claim: true, ..PolicyConfig::default() });
+    // The wall clock is held still, so that only the block heights count:
+    let actions_at = |trade_model: &mut TradeModel, height| engine.apply(trade_model, Some(10), now, Some(height))
+        .into_iter()
+        .map(|event| match event {
+            TradeEvent::PolicyAction { action, dry_run, .. } => (action.kind, dry_run),
+            event => panic!("unexpected event: {:?}", event),
+        })
+        .collect::<Vec<_>>();
+
+    let mut live = trade_model();
+    let actions: Vec<_> = [900_005, 900_006, 900_007, 900_015, 900_016, 900_100].into_iter()
+        .flat_map(|height| actions_at(&mut live, height).into_iter().map(move |action| (height, action)))
+        .collect();
+    assert_eq!(actions, [
+        (900_006, (PolicyActionKind::PublishWarningTx, false)),
+        (900_016, (PolicyActionKind::ClaimWarningTx, false)),
+    ]);
+    assert_eq!(live.policy_actions.iter().map(|action| action.kind).collect::<Vec<_>>(),
+        [PolicyActionKind::PublishWarningTx, PolicyActionKind::ClaimWarningTx]);
+
+    // A dry run announces the response once, without recording it with the trade:
+    let mut dry_run = trade_model();
+    dry_run.policy_overrides = PolicyOverrides { dry_run: Some(true), ..PolicyOverrides::default() };
+    assert_eq!(actions_at(&mut dry_run, 900_006), [(PolicyActionKind::PublishWarningTx, true)]);
+    assert_eq!(actions_at(&mut dry_run, 900_007), []);
+    assert!(dry_run.policy_actions.is_empty());
+
+    // Overriding the number of blocks with 0 turns the response off:
+    let mut off = trade_model();
+    off.policy_overrides = PolicyOverrides { warning_tx_after_blocks: Some(0), ..PolicyOverrides::default() };
+    assert_eq!(actions_at(&mut off, 900_100), []);
+}
+
+#[tokio::test]
+async fn unresponsive_peer_is_announced_once_until_seen_again() {
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let buyer = TradeClient::new(serve(MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default())).await);
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+    buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt)).await.unwrap();
+
+    // The seller was last seen in its partial signatures, as the buyer signed the deposit tx:
+    let last_seen = store.get_trade_model("trade").unwrap().lock().unwrap().peer_last_seen.unwrap();
+    let state = buyer.get_trade_state("trade").await.unwrap();
+    assert_eq!((state.peer_last_seen_millis, state.peer_unresponsive), (Some(convert::to_millis(last_seen)), false));
+
+    let events = TradeEventBus::default();
+    let mut receiver = events.subscribe();
+    let config = DeadlineConfig { peer_unresponsive_after: Some(Duration::from_mins(30)), ..DeadlineConfig::default() };
+    let policy = PolicyEngine::new(PolicyConfig { warning_tx_on_unresponsive_peer: true, ..PolicyConfig::default() });
+    let mut scan = |after_mins| {
+        deadlines::update_deadlines(&*store, &events, &config, &policy, last_seen + Duration::from_mins(after_mins), None);
+        iter::from_fn(|| receiver.try_recv().ok()).collect::<Vec<_>>()
+    };
+    assert!(scan(29).is_empty());
+    let announced = scan(30);
+    assert!(matches!(announced.as_slice(), [
+        TradeEvent::PeerUnresponsive { last_seen: Some(at), .. },
+        TradeEvent::PolicyAction { action: PolicyAction { kind: PolicyActionKind::PublishWarningTx, .. }, dry_run: false, .. },
+    ] if *at == last_seen), "unexpected events: {:?}", announced);
+    assert!(scan(60).is_empty());
+    assert!(buyer.get_trade_state("trade").await.unwrap().peer_unresponsive);
+
+    // Seeing the peer again, in its private key share, clears the flag:
+    buyer.confirm_payment_started("trade", None).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    seller.confirm_payment_received("trade", None).await.unwrap();
+    let swap_tx = seller.sign_swap_tx(SignSwapTx::new("trade").peers_partial_signatures(&buyer_sigs)).await.unwrap();
+    buyer.close_trade(CloseTrade::new("trade").peers_prv_key_share(&swap_tx.peer_output_prv_key_share)).await.unwrap();
+    assert!(!buyer.get_trade_state("trade").await.unwrap().peer_unresponsive);
+    assert!(store.get_trade_model("trade").unwrap().lock().unwrap().peer_last_seen > Some(last_seen));
+    drop((buyer, seller));
+}
+
+#[tokio::test]
+async fn completed_steps_are_timed_in_trade_state_and_duration_histograms() {
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let buyer = TradeClient::new(serve(MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default())).await);
+    let seller = spawn_client().await;
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    seller.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    // A failed step is not taken as completed:
+    assert!(buyer.close_trade(CloseTrade::new("trade")).await.is_err());
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+    buyer.publish_deposit_tx(PublishDepositTx::new("trade").deposit_psbt(deposit_psbt)).await.unwrap();
+
+    let state = buyer.get_trade_state("trade").await.unwrap();
+    assert_eq!(state.step_completions.iter().map(|completion| &completion.step[..]).collect::<Vec<_>>(),
+        ["GetNonceShares", "GetPartialSignatures", "SignDepositTx", "PublishDepositTx"]);
+    let trade_model = store.get_trade_model("trade").unwrap();
+    let trade_model = lock_trade_model(&trade_model);
+    let created_at = convert::to_millis(trade_model.created_at().unwrap());
+    assert!(state.step_completions.iter().all(|completion| completion.at_millis >= created_at));
+    assert!(state.step_completions.is_sorted_by_key(|completion| completion.at_millis));
+
+    // Only the steps reaching a milestone are observed, into the bucket of the time taken:
+    let durations = TradeDurations::new();
+    for step in ["GetNonceShares", "GetPartialSignatures", "PublishDepositTx"] {
+        durations.observe(&trade_model, step);
+    }
+    drop(trade_model);
+    let mut out = String::new();
+    durations.write(&mut out);
+    for line in ["musig_trade_nonce_exchange_seconds_bucket{le=\"1\"} 1", "musig_trade_nonce_exchange_seconds_count 1",
+        "musig_trade_deposit_confirmation_seconds_bucket{le=\"+Inf\"} 1", "musig_trade_close_seconds_bucket{le=\"+Inf\"} 0",
+        "musig_trade_close_seconds_count 0"]
+    {
+        assert!(out.lines().any(|l| l == line), "missing {:?} in:\n{}", line, out);
+    }
+    drop((buyer, seller));
+}
+
+#[tokio::test]
+async fn prepared_txs_are_exported_fully_signed_once_deposit_tx_is_signed() {
+    let (buyer, seller) = (spawn_client().await, spawn_client().await);
+    let buyer_keys = buyer.init_trade(InitTrade::new("trade", Role::BuyerAsTaker)).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new("trade", Role::SellerAsMaker)).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares("trade", &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares("trade", &buyer_keys)).await.unwrap();
+    buyer.get_partial_signatures(GetPartialSignatures::new("trade").peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new("trade")
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    assert_eq!(code(buyer.export_prepared_tx_package("trade").await), Code::FailedPrecondition);
+    assert_eq!(code(buyer.export_prepared_tx_package("other trade").await), Code::NotFound);
+
+    buyer.sign_deposit_tx(SignDepositTx::new("trade").peers_partial_signatures(&seller_sigs)).await.unwrap();
+    let exported = buyer.export_prepared_tx_package("trade").await.unwrap();
+    assert_eq!(exported.identity_pub_key.len(), 33);
+    assert_eq!(exported.identity_signature.len(), 64);
+    let package = helloworld::PreparedTxPackage::decode(&exported.package[..]).unwrap();
+    assert_eq!((package.version, &package.trade_id[..], package.my_role()), (1, "trade", helloworld::Role::BuyerAsTaker));
+    for input in [&package.warning_tx_buyer_input, &package.warning_tx_seller_input, &package.redirect_tx_input] {
+        let input = input.as_ref().unwrap();
+        let pub_key = Point::lift_x(&input.pub_key[..].try_into().unwrap()).unwrap();
+        let signature = LiftedSignature::from_bytes(&input.signature).unwrap();
+        musig2::verify_single(pub_key, signature, &input.message).unwrap();
+    }
+
+    // Our warning tx's escrow output has a claim path with our key share for our own output:
+    let claim_key = buyer_keys.buyer_output_pub_key_share.serialize_xonly();
+    let claim_key = claim_key.iter().fold(String::new(), |mut hex, b| {
+        write!(hex, "{:02x}", b).unwrap();
+        hex
+    });
+    let descriptor = package.claim_descriptor.unwrap();
+    assert!(descriptor.starts_with("tr("), "{}", descriptor);
+    assert!(descriptor.contains(&format!(",and_v(v:pk({}),older(720)))#", claim_key)), "{}", descriptor);
+    drop((buyer, seller));
+}
+
+#[tokio::test]
+async fn services_are_served_at_once_on_every_listener_each_with_its_own_auth() {
+    let dir = std::env::temp_dir().join(format!("musig-listeners-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("admin.sock");
+    std::env::set_var("MUSIG_LISTENERS_TEST_TOKEN", "listener token");
+    let config = Config::parse(&format!("
+        listener.guarded.addr = 127.0.0.1:0
+        listener.guarded.token_env = MUSIG_LISTENERS_TEST_TOKEN
+        listener.admin.addr = unix:{}
+        listener.admin.service = admin
+    ", socket.display())).unwrap();
+    // An onion listener needs an onion service, and serves the peer service over TCP:
+    assert!(matches!(Config::parse("listener.x.addr = 127.0.0.1:0\nlistener.x.onion = true"),
+        Err(ConfigError::InvalidListener(name, _)) if name == "x"));
+    assert!(matches!(Config::parse("listener.x.service = peer"), Err(ConfigError::InvalidListener(..))));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let mut listeners = vec![];
+    for listener_config in &config.listeners {
+        listeners.push(Listener::bind(&listener_config.addr).await.unwrap());
+    }
+    let guarded_url = format!("http://{}", listeners[0].tcp_addr().unwrap());
+    let calls = async {
+        MuSigClient::connect(url).await.unwrap().get_service_info(helloworld::GetServiceInfoRequest {}).await.unwrap();
+
+        let mut guarded = MuSigClient::connect(guarded_url).await.unwrap();
+        let result = guarded.get_service_info(helloworld::GetServiceInfoRequest {}).await;
+        assert_eq!(result.unwrap_err().code(), Code::Unauthenticated);
+        guarded.get_service_info(with_admin_token(helloworld::GetServiceInfoRequest {}, "listener token")).await.unwrap();
+        drop(guarded);
+
+        // As the admin socket is only reachable locally, it needs no token:
+        let channel = Endpoint::from_static("http://musig.test")
+            .connect_with_connector(UnixConnector(socket.clone())).await.unwrap();
+        AdminClient::new(channel).get_stats(GetStatsRequest {}).await.unwrap();
+    };
+    tokio::select! {
+        result = crate::serve_on(&config, TradeModelMemoryStore::default(), listener, None, listeners) =>
+            panic!("daemon stopped: {:?}", result.map_err(|e| e.to_string())),
+        () = calls => {}
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn client_is_known_by_its_cert_over_mtls_else_by_its_ip() {
+    let cert = CertificateDer::from(b"client cert".to_vec());
+    let addr = "192.0.2.1:4242".parse().unwrap();
+    let identity = ClientIdentity::of_connection(Some(std::slice::from_ref(&cert)), Some(addr)).unwrap();
+    assert_eq!(identity, ClientIdentity::of_connection(Some(&[cert]), None).unwrap());
+    assert!(identity.to_string().starts_with("cert:"));
+    assert_eq!(identity.to_string().len(), "cert:".len() + 64);
+
+    let identity = ClientIdentity::of_connection(Some(&[]), Some(addr)).unwrap();
+    assert_eq!(identity.to_string(), "192.0.2.1");
+    assert_eq!(ClientIdentity::of_connection(None, None), None);
+
+    // A client CA is only of use with a TLS identity to serve:
+    assert!(matches!(Config::parse("listener.x.addr = 127.0.0.1:0\nlistener.x.tls_client_ca_file = ca.pem"),
+        Err(ConfigError::InvalidListener(..))));
+}
+
+#[test]
+fn recovered_key_shares_are_only_written_to_an_explicit_private_output_file() {
+    let dir = std::env::temp_dir().join(format!("musig-backup-test-{}", std::process::id()));
+    let recipient_keys: Vec<_> = (0..3).map(|_| Scalar::random(&mut rand::thread_rng())).collect();
+    let recipients = recipient_keys.iter().map(Scalar::base_point_mul).collect();
+    let backup = KeyShareBackup::open(&BackupConfig { dir: dir.clone(), recipients, threshold: 2 }).unwrap();
+    let key_shares: Vec<_> = (0..2).map(|_| Scalar::random(&mut rand::thread_rng()))
+        .map(|prv_key| (prv_key.base_point_mul(), prv_key))
+        .collect();
+    backup.back_up("trade", &key_shares).unwrap();
+    let backup_file = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+
+    let (trade_id, recovered) = backup::recover(&fs::read(&backup_file).unwrap(), &recipient_keys[1..]).unwrap();
+    assert_eq!(trade_id, "trade");
+    let output = dir.join("key_shares.txt");
+    backup::write_key_shares(&output, &recovered).unwrap();
+    let expected: Vec<_> = key_shares.iter().map(|(pub_key, prv_key)| format!("{:x} {:x}", pub_key, prv_key)).collect();
+    assert_eq!(fs::read_to_string(&output).unwrap().lines().collect::<Vec<_>>(), expected);
+    #[cfg(unix)]
+    assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&output).unwrap().permissions()) & 0o777, 0o600);
+
+    let args = |args: &[&str]| args.iter().map(|&arg| arg.to_owned()).collect::<Vec<_>>().into_iter();
+    assert!(matches!(Config::from_args(args(&["recover-key-shares", "a.backup", "--output", "out.txt"])),
+        Ok((_, Command::RecoverKeyShares(path, output))) if path == Path::new("a.backup") && output == Path::new("out.txt")));
+    assert!(matches!(Config::from_args(args(&["recover-key-shares", "a.backup"])), Err(ConfigError::MissingOutputArg(_))));
+    assert!(matches!(Config::from_args(args(&["recover-key-shares", "a.backup", "out.txt"])), Err(ConfigError::MissingOutputArg(_))));
+    fs::remove_dir_all(&dir).unwrap();
+}
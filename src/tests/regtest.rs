@@ -0,0 +1,394 @@
+//! End-to-end tests of whole trades against a `bitcoind` in regtest mode, built with the
+//! `regtest-tests` feature, and skipped if there is no `bitcoind` on the `PATH` to launch.
+//!
+//! Each party's funding input is a real P2TR output, mined on the regtest chain, and the deposit tx
+//! is built on it from the payout scripts of the trade and broadcast, before the outputs are swept
+//! with the private keys aggregated by the daemons once the trade closes. The prepared txs and the
+//! swap tx are still stand-ins, signing dummy messages, so they are not put on the chain: on a swap
+//! tx close, only the buyer's payout is swept, with the key recovered from the swap tx signature.
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use musig_trade_client::{CloseTrade, GetPartialSignatures, InitTrade, PublishDepositTx, RetryPolicy, SignDepositTx,
+    SignSwapTx, TradeClient};
+use musig_trade_protocol::{funding_input_ownership_message, lock_trade_model, AssembledDepositTx, FundingInput,
+    LocalSigner, Role, TradeModelMemoryStore, TradeModelStore as _};
+use musig_trade_protocol::tx_outputs::TxOutput;
+use secp::{Point, Scalar};
+use sha2::{Digest as _, Sha256};
+use std::fmt::Write as _;
+use std::fs;
+use std::iter;
+use std::path::PathBuf;
+use std::prelude::rust_2021::*;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+use super::{get_nonce_shares, serve, unhex};
+use crate::http;
+use crate::json::{self, Json};
+use crate::MyMuSig;
+
+const RPC_AUTH: &str = "musig:musig";
+/// How many times to try reaching the RPC server of a freshly launched `bitcoind`, and how long to
+/// wait in between.
+const STARTUP_ATTEMPTS: u32 = 300;
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The prefix of a regtest (or testnet) WIF private key.
+const WIF_PREFIX: u8 = 0xef;
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// The funding of each party's half of the deposit tx, in sats, from a single output of its own.
+const BUYERS_FUNDING: u64 = 100_000;
+const SELLERS_FUNDING: u64 = 300_000;
+/// The virtual size of the deposit tx: two P2TR key path inputs, and four P2TR outputs (the payouts
+/// and the change of each party), to pay the deposit tx fee rate agreed on.
+const DEPOSIT_TX_VSIZE: u64 = 298;
+
+/// A `bitcoind` in regtest mode, with its own data directory and ports, which is killed (and its
+/// data directory removed) once dropped.
+struct Bitcoind {
+    process: Child,
+    data_dir: PathBuf,
+    url: String,
+}
+
+impl Bitcoind {
+    /// Launch a `bitcoind` in regtest mode, waiting for its RPC server to come up, unless there is
+    /// no `bitcoind` on the `PATH`.
+    async fn launch() -> Option<Self> {
+        Command::new("bitcoind").arg("-version").stdout(Stdio::null()).status().ok()?;
+        let data_dir = std::env::temp_dir().join(format!("musig-regtest-{}", process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(&data_dir).unwrap();
+        let rpc_port = free_port();
+        let process = Command::new("bitcoind")
+            .args(["-regtest", "-server", "-listen=0", "-fallbackfee=0.0002", "-printtoconsole=0"])
+            .arg(format!("-datadir={}", data_dir.display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg(format!("-rpcuser={}", RPC_AUTH.split_once(':').unwrap().0))
+            .arg(format!("-rpcpassword={}", RPC_AUTH.split_once(':').unwrap().1))
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let bitcoind = Self { process, data_dir, url: format!("http://127.0.0.1:{}", rpc_port) };
+        for _ in 0..STARTUP_ATTEMPTS {
+            if matches!(bitcoind.try_rpc(None, "getblockchaininfo", "").await, Ok((200, _))) {
+                return Some(bitcoind);
+            }
+            time::sleep(STARTUP_POLL_INTERVAL).await;
+        }
+        panic!("bitcoind did not come up in time");
+    }
+
+    async fn try_rpc(&self, wallet: Option<&str>, method: &str, params: &str) -> std::io::Result<(u16, String)> {
+        let url = wallet.map_or_else(|| self.url.clone(), |wallet| format!("{}/wallet/{}", self.url, wallet));
+        let auth = format!("Basic {}", STANDARD.encode(RPC_AUTH));
+        let body = format!(r#"{{"jsonrpc":"1.0","id":"test","method":"{}","params":[{}]}}"#, method, params);
+        http::request("POST", &url, &[("Authorization", &auth), ("Content-Type", "application/json")],
+            body.as_bytes(), None).await
+    }
+
+    /// Call the given RPC method (of the given wallet, if any) with the given JSON params (without
+    /// the enclosing brackets), returning its result.
+    async fn rpc(&self, wallet: Option<&str>, method: &str, params: &str) -> Json {
+        let (_, reply) = self.try_rpc(wallet, method, params).await.unwrap();
+        let reply = json::parse(&reply).unwrap_or_else(|e| panic!("malformed reply to {}: {}", method, e));
+        assert_eq!(reply.get("error"), Some(&Json::Null), "{} failed: {}", method, reply);
+        reply.get("result").unwrap().clone()
+    }
+
+    /// Create a descriptor wallet of the given name, holding no keys unless it is to mine.
+    async fn create_wallet(&self, name: &str, blank: bool) {
+        self.rpc(None, "createwallet", &format!(r#""{}",false,{}"#, name, blank)).await;
+    }
+
+    async fn mine(&self, blocks: u32, miner_address: &str) {
+        // A few blocks at a time, to keep each reply short:
+        let mut left = blocks;
+        while left > 0 {
+            let batch = left.min(20);
+            self.rpc(None, "generatetoaddress", &format!(r#"{},"{}""#, batch, miner_address)).await;
+            left -= batch;
+        }
+    }
+
+    async fn with_checksum(&self, descriptor: &str) -> String {
+        let info = self.rpc(None, "getdescriptorinfo", &format!(r#""{}""#, descriptor)).await;
+        format!("{}#{}", descriptor, info.get("checksum").unwrap().as_str().unwrap())
+    }
+
+    /// The address of a P2TR output to the given x-only key as it is, with no taproot tweak, as are
+    /// the funding inputs and payout outputs of a trade.
+    async fn raw_tr_address(&self, xonly_key: &[u8]) -> String {
+        let descriptor = self.with_checksum(&format!("rawtr({})", hex(xonly_key))).await;
+        let addresses = self.rpc(None, "deriveaddresses", &format!(r#""{}""#, descriptor)).await;
+        addresses.as_array().unwrap()[0].as_str().unwrap().to_owned()
+    }
+
+    /// Import the given private key into the given wallet, to spend any P2TR output to it as it is.
+    async fn import_key(&self, wallet: &str, key: Scalar) {
+        let descriptor = self.with_checksum(&format!("rawtr({})", wif(key))).await;
+        let results = self.rpc(Some(wallet), "importdescriptors",
+            &format!(r#"[{{"desc":"{}","timestamp":0}}]"#, descriptor)).await;
+        assert_eq!(results.as_array().unwrap()[0].get("success"), Some(&Json::Bool(true)), "{}", results);
+    }
+
+    /// Pay the given amount from the mining wallet to an output of the given key, returning the
+    /// outpoint, with its txid as it is serialized in txs (that is, byte-reversed from its hex).
+    async fn fund(&self, key: Point, amount: u64) -> ([u8; 32], u32) {
+        let address = self.raw_tr_address(&key.serialize_xonly()).await;
+        let txid = self.rpc(Some("miner"), "sendtoaddress", &format!(r#""{}",{}"#, address, btc(amount))).await;
+        let txid = txid.as_str().unwrap().to_owned();
+        let tx = self.rpc(Some("miner"), "gettransaction", &format!(r#""{}""#, txid)).await;
+        let vout = tx.get("details").unwrap().as_array().unwrap().iter()
+            .find(|detail| detail.get("address").and_then(Json::as_str) == Some(&address))
+            .and_then(|detail| detail.get("vout")?.as_u64())
+            .unwrap();
+        (txid_bytes(&txid), u32::try_from(vout).unwrap())
+    }
+
+    /// The number of confirmations of the given output, if unspent.
+    async fn confirmations(&self, txid: &str, vout: u32) -> Option<u64> {
+        let tx_out = self.rpc(None, "gettxout", &format!(r#""{}",{}"#, txid, vout)).await;
+        tx_out.get("confirmations").and_then(Json::as_u64)
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+        write!(hex, "{:02x}", b).unwrap();
+        hex
+    })
+}
+
+/// The given txid hex (as shown by `bitcoind`) as it is serialized in txs.
+fn txid_bytes(txid: &str) -> [u8; 32] {
+    let mut bytes: [u8; 32] = unhex(txid).try_into().unwrap();
+    bytes.reverse();
+    bytes
+}
+
+fn txid_hex(txid: &[u8; 32]) -> String {
+    hex(&txid.iter().rev().copied().collect::<Vec<_>>())
+}
+
+/// The given amount in sats, in BTC, as `bitcoind` takes it.
+fn btc(sats: u64) -> String {
+    format!("{}.{:08}", sats / 100_000_000, sats % 100_000_000)
+}
+
+/// The given amount in BTC, as `bitcoind` gives it, in sats.
+fn sats(btc: &Json) -> u64 {
+    let Json::Number(btc) = btc else { panic!("expected an amount, got: {}", btc) };
+    let (whole, fraction) = btc.split_once('.').unwrap_or((btc, ""));
+    whole.parse::<u64>().unwrap() * 100_000_000 + format!("{:0<8}", fraction).parse::<u64>().unwrap()
+}
+
+/// The given private key in the wallet import format of regtest, for a compressed pubkey.
+fn wif(key: Scalar) -> String {
+    base58_check(&[&[WIF_PREFIX][..], &key.serialize(), &[1]].concat())
+}
+
+fn base58_check(payload: &[u8]) -> String {
+    let checksum = Sha256::digest(Sha256::digest(payload));
+    let data = [payload, &checksum[..4]].concat();
+    // The base 58 digits of the data, least significant first:
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data {
+        let mut carry = u32::from(byte);
+        for digit in &mut digits {
+            carry += u32::from(*digit) << 8;
+            *digit = u8::try_from(carry % 58).unwrap();
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push(u8::try_from(carry % 58).unwrap());
+            carry /= 58;
+        }
+    }
+    let leading_zeros = data.iter().take_while(|&&b| b == 0).count();
+    iter::repeat_n(b'1', leading_zeros)
+        .chain(digits.iter().rev().map(|&digit| BASE58_ALPHABET[usize::from(digit)]))
+        .map(char::from)
+        .collect()
+}
+
+/// A party's single funding input to the trade with the given ID: a fresh output to a key of its
+/// own (imported into the funding wallet, to sign the deposit tx with), with its ownership proof.
+async fn funding_input(bitcoind: &Bitcoind, trade_id: &str, amount: u64) -> FundingInput {
+    let key = Scalar::random(&mut rand::thread_rng());
+    bitcoind.import_key("funding", key).await;
+    let (txid, vout) = bitcoind.fund(key.base_point_mul(), amount).await;
+    let mut input = FundingInput {
+        txid, vout, amount, owner_pub_key: key.base_point_mul(),
+        ownership_proof: musig2::sign_solo(key, [0; 32], rand::random::<[u8; 32]>()),
+    };
+    let message = funding_input_ownership_message(trade_id, &input.script_pub_key(), &txid, vout, amount);
+    input.ownership_proof = musig2::sign_solo(key, message, rand::random::<[u8; 32]>());
+    input
+}
+
+async fn spawn_party() -> (TradeClient, Arc<TradeModelMemoryStore>) {
+    let store = Arc::new(TradeModelMemoryStore::default());
+    let musig = MyMuSig::new(Arc::clone(&store), Arc::new(LocalSigner), None, Arc::default());
+    (TradeClient::new(serve(musig).await).with_retry_policy(RetryPolicy::never()), store)
+}
+
+/// Build the deposit tx of the given trade, spending both parties' funding inputs, with each
+/// party's change going back to the mining wallet, then check it against the buyer's trade model
+/// and broadcast it, returning its txid & the vouts of the buyer's & seller's payouts.
+async fn publish_deposit_tx(bitcoind: &Bitcoind, store: &TradeModelMemoryStore, trade_id: &str,
+                            inputs: [&FundingInput; 2]) -> (String, [u32; 2])
+{
+    let trade_model = store.get_trade_model(trade_id).unwrap();
+    let (payout_scripts, payout_amounts, fee_rate) = {
+        let trade_model = lock_trade_model(&trade_model);
+        let amounts = [true, false].map(|buyer| trade_model.funding_contribution(buyer).unwrap());
+        (trade_model.deposit_tx_payout_scripts().unwrap(), amounts, trade_model.deposit_tx_fee_rate.unwrap())
+    };
+    #[expect(clippy::cast_possible_truncation, clippy::cast_precision_loss, clippy::cast_sign_loss, reason = "the fee is far below 2^52")]
+    let fee_share = (fee_rate * DEPOSIT_TX_VSIZE as f64 / 2.0).ceil() as u64;
+    let mut outputs = Vec::new();
+    for (script, amount) in payout_scripts.iter().zip(payout_amounts) {
+        outputs.push((bitcoind.raw_tr_address(&script[2..]).await, amount));
+    }
+    for (input, payout_amount) in inputs.iter().zip(payout_amounts) {
+        let change_address = bitcoind.rpc(Some("miner"), "getnewaddress", r#""","bech32m""#).await;
+        outputs.push((change_address.as_str().unwrap().to_owned(), input.amount - payout_amount - fee_share));
+    }
+    let inputs_json: Vec<_> = inputs.iter()
+        .map(|input| format!(r#"{{"txid":"{}","vout":{}}}"#, txid_hex(&input.txid), input.vout))
+        .collect();
+    let outputs_json: Vec<_> = outputs.iter()
+        .map(|(address, amount)| format!(r#"{{"{}":{}}}"#, address, btc(*amount)))
+        .collect();
+    let unsigned_tx = bitcoind.rpc(None, "createrawtransaction",
+        &format!("[{}],[{}]", inputs_json.join(","), outputs_json.join(","))).await;
+    let signed = bitcoind.rpc(Some("funding"), "signrawtransactionwithwallet", &unsigned_tx.to_string()).await;
+    assert_eq!(signed.get("complete"), Some(&Json::Bool(true)), "{}", signed);
+    let signed_tx = signed.get("hex").unwrap().to_string();
+
+    // The tx as broadcast must pass the daemon's own re-validation, down to its fee rate:
+    let decoded = bitcoind.rpc(None, "decoderawtransaction", &signed_tx).await;
+    let vouts = decoded.get("vout").unwrap().as_array().unwrap();
+    let assembled = AssembledDepositTx {
+        inputs: inputs.iter().map(|input| (input.txid, input.vout, input.amount)).collect(),
+        outputs: vouts.iter().map(|output| TxOutput {
+            script_pub_key: unhex(output.get("scriptPubKey").unwrap().get("hex").unwrap().as_str().unwrap()),
+            amount: sats(output.get("value").unwrap()),
+        }).collect(),
+        vsize: decoded.get("vsize").unwrap().as_u64(),
+    };
+    lock_trade_model(&trade_model).check_deposit_tx(&assembled).unwrap();
+    let payout_vouts = payout_scripts.map(|script| {
+        let vout = assembled.outputs.iter().position(|output| output.script_pub_key == script).unwrap();
+        u32::try_from(vout).unwrap()
+    });
+    let txid = bitcoind.rpc(None, "sendrawtransaction", &signed_tx).await;
+    (txid.as_str().unwrap().to_owned(), payout_vouts)
+}
+
+/// Sweep every output to the given key into the mining wallet, from a wallet of its own of the
+/// given name, returning the txid of the sweep.
+async fn sweep(bitcoind: &Bitcoind, wallet: &str, key: Scalar, miner_address: &str) -> String {
+    bitcoind.create_wallet(wallet, true).await;
+    bitcoind.import_key(wallet, key).await;
+    let result = bitcoind.rpc(Some(wallet), "sendall", &format!(r#"["{}"]"#, miner_address)).await;
+    assert_eq!(result.get("complete"), Some(&Json::Bool(true)), "{}", result);
+    result.get("txid").unwrap().as_str().unwrap().to_owned()
+}
+
+/// Run a whole trade with the given ID, funded from the regtest chain, through the confirmation
+/// of its deposit tx to its close (cooperatively or via the swap tx), then sweep its payouts with
+/// the private keys the daemons end up with.
+async fn trade_on_regtest(bitcoind: &Bitcoind, miner_address: &str, trade_id: &str, close_via_swap_tx: bool) {
+    let ((buyer, buyer_store), (seller, seller_store)) = (spawn_party().await, spawn_party().await);
+    let buyer_input = funding_input(bitcoind, trade_id, BUYERS_FUNDING).await;
+    let seller_input = funding_input(bitcoind, trade_id, SELLERS_FUNDING).await;
+    bitcoind.mine(1, miner_address).await;
+
+    let buyer_keys = buyer.init_trade(InitTrade::new(trade_id, Role::BuyerAsTaker)
+        .funding_inputs(std::slice::from_ref(&buyer_input))).await.unwrap();
+    let seller_keys = seller.init_trade(InitTrade::new(trade_id, Role::SellerAsMaker)
+        .funding_inputs(std::slice::from_ref(&seller_input))).await.unwrap();
+    let buyer_nonces = buyer.get_nonce_shares(get_nonce_shares(trade_id, &seller_keys)).await.unwrap();
+    let seller_nonces = seller.get_nonce_shares(get_nonce_shares(trade_id, &buyer_keys)).await.unwrap();
+    let buyer_sigs = buyer.get_partial_signatures(GetPartialSignatures::new(trade_id)
+        .peers_nonce_shares(&seller_nonces)).await.unwrap();
+    let seller_sigs = seller.get_partial_signatures(GetPartialSignatures::new(trade_id)
+        .peers_nonce_shares(&buyer_nonces)).await.unwrap();
+    seller.sign_deposit_tx(SignDepositTx::new(trade_id).peers_partial_signatures(&buyer_sigs.redacted())).await.unwrap();
+    let deposit_psbt = buyer.sign_deposit_tx(SignDepositTx::new(trade_id).peers_partial_signatures(&seller_sigs))
+        .await.unwrap();
+
+    let (deposit_txid, [buyer_vout, seller_vout]) =
+        publish_deposit_tx(bitcoind, &buyer_store, trade_id, [&buyer_input, &seller_input]).await;
+    bitcoind.mine(1, miner_address).await;
+    for vout in [buyer_vout, seller_vout] {
+        assert_eq!(bitcoind.confirmations(&deposit_txid, vout).await, Some(1));
+    }
+    let mut confirmations = buyer.publish_deposit_tx(PublishDepositTx::new(trade_id).deposit_psbt(deposit_psbt))
+        .await.unwrap();
+    while confirmations.message().await.unwrap().is_some() {}
+
+    buyer.confirm_payment_started(trade_id, None).await.unwrap();
+    seller.confirm_payment_received(trade_id, None).await.unwrap();
+    let swap_tx = seller.sign_swap_tx(SignSwapTx::new(trade_id).peers_partial_signatures(&buyer_sigs)).await.unwrap();
+    if close_via_swap_tx {
+        seller.close_trade(CloseTrade::new(trade_id)).await.unwrap();
+        buyer.close_trade(CloseTrade::new(trade_id).swap_tx(swap_tx.swap_tx)).await.unwrap();
+    } else {
+        let buyer_key_share = buyer.close_trade(CloseTrade::new(trade_id)
+            .peers_prv_key_share(&swap_tx.peer_output_prv_key_share)).await.unwrap();
+        seller.close_trade(CloseTrade::new(trade_id).peers_prv_key_share(&buyer_key_share)).await.unwrap();
+    }
+
+    // The seller's payout would be spent by the swap tx, which is still a stand-in:
+    let payouts = if close_via_swap_tx {
+        vec![(buyer_store, buyer_vout, "buyer")]
+    } else {
+        vec![(buyer_store, buyer_vout, "buyer"), (seller_store, seller_vout, "seller")]
+    };
+    for (store, vout, party) in payouts {
+        let key = {
+            let trade_model = store.get_trade_model(trade_id).unwrap();
+            let mut trade_model = lock_trade_model(&trade_model);
+            *trade_model.aggregate_private_keys_for_my_output().unwrap()
+        };
+        let sweep_txid = sweep(bitcoind, &format!("{}-{}-payout", trade_id, party), key, miner_address).await;
+        bitcoind.mine(1, miner_address).await;
+        assert_eq!(bitcoind.confirmations(&deposit_txid, vout).await, None, "{}'s payout left unspent", party);
+        assert_eq!(bitcoind.confirmations(&sweep_txid, 0).await, Some(1));
+    }
+}
+
+#[tokio::test]
+async fn trade_payouts_are_spendable_by_aggregated_keys_on_regtest() {
+    let Some(bitcoind) = Bitcoind::launch().await else {
+        eprintln!("Skipping the regtest test, as there is no bitcoind on the PATH");
+        return;
+    };
+    bitcoind.create_wallet("miner", false).await;
+    bitcoind.create_wallet("funding", true).await;
+    let miner_address = bitcoind.rpc(Some("miner"), "getnewaddress", "").await.as_str().unwrap().to_owned();
+    // Enough blocks for the first coinbase to mature:
+    bitcoind.mine(101, &miner_address).await;
+
+    trade_on_regtest(&bitcoind, &miner_address, "cooperative-trade", false).await;
+    trade_on_regtest(&bitcoind, &miner_address, "swap-tx-trade", true).await;
+}
@@ -0,0 +1,125 @@
+//! The deadlines of the calls to the `MuSig` service: the earlier of that set by the client (by the
+//! `grpc-timeout` header of the call) and the configured server-side timeout of the RPC, if either.
+//! A call still running at its deadline fails with `DEADLINE_EXCEEDED`, and its work is checked
+//! against the deadline before each long operation (a queued protocol step or blocking task), so
+//! that none is started once the client has given up on the call.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::config::RpcTimeoutConfig;
+
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+tokio::task_local! {
+    static CALL_DEADLINE: Instant;
+}
+
+/// The deadline of the call being served by the current task, if it has one.
+pub fn call_deadline() -> Option<Instant> {
+    CALL_DEADLINE.try_with(|&deadline| deadline).ok()
+}
+
+/// Run the given future as serving a call with the given deadline, without enforcing it.
+pub async fn with_call_deadline<F: Future>(deadline: Instant, f: F) -> F::Output {
+    CALL_DEADLINE.scope(deadline, f).await
+}
+
+/// Check that the given call deadline (if any) hasn't passed, before starting on some long work.
+///
+/// # Errors
+///
+/// Fails with `DEADLINE_EXCEEDED` if it has.
+pub fn check_deadline(deadline: Option<Instant>, work: &str) -> Result<(), Status> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(Status::deadline_exceeded(format!(
+            "call deadline exceeded before {} could be started", work))),
+        _ => Ok(()),
+    }
+}
+
+/// Parse the value of a `grpc-timeout` header: at most 8 digits followed by a unit.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (digits, unit) = value.split_at_checked(value.len().checked_sub(1)?)?;
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_hours(n),
+        "M" => Duration::from_mins(n),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+/// A layer setting the deadline of each call, from its `grpc-timeout` header & the configured
+/// server-side timeouts.
+#[derive(Clone)]
+pub struct TimeoutLayer(Arc<RpcTimeoutConfig>);
+
+impl TimeoutLayer {
+    pub fn new(config: RpcTimeoutConfig) -> Self {
+        Self(Arc::new(config))
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Timeout { inner, config: Arc::clone(&self.0) }
+    }
+}
+
+/// A service running each call with its deadline (if any) set for the task serving it, failing the
+/// call with `DEADLINE_EXCEEDED` should it still be running at the deadline. A malformed
+/// `grpc-timeout` header is ignored, as by tonic itself.
+#[derive(Clone)]
+pub struct Timeout<S> {
+    inner: S,
+    config: Arc<RpcTimeoutConfig>,
+}
+
+impl<S, B> Service<Request<B>> for Timeout<S>
+    where S: Service<Request<B>, Response=Response<BoxBody>>,
+          S::Future: Send + 'static
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let client_timeout = req.headers().get(GRPC_TIMEOUT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_grpc_timeout);
+        let rpc = req.uri().path().rsplit('/').next().unwrap_or_default();
+        let timeout = [client_timeout, self.config.timeout(rpc)].into_iter().flatten().min();
+        let response = self.inner.call(req);
+        let Some(timeout) = timeout else { return Box::pin(response) };
+        let deadline = Instant::now() + timeout;
+        Box::pin(with_call_deadline(deadline, async move {
+            match time::timeout_at(deadline.into(), response).await {
+                Ok(response) => response,
+                Err(_) => Ok(Status::deadline_exceeded("call deadline exceeded").into_http()),
+            }
+        }))
+    }
+}
@@ -0,0 +1,183 @@
+//! Routing of our outbound connections through Tor (or any other SOCKS5 proxy), and publishing of
+//! the peer service as a Tor onion service, so that a node needn't reveal its IP address to its
+//! peers, or to anyone watching its traffic.
+
+use hyper_util::rt::TokioIo;
+use std::fmt::Write as _;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::prelude::rust_2021::*;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::TcpStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower_service::Service;
+
+use crate::config::OnionServiceConfig;
+use crate::file_store::write_atomically;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+fn proxy_error(msg: String) -> io::Error {
+    io::Error::other(msg)
+}
+
+/// A SOCKS5 proxy to make outbound connections through, such as the SOCKS port of a Tor daemon.
+#[derive(Clone, Copy, Debug)]
+pub struct Socks5Proxy {
+    pub addr: SocketAddr,
+}
+
+impl Socks5Proxy {
+    /// Connect to the given host & port through the proxy, leaving the proxy to resolve the host, as
+    /// it must for an onion address. Streams given different isolation tokens are kept to separate
+    /// Tor circuits, as the token is passed as the SOCKS username (see Tor's `IsolateSOCKSAuth`).
+    pub async fn connect(&self, host: &str, port: u16, isolation: Option<&str>) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(self.addr).await?;
+        let method = if isolation.is_some() { USERNAME_PASSWORD } else { NO_AUTH };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply != [SOCKS_VERSION, method] {
+            return Err(proxy_error(format!("SOCKS proxy refused authentication method {}", method)));
+        }
+        if let Some(token) = isolation {
+            // As per RFC 1929, with a dummy password, as Tor only uses the credentials for isolation:
+            let len = u8::try_from(token.len()).map_err(|_| proxy_error(format!("isolation token too long: {}", token)))?;
+            stream.write_all(&[&[1, len], token.as_bytes(), &[1, b'-']].concat()).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(proxy_error("SOCKS proxy rejected our credentials".to_owned()));
+            }
+        }
+        let len = u8::try_from(host.len()).map_err(|_| proxy_error(format!("host name too long: {}", host)))?;
+        stream.write_all(&[&[SOCKS_VERSION, CONNECT, 0, DOMAIN_NAME, len], host.as_bytes(), &port.to_be_bytes()].concat()).await?;
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        if header[1] != 0 {
+            return Err(proxy_error(format!("SOCKS proxy could not connect to {}:{}, with reply code {}", host, port, header[1])));
+        }
+        // Skip the address the proxy bound to, which is of no use to us:
+        let addr_len = match header[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => usize::from(stream.read_u8().await?),
+            atyp => return Err(proxy_error(format!("SOCKS proxy replied with unknown address type {}", atyp))),
+        };
+        stream.read_exact(&mut vec![0; addr_len + 2]).await?;
+        Ok(stream)
+    }
+}
+
+/// A tonic connector making every connection through the proxy, with the given isolation token.
+#[derive(Clone)]
+struct ProxyConnector {
+    proxy: Socks5Proxy,
+    isolation: Option<String>,
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output=io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Uri) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let host = req.host().ok_or_else(|| proxy_error(format!("missing host in URL: {}", req)))?;
+            let port = req.port_u16().unwrap_or(if req.scheme_str() == Some("https") { 443 } else { 80 });
+            Ok(TokioIo::new(this.proxy.connect(host, port, this.isolation.as_deref()).await?))
+        })
+    }
+}
+
+/// Whether the URI is of a loopback address, to connect to directly rather than through the proxy.
+pub fn is_loopback(uri: &Uri) -> bool {
+    uri.host().is_some_and(|host| host == "localhost"
+        || host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback()))
+}
+
+/// Connect to the endpoint through the proxy, if one is given, with the given isolation token. An
+/// endpoint on a loopback address (such as a local signing service) is always connected to directly.
+pub async fn connect(endpoint: &Endpoint, proxy: Option<Socks5Proxy>, isolation: Option<&str>) -> Result<Channel, tonic::transport::Error> {
+    match proxy {
+        Some(proxy) if !is_loopback(endpoint.uri()) => {
+            endpoint.connect_with_connector(ProxyConnector { proxy, isolation: isolation.map(ToOwned::to_owned) }).await
+        }
+        _ => endpoint.connect().await,
+    }
+}
+
+/// An onion service added through the control port of a Tor daemon, which lasts only as long as the
+/// control connection is kept open, so is taken down along with the server.
+pub struct OnionService {
+    /// The onion address of the service, without the port.
+    pub address: String,
+    _control: BufReader<TcpStream>,
+}
+
+async fn send_command(control: &mut BufReader<TcpStream>, command: &str) -> io::Result<Vec<String>> {
+    control.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if control.read_line(&mut line).await? == 0 {
+            return Err(proxy_error("Tor control connection closed".to_owned()));
+        }
+        let line = line.trim_end();
+        if !line.starts_with("250") {
+            return Err(proxy_error(format!("Tor control command failed: {}", line)));
+        }
+        lines.push(line.get(4..).unwrap_or_default().to_owned());
+        // The last line of a reply has a space after the status code, rather than a '-' or '+':
+        if line.as_bytes().get(3) == Some(&b' ') {
+            return Ok(lines);
+        }
+    }
+}
+
+/// Publish the peer service, which listens on the given local address, as an onion service through
+/// the configured Tor control port. Its private key is kept in the configured key file (generated by
+/// Tor on first use), so that its address stays the same across restarts, as the peers of ongoing
+/// trades will have recorded it.
+pub async fn publish_onion_service(config: &OnionServiceConfig, target: SocketAddr) -> io::Result<OnionService> {
+    let mut control = BufReader::new(TcpStream::connect(config.control_addr).await?);
+    let mut authenticate = "AUTHENTICATE".to_owned();
+    if let Some(path) = &config.cookie_file {
+        authenticate.push(' ');
+        for b in fs::read(path)? {
+            write!(authenticate, "{:02x}", b).unwrap();
+        }
+    }
+    send_command(&mut control, &authenticate).await?;
+    let key = match fs::read_to_string(&config.key_file) {
+        Ok(key) => key.trim().to_owned(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => "NEW:ED25519-V3".to_owned(),
+        Err(e) => return Err(e),
+    };
+    let reply = send_command(&mut control, &format!("ADD_ONION {} Port={},{}", key, config.port, target)).await?;
+    let mut service_id = None;
+    for line in reply {
+        if let Some(id) = line.strip_prefix("ServiceID=") {
+            service_id = Some(id.to_owned());
+        } else if let Some(new_key) = line.strip_prefix("PrivateKey=") {
+            write_atomically(Path::new(&config.key_file), new_key.as_bytes())?;
+        }
+    }
+    let service_id = service_id.ok_or_else(|| proxy_error("Tor did not return the onion service ID".to_owned()))?;
+    Ok(OnionService { address: format!("{}.onion", service_id), _control: control })
+}
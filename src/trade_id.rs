@@ -0,0 +1,50 @@
+//! The IDs of the trades: those supplied by the client, which are checked to be of a strict form
+//! (so that they are safe to log), and those generated by the daemon for a trade opened without one,
+//! which are version 7 UUIDs. Either kind is hex-encoded to build file names from.
+
+use rand::RngCore as _;
+use std::fmt::Write as _;
+use std::prelude::rust_2021::*;
+use std::time::SystemTime;
+use tonic::Status;
+
+use musig_proto::convert::to_millis;
+
+/// The longest trade ID accepted from a client, short enough that a file name holding it hex-encoded
+/// (with a short prefix & extension) fits within the usual limit of 255 bytes.
+pub const MAX_TRADE_ID_LEN: usize = 120;
+
+/// Check that the trade ID supplied by the client in the given field is 1 to [`MAX_TRADE_ID_LEN`]
+/// ASCII letters, digits, `-`, `_`, `.` or `:`, starting with a letter or digit.
+///
+/// # Errors
+///
+/// Fails with `INVALID_ARGUMENT` if it is not.
+pub fn check_trade_id(trade_id: &str, field: &str) -> Result<(), Status> {
+    let valid = trade_id.len() <= MAX_TRADE_ID_LEN
+        && trade_id.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && trade_id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    if valid {
+        Ok(())
+    } else {
+        // The ID is left out of the message, as it may hold anything:
+        Err(Status::invalid_argument(format!(
+            "malformed {}: expected 1 to {} ASCII letters, digits, '-', '_', '.' or ':', starting with a letter or digit",
+            field, MAX_TRADE_ID_LEN)))
+    }
+}
+
+/// A new random trade ID, a version 7 UUID (as per RFC 9562) for the current time, so that the IDs
+/// generated sort in order of creation.
+pub fn new_trade_id() -> String {
+    let mut bytes = [0; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[..6].copy_from_slice(&to_millis(SystemTime::now()).to_be_bytes()[2..]);
+    bytes[6] = 0x70 | (bytes[6] & 0x0f);
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+    let mut hex = String::with_capacity(32);
+    for b in bytes {
+        write!(hex, "{:02x}", b).unwrap();
+    }
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
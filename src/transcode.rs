@@ -0,0 +1,497 @@
+//! The transcoding of protobuf messages to & from JSON by their descriptors (as compiled into the
+//! proto crate), for the JSON gateway. This follows the proto3 JSON mapping: fields are named by
+//! their JSON (lowerCamelCase) names, though the proto names are accepted too; 64-bit integers are
+//! strings, so as not to lose precision in JavaScript; bytes are base64; and enum values are named.
+//! Only the fields found on the wire are written out, so proto3 fields left at their defaults are
+//! omitted. The well-known types get no special treatment, as the `MuSig` service uses none.
+
+use base64::Engine as _;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use prost::Message as _;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto, FileDescriptorSet, MethodDescriptorProto};
+use std::collections::HashMap;
+use std::prelude::rust_2021::*;
+use thiserror::Error;
+
+use crate::json::Json;
+
+/// How deeply messages may nest, so that a hostile message or document cannot exhaust the stack.
+const MAX_DEPTH: usize = 32;
+/// A generous estimate of the memory each field of a message takes in the decoded struct (such as a
+/// `Vec` or `String`, of 24 bytes, or an `Option` of one), for [`Descriptors::check_limits`].
+const DECODED_FIELD_SIZE: usize = 32;
+/// The most memory each value of a packed repeated scalar field takes once decoded.
+const DECODED_SCALAR_SIZE: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum TranscodeError {
+    #[error("unknown message type: {0}")]
+    UnknownType(String),
+    #[error("unknown field '{field}' of message {message}")]
+    UnknownField { message: String, field: String },
+    #[error("invalid value of field '{field}': expected {expected}")]
+    InvalidValue { field: String, expected: &'static str },
+    #[error("expected a JSON object for message {0}")]
+    NotAnObject(String),
+    #[error("messages nested too deeply")]
+    TooDeep,
+    #[error("malformed protobuf message: {0}")]
+    Malformed(&'static str),
+    #[error("field '{field}' of message {message} is {len} bytes long, over the limit of {max} bytes")]
+    FieldTooLong { message: String, field: String, len: usize, max: usize },
+    #[error("message would take over {0} bytes of memory once decoded")]
+    OverAllocationBudget(usize),
+}
+
+type Result<T, E = TranscodeError> = std::result::Result<T, E>;
+
+/// The limits a protobuf message is held to on the wire by [`Descriptors::check_limits`].
+pub struct WireLimits<'a> {
+    /// The most bytes each bytes or string field may hold, by the names of its message & field.
+    pub max_field_len: &'a dyn Fn(&str, &str) -> usize,
+    /// The most memory the message may take once decoded, by a rough (but conservative) estimate.
+    pub allocation_budget: usize,
+}
+
+/// The message & enum types and the service methods of a set of proto files, by their full names
+/// (with a leading '.', as in the `type_name` of each field, for the types).
+#[derive(Default)]
+pub struct Descriptors {
+    messages: HashMap<String, DescriptorProto>,
+    enums: HashMap<String, EnumDescriptorProto>,
+    methods: HashMap<String, MethodDescriptorProto>,
+}
+
+impl Descriptors {
+    /// Load the descriptors from the given encoded `FileDescriptorSet`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the set does not decode.
+    pub fn load(file_descriptor_set: &[u8]) -> Result<Self, prost::DecodeError> {
+        let mut descriptors = Self::default();
+        for file in FileDescriptorSet::decode(file_descriptor_set)?.file {
+            let package = file.package().to_owned();
+            let prefix = if package.is_empty() { String::new() } else { format!(".{}", package) };
+            for message in file.message_type {
+                descriptors.add_message(&prefix, message);
+            }
+            for enum_type in file.enum_type {
+                descriptors.enums.insert(format!("{}.{}", prefix, enum_type.name()), enum_type);
+            }
+            for service in file.service {
+                let service_name = if package.is_empty() { service.name().to_owned() } else {
+                    format!("{}.{}", package, service.name())
+                };
+                for method in service.method {
+                    descriptors.methods.insert(format!("{}/{}", service_name, method.name()), method);
+                }
+            }
+        }
+        Ok(descriptors)
+    }
+
+    fn add_message(&mut self, prefix: &str, mut message: DescriptorProto) {
+        let full_name = format!("{}.{}", prefix, message.name());
+        for nested in message.nested_type.drain(..) {
+            self.add_message(&full_name, nested);
+        }
+        for enum_type in message.enum_type.drain(..) {
+            self.enums.insert(format!("{}.{}", full_name, enum_type.name()), enum_type);
+        }
+        self.messages.insert(full_name, message);
+    }
+
+    /// The method of the given (package-qualified) service with the given name, if any.
+    pub fn method(&self, service: &str, name: &str) -> Option<&MethodDescriptorProto> {
+        self.methods.get(&format!("{}/{}", service, name))
+    }
+
+    fn message(&self, type_name: &str) -> Result<&DescriptorProto> {
+        self.messages.get(type_name).ok_or_else(|| TranscodeError::UnknownType(type_name.to_owned()))
+    }
+
+    /// Encode the given JSON object as a protobuf message of the given type.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the JSON is not an object of the message's fields with values of the right types.
+    pub fn encode(&self, type_name: &str, json: &Json) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.encode_message(type_name, json, &mut buf, 0)?;
+        Ok(buf)
+    }
+
+    /// Decode the given protobuf message of the given type to a JSON object. Fields unknown to the
+    /// descriptors are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the message is malformed or a field has the wrong wire type.
+    pub fn decode(&self, type_name: &str, bytes: &[u8]) -> Result<Json> {
+        self.decode_message(type_name, bytes, 0)
+    }
+
+    /// Check the given protobuf message of the given type against the given limits, without
+    /// decoding it: its bytes & string fields must be no longer than their caps, and the memory it
+    /// would take once decoded within the budget. Fields unknown to the descriptors are skipped, as
+    /// they are by prost.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the message breaks a limit, is malformed or nests too deeply.
+    pub fn check_limits(&self, type_name: &str, bytes: &[u8], limits: &WireLimits<'_>) -> Result<()> {
+        let mut allocated = 0;
+        self.check_message_limits(type_name, bytes, limits, &mut allocated, 0)
+    }
+
+    fn check_message_limits(&self, type_name: &str, mut bytes: &[u8], limits: &WireLimits<'_>, allocated: &mut usize,
+                            depth: usize) -> Result<()> {
+        if depth >= MAX_DEPTH {
+            return Err(TranscodeError::TooDeep);
+        }
+        let message = self.message(type_name)?;
+        let charge = |allocated: &mut usize, size: usize| {
+            *allocated = allocated.saturating_add(size);
+            if *allocated > limits.allocation_budget {
+                return Err(TranscodeError::OverAllocationBudget(limits.allocation_budget));
+            }
+            Ok(())
+        };
+        charge(allocated, message.field.len().max(1) * DECODED_FIELD_SIZE)?;
+        while !bytes.is_empty() {
+            let key = get_varint(&mut bytes)?;
+            let value = skip_wire_value(&mut bytes, key & 7)?;
+            let Some(field) = i32::try_from(key >> 3).ok()
+                .and_then(|number| message.field.iter().find(|field| field.number() == number)) else { continue };
+            let repeated = field.label() == Label::Repeated;
+            match (field.r#type(), value) {
+                (Type::Message, Some(nested)) => self.check_message_limits(field.type_name(), nested, limits, allocated, depth + 1)?,
+                (Type::String | Type::Bytes, Some(value)) => {
+                    let max = (limits.max_field_len)(message.name(), field.name());
+                    if value.len() > max {
+                        return Err(TranscodeError::FieldTooLong {
+                            message: message.name().to_owned(), field: field.name().to_owned(), len: value.len(), max,
+                        });
+                    }
+                    charge(allocated, value.len() + if repeated { DECODED_FIELD_SIZE } else { 0 })?;
+                }
+                (_, Some(packed)) => charge(allocated, packed.len().saturating_mul(DECODED_SCALAR_SIZE))?,
+                (_, None) if repeated => charge(allocated, DECODED_SCALAR_SIZE)?,
+                (_, None) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_message(&self, type_name: &str, json: &Json, buf: &mut Vec<u8>, depth: usize) -> Result<()> {
+        if depth >= MAX_DEPTH {
+            return Err(TranscodeError::TooDeep);
+        }
+        let message = self.message(type_name)?;
+        let Json::Object(entries) = json else { return Err(TranscodeError::NotAnObject(type_name.to_owned())) };
+        for (key, value) in entries {
+            let field = message.field.iter()
+                .find(|field| field.json_name.as_deref() == Some(key) || field.name() == key)
+                .ok_or_else(|| TranscodeError::UnknownField { message: type_name.to_owned(), field: key.clone() })?;
+            match value {
+                Json::Null => {}
+                Json::Array(items) if field.label() == Label::Repeated => {
+                    for item in items {
+                        self.encode_field(field, item, buf, depth)?;
+                    }
+                }
+                _ if field.label() == Label::Repeated => return Err(invalid(field, "an array")),
+                value => self.encode_field(field, value, buf, depth)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_field(&self, field: &FieldDescriptorProto, json: &Json, buf: &mut Vec<u8>, depth: usize) -> Result<()> {
+        let number = u64::try_from(field.number()).map_err(|_| TranscodeError::Malformed("negative field number"))?;
+        let (wire_type, value) = match field.r#type() {
+            Type::Message => {
+                let mut nested = Vec::new();
+                self.encode_message(field.type_name(), json, &mut nested, depth + 1)?;
+                (WireType::Len, WireValue::Len(nested))
+            }
+            Type::String => match json {
+                Json::String(s) => (WireType::Len, WireValue::Len(s.as_bytes().to_vec())),
+                _ => return Err(invalid(field, "a string")),
+            },
+            Type::Bytes => {
+                let bytes = json.as_str().and_then(decode_base64).ok_or_else(|| invalid(field, "a base64 string"))?;
+                (WireType::Len, WireValue::Len(bytes))
+            }
+            // A boolean may be given as a string too, as query parameters give every value as one:
+            Type::Bool => match json {
+                Json::Bool(b) => (WireType::Varint, WireValue::Varint(u64::from(*b))),
+                Json::String(s) if s == "true" || s == "false" => (WireType::Varint, WireValue::Varint(u64::from(s == "true"))),
+                _ => return Err(invalid(field, "a boolean")),
+            },
+            Type::Enum => {
+                let number = match json {
+                    Json::String(name) => self.enums.get(field.type_name())
+                        .and_then(|e| e.value.iter().find(|v| v.name() == name))
+                        .map(EnumValueDescriptorProto::number),
+                    json => int_text(json).and_then(|n| n.parse().ok()),
+                };
+                let number = number.ok_or_else(|| invalid(field, "an enum value name or number"))?;
+                (WireType::Varint, WireValue::Varint(i64::from(number).cast_unsigned()))
+            }
+            Type::Int32 | Type::Sint32 | Type::Sfixed32 => {
+                let n: i32 = parse_int(field, json, "a 32-bit integer")?;
+                match field.r#type() {
+                    // Negative int32 values are sign-extended to 64 bits on the wire:
+                    Type::Int32 => (WireType::Varint, WireValue::Varint(i64::from(n).cast_unsigned())),
+                    Type::Sint32 => (WireType::Varint, WireValue::Varint(u64::from(zigzag32(n)))),
+                    _ => (WireType::Fixed32, WireValue::Fixed32(n.cast_unsigned())),
+                }
+            }
+            Type::Uint32 | Type::Fixed32 => {
+                let n: u32 = parse_int(field, json, "an unsigned 32-bit integer")?;
+                match field.r#type() {
+                    Type::Uint32 => (WireType::Varint, WireValue::Varint(u64::from(n))),
+                    _ => (WireType::Fixed32, WireValue::Fixed32(n)),
+                }
+            }
+            Type::Int64 | Type::Sint64 | Type::Sfixed64 => {
+                let n: i64 = parse_int(field, json, "a 64-bit integer")?;
+                match field.r#type() {
+                    Type::Int64 => (WireType::Varint, WireValue::Varint(n.cast_unsigned())),
+                    Type::Sint64 => (WireType::Varint, WireValue::Varint(zigzag64(n))),
+                    _ => (WireType::Fixed64, WireValue::Fixed64(n.cast_unsigned())),
+                }
+            }
+            Type::Uint64 | Type::Fixed64 => {
+                let n: u64 = parse_int(field, json, "an unsigned 64-bit integer")?;
+                match field.r#type() {
+                    Type::Uint64 => (WireType::Varint, WireValue::Varint(n)),
+                    _ => (WireType::Fixed64, WireValue::Fixed64(n)),
+                }
+            }
+            Type::Double => (WireType::Fixed64, WireValue::Fixed64(parse_float(field, json)?.to_bits())),
+            #[expect(clippy::cast_possible_truncation, reason = "a float field holds just the nearest f32")]
+            Type::Float => (WireType::Fixed32, WireValue::Fixed32((parse_float(field, json)? as f32).to_bits())),
+            Type::Group => return Err(invalid(field, "no value (groups are unsupported)")),
+        };
+        put_varint(buf, number << 3 | wire_type as u64);
+        match value {
+            WireValue::Varint(n) => put_varint(buf, n),
+            WireValue::Fixed32(n) => buf.extend_from_slice(&n.to_le_bytes()),
+            WireValue::Fixed64(n) => buf.extend_from_slice(&n.to_le_bytes()),
+            WireValue::Len(bytes) => {
+                put_varint(buf, bytes.len() as u64);
+                buf.extend_from_slice(&bytes);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_message(&self, type_name: &str, mut bytes: &[u8], depth: usize) -> Result<Json> {
+        if depth >= MAX_DEPTH {
+            return Err(TranscodeError::TooDeep);
+        }
+        let message = self.message(type_name)?;
+        let mut entries: Vec<(String, Json)> = Vec::new();
+        while !bytes.is_empty() {
+            let key = get_varint(&mut bytes)?;
+            let value = get_wire_value(&mut bytes, key & 7)?;
+            let Some(field) = i32::try_from(key >> 3).ok()
+                .and_then(|number| message.field.iter().find(|field| field.number() == number)) else { continue };
+            let name = field.json_name.clone().unwrap_or_else(|| field.name().to_owned());
+            let values = match value {
+                // A packed repeated scalar field holds any number of values:
+                WireValue::Len(packed) if field.label() == Label::Repeated && is_packable(field.r#type()) =>
+                    unpack(field, &packed)?.into_iter().map(|value| self.decode_field(field, value, depth)).collect::<Result<_>>()?,
+                value => vec![self.decode_field(field, value, depth)?],
+            };
+            let entry = entries.iter_mut().position(|(key, _)| *key == name);
+            match (field.label(), entry) {
+                (Label::Repeated, Some(i)) => if let Json::Array(items) = &mut entries[i].1 {
+                    items.extend(values);
+                },
+                (Label::Repeated, None) => entries.push((name, Json::Array(values))),
+                // The last of a non-repeated field wins:
+                (_, Some(i)) => entries[i].1 = values.into_iter().next_back().unwrap_or(Json::Null),
+                (_, None) => entries.extend(values.into_iter().next_back().map(|value| (name, value))),
+            }
+        }
+        Ok(Json::Object(entries))
+    }
+
+    fn decode_field(&self, field: &FieldDescriptorProto, value: WireValue, depth: usize) -> Result<Json> {
+        let number = |n: &dyn ToString| Json::Number(n.to_string());
+        let string = |n: &dyn ToString| Json::String(n.to_string());
+        Ok(match (field.r#type(), value) {
+            (Type::Message, WireValue::Len(bytes)) => self.decode_message(field.type_name(), &bytes, depth + 1)?,
+            (Type::String, WireValue::Len(bytes)) => Json::String(String::from_utf8(bytes)
+                .map_err(|_| TranscodeError::Malformed("string field not valid UTF-8"))?),
+            (Type::Bytes, WireValue::Len(bytes)) => Json::String(STANDARD.encode(bytes)),
+            (Type::Bool, WireValue::Varint(n)) => Json::Bool(n != 0),
+            #[expect(clippy::cast_possible_truncation, reason = "an enum value is a truncated int32 on the wire")]
+            (Type::Enum, WireValue::Varint(n)) => {
+                let n = n as i32;
+                self.enums.get(field.type_name())
+                    .and_then(|e| e.value.iter().find(|v| v.number() == n))
+                    .map_or_else(|| number(&n), |v| Json::String(v.name().to_owned()))
+            }
+            #[expect(clippy::cast_possible_truncation, reason = "an int32 is sign-extended to 64 bits on the wire")]
+            (Type::Int32, WireValue::Varint(n)) => number(&(n as i32)),
+            #[expect(clippy::cast_possible_truncation, reason = "a uint32 is zero-extended to 64 bits on the wire")]
+            (Type::Uint32, WireValue::Varint(n)) => number(&(n as u32)),
+            #[expect(clippy::cast_possible_truncation, reason = "a sint32 is zigzag-encoded in 32 bits")]
+            (Type::Sint32, WireValue::Varint(n)) => number(&(unzigzag(n).cast_signed() as i32)),
+            (Type::Int64, WireValue::Varint(n)) | (Type::Sfixed64, WireValue::Fixed64(n)) => string(&n.cast_signed()),
+            (Type::Uint64, WireValue::Varint(n)) | (Type::Fixed64, WireValue::Fixed64(n)) => string(&n),
+            (Type::Sint64, WireValue::Varint(n)) => string(&unzigzag(n).cast_signed()),
+            (Type::Fixed32, WireValue::Fixed32(n)) => number(&n),
+            (Type::Sfixed32, WireValue::Fixed32(n)) => number(&n.cast_signed()),
+            (Type::Double, WireValue::Fixed64(n)) => float_json(f64::from_bits(n)),
+            (Type::Float, WireValue::Fixed32(n)) => float_json(f64::from(f32::from_bits(n))),
+            _ => return Err(TranscodeError::Malformed("field of the wrong wire type")),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum WireType {
+    Varint = 0,
+    Fixed64 = 1,
+    Len = 2,
+    Fixed32 = 5,
+}
+
+enum WireValue {
+    Varint(u64),
+    Fixed64(u64),
+    Len(Vec<u8>),
+    Fixed32(u32),
+}
+
+fn invalid(field: &FieldDescriptorProto, expected: &'static str) -> TranscodeError {
+    TranscodeError::InvalidValue { field: field.json_name.clone().unwrap_or_else(|| field.name().to_owned()), expected }
+}
+
+/// The text of a JSON integer, which may be given as a number or (for 64-bit types) a string.
+fn int_text(json: &Json) -> Option<&str> {
+    match json {
+        Json::Number(n) | Json::String(n) => Some(n),
+        _ => None,
+    }
+}
+
+fn parse_int<T: std::str::FromStr>(field: &FieldDescriptorProto, json: &Json, expected: &'static str) -> Result<T> {
+    int_text(json).and_then(|n| n.parse().ok()).ok_or_else(|| invalid(field, expected))
+}
+
+fn parse_float(field: &FieldDescriptorProto, json: &Json) -> Result<f64> {
+    match json {
+        Json::String(s) if s == "NaN" => Ok(f64::NAN),
+        Json::String(s) if s == "Infinity" => Ok(f64::INFINITY),
+        Json::String(s) if s == "-Infinity" => Ok(f64::NEG_INFINITY),
+        json => int_text(json).and_then(|n| n.parse().ok()).ok_or_else(|| invalid(field, "a number")),
+    }
+}
+
+fn float_json(x: f64) -> Json {
+    match x {
+        x if x.is_nan() => Json::String("NaN".to_owned()),
+        f64::INFINITY => Json::String("Infinity".to_owned()),
+        f64::NEG_INFINITY => Json::String("-Infinity".to_owned()),
+        x => Json::Number(x.to_string()),
+    }
+}
+
+/// Decode base64 with either the standard or the URL-safe alphabet, padded or not, as the proto3
+/// JSON mapping asks parsers to accept.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let unpadded = s.trim_end_matches('=').replace('+', "-").replace('/', "_");
+    URL_SAFE_NO_PAD.decode(unpadded).ok()
+}
+
+const fn zigzag32(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)).cast_unsigned()
+}
+
+const fn zigzag64(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)).cast_unsigned()
+}
+
+const fn unzigzag(n: u64) -> u64 {
+    (n >> 1) ^ (n & 1).wrapping_neg()
+}
+
+const fn is_packable(field_type: Type) -> bool {
+    !matches!(field_type, Type::String | Type::Bytes | Type::Message | Type::Group)
+}
+
+fn unpack(field: &FieldDescriptorProto, mut packed: &[u8]) -> Result<Vec<WireValue>> {
+    let wire_type = match field.r#type() {
+        Type::Double | Type::Fixed64 | Type::Sfixed64 => WireType::Fixed64,
+        Type::Float | Type::Fixed32 | Type::Sfixed32 => WireType::Fixed32,
+        _ => WireType::Varint,
+    };
+    let mut values = Vec::new();
+    while !packed.is_empty() {
+        values.push(get_wire_value(&mut packed, wire_type as u64)?);
+    }
+    Ok(values)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    #[expect(clippy::cast_possible_truncation, reason = "the last byte is under 0x80")]
+    buf.push(n as u8);
+}
+
+fn get_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or(TranscodeError::Malformed("truncated varint"))?;
+        *bytes = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte < 0x80 {
+            return Ok(n);
+        }
+    }
+    Err(TranscodeError::Malformed("varint too long"))
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    let (taken, rest) = bytes.split_at_checked(len).ok_or(TranscodeError::Malformed("truncated field"))?;
+    *bytes = rest;
+    Ok(taken)
+}
+
+/// Skip over a value of the given wire type, returning the bytes of a length-delimited one.
+fn skip_wire_value<'a>(bytes: &mut &'a [u8], wire_type: u64) -> Result<Option<&'a [u8]>> {
+    match wire_type {
+        0 => get_varint(bytes).map(|_| None),
+        1 => take(bytes, 8).map(|_| None),
+        2 => {
+            let len = usize::try_from(get_varint(bytes)?).map_err(|_| TranscodeError::Malformed("field too long"))?;
+            take(bytes, len).map(Some)
+        }
+        5 => take(bytes, 4).map(|_| None),
+        _ => Err(TranscodeError::Malformed("unsupported wire type")),
+    }
+}
+
+fn get_wire_value(bytes: &mut &[u8], wire_type: u64) -> Result<WireValue> {
+    Ok(match wire_type {
+        0 => WireValue::Varint(get_varint(bytes)?),
+        1 => WireValue::Fixed64(u64::from_le_bytes(take(bytes, 8)?.try_into().unwrap())),
+        2 => {
+            let len = usize::try_from(get_varint(bytes)?).map_err(|_| TranscodeError::Malformed("field too long"))?;
+            WireValue::Len(take(bytes, len)?.to_vec())
+        }
+        5 => WireValue::Fixed32(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap())),
+        _ => return Err(TranscodeError::Malformed("unsupported wire type")),
+    })
+}
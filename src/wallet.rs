@@ -0,0 +1,101 @@
+//! The reservation of the wallet's UTXOs as our funding inputs to the deposit txs of live trades (as
+//! handed to `InitTrade`), so that no UTXO funds two trades at once. The reservations are read off
+//! the live trades themselves, so they outlive a restart, and are released as soon as a trade is
+//! cancelled, or aborted or archived, with no bookkeeping of their own. Dry runs hold none, having
+//! no real funds at stake.
+
+use musig_proto::helloworld::{self, WalletStatus};
+use musig_trade_protocol::status::{self, FieldViolation};
+use musig_trade_protocol::{lock_trade_model, FundingInput, TradeModelStore, TradePhase};
+use std::collections::HashMap;
+use std::prelude::rust_2021::*;
+use std::sync::{Mutex, PoisonError};
+use tonic::{Code, Status};
+
+#[derive(Default)]
+pub struct UtxoReservations {
+    /// Held while a trade is checked against the reservations of the others and opened, so that
+    /// trades opened concurrently cannot both reserve the same UTXO.
+    opening: Mutex<()>,
+}
+
+/// A UTXO reserved by a live trade, which is spent once the trade's deposit tx is published.
+pub struct Reservation {
+    pub trade_id: String,
+    pub input: FundingInput,
+    pub spent: bool,
+}
+
+impl UtxoReservations {
+    /// Open a trade reserving the given funding inputs, by the given function (which adds it to the
+    /// store), unless another live trade has already reserved one of them.
+    ///
+    /// # Errors
+    /// With `FAILED_PRECONDITION` if a funding input is already reserved, naming it, or whatever the
+    /// function fails with.
+    pub fn reserve<T>(&self, store: &impl TradeModelStore, funding_inputs: &[FundingInput],
+                      open: impl FnOnce() -> Result<T, Status>) -> Result<T, Status>
+    {
+        if funding_inputs.is_empty() {
+            return open();
+        }
+        let opening = self.opening.lock().unwrap_or_else(PoisonError::into_inner);
+        let reserved: HashMap<_, _> = reservations(store).into_iter()
+            .map(|reservation| ((reservation.input.txid, reservation.input.vout), reservation.trade_id))
+            .collect();
+        for (i, input) in funding_inputs.iter().enumerate() {
+            if let Some(trade_id) = reserved.get(&(input.txid, input.vout)) {
+                let description = format!("funding input {} is already reserved by trade with id {}", i, trade_id);
+                return Err(status::with_bad_request(Code::FailedPrecondition, description.clone(),
+                    FieldViolation { field: format!("fundingInputs[{}]", i), description }));
+            }
+        }
+        let opened = open();
+        drop(opening);
+        opened
+    }
+}
+
+/// The UTXOs reserved by the live trades in the given store, in the order of the trades.
+pub fn reservations(store: &impl TradeModelStore) -> Vec<Reservation> {
+    let mut reservations = Vec::new();
+    for summary in store.list_trade_models() {
+        // A trade archived since it was listed no longer reserves any:
+        let Some(trade_model) = store.get_trade_model(&summary.trade_id) else { continue };
+        let trade_model = lock_trade_model(&trade_model);
+        if trade_model.dry_run || trade_model.cancellation.is_some() {
+            continue;
+        }
+        let spent = trade_model.phase() >= TradePhase::DepositTxPublished;
+        reservations.extend(trade_model.my_funding_inputs().iter().map(|input| Reservation {
+            trade_id: summary.trade_id.clone(),
+            input: input.clone(),
+            spent,
+        }));
+    }
+    reservations
+}
+
+/// The status of the wallet with the given UTXOs (of which only the txid, vout & amount are read),
+/// by the reservations of the live trades in the given store.
+pub fn wallet_status(store: &impl TradeModelStore, wallet_utxos: &[helloworld::FundingInput]) -> WalletStatus {
+    let reservations = reservations(store);
+    let reserved_sats = reservations.iter().filter(|reservation| !reservation.spent)
+        .map(|reservation| reservation.input.amount).sum();
+    let available_sats = wallet_utxos.iter()
+        .filter(|utxo| !reservations.iter()
+            .any(|reservation| reservation.input.txid[..] == utxo.txid[..] && reservation.input.vout == utxo.vout))
+        .map(|utxo| utxo.amount)
+        .sum();
+    WalletStatus {
+        reserved_utxos: reservations.into_iter().map(|reservation| helloworld::ReservedUtxo {
+            txid: reservation.input.txid.to_vec(),
+            vout: reservation.input.vout,
+            amount: reservation.input.amount,
+            trade_id: reservation.trade_id,
+            spent: reservation.spent,
+        }).collect(),
+        reserved_sats,
+        available_sats,
+    }
+}
@@ -0,0 +1,157 @@
+//! Notification of trade events by webhook, for operators to hook the daemon up to their existing
+//! alerting, rather than have it poll the gRPC service.
+//!
+//! Each event of the configured kinds is posted to every webhook as a JSON object, signed with an
+//! `HMAC-SHA256` of the body under a secret key shared with the receiver, in the `X-MuSig-Signature`
+//! header as `sha256=<hex>`. The object holds the `event` kind, the `trade_id` and the time it was
+//! sent, `at` (in Unix millis), so that a replayed payload can be told apart, along with any details
+//! of the event. A failed delivery is only logged, not retried. Payloads are posted through the SOCKS
+//! proxy, if one is configured, unless to a loopback address, so as not to reveal our IP address.
+
+use hmac::{Hmac, Mac as _};
+use sha2::Sha256;
+use std::fmt::Write as _;
+use std::io;
+use std::prelude::rust_2021::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time;
+
+use crate::config::WebhookConfig;
+use crate::events::{TradeEvent, TradeEventBus};
+use crate::http;
+use crate::tor::Socks5Proxy;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long to wait for a webhook to take each payload.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct WebhookNotifier {
+    urls: Vec<String>,
+    events: Vec<&'static str>,
+    secret_key: Vec<u8>,
+    proxy: Option<Socks5Proxy>,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: &WebhookConfig, secret_key: Vec<u8>) -> Self {
+        Self { urls: config.urls.clone(), events: config.events.clone(), secret_key, proxy: None }
+    }
+
+    /// Post the payloads through the given proxy, if any.
+    pub const fn with_proxy(mut self, proxy: Option<Socks5Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// A notifier as configured, with its secret key read from the configured env var.
+    pub fn from_env(config: &WebhookConfig) -> io::Result<Self> {
+        let secret_key = std::env::var(&config.secret_env).map_err(|e| io::Error::new(io::ErrorKind::NotFound,
+            format!("could not read webhook secret from env var {}: {}", config.secret_env, e)))?;
+        Ok(Self::new(config, secret_key.into_bytes()))
+    }
+
+    /// The JSON payload of the event sent at the given time, with its signature, if it is of a
+    /// kind to notify of.
+    pub fn payload(&self, event: &TradeEvent, at: SystemTime) -> Option<(String, String)> {
+        if !self.events.contains(&event.kind()) {
+            return None;
+        }
+        let at = at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis();
+        let mut body = format!(r#"{{"event":"{}","trade_id":{},"at":{}"#, event.kind(), json_string(event.trade_id()), at);
+        let mut field = |name: &str, value: String| write!(body, r#","{}":{}"#, name, value).unwrap();
+        match event {
+            TradeEvent::Aborted(summary) => field("phase", json_string(&format!("{:?}", summary.phase))),
+            TradeEvent::DeadlineApproaching { deadline, .. } | TradeEvent::DeadlinePassed { deadline, .. } => {
+                field("deadline", json_string(&format!("{:?}", deadline.kind)));
+                field("phase", json_string(&format!("{:?}", deadline.phase)));
+            }
+            TradeEvent::PeerUnresponsive { last_seen, .. } => if let Some(last_seen) = last_seen {
+                field("last_seen", last_seen.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis().to_string());
+            },
+            TradeEvent::PeerNonceReused { reused_from, .. } => field("reused_from", json_string(reused_from)),
+            TradeEvent::PolicyAction { action, dry_run, .. } => {
+                field("action", json_string(&format!("{:?}", action.kind)));
+                field("dry_run", dry_run.to_string());
+            }
+            TradeEvent::StepFailed { step, code, message, .. } => {
+                field("step", json_string(step));
+                field("code", json_string(&format!("{:?}", code)));
+                field("message", json_string(message));
+            }
+            TradeEvent::Closed { .. } => {}
+        }
+        if let Some(correlation_id) = event.correlation_id() {
+            field("correlation_id", json_string(correlation_id));
+        }
+        body.push('}');
+        let signature = self.sign(&body);
+        Some((body, signature))
+    }
+
+    /// The signature of the given body, as sent in the `X-MuSig-Signature` header.
+    pub fn sign(&self, body: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.secret_key).unwrap();
+        mac.update(body.as_bytes());
+        let mut signature = "sha256=".to_owned();
+        for b in mac.finalize().into_bytes() {
+            write!(signature, "{:02x}", b).unwrap();
+        }
+        signature
+    }
+}
+
+/// Post each trade event of the configured kinds to every webhook, each delivery in its own task,
+/// so that a slow webhook cannot hold up the rest. This only returns once the event bus closes.
+pub async fn notify_webhooks(events: TradeEventBus, notifier: Arc<WebhookNotifier>) {
+    let mut events = events.subscribe();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                eprintln!("Missed {} trade events for the webhooks", n);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let Some((body, signature)) = notifier.payload(&event, SystemTime::now()) else { continue };
+        let (kind, payload) = (event.kind(), Arc::new((body, signature)));
+        for url in &notifier.urls {
+            let (url, payload, proxy) = (url.clone(), Arc::clone(&payload), notifier.proxy);
+            tokio::spawn(async move {
+                if let Err(e) = deliver(&url, &payload.0, &payload.1, proxy).await {
+                    eprintln!("Could not notify webhook {} of {} event: {}", url, kind, e);
+                }
+            });
+        }
+    }
+}
+
+async fn deliver(url: &str, body: &str, signature: &str, proxy: Option<Socks5Proxy>) -> io::Result<()> {
+    let headers = [("Content-Type", "application/json"), ("X-MuSig-Signature", signature)];
+    let (status, _) = time::timeout(DELIVERY_TIMEOUT, http::request("POST", url, &headers, body.as_bytes(), proxy)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))??;
+    if !(200..300).contains(&status) {
+        return Err(io::Error::other(format!("unexpected HTTP status: {}", status)));
+    }
+    Ok(())
+}
+
+/// The given string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => write!(json, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}